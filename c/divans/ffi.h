@@ -31,10 +31,17 @@ typedef uint8_t DivansOptionSelect;
 #define DIVANS_OPTION_PRIOR_BITMASK_DETECTION 17
 #define DIVANS_OPTION_Q9_5 18
 #define DIVANS_OPTION_FORCE_LITERAL_CONTEXT_MODE 19
+#define DIVANS_OPTION_IR_OPTIMIZER 20
+#define DIVANS_OPTION_DISTANCE_POSTFIX_BITS 21
+#define DIVANS_OPTION_NUM_DIRECT_DISTANCES 22
+#define DIVANS_OPTION_LITERAL_PRESCAN 23
 
 
 /// a struct specifying custom allocators for divans to use instead of the builtin rust allocators.
 /// if all 3 values are set to NULL, the Rust allocators are used instead.
+/// Given allocators, every allocation an instance makes, its state included, goes through them with
+/// its opaque, and nothing is kept in globals or thread locals: instances may be used concurrently,
+/// each from one thread at a time, and with allocators of their own.
 struct CAllocator {
     /// Allocate length bytes. The returned pointer must be 32-byte aligned unless divans was built without features=simd
     void* (*alloc_func)(void * opaque, size_t length);
@@ -58,7 +65,10 @@ void divans_free_compressor(struct DivansCompressorState* mfd);
 
 
 struct DivansDecompressorState* divans_new_decompressor();
-struct DivansDecompressorState* divans_new_decompressor_with_custom_alloc(struct CAllocator alloc, uint8_t skip_crc);
+struct DivansDecompressorState* divans_new_serial_decompressor();
+/// multithread decodes on a worker thread, which needs the Rust allocator: given alloc_func it returns NULL
+struct DivansDecompressorState* divans_new_decompressor_with_custom_alloc(struct CAllocator alloc, uint8_t skip_crc,
+                                                                          uint8_t multithread);
 DivansResult divans_decode(struct DivansDecompressorState* state,
                            const uint8_t *input_buf_ptr, size_t input_size, size_t*input_offset,
                            uint8_t *output_buf_ptr, size_t output_size, size_t *output_offset);
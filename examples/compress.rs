@@ -29,6 +29,21 @@ fn main() {
                 stride_detection_quality: example_opts.stride_detection_quality,
                 prior_bitmask_detection: example_opts.prior_bitmask_detection,
                 divans_ir_optimizer:example_opts.divans_ir_optimizer,
+                literal_prescan:example_opts.literal_prescan,
+                stream_features:example_opts.stream_features, // ignored by the hybrid compressor
+                distance_bucketing:example_opts.distance_bucketing,
+                frame_alignment:example_opts.frame_alignment,
+                authentication_key:example_opts.authentication_key,
+                mixer_warm_start:example_opts.mixer_warm_start,
+                extended_stream_features:example_opts.extended_stream_features,
+                framing_tag:example_opts.framing_tag,
+                compact_priors:example_opts.compact_priors,
+                format_version:example_opts.format_version,
+                content_hint:example_opts.content_hint,
+                freeze_adaptation_after:example_opts.freeze_adaptation_after,
+                priors_reset_interval:example_opts.priors_reset_interval,
+                extended_stream_features2:example_opts.extended_stream_features2,
+                checksum:example_opts.checksum, // crc32c, or xxh3 for archives too large for 32 bits
             },
             4096, // internal buffer size
         );
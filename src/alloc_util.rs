@@ -19,6 +19,68 @@ pub use super::slice_util::AllocatedMemoryPrefix;
 pub struct RepurposingAlloc<T, AllocT: Allocator<T>> {
     alloc: AllocT,
     cached_allocation: AllocT::AllocatedMemory,
+    slab: SlabArena<T, AllocT>,
+}
+
+// the smallest size class holds cells of 1 << SLAB_MIN_CLASS_LOG items, the largest of 1 << SLAB_MAX_CLASS_LOG
+const SLAB_MIN_CLASS_LOG: u32 = 4;
+const SLAB_MAX_CLASS_LOG: u32 = 12;
+const SLAB_NUM_CLASSES: usize = (SLAB_MAX_CLASS_LOG - SLAB_MIN_CLASS_LOG + 1) as usize;
+const SLAB_SLOTS_PER_CLASS: usize = 8;
+
+/// Freed cells kept by size class for the short lived buffers of literal commands: the codec
+/// allocates one per literal, and a decoder holds many at once in its command buffer, which the
+/// single cached allocation of RepurposingAlloc cannot cover.  Cells are allocated a whole class
+/// large so any cell of a class serves any request rounding up to it.
+struct SlabArena<T, AllocT: Allocator<T>> {
+    cells: [[AllocT::AllocatedMemory; SLAB_SLOTS_PER_CLASS]; SLAB_NUM_CLASSES],
+    counts: [u8; SLAB_NUM_CLASSES],
+}
+
+impl<T, AllocT: Allocator<T>> SlabArena<T, AllocT> {
+    fn new() -> Self {
+        SlabArena {
+            cells: core::array::from_fn(|_| core::array::from_fn(|_| AllocT::AllocatedMemory::default())),
+            counts: [0; SLAB_NUM_CLASSES],
+        }
+    }
+    // the class serving requests of size items, if any
+    fn class_of(size: usize) -> Option<usize> {
+        if size == 0 || size > 1 << SLAB_MAX_CLASS_LOG {
+            return None;
+        }
+        let log = core::cmp::max(size.next_power_of_two().trailing_zeros(), SLAB_MIN_CLASS_LOG);
+        Some((log - SLAB_MIN_CLASS_LOG) as usize)
+    }
+    fn class_size(class: usize) -> usize {
+        1 << (class as u32 + SLAB_MIN_CLASS_LOG)
+    }
+    fn take(&mut self, class: usize) -> Option<AllocT::AllocatedMemory> {
+        if self.counts[class] == 0 {
+            return None;
+        }
+        self.counts[class] -= 1;
+        Some(core::mem::take(&mut self.cells[class][usize::from(self.counts[class])]))
+    }
+    // keeps cell if it is exactly a class large and the class has room, else hands it back
+    fn put(&mut self, cell: AllocT::AllocatedMemory) -> Option<AllocT::AllocatedMemory> {
+        match Self::class_of(cell.slice().len()) {
+            Some(class) if Self::class_size(class) == cell.slice().len()
+                && usize::from(self.counts[class]) < SLAB_SLOTS_PER_CLASS => {
+                self.cells[class][usize::from(self.counts[class])] = cell;
+                self.counts[class] += 1;
+                None
+            },
+            _ => Some(cell),
+        }
+    }
+    fn free(&mut self, alloc: &mut AllocT) {
+        for class in 0..SLAB_NUM_CLASSES {
+            while let Some(cell) = self.take(class) {
+                alloc.free_cell(cell);
+            }
+        }
+    }
 }
 /*
 pub struct LimitedAllocatedMemory32<T, AllocT:Allocator<T>> {
@@ -59,6 +121,16 @@ impl<T, AllocT:Allocator<T> > core::ops::IndexMut<usize> for LimitedAllocatedMem
 }
 */
 
+// copies src into a fresh allocation; an empty cell stays unallocated
+pub fn duplicate_cell<T: Clone, AllocT: Allocator<T>>(alloc: &mut AllocT, src: &AllocT::AllocatedMemory) -> AllocT::AllocatedMemory {
+    if src.slice().is_empty() {
+        return AllocT::AllocatedMemory::default();
+    }
+    let mut ret = alloc.alloc_cell(src.slice().len());
+    ret.slice_mut().clone_from_slice(src.slice());
+    ret
+}
+
 pub trait ShouldClearCacheOnAlloc<T> {
     fn should_clear() -> Option<T>;
 }
@@ -114,20 +186,65 @@ impl<'a, T: 'a, AllocT: 'a + Allocator<T>, ShouldClear: ShouldClearCacheOnAlloc<
     }
 }
 
+pub struct SlabAllocator<'a, T: 'a, AllocT: 'a + Allocator<T>, ShouldClear: ShouldClearCacheOnAlloc<T>> {
+    alloc: &'a mut RepurposingAlloc<T, AllocT>,
+    marker: core::marker::PhantomData<ShouldClear>,
+}
+
+impl<'a, T: 'a, AllocT: 'a + Allocator<T>, ShouldClear: ShouldClearCacheOnAlloc<T>> Allocator<T> for SlabAllocator<'a, T, AllocT, ShouldClear> {
+    type AllocatedMemory = AllocatedMemoryPrefix<T, AllocT>;
+    fn alloc_cell(&mut self, s: usize) -> AllocatedMemoryPrefix<T, AllocT> {
+        let class = match SlabArena::<T, AllocT>::class_of(s) {
+            Some(class) => class,
+            None => return self.alloc.use_cached_allocation::<ShouldClear>().alloc_cell(s),
+        };
+        match self.alloc.slab.take(class) {
+            Some(mut cell) => {
+                if ShouldClear::should_clear().is_some() {
+                    for item in cell.slice_mut()[..s].iter_mut() {
+                        *item = ShouldClear::should_clear().unwrap();
+                    }
+                }
+                AllocatedMemoryPrefix::<T, AllocT>::realloc(cell, s)
+            },
+            None => AllocatedMemoryPrefix::<T, AllocT>::realloc(
+                self.alloc.alloc.alloc_cell(SlabArena::<T, AllocT>::class_size(class)), s),
+        }
+    }
+    fn free_cell(&mut self, cell: AllocatedMemoryPrefix<T, AllocT>) {
+        if let Some(mem) = self.alloc.slab.put(cell.components().0) {
+            self.alloc.use_cached_allocation::<ShouldClear>().free_cell(AllocatedMemoryPrefix::<T, AllocT>(mem, 0));
+        }
+    }
+}
+
 impl<T, AllocT: Allocator<T>> RepurposingAlloc<T, AllocT> {
     pub fn new(alloc: AllocT) -> Self {
         Self {
             alloc: alloc,
             cached_allocation: AllocT::AllocatedMemory::default(),
+            slab: SlabArena::new(),
         }
     }
-    pub fn disassemble(self) -> (AllocT, AllocT::AllocatedMemory) {
+    pub fn disassemble(mut self) -> (AllocT, AllocT::AllocatedMemory) {
+        self.slab.free(&mut self.alloc);
         (self.alloc, self.cached_allocation)
     }
     pub fn reassemble(data: (AllocT, AllocT::AllocatedMemory)) -> Self {
         Self {
             alloc:data.0,
             cached_allocation:data.1,
+            slab: SlabArena::new(),
+        }
+    }
+    // Serves small requests from cells kept by size class, and the rest as use_cached_allocation
+    // would.  Cells from either may be freed to either, though only this one keeps them by class.
+    pub fn use_slab_arena<'a, ClearCacheDecision: ShouldClearCacheOnAlloc<T>>(
+        &'a mut self,
+    ) -> SlabAllocator<'a, T, AllocT, ClearCacheDecision> {
+        SlabAllocator::<T, AllocT, ClearCacheDecision> {
+            alloc: self,
+            marker: core::marker::PhantomData,
         }
     }
     pub fn use_cached_allocation<'a, ClearCacheDecision: ShouldClearCacheOnAlloc<T>>(
@@ -142,6 +259,7 @@ impl<T, AllocT: Allocator<T>> RepurposingAlloc<T, AllocT> {
         &mut self.alloc
     }
     pub fn free_ref(&mut self) {
+        self.slab.free(&mut self.alloc);
         self.alloc.free_cell(core::mem::replace(&mut self.cached_allocation, AllocT::AllocatedMemory::default()));
     }
     pub fn free(mut self) -> AllocT {
@@ -165,7 +283,7 @@ impl<T, AllocT: Allocator<T>> Allocator<T> for RepurposingAlloc<T, AllocT> {
 mod test {
     use core;
     use alloc_stdlib::HeapAlloc;
-    use super::{Allocator, AllocatedMemoryPrefix, RepurposingAlloc, UninitializedOnAlloc};
+    use super::{Allocator, AllocatedMemoryPrefix, RepurposingAlloc, SliceWrapper, UninitializedOnAlloc};
     struct LoggedAllocator<T, AllocT: Allocator<T>> {
         alloc: AllocT,
         count_alloc_cell: usize,
@@ -244,4 +362,31 @@ mod test {
         assert_eq!(cached_alloc.alloc.get_base_alloc().count_alloc_cell, 4);
         assert_eq!(cached_alloc.alloc.get_base_alloc().count_free_cell, 5);
     }
+
+    #[test]
+    fn test_slab_reuse() {
+        let base_alloc = LoggedAllocator::<u8, HeapAlloc<u8>>::new(HeapAlloc::<u8>::new(0u8));
+        let mut alloc = RepurposingAlloc::<u8, LoggedAllocator<u8, HeapAlloc<u8>>>::new(base_alloc);
+        type AllocatedMemory = AllocatedMemoryPrefix<u8, LoggedAllocator<u8, HeapAlloc<u8>>>;
+        // literals of a few sizes, several outstanding at once, over and over
+        for _ in 0..100 {
+            let mut slab = alloc.use_slab_arena::<UninitializedOnAlloc>();
+            let mut cells = [slab.alloc_cell(3), slab.alloc_cell(17), slab.alloc_cell(20), slab.alloc_cell(1000)];
+            assert_eq!(cells[1].slice().len(), 17);
+            for c in cells.iter_mut() {
+                slab.free_cell(core::mem::replace(c, AllocatedMemory::default()));
+            }
+        }
+        assert_eq!(alloc.get_base_alloc().count_alloc_cell, 4);
+        assert_eq!(alloc.get_base_alloc().count_free_cell, 0);
+        // too large for any class: served as use_cached_allocation would
+        let mut slab = alloc.use_slab_arena::<UninitializedOnAlloc>();
+        let large = slab.alloc_cell(100000);
+        slab.free_cell(large);
+        assert_eq!(alloc.get_base_alloc().count_alloc_cell, 5);
+        assert_eq!(alloc.get_base_alloc().count_free_cell, 1); // the empty cached allocation it replaced
+        // the class cells and the cached allocation holding the large one are all freed
+        let base_alloc = alloc.free();
+        assert_eq!(base_alloc.count_free_cell, 6);
+    }
 }
@@ -12,26 +12,35 @@
 //   See the License for the specific language governing permissions and
 //   limitations under the License.
 
+#[cfg(feature="encoder")]
 use core;
+use alloc::Allocator;
+#[cfg(feature="encoder")]
 use alloc::{
-    Allocator,
     SliceWrapper,
     SliceWrapperMut
 };
 use core::default::Default;
 use core::{mem, cmp};
-use probability::{CDF16, BaseCDF, Prob, LOG2_SCALE, ProbRange};
+use probability::{CDF16, Prob, LOG2_SCALE, ProbRange};
+#[cfg(feature="decoder")]
+use probability::BaseCDF;
+use super::interface::NewWithAllocator;
+#[cfg(feature="decoder")]
+use super::interface::BillingCapability;
+#[cfg(feature="encoder")]
 use super::interface::{
     ArithmeticEncoderOrDecoder,
-    NewWithAllocator,
-    BillingCapability,
+    DuplicateWithAllocator,
 };
+#[cfg(feature="encoder")]
+use alloc_util::duplicate_cell;
 use super::DivansResult;
-use super::arithmetic_coder::{
-    EntropyEncoder,
-    EntropyDecoder,
-    ByteQueue,
-};
+use super::arithmetic_coder::ByteQueue;
+#[cfg(feature="decoder")]
+use super::arithmetic_coder::EntropyDecoder;
+#[cfg(feature="encoder")]
+use super::arithmetic_coder::EntropyEncoder;
 
 /*
 #[cfg(test)]
@@ -56,11 +65,13 @@ macro_rules! perror(
 */
 pub const MAX_BUFFER_SIZE: usize = 256*1024; // with space for size
 
+#[cfg(feature="encoder")]
 pub struct ByteStack<AllocU8: Allocator<u8>>  {
     data : AllocU8::AllocatedMemory,
     nbytes : usize,
 }
 
+#[cfg(feature="encoder")]
 impl<A: Allocator<u8>> NewWithAllocator<A> for ByteStack<A> {
     fn new(m8: &mut A) -> Self {
         let data = m8.alloc_cell(MAX_BUFFER_SIZE);
@@ -71,6 +82,17 @@ impl<A: Allocator<u8>> NewWithAllocator<A> for ByteStack<A> {
     }
 }
 
+#[cfg(feature="encoder")]
+impl<AllocU8: Allocator<u8>> DuplicateWithAllocator<AllocU8> for ByteStack<AllocU8> {
+    fn duplicate(&self, m8: &mut AllocU8) -> Self {
+        ByteStack::<AllocU8> {
+            data: duplicate_cell(m8, &self.data),
+            nbytes: self.nbytes,
+        }
+    }
+}
+
+#[cfg(feature="encoder")]
 impl<AllocU8: Allocator<u8>> ByteStack<AllocU8> {
     pub fn mov(&mut self) -> Self {
         ByteStack::<AllocU8> {
@@ -107,6 +129,7 @@ impl<AllocU8: Allocator<u8>> ByteStack<AllocU8> {
     }
 }
 
+#[cfg(feature="encoder")]
 impl<AllocU8: Allocator<u8>> ByteQueue for ByteStack<AllocU8> {
     fn num_push_bytes_avail(&self) -> usize {
         self.nbytes
@@ -133,11 +156,14 @@ impl<AllocU8: Allocator<u8>> ByteQueue for ByteStack<AllocU8> {
 type ANSState = u64;
 type StartFreqType = Prob;
 const NORMALIZATION_INTERVAL: ANSState = 1u64 << 31;
+#[cfg(feature="encoder")]
 const ENC_START_STATE: ANSState = NORMALIZATION_INTERVAL;
 
-const NUM_SYMBOLS_BEFORE_FLUSH:u32 = (MAX_BUFFER_SIZE as u32) >> 2;
+pub const NUM_SYMBOLS_BEFORE_FLUSH:u32 = (MAX_BUFFER_SIZE as u32) >> 2;
+#[cfg(feature="decoder")]
 const SCALE_MASK:u64 = ((1u64 << LOG2_SCALE) - 1);
 
+#[cfg(feature="decoder")]
 #[derive(Debug, Clone)]
 pub struct ANSDecoder {
     state_a: u64,
@@ -147,6 +173,7 @@ pub struct ANSDecoder {
     buffer_b_bytes_required: u8, // needs 8 to start with
 }
 
+#[cfg(feature="decoder")]
 impl Default for ANSDecoder {
     fn default() -> Self {
         let ret = ANSDecoder{
@@ -161,6 +188,7 @@ impl Default for ANSDecoder {
     }
 }
 
+#[cfg(feature="decoder")]
 impl<A: Allocator<u8>> NewWithAllocator<A> for ANSDecoder {
     fn new(_m8: &mut A) -> Self {
         Self::default()
@@ -169,6 +197,7 @@ impl<A: Allocator<u8>> NewWithAllocator<A> for ANSDecoder {
     }
 }
 
+#[cfg(feature="decoder")]
 impl ANSDecoder {
     fn helper_push_data_rare_cases(&mut self, data: &[u8]) -> usize{
         if self.buffer_a_bytes_required < 16 && self.buffer_a_bytes_required > 4 { // initial setup
@@ -251,10 +280,12 @@ impl ANSDecoder {
         (sym_start_freq.sym, sym_start_freq.range)
     }
 }
+#[cfg(feature="encoder")]
 pub struct ANSEncoder<AllocU8:Allocator<u8>> {
     q: ByteStack<AllocU8>,
     start_freq: ByteStack<AllocU8>,
 }
+#[cfg(feature="encoder")]
 impl<A: Allocator<u8>> NewWithAllocator<A> for ANSEncoder<A> {
     fn new(m8: &mut A) -> Self {
         let q = ByteStack::<A>::new(m8);
@@ -268,6 +299,14 @@ impl<A: Allocator<u8>> NewWithAllocator<A> for ANSEncoder<A> {
     }
 }
 
+#[cfg(feature="encoder")]
+impl<A: Allocator<u8>> DuplicateWithAllocator<A> for ANSEncoder<A> {
+    fn duplicate(&self, m8: &mut A) -> Self {
+        ANSEncoder{q:self.q.duplicate(m8), start_freq:self.start_freq.duplicate(m8)}
+    }
+}
+
+#[cfg(feature="encoder")]
 impl<AllocU8:Allocator<u8> > ANSEncoder<AllocU8> {
     fn mov_internal(&mut self) -> Self {
         let old_q = self.q.mov();
@@ -378,6 +417,7 @@ impl<AllocU8:Allocator<u8> > ANSEncoder<AllocU8> {
     }
 }
 
+#[cfg(feature="encoder")]
 impl<AllocU8: Allocator<u8>> EntropyEncoder for ANSEncoder<AllocU8> {
     type Queue = ByteStack<AllocU8>;
     #[inline(always)]
@@ -403,6 +443,7 @@ impl<AllocU8: Allocator<u8>> EntropyEncoder for ANSEncoder<AllocU8> {
         self.flush_chunk()
     }
 }
+#[cfg(feature="decoder")]
 impl ByteQueue for ANSDecoder {
     #[inline(always)]
     fn num_push_bytes_avail(&self) -> usize {
@@ -445,6 +486,7 @@ impl ByteQueue for ANSDecoder {
         0
     }    
 }
+#[cfg(feature="decoder")]
 impl EntropyDecoder for ANSDecoder {
     type Queue = Self;
     #[inline(always)]
@@ -476,10 +518,31 @@ impl EntropyDecoder for ANSDecoder {
     }
 }
 
+#[cfg(feature="encoder")]
 impl<AllocU8: Allocator<u8>> ArithmeticEncoderOrDecoder for ANSEncoder<AllocU8> {
     arithmetic_encoder_or_decoder_methods!();
+    const BATCHES_NIBBLES: bool = true;
+    // stacks as much of the batch as fits in the chunk, checking whether to flush just once at the end
+    fn get_or_put_nibble_batch_without_billing<CDF:CDF16>(&mut self,
+                                                          nibbles: &mut [u8],
+                                                          probs: &[CDF]) -> usize {
+        debug_assert!(self.q.is_empty());
+        let count = cmp::min(nibbles.len(), self.start_freq.stack_bytes_avail() >> 2);
+        for (nibble, prob) in nibbles.iter().zip(probs.iter()).take(count) {
+            let start_freq = prob.sym_to_start_and_freq(*nibble).range;
+            debug_assert!(start_freq.start >= 0 && i32::from(start_freq.start) < (1 << LOG2_SCALE));
+            debug_assert!(start_freq.freq > 0 && i32::from(start_freq.freq) < (1 << LOG2_SCALE));
+            self.start_freq.stack_u16(start_freq.freq as u16);
+            self.start_freq.stack_u16(start_freq.start as u16);
+        }
+        if self.start_freq.stack_bytes_avail() == 0 {
+            self.flush_chunk()
+        }
+        count
+    }
 }
 
+#[cfg(feature="decoder")]
 impl BillingCapability for ANSDecoder {
 }
 
@@ -0,0 +1,217 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Several named files in one file, each compressed to a divans stream of its own.
+// The envelope is ARCHIVE_MAGIC followed by one record per entry and an empty name:
+//   name length (u16, nonzero), name (utf-8), size (u64), compressed size (u64), divans stream
+// Since each record says how long its stream is, a reader lists the entries by seeking past the
+// streams and extracts any one of them without decoding the others.
+#![cfg(feature="std")]
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::string::String;
+use std::vec::Vec;
+use interface::{DivansCompressorOptions, ErrMsg};
+use error::Error;
+use reader::DivansDecompressorReader;
+use writer::DivansBrotliHybridCompressorWriter;
+use wire::{u16_from_le, u16_to_le, u64_from_le, u64_to_le};
+
+pub const ARCHIVE_MAGIC: [u8; 4] = [b'd', b'v', b'a', b'r'];
+// name length, size and compressed size
+const RECORD_HEADER_LENGTH: usize = 18;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveEntry {
+    pub name: String,
+    pub size: u64, // decompressed
+    pub compressed_size: u64,
+    pub stream_offset: u64, // of the divans stream from the start of the archive
+}
+
+pub struct ArchiveWriter<W: Write> {
+    output: W,
+    opts: DivansCompressorOptions,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    // every entry is compressed with opts
+    pub fn new(mut output: W, opts: DivansCompressorOptions) -> Result<Self, Error> {
+        output.write_all(&ARCHIVE_MAGIC[..])?;
+        Ok(ArchiveWriter {
+            output,
+            opts,
+        })
+    }
+    // Compresses what input holds as the next entry and returns its size.  The stream is built in
+    // memory first, since its length precedes it.
+    pub fn add_entry<R: Read>(&mut self, name: &str, input: &mut R) -> Result<u64, Error> {
+        if name.is_empty() || name.len() > usize::from(u16::MAX) {
+            return Err(Error::from(io::Error::new(io::ErrorKind::InvalidInput, "archive entry names take 1 to 65535 bytes")));
+        }
+        let mut stream = Vec::<u8>::new();
+        let size = {
+            let mut compressor = DivansBrotliHybridCompressorWriter::new(&mut stream, self.opts, 0);
+            let size = io::copy(input, &mut compressor)?;
+            compressor.flush()?;
+            size
+        };
+        self.output.write_all(&u16_to_le(name.len() as u16)[..])?;
+        self.output.write_all(name.as_bytes())?;
+        self.output.write_all(&u64_to_le(size)[..])?;
+        self.output.write_all(&u64_to_le(stream.len() as u64)[..])?;
+        self.output.write_all(&stream[..])?;
+        Ok(size)
+    }
+    // writes the end of the archive and hands back the output
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.output.write_all(&u16_to_le(0)[..])?;
+        self.output.flush()?;
+        Ok(self.output)
+    }
+}
+
+fn truncated() -> Error {
+    Error::from(ErrMsg::TruncatedInput).in_state("reading the archive index")
+}
+
+fn read_exact_or_truncated<R: Read>(input: &mut R, buf: &mut [u8]) -> Result<(), Error> {
+    input.read_exact(buf).map_err(|e| if e.kind() == io::ErrorKind::UnexpectedEof { truncated() } else { Error::from(e) })
+}
+
+pub struct ArchiveReader<R: Read + Seek> {
+    input: R,
+    entries: Vec<ArchiveEntry>,
+}
+
+impl<R: Read + Seek> ArchiveReader<R> {
+    // reads the record headers of every entry, seeking over the streams between them
+    pub fn new(mut input: R) -> Result<Self, Error> {
+        let mut magic = [0u8; 4];
+        read_exact_or_truncated(&mut input, &mut magic[..])?;
+        if magic != ARCHIVE_MAGIC {
+            return Err(Error::from(ErrMsg::MagicNumberWrongA(magic[0], magic[1])).in_state("reading the archive index"));
+        }
+        let end = input.seek(SeekFrom::End(0))?;
+        let mut offset = input.seek(SeekFrom::Start(ARCHIVE_MAGIC.len() as u64))?;
+        let mut entries = Vec::<ArchiveEntry>::new();
+        loop {
+            let mut name_length = [0u8; 2];
+            read_exact_or_truncated(&mut input, &mut name_length[..])?;
+            let name_length = usize::from(u16_from_le(&name_length[..]));
+            if name_length == 0 {
+                break;
+            }
+            let mut name = vec![0u8; name_length];
+            read_exact_or_truncated(&mut input, &mut name[..])?;
+            let name = String::from_utf8(name).map_err(|e| Error::from(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+            let mut sizes = [0u8; 16];
+            read_exact_or_truncated(&mut input, &mut sizes[..])?;
+            let compressed_size = u64_from_le(&sizes[8..]);
+            let stream_offset = offset + (RECORD_HEADER_LENGTH + name_length) as u64;
+            if compressed_size > end - stream_offset {
+                return Err(truncated());
+            }
+            offset = input.seek(SeekFrom::Start(stream_offset + compressed_size))?;
+            entries.push(ArchiveEntry {
+                name,
+                size: u64_from_le(&sizes[..8]),
+                compressed_size,
+                stream_offset,
+            });
+        }
+        Ok(ArchiveReader {
+            input,
+            entries,
+        })
+    }
+    pub fn entries(&self) -> &[ArchiveEntry] {
+        &self.entries[..]
+    }
+    // the index into entries() of the first entry called name
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.name == name)
+    }
+    // Decompresses entry index into output, decoding only its own stream; returns its size.
+    pub fn extract<W: Write>(&mut self, index: usize, output: &mut W) -> Result<u64, Error> {
+        let entry = match self.entries.get(index) {
+            Some(entry) => entry.clone(),
+            None => return Err(Error::from(ErrMsg::InputOffsetOutOfBounds)),
+        };
+        self.input.seek(SeekFrom::Start(entry.stream_offset))?;
+        let mut decompressor = DivansDecompressorReader::new(self.input.by_ref().take(entry.compressed_size), 0, false, false);
+        let size = io::copy(&mut decompressor, output)
+            .map_err(|e| Error::from(e).in_state("extracting").shifted(entry.stream_offset, 0))?;
+        if size != entry.size {
+            return Err(Error::from(ErrMsg::ArchiveSizeMismatch).in_state("extracting"));
+        }
+        Ok(size)
+    }
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::vec::Vec;
+    use conformance::{conformance_commands, conformance_expected_output};
+    use super::{ArchiveReader, ArchiveWriter, ARCHIVE_MAGIC};
+    use std::io::Cursor;
+    use interface::{
+        DivansCompressorOptions,
+    };
+    use test_support::decode;
+
+    #[test]
+    fn test_archive() {
+        let files: [(&str, Vec<u8>); 3] = [
+            ("mixed", conformance_expected_output(&conformance_commands("mixed")[..]).unwrap()),
+            ("empty", Vec::new()),
+            ("dir/dict", conformance_expected_output(&conformance_commands("dict")[..]).unwrap()),
+        ];
+        let mut writer = ArchiveWriter::new(Vec::<u8>::new(), DivansCompressorOptions::default()).unwrap();
+        for &(name, ref data) in files.iter() {
+            assert_eq!(writer.add_entry(name, &mut &data[..]).unwrap(), data.len() as u64);
+        }
+        assert!(writer.add_entry("", &mut &b"unnamed"[..]).is_err());
+        let archive = writer.finish().unwrap();
+        assert_eq!(archive[..4], ARCHIVE_MAGIC);
+        let mut reader = ArchiveReader::new(Cursor::new(&archive[..])).unwrap();
+        let names: Vec<&str> = reader.entries().iter().map(|entry| &entry.name[..]).collect();
+        assert_eq!(names, ["mixed", "empty", "dir/dict"]);
+        // each stream is a divans stream of its own
+        let last = reader.entries()[2].clone();
+        let stream = &archive[last.stream_offset as usize..(last.stream_offset + last.compressed_size) as usize];
+        assert_eq!(decode(stream).unwrap(), files[2].1);
+        // entries come out in any order
+        for index in [2usize, 0, 1].iter() {
+            let mut output = Vec::<u8>::new();
+            assert_eq!(reader.extract(*index, &mut output).unwrap(), files[*index].1.len() as u64);
+            assert_eq!(output, files[*index].1);
+        }
+        assert_eq!(reader.find("dir/dict"), Some(2));
+        assert_eq!(reader.find("dict"), None);
+        assert!(reader.extract(3, &mut Vec::<u8>::new()).is_err());
+        // a cut short archive fails to list rather than listing a partial entry
+        assert!(ArchiveReader::new(Cursor::new(&archive[..archive.len() - 3])).is_err());
+        assert!(ArchiveReader::new(Cursor::new(&archive[1..])).is_err());
+        // a record claiming the wrong size is caught on extraction
+        let mut resized = archive.clone();
+        let size_offset = 4 + 2 + "mixed".len();
+        resized[size_offset] ^= 1;
+        let mut reader = ArchiveReader::new(Cursor::new(&resized[..])).unwrap();
+        assert!(reader.extract(0, &mut Vec::<u8>::new()).is_err());
+    }
+}
@@ -81,6 +81,7 @@ impl ByteQueue for RegisterQueue {
 }
 
 
+#[cfg(feature="encoder")]
 pub trait EntropyEncoder {
     type Queue:ByteQueue;
     // if it's a register, should have a get and a set and pass by value and clobber?
@@ -216,6 +217,7 @@ impl<Decoder:EntropyDecoder+Clone> ArithmeticEncoderOrDecoder for Decoder {
     }
 }
 
+#[cfg(feature="encoder")]
 macro_rules! arithmetic_encoder_or_decoder_methods(
     () => {
             #[inline(always)]
@@ -256,6 +258,7 @@ macro_rules! arithmetic_encoder_or_decoder_methods(
 );
 
 #[cfg(test)]
+#[cfg(all(feature="encoder", feature="decoder"))]
 mod test {
     extern crate std;
     use core::fmt::{Debug, Error};
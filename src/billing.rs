@@ -17,7 +17,7 @@ use core::mem;
 use core::iter::FromIterator;
 use core::marker::PhantomData;
 use alloc::{Allocator};
-use interface::{ArithmeticEncoderOrDecoder, BillingDesignation, NewWithAllocator, BillingCapability};
+use interface::{ArithmeticEncoderOrDecoder, BillingDesignation, NewWithAllocator, DuplicateWithAllocator, BillingCapability};
 use super::probability::{CDF16, ProbRange};
 use interface::{DivansResult, ReadableBytes, WritableBytes};
 
@@ -37,6 +37,33 @@ macro_rules! println_stderr(
     } }
 );
 
+// What a coder spent on one designation, named by its Debug form since BillingDesignation holds the
+// substates of every part of the codec
+#[cfg(feature="billing")]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+pub struct BillingEntry {
+    pub designation: billing::String,
+    pub bits: f64,
+    pub virtual_bits: f64,
+}
+
+// The bill of a coder, sorted by designation, as printed when the coder is dropped
+#[cfg(feature="billing")]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+pub struct BillingReport {
+    pub entries: billing::Vec<BillingEntry>,
+}
+
+#[cfg(feature="billing")]
+impl BillingReport {
+    // the (bits, virtual bits) pair over every entry
+    pub fn total(&self) -> (f64, f64) {
+        self.entries.iter().fold((0.0, 0.0), |(bits, vbits), entry| (bits + entry.bits, vbits + entry.virtual_bits))
+    }
+}
+
 #[cfg(feature="billing")]
 pub struct BillingArithmeticCoder<AllocU8:Allocator<u8>, Coder:ArithmeticEncoderOrDecoder> {
     coder: Coder,
@@ -61,6 +88,19 @@ impl<AllocU8:Allocator<u8>,
     }
 }
 
+#[cfg(feature="billing")]
+impl<AllocU8:Allocator<u8>,
+     Coder:ArithmeticEncoderOrDecoder+DuplicateWithAllocator<AllocU8>> DuplicateWithAllocator<AllocU8> for BillingArithmeticCoder<AllocU8, Coder> {
+    fn duplicate(&self, m8: &mut AllocU8) -> Self {
+        BillingArithmeticCoder::<AllocU8, Coder>{
+            coder: self.coder.duplicate(m8),
+            counter: billing::HashMap::new(), // a trial encode starts its own bill
+            movd: true, // and never prints it
+            _phantom:PhantomData::<AllocU8>::default(),
+        }
+    }
+}
+
 #[cfg(feature="billing")]
 impl<AllocU8:Allocator<u8>, Coder:ArithmeticEncoderOrDecoder> BillingArithmeticCoder<AllocU8, Coder> {
     // Return the (bits, virtual bits) pair.
@@ -73,7 +113,16 @@ impl<AllocU8:Allocator<u8>, Coder:ArithmeticEncoderOrDecoder> BillingArithmeticC
         }
         (total_bits, total_vbits)
     }
-    pub fn print_compression_ratio(&self, original_bytes : usize) {
+    pub fn report(&self) -> BillingReport {
+        let mut entries: billing::Vec<BillingEntry> = self.counter.iter().map(|(k, v)| BillingEntry {
+            designation: format!("{:?}", k),
+            bits: v.0,
+            virtual_bits: v.1,
+        }).collect();
+        entries.sort_by(|a, b| a.designation.cmp(&b.designation));
+        BillingReport{entries: entries}
+    }
+    pub fn print_compression_ratio(&self, original_bytes : u64) {
         let (total_bits, _) = self.get_total();
         println_stderr!("{:.2}/{:}  Ratio {:.3}%",
                         total_bits / 8.0, original_bytes, total_bits * 100.0 / 8.0 / (original_bytes as f64));
@@ -86,23 +135,16 @@ impl<AllocU8:Allocator<u8>, Coder:ArithmeticEncoderOrDecoder> Drop for BillingAr
         if self.movd {
             return;
         }
-        let max_key_len = self.counter.keys().map(|k| format!("{:?}", k).len()).max().unwrap_or(5);
-        let report = |k, v: (f64, f64)| {
+        let bill = self.report();
+        let max_key_len = bill.entries.iter().map(|entry| entry.designation.len()).max().unwrap_or(5);
+        let report = |k: &str, v: (f64, f64)| {
             println_stderr!("{1:0$} Bit count: {2:9.1} Byte count: {3:11.3} Virtual bits: {4:7.0}",
                             max_key_len, k, v.0, v.0 / 8.0, v.1);
         };
-        let mut sorted_entries = billing::Vec::from_iter(self.counter.iter());
-        sorted_entries.sort_by_key(|&(k, _)| format!("{:?}", k));
-
-        let mut total_bits : f64 = 0.0;
-        let mut total_vbits : f64 = 0.0;
-
-        for (k, v) in sorted_entries {
-            report(format!("{:?}", k), *v);
-            total_bits += v.0;
-            total_vbits += v.1;
+        for entry in bill.entries.iter() {
+            report(&entry.designation, (entry.bits, entry.virtual_bits));
         }
-        report(billing::String::from("Total"), (total_bits, total_vbits));
+        report("Total", bill.total());
     }
 }
 
@@ -162,6 +204,25 @@ impl<AllocU8:Allocator<u8>, Coder:ArithmeticEncoderOrDecoder> ArithmeticEncoderO
         (*v).1 += 4.0;
         ret
     }
+    const BATCHES_NIBBLES: bool = Coder::BATCHES_NIBBLES;
+    fn get_or_put_nibble_batch_without_billing<C: CDF16>(&mut self,
+                                                         nibbles: &mut [u8],
+                                                         probs: &[C]) -> usize {
+        self.get_or_put_nibble_batch(nibbles, probs, BillingDesignation::Unknown)
+    }
+    fn get_or_put_nibble_batch<C: CDF16>(&mut self,
+                                         nibbles: &mut [u8],
+                                         probs: &[C],
+                                         billing: BillingDesignation) -> usize {
+        let count = self.coder.get_or_put_nibble_batch_without_billing(nibbles, probs);
+        let v = self.counter.entry(billing).or_insert((0.0, 0.0));
+        for (nibble, prob) in nibbles[..count].iter().zip(probs.iter()) {
+            let actual_prob = prob.pdf(*nibble) as f64 / (prob.max() as f64);
+            (*v).0 += -actual_prob.log2();
+            (*v).1 += 4.0;
+        }
+        count
+    }
     fn close(&mut self) -> DivansResult {
         self.coder.close()
     }
@@ -170,7 +231,7 @@ impl<AllocU8:Allocator<u8>, Coder:ArithmeticEncoderOrDecoder> ArithmeticEncoderO
 // only need to implement this for feature=billing, since it's defined for any T in the default case
 #[cfg(feature="billing")]
 impl<AllocU8:Allocator<u8>, Coder:ArithmeticEncoderOrDecoder> BillingCapability for BillingArithmeticCoder<AllocU8, Coder> {
-    fn debug_print(&self, byte_size: usize) {
+    fn debug_print(&self, byte_size: u64) {
         self.print_compression_ratio(byte_size);
     }
 }
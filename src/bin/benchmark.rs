@@ -271,7 +271,7 @@ fn bench_with_ir<Run: Runner,
     opts.literal_adaptation = Some([Speed::MUD,Speed::SLOW, Speed::GLACIAL, Speed::GEOLOGIC]);
     super::compress_ir(&mut buf_ir,
                        &mut dv_buffer,
-                       opts).unwrap();
+                       opts, false).unwrap();
     {
         let mut decompress_lambda = || {
             dv_buffer.reset_read();
@@ -595,3 +595,80 @@ fn bench_e2e_roundtrip_simple_100k(b: &mut Bencher) {
                 &mut BenchmarkPassthrough(b));
 
 }
+
+// compresses with brotli and divans, from generated input rather than a corpus
+fn bench_synthetic<Run: Runner>(kind: divans::synthetic::Synthetic,
+                                size: usize,
+                                ratio: f64,
+                                measure_compress: bool,
+                                runner: &mut Run) {
+    let input = kind.generate(1, size);
+    let mut dv_buffer = Vec::<u8>::new();
+    let mut rt_buffer = Vec::<u8>::new();
+    let mut compress_or_decompress_lambda = |compress:bool| {
+        if compress {
+            dv_buffer.clear();
+            super::compress_raw(&mut &input[..],
+                                &mut dv_buffer,
+                                divans::DivansCompressorOptions::default(),
+                                65536,
+                                true,
+                                false,
+                                false).unwrap();
+        } else {
+            rt_buffer.clear();
+            super::decompress(&mut &dv_buffer[..], &mut rt_buffer, 65536, &mut[], false, MULTI).unwrap();
+            assert_eq!(rt_buffer, input);
+            let actual_ratio = dv_buffer.len() as f64 / input.len() as f64;
+            assert!(actual_ratio <= ratio, "{} > {}", actual_ratio, ratio);
+        }
+    };
+    if !measure_compress {
+        compress_or_decompress_lambda(true);
+    }
+    runner.iter(&mut || {
+        if measure_compress {
+            compress_or_decompress_lambda(true);
+        }
+        compress_or_decompress_lambda(false);
+    });
+}
+
+#[test]
+fn test_synthetic_roundtrip() {
+    bench_synthetic(divans::synthetic::Synthetic::Mixed,
+                    1024 * 1024 / 4,
+                    0.6,
+                    true,
+                    &mut Passthrough{});
+}
+
+#[cfg(feature="benchmark")]
+#[bench]
+fn bench_e2e_decode_synthetic_text_1024k(b: &mut Bencher) {
+    bench_synthetic(divans::synthetic::Synthetic::Text{vocabulary: 2000},
+                    1024 * 1024,
+                    0.5,
+                    false,
+                    &mut BenchmarkPassthrough(b));
+}
+
+#[cfg(feature="benchmark")]
+#[bench]
+fn bench_e2e_decode_synthetic_records_1024k(b: &mut Bencher) {
+    bench_synthetic(divans::synthetic::Synthetic::Records{stride: 24},
+                    1024 * 1024,
+                    0.5,
+                    false,
+                    &mut BenchmarkPassthrough(b));
+}
+
+#[cfg(feature="benchmark")]
+#[bench]
+fn bench_e2e_roundtrip_synthetic_mixed_1024k(b: &mut Bencher) {
+    bench_synthetic(divans::synthetic::Synthetic::Mixed,
+                    1024 * 1024,
+                    0.6,
+                    true,
+                    &mut BenchmarkPassthrough(b));
+}
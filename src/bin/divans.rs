@@ -76,12 +76,8 @@ fn is_divans(header:&[u8]) -> bool {
     if header.len() < divans::MAGIC_NUMBER.len() {
         return false;
     }
-    for (a, b) in header[..divans::MAGIC_NUMBER.len()].iter().zip(divans::MAGIC_NUMBER[..].iter()) {
-        if a != b {
-            return false;
-        }
-    }
-    return true;
+    header[..divans::MAGIC_NUMBER.len()] == divans::MAGIC_NUMBER ||
+        header[..divans::MAGIC_NUMBER.len()] == divans::interface::MAGIC_NUMBER_EXTENDED
 }
 
 use std::path::Path;
@@ -323,7 +319,7 @@ fn command_parse(s : &str) -> Result<Option<Command<ItemVec<u8>>>, io::Error> {
                                       "*type needs 1 argument"));
         }
         let block_type = match command_vec[1].parse::<u32>() {
-            Ok(el) => el as u8,
+            Ok(el) => el,
             Err(msg) => {
                 return Err(io::Error::new(io::ErrorKind::InvalidInput,
                                           msg.description()));
@@ -622,7 +618,8 @@ fn compress_inner<Reader:std::io::BufRead,
                                 AllocU32,
                                 AllocCDF16>,
     r:&mut Reader,
-    w:&mut Writer) -> io::Result<()> {
+    w:&mut Writer,
+    peephole: bool) -> io::Result<()> {
     let mut buffer = String::new();
     let mut obuffer = vec![0u8; 65_536];
     let mut ibuffer:[Command<ItemVec<u8>>; CMD_BUFFER_SIZE] = [Command::<ItemVec<u8>>::nop(),
@@ -645,6 +642,11 @@ fn compress_inner<Reader:std::io::BufRead,
     let mut i_read_index = 0usize;
     let mut last_literal_switch = LiteralBlockSwitch::new(0, 0);
     let mut m8 = ItemVecAllocator::<u8>::default();
+    let mut optimizer = if peephole {
+        Some(divans::IrPeephole::new(&mut m8))
+    } else {
+        None
+    };
     loop {
         buffer.clear();
         match r.read_line(&mut buffer) {
@@ -656,6 +658,9 @@ fn compress_inner<Reader:std::io::BufRead,
             },
             Ok(count) => {
                 if i_read_index == ibuffer.len() || count == 0 {
+                    if let Some(ref mut optimizer) = optimizer {
+                        i_read_index = optimizer.optimize(&mut m8, &mut ibuffer[..i_read_index]);
+                    }
                     try!(recode_cmd_buffer(&mut state, ibuffer.split_at(i_read_index).0, w,
                                                &mut obuffer[..]));
 
@@ -705,6 +710,9 @@ fn compress_inner<Reader:std::io::BufRead,
             }
         }
     }
+    if let Some(mut optimizer) = optimizer {
+        optimizer.free(&mut m8);
+    }
     Ok(())
 }
 fn compress_raw_inner<Compressor: divans::interface::Compressor,
@@ -947,6 +955,7 @@ fn compress_ir<Reader:std::io::BufRead,
     r:&mut Reader,
     w:&mut Writer,
     mut opts: divans::DivansCompressorOptions,
+    peephole: bool,
 ) -> io::Result<()> {
     let window_size : i32;
     let mut buffer = String::new();
@@ -974,7 +983,7 @@ fn compress_ir<Reader:std::io::BufRead,
         opts,
         (),
     );
-    compress_inner(state, r, w)
+    compress_inner(state, r, w, peephole)
 }
 
 fn decompress<Reader:std::io::Read, Writer:std::io::Write>(r:&mut Reader,
@@ -1003,6 +1012,354 @@ fn decompress<Reader:std::io::Read, Writer:std::io::Write>(r:&mut Reader,
     ret
 }
 
+// decompresses only streams tagged under key, always checking the crc
+fn decompress_authenticated<Reader:std::io::Read, Writer:std::io::Write>(r:&mut Reader,
+                                                                         w:&mut Writer,
+                                                                         buffer_size: usize,
+                                                                         key: [u8; divans::interface::AUTHENTICATION_KEY_LENGTH],
+                                                                         multithread:bool) -> io::Result<()> {
+    let mut state = DivansDecompressorFactoryStruct::<ItemVecAllocator<u8>, ItemVecAllocator<divans::DefaultCDF16>, ItemVecAllocator<StaticCommand>>::new_authenticated(
+        ItemVecAllocator::<u8>::default(),
+        ItemVecAllocator::<divans::DefaultCDF16>::default(),
+        ItemVecAllocator::<StaticCommand>::default(),
+        key,
+        multithread,
+    );
+    let ret = decompress_generic(r, w, &mut state, &mut [], buffer_size);
+    state.free();
+    ret
+}
+
+fn parse_authentication_key(hex: &str) -> [u8; divans::interface::AUTHENTICATION_KEY_LENGTH] {
+    let mut key = [0u8; divans::interface::AUTHENTICATION_KEY_LENGTH];
+    if hex.len() != 2 * key.len() {
+        panic!("-key= takes {} hex digits", 2 * key.len());
+    }
+    for (index, byte) in key.iter_mut().enumerate() {
+        *byte = match u8::from_str_radix(&hex[2 * index..2 * index + 2], 16) {
+            Ok(b) => b,
+            Err(e) => panic!("-key= takes {} hex digits: {:?}", 2 * divans::interface::AUTHENTICATION_KEY_LENGTH, e),
+        };
+    }
+    key
+}
+
+// decompresses like decompress, then writes the codec state machine trace, even if decoding failed
+#[cfg(feature="trace")]
+fn decompress_with_trace<Reader:std::io::Read, Writer:std::io::Write>(r:&mut Reader,
+                                                                      w:&mut Writer,
+                                                                      buffer_size: usize,
+                                                                      skip_crc: bool,
+                                                                      multithread:bool,
+                                                                      trace_filename: &str) -> io::Result<()> {
+    let mut state = DivansDecompressorFactoryStruct::<ItemVecAllocator<u8>, ItemVecAllocator<divans::DefaultCDF16>, ItemVecAllocator<StaticCommand>>::new(
+        ItemVecAllocator::<u8>::default(),
+        ItemVecAllocator::<divans::DefaultCDF16>::default(),
+        ItemVecAllocator::<StaticCommand>::default(),
+        skip_crc,
+        multithread,
+    );
+    let ret = decompress_generic(r, w, &mut state, &mut [], buffer_size);
+    let trace = state.take_trace();
+    state.free();
+    let mut trace_file = File::create(&Path::new(trace_filename))?;
+    trace_file.write_all(&trace[..])?;
+    ret
+}
+
+#[cfg(not(feature="trace"))]
+fn decompress_with_trace<Reader:std::io::Read, Writer:std::io::Write>(_r:&mut Reader,
+                                                                      _w:&mut Writer,
+                                                                      _buffer_size: usize,
+                                                                      _skip_crc: bool,
+                                                                      _multithread:bool,
+                                                                      _trace_filename: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "divans was built without the trace feature"))
+}
+
+// forwards decode calls, letting observe look at the decompressor after each one
+#[cfg(feature="internals")]
+struct ObservedDecompressor<'a, D:Decompressor+'a, F:FnMut(&mut D)> {
+    inner: &'a mut D,
+    observe: F,
+}
+
+#[cfg(feature="internals")]
+impl<'a, D:Decompressor+'a, F:FnMut(&mut D)> ObservedDecompressor<'a, D, F> {
+    fn new(inner: &'a mut D, observe: F) -> Self {
+        ObservedDecompressor {
+            inner,
+            observe,
+        }
+    }
+}
+
+#[cfg(feature="internals")]
+impl<'a, D:Decompressor+'a, F:FnMut(&mut D)> Decompressor for ObservedDecompressor<'a, D, F> {
+    fn decode(&mut self,
+              input:&[u8],
+              input_offset : &mut usize,
+              output :&mut[u8],
+              output_offset: &mut usize) -> DivansResult {
+        let ret = self.inner.decode(input, input_offset, output, output_offset);
+        (self.observe)(self.inner);
+        ret
+    }
+}
+
+// decompresses like decompress, then writes one line per literal block with the mixer weights it ended on
+#[cfg(feature="internals")]
+fn decompress_with_mixer_weights<Reader:std::io::Read, Writer:std::io::Write>(r:&mut Reader,
+                                                                              w:&mut Writer,
+                                                                              buffer_size: usize,
+                                                                              skip_crc: bool,
+                                                                              multithread:bool,
+                                                                              weights_filename: &str) -> io::Result<()> {
+    let mut state = DivansDecompressorFactoryStruct::<ItemVecAllocator<u8>, ItemVecAllocator<divans::DefaultCDF16>, ItemVecAllocator<StaticCommand>>::new(
+        ItemVecAllocator::<u8>::default(),
+        ItemVecAllocator::<divans::DefaultCDF16>::default(),
+        ItemVecAllocator::<StaticCommand>::default(),
+        skip_crc,
+        multithread,
+    );
+    let mut snapshots = Vec::<divans::MixerSnapshot>::new();
+    let ret = {
+        let mut observed = ObservedDecompressor::new(&mut state, |decompressor| {
+            let mut batch = [divans::MixerSnapshot::default(); divans::MIXER_SNAPSHOT_QUEUE_SIZE];
+            let count = decompressor.take_mixer_snapshots(&mut batch[..]);
+            snapshots.extend_from_slice(&batch[..count]);
+        });
+        decompress_generic(r, w, &mut observed, &mut [], buffer_size)
+    };
+    snapshots.push(state.mixer_snapshot()); // the last block never sees a block switch
+    let dropped = state.mixer_snapshots_dropped();
+    state.free();
+    let mut weights_file = File::create(&Path::new(weights_filename))?;
+    writeln!(weights_file, "# block_index block_type mixing_param low_weight0 low_weight1 high_weight0 high_weight1 low_norm high_norm")?;
+    for snapshot in snapshots.iter() {
+        writeln!(weights_file, "{} {} {} {} {} {} {} {} {}",
+                 snapshot.block_index, snapshot.block_type, snapshot.mixing_param,
+                 snapshot.model_weights[0][0], snapshot.model_weights[0][1],
+                 snapshot.model_weights[1][0], snapshot.model_weights[1][1],
+                 snapshot.norm_weights[0], snapshot.norm_weights[1])?;
+    }
+    if dropped != 0 {
+        writeln!(weights_file, "# {} blocks dropped", dropped)?;
+    }
+    ret
+}
+
+#[cfg(not(feature="internals"))]
+fn decompress_with_mixer_weights<Reader:std::io::Read, Writer:std::io::Write>(_r:&mut Reader,
+                                                                              _w:&mut Writer,
+                                                                              _buffer_size: usize,
+                                                                              _skip_crc: bool,
+                                                                              _multithread:bool,
+                                                                              _weights_filename: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "divans was built without the internals feature"))
+}
+
+// prints one line per recorded state transition of a trace written by -trace=
+#[cfg(feature="internals")]
+fn trace_explain<Writer:std::io::Write>(data: &[u8], w: &mut Writer) -> io::Result<()> {
+    let mut reader = match divans::trace::TraceReader::new(data) {
+        Some(reader) => reader,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "not a divans trace")),
+    };
+    writeln!(w, "{:>10} {:>12} {:>12}  state(arg)", "seq", "input", "output")?;
+    let mut count = 0usize;
+    for rec in reader.by_ref() {
+        writeln!(w, "{:>10} {:>12} {:>12}  {}({})",
+                 rec.seq, rec.input_offset, rec.output_offset, divans::trace::tag_name(rec.tag), rec.arg)?;
+        count += 1;
+    }
+    if reader.is_truncated() {
+        writeln!(w, "trace truncated after {} records", count)?;
+    } else {
+        writeln!(w, "{} records", count)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature="internals"))]
+fn trace_explain<Writer:std::io::Write>(_data: &[u8], _w: &mut Writer) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "divans was built without the internals feature"))
+}
+
+// prints one line per region of an entropy map, with a bar a column wide per quarter bit per byte
+fn entropy_map_render<Writer:std::io::Write>(regions: &[divans::EntropyRegion], w: &mut Writer) -> io::Result<()> {
+    writeln!(w, "{:>12} {:>12} {:>9}", "start", "end", "bits/byte")?;
+    let mut bits = 0.0f64;
+    for region in regions.iter() {
+        let width = core::cmp::min(32, (region.bits_per_byte() * 4.0).round() as usize);
+        writeln!(w, "{:>12} {:>12} {:>9.3}  |{:<32}|", region.start, region.end, region.bits_per_byte(),
+                 "#".repeat(width))?;
+        bits += region.bits;
+    }
+    let len = regions.last().map(|region| region.end).unwrap_or(0);
+    writeln!(w, "{} bytes priced at {:.0} bytes, {:.3} bits/byte", len, bits / 8.0,
+             if len == 0 { 0.0 } else { bits / len as f64 })?;
+    Ok(())
+}
+
+// divans inspect --entropy-map [-region=N] [compressed_file]
+fn inspect_command(args: &[String]) -> io::Result<()> {
+    use std::io::Read;
+    let mut region_size = 65536u64;
+    let mut filename: Option<&String> = None;
+    let mut entropy_map = false;
+    for argument in args.iter() {
+        if argument == "--entropy-map" {
+            entropy_map = true;
+        } else if argument.starts_with("-region=") {
+            region_size = match argument.trim_start_matches("-region=").parse::<u64>() {
+                Ok(size) if size != 0 => size,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "-region= takes a positive byte count")),
+            };
+        } else if filename.is_none() {
+            filename = Some(argument);
+        } else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unexpected argument {}", argument)));
+        }
+    }
+    if !entropy_map {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "usage: inspect --entropy-map [-region=N] [compressed_file]"));
+    }
+    let mut data = Vec::<u8>::new();
+    match filename {
+        Some(name) => File::open(&Path::new(name))?.read_to_end(&mut data)?,
+        None => io::stdin().read_to_end(&mut data)?,
+    };
+    let regions = divans::entropy_map(&data[..], None, region_size)?;
+    entropy_map_render(&regions[..], &mut io::stdout())
+}
+
+// divans calibrate [raw_input_file]: prints each candidate's size and time on the sample, then the
+// candidate recommended for each speed class
+fn calibrate_command(args: &[String]) -> io::Result<()> {
+    use std::io::Read;
+    if args.len() > 1 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "usage: calibrate [raw_input_file]"));
+    }
+    let mut data = Vec::<u8>::new();
+    match args.first() {
+        Some(name) => File::open(&Path::new(name))?.read_to_end(&mut data)?,
+        None => io::stdin().read_to_end(&mut data)?,
+    };
+    let recommended = match divans::calibrate(&data[..]) {
+        Ok(recommended) => recommended,
+        Err(e) => return Err(io::Error::new(io::ErrorKind::Other, format!("{}", e))),
+    };
+    let mut w = io::stdout();
+    writeln!(w, "{:<24} {:>12} {:>9} {:>12}", "candidate", "bytes", "ratio", "ns/byte")?;
+    let sample_size = core::cmp::max(recommended.sample_size, 1) as f64;
+    for m in recommended.measurements.iter() {
+        writeln!(w, "{:<24} {:>12} {:>9.3} {:>12.1}", m.candidate.name, m.compressed_size,
+                 sample_size / core::cmp::max(m.compressed_size, 1) as f64, m.elapsed_ns as f64 / sample_size)?;
+    }
+    for &(label, class) in [("fast", divans::SpeedClass::Fast),
+                            ("balanced", divans::SpeedClass::Balanced),
+                            ("max", divans::SpeedClass::Max)].iter() {
+        writeln!(w, "{}: {}", label, recommended.measurement(class).candidate.name)?;
+    }
+    Ok(())
+}
+
+// divans verify compressed_file...: a line per file, ok with its sizes or the first thing wrong with it;
+// Ok(false) if anything was
+fn verify_command(args: &[String]) -> io::Result<bool> {
+    let mut w = io::stdout();
+    let mut all_ok = true;
+    for name in args.iter() {
+        let report = divans::verify(name);
+        match report.error {
+            None => writeln!(w, "{}: ok, {} bytes decode to {}", name, report.input_bytes, report.output_bytes)?,
+            Some(ref e) => {
+                all_ok = false;
+                writeln!(w, "{}: {}", name, e)?
+            },
+        }
+    }
+    Ok(all_ok)
+}
+
+// writes [name].dv and the expected [name].raw for every generated conformance vector into dir
+fn write_conformance_vectors(dir: &Path) -> io::Result<usize> {
+    let vectors = match divans::conformance::generate_conformance_vectors() {
+        Ok(vectors) => vectors,
+        Err(e) => return Err(io::Error::new(io::ErrorKind::Other, format!("{:?}", e))),
+    };
+    std::fs::create_dir_all(dir)?;
+    for vector in vectors.iter() {
+        File::create(dir.join(format!("{}.dv", vector.name)))?.write_all(&vector.compressed[..])?;
+        File::create(dir.join(format!("{}.raw", vector.name)))?.write_all(&vector.expected[..])?;
+    }
+    Ok(vectors.len())
+}
+
+// adds the files under dir to archive as paths relative to root, with / between components
+fn archive_add_dir<W:std::io::Write>(archive: &mut divans::ArchiveWriter<W>, root: &Path, dir: &Path) -> io::Result<usize> {
+    let mut paths = std::fs::read_dir(dir)?.map(|entry| entry.map(|entry| entry.path())).collect::<io::Result<Vec<_>>>()?;
+    paths.sort();
+    let mut count = 0usize;
+    for path in paths.iter() {
+        if path.is_dir() {
+            count += archive_add_dir(archive, root, path)?;
+            continue;
+        }
+        let name = path.strip_prefix(root).unwrap().components().map(
+            |component| component.as_os_str().to_string_lossy().into_owned()).collect::<Vec<String>>().join("/");
+        archive.add_entry(&name, &mut File::open(path)?)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// divans archive create DIR OUT | list ARCHIVE | extract ARCHIVE DIR [NAME...]
+fn archive_command(args: &[String]) -> io::Result<()> {
+    match (args[0].as_str(), args.len()) {
+        ("create", 3) => {
+            let mut archive = divans::ArchiveWriter::new(io::BufWriter::new(File::create(&args[2])?),
+                                                         divans::interface::DivansCompressorOptions::default())?;
+            let count = archive_add_dir(&mut archive, Path::new(&args[1]), Path::new(&args[1]))?;
+            archive.finish()?;
+            println_stderr!("Archived {} files into {}", count, args[2]);
+            Ok(())
+        },
+        ("list", 2) => {
+            let archive = divans::ArchiveReader::new(BufReader::new(File::open(&args[1])?))?;
+            for entry in archive.entries().iter() {
+                println!("{:>12} {:>12}  {}", entry.size, entry.compressed_size, entry.name);
+            }
+            Ok(())
+        },
+        ("extract", len) if len >= 3 => {
+            let mut archive = divans::ArchiveReader::new(BufReader::new(File::open(&args[1])?))?;
+            let dir = Path::new(&args[2]);
+            for index in 0..archive.entries().len() {
+                let name = archive.entries()[index].name.clone();
+                if len > 3 && !args[3..].contains(&name) {
+                    continue;
+                }
+                let relative = Path::new(&name);
+                if relative.components().any(|component| !matches!(component, std::path::Component::Normal(_))) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("refusing to extract {}", name)));
+                }
+                let path = dir.join(relative);
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let mut output = io::BufWriter::new(File::create(&path)?);
+                archive.extract(index, &mut output)?;
+                output.flush()?;
+            }
+            Ok(())
+        },
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                "usage: archive create DIR OUT | list ARCHIVE | extract ARCHIVE DIR [NAME...]")),
+    }
+}
 
 #[allow(unused_assignments)]
 fn decompress_generic<Reader:std::io::Read,
@@ -1201,6 +1558,17 @@ fn main() {
     let mut raw_compress = true;
     let mut q9_5 = false;
     let mut divans_ir_optimizer = false;
+    let mut literal_prescan = false;
+    let mut ir_peephole = false;
+    let mut compact_priors = true;
+    let mut format_version: Option<divans::interface::FormatVersion> = None;
+    let mut content_hint = divans::interface::ContentHint::Unknown;
+    let mut freeze_adaptation_after: Option<u32> = None;
+    let mut priors_reset_interval: Option<u32> = None;
+    let mut checksum = divans::ChecksumType::Crc32c;
+    let mut stream_features = divans::interface::STREAM_FEATURE_COMMAND_TYPE_TREE | divans::interface::STREAM_FEATURE_CONTEXT_MAP_RLE;
+    let mut extended_stream_features = 0u8;
+    let mut extended_stream_features2 = 0u8;
     let mut do_recode = false;
     let mut filenames = [std::string::String::new(), std::string::String::new()];
     let mut num_benchmarks = 1;
@@ -1216,6 +1584,11 @@ fn main() {
     let mut dynamic_context_mixing: Option<u8> = Some(1);
     let mut buffer_size:usize = 65_536;
     let mut force_prior_depth: Option<u8> = None;
+    let mut distance_postfix_bits = 0u8;
+    let mut num_direct_distances = 0u8;
+    let mut mixer_warm_start: Option<divans::MixerWarmStart> = None;
+    let mut frame_alignment: Option<u32> = None;
+    let mut authentication_key: Option<[u8; divans::interface::AUTHENTICATION_KEY_LENGTH]> = None;
     let mut set_low = false;
     let mut brotli_literal_byte_score: Option<u32> = None;
     let mut doubledash = false;
@@ -1223,9 +1596,73 @@ fn main() {
     let mut force_literal_context_mode:Option<LiteralPredictionModeNibble> = None;
     let mut skip_crc = false;
     let mut parallel = PARALLEL_AVAILABLE;
+    let mut trace_filename: Option<String> = None;
+    let mut mixer_weights_filename: Option<String> = None;
     {
+        let args: Vec<String> = env::args().collect();
+        if args.len() >= 3 && args[1] == "trace" && args[2] == "explain" {
+            use std::io::Read;
+            let mut data = Vec::<u8>::new();
+            let read_result = if args.len() > 3 {
+                match File::open(&Path::new(&args[3])) {
+                    Err(why) => panic!("couldn't open {:}\n{:}", args[3], why),
+                    Ok(mut file) => file.read_to_end(&mut data),
+                }
+            } else {
+                io::stdin().read_to_end(&mut data)
+            };
+            if let Err(e) = read_result {
+                panic!("Error {:?}", e);
+            }
+            match trace_explain(&data[..], &mut io::stdout()) {
+                Ok(_) => return,
+                Err(e) => panic!("Error {:?}", e),
+            }
+        }
+        if args.len() >= 3 && args[1] == "inspect" {
+            match inspect_command(&args[2..]) {
+                Ok(_) => return,
+                Err(e) => panic!("Error {:?}", e),
+            }
+        }
+        if args.len() >= 2 && args[1] == "calibrate" {
+            match calibrate_command(&args[2..]) {
+                Ok(_) => return,
+                Err(e) => panic!("Error {:?}", e),
+            }
+        }
+        if args.len() >= 3 && args[1] == "archive" {
+            match archive_command(&args[2..]) {
+                Ok(_) => return,
+                Err(e) => panic!("Error {:?}", e),
+            }
+        }
+        if args.len() >= 3 && args[1] == "verify" {
+            match verify_command(&args[2..]) {
+                Ok(true) => return,
+                Ok(false) => std::process::exit(1),
+                Err(e) => panic!("Error {:?}", e),
+            }
+        }
+        if args.len() == 3 && args[1] == "conformance" {
+            match write_conformance_vectors(&Path::new(&args[2])) {
+                Ok(count) => {
+                    println_stderr!("Wrote {} conformance vectors to {}", count, args[2]);
+                    return;
+                },
+                Err(e) => panic!("Error {:?}", e),
+            }
+        }
         for argument in env::args().skip(1) {
             if !doubledash {
+                if argument.starts_with("-trace=") {
+                    trace_filename = Some(argument.trim_start_matches("-trace=").to_string());
+                    continue;
+                }
+                if argument.starts_with("-mixer-weights=") {
+                    mixer_weights_filename = Some(argument.trim_start_matches("-mixer-weights=").to_string());
+                    continue;
+                }
                 if argument == "-d" {
                     do_compress = false;
                     continue;
@@ -1242,6 +1679,10 @@ fn main() {
                     skip_crc = true;
                     continue;
                 }
+                if argument == "-checksum64" {
+                    checksum = divans::ChecksumType::Xxh3;
+                    continue;
+                }
                 if argument == "--" {
                     doubledash = true;
                     continue;
@@ -1303,6 +1744,60 @@ fn main() {
                     do_recode = true;
                     continue;
                 }
+                if argument.starts_with("-npostfix=") {
+                    distance_postfix_bits = argument.trim_start_matches("-npostfix=").parse::<u8>().unwrap();
+                    continue;
+                }
+                if argument.starts_with("-key=") {
+                    authentication_key = Some(parse_authentication_key(argument.trim_start_matches("-key=")));
+                    continue;
+                }
+                if argument.starts_with("-formatversion=") {
+                    format_version = Some(match argument.trim_start_matches("-formatversion=") {
+                        "1" => divans::interface::FormatVersion::Original,
+                        "2" => divans::interface::FormatVersion::StreamFeatures,
+                        "3" => divans::interface::FormatVersion::Extended,
+                        "4" => divans::interface::FormatVersion::StoredFrames,
+                        version => panic!("-formatversion must be 1, 2, 3 or 4, not {}", version),
+                    });
+                    continue;
+                }
+                if argument.starts_with("-content=") {
+                    content_hint = match argument.trim_start_matches("-content=") {
+                        "sniff" => divans::interface::ContentHint::Unknown,
+                        "utf16" => divans::interface::ContentHint::Utf16,
+                        "opaque" => divans::interface::ContentHint::Opaque,
+                        hint => panic!("-content must be sniff, utf16 or opaque, not {}", hint),
+                    };
+                    continue;
+                }
+                if argument.starts_with("-align=") {
+                    frame_alignment = Some(argument.trim_start_matches("-align=").parse::<u32>().unwrap());
+                    continue;
+                }
+                if argument.starts_with("-ndirect=") {
+                    num_direct_distances = argument.trim_start_matches("-ndirect=").parse::<u8>().unwrap();
+                    continue;
+                }
+                if argument.starts_with("-freeze=") {
+                    freeze_adaptation_after = Some(argument.trim_start_matches("-freeze=").parse::<u32>().unwrap());
+                    continue;
+                }
+                if argument.starts_with("-resetpriors=") {
+                    let megabytes = argument.trim_start_matches("-resetpriors=").parse::<u32>().unwrap();
+                    priors_reset_interval = Some(megabytes << 20);
+                    continue;
+                }
+                if argument.starts_with("-warmstart=") {
+                    let mut fields = argument.trim_start_matches("-warmstart=").split(',');
+                    let cm_share = fields.next().unwrap().parse::<u8>().unwrap();
+                    let seed_high_nibble = fields.next().unwrap_or("0").parse::<u8>().unwrap();
+                    mixer_warm_start = match divans::MixerWarmStart::new(cm_share, seed_high_nibble) {
+                        Ok(warm_start) => Some(warm_start),
+                        Err(e) => panic!("-warmstart takes a cm share and a seed nibble up to 15, not both 0: {:?}", e),
+                    };
+                    continue;
+                }
                 if argument.starts_with("-lgwin") {
                     let fs = argument.trim_matches(
                         '-').trim_matches(
@@ -1431,6 +1926,63 @@ fn main() {
                     raw_compress = false;
                     continue;
                 }
+                if argument == "-peephole" {
+                    ir_peephole = true;
+                    continue;
+                }
+                if argument == "-prescan" {
+                    literal_prescan = true;
+                    continue;
+                }
+                if argument == "-nocompactpriors" {
+                    compact_priors = false;
+                    continue;
+                }
+                if argument == "-legacycmdselect" {
+                    // pick the command type with one nibble, as decoders predating the type tree do
+                    stream_features &= !divans::interface::STREAM_FEATURE_COMMAND_TYPE_TREE;
+                    continue;
+                }
+                if argument == "-cmapmodel" {
+                    extended_stream_features |= divans::interface::EXTENDED_FEATURE_CONTEXT_MAP_MODEL;
+                    continue;
+                }
+                if argument == "-staticmodel" {
+                    extended_stream_features |= divans::interface::EXTENDED_FEATURE_STATIC_LITERAL_MODEL;
+                    continue;
+                }
+                if argument == "-dictmodel" {
+                    extended_stream_features |= divans::interface::EXTENDED_FEATURE_DICT_TRANSFORM_MODEL;
+                    continue;
+                }
+                if argument == "-matchmodel" {
+                    extended_stream_features |= divans::interface::EXTENDED_FEATURE_MATCH_BYTE_CONTEXT;
+                    continue;
+                }
+                if argument == "-blockmixer" {
+                    extended_stream_features |= divans::interface::EXTENDED_FEATURE_BLOCK_TYPE_MIXER;
+                    continue;
+                }
+                if argument == "-dictcopyfusion" {
+                    extended_stream_features2 |= divans::interface::EXTENDED_FEATURE2_DICT_COPY_FUSION;
+                    continue;
+                }
+                if argument == "-autonodict" {
+                    extended_stream_features2 |= divans::interface::EXTENDED_FEATURE2_AUTO_NO_DICT;
+                    continue;
+                }
+                if argument == "-classmodel" {
+                    extended_stream_features2 |= divans::interface::EXTENDED_FEATURE2_BYTE_CLASS_CONTEXT;
+                    continue;
+                }
+                if argument == "-wideblocktypes" {
+                    extended_stream_features2 |= divans::interface::EXTENDED_FEATURE2_WIDE_BLOCK_TYPES;
+                    continue;
+                }
+                if argument == "-ironly" {
+                    extended_stream_features |= divans::interface::EXTENDED_FEATURE_IR_ONLY;
+                    continue;
+                }
                 if argument.starts_with("-O") {
                     if argument != "-O0" {
                         divans_ir_optimizer = true
@@ -1568,6 +2120,14 @@ fn main() {
                 if argument == "-h" || argument == "-help" || argument == "--help" {
                     println_stderr!("Compression: divans {{-c [raw_input_file] | -i [ir_file]}} [output_file]");
                     println_stderr!("Decompression: divans [input_file] [output_file]");
+                    println_stderr!("Trace decompression: divans -d -trace=[trace_file] [input_file] [output_file]");
+                    println_stderr!("Explain a trace: divans trace explain [trace_file]");
+                    println_stderr!("Map the bits per byte of a compressed file: divans inspect --entropy-map [-region=bytes] [input_file]");
+                    println_stderr!("Recommend options for a sample of your data: divans calibrate [raw_input_file]");
+                    println_stderr!("Dump literal mixer weights per block: divans -d -mixer-weights=[weights_file] [input_file] [output_file]");
+                    println_stderr!("Write conformance test vectors: divans conformance [output_dir]");
+                    println_stderr!("Archive a directory: divans archive create [dir] [archive_file]");
+                    println_stderr!("List or extract an archive: divans archive {{list [archive_file] | extract [archive_file] [dir] [name...]}}");
                     return;
                 }
                 if argument == "-v" || argument == "-version" || argument == "--version" {
@@ -1607,7 +2167,29 @@ fn main() {
             prior_bitmask_detection: if prior_bitmask_detection {1} else {0},
             force_literal_context_mode: force_literal_context_mode,
             divans_ir_optimizer: if divans_ir_optimizer {1} else {0},
+            literal_prescan: literal_prescan,
+            // the default coding choices would need a newer format
+            stream_features: if format_version == Some(divans::interface::FormatVersion::Original) {0} else {stream_features},
+            distance_bucketing: match divans::DistanceBucketing::new(distance_postfix_bits, num_direct_distances) {
+                Ok(bucketing) => bucketing,
+                Err(e) => panic!("-npostfix must be at most 3 and -ndirect a multiple of 1 << npostfix up to 15 << npostfix: {:?}", e),
+            },
+            frame_alignment: frame_alignment,
+            authentication_key: authentication_key,
+            mixer_warm_start,
+            extended_stream_features,
+            framing_tag: None,
+            compact_priors,
+            format_version,
+            content_hint,
+            freeze_adaptation_after,
+            priors_reset_interval,
+            extended_stream_features2,
+            checksum,
         };
+        if let Err(e) = opts.validate() {
+            panic!("Conflicting or out of range options: {:?}", e);
+        }
         if filenames[0] != "" {
             let mut input = match File::open(&Path::new(&filenames[0])) {
                 Err(why) => panic!("couldn't open {:}\n{:}", filenames[0], why),
@@ -1621,7 +2203,7 @@ fn main() {
                 for i in 0..num_benchmarks {
                     if do_compress && !raw_compress {
                         let mut buffered_input = BufReader::new(input);
-                        match compress_ir(&mut buffered_input, &mut output, opts) {
+                        match compress_ir(&mut buffered_input, &mut output, opts, ir_peephole) {
                             Ok(_) => {}
                             Err(e) => panic!("Error {:?}", e),
                         }
@@ -1640,7 +2222,16 @@ fn main() {
                                &mut output).unwrap();
                         input = buffered_input.into_inner();
                     } else {
-                        match decompress(&mut input, &mut output, buffer_size, &mut [], skip_crc, parallel) {
+                        match match trace_filename {
+                            Some(ref trace_file) => decompress_with_trace(&mut input, &mut output, buffer_size, skip_crc, parallel, trace_file),
+                            None => match mixer_weights_filename {
+                                Some(ref weights_file) => decompress_with_mixer_weights(&mut input, &mut output, buffer_size, skip_crc, parallel, weights_file),
+                                None => match authentication_key {
+                                    Some(key) => decompress_authenticated(&mut input, &mut output, buffer_size, key, parallel),
+                                    None => decompress(&mut input, &mut output, buffer_size, &mut [], skip_crc, parallel),
+                                },
+                            },
+                        } {
                             Ok(_) => {}
                             Err(e) => panic!("Error {:?}", e),
                         }
@@ -1655,7 +2246,7 @@ fn main() {
                 assert_eq!(num_benchmarks, 1);
                 if do_compress && !raw_compress {
                     let mut buffered_input = BufReader::new(input);
-                    match compress_ir (&mut buffered_input, &mut io::stdout(), opts) {
+                    match compress_ir (&mut buffered_input, &mut io::stdout(), opts, ir_peephole) {
                         Ok(_) => {}
                         Err(e) => panic!("Error {:?}", e),
                     }
@@ -1673,7 +2264,16 @@ fn main() {
                     recode(&mut buffered_input,
                            &mut io::stdout()).unwrap()
                 } else {
-                    match decompress(&mut input, &mut io::stdout(), buffer_size, &mut [], skip_crc, parallel) {
+                    match match trace_filename {
+                        Some(ref trace_file) => decompress_with_trace(&mut input, &mut io::stdout(), buffer_size, skip_crc, parallel, trace_file),
+                        None => match mixer_weights_filename {
+                            Some(ref weights_file) => decompress_with_mixer_weights(&mut input, &mut io::stdout(), buffer_size, skip_crc, parallel, weights_file),
+                            None => match authentication_key {
+                                Some(key) => decompress_authenticated(&mut input, &mut io::stdout(), buffer_size, key, parallel),
+                                None => decompress(&mut input, &mut io::stdout(), buffer_size, &mut [], skip_crc, parallel),
+                            },
+                        },
+                    } {
                         Ok(_) => {}
                         Err(e) => panic!("Error {:?}", e),
                     }
@@ -1684,7 +2284,7 @@ fn main() {
             if do_compress && !raw_compress {
                 let stdin = std::io::stdin();
                 let mut stdin = stdin.lock();
-                match compress_ir(&mut stdin, &mut io::stdout(), opts) {
+                match compress_ir(&mut stdin, &mut io::stdout(), opts, ir_peephole) {
                     Ok(_) => return,
                     Err(e) => panic!("Error {:?}", e),
                 }
@@ -1703,7 +2303,16 @@ fn main() {
                 recode(&mut stdin,
                        &mut io::stdout()).unwrap()
             } else {
-                match decompress(&mut io::stdin(), &mut io::stdout(), buffer_size, &mut [], skip_crc, parallel) {
+                match match trace_filename {
+                    Some(ref trace_file) => decompress_with_trace(&mut io::stdin(), &mut io::stdout(), buffer_size, skip_crc, parallel, trace_file),
+                    None => match mixer_weights_filename {
+                        Some(ref weights_file) => decompress_with_mixer_weights(&mut io::stdin(), &mut io::stdout(), buffer_size, skip_crc, parallel, weights_file),
+                        None => match authentication_key {
+                            Some(key) => decompress_authenticated(&mut io::stdin(), &mut io::stdout(), buffer_size, key, parallel),
+                            None => decompress(&mut io::stdin(), &mut io::stdout(), buffer_size, &mut [], skip_crc, parallel),
+                        },
+                    },
+                } {
                     Ok(_) => return,
                     Err(e) => panic!("Error {:?}", e),
                 }
@@ -18,7 +18,7 @@ use std::io;
 
 use std::io::BufReader;
 use core::cmp;
-use divans::{Speed, StrideSelection, DivansCompressorOptions, BrotliCompressionSetting};
+use divans::{Speed, StrideSelection, DivansCompressorOptions, BrotliCompressionSetting, ContentHint};
 #[cfg(feature="no-stdlib")]
 pub static MULTI: bool = false;
 #[cfg(not(feature="no-stdlib"))]
@@ -132,6 +132,21 @@ fn e2e_no_ir(buffer_size: usize, use_serialized_priors: bool, use_brotli: bool,
                             prior_bitmask_detection: 1,
                             stride_detection_quality: None,
                             divans_ir_optimizer:1,
+                            literal_prescan: false,
+                            stream_features: 0,
+                            distance_bucketing: divans::interface::DistanceBucketing::default(),
+                            mixer_warm_start: None,
+                            extended_stream_features: 0,
+                            framing_tag: None,
+                            compact_priors: true,
+                            format_version: None,
+                            content_hint: ContentHint::Unknown,
+                            freeze_adaptation_after: None,
+                            priors_reset_interval: None,
+                            extended_stream_features2: 0,
+                            checksum: divans::ChecksumType::Crc32c,
+                            frame_alignment: None,
+                            authentication_key: None,
                         },
                         buffer_size,
                         use_brotli,
@@ -148,6 +163,36 @@ fn e2e_no_ir(buffer_size: usize, use_serialized_priors: bool, use_brotli: bool,
     }
 }
 
+// text followed by fixed size records, so the two halves want different literal models,
+// while without prior bitmask detection brotli picks one mixing value for every context
+fn e2e_prescan(literal_prescan: bool) -> usize {
+    let mut data = include_bytes!("../../testdata/alice29")[..65536].to_vec();
+    for i in 0..16384usize {
+        data.extend(&[b'A' + (i % 3) as u8, (i / 7) as u8, 0x80 | (i % 5) as u8, (i / 300) as u8]);
+    }
+    let mut in_buffer = UnlimitedBuffer::new(&data[..]);
+    let mut dv_buffer = UnlimitedBuffer::new(&[]);
+    let mut rt_buffer = UnlimitedBuffer::new(&[]);
+    let mut opts = DivansCompressorOptions::default();
+    opts.quality = Some(10);
+    opts.dynamic_context_mixing = Some(2);
+    opts.use_context_map = true;
+    opts.literal_prescan = literal_prescan;
+    opts.prior_bitmask_detection = 0;
+    super::compress_raw(&mut in_buffer, &mut dv_buffer, opts, 4096, true, true, true).unwrap();
+    super::decompress(&mut dv_buffer, &mut rt_buffer, 4096, &mut[], false, MULTI).unwrap();
+    assert_eq!(rt_buffer.data, in_buffer.data);
+    dv_buffer.data.len()
+}
+
+#[test]
+fn test_e2e_literal_prescan() {
+    let plain = e2e_prescan(false);
+    let prescanned = e2e_prescan(true);
+    println!("without prescan: {} with prescan: {}", plain, prescanned);
+    assert!(prescanned < plain);
+}
+
 #[test]
 fn test_e2e_ones_tinybuf() {
     let data = [1u8, 2u8, 3u8, 4u8,255u8,1u8,2u8,3u8,0u8,1u8,2u8,3u8,8u8,4u8,3u8,
@@ -247,7 +292,7 @@ fn e2e_alice(buffer_size: usize, use_serialized_priors: bool) {
    opts.prior_bitmask_detection=1;
    opts.dynamic_context_mixing=Some(1);
    opts.use_context_map = true;
-    super::compress_ir(&mut buf_ir, &mut dv_buffer, opts).unwrap();
+    super::compress_ir(&mut buf_ir, &mut dv_buffer, opts, false).unwrap();
     
    super::decompress(&mut dv_buffer, &mut rt_buffer, buffer_size, &mut[], false, MULTI).unwrap();
    println!("dv_buffer size: {}", dv_buffer.data.len());
@@ -273,6 +318,48 @@ fn test_e2e_tinybuf() {
 }
 
 
+// Mimics a naive frontend by cutting every insert and copy of a brotli IR into pieces of at most 3 bytes
+fn fragment_ir(ir: &[u8]) -> Vec<u8> {
+   let mut ret = Vec::new();
+   for line in core::str::from_utf8(ir).unwrap().lines() {
+       let fields: Vec<&str> = line.split(' ').collect();
+       if fields[0] == "insert" && fields[1] != "0" {
+           for chunk in fields[2].as_bytes().chunks(6) {
+               ret.extend(format!("insert {} {}\n", chunk.len() / 2, core::str::from_utf8(chunk).unwrap()).bytes());
+           }
+       } else if fields[0] == "copy" {
+           let mut remaining = fields[1].parse::<u32>().unwrap();
+           while remaining != 0 {
+               let len = cmp::min(remaining, 3);
+               ret.extend(format!("copy {} from {} ctx {}\n", len, fields[3], fields[5]).bytes());
+               remaining -= len;
+           }
+       } else {
+           ret.extend(line.bytes());
+           ret.push(b'\n');
+       }
+   }
+   ret
+}
+
+#[test]
+fn test_e2e_alice_peephole() {
+   let raw_text_buffer = UnlimitedBuffer::new(&include_bytes!("../../testdata/alice29")[..]);
+   let naive_ir = fragment_ir(include_bytes!("../../testdata/alice29.ir"));
+   let mut plain_buffer = UnlimitedBuffer::new(&[]);
+   let mut dv_buffer = UnlimitedBuffer::new(&[]);
+   let mut rt_buffer = UnlimitedBuffer::new(&[]);
+   super::compress_ir(&mut BufReader::new(UnlimitedBuffer::new(&naive_ir[..])), &mut plain_buffer,
+                      DivansCompressorOptions::default(), false).unwrap();
+   super::compress_ir(&mut BufReader::new(UnlimitedBuffer::new(&naive_ir[..])), &mut dv_buffer,
+                      DivansCompressorOptions::default(), true).unwrap();
+   println!("naive {} peephole {}", plain_buffer.data.len(), dv_buffer.data.len());
+   assert!(dv_buffer.data.len() < plain_buffer.data.len());
+   super::decompress(&mut dv_buffer, &mut rt_buffer, 65536, &mut[], false, MULTI).unwrap();
+   assert_eq!(rt_buffer.data, raw_text_buffer.data);
+}
+
+
 #[test]
 fn test_e2e_32xx() {
    let raw_text_buffer = UnlimitedBuffer::new(b"XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX");
@@ -283,7 +370,7 @@ fn test_e2e_32xx() {
    let mut opts = DivansCompressorOptions::default();
    opts.literal_adaptation = None;
 
-   super::compress_ir(&mut buf_ir, &mut dv_buffer, opts).unwrap();
+   super::compress_ir(&mut buf_ir, &mut dv_buffer, opts, false).unwrap();
    super::decompress(&mut dv_buffer, &mut rt_buffer, 15, &mut [],  false, MULTI).unwrap();
    let a =  rt_buffer.data;
    let b = raw_text_buffer.data;
@@ -303,7 +390,7 @@ fn test_e2e_262145_at() {
    opts.literal_adaptation = Some([Speed::MUD, Speed::ROCKET, Speed::FAST, Speed::GLACIAL]);
    opts.use_context_map = true;
    opts.dynamic_context_mixing = Some(2);
-   super::compress_ir(&mut buf_ir, &mut dv_buffer, opts).unwrap();
+   super::compress_ir(&mut buf_ir, &mut dv_buffer, opts, false).unwrap();
    super::decompress(&mut dv_buffer, &mut rt_buffer, 15, &mut[], false, MULTI).unwrap();
    let a =  rt_buffer.data;
    let b = raw_text_buffer.data;
@@ -325,7 +412,7 @@ fn test_e2e_64xp() {
    opts.literal_adaptation = Some([Speed::FAST, Speed::SLOW, Speed::FAST, Speed::FAST]);
    opts.use_context_map = true;
    opts.dynamic_context_mixing = Some(1);
-   match super::compress_ir(&mut buf_ir, &mut dv_buffer, opts) {
+   match super::compress_ir(&mut buf_ir, &mut dv_buffer, opts, false) {
       Ok(_) => assert_eq!(EXTERNAL_PROB_FEATURE, true),
       Err(_) => assert_eq!(EXTERNAL_PROB_FEATURE, false),
    };
@@ -336,3 +423,140 @@ fn test_e2e_64xp() {
 }
 
 
+
+fn digest_roundtrip(data: &[u8], multithread: bool) {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hasher;
+    use super::ItemVecAllocator;
+    use divans::{DivansCompressorFactory, DivansDecompressorFactory, Compressor, Decompressor, DivansResult, DivansOutputResult};
+    let mut compressor = divans::DivansCompressorFactoryStruct::<ItemVecAllocator<u8>,
+                                                                 ItemVecAllocator<divans::DefaultCDF16>,
+                                                                 DefaultHasher>::new(
+        ItemVecAllocator::<u8>::default(),
+        ItemVecAllocator::<u32>::default(),
+        ItemVecAllocator::<divans::DefaultCDF16>::default(),
+        DivansCompressorOptions::default(),
+        (),
+    );
+    let mut compressed = vec![0u8; data.len() + 256];
+    let mut ioffset = 0usize;
+    let mut ooffset = 0usize;
+    match compressor.encode(data, &mut ioffset, &mut compressed[..], &mut ooffset) {
+        DivansResult::NeedsMoreInput => {},
+        res => panic!("{:?}", res),
+    }
+    match compressor.flush(&mut compressed[..], &mut ooffset) {
+        DivansOutputResult::Success => {},
+        res => panic!("{:?}", res),
+    }
+    compressed.truncate(ooffset);
+    let encoder_digest = compressor.take_digest().finish();
+    compressor.free();
+    let mut expected = DefaultHasher::default();
+    expected.write(&compressed[..compressed.len() - 8]); // the trailer itself is not hashed
+    assert_eq!(encoder_digest, expected.finish());
+
+    let mut decompressor = divans::DivansDecompressorFactoryStruct::<ItemVecAllocator<u8>,
+                                                                     ItemVecAllocator<divans::DefaultCDF16>,
+                                                                     ItemVecAllocator<divans::StaticCommand>>::new_with_digest::<DefaultHasher>(
+        ItemVecAllocator::<u8>::default(),
+        ItemVecAllocator::<divans::DefaultCDF16>::default(),
+        ItemVecAllocator::<divans::StaticCommand>::default(),
+        false,
+        multithread,
+    );
+    let mut rt = vec![0u8; data.len()];
+    let mut ioffset = 0usize;
+    let mut ooffset = 0usize;
+    match decompressor.decode(&compressed[..], &mut ioffset, &mut rt[..], &mut ooffset) {
+        DivansResult::Success => {},
+        res => panic!("{:?}", res),
+    }
+    assert_eq!(&rt[..ooffset], data);
+    assert_eq!(decompressor.take_digest().finish(), encoder_digest);
+    decompressor.free();
+}
+
+#[test]
+fn test_take_digest_serial() {
+    digest_roundtrip(&include_bytes!("../../testdata/alice29")[..4096], false);
+}
+
+#[test]
+fn test_take_digest_multi() {
+    digest_roundtrip(&include_bytes!("../../testdata/alice29")[..4096], MULTI);
+}
+
+#[cfg(feature="trace")]
+#[test]
+fn test_trace_explain_roundtrip() {
+    use super::ItemVecAllocator;
+    use divans::{DivansCompressorFactory, DivansDecompressorFactory, Compressor, Decompressor, DivansResult, DivansOutputResult};
+    let data = &include_bytes!("../../testdata/alice29")[..4096];
+    let mut compressor = divans::DivansCompressorFactoryStruct::<ItemVecAllocator<u8>,
+                                                                 ItemVecAllocator<divans::DefaultCDF16>>::new(
+        ItemVecAllocator::<u8>::default(),
+        ItemVecAllocator::<u32>::default(),
+        ItemVecAllocator::<divans::DefaultCDF16>::default(),
+        DivansCompressorOptions::default(),
+        (),
+    );
+    let mut compressed = vec![0u8; data.len() + 256];
+    let mut ioffset = 0usize;
+    let mut ooffset = 0usize;
+    match compressor.encode(data, &mut ioffset, &mut compressed[..], &mut ooffset) {
+        DivansResult::NeedsMoreInput => {},
+        res => panic!("{:?}", res),
+    }
+    match compressor.flush(&mut compressed[..], &mut ooffset) {
+        DivansOutputResult::Success => {},
+        res => panic!("{:?}", res),
+    }
+    compressed.truncate(ooffset);
+    let encoder_trace = compressor.take_trace();
+    compressor.free();
+    let records: Vec<divans::trace::TraceRecord> = divans::trace::TraceReader::new(&encoder_trace[..]).unwrap().collect();
+    assert_eq!(divans::trace::tag_name(records.last().unwrap().tag), "DivansSuccess");
+    // the checksum trailer is emitted after the last state change
+    assert_eq!(records.last().unwrap().output_offset as usize, compressed.len());
+
+    let mut decompressor = divans::DivansDecompressorFactoryStruct::<ItemVecAllocator<u8>,
+                                                                     ItemVecAllocator<divans::DefaultCDF16>,
+                                                                     ItemVecAllocator<divans::StaticCommand>>::new(
+        ItemVecAllocator::<u8>::default(),
+        ItemVecAllocator::<divans::DefaultCDF16>::default(),
+        ItemVecAllocator::<divans::StaticCommand>::default(),
+        false,
+        false,
+    );
+    let mut rt = vec![0u8; data.len()];
+    let mut ioffset = 0usize;
+    let mut ooffset = 0usize;
+    let mut ilim = 0usize;
+    let mut olim = 0usize;
+    loop {
+        match decompressor.decode(&compressed[..ilim], &mut ioffset, &mut rt[..olim], &mut ooffset) {
+            DivansResult::Success => break,
+            DivansResult::NeedsMoreInput => ilim = cmp::min(ilim + 97, compressed.len()),
+            DivansResult::NeedsMoreOutput => olim = cmp::min(olim + 301, rt.len()),
+            res => panic!("{:?}", res),
+        }
+    }
+    assert_eq!(&rt[..ooffset], data);
+    let decoder_trace = decompressor.take_trace();
+    decompressor.free();
+    let records: Vec<divans::trace::TraceRecord> = divans::trace::TraceReader::new(&decoder_trace[..]).unwrap().collect();
+    assert!(records.iter().any(|r| divans::trace::tag_name(r.tag) == "Lit::FullyDecoded"));
+    for pair in records.windows(2) {
+        assert!(pair[0].seq < pair[1].seq);
+        assert!(pair[0].input_offset <= pair[1].input_offset);
+        assert!(pair[0].output_offset <= pair[1].output_offset);
+    }
+    assert!(records.last().unwrap().input_offset as usize <= compressed.len());
+    assert!(records.last().unwrap().output_offset as usize <= data.len());
+    let mut explained = Vec::<u8>::new();
+    super::trace_explain(&decoder_trace[..], &mut explained).unwrap();
+    let explained = String::from_utf8(explained).unwrap();
+    assert_eq!(explained.lines().count(), records.len() + 2);
+    assert!(explained.ends_with(&format!("{} records\n", records.len())));
+}
@@ -31,9 +31,14 @@ pub use codec::{EncoderOrDecoderSpecialization, DivansCodec, StrideSelection};
 use super::resizable_buffer::ResizableByteBuffer;
 use super::interface;
 use super::interface::{DivansOutputResult, DivansResult, ErrMsg};
-use super::brotli::enc::encode::{BrotliEncoderStateStruct, BrotliEncoderCompressStream, BrotliEncoderOperation, BrotliEncoderIsFinished};
+use super::cancel::CancelToken;
+#[cfg(feature="timings")]
+use super::timings::{Clock, EncodeTimings, StageClock};
+use super::brotli::enc::encode::{BrotliEncoderStateStruct, BrotliEncoderCompressStream, BrotliEncoderOperation, BrotliEncoderIsFinished, BrotliEncoderHasMoreOutput};
 use super::brotli::enc::backward_references::BrotliEncoderMode;
-use super::divans_compressor::write_header;
+use super::divans_compressor::{make_header, write_header};
+use super::stored_frame;
+use super::stored_frame::{ModeledCompressor, TinyInput};
 pub struct BrotliDivansHybridCompressor<SelectedCDF:CDF16,
                             ChosenEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                             AllocU8:Allocator<u8>,
@@ -48,6 +53,57 @@ pub struct BrotliDivansHybridCompressor<SelectedCDF:CDF16,
     divans_data: ResizableByteBuffer<u8, AllocU8>,
     encoded_byte_offset: usize,
     opt: super::interface::DivansCompressorOptions,
+    // trailing copy of the last metablock, held back so it can be merged with a continuation in the next one
+    pending_copy: Option<CopyCommand>,
+    // brotli cannot stop partway through the input it is given, so this is looked at between calls
+    cancel: CancelToken,
+    // what encode and flush fail with when the options need a newer format than they allow
+    format_error: Option<ErrMsg>,
+    tiny: TinyInput,
+    #[cfg(feature="timings")]
+    clock: StageClock,
+    #[cfg(feature="timings")]
+    timings: EncodeTimings, // only parse_ns and model_ns: the codec keeps the rest
+}
+
+// brotli caps a copy at the metablock size, so long repeats arrive as one copy per metablock,
+// each following the block switches that open the metablock.
+// Returns (split, lead, resume, end): encode cmds[..split], then lead, then cmds[resume..end].
+// A trailing copy is left in pending so the next call can extend it.
+fn coalesce_copies(pending: &mut Option<CopyCommand>,
+                   cmds: &[brotli::interface::Command<brotli::SliceOffset>]) -> (usize, Option<CopyCommand>, usize, usize) {
+    let mut split = 0usize;
+    let mut resume = 0usize;
+    let mut end = cmds.len();
+    let mut lead = pending.take();
+    if let Some(ref mut held) = lead {
+        let first_output = cmds.iter().position(|cmd| match *cmd {
+            brotli::interface::Command::BlockSwitchLiteral(_)
+                | brotli::interface::Command::BlockSwitchCommand(_)
+                | brotli::interface::Command::BlockSwitchDistance(_) => false,
+            _ => true,
+        });
+        if let Some(index) = first_output {
+            if let brotli::interface::Command::Copy(ref next) = cmds[index] {
+                if next.distance == held.distance {
+                    if let Some(num_bytes) = held.num_bytes.checked_add(next.num_bytes) {
+                        held.num_bytes = num_bytes;
+                        split = index;
+                        resume = index + 1;
+                    }
+                }
+            }
+        }
+    }
+    if end > resume {
+        if let brotli::interface::Command::Copy(ref last) = cmds[end - 1] {
+            *pending = Some(*last);
+            end -= 1;
+        }
+    } else if resume != 0 {
+        *pending = lead.take(); // the whole metablock continued the held copy: keep extending it
+    }
+    (split, lead, resume, end)
 }
 
 
@@ -65,6 +121,41 @@ impl<SelectedCDF:CDF16,
     pub fn get_m8(&mut self) -> &mut AllocU8 {
        self.codec.get_m8().unwrap().get_base_alloc()
     }
+    // encode and flush fail with ErrMsg::Cancelled before doing anything once token is cancelled,
+    // and carry on when called again after it is reset
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        self.cancel = token;
+    }
+    // times the stages of encode and flush from now on; see timings.rs
+    #[cfg(feature="timings")]
+    pub fn set_clock(&mut self, clock: &'static dyn Clock) {
+        self.clock = StageClock::new(clock);
+        self.codec.set_clock(clock);
+    }
+    #[cfg(feature="timings")]
+    pub fn timings(&self) -> EncodeTimings {
+        let coded = self.codec.timings();
+        EncodeTimings {
+            entropy_code_ns: coded.entropy_code_ns,
+            recode_ns: coded.recode_ns,
+            ..self.timings
+        }
+    }
+    // once flush has returned NeedsMoreOutput, the room the next call needs to finish: the rest of
+    // the stream, which flush has finished encoding by then, or of a stored frame
+    pub fn output_needed(&self) -> usize {
+        if let Some(remaining) = self.tiny.frame_remaining() {
+            return remaining;
+        }
+        if self.header_progress != self.codec.header_length() {
+            return self.codec.header_length() - self.header_progress;
+        }
+        self.divans_data.len() - self.encoded_byte_offset
+    }
+    // applies from the next metablock on; see DivansCodec::set_literal_modeling
+    pub fn set_literal_modeling(&mut self, dynamic_context_mixing: u8, use_context_map: bool) {
+        self.codec.set_literal_modeling(dynamic_context_mixing, use_context_map);
+    }
     #[cfg(not(feature="std"))]
     fn do_panic(_m:ErrMsg) {
         panic!("Internal Error With Compression Stage")
@@ -91,8 +182,8 @@ impl<SelectedCDF:CDF16,
             {
                 let output = data.checkout_next_buffer(codec.get_m8().as_mut().unwrap().get_base_alloc(),
                                                            Some(interface::HEADER_LENGTH + 256));
-                if *header_progress != interface::HEADER_LENGTH {
-                    match write_header(header_progress, window_size, output, &mut output_offset, codec.get_crc()) {
+                if *header_progress != codec.header_length() {
+                    match write_header(header_progress, &make_header(window_size, codec.stream_features(), codec.extended_stream_features(), codec.freeze_adaptation_after(), codec.priors_reset_interval(), codec.extended_stream_features2(), codec.checksum_type()), codec.framing_tag(), output, &mut output_offset, codec.get_crc()) {
                         DivansOutputResult::Success => {},
                         _ => panic!("Unexpected failure writing header"),
                     }
@@ -130,11 +221,41 @@ impl<SelectedCDF:CDF16,
             let header_progress_ref = &mut self.header_progress;
             let window_size = self.window_size;
             let opt = self.opt;
+            let pending_copy_ref = &mut self.pending_copy;
+            #[cfg(feature="timings")]
+            let clock = self.clock;
+            #[cfg(feature="timings")]
+            let timings_ref = &mut self.timings;
+            #[cfg(feature="timings")]
+            let mut callback_ns = 0u64; // brotli's parse time is what it spends outside the callback
             let mut cb = |pm:&mut brotli::interface::PredictionModeContextMap<brotli::InputReferenceMut>,
                           a:&mut [brotli::interface::Command<brotli::SliceOffset>],
                           mb:brotli::InputPair,
                           mc:&mut AllocBrotli| {
+                              #[cfg(feature="timings")]
+                              let callback_start = clock.now();
                               let mut expanded_buffer  = <AllocBrotli as Allocator<brotli::enc::StaticCommand>>::AllocatedMemory::default();
+                              let utf16 = match opt.content_hint {
+                                  interface::ContentHint::Unknown => super::literal_prescan::sniff_utf16(&mb),
+                                  interface::ContentHint::Utf16 => true,
+                                  interface::ContentHint::Opaque => false,
+                              };
+                              if utf16 {
+                                  super::literal_prescan::use_utf16_model(pm, a);
+                              }
+                              if opt.literal_prescan {
+                                  super::literal_prescan::prescan_literal_blocks(divans_codec_ref.get_m8().unwrap().get_base_alloc(),
+                                                                                 pm, a, &mb);
+                              }
+                              if opt.extended_stream_features & interface::EXTENDED_FEATURE_LITERAL_NIBBLE_ORDER != 0 {
+                                  super::literal_prescan::choose_literal_nibble_order(divans_codec_ref.get_m8().unwrap().get_base_alloc(),
+                                                                                      pm, a, &mb);
+                              }
+                              if opt.extended_stream_features & interface::EXTENDED_FEATURE_STATIC_LITERAL_MODEL != 0 {
+                                  if let Some(weights) = divans_codec_ref.static_literal_weights_mut() {
+                                      super::static_literal_model::choose_static_literal_weights(mc, pm, a, &mb, weights);
+                                  }
+                              }
                               {
                                   let final_cmd = if opt.divans_ir_optimizer != 0 {
                                       match super::ir_optimize::ir_optimize(pm, a, mb, divans_codec_ref, window_size, opt, mc, &mut  expanded_buffer) {
@@ -144,6 +265,10 @@ impl<SelectedCDF:CDF16,
                                   } else {
                                       a
                                   };
+                                  #[cfg(feature="timings")]
+                                  {
+                                      timings_ref.model_ns += clock.since(callback_start);
+                                  }
                                   let tmp = Command::PredictionMode(PredictionModeContextMap::<brotli::InputReference>{
                                       literal_context_map:brotli::InputReference::from(&pm.literal_context_map),
                                       predmode_speed_and_distance_context_map:brotli::InputReference::from(&pm.predmode_speed_and_distance_context_map),
@@ -153,8 +278,27 @@ impl<SelectedCDF:CDF16,
                                                                divans_data_ref,
                                                                divans_codec_ref,
                                                                window_size);
-                                  if final_cmd.len() != 0 {
-                                      Self::divans_encode_commands(&ThawingSliceArray(final_cmd, mb),
+                                  let (split, lead, resume, end) = if opt.divans_ir_optimizer != 0 {
+                                      (0, None, 0, final_cmd.len()) // the optimizer's cost model expects the codec to be caught up
+                                  } else {
+                                      coalesce_copies(pending_copy_ref, final_cmd)
+                                  };
+                                  if split != 0 {
+                                      Self::divans_encode_commands(&ThawingSliceArray(&final_cmd[..split], mb),
+                                                                   header_progress_ref,
+                                                                   divans_data_ref,
+                                                                   divans_codec_ref,
+                                                                   window_size);
+                                  }
+                                  if let Some(lead_copy) = lead {
+                                      Self::divans_encode_commands(&CommandSliceArray(&[Command::<brotli::InputReference>::Copy(lead_copy)]),
+                                                                   header_progress_ref,
+                                                                   divans_data_ref,
+                                                                   divans_codec_ref,
+                                                                   window_size);
+                                  }
+                                  if end > resume {
+                                      Self::divans_encode_commands(&ThawingSliceArray(&final_cmd[resume..end], mb),
                                                                    header_progress_ref,
                                                                    divans_data_ref,
                                                                    divans_codec_ref,
@@ -164,6 +308,10 @@ impl<SelectedCDF:CDF16,
                               if expanded_buffer.len() != 0 {
                                   <AllocBrotli as Allocator<brotli::enc::StaticCommand>>::free_cell(mc, expanded_buffer);
                               }
+                              #[cfg(feature="timings")]
+                              {
+                                  callback_ns += clock.since(callback_start);
+                              }
             };
             {
                 let mut available_in = input.len() - *input_offset;
@@ -175,7 +323,8 @@ impl<SelectedCDF:CDF16,
                 {
                     let brotli_buffer = self.brotli_data.checkout_next_buffer(&mut self.brotli_encoder.m8, Some(256));
                     available_out = brotli_buffer.len();
-
+                    #[cfg(feature="timings")]
+                    let compress_start = clock.now();
                     if BrotliEncoderCompressStream(&mut self.brotli_encoder,
                                                    op,
                                                    &mut available_in,
@@ -188,6 +337,11 @@ impl<SelectedCDF:CDF16,
                                                    &mut cb) <= 0 {
                         return DivansResult::Failure(ErrMsg::BrotliCompressStreamFail(0xff, 0xff));
                     }
+                    #[cfg(feature="timings")]
+                    {
+                        let compress_ns = clock.since(compress_start);
+                        self.timings.parse_ns += compress_ns.saturating_sub(callback_ns);
+                    }
                 }
                 self.brotli_data.commit_next_buffer(brotli_out_offset);
                 if available_out != 0 && available_in == 0 && BrotliEncoderIsFinished(&mut self.brotli_encoder) == 0 {
@@ -202,6 +356,7 @@ impl<SelectedCDF:CDF16,
             return DivansResult::NeedsMoreOutput;
         }
         if is_end {
+            self.encode_pending_copy();
             loop { // flush divans coder
                 let ret;
                 let mut output_offset = 0usize;
@@ -220,6 +375,29 @@ impl<SelectedCDF:CDF16,
             return DivansResult::NeedsMoreInput
         }
     }
+    // the copy held back to coalesce with the next metablock's first goes to the codec now
+    fn encode_pending_copy(&mut self) {
+        if let Some(pending_copy) = self.pending_copy.take() {
+            Self::divans_encode_commands(&CommandSliceArray(&[Command::<brotli::InputReference>::Copy(pending_copy)]),
+                                         &mut self.header_progress,
+                                         &mut self.divans_data,
+                                         &mut self.codec,
+                                         self.window_size);
+        }
+    }
+    // hands the bytes the codec has produced but the caller has not yet seen to output
+    fn copy_out_divans_data(&mut self, output: &mut [u8], output_offset: &mut usize) -> DivansOutputResult {
+        let destination = output.split_at_mut(*output_offset).1;
+        let src = self.divans_data.slice().split_at(self.encoded_byte_offset).1;
+        let copy_len = min(src.len(), destination.len());
+        destination.split_at_mut(copy_len).0.clone_from_slice(src.split_at(copy_len).0);
+        *output_offset += copy_len;
+        self.encoded_byte_offset += copy_len;
+        if self.encoded_byte_offset == self.divans_data.len() {
+            return DivansOutputResult::Success;
+        }
+        DivansOutputResult::NeedsMoreOutput
+    }
     fn free_internal(&mut self) {
         self.brotli_data.free(&mut self.brotli_encoder.m8);
         self.divans_data.free(&mut self.codec.get_m8().as_mut().unwrap().get_base_alloc());
@@ -241,16 +419,28 @@ impl<SelectedCDF:CDF16,
      AllocU8:Allocator<u8>,
      AllocCDF16:Allocator<SelectedCDF>,
      AllocBrotli: brotli::enc::BrotliAlloc>
-     Compressor for BrotliDivansHybridCompressor<SelectedCDF,
-                                                 ChosenEncoder,
-                                                 AllocU8,
-                                                 AllocCDF16,
-                                                 AllocBrotli> {
-    fn encode(&mut self,
-              input: &[u8],
-              input_offset: &mut usize,
-              _output: &mut [u8],
-              _output_offset: &mut usize) -> DivansResult {
+     ModeledCompressor for BrotliDivansHybridCompressor<SelectedCDF,
+                                                        ChosenEncoder,
+                                                        AllocU8,
+                                                        AllocCDF16,
+                                                        AllocBrotli> {
+    fn tiny_input(&mut self) -> &mut TinyInput {
+        &mut self.tiny
+    }
+    fn encode_modeled(&mut self,
+                      input: &[u8],
+                      input_offset: &mut usize,
+                      _output: &mut [u8],
+                      _output_offset: &mut usize) -> DivansResult {
+        if let Some(m) = self.format_error {
+            return DivansResult::Failure(m);
+        }
+        if self.cancel.is_cancelled() {
+            return DivansResult::Failure(ErrMsg::Cancelled);
+        }
+        if self.codec.is_finishing() || BrotliEncoderIsFinished(&mut self.brotli_encoder) != 0 {
+            return DivansResult::Failure(ErrMsg::NotAllowedToEncodeAfterFlush);
+        }
         match self.internal_encode_stream(BrotliEncoderOperation::BROTLI_OPERATION_PROCESS,
                                           input,
                                           input_offset,
@@ -260,18 +450,23 @@ impl<SelectedCDF:CDF16,
             DivansResult::Success | DivansResult::NeedsMoreInput => DivansResult::NeedsMoreInput,
         }
     }
-    fn flush(&mut self,
-             output: &mut [u8],
-             output_offset: &mut usize) -> DivansOutputResult {
+    fn flush_modeled(&mut self,
+                     output: &mut [u8],
+                     output_offset: &mut usize) -> DivansOutputResult {
         let mut zero = 0usize;
-        if self.header_progress != interface::HEADER_LENGTH {
-            match write_header(&mut self.header_progress, self.window_size, output, output_offset, self.codec.get_crc()) {
+        if let Some(m) = self.format_error {
+            return DivansOutputResult::Failure(m);
+        }
+        if self.header_progress != self.codec.header_length() {
+            match write_header(&mut self.header_progress, &make_header(self.window_size, self.codec.stream_features(), self.codec.extended_stream_features(), self.codec.freeze_adaptation_after(), self.codec.priors_reset_interval(), self.codec.extended_stream_features2(), self.codec.checksum_type()), self.codec.framing_tag(), output, output_offset, self.codec.get_crc()) {
                 DivansOutputResult::Success => {},
                 need => return need,
             }
         }
         loop {
-
+            if self.cancel.is_cancelled() {
+                return DivansOutputResult::Failure(ErrMsg::Cancelled);
+            }
             match self.internal_encode_stream(BrotliEncoderOperation::BROTLI_OPERATION_FINISH,
                                               &[],
                                               &mut zero,
@@ -283,24 +478,91 @@ impl<SelectedCDF:CDF16,
             }
         }
         // we're in success area here
-        let destination = output.split_at_mut(*output_offset).1;
-        let src = self.divans_data.slice().split_at(self.encoded_byte_offset).1;
-        let copy_len = min(src.len(), destination.len());
-        destination.split_at_mut(copy_len).0.clone_from_slice(src.split_at(copy_len).0);
-        *output_offset += copy_len;
-        self.encoded_byte_offset += copy_len;
-        if self.encoded_byte_offset == self.divans_data.len() {
-            return DivansOutputResult::Success;
+        self.copy_out_divans_data(output, output_offset)
+    }
+    fn sync_flush_modeled(&mut self,
+                          output: &mut [u8],
+                          output_offset: &mut usize) -> DivansOutputResult {
+        let mut zero = 0usize;
+        if let Some(m) = self.format_error {
+            return DivansOutputResult::Failure(m);
         }
-        DivansOutputResult::NeedsMoreOutput
+        if self.codec.is_finishing() || BrotliEncoderIsFinished(&mut self.brotli_encoder) != 0 {
+            return DivansOutputResult::Failure(ErrMsg::NotAllowedToEncodeAfterFlush);
+        }
+        if self.header_progress != self.codec.header_length() {
+            match write_header(&mut self.header_progress, &make_header(self.window_size, self.codec.stream_features(), self.codec.extended_stream_features(), self.codec.freeze_adaptation_after(), self.codec.priors_reset_interval(), self.codec.extended_stream_features2(), self.codec.checksum_type()), self.codec.framing_tag(), output, output_offset, self.codec.get_crc()) {
+                DivansOutputResult::Success => {},
+                need => return need,
+            }
+        }
+        // brotli emits the metablock it holds, and its commands reach the codec through the callback
+        loop {
+            if self.cancel.is_cancelled() {
+                return DivansOutputResult::Failure(ErrMsg::Cancelled);
+            }
+            if let DivansResult::Failure(m) = self.internal_encode_stream(BrotliEncoderOperation::BROTLI_OPERATION_FLUSH,
+                                                                          &[],
+                                                                          &mut zero,
+                                                                          false) {
+                return DivansOutputResult::Failure(m);
+            }
+            if BrotliEncoderHasMoreOutput(&mut self.brotli_encoder) == 0 {
+                break;
+            }
+        }
+        self.encode_pending_copy();
+        loop {
+            let ret;
+            let mut divans_output_offset = 0usize;
+            {
+                let mut divans_output = self.divans_data.checkout_next_buffer(self.codec.get_m8().as_mut().unwrap().get_base_alloc(),
+                                                                              Some(256));
+                ret = self.codec.sync_flush(&mut divans_output, &mut divans_output_offset);
+            }
+            self.divans_data.commit_next_buffer(divans_output_offset);
+            match ret {
+                DivansOutputResult::Success => break,
+                DivansOutputResult::NeedsMoreOutput => {},
+                DivansOutputResult::Failure(m) => return DivansOutputResult::Failure(m),
+            }
+        }
+        self.copy_out_divans_data(output, output_offset)
+    }
+}
+
+impl<SelectedCDF:CDF16,
+     ChosenEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
+     AllocU8:Allocator<u8>,
+     AllocCDF16:Allocator<SelectedCDF>,
+     AllocBrotli: brotli::enc::BrotliAlloc>
+     Compressor for BrotliDivansHybridCompressor<SelectedCDF,
+                                                 ChosenEncoder,
+                                                 AllocU8,
+                                                 AllocCDF16,
+                                                 AllocBrotli> {
+    fn encode(&mut self,
+              input: &[u8],
+              input_offset: &mut usize,
+              output: &mut [u8],
+              output_offset: &mut usize) -> DivansResult {
+        stored_frame::encode(self, input, input_offset, output, output_offset)
     }
     fn encode_commands<SliceType:SliceWrapper<u8>+Default>(&mut self,
                                                            input:&[Command<SliceType>],
                                                            input_offset : &mut usize,
                                                            output :&mut[u8],
                                                            output_offset: &mut usize) -> DivansOutputResult {
-        if self.header_progress != interface::HEADER_LENGTH {
-            match write_header(&mut self.header_progress, self.window_size, output, output_offset, self.codec.get_crc()) {
+        if let Some(m) = self.format_error {
+            return DivansOutputResult::Failure(m);
+        }
+        match stored_frame::release_held_input(self, output, output_offset) {
+            DivansResult::NeedsMoreInput | DivansResult::Success => {},
+            DivansResult::NeedsMoreOutput => return DivansOutputResult::NeedsMoreOutput,
+            DivansResult::Failure(m) => return DivansOutputResult::Failure(m),
+        }
+        if self.header_progress != self.codec.header_length() {
+            match write_header(&mut self.header_progress, &make_header(self.window_size, self.codec.stream_features(), self.codec.extended_stream_features(), self.codec.freeze_adaptation_after(), self.codec.priors_reset_interval(), self.codec.extended_stream_features2(), self.codec.checksum_type()), self.codec.framing_tag(), output, output_offset, self.codec.get_crc()) {
                 DivansOutputResult::Success => {},
                 res => return res,
             }
@@ -317,6 +579,15 @@ impl<SelectedCDF:CDF16,
             DivansResult::NeedsMoreOutput => DivansOutputResult::NeedsMoreOutput,
         }
     }
+    fn flush(&mut self,
+             output: &mut [u8],
+             output_offset: &mut usize) -> DivansOutputResult {
+        stored_frame::flush(self, output, output_offset)
+    }    fn sync_flush(&mut self,
+                  output: &mut [u8],
+                  output_offset: &mut usize) -> DivansOutputResult {
+        stored_frame::sync_flush(self, output, output_offset)
+    }
 }
 
 pub struct BrotliDivansHybridCompressorFactory<AllocU8:Allocator<u8>,
@@ -370,7 +641,45 @@ impl<AllocU8:Allocator<u8>,
             opt:opt,
             header_progress: 0,
             window_size: window_size as u8,
+            pending_copy: None,
+            cancel: CancelToken::default(),
+            format_error: opt.check_format_version().err(),
+            tiny: TinyInput::new(opt.stores_tiny_inputs()),
+            #[cfg(feature="timings")]
+            clock: StageClock::default(),
+            #[cfg(feature="timings")]
+            timings: EncodeTimings::default(),
         };
+        // brotli may emit any command, so only the coding choices can be requested
+        let mut coding_features = opt.stream_features & (interface::STREAM_FEATURE_COMMAND_TYPE_TREE
+                                                         | interface::STREAM_FEATURE_CONTEXT_MAP_RLE
+                                                         | interface::STREAM_FEATURE_SPARSE_CONTEXT_MAP);
+        ret.codec.set_framing_tag(opt.framing_tag);
+        ret.codec.set_checksum_type(opt.checksum);
+        if let Some(alignment) = opt.frame_alignment {
+            coding_features |= interface::STREAM_FEATURE_MUX_PADDING;
+            ret.codec.set_frame_alignment(alignment as usize);
+        }
+        if let Some(key) = opt.authentication_key {
+            coding_features |= interface::STREAM_FEATURE_AUTHENTICATED;
+            ret.codec.set_authentication_key(&key[..]);
+        }
+        if opt.distance_bucketing.is_identity() {
+            ret.codec.set_stream_features(coding_features);
+        } else {
+            ret.codec.set_stream_features(coding_features | interface::STREAM_FEATURE_DISTANCE_BUCKETING);
+            ret.codec.set_distance_bucketing(opt.distance_bucketing);
+        }
+        ret.codec.set_mixer_warm_start(opt.mixer_warm_start);
+        ret.codec.set_compact_priors(opt.compact_priors);
+        if opt.freeze_adaptation_after.is_some() {
+            ret.codec.set_extended_stream_features(opt.extended_stream_features | interface::EXTENDED_FEATURE_FREEZE_ADAPTATION);
+        } else {
+            ret.codec.set_extended_stream_features(opt.extended_stream_features);
+        }
+        ret.codec.set_freeze_adaptation_after(opt.freeze_adaptation_after);
+        ret.codec.set_priors_reset_interval(opt.priors_reset_interval);
+        ret.codec.set_extended_stream_features2(opt.extended_stream_features2);
         if let Some(prediction_mode) = opt.force_literal_context_mode {
             brotli::enc::encode::BrotliEncoderSetParameter(
                 &mut ret.brotli_encoder,
@@ -452,9 +761,42 @@ struct ThawingSliceArray<'a>(&'a [brotli::interface::Command<brotli::SliceOffset
 
 impl<'a> CommandArray for ThawingSliceArray<'a> {
     fn get_input_command(&self, offset:usize) -> Command<brotli::InputReference> {
-        brotli::thaw_pair(&self.0[offset], &self.1)
+        interface::thaw_pair(&self.0[offset], &self.1)
     }
     fn len(&self) -> usize {
         self.0.len()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{coalesce_copies, brotli, CopyCommand};
+    use brotli::interface::{Command, LiteralBlockSwitch};
+    fn copy(num_bytes: u32, distance: u32) -> Command<brotli::SliceOffset> {
+        Command::Copy(CopyCommand{num_bytes: num_bytes, distance: distance})
+    }
+    #[test]
+    fn test_coalesce_copies_across_metablocks() {
+        let bl = Command::BlockSwitchLiteral(LiteralBlockSwitch::new(0, 0));
+        let mut pending = None;
+        let first = [bl, copy(1000, 8), copy(1 << 23, 1000)];
+        let (split, lead, resume, end) = coalesce_copies(&mut pending, &first[..]);
+        assert!(lead.is_none());
+        assert_eq!((split, resume, end), (0, 0, 2));
+        let second = [bl, copy(1 << 23, 1000)];
+        let (split, lead, resume, end) = coalesce_copies(&mut pending, &second[..]);
+        assert!(lead.is_none());
+        assert_eq!((split, resume, end), (1, 2, 2)); // only the block switch is encoded
+        assert_eq!(pending.unwrap().num_bytes, 1 << 24);
+        let third = [bl, copy(77, 1000), copy(4, 3)];
+        let (split, lead, resume, end) = coalesce_copies(&mut pending, &third[..]);
+        assert_eq!((split, resume, end), (1, 2, 2));
+        assert_eq!(lead.unwrap().num_bytes, (1 << 24) + 77);
+        assert_eq!(pending.unwrap().distance, 3);
+        let fourth = [bl, copy(5, 9)]; // different distance: emit the held copy first
+        let (split, lead, resume, end) = coalesce_copies(&mut pending, &fourth[..]);
+        assert_eq!((split, resume, end), (0, 0, 1));
+        assert_eq!(lead.unwrap().distance, 3);
+        assert_eq!(pending.unwrap().distance, 9);
+    }
+}
@@ -0,0 +1,163 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Compression under a time budget: the brotli hybrid compressor starts with the most expensive
+// literal modeling and steps down whenever the ns/byte measured so far projects past the budget.
+// Each prediction mode command carries the modeling it selects, so any mix of levels decodes.
+#![cfg(feature="std")]
+use std::time::Instant;
+use std::vec::Vec;
+use interface::{Compressor, DivansCompressorOptions, DivansResult, DivansOutputResult, ErrMsg};
+use writer::new_brotli_hybrid_compressor;
+use error::Error;
+
+// (dynamic_context_mixing, use_context_map) from the best ratio to the cheapest
+pub const BUDGET_LEVELS: [(u8, bool); 3] = [(2, true), (1, true), (0, false)];
+// input handed to the compressor between measurements
+const BUDGET_CHUNK_SIZE: usize = 1 << 16;
+// metablocks are kept small so a downgrade takes effect soon after it is decided
+const BUDGET_LGBLOCK: u32 = 16;
+
+pub struct BudgetGovernor {
+    budget_ns: u64,
+    level: usize,
+    level_start_ns: u64,
+    level_start_bytes: usize,
+}
+
+impl BudgetGovernor {
+    pub fn new(cpu_millis: u64) -> Self {
+        BudgetGovernor {
+            budget_ns: cpu_millis.saturating_mul(1_000_000),
+            level: 0,
+            level_start_ns: 0,
+            level_start_bytes: 0,
+        }
+    }
+    // index into BUDGET_LEVELS
+    pub fn level(&self) -> usize {
+        self.level
+    }
+    // Projects the total time from the rate measured since the last level change and returns the
+    // next level down if that exceeds the budget.
+    pub fn observe(&mut self, elapsed_ns: u64, bytes_done: usize, bytes_total: usize) -> Option<usize> {
+        if self.level + 1 == BUDGET_LEVELS.len() || bytes_done <= self.level_start_bytes {
+            return None;
+        }
+        let level_ns = u128::from(elapsed_ns.saturating_sub(self.level_start_ns));
+        let level_bytes = (bytes_done - self.level_start_bytes) as u128;
+        let remaining = bytes_total.saturating_sub(bytes_done) as u128;
+        let projected = u128::from(elapsed_ns) + level_ns * remaining / level_bytes;
+        if projected <= u128::from(self.budget_ns) {
+            return None;
+        }
+        self.level += 1;
+        self.level_start_ns = elapsed_ns;
+        self.level_start_bytes = bytes_done;
+        Some(self.level)
+    }
+}
+
+fn elapsed_ns(start: &Instant) -> u64 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs().saturating_mul(1_000_000_000).saturating_add(u64::from(elapsed.subsec_nanos()))
+}
+
+// Compresses input with the brotli hybrid compressor, trading ratio for speed as needed to
+// finish within cpu_millis of wall clock time.  Once the cheapest level is reached it is kept
+// even if the projection remains over budget.
+pub fn encode_with_budget(input: &[u8], cpu_millis: u64) -> Result<Vec<u8>, Error> {
+    let mut opts = DivansCompressorOptions::default();
+    opts.quality = Some(9);
+    opts.lgblock = Some(BUDGET_LGBLOCK);
+    opts.dynamic_context_mixing = Some(BUDGET_LEVELS[0].0);
+    opts.use_context_map = BUDGET_LEVELS[0].1;
+    let mut compressor = new_brotli_hybrid_compressor(opts);
+    let mut governor = BudgetGovernor::new(cpu_millis);
+    let start = Instant::now();
+    let mut input_offset = 0usize;
+    let mut unused = 0usize;
+    let mut result = Ok(());
+    while result.is_ok() && input_offset < input.len() {
+        let chunk_end = core::cmp::min(input.len(), input_offset + BUDGET_CHUNK_SIZE);
+        match compressor.encode(&input[..chunk_end], &mut input_offset, &mut [], &mut unused) {
+            DivansResult::NeedsMoreInput | DivansResult::Success => {},
+            DivansResult::NeedsMoreOutput => result = Err(ErrMsg::BrotliInternalEncodeStreamNeedsOutputWithoutFlush),
+            DivansResult::Failure(m) => result = Err(m),
+        }
+        if let Some(level) = governor.observe(elapsed_ns(&start), input_offset, input.len()) {
+            compressor.set_literal_modeling(BUDGET_LEVELS[level].0, BUDGET_LEVELS[level].1);
+        }
+    }
+    let mut ret = Vec::<u8>::new();
+    let mut buffer = [0u8; 4096];
+    while result.is_ok() {
+        let mut offset = 0usize;
+        let res = compressor.flush(&mut buffer[..], &mut offset);
+        ret.extend(&buffer[..offset]);
+        match res {
+            DivansOutputResult::Success => break,
+            DivansOutputResult::NeedsMoreOutput => {},
+            DivansOutputResult::Failure(m) => result = Err(m),
+        }
+    }
+    compressor.free();
+    let output_len = ret.len() as u64;
+    result.map(|_| ret).map_err(|m| Error::from(m).in_state("compressing").at(input_offset as u64, output_len))
+}
+
+#[cfg(test)]
+#[cfg(feature="decoder")]
+mod test {
+    use std::io::Write;
+    use std::vec::Vec;
+    use super::{BudgetGovernor, BUDGET_LEVELS, encode_with_budget};
+    use ::DivansDecompressorWriter;
+
+    fn decompress(compressed: &[u8]) -> Vec<u8> {
+        let mut ret = Vec::<u8>::new();
+        {
+            let mut writer = DivansDecompressorWriter::new(&mut ret, 4096, false, false);
+            writer.write_all(compressed).unwrap();
+            writer.flush().unwrap();
+        }
+        ret
+    }
+
+    #[test]
+    fn test_governor_downgrades_on_projection() {
+        let mut governor = BudgetGovernor::new(100);
+        // 10ms for the first quarter projects 40ms: within budget
+        assert_eq!(governor.observe(10_000_000, 1000, 4000), None);
+        // 60ms for half projects 120ms
+        assert_eq!(governor.observe(60_000_000, 2000, 4000), Some(1));
+        assert_eq!(governor.level(), 1);
+        // only the time since the downgrade counts toward the new rate: 65 + 5 * 1500/500 = 80ms
+        assert_eq!(governor.observe(65_000_000, 2500, 4000), None);
+        assert_eq!(governor.observe(95_000_000, 3000, 4000), Some(2));
+        assert_eq!(governor.observe(u64::max_value(), 3500, 4000), None);
+        assert_eq!(governor.level(), BUDGET_LEVELS.len() - 1);
+    }
+
+    #[test]
+    fn test_encode_with_budget_roundtrip() {
+        let data = &include_bytes!("../testdata/alice29")[..];
+        let relaxed = encode_with_budget(data, 1 << 40).unwrap();
+        let rushed = encode_with_budget(data, 0).unwrap();
+        assert!(relaxed != rushed);
+        assert_eq!(&decompress(&relaxed[..])[..], &data[..]);
+        assert_eq!(&decompress(&rushed[..])[..], &data[..]);
+        assert_eq!(&decompress(&encode_with_budget(&[], 0).unwrap()[..])[..], &[] as &[u8]);
+    }
+}
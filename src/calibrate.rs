@@ -0,0 +1,211 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Picks options for a caller's data: a sample is compressed under each of CALIBRATION_CANDIDATES
+// and every speed class is handed the smallest output among the candidates fast enough for it.
+// Speed budgets are multiples of the fastest candidate's time, so the answer carries over to
+// machines faster or slower than the one that measured it.
+#![cfg(feature="std")]
+use std::time::Instant;
+use std::vec::Vec;
+use interface::DivansCompressorOptions;
+use codec::StrideSelection;
+use probability::Speed;
+use oneshot::compress;
+use error::Error;
+
+// the sample is cut to this many bytes so calibrating stays quick
+pub const CALIBRATION_SAMPLE_LIMIT: usize = 1 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpeedClass {
+    Fast,
+    Balanced,
+    Max,
+}
+
+impl SpeedClass {
+    // time allowed as a multiple of the fastest candidate's; None is unbounded
+    pub fn budget_multiple(&self) -> Option<u64> {
+        match *self {
+            SpeedClass::Fast => Some(2),
+            SpeedClass::Balanced => Some(4),
+            SpeedClass::Max => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CalibrationCandidate {
+    pub name: &'static str,
+    pub quality: u16,
+    pub dynamic_context_mixing: u8,
+    pub use_context_map: bool,
+    pub force_stride_value: StrideSelection,
+    // the literal adaptation speed of every nibble; None lets the compressor pick
+    pub literal_adaptation: Option<Speed>,
+}
+
+impl CalibrationCandidate {
+    pub fn options(&self) -> DivansCompressorOptions {
+        let mut opts = DivansCompressorOptions::default();
+        opts.quality = Some(self.quality);
+        opts.dynamic_context_mixing = Some(self.dynamic_context_mixing);
+        opts.use_context_map = self.use_context_map;
+        opts.force_stride_value = self.force_stride_value;
+        opts.literal_adaptation = self.literal_adaptation.map(|speed| [speed; 4]);
+        opts
+    }
+}
+
+// from the cheapest literal modeling to the most expensive
+pub const CALIBRATION_CANDIDATES: [CalibrationCandidate; 6] = [
+    CalibrationCandidate{name: "q9-nomix", quality: 9, dynamic_context_mixing: 0, use_context_map: false,
+                         force_stride_value: StrideSelection::PriorDisabled, literal_adaptation: None},
+    CalibrationCandidate{name: "q9-stride", quality: 9, dynamic_context_mixing: 0, use_context_map: false,
+                         force_stride_value: StrideSelection::UseBrotliRec, literal_adaptation: None},
+    CalibrationCandidate{name: "q10-mix1", quality: 10, dynamic_context_mixing: 1, use_context_map: true,
+                         force_stride_value: StrideSelection::PriorDisabled, literal_adaptation: None},
+    CalibrationCandidate{name: "q10-mix1-stride", quality: 10, dynamic_context_mixing: 1, use_context_map: true,
+                         force_stride_value: StrideSelection::UseBrotliRec, literal_adaptation: None},
+    CalibrationCandidate{name: "q11-mix2-stride", quality: 11, dynamic_context_mixing: 2, use_context_map: true,
+                         force_stride_value: StrideSelection::UseBrotliRec, literal_adaptation: None},
+    CalibrationCandidate{name: "q11-mix2-stride-fast", quality: 11, dynamic_context_mixing: 2, use_context_map: true,
+                         force_stride_value: StrideSelection::UseBrotliRec, literal_adaptation: Some(Speed::FAST)},
+];
+
+#[derive(Clone, Copy, Debug)]
+pub struct CalibrationMeasurement {
+    pub candidate: CalibrationCandidate,
+    pub compressed_size: usize,
+    pub elapsed_ns: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct RecommendedOptions {
+    // the sample bytes each candidate compressed
+    pub sample_size: usize,
+    // one per CALIBRATION_CANDIDATES, in the same order
+    pub measurements: Vec<CalibrationMeasurement>,
+    fast: usize,
+    balanced: usize,
+    max: usize,
+}
+
+impl RecommendedOptions {
+    // picks the smallest output within each speed class's budget, the faster candidate on a tie
+    pub fn from_measurements(sample_size: usize, measurements: Vec<CalibrationMeasurement>) -> Self {
+        assert!(!measurements.is_empty());
+        let fastest_ns = measurements.iter().map(|m| m.elapsed_ns).min().unwrap_or(0);
+        let pick = |class: SpeedClass| {
+            let budget_ns = class.budget_multiple().map(|multiple| fastest_ns.saturating_mul(multiple));
+            let mut best: Option<usize> = None;
+            for (index, m) in measurements.iter().enumerate() {
+                if budget_ns.map_or(false, |budget_ns| m.elapsed_ns > budget_ns) {
+                    continue;
+                }
+                best = match best {
+                    Some(b) if (measurements[b].compressed_size, measurements[b].elapsed_ns)
+                        <= (m.compressed_size, m.elapsed_ns) => Some(b),
+                    _ => Some(index),
+                };
+            }
+            best.unwrap()
+        };
+        let (fast, balanced, max) = (pick(SpeedClass::Fast), pick(SpeedClass::Balanced), pick(SpeedClass::Max));
+        RecommendedOptions {
+            sample_size,
+            measurements,
+            fast,
+            balanced,
+            max,
+        }
+    }
+    pub fn measurement(&self, class: SpeedClass) -> &CalibrationMeasurement {
+        &self.measurements[match class {
+            SpeedClass::Fast => self.fast,
+            SpeedClass::Balanced => self.balanced,
+            SpeedClass::Max => self.max,
+        }]
+    }
+    pub fn options(&self, class: SpeedClass) -> DivansCompressorOptions {
+        self.measurement(class).candidate.options()
+    }
+}
+
+fn elapsed_ns(start: &Instant) -> u64 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs().saturating_mul(1_000_000_000).saturating_add(u64::from(elapsed.subsec_nanos()))
+}
+
+// Compresses up to CALIBRATION_SAMPLE_LIMIT bytes of sample under every candidate and recommends
+// options for each speed class.  Timings are wall clock, so run it on an otherwise idle machine.
+pub fn calibrate(sample: &[u8]) -> Result<RecommendedOptions, Error> {
+    let sample = &sample[..core::cmp::min(sample.len(), CALIBRATION_SAMPLE_LIMIT)];
+    let mut measurements = Vec::<CalibrationMeasurement>::with_capacity(CALIBRATION_CANDIDATES.len());
+    for candidate in CALIBRATION_CANDIDATES.iter() {
+        let start = Instant::now();
+        let compressed = compress(sample, candidate.options())?;
+        measurements.push(CalibrationMeasurement {
+            candidate: *candidate,
+            compressed_size: compressed.len(),
+            elapsed_ns: elapsed_ns(&start),
+        });
+    }
+    Ok(RecommendedOptions::from_measurements(sample.len(), measurements))
+}
+
+#[cfg(test)]
+#[cfg(feature="decoder")]
+mod test {
+    use std::vec::Vec;
+    use super::{calibrate, CalibrationMeasurement, RecommendedOptions, SpeedClass, CALIBRATION_CANDIDATES};
+    use oneshot::{compress, decompress};
+
+    fn measured(sizes_and_ns: &[(usize, u64)]) -> Vec<CalibrationMeasurement> {
+        sizes_and_ns.iter().zip(CALIBRATION_CANDIDATES.iter()).map(|(&(compressed_size, elapsed_ns), candidate)| {
+            CalibrationMeasurement{candidate: *candidate, compressed_size, elapsed_ns}
+        }).collect()
+    }
+
+    #[test]
+    fn test_recommendation_within_budget() {
+        let recommended = RecommendedOptions::from_measurements(1000, measured(
+            &[(500, 100), (480, 150), (450, 300), (450, 250), (400, 1000), (420, 900)]));
+        // 200ns allowed: only the first two
+        assert_eq!(recommended.measurement(SpeedClass::Fast).compressed_size, 480);
+        // 400ns allowed: the faster of the two 450 byte outputs
+        assert_eq!(recommended.measurement(SpeedClass::Balanced).elapsed_ns, 250);
+        assert_eq!(recommended.options(SpeedClass::Balanced), CALIBRATION_CANDIDATES[3].options());
+        assert_eq!(recommended.measurement(SpeedClass::Max).compressed_size, 400);
+    }
+
+    #[test]
+    fn test_calibrate() {
+        let sample = &include_bytes!("../testdata/alice29")[..32768];
+        let recommended = calibrate(sample).unwrap();
+        assert_eq!(recommended.sample_size, sample.len());
+        assert_eq!(recommended.measurements.len(), CALIBRATION_CANDIDATES.len());
+        // timings vary from run to run, but a wider budget never recommends a larger output
+        let fast = recommended.measurement(SpeedClass::Fast).compressed_size;
+        let balanced = recommended.measurement(SpeedClass::Balanced).compressed_size;
+        let max = recommended.measurement(SpeedClass::Max).compressed_size;
+        assert!(max <= balanced && balanced <= fast);
+        assert_eq!(max, recommended.measurements.iter().map(|m| m.compressed_size).min().unwrap());
+        for class in [SpeedClass::Fast, SpeedClass::Balanced, SpeedClass::Max].iter() {
+            let compressed = compress(sample, recommended.options(*class)).unwrap();
+            assert_eq!(decompress(&compressed[..]).unwrap(), &sample[..]);
+        }
+    }
+}
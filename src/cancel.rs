@@ -0,0 +1,212 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Lets another thread stop a long encode or decode without killing the process.
+// The codec looks at the token before each command and fails with ErrMsg::Cancelled instead of
+// starting it, so nothing is half coded: once the token is reset the same call picks up where it
+// stopped, or the codec may simply be freed.
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature="std")]
+use std::sync::Arc;
+
+#[derive(Clone, Debug)]
+enum Flag {
+    Static(&'static AtomicBool),
+    #[cfg(feature="std")]
+    Shared(Arc<AtomicBool>),
+}
+
+// the default token is never cancelled
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    flag: Option<Flag>,
+}
+
+impl CancelToken {
+    // a token of its own; clones share it
+    #[cfg(feature="std")]
+    pub fn new() -> Self {
+        CancelToken {
+            flag: Some(Flag::Shared(Arc::new(AtomicBool::new(false)))),
+        }
+    }
+    // a token that follows flag, eg. a static set by a signal handler
+    pub fn from_static(flag: &'static AtomicBool) -> Self {
+        CancelToken {
+            flag: Some(Flag::Static(flag)),
+        }
+    }
+    fn flag(&self) -> Option<&AtomicBool> {
+        match self.flag {
+            Some(Flag::Static(flag)) => Some(flag),
+            #[cfg(feature="std")]
+            Some(Flag::Shared(ref flag)) => Some(flag),
+            None => None,
+        }
+    }
+    pub fn cancel(&self) {
+        if let Some(flag) = self.flag() {
+            flag.store(true, Ordering::Relaxed);
+        }
+    }
+    // lets a cancelled codec carry on
+    pub fn reset(&self) {
+        if let Some(flag) = self.flag() {
+            flag.store(false, Ordering::Relaxed);
+        }
+    }
+    #[inline(always)]
+    pub fn is_cancelled(&self) -> bool {
+        match self.flag() {
+            Some(flag) => flag.load(Ordering::Relaxed),
+            None => false,
+        }
+    }
+}
+
+#[cfg(all(test, feature="std", feature="encoder", feature="decoder"))]
+mod test {
+    use std::vec::Vec;
+    use alloc_stdlib::HeapAlloc;
+    use conformance::{conformance_commands, conformance_expected_output};
+    use writer::new_brotli_hybrid_compressor;
+    use super::CancelToken;
+    use divans_compressor::DivansCompressorFactoryStruct;
+    use interface::{
+        Compressor,
+        Decompressor,
+        DivansCompressorFactory,
+        DivansCompressorOptions,
+        DivansOutputResult,
+        DivansResult,
+        ErrMsg,
+        DefaultCDF16,
+    };
+    use test_support::{new_decompressor, compress_hybrid, decode};
+
+    // encodes input 4096 bytes a call, cancelling the token before call cancel_at and resetting it
+    // once a call fails; returns the stream and whether one did
+    fn encode_cancelled_once<C:Compressor>(compressor: &mut C, token: &CancelToken, input: &[u8], cancel_at: usize) -> (Vec<u8>, bool) {
+        let mut stream = Vec::<u8>::new();
+        let mut buffer = [0u8; 4096];
+        let mut input_offset = 0usize;
+        let mut calls = 0usize;
+        let mut cancelled = false;
+        while input_offset != input.len() {
+            if calls == cancel_at {
+                token.cancel();
+            }
+            calls += 1;
+            let mut offset = 0usize;
+            let end = core::cmp::min(input.len(), input_offset + 4096);
+            let res = compressor.encode(&input[..end], &mut input_offset, &mut buffer[..], &mut offset);
+            stream.extend(&buffer[..offset]);
+            match res {
+                DivansResult::Failure(ErrMsg::Cancelled) => {
+                    cancelled = true;
+                    token.reset();
+                },
+                DivansResult::Failure(m) => panic!("{:?}", m),
+                _ => {},
+            }
+        }
+        loop {
+            let mut offset = 0usize;
+            let res = compressor.flush(&mut buffer[..], &mut offset);
+            stream.extend(&buffer[..offset]);
+            match res {
+                DivansOutputResult::Success => break,
+                DivansOutputResult::NeedsMoreOutput => {},
+                DivansOutputResult::Failure(ErrMsg::Cancelled) => {
+                    cancelled = true;
+                    token.reset();
+                },
+                DivansOutputResult::Failure(m) => panic!("{:?}", m),
+            }
+        }
+        (stream, cancelled)
+    }
+
+    #[test]
+    fn test_cancel_token() {
+        let mut input = Vec::<u8>::new();
+        let mut seed = 0x2545f491u32;
+        for name in ["mixed", "dict", "copy", "mixed"].iter().cycle().take(64) {
+            input.extend(conformance_expected_output(&conformance_commands(name)[..]).unwrap());
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            input.push((seed >> 24) as u8);
+        }
+        let opts = DivansCompressorOptions::default();
+        // a cancelled compressor carries on where it stopped once the token is reset
+        let token = CancelToken::new();
+        let mut streams = Vec::<Vec<u8>>::new();
+        for cancel_at in [usize::MAX, 8].iter() {
+            let mut compressor = DivansCompressorFactoryStruct::<HeapAlloc<u8>, HeapAlloc<DefaultCDF16>>::new(
+                HeapAlloc::<u8>::new(0),
+                HeapAlloc::<u32>::new(0),
+                HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+                opts,
+                ());
+            compressor.set_cancel_token(token.clone());
+            let (stream, cancelled) = encode_cancelled_once(&mut compressor, &token, &input[..], *cancel_at);
+            assert_eq!(cancelled, *cancel_at != usize::MAX);
+            compressor.free();
+            streams.push(stream);
+        }
+        assert_eq!(streams[0], streams[1]);
+        let stream = streams.pop().unwrap();
+        assert_eq!(decode(&stream[..]).unwrap(), input);
+        let mut hybrid = new_brotli_hybrid_compressor(opts);
+        hybrid.set_cancel_token(token.clone());
+        let (hybrid_stream, cancelled) = encode_cancelled_once(&mut hybrid, &token, &input[..], 8);
+        assert!(cancelled);
+        hybrid.free();
+        assert_eq!(hybrid_stream, compress_hybrid(&input[..], opts));
+        for multithread in [false, true].iter() {
+            let token = CancelToken::new();
+            let mut decompressor = new_decompressor(false, *multithread);
+            decompressor.set_cancel_token(token.clone()).unwrap();
+            let mut out = Vec::<u8>::new();
+            let mut buffer = [0u8; 256];
+            let mut input_offset = 0usize;
+            let mut cancelled = false;
+            loop {
+                if out.len() > input.len() / 2 && !cancelled {
+                    token.cancel();
+                }
+                let mut output_offset = 0usize;
+                let res = decompressor.decode(&stream[..], &mut input_offset, &mut buffer[..], &mut output_offset);
+                out.extend(&buffer[..output_offset]);
+                match res {
+                    DivansResult::Success => break,
+                    DivansResult::NeedsMoreOutput => {},
+                    DivansResult::NeedsMoreInput => panic!("truncated stream"),
+                    DivansResult::Failure(ErrMsg::Cancelled) => {
+                        cancelled = true;
+                        if *multithread {
+                            break; // the worker is gone
+                        }
+                        token.reset();
+                    },
+                    DivansResult::Failure(m) => panic!("{:?}", m),
+                }
+            }
+            assert!(cancelled);
+            if !*multithread {
+                assert_eq!(out, input);
+            }
+            decompressor.free();
+        }
+    }
+}
@@ -19,6 +19,7 @@ use codec::EncoderOrDecoderSpecialization;
 use super::interface::{CopyCommand,DictCommand,LiteralCommand,Command};
 
 use slice_util::AllocatedMemoryPrefix;
+#[derive(Clone)]
 pub struct EncoderSpecialization {
     backing: [u8; 128],
     max_size: usize,
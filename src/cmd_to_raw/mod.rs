@@ -24,7 +24,7 @@ mod test;
 
 #[derive(Clone)]
 pub struct RingBufferIndexState {
-    total_offset: usize,
+    total_offset: u64,
     input_sub_offset: usize,
     ring_buffer_decode_index: u32,
     ring_buffer_output_index: u32,
@@ -33,6 +33,12 @@ pub struct RingBufferIndexState {
 pub struct DivansRecodeState<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>>{
     pub ring_buffer: RingBuffer,
     state: RingBufferIndexState,
+    // While speculating, the window bytes overwritten since the snapshot, in the order written from
+    // undo_start on.  The buffer is kept from one speculation to the next.
+    undo: Option<RingBuffer>,
+    undo_start: u32,
+    undo_len: u32,
+    speculating: bool,
 }
 
 const REPEAT_BUFFER_MAX_SIZE: u32 = 64;
@@ -53,18 +59,88 @@ impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>> DivansRecodeState<RingB
                 input_sub_offset: 0,
                 total_offset:0,
             },
+            undo: None,
+            undo_start: 0,
+            undo_len: 0,
+            speculating: false,
         }
     }
     pub fn snapshot_ringbuffer(&self) -> RingBufferSnapshot {
         self.state.clone()
     }
+    // Puts back the indices of the snapshot and, while speculating from it, the window bytes written over since.
     pub fn restore_ringbuffer_to_snapshot(&mut self, data: RingBufferSnapshot) {
+        if self.speculating {
+            let mask = self.ring_buffer.slice().len() as u32 - 1;
+            if let Some(ref undo) = self.undo {
+                let mut restored = 0u32;
+                while restored < self.undo_len {
+                    let index = ((self.undo_start + restored) & mask) as usize;
+                    let count = core::cmp::min(self.undo_len - restored, mask + 1 - index as u32) as usize;
+                    self.ring_buffer.slice_mut()[index..index + count].clone_from_slice(
+                        &undo.slice()[restored as usize..restored as usize + count]);
+                    restored += count as u32;
+                }
+            }
+            self.undo_start = data.ring_buffer_decode_index;
+            self.undo_len = 0;
+        }
         self.state = data;
     }
+    // A snapshot to trial-encode from: until end_speculation, bytes are copied out of the window as
+    // writes first reach them, so restoring to it costs only what the trials overwrote.  The copies go
+    // to a buffer the size of the ring buffer, allocated from m8 by the first speculation.
+    pub fn begin_speculation<AllocU8:Allocator<u8, AllocatedMemory=RingBuffer>>(&mut self, m8: &mut AllocU8) -> RingBufferSnapshot {
+        if self.undo.is_none() {
+            self.undo = Some(m8.alloc_cell(self.ring_buffer.slice().len()));
+        }
+        self.undo_start = self.state.ring_buffer_decode_index;
+        self.undo_len = 0;
+        self.speculating = true;
+        self.snapshot_ringbuffer()
+    }
+    // keeps whatever was written since, which the snapshot can no longer be restored over
+    pub fn end_speculation(&mut self) {
+        self.speculating = false;
+    }
+    pub fn free_speculation<AllocU8:Allocator<u8, AllocatedMemory=RingBuffer>>(&mut self, m8: &mut AllocU8) {
+        self.speculating = false;
+        if let Some(undo) = self.undo.take() {
+            m8.free_cell(undo);
+        }
+    }
+    // copies out the window bytes the next amount written at the decode index will overwrite, if not already
+    #[inline(always)]
+    fn save_window(&mut self, amount: u32) {
+        if !self.speculating {
+            return;
+        }
+        let len = self.ring_buffer.slice().len() as u32;
+        // exact until the whole window has been saved, as nothing is written before it is saved
+        let written = (self.state.ring_buffer_decode_index + len - self.undo_start) & (len - 1);
+        let target = core::cmp::min(len, written + amount);
+        if let Some(ref mut undo) = self.undo {
+            while self.undo_len < target {
+                let index = ((self.undo_start + self.undo_len) & (len - 1)) as usize;
+                let count = core::cmp::min(target - self.undo_len, len - index as u32) as usize;
+                undo.slice_mut()[self.undo_len as usize..self.undo_len as usize + count].clone_from_slice(
+                    &self.ring_buffer.slice()[index..index + count]);
+                self.undo_len += count as u32;
+            }
+        }
+    }
     #[inline(always)]
-    pub fn num_bytes_encoded(&self) -> usize {
+    pub fn num_bytes_encoded(&self) -> u64 {
         self.state.total_offset
     }
+    // bytes in the ring buffer that flush has yet to write out
+    pub fn bytes_pending(&self) -> usize {
+        let len = self.ring_buffer.slice().len();
+        if len == 0 {
+            return 0;
+        }
+        (self.state.ring_buffer_decode_index as usize + len - self.state.ring_buffer_output_index as usize) & (len - 1)
+    }
     #[cold]
     fn fallback_last_8_literals(&self) -> [u8; 8] {
         let len = self.ring_buffer.slice().len();
@@ -74,6 +150,14 @@ impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>> DivansRecodeState<RingB
         }
         ret
     }
+    // the byte a copy of this distance would produce next, if the ring buffer reaches back that far
+    pub fn continuation_byte(&self, distance: u32) -> Option<u8> {
+        let len = self.ring_buffer.slice().len();
+        if distance == 0 || distance as usize > len {
+            return None;
+        }
+        Some(self.ring_buffer.slice()[(self.state.ring_buffer_decode_index as usize + len - distance as usize) & (len - 1)])
+    }
     #[inline(always)]
     pub fn last_8_literals(&self) -> [u8; 8] {
         if self.state.ring_buffer_decode_index < 8 {
@@ -113,7 +197,7 @@ impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>> DivansRecodeState<RingB
                self.state.ring_buffer_output_index = 0;
             }           
         }
-        self.state.total_offset += *output_offset - prev_output_offset;
+        self.state.total_offset += (*output_offset - prev_output_offset) as u64;
         if self.state.ring_buffer_output_index != self.state.ring_buffer_decode_index {
             return DivansOutputResult::NeedsMoreOutput;
         }
@@ -170,6 +254,7 @@ impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>> DivansRecodeState<RingB
         let mut trunc_amount_to_copy = core::cmp::min(core::cmp::min(left_dst_before_wrap,
                                                                  left_src_before_wrap),
                                                   desired_amount_to_copy);
+        self.save_window(trunc_amount_to_copy);
         if src_distance_index < self.state.ring_buffer_decode_index {
             let (_unused, src_and_dst) = self.ring_buffer.slice_mut().split_at_mut(src_distance_index as usize);
             let (src, dst) = src_and_dst.split_at_mut((self.state.ring_buffer_decode_index - src_distance_index) as usize);
@@ -192,6 +277,7 @@ impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>> DivansRecodeState<RingB
     #[inline(always)]
     fn copy_to_ring_buffer(&mut self, mut data: &[u8]) -> usize {
         data = data.split_at(core::cmp::min(data.len() as u32, self.decode_space_left_in_ring_buffer()) as usize).0;
+        self.save_window(data.len() as u32);
         let mut retval = 0usize;
         let first_section = self.ring_buffer.slice_mut().len() as u32 - self.state.ring_buffer_decode_index;
         let amount_to_copy = core::cmp::min(data.len() as u32, first_section);
@@ -232,6 +318,7 @@ impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>> DivansRecodeState<RingB
             let mut src = self.state.ring_buffer_decode_index + self.ring_buffer.slice().len() as u32 - copy.distance;
             src &= self.ring_buffer.slice().len() as u32 - 1;
             let src_val = self.ring_buffer.slice()[src as usize];
+            self.save_window(1);
             self.ring_buffer.slice_mut()[self.state.ring_buffer_decode_index as usize] = src_val;
             self.state.ring_buffer_decode_index += 1;
             if self.state.ring_buffer_decode_index == self.ring_buffer.slice().len() as u32 {
@@ -324,6 +411,22 @@ impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>> DivansRecodeState<RingB
               | &Command::BlockSwitchLiteral(_) => DivansOutputResult::Success,
         }
     }
+    // encode_cmd for a window nobody writes out, as kept by a worker that decodes literals: the
+    // bytes flush would have written are dropped instead
+    pub fn replay_cmd<SliceType:SliceWrapper<u8>>(&mut self, cmd:&Command<SliceType>) -> DivansOutputResult {
+        loop {
+            match self.parse_command(cmd) {
+                DivansOutputResult::NeedsMoreOutput => {
+                    self.state.total_offset += self.bytes_pending() as u64;
+                    self.state.ring_buffer_output_index = self.state.ring_buffer_decode_index;
+                },
+                res => {
+                    self.state.input_sub_offset = 0;
+                    return res;
+                },
+            }
+        }
+    }
     #[cfg_attr(not(feature="no-inline"), inline(always))]
     pub fn encode_cmd<SliceType:SliceWrapper<u8>>(&mut self,
                   cmd:&Command<SliceType>,
@@ -361,6 +464,49 @@ impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>> DivansRecodeState<RingB
         }
     }
 }
+// Replays divans commands (for instance an IR dump) into the bytes they describe without running
+// the arithmetic codec. The ring buffer length must be a power of two no smaller than the window the
+// commands were produced with, since copies and dictionary words are resolved against it.
+pub struct CommandToRawConverter<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>> {
+    recoder: DivansRecodeState<RingBuffer>,
+}
+
+impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>> CommandToRawConverter<RingBuffer> {
+    pub fn new(ring_buffer: RingBuffer) -> Self {
+        CommandToRawConverter {
+            recoder: DivansRecodeState::new(ring_buffer),
+        }
+    }
+    // Applies cmd, writing bytes that are about to leave the window to output.
+    // On NeedsMoreOutput the same command must be passed again once output has room;
+    // prediction mode and block switch commands produce no bytes.
+    pub fn encode_cmd<SliceType:SliceWrapper<u8>>(&mut self,
+                                                  cmd: &Command<SliceType>,
+                                                  output: &mut [u8],
+                                                  output_offset: &mut usize) -> DivansOutputResult {
+        self.recoder.encode_cmd(cmd, output, output_offset)
+    }
+    // writes out everything decoded so far; call until it returns Success after the last command
+    pub fn flush(&mut self, output: &mut [u8], output_offset: &mut usize) -> DivansOutputResult {
+        self.recoder.flush(output, output_offset)
+    }
+    // the 8 bytes most recently produced, used as literal context by the codec
+    pub fn last_8_literals(&self) -> [u8; 8] {
+        self.recoder.last_8_literals()
+    }
+    // total bytes written to output so far
+    pub fn num_bytes_encoded(&self) -> u64 {
+        self.recoder.num_bytes_encoded()
+    }
+    // bytes produced by the commands so far that flush has yet to write out
+    pub fn bytes_pending(&self) -> usize {
+        self.recoder.bytes_pending()
+    }
+    pub fn into_ring_buffer(self) -> RingBuffer {
+        self.recoder.ring_buffer
+    }
+}
+
 impl<RingBuffer:SliceWrapperMut<u8> + SliceWrapper<u8> + Default> Compressor for DivansRecodeState<RingBuffer> {
     fn encode(&mut self, input:&[u8], input_offset: &mut usize, output: &mut [u8], output_offset: &mut usize) -> DivansResult {
        let amt_to_copy = core::cmp::min(input.len() - *input_offset, output.len() - *output_offset);
@@ -408,5 +554,9 @@ impl<RingBuffer:SliceWrapperMut<u8> + SliceWrapper<u8> + Default> Compressor for
              _output:&mut[u8],
              _output_offset:&mut usize)->DivansOutputResult{
         DivansOutputResult::Success
+    }    fn sync_flush(&mut self,
+                  _output:&mut[u8],
+                  _output_offset:&mut usize)->DivansOutputResult{
+        DivansOutputResult::Success
     }
 }
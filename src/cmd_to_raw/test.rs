@@ -13,6 +13,8 @@
 //   limitations under the License.
 
 #![cfg(test)]
+extern crate std;
+use std::vec::Vec;
 
 use super::super::SliceWrapper;
 use super::super::{DivansOutputResult};
@@ -35,15 +37,7 @@ impl super::SliceWrapper<u8> for ExRingBuffer {
 }
 #[allow(unused)]
 fn make_ring_buffer_state() -> super::DivansRecodeState<ExRingBuffer>{
-    super::DivansRecodeState{
-        state:super::RingBufferIndexState{
-            total_offset:0,
-            input_sub_offset: 0,
-            ring_buffer_decode_index:0,
-            ring_buffer_output_index:0,
-        },
-        ring_buffer: ExRingBuffer::default(),
-    }
+    super::DivansRecodeState::new(ExRingBuffer::default())
 }
 #[allow(unused)]
 fn help_ring_buffer_dict(mut state: super::DivansRecodeState<ExRingBuffer>) -> super::DivansRecodeState<ExRingBuffer>{
@@ -388,3 +382,89 @@ fn test_insert_huge() {
     }
     help_test_insert(state, &values[..]);
 }
+
+struct ExRingBufferAlloc;
+impl super::Allocator<u8> for ExRingBufferAlloc {
+    type AllocatedMemory = ExRingBuffer;
+    fn alloc_cell(&mut self, len: usize) -> ExRingBuffer {
+        assert_eq!(len, TEST_RING_SIZE);
+        ExRingBuffer::default()
+    }
+    fn free_cell(&mut self, _data: ExRingBuffer) {}
+}
+#[test]
+fn test_speculation_restores_window() {
+    let mut state = help_ring_buffer_dict(make_ring_buffer_state());
+    let mut m8 = ExRingBufferAlloc;
+    let snapshot = state.begin_speculation(&mut m8);
+    let mut discard = [0u8; TEST_RING_SIZE];
+    // a short trial, then one that writes over the whole window and wraps
+    for trial_len in [29usize, 300].iter() {
+        let mut written = 0;
+        while written < *trial_len {
+            let mut oindex = 0;
+            state.flush(&mut discard[..], &mut oindex);
+            state.state.input_sub_offset = 0;
+            let trial = [(written & 0xff) as u8; 16];
+            written += state.copy_to_ring_buffer(&trial[..core::cmp::min(16, *trial_len - written)]);
+        }
+        state.restore_ringbuffer_to_snapshot(snapshot.clone());
+        assert_eq!(&state.ring_buffer.0[..], &make_ring_buffer_state_history()[..]);
+    }
+    state.end_speculation();
+    let mut prev_buffer = [0u8; TEST_RING_SIZE];
+    prev_buffer.clone_from_slice(&HISTORY_OF_DICT_TEST[..]);
+    state = help_copy_far(state, &mut prev_buffer[..]);
+    state.free_speculation(&mut m8);
+}
+// the ring buffer as help_ring_buffer_dict leaves it
+fn make_ring_buffer_state_history() -> [u8; TEST_RING_SIZE] {
+    help_ring_buffer_dict(make_ring_buffer_state()).ring_buffer.0
+}
+
+#[test]
+fn test_command_to_raw_converter() {
+    use brotli::InputReference;
+    use super::{CommandToRawConverter, Command, CopyCommand, LiteralCommand, FeatureFlagSliceType};
+    let lit = LiteralCommand::<InputReference> {
+        data: InputReference{data: b"divans", orig_offset: 0},
+        prob: FeatureFlagSliceType::<InputReference>::default(),
+        high_entropy: false,
+    };
+    let cmds = [Command::Literal(lit),
+                Command::Copy(CopyCommand{distance: 6, num_bytes: 200}),
+                Command::Copy(CopyCommand{distance: 1, num_bytes: 3})];
+    let mut expected = b"divans".to_vec();
+    for _ in 0..200 {
+        let b = expected[expected.len() - 6];
+        expected.push(b);
+    }
+    expected.extend(&[expected[expected.len() - 1]; 3]);
+    let mut converter = CommandToRawConverter::<ExRingBuffer>::new(ExRingBuffer::default());
+    let mut output = Vec::<u8>::new();
+    let mut buf = [0u8; 5];
+    for cmd in cmds.iter() {
+        loop {
+            let mut offset = 0usize;
+            let res = converter.encode_cmd(cmd, &mut buf[..], &mut offset);
+            output.extend(&buf[..offset]);
+            match res {
+                DivansOutputResult::Success => break,
+                DivansOutputResult::NeedsMoreOutput => {},
+                DivansOutputResult::Failure(e) => panic!("{:?}", e),
+            }
+        }
+    }
+    loop {
+        let mut offset = 0usize;
+        let res = converter.flush(&mut buf[..], &mut offset);
+        output.extend(&buf[..offset]);
+        match res {
+            DivansOutputResult::Success => break,
+            DivansOutputResult::NeedsMoreOutput => {},
+            DivansOutputResult::Failure(e) => panic!("{:?}", e),
+        }
+    }
+    assert_eq!(output, expected);
+    assert_eq!(converter.num_bytes_encoded(), expected.len() as u64);
+}
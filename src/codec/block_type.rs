@@ -1,4 +1,4 @@
-use interface::{DivansResult, StreamMuxer, StreamDemuxer};
+use interface::{DivansResult, ErrMsg, StreamMuxer, StreamDemuxer};
 use alloc::Allocator;
 use super::interface::{
     EncoderOrDecoderSpecialization,
@@ -11,16 +11,24 @@ use ::interface::{
     CrossCommandBilling,
     BlockSwitch,
     LiteralBlockSwitch,
+    EXTENDED_FEATURE_LITERAL_NIBBLE_ORDER,
+    EXTENDED_FEATURE2_WIDE_BLOCK_TYPES,
+    LITERAL_BLOCK_SWITCH_LSB_FIRST,
 };
 use ::probability::{Speed, CDF16};
 use ::priors::PriorCollection;
-use super::priors::{BlockTypePriorType};
+use super::priors::{BlockTypePriorType, NUM_WIDE_BLOCK_TYPE_NIBBLE_PRIORS};
+
+// the two nibble block type that escapes to a wide one, for EXTENDED_FEATURE2_WIDE_BLOCK_TYPES streams
+const WIDE_BLOCK_TYPE_ESCAPE: u32 = 0xff;
+
 #[derive(Clone,Copy,PartialEq,Eq, Hash, Debug)]
 pub enum BlockTypeState {
     Begin,
     TwoNibbleType,
     FinalNibble(u8),
-    FullyDecoded(u8),
+    WideNibble(u32, u8), // the bits of the varint so far and how many
+    FullyDecoded(u32),
 }
 
 
@@ -47,18 +55,30 @@ impl BlockTypeState {
         block_type_switch_index:usize,
         output_bytes: &mut [u8],
         output_offset: &mut usize) -> DivansResult {
+        let adapt_cdf = superstate.adapt_cdf();
+        let wide = superstate.bk.extended_stream_features2 & EXTENDED_FEATURE2_WIDE_BLOCK_TYPES != 0;
+        if !wide && input_bs.block_type() > 0xff {
+            return DivansResult::Failure(ErrMsg::WideBlockTypesDisabled);
+        }
+        // without wide block types the block type after the greatest seen wraps around as a u8 would
+        let next_block_type = if wide {
+            superstate.bk.btype_max_seen[block_type_switch_index].wrapping_add(1)
+        } else {
+            (superstate.bk.btype_max_seen[block_type_switch_index] + 1) & 0xff
+        };
         let mut varint_nibble:u8 =
             if input_bs.block_type() == superstate.bk.btype_lru[block_type_switch_index][1] {
                 0
-            } else if input_bs.block_type() == superstate.bk.btype_max_seen[block_type_switch_index].wrapping_add(1) {
+            } else if input_bs.block_type() == next_block_type {
                 1
             } else if input_bs.block_type() <= 12 {
-                input_bs.block_type() + 2
+                input_bs.block_type() as u8 + 2
             } else {
                 15
             };
-        let mut first_nibble:u8 = input_bs.block_type() & 0xf;
-        let mut second_nibble:u8 = input_bs.block_type() >> 4;
+        let two_nibble_type = core::cmp::min(input_bs.block_type(), WIDE_BLOCK_TYPE_ESCAPE) as u8;
+        let mut first_nibble:u8 = two_nibble_type & 0xf;
+        let mut second_nibble:u8 = two_nibble_type >> 4;
         loop {
             match superstate.drain_or_fill_internal_buffer_cmd(
                                                            output_bytes,
@@ -72,23 +92,22 @@ impl BlockTypeState {
                     let mut nibble_prob = superstate.bk.btype_priors.get(BlockTypePriorType::Mnemonic,
                                                                          (block_type_switch_index,));
                     superstate.coder.get_or_put_nibble(&mut varint_nibble, nibble_prob, billing);
-                    if superstate.specialization.adapt_cdf() {
+                    if adapt_cdf {
                         nibble_prob.blend(varint_nibble, Speed::SLOW);
                     }
                     match varint_nibble {
                         0 => *self = BlockTypeState::FullyDecoded(
                             superstate.bk.btype_lru[block_type_switch_index][1]),
-                        1 => *self = BlockTypeState::FullyDecoded(
-                            superstate.bk.btype_max_seen[block_type_switch_index].wrapping_add(1)),
+                        1 => *self = BlockTypeState::FullyDecoded(next_block_type),
                         15 => *self = BlockTypeState::TwoNibbleType,
-                        val => *self = BlockTypeState::FullyDecoded(val - 2),
+                        val => *self = BlockTypeState::FullyDecoded(u32::from(val - 2)),
                     }
                 },
                 BlockTypeState::TwoNibbleType => {
                     let mut nibble_prob = superstate.bk.btype_priors.get(BlockTypePriorType::FirstNibble,
                                                                          (block_type_switch_index,));
                     superstate.coder.get_or_put_nibble(&mut first_nibble, nibble_prob, billing);
-                    if superstate.specialization.adapt_cdf() {
+                    if adapt_cdf {
                         nibble_prob.blend(first_nibble, Speed::SLOW);
                     }
                     *self = BlockTypeState::FinalNibble(first_nibble);
@@ -97,10 +116,42 @@ impl BlockTypeState {
                     let mut nibble_prob = superstate.bk.btype_priors.get(BlockTypePriorType::SecondNibble,
                                                                          (block_type_switch_index,));
                     superstate.coder.get_or_put_nibble(&mut second_nibble, nibble_prob, billing);
-                    if superstate.specialization.adapt_cdf() {
+                    if adapt_cdf {
                         nibble_prob.blend(second_nibble, Speed::SLOW);
                     }
-                    *self = BlockTypeState::FullyDecoded((second_nibble << 4) | first_nibble);
+                    let two_nibble_type = u32::from((second_nibble << 4) | first_nibble);
+                    if wide && two_nibble_type == WIDE_BLOCK_TYPE_ESCAPE {
+                        *self = BlockTypeState::WideNibble(0, 0);
+                    } else {
+                        *self = BlockTypeState::FullyDecoded(two_nibble_type);
+                    }
+                }
+                BlockTypeState::WideNibble(bits, shift) => {
+                    if shift >= 32 {
+                        return DivansResult::Failure(ErrMsg::BlockTypeOutOfRange);
+                    }
+                    let past_escape = input_bs.block_type().wrapping_sub(WIDE_BLOCK_TYPE_ESCAPE) >> shift;
+                    let mut wide_nibble = (past_escape & 7) as u8 | if past_escape > 7 { 8 } else { 0 };
+                    let position = core::cmp::min(usize::from(shift / 3), NUM_WIDE_BLOCK_TYPE_NIBBLE_PRIORS - 1);
+                    let nibble_prob = superstate.bk.btype_priors.get(BlockTypePriorType::WideNibble,
+                                                                         (block_type_switch_index, position));
+                    superstate.coder.get_or_put_nibble(&mut wide_nibble, nibble_prob, billing);
+                    if adapt_cdf {
+                        nibble_prob.blend(wide_nibble, Speed::SLOW);
+                    }
+                    let group = u32::from(wide_nibble & 7);
+                    if (group << shift) >> shift != group {
+                        return DivansResult::Failure(ErrMsg::BlockTypeOutOfRange);
+                    }
+                    let bits = bits | (group << shift);
+                    if wide_nibble & 8 != 0 {
+                        *self = BlockTypeState::WideNibble(bits, shift + 3);
+                    } else {
+                        match bits.checked_add(WIDE_BLOCK_TYPE_ESCAPE) {
+                            Some(block_type) => *self = BlockTypeState::FullyDecoded(block_type),
+                            None => return DivansResult::Failure(ErrMsg::BlockTypeOutOfRange),
+                        }
+                    }
                 }
                 BlockTypeState::FullyDecoded(_) =>   {
                     return DivansResult::Success;
@@ -110,12 +161,13 @@ impl BlockTypeState {
     }
 }
 
-#[derive(Clone,Copy)]
+#[derive(Clone,Copy,PartialEq,Eq,Hash,Debug)]
 pub enum LiteralBlockTypeState {
     Begin,
     Intermediate(BlockTypeState),
-    StrideNibble(u8),
-    FullyDecoded(u8, u8),
+    StrideNibble(u32),
+    NibbleOrder(u32, u8),
+    FullyDecoded(u32, u8), // the stride may carry LITERAL_BLOCK_SWITCH_LSB_FIRST
 }
 
 impl LiteralBlockTypeState {
@@ -140,6 +192,7 @@ impl LiteralBlockTypeState {
         input_bs: LiteralBlockSwitch,
         output_bytes: &mut [u8],
         output_offset: &mut usize) -> DivansResult {
+        let adapt_cdf = superstate.adapt_cdf();
         loop {
             let billing = BillingDesignation::CrossCommand(CrossCommandBilling::BlockSwitchType);
             match *self {
@@ -175,16 +228,36 @@ impl LiteralBlockTypeState {
                          need_something => return need_something,
                     }
 		            let mut stride_nibble = match superstate.bk.desired_force_stride {
-                        super::StrideSelection::UseBrotliRec => input_bs.stride(),
+                        super::StrideSelection::UseBrotliRec => input_bs.stride() & 0xf,
                         matched_stride => matched_stride as u8,
                     };
                     let mut nibble_prob = superstate.bk.btype_priors.get(BlockTypePriorType::StrideNibble,
                                                                          (0,));
                     superstate.coder.get_or_put_nibble(&mut stride_nibble, nibble_prob, billing);
-                    if superstate.specialization.adapt_cdf() {
+                    if adapt_cdf {
                         nibble_prob.blend(stride_nibble, Speed::SLOW);
                     }
-                    *self = LiteralBlockTypeState::FullyDecoded(ltype, stride_nibble);
+                    if superstate.bk.extended_stream_features & EXTENDED_FEATURE_LITERAL_NIBBLE_ORDER != 0 {
+                        *self = LiteralBlockTypeState::NibbleOrder(ltype, stride_nibble);
+                    } else {
+                        *self = LiteralBlockTypeState::FullyDecoded(ltype, stride_nibble);
+                    }
+                },
+                LiteralBlockTypeState::NibbleOrder(ltype, stride) => {
+                    match superstate.drain_or_fill_internal_buffer_cmd(output_bytes,
+                                                                       output_offset) {
+                         DivansResult::Success => {},
+                         need_something => return need_something,
+                    }
+                    let mut order_nibble = (input_bs.stride() & LITERAL_BLOCK_SWITCH_LSB_FIRST != 0) as u8;
+                    let nibble_prob = superstate.bk.btype_priors.get(BlockTypePriorType::NibbleOrder,
+                                                                         (0,));
+                    superstate.coder.get_or_put_nibble(&mut order_nibble, nibble_prob, billing);
+                    if adapt_cdf {
+                        nibble_prob.blend(order_nibble, Speed::SLOW);
+                    }
+                    *self = LiteralBlockTypeState::FullyDecoded(
+                        ltype, if order_nibble != 0 { stride | LITERAL_BLOCK_SWITCH_LSB_FIRST } else { stride });
                 },
                 LiteralBlockTypeState::FullyDecoded(_ltype, _stride) => {
                     return DivansResult::Success;
@@ -193,3 +266,84 @@ impl LiteralBlockTypeState {
         }
     }
 }
+
+#[cfg(all(test, feature="encoder", feature="decoder"))]
+mod test {
+    use core;
+    use std::vec::Vec;
+    use conformance::{conformance_expected_output, ConformanceSlice};
+    use decoded_command_iter::DecodedCommandIter;
+    use interface::{
+        BlockSwitch,
+        Command,
+        CopyCommand,
+        DivansCompressorOptions,
+        ErrMsg,
+        FeatureFlagSliceType,
+        LiteralBlockSwitch,
+        LiteralCommand,
+        EXTENDED_FEATURE2_WIDE_BLOCK_TYPES,
+    };
+    use test_support::{new_decompressor, encode_with_options, decode_with, DecodeConfig, assert_same_command};
+
+    fn literal(data: &[u8]) -> Command<ConformanceSlice> {
+        Command::Literal(LiteralCommand {
+            data: ConformanceSlice(data.to_vec()),
+            prob: FeatureFlagSliceType::<ConformanceSlice>::default(),
+            high_entropy: false,
+        })
+    }
+
+    // switches of every kind through more than 256 block types, counting up past 255 and jumping
+    // to block types whose varints take every length, with literals and copies coded under each
+    fn wide_block_type_commands() -> Vec<Command<ConformanceSlice>> {
+        let mut block_types: Vec<u32> = (0..300).collect();
+        block_types.extend([255, 256, 262, 1000, 4096, 1 << 20, 299, 0, core::u32::MAX - 1, core::u32::MAX].iter());
+        let mut commands = vec![literal(b"wide block types")];
+        for (index, block_type) in block_types.iter().enumerate() {
+            commands.push(match index % 3 {
+                0 => Command::BlockSwitchLiteral(LiteralBlockSwitch::new(*block_type, (index % 5) as u8)),
+                1 => Command::BlockSwitchCommand(BlockSwitch::new(*block_type)),
+                _ => Command::BlockSwitchDistance(BlockSwitch::new(*block_type)),
+            });
+            commands.push(literal(&[b'a' + (index % 26) as u8, b' ', (block_type & 0xff) as u8]));
+            commands.push(Command::Copy(CopyCommand{distance: 3 + (index % 7) as u32, num_bytes: 4 + (index % 5) as u32}));
+        }
+        commands
+    }
+
+    #[test]
+    fn test_wide_block_types_round_trip() {
+        let commands = wide_block_type_commands();
+        let expected = conformance_expected_output(&commands[..]).unwrap();
+        let mut opts = DivansCompressorOptions::default();
+        opts.extended_stream_features2 = EXTENDED_FEATURE2_WIDE_BLOCK_TYPES;
+        let stream = encode_with_options(&commands[..], opts).unwrap();
+        for multithread in [false, true].iter() {
+            assert_eq!(decode_with(&stream[..], DecodeConfig::new(*multithread), |_| {}).into_output().unwrap(), expected);
+        }
+        let decompressor = new_decompressor(false, false);
+        let mut remaining = &stream[..];
+        let mut input_buffer = [0u8; 64];
+        let mut iter = DecodedCommandIter::new(decompressor, &mut input_buffer[..], |buf: &mut [u8]| {
+            let len = core::cmp::min(buf.len(), remaining.len());
+            buf[..len].clone_from_slice(&remaining[..len]);
+            remaining = &remaining[len..];
+            len
+        });
+        let mut count = 0usize;
+        while let Some(cmd) = iter.next_borrowed() {
+            assert_same_command(&commands[count], &cmd.unwrap());
+            count += 1;
+        }
+        assert_eq!(count, commands.len());
+        iter.into_inner().free();
+    }
+
+    #[test]
+    fn test_wide_block_types_need_the_feature() {
+        let commands = wide_block_type_commands();
+        assert_eq!(encode_with_options(&commands[..], DivansCompressorOptions::default()),
+                   Err(ErrMsg::WideBlockTypesDisabled));
+    }
+}
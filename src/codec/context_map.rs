@@ -17,6 +17,15 @@ use ::interface::{
     MAX_LITERAL_CONTEXT_MAP_SIZE,
     MAX_PREDMODE_SPEED_AND_DISTANCE_CONTEXT_MAP_SIZE,
     NUM_MIXING_VALUES,
+    DistanceBucketing,
+    MAX_DISTANCE_POSTFIX_BITS,
+    STREAM_FEATURE_DISTANCE_BUCKETING,
+    STREAM_FEATURE_CONTEXT_MAP_RLE,
+    STREAM_FEATURE_SPARSE_CONTEXT_MAP,
+    MixerWarmStart,
+    MIXER_WARM_START_FLAG,
+    MIXER_WARM_START_OFFSET,
+    EXTENDED_FEATURE_CONTEXT_MAP_MODEL,
 };
 use ::priors::PriorCollection;
 use ::probability::{Speed, CDF16, SpeedPalette};
@@ -31,12 +40,20 @@ pub struct PredictionModeState<AllocU8:Allocator<u8>> {
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum PredictionModeSubstate {
     Begin,
+    MixerWarmStart(Option<u8>), // cm share once decoded
     DynamicContextMixing,
     PriorDepth(bool),
+    DistanceBucketing(Option<u8>, bool), // postfix bits once decoded
     AdaptationSpeed(u32, [(u8,u8);4], bool),
     ContextMapMnemonic(u32, ContextMapType, bool),
     ContextMapFirstNibble(u32, ContextMapType, bool),
     ContextMapSecondNibble(u32, ContextMapType, u8, bool),
+    ContextMapRunLengthNibbles(u32, ContextMapType, bool), // index of the first further repeat
+    ContextMapRunLength(u32, ContextMapType, u8, u32, bool), // nibbles remaining and the run so far
+    ContextMapSparseDefault(Option<u8>, bool), // high nibble of the default literal class once decoded
+    ContextMapSparseGap(u32, u8, bool), // index and default class
+    ContextMapSparseGapNibbles(u32, u8, u8, u32, bool), // nibbles remaining and the gap so far
+    ContextMapSparseValue(u32, u8, Option<u8>, bool), // high nibble of the exception once decoded
     MixingValues(usize, bool),
     FullyDecoded,
 }
@@ -65,6 +82,59 @@ fn find_best_match(data: Speed, palette: &SpeedPalette) -> usize {
     }
     best_index
 }
+// the context map being encoded; empty when decoding or when context maps are not coded
+fn source_context_map<SliceType:SliceWrapper<u8>>(in_cmd: &PredictionModeContextMap<SliceType>,
+                                                  context_map_type: ContextMapType,
+                                                  do_context_map: bool) -> &[u8] {
+    if !do_context_map {
+        return &[];
+    }
+    match context_map_type {
+        ContextMapType::Literal => in_cmd.literal_context_map.slice(),
+        ContextMapType::Distance => if in_cmd.has_context_speeds() {in_cmd.distance_context_map() } else {&[]},
+    }
+}
+
+// the entry for the same context one block type before index, if index is past the first block type
+fn previous_block_entry(context_map: &[u8], context_map_type: ContextMapType, index: u32) -> Option<u8> {
+    let stride = match context_map_type {
+        ContextMapType::Literal => 64,
+        ContextMapType::Distance => 4,
+    };
+    (index as usize).checked_sub(stride).and_then(|prev| context_map.get(prev).cloned())
+}
+
+// how many entries from index on repeat val, the entry just before them
+fn repeats_of_last_entry(context_map: &[u8], index: u32, val: u8) -> u32 {
+    match context_map.get(index as usize..) {
+        Some(rest) => rest.iter().take_while(|x| **x == val).count() as u32,
+        None => 0,
+    }
+}
+
+// the class most entries share, the smaller one on a tie
+fn most_frequent_entry(context_map: &[u8]) -> u8 {
+    let mut counts = [0u32; 256];
+    for val in context_map.iter() {
+        counts[*val as usize] += 1;
+    }
+    let mut best = 0;
+    for (val, count) in counts.iter().enumerate() {
+        if *count > counts[best] {
+            best = val;
+        }
+    }
+    best as u8
+}
+
+// where the next entry from index on that differs from the default class is
+fn next_exception(context_map: &[u8], index: u32, default_class: u8) -> Option<u32> {
+    match context_map.get(index as usize..) {
+        Some(rest) => rest.iter().position(|x| *x != default_class).map(|pos| index + pos as u32),
+        None => None,
+    }
+}
+
 impl <AllocU8:Allocator<u8>> PredictionModeState<AllocU8> {
     pub fn begin(m8:&mut RepurposingAlloc<u8, AllocU8>) -> Self {
         let mut ret = Self::nop();
@@ -73,25 +143,34 @@ impl <AllocU8:Allocator<u8>> PredictionModeState<AllocU8> {
         ret
     }
     pub fn free(&mut self, m8:&mut RepurposingAlloc<u8, AllocU8>) {
-        m8.use_cached_allocation::<UninitializedOnAlloc>().free_cell(
+        m8.use_slab_arena::<UninitializedOnAlloc>().free_cell(
             core::mem::replace(&mut self.pm.literal_context_map,
                                AllocatedMemoryPrefix::<u8, AllocU8>::default()));
                     
-        m8.use_cached_allocation::<UninitializedOnAlloc>().free_cell(
+        m8.use_slab_arena::<UninitializedOnAlloc>().free_cell(
             core::mem::replace(&mut self.pm.predmode_speed_and_distance_context_map,
                                AllocatedMemoryPrefix::<u8, AllocU8>::default()));
     }
     pub fn reset(&mut self, m8:&mut RepurposingAlloc<u8, AllocU8>) {
         if self.pm.literal_context_map.0.slice().len() == 0 {
-            let lit = m8.use_cached_allocation::<UninitializedOnAlloc>().alloc_cell(MAX_LITERAL_CONTEXT_MAP_SIZE);
+            let lit = m8.use_slab_arena::<UninitializedOnAlloc>().alloc_cell(MAX_LITERAL_CONTEXT_MAP_SIZE);
             self.pm = PredictionModeContextMap::<AllocatedMemoryPrefix<u8, AllocU8>> {
                 literal_context_map:lit,
-                predmode_speed_and_distance_context_map:m8.use_cached_allocation::<UninitializedOnAlloc>().alloc_cell(
+                predmode_speed_and_distance_context_map:m8.use_slab_arena::<UninitializedOnAlloc>().alloc_cell(
                     MAX_PREDMODE_SPEED_AND_DISTANCE_CONTEXT_MAP_SIZE),
             };
         }
         self.state = PredictionModeSubstate::Begin;
     }
+    pub fn duplicate(&self, m8: &mut AllocU8) -> Self {
+        PredictionModeState::<AllocU8> {
+            pm:PredictionModeContextMap::<AllocatedMemoryPrefix<u8, AllocU8>> {
+                literal_context_map: self.pm.literal_context_map.duplicate(m8),
+                predmode_speed_and_distance_context_map: self.pm.predmode_speed_and_distance_context_map.duplicate(m8),
+            },
+            state: self.state,
+        }
+    }
     pub fn nop() -> Self {
         PredictionModeState::<AllocU8> {
             pm:PredictionModeContextMap::<AllocatedMemoryPrefix<u8, AllocU8>> {
@@ -119,6 +198,7 @@ impl <AllocU8:Allocator<u8>> PredictionModeState<AllocU8> {
                                                in_cmd: &PredictionModeContextMap<SliceType>,
                                                output_bytes:&mut [u8],
                                                output_offset: &mut usize) -> DivansResult {
+        let adapt_cdf = superstate.adapt_cdf();
         let mut desired_speeds = [super::interface::default_literal_speed();4];
         if in_cmd.has_context_speeds() {
             let cm = in_cmd.context_map_speed_f8();
@@ -160,8 +240,20 @@ impl <AllocU8:Allocator<u8>> PredictionModeState<AllocU8> {
                     _, context_map_type, _, _) => PredictionModeSubstate::ContextMapSecondNibble(0,
                                                                                            context_map_type,
                                                                                            0, true),
+                PredictionModeSubstate::ContextMapRunLengthNibbles(
+                    _, context_map_type, _) => PredictionModeSubstate::ContextMapRunLengthNibbles(0, context_map_type, true),
+                PredictionModeSubstate::ContextMapRunLength(
+                    _, context_map_type, _, _, _) => PredictionModeSubstate::ContextMapRunLength(0, context_map_type, 0, 0, true),
+                PredictionModeSubstate::ContextMapSparseDefault(_, _) => PredictionModeSubstate::ContextMapSparseDefault(None, true),
+                PredictionModeSubstate::ContextMapSparseGap(_, _, _) => PredictionModeSubstate::ContextMapSparseGap(0, 0, true),
+                PredictionModeSubstate::ContextMapSparseGapNibbles(
+                    _, _, _, _, _) => PredictionModeSubstate::ContextMapSparseGapNibbles(0, 0, 0, 0, true),
+                PredictionModeSubstate::ContextMapSparseValue(
+                    _, _, _, _) => PredictionModeSubstate::ContextMapSparseValue(0, 0, None, true),
                 PredictionModeSubstate::AdaptationSpeed(_,_, _) => PredictionModeSubstate::FullyDecoded,
+                PredictionModeSubstate::DistanceBucketing(_, _) => PredictionModeSubstate::DistanceBucketing(None, true),
                 PredictionModeSubstate::MixingValues(_, _) => PredictionModeSubstate::MixingValues(0, true),
+                PredictionModeSubstate::MixerWarmStart(_) => PredictionModeSubstate::MixerWarmStart(None),
                 a => a,
             });
 
@@ -170,19 +262,53 @@ impl <AllocU8:Allocator<u8>> PredictionModeState<AllocU8> {
                    superstate.bk.reset_context_map_lru();
                    superstate.bk.reset_distance_context_map();
                    let mut beg_nib = in_cmd.literal_prediction_mode().prediction_mode();
+                   if beg_nib & !3 != 0 { // would be mistaken for MIXER_WARM_START_FLAG
+                       return DivansResult::Failure(ErrMsg::PredictionModeOutOfBounds(beg_nib));
+                   }
+                   if superstate.bk.desired_mixer_warm_start.is_some() {
+                       beg_nib |= MIXER_WARM_START_FLAG;
+                   }
                    {
                        let mut nibble_prob = superstate.bk.prediction_priors.get(PredictionModePriorType::Only, (0,));
                        superstate.coder.get_or_put_nibble(&mut beg_nib, nibble_prob, billing);
-                       if superstate.specialization.adapt_cdf() {
+                       if adapt_cdf {
                            nibble_prob.blend(beg_nib, Speed::MED);
                        }
                    }
-                   let pred_mode = match LiteralPredictionModeNibble::new(beg_nib) {
+                   let pred_mode = match LiteralPredictionModeNibble::new(beg_nib & !MIXER_WARM_START_FLAG) {
                       Err(x) => return DivansResult::Failure(ErrMsg::PredictionModeFail(x)),
                       Ok(pred_mode) => pred_mode,
                    };
                    self.pm.set_literal_prediction_mode(pred_mode);
-                   self.state = PredictionModeSubstate::DynamicContextMixing;
+                   if beg_nib & MIXER_WARM_START_FLAG != 0 {
+                       self.state = PredictionModeSubstate::MixerWarmStart(None);
+                   } else {
+                       self.pm.predmode_speed_and_distance_context_map.slice_mut()[MIXER_WARM_START_OFFSET] = 0;
+                       self.state = PredictionModeSubstate::DynamicContextMixing;
+                   }
+               },
+               PredictionModeSubstate::MixerWarmStart(cm_share) => {
+                   let desired = superstate.bk.desired_mixer_warm_start.unwrap_or_default();
+                   let mut beg_nib = match cm_share {
+                       None => desired.cm_share,
+                       Some(_) => desired.seed_high_nibble,
+                   };
+                   {
+                       let nibble_prob = superstate.bk.prediction_priors.get(
+                           PredictionModePriorType::MixerWarmStart, (cm_share.is_some() as usize,));
+                       superstate.coder.get_or_put_nibble(&mut beg_nib, nibble_prob, billing);
+                       if adapt_cdf {
+                           nibble_prob.blend(beg_nib, Speed::FAST);
+                       }
+                   }
+                   match cm_share {
+                       None => self.state = PredictionModeSubstate::MixerWarmStart(Some(beg_nib)),
+                       Some(cm_share) => {
+                           let warm_start = MixerWarmStart{cm_share, seed_high_nibble: beg_nib};
+                           self.pm.predmode_speed_and_distance_context_map.slice_mut()[MIXER_WARM_START_OFFSET] = warm_start.to_byte();
+                           self.state = PredictionModeSubstate::DynamicContextMixing;
+                       },
+                   }
                },
                PredictionModeSubstate::DynamicContextMixing => {
                    let is_adv = in_cmd.get_is_adv_context_map();
@@ -195,7 +321,7 @@ impl <AllocU8:Allocator<u8>> PredictionModeState<AllocU8> {
                        let mut nibble_prob = superstate.bk.prediction_priors.get(
                            PredictionModePriorType::DynamicContextMixingSpeed, (0,));
                        superstate.coder.get_or_put_nibble(&mut beg_nib, nibble_prob, billing);
-                       if superstate.specialization.adapt_cdf() {
+                       if adapt_cdf {
                            nibble_prob.blend(beg_nib, Speed::MED);
                        }
                    }
@@ -211,13 +337,50 @@ impl <AllocU8:Allocator<u8>> PredictionModeState<AllocU8> {
                        let mut nibble_prob = superstate.bk.prediction_priors.get(
                            PredictionModePriorType::PriorDepth, (0,));
                        superstate.coder.get_or_put_nibble(&mut beg_nib, nibble_prob, billing);
-                       if superstate.specialization.adapt_cdf() {
+                       if adapt_cdf {
                            nibble_prob.blend(beg_nib, Speed::FAST);
                        }
                    }
                    superstate.bk.obs_prior_depth(beg_nib); // FIXME: this is not persisted in the command
-                   self.state = PredictionModeSubstate::AdaptationSpeed(0, [(0,0);4], combine_literal_predictions);
+                   if superstate.bk.stream_features & STREAM_FEATURE_DISTANCE_BUCKETING != 0 {
+                       self.state = PredictionModeSubstate::DistanceBucketing(None, combine_literal_predictions);
+                   } else {
+                       self.state = PredictionModeSubstate::AdaptationSpeed(0, [(0,0);4], combine_literal_predictions);
+                   }
                }
+               PredictionModeSubstate::DistanceBucketing(postfix_bits, combine_literal_predictions) => {
+                   let desired = superstate.bk.desired_distance_bucketing;
+                   if let Err(e) = DistanceBucketing::new(desired.postfix_bits, desired.num_direct) {
+                       return DivansResult::Failure(e);
+                   }
+                   let mut beg_nib = match postfix_bits {
+                       None => desired.postfix_bits,
+                       Some(_) => desired.num_direct >> desired.postfix_bits,
+                   };
+                   {
+                       let nibble_prob = superstate.bk.prediction_priors.get(
+                           PredictionModePriorType::DistanceBucketing, (postfix_bits.is_some() as usize,));
+                       superstate.coder.get_or_put_nibble(&mut beg_nib, nibble_prob, billing);
+                       if adapt_cdf {
+                           nibble_prob.blend(beg_nib, Speed::FAST);
+                       }
+                   }
+                   match postfix_bits {
+                       None => {
+                           if beg_nib > MAX_DISTANCE_POSTFIX_BITS {
+                               return DivansResult::Failure(ErrMsg::InvalidDistanceBucketing(beg_nib, 0));
+                           }
+                           self.state = PredictionModeSubstate::DistanceBucketing(Some(beg_nib), combine_literal_predictions);
+                       },
+                       Some(postfix_bits) => {
+                           match DistanceBucketing::new(postfix_bits, beg_nib << postfix_bits) {
+                               Ok(bucketing) => superstate.bk.distance_bucketing = bucketing,
+                               Err(e) => return DivansResult::Failure(e),
+                           }
+                           self.state = PredictionModeSubstate::AdaptationSpeed(0, [(0,0);4], combine_literal_predictions);
+                       },
+                   }
+               },
                PredictionModeSubstate::AdaptationSpeed(index, mut out_adapt_speed, combine_literal_predictions) => {
                    let speed_index = index as usize >> 2;
                    let cur_speed = desired_speeds[speed_index].to_f8_tuple();
@@ -235,7 +398,7 @@ impl <AllocU8:Allocator<u8>> PredictionModeState<AllocU8> {
                    let mut nibble_prob = superstate.bk.prediction_priors.get(PredictionModePriorType::ContextMapSpeedPalette,
                                                                              (palette_type as usize,));
                    superstate.coder.get_or_put_nibble(&mut nibble, nibble_prob, billing);
-                   if superstate.specialization.adapt_cdf() {
+                   if adapt_cdf {
                        nibble_prob.blend(nibble, Speed::FAST);
                    }
                    if palette_type == 0 {
@@ -256,7 +419,11 @@ impl <AllocU8:Allocator<u8>> PredictionModeState<AllocU8> {
                        self.pm.set_context_map_speed([(u8_to_speed(out_adapt_speed[2].0),u8_to_speed(out_adapt_speed[2].1)),
                                                       (u8_to_speed(out_adapt_speed[3].0),u8_to_speed(out_adapt_speed[3].1))]);
                            
-                       self.state = PredictionModeSubstate::ContextMapMnemonic(0, ContextMapType::Literal, combine_literal_predictions);
+                       if superstate.bk.stream_features & STREAM_FEATURE_SPARSE_CONTEXT_MAP != 0 {
+                           self.state = PredictionModeSubstate::ContextMapSparseDefault(None, combine_literal_predictions);
+                       } else {
+                           self.state = PredictionModeSubstate::ContextMapMnemonic(0, ContextMapType::Literal, combine_literal_predictions);
+                       }
                    } else {
                        self.state = PredictionModeSubstate::AdaptationSpeed(index + 1, out_adapt_speed, combine_literal_predictions);
                    }
@@ -288,9 +455,21 @@ impl <AllocU8:Allocator<u8>> PredictionModeState<AllocU8> {
                        res
                    };
                    {
-                       let mut nibble_prob = superstate.bk.prediction_priors.get(PredictionModePriorType::Mnemonic, (context_map_type as usize,));
+                       let mut nibble_prob = if superstate.bk.extended_stream_features & EXTENDED_FEATURE_CONTEXT_MAP_MODEL != 0 {
+                           // block types tend to repeat each other's classes, so a hit on the previous one's entry
+                           // makes the mnemonic its lru slot
+                           let ctx = match previous_block_entry(source_context_map(&self.pm, context_map_type, true),
+                                                                context_map_type, index) {
+                               Some(prev) => superstate.bk.cmap_lru.iter().position(|x| *x == prev).unwrap_or(13),
+                               None => 14,
+                           };
+                           superstate.bk.prediction_priors.get(PredictionModePriorType::ContextMapModelMnemonic,
+                                                               (ctx, context_map_type as usize))
+                       } else {
+                           superstate.bk.prediction_priors.get(PredictionModePriorType::Mnemonic, (context_map_type as usize,))
+                       };
                        superstate.coder.get_or_put_nibble(&mut mnemonic_nibble, nibble_prob, billing);
-                       if superstate.specialization.adapt_cdf() {
+                       if adapt_cdf {
                            nibble_prob.blend(mnemonic_nibble, Speed::MED);
                        }
                    }
@@ -326,8 +505,184 @@ impl <AllocU8:Allocator<u8>> PredictionModeState<AllocU8> {
                        } else {
                            return DivansResult::Failure(ErrMsg::IndexBeyondContextMapSize(index as u8, (index >> 8) as u8));
                        }
-                       self.state = PredictionModeSubstate::ContextMapMnemonic(index + 1, context_map_type, combine_literal_predictions);
+                       if mnemonic_nibble == 0 && superstate.bk.stream_features & STREAM_FEATURE_CONTEXT_MAP_RLE != 0 {
+                           self.state = PredictionModeSubstate::ContextMapRunLengthNibbles(index + 1, context_map_type, combine_literal_predictions);
+                       } else {
+                           self.state = PredictionModeSubstate::ContextMapMnemonic(index + 1, context_map_type, combine_literal_predictions);
+                       }
+                   }
+               },
+               PredictionModeSubstate::ContextMapRunLengthNibbles(index, context_map_type, combine_literal_predictions) => {
+                   let run = repeats_of_last_entry(source_context_map(in_cmd, context_map_type, superstate.bk.desired_do_context_map),
+                                                   index, superstate.bk.cmap_lru[0]);
+                   let mut num_nibbles = ((32 - run.leading_zeros() + 3) >> 2) as u8;
+                   {
+                       let nibble_prob = superstate.bk.prediction_priors.get(PredictionModePriorType::ContextMapRunLength,
+                                                                             (0, context_map_type as usize));
+                       superstate.coder.get_or_put_nibble(&mut num_nibbles, nibble_prob, billing);
+                       if adapt_cdf {
+                           nibble_prob.blend(num_nibbles, Speed::MED);
+                       }
+                   }
+                   if num_nibbles == 0 {
+                       self.state = PredictionModeSubstate::ContextMapMnemonic(index, context_map_type, combine_literal_predictions);
+                   } else if num_nibbles > 4 { // longer than any context map
+                       return DivansResult::Failure(ErrMsg::IndexBeyondContextMapSize(index as u8, (index >> 8) as u8));
+                   } else {
+                       self.state = PredictionModeSubstate::ContextMapRunLength(index, context_map_type, num_nibbles, 0, combine_literal_predictions);
+                   }
+               },
+               PredictionModeSubstate::ContextMapRunLength(index, context_map_type, nibbles_left, run_so_far, combine_literal_predictions) => {
+                   let run = repeats_of_last_entry(source_context_map(in_cmd, context_map_type, superstate.bk.desired_do_context_map),
+                                                   index, superstate.bk.cmap_lru[0]);
+                   let mut run_nib = ((run >> (4 * u32::from(nibbles_left - 1))) & 0xf) as u8;
+                   {
+                       let nibble_prob = superstate.bk.prediction_priors.get(PredictionModePriorType::ContextMapRunLength,
+                                                                             (nibbles_left as usize, context_map_type as usize));
+                       superstate.coder.get_or_put_nibble(&mut run_nib, nibble_prob, billing);
+                       if adapt_cdf {
+                           nibble_prob.blend(run_nib, Speed::MED);
+                       }
+                   }
+                   let run_so_far = (run_so_far << 4) | u32::from(run_nib);
+                   if nibbles_left != 1 {
+                       self.state = PredictionModeSubstate::ContextMapRunLength(index, context_map_type, nibbles_left - 1, run_so_far,
+                                                                                combine_literal_predictions);
+                       continue;
+                   }
+                   let val = superstate.bk.cmap_lru[0];
+                   for repeat_index in index..index + run_so_far {
+                       let out_context_map = match context_map_type {
+                           ContextMapType::Literal => self.pm.literal_context_map.slice_mut(),
+                           ContextMapType::Distance => if self.pm.has_context_speeds() {self.pm.distance_context_map_mut() } else {&mut[]},
+                       };
+                       if (repeat_index as usize) < out_context_map.len() {
+                           out_context_map[repeat_index as usize] = val;
+                       } else {
+                           return DivansResult::Failure(ErrMsg::IndexBeyondContextMapSize(repeat_index as u8, (repeat_index >> 8) as u8));
+                       }
+                       if let DivansOpResult::Failure(m) = superstate.bk.obs_context_map_for_lru(context_map_type, repeat_index, val) {
+                           return DivansResult::Failure(m);
+                       }
                    }
+                   self.state = PredictionModeSubstate::ContextMapMnemonic(index + run_so_far, context_map_type, combine_literal_predictions);
+               },
+               PredictionModeSubstate::ContextMapSparseDefault(high_nibble, combine_literal_predictions) => {
+                   let default_class = most_frequent_entry(source_context_map(in_cmd, ContextMapType::Literal,
+                                                                              superstate.bk.desired_do_context_map));
+                   let mut default_nib = match high_nibble {
+                       None => default_class >> 4,
+                       Some(_) => default_class & 0xf,
+                   };
+                   {
+                       let nibble_prob = superstate.bk.prediction_priors.get(PredictionModePriorType::ContextMapSparse,
+                                                                             (high_nibble.is_some() as usize,));
+                       superstate.coder.get_or_put_nibble(&mut default_nib, nibble_prob, billing);
+                       if adapt_cdf {
+                           nibble_prob.blend(default_nib, Speed::FAST);
+                       }
+                   }
+                   match high_nibble {
+                       None => self.state = PredictionModeSubstate::ContextMapSparseDefault(Some(default_nib), combine_literal_predictions),
+                       Some(high_nibble) => self.state = PredictionModeSubstate::ContextMapSparseGap(0, (high_nibble << 4) | default_nib,
+                                                                                                   combine_literal_predictions),
+                   }
+               },
+               PredictionModeSubstate::ContextMapSparseGap(index, default_class, combine_literal_predictions) => {
+                   let exception = next_exception(source_context_map(in_cmd, ContextMapType::Literal, superstate.bk.desired_do_context_map),
+                                                  index, default_class);
+                   let mut num_nibbles = match exception {
+                       Some(exception_index) => ((32 - (exception_index - index).leading_zeros() + 3) >> 2) as u8,
+                       None => 15, // no further exceptions
+                   };
+                   {
+                       let nibble_prob = superstate.bk.prediction_priors.get(PredictionModePriorType::ContextMapSparse, (2,));
+                       superstate.coder.get_or_put_nibble(&mut num_nibbles, nibble_prob, billing);
+                       if adapt_cdf {
+                           nibble_prob.blend(num_nibbles, Speed::MED);
+                       }
+                   }
+                   if num_nibbles == 15 {
+                       // the unlisted entries take the default here, so LiteralBookKeeping resolves them from the map alone
+                       for val in self.pm.literal_context_map.slice_mut().iter_mut().skip(index as usize) {
+                           *val = default_class;
+                       }
+                       superstate.bk.reset_context_map_lru(); // distance context map should start with 0..14 as lru
+                       self.state = PredictionModeSubstate::ContextMapMnemonic(0, ContextMapType::Distance, combine_literal_predictions);
+                   } else if num_nibbles == 0 {
+                       self.state = PredictionModeSubstate::ContextMapSparseValue(index, default_class, None, combine_literal_predictions);
+                   } else if num_nibbles > 4 { // longer than any context map
+                       return DivansResult::Failure(ErrMsg::IndexBeyondContextMapSize(index as u8, (index >> 8) as u8));
+                   } else {
+                       self.state = PredictionModeSubstate::ContextMapSparseGapNibbles(index, default_class, num_nibbles, 0,
+                                                                                       combine_literal_predictions);
+                   }
+               },
+               PredictionModeSubstate::ContextMapSparseGapNibbles(index, default_class, nibbles_left, gap_so_far, combine_literal_predictions) => {
+                   let gap = match next_exception(source_context_map(in_cmd, ContextMapType::Literal, superstate.bk.desired_do_context_map),
+                                                  index, default_class) {
+                       Some(exception_index) => exception_index - index,
+                       None => 0,
+                   };
+                   let mut gap_nib = ((gap >> (4 * u32::from(nibbles_left - 1))) & 0xf) as u8;
+                   {
+                       let nibble_prob = superstate.bk.prediction_priors.get(PredictionModePriorType::ContextMapSparse,
+                                                                             (2 + nibbles_left as usize,));
+                       superstate.coder.get_or_put_nibble(&mut gap_nib, nibble_prob, billing);
+                       if adapt_cdf {
+                           nibble_prob.blend(gap_nib, Speed::MED);
+                       }
+                   }
+                   let gap_so_far = (gap_so_far << 4) | u32::from(gap_nib);
+                   if nibbles_left != 1 {
+                       self.state = PredictionModeSubstate::ContextMapSparseGapNibbles(index, default_class, nibbles_left - 1, gap_so_far,
+                                                                                       combine_literal_predictions);
+                       continue;
+                   }
+                   let exception_index = index + gap_so_far;
+                   match self.pm.literal_context_map.slice_mut().get_mut(index as usize..exception_index as usize) {
+                       Some(gap_entries) => for val in gap_entries.iter_mut() {
+                           *val = default_class;
+                       },
+                       None => return DivansResult::Failure(ErrMsg::IndexBeyondContextMapSize(exception_index as u8,
+                                                                                              (exception_index >> 8) as u8)),
+                   }
+                   self.state = PredictionModeSubstate::ContextMapSparseValue(exception_index, default_class, None, combine_literal_predictions);
+               },
+               PredictionModeSubstate::ContextMapSparseValue(index, default_class, high_nibble, combine_literal_predictions) => {
+                   let target_val = match source_context_map(in_cmd, ContextMapType::Literal, superstate.bk.desired_do_context_map)
+                       .get(index as usize) {
+                       Some(val) => *val,
+                       None => 0,
+                   };
+                   let mut val_nib = match high_nibble {
+                       None => target_val >> 4,
+                       Some(_) => target_val & 0xf,
+                   };
+                   {
+                       let prior_type = match high_nibble {
+                           None => PredictionModePriorType::FirstNibble,
+                           Some(_) => PredictionModePriorType::SecondNibble,
+                       };
+                       let nibble_prob = superstate.bk.prediction_priors.get(prior_type, (ContextMapType::Literal as usize,));
+                       superstate.coder.get_or_put_nibble(&mut val_nib, nibble_prob, billing);
+                       if adapt_cdf {
+                           nibble_prob.blend(val_nib, Speed::MED);
+                       }
+                   }
+                   let high_nibble = match high_nibble {
+                       None => {
+                           self.state = PredictionModeSubstate::ContextMapSparseValue(index, default_class, Some(val_nib),
+                                                                                      combine_literal_predictions);
+                           continue;
+                       },
+                       Some(high_nibble) => high_nibble,
+                   };
+                   match self.pm.literal_context_map.slice_mut().get_mut(index as usize) {
+                       Some(val) => *val = (high_nibble << 4) | val_nib,
+                       None => return DivansResult::Failure(ErrMsg::IndexBeyondContextMapSize(index as u8, (index >> 8) as u8)),
+                   }
+                   self.state = PredictionModeSubstate::ContextMapSparseGap(index + 1, default_class, combine_literal_predictions);
                },
                PredictionModeSubstate::ContextMapFirstNibble(index, context_map_type, combine_literal_predictions) => {
                    let cur_context_map = match context_map_type {
@@ -340,10 +695,20 @@ impl <AllocU8:Allocator<u8>> PredictionModeState<AllocU8> {
                    } else {
                        cur_context_map[index as usize] >> 4
                    };
-                   let mut nibble_prob = superstate.bk.prediction_priors.get(PredictionModePriorType::FirstNibble, (context_map_type as usize,));
+                   let mut nibble_prob = if superstate.bk.extended_stream_features & EXTENDED_FEATURE_CONTEXT_MAP_MODEL != 0 {
+                       let ctx = match previous_block_entry(source_context_map(&self.pm, context_map_type, true),
+                                                            context_map_type, index) {
+                           Some(prev) => (prev >> 4) as usize,
+                           None => 16,
+                       };
+                       superstate.bk.prediction_priors.get(PredictionModePriorType::ContextMapModelFirstNibble,
+                                                           (ctx, context_map_type as usize))
+                   } else {
+                       superstate.bk.prediction_priors.get(PredictionModePriorType::FirstNibble, (context_map_type as usize,))
+                   };
 
                    superstate.coder.get_or_put_nibble(&mut msn_nib, nibble_prob, billing);
-                   if superstate.specialization.adapt_cdf() {
+                   if adapt_cdf {
                        nibble_prob.blend(msn_nib, Speed::MED);
                    }
                    self.state = PredictionModeSubstate::ContextMapSecondNibble(index, context_map_type, msn_nib, combine_literal_predictions, );
@@ -360,11 +725,17 @@ impl <AllocU8:Allocator<u8>> PredictionModeState<AllocU8> {
                        cur_context_map[index as usize] & 0xf
                    };
                    {
-                       let mut nibble_prob = superstate.bk.prediction_priors.get(PredictionModePriorType::SecondNibble, (context_map_type as usize,));
                        // could put first_nibble as ctx instead of 0, but that's probably not a good idea since we never see
                        // the same nibble twice in all likelihood if it was covered by the mnemonic--unless we want random (possible?)
+                       // The context map model does, since the classes a stream uses cluster by high nibble
+                       let mut nibble_prob = if superstate.bk.extended_stream_features & EXTENDED_FEATURE_CONTEXT_MAP_MODEL != 0 {
+                           superstate.bk.prediction_priors.get(PredictionModePriorType::ContextMapModelSecondNibble,
+                                                               (most_significant_nibble as usize, context_map_type as usize))
+                       } else {
+                           superstate.bk.prediction_priors.get(PredictionModePriorType::SecondNibble, (context_map_type as usize,))
+                       };
                        superstate.coder.get_or_put_nibble(&mut lsn_nib, nibble_prob, billing);
-                       if superstate.specialization.adapt_cdf() {
+                       if adapt_cdf {
                            nibble_prob.blend(lsn_nib, Speed::MED);
                        }
                    }
@@ -401,7 +772,7 @@ impl <AllocU8:Allocator<u8>> PredictionModeState<AllocU8> {
                        let mut nibble_prob = superstate.bk.prediction_priors.get(
                            PredictionModePriorType::PriorMixingValue, (prior,));
                        superstate.coder.get_or_put_nibble(&mut mixing_nib, nibble_prob, billing);
-                       if superstate.specialization.adapt_cdf() {
+                       if adapt_cdf {
                            nibble_prob.blend(mixing_nib, Speed::PLANE);
                        }
                    }
@@ -427,3 +798,93 @@ impl <AllocU8:Allocator<u8>> PredictionModeState<AllocU8> {
         }
     }
 }
+
+#[cfg(all(test, feature="std", feature="encoder", feature="decoder"))]
+mod test {
+    use conformance::{conformance_commands, conformance_expected_output, ConformanceSlice};
+    use interface::{
+        Command,
+        DivansCompressorOptions,
+        HEADER_FEATURE_OFFSET,
+        HEADER_EXTENDED_FEATURE_OFFSET,
+        MAGIC_NUMBER,
+        MAGIC_NUMBER_EXTENDED,
+        EXTENDED_FEATURE_CONTEXT_MAP_MODEL,
+        STREAM_FEATURE_COMMAND_TYPE_TREE,
+        STREAM_FEATURE_CONTEXT_MAP_RLE,
+        STREAM_FEATURE_SPARSE_CONTEXT_MAP,
+    };
+    use test_support::{encode, encode_with_options, decode, decoded_options};
+
+    #[test]
+    fn test_context_map_rle_roundtrip() {
+        let mut commands = conformance_commands("mixed");
+        let expected = conformance_expected_output(&commands[..]).unwrap();
+        // the conformance maps hardly repeat an entry, so nearly every run length is implicit
+        let rle = encode(&commands[..], STREAM_FEATURE_CONTEXT_MAP_RLE).unwrap();
+        assert_eq!(rle[HEADER_FEATURE_OFFSET], STREAM_FEATURE_CONTEXT_MAP_RLE);
+        assert_eq!(decode(&rle[..]).unwrap(), expected);
+        // a map the size of one with 32 block types, where each half of a block type shares a context
+        if let Command::PredictionMode(ref mut pm) = commands[0] {
+            pm.literal_context_map = ConformanceSlice((0..64 * 32).map(|index| ((index / 32) % 5) as u8).collect());
+            for val in pm.distance_context_map_mut().iter_mut() {
+                *val = 1;
+            }
+        }
+        let plain = encode(&commands[..], STREAM_FEATURE_COMMAND_TYPE_TREE).unwrap();
+        let rle = encode(&commands[..], STREAM_FEATURE_COMMAND_TYPE_TREE | STREAM_FEATURE_CONTEXT_MAP_RLE).unwrap();
+        assert_eq!(decode(&plain[..]).unwrap(), expected);
+        assert_eq!(decode(&rle[..]).unwrap(), expected);
+        assert!(rle.len() < plain.len(), "{} >= {}", rle.len(), plain.len());
+    }
+
+    #[test]
+    fn test_sparse_context_map_roundtrip() {
+        let mut commands = conformance_commands("mixed");
+        let expected = conformance_expected_output(&commands[..]).unwrap();
+        let sparse = encode(&commands[..], STREAM_FEATURE_SPARSE_CONTEXT_MAP).unwrap();
+        assert_eq!(sparse[HEADER_FEATURE_OFFSET], STREAM_FEATURE_SPARSE_CONTEXT_MAP);
+        assert_eq!(decode(&sparse[..]).unwrap(), expected);
+        // 32 block types that nearly all share one context, with a scattered handful of exceptions
+        if let Command::PredictionMode(ref mut pm) = commands[0] {
+            pm.literal_context_map = ConformanceSlice((0..64 * 32).map(
+                |index| if index % 301 == 7 { (index % 11) as u8 } else { 3 }).collect());
+        }
+        let dense = encode(&commands[..], STREAM_FEATURE_COMMAND_TYPE_TREE | STREAM_FEATURE_CONTEXT_MAP_RLE).unwrap();
+        let sparse = encode(&commands[..], STREAM_FEATURE_COMMAND_TYPE_TREE | STREAM_FEATURE_SPARSE_CONTEXT_MAP).unwrap();
+        assert_eq!(decode(&dense[..]).unwrap(), expected);
+        assert_eq!(decode(&sparse[..]).unwrap(), expected);
+        assert!(sparse.len() < dense.len(), "{} >= {}", sparse.len(), dense.len());
+    }
+
+    #[test]
+    fn test_context_map_model_roundtrip() {
+        let mut commands = conformance_commands("mixed");
+        let expected = conformance_expected_output(&commands[..]).unwrap();
+        let mut opts = DivansCompressorOptions::default();
+        let plain = encode_with_options(&commands[..], opts).unwrap();
+        assert_eq!(plain[..4], MAGIC_NUMBER);
+        opts.extended_stream_features = EXTENDED_FEATURE_CONTEXT_MAP_MODEL;
+        let model = encode_with_options(&commands[..], opts).unwrap();
+        assert_eq!(model[..4], MAGIC_NUMBER_EXTENDED);
+        assert_eq!(model[HEADER_EXTENDED_FEATURE_OFFSET], EXTENDED_FEATURE_CONTEXT_MAP_MODEL);
+        assert_eq!(decode(&model[..]).unwrap(), expected);
+        for multithread in [false, true].iter() {
+            assert_eq!(decoded_options(&model[..], *multithread).unwrap().extended_stream_features,
+                       EXTENDED_FEATURE_CONTEXT_MAP_MODEL);
+            assert_eq!(decoded_options(&plain[..], *multithread).unwrap().extended_stream_features, 0);
+        }
+        // 32 block types that each repeat the classes of the one before, with the odd change
+        if let Command::PredictionMode(ref mut pm) = commands[0] {
+            pm.literal_context_map = ConformanceSlice((0..64 * 32).map(
+                |index| if index % 97 == 5 { 40 } else { ((index % 64) * 37 % 29) as u8 }).collect());
+        }
+        opts.extended_stream_features = 0;
+        let plain = encode_with_options(&commands[..], opts).unwrap();
+        opts.extended_stream_features = EXTENDED_FEATURE_CONTEXT_MAP_MODEL;
+        let model = encode_with_options(&commands[..], opts).unwrap();
+        assert_eq!(decode(&plain[..]).unwrap(), expected);
+        assert_eq!(decode(&model[..]).unwrap(), expected);
+        assert!(model.len() < plain.len(), "{} >= {}", model.len(), plain.len());
+    }
+}
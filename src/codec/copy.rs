@@ -11,6 +11,8 @@ use ::interface::{
     ArithmeticEncoderOrDecoder,
     BillingDesignation,
     CopyCommand,
+    DistanceBucketing,
+    DISTANCE_POSTFIX_DIRECT,
 };
 use ::priors::PriorCollection;
 use ::probability::{Speed, CDF16};
@@ -24,14 +26,17 @@ pub enum CopySubstate {
     CountMantissaNibbles(u8, u8, u32), //nibble count, intermediate result
     CountDecoded,
     DistanceLengthMnemonic, // references a recent distance cached value
+    DistancePostfix, // only when the stream buckets distances
     DistanceLengthFirst,
     DistanceLengthGreater14Less25, // length not between 1 and 15, inclusive.. second nibble results in 15-24
     DistanceMantissaNibbles(u8, u8, u32), // nibble count (up to 6), intermediate result
     FullyDecoded,
 }
+#[derive(Clone, Copy)]
 pub struct CopyState {
    pub cc:CopyCommand,
    pub state: CopySubstate,
+   pub postfix: Option<u8>, // decoded postfix, applied to cc.distance once the remaining bits are known
 }
 
 
@@ -44,6 +49,13 @@ impl CopyState {
                 num_bytes:0,
             },
             state:CopySubstate::Begin,
+            postfix: None,
+        }
+    }
+    fn join_postfix(&mut self, bucketing: &DistanceBucketing, last_postfix: &mut u8) {
+        if let Some(postfix) = self.postfix.take() {
+            self.cc.distance = bucketing.join(postfix, self.cc.distance);
+            *last_postfix = postfix;
         }
     }
     #[cfg_attr(not(feature="no-inline"), inline(always))]
@@ -65,11 +77,18 @@ impl CopyState {
                                                      in_cmd: &CopyCommand,
                                                      output_bytes:&mut [u8],
                                                      output_offset: &mut usize) -> DivansResult {
-        let dlen: u8 = (core::mem::size_of_val(&in_cmd.distance) as u32 * 8 - in_cmd.distance.leading_zeros()) as u8;
-        let clen: u8 = (core::mem::size_of_val(&in_cmd.num_bytes) as u32 * 8 - in_cmd.num_bytes.leading_zeros()) as u8;
-        if dlen ==0 {
+        let adapt_cdf = superstate.adapt_cdf();
+        if in_cmd.distance == 0 {
             return DivansResult::Failure(ErrMsg::Distance0NotAllowed); // not allowed to copy from 0 distance
         }
+        // the length and mantissa nibbles code what is left of the distance after the postfix
+        let (in_postfix, in_distance) = if superstate.bk.distance_bucketing.is_identity() {
+            (DISTANCE_POSTFIX_DIRECT, in_cmd.distance)
+        } else {
+            superstate.bk.distance_bucketing.split(in_cmd.distance)
+        };
+        let dlen: u8 = (core::mem::size_of_val(&in_distance) as u32 * 8 - in_distance.leading_zeros()) as u8;
+        let clen: u8 = (core::mem::size_of_val(&in_cmd.num_bytes) as u32 * 8 - in_cmd.num_bytes.leading_zeros()) as u8;
         loop {
             match superstate.drain_or_fill_internal_buffer_cmd(output_bytes, output_offset) {
                 DivansResult::Success => {},
@@ -91,7 +110,7 @@ impl CopyState {
                     let mut nibble_prob = superstate.bk.copy_priors.get(
                         CopyCommandNibblePriorType::CountSmall, (ctype, index));
                     superstate.coder.get_or_put_nibble(&mut shortcut_nib, nibble_prob, billing);
-                    if superstate.specialization.adapt_cdf() {
+                    if adapt_cdf {
                         nibble_prob.blend(shortcut_nib, Speed::MUD);
                     }
 
@@ -112,7 +131,7 @@ impl CopyState {
                     let mut nibble_prob = superstate.bk.copy_priors.get(
                         CopyCommandNibblePriorType::CountBegNib, (ctype, index));
                     superstate.coder.get_or_put_nibble(&mut beg_nib, nibble_prob, billing);
-                    if superstate.specialization.adapt_cdf() {
+                    if adapt_cdf {
                         nibble_prob.blend(beg_nib, Speed::FAST);
                     }
                     if beg_nib == 15 {
@@ -129,7 +148,7 @@ impl CopyState {
                     let mut nibble_prob = superstate.bk.copy_priors.get(
                         CopyCommandNibblePriorType::CountLastNib, (ctype, index));
                     superstate.coder.get_or_put_nibble(&mut last_nib, nibble_prob, billing);
-                    if superstate.specialization.adapt_cdf() {
+                    if adapt_cdf {
                         nibble_prob.blend(last_nib, Speed::FAST);
                     }
                     superstate.bk.last_clen = last_nib + 19;
@@ -146,7 +165,7 @@ impl CopyState {
                         CopyCommandNibblePriorType::CountMantissaNib, (ctype, index));
                     superstate.coder.get_or_put_nibble(&mut last_nib, nibble_prob, billing);
                     let next_decoded_so_far = decoded_so_far | (u32::from(last_nib) << next_len_remaining);
-                    if superstate.specialization.adapt_cdf() {
+                    if adapt_cdf {
                         nibble_prob.blend(last_nib, Speed::SLOW);
                     }
 
@@ -175,14 +194,17 @@ impl CopyState {
                         let mut nibble_prob = superstate.bk.copy_priors.get(
                             CopyCommandNibblePriorType::DistanceMnemonic, (actual_prior as usize, ((superstate.bk.last_llen < 8) as usize)));
                         superstate.coder.get_or_put_nibble(&mut beg_nib, nibble_prob, billing);
-                        if superstate.specialization.adapt_cdf() {
+                        if adapt_cdf {
                             nibble_prob.blend(beg_nib, Speed::SLOW);
                         }
                     }
-                    //println_stderr!("D {},{} => {} as {}", dtype, distance_map_index, actual_prior, beg_nib);
-                    //eprintln!("M:{}", beg_nib);
+                    divans_trace!(actual_prior = actual_prior, mnemonic = beg_nib; "copy distance mnemonic");
                     if beg_nib == 15 {
-                        self.state = CopySubstate::DistanceLengthFirst;
+                        if superstate.bk.distance_bucketing.is_identity() {
+                            self.state = CopySubstate::DistanceLengthFirst;
+                        } else {
+                            self.state = CopySubstate::DistancePostfix;
+                        }
                     } else {
                         let (dist, ok, _cache_index) = get_distance_from_mnemonic_code(&superstate.bk.distance_lru, beg_nib, self.cc.num_bytes);
                         self.cc.distance = dist;
@@ -194,9 +216,26 @@ impl CopyState {
                         self.state = CopySubstate::FullyDecoded;
                     }
                 },
+                CopySubstate::DistancePostfix => {
+                    let mut postfix_nib = in_postfix;
+                    let index = core::cmp::min(superstate.bk.last_distance_postfix, 8) as usize;
+                    let actual_prior = superstate.bk.get_distance_prior(self.cc.num_bytes);
+                    let nibble_prob = superstate.bk.copy_priors.get(
+                        CopyCommandNibblePriorType::DistancePostfix, (actual_prior, index));
+                    superstate.coder.get_or_put_nibble(&mut postfix_nib, nibble_prob, billing);
+                    if adapt_cdf {
+                        nibble_prob.blend(postfix_nib, Speed::MED);
+                    }
+                    if postfix_nib != DISTANCE_POSTFIX_DIRECT
+                        && postfix_nib >> superstate.bk.distance_bucketing.postfix_bits != 0 {
+                        return DivansResult::Failure(ErrMsg::DistancePostfixOutOfRange(postfix_nib));
+                    }
+                    self.postfix = Some(postfix_nib);
+                    self.state = CopySubstate::DistanceLengthFirst;
+                },
                 CopySubstate::DistanceLengthFirst => {
                     let mut beg_nib = core::cmp::min(14, dlen - 1);
-                    if superstate.bk.distance_lru[1].wrapping_sub(3) == in_cmd.distance {
+                    if self.postfix.is_none() && superstate.bk.distance_lru[1].wrapping_sub(3) == in_distance {
                         beg_nib = 15
                     }
                     let index = (core::mem::size_of_val(&self.cc.num_bytes) as u32 * 8 - self.cc.num_bytes.leading_zeros()) as usize >> 2;
@@ -204,7 +243,7 @@ impl CopyState {
                     let mut nibble_prob = superstate.bk.copy_priors.get(
                         CopyCommandNibblePriorType::DistanceBegNib, (actual_prior as usize, index));
                     superstate.coder.get_or_put_nibble(&mut beg_nib, nibble_prob, billing);
-                    if superstate.specialization.adapt_cdf() {
+                    if adapt_cdf {
                         nibble_prob.blend(beg_nib, Speed::SLOW);
                     }
                     if beg_nib == 14 {
@@ -231,7 +270,7 @@ impl CopyState {
                     let mut nibble_prob = superstate.bk.copy_priors.get(
                         CopyCommandNibblePriorType::DistanceLastNib, (actual_prior, index));
                     superstate.coder.get_or_put_nibble(&mut last_nib, nibble_prob, billing);
-                    if superstate.specialization.adapt_cdf() {
+                    if adapt_cdf {
                         nibble_prob.blend(last_nib, Speed::ROCKET);
                     }
                     superstate.bk.last_dlen = (last_nib + 14) + 1;
@@ -241,7 +280,7 @@ impl CopyState {
                     for next_len_remaining_sr2 in (0..((start_len_remaining as usize + 3) >> 2)).rev() {
                         let next_len_remaining = (next_len_remaining_sr2 as u8) << 2;
                         let actual_prior = superstate.bk.get_distance_prior(self.cc.num_bytes);
-                        let last_nib_as_u32 = (in_cmd.distance ^ decoded_so_far) >> next_len_remaining;
+                        let last_nib_as_u32 = (in_distance ^ decoded_so_far) >> next_len_remaining;
                         let mut last_nib = last_nib_as_u32 as u8;
                         let index = if len_decoded == 0 { ((superstate.bk.last_dlen & 3) + 1) as usize } else { 0usize };
                         let four_if_0_or_1_64_if_2_3_or_4 = 0x4 << ((index & 6) << ((index & 2)>>1));
@@ -252,7 +291,7 @@ impl CopyState {
                             superstate.coder.get_or_put_nibble(&mut last_nib, nibble_prob, BillingDesignation::CopyCommand(
                                 CopySubstate::DistanceMantissaNibbles(0, 0, 0)));
                             next_decoded_so_far = decoded_so_far | (u32::from(last_nib) << next_len_remaining);
-                            if superstate.specialization.adapt_cdf() {
+                            if adapt_cdf {
                                 nibble_prob.blend(last_nib, Speed::new(four_if_0_or_1_64_if_2_3_or_4, 0x4000));
                             }
                         }
@@ -275,13 +314,71 @@ impl CopyState {
                         decoded_so_far = next_decoded_so_far;
                     }
                     self.cc.distance = decoded_so_far;
+                    self.join_postfix(&superstate.bk.distance_bucketing, &mut superstate.bk.last_distance_postfix);
                     self.state = CopySubstate::FullyDecoded;
                     return DivansResult::Success;
                 },
                 CopySubstate::FullyDecoded => {
+                    self.join_postfix(&superstate.bk.distance_bucketing, &mut superstate.bk.last_distance_postfix);
                     return DivansResult::Success;
                 }
             }
         }
     }
 }
+
+#[cfg(all(test, feature="std", feature="encoder", feature="decoder"))]
+mod test {
+    use conformance::{conformance_commands, conformance_expected_output};
+    use interface::{
+        Command,
+        CopyCommand,
+        DistanceBucketing,
+        DivansCompressorOptions,
+        ErrMsg,
+        HEADER_FEATURE_OFFSET,
+        STREAM_FEATURE_DISTANCE_BUCKETING,
+        DISTANCE_POSTFIX_DIRECT,
+    };
+    use test_support::{encode_with_options, decode};
+
+    #[test]
+    fn test_distance_bucketing_split_join() {
+        for &(postfix_bits, num_direct) in [(0u8, 0u8), (0, 15), (1, 4), (2, 0), (2, 60), (3, 120)].iter() {
+            let bucketing = DistanceBucketing::new(postfix_bits, num_direct).unwrap();
+            for distance in (1u32..5000).chain([(1 << 24) + 7, 0xffffffff].iter().cloned()) {
+                let (postfix, coded) = bucketing.split(distance);
+                assert!(coded != 0);
+                assert_eq!(postfix == DISTANCE_POSTFIX_DIRECT, distance <= u32::from(num_direct));
+                assert_eq!(bucketing.join(postfix, coded), distance);
+            }
+        }
+        assert!(DistanceBucketing::new(4, 0).is_err());
+        assert!(DistanceBucketing::new(2, 6).is_err());
+        assert!(DistanceBucketing::new(1, 32).is_err());
+    }
+
+    #[test]
+    fn test_distance_bucketing_roundtrip() {
+        let mut commands = conformance_commands("prediction_mode");
+        commands.truncate(2);
+        commands.extend(conformance_commands("copy"));
+        for distance in [16u32, 32, 48, 16, 64, 17].iter() {
+            commands.push(Command::Copy(CopyCommand{distance: *distance, num_bytes: 12}));
+        }
+        let expected = conformance_expected_output(&commands[..]).unwrap();
+        for &(postfix_bits, num_direct) in [(0u8, 0u8), (0, 15), (2, 0), (2, 8), (3, 120)].iter() {
+            let mut opts = DivansCompressorOptions::default();
+            opts.distance_bucketing = DistanceBucketing::new(postfix_bits, num_direct).unwrap();
+            let stream = encode_with_options(&commands[..], opts).unwrap();
+            assert_eq!(stream[HEADER_FEATURE_OFFSET] & STREAM_FEATURE_DISTANCE_BUCKETING != 0, postfix_bits | num_direct != 0);
+            assert_eq!(decode(&stream[..]).unwrap(), expected);
+        }
+        let mut opts = DivansCompressorOptions::default();
+        opts.distance_bucketing = DistanceBucketing{postfix_bits: 2, num_direct: 3};
+        match encode_with_options(&commands[..], opts) {
+            Err(ErrMsg::InvalidDistanceBucketing(2, 3)) => {},
+            res => panic!("{:?}", res.map(|v| v.len())),
+        }
+    }
+}
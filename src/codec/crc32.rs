@@ -14,6 +14,7 @@ Redistribution and use in source and binary forms, with or without modification,
 #[allow(unused_imports)]
 use core;
 use super::crc32_table::TABLE16;
+use ::wire;
 pub fn crc32c_init() -> u32 {
     0
 }
@@ -38,7 +39,7 @@ pub fn crc32c_update(crc:u32, buf: &[u8]) -> u32 {
 pub fn fallback_crc32c_update(mut crc:u32, mut buf: &[u8]) -> u32 {
     crc = !crc;
     while buf.len() >= 16 {
-        crc ^= u32::from(buf[0]) | (u32::from(buf[1]) << 8) | (u32::from(buf[2]) << 16) | (u32::from(buf[3]) << 24);
+        crc ^= wire::u32_from_le(buf);
         crc = TABLE16[0][buf[15] as usize]
             ^ TABLE16[1][buf[14] as usize]
             ^ TABLE16[2][buf[13] as usize]
@@ -74,9 +75,7 @@ fn sse_crc32c_update(_crc:u32, _buf: &[u8]) -> u32 {
 unsafe fn sse_crc32c_update(mut crc:u32, mut buf: &[u8]) -> u32 {
     crc = !crc;
     while buf.len() >= 8 {
-        crc = core::arch::x86_64::_mm_crc32_u64(u64::from(crc),
-                                                u64::from(buf[0]) | (u64::from(buf[1]) << 8) | (u64::from(buf[2]) << 16) | (u64::from(buf[3]) << 24)
-                                                |(u64::from(buf[4])<<32) | (u64::from(buf[5]) << 40) | (u64::from(buf[6]) << 48) | (u64::from(buf[7]) << 56)) as u32;
+        crc = core::arch::x86_64::_mm_crc32_u64(u64::from(crc), wire::u64_from_le(buf)) as u32;
         buf = &buf.split_at(8).1;
     }
     for &b in buf {
@@ -1,17 +1,26 @@
 // This file contains a threaded decoder
 use core;
 use core::hash::Hasher;
-use interface::{DivansOpResult, DivansResult, DivansOutputResult, DivansInputResult, StreamDemuxer, StreamID, ErrMsg};
+use core::time::Duration;
+use interface::{DivansOpResult, DivansResult, DivansOutputResult, DivansInputResult, StreamDemuxer, StreamID, ErrMsg, DecodedStreamOptions, DecodeStats, ChecksumPolicy, ChecksumType, MixerWarmStart, HEADER_FEATURE_OFFSET, EXTENDED_FEATURE_IR_ONLY, extended_stream_features, freeze_adaptation_after, priors_reset_interval, extended_stream_features2};
 use mux::DevNull;
+use ::cancel::CancelToken;
 use ::probability::{CDF16};
 use ::slice_util::{AllocatedMemoryPrefix, AllocatedMemoryRange};
 use ::alloc_util::UninitializedOnAlloc;
 use ::divans_to_raw::DecoderSpecialization;
+use super::weights::MixerSnapshot;
 use super::literal::{LiteralState, LiteralSubstate};
+#[cfg(feature="trace")]
+use super::trace::TraceLog;
 use alloc::{SliceWrapper, Allocator, SliceWrapperMut};
 use super::crc32::{crc32c_init,crc32c_update};
+use ::wire;
+use ::mac::{self, HmacSha256};
+use ::output_digest::Xxh3;
 use super::interface::{
     MainThreadContext,
+    literal_adaptation_of,
     CMD_CODER,
     LIT_CODER,
 };
@@ -32,34 +41,60 @@ use ::interface::{
     free_cmd,
 };
 
-use threading::{MainToThread, PullAllocatedCommand, CommandResult, NUM_SERIAL_COMMANDS_BUFFERED, StaticCommand};
+use threading::{MainToThread, PullAllocatedCommand, CommandResult, NUM_SERIAL_COMMANDS_BUFFERED, StaticCommand, LiteralSpan};
+#[cfg(feature="std")]
+use multithreading::CrcWorker;
 
 pub struct DivansDecoderCodec<Cdf16:CDF16,
                           AllocU8:Allocator<u8>,
                               AllocCDF16:Allocator<Cdf16>,
                               AllocCommand:Allocator<StaticCommand>,
                           ArithmeticCoder:ArithmeticEncoderOrDecoder+NewWithAllocator<AllocU8>,
-                          LinearInputBytes: StreamDemuxer<AllocU8>> {
+                          LinearInputBytes: StreamDemuxer<AllocU8>,
+                          D:Hasher+Default=SubDigest> {
     pub ctx: MainThreadContext<Cdf16, AllocU8, AllocCDF16, ArithmeticCoder>,
     pub demuxer: LinearInputBytes,
     pub devnull: DevNull<AllocU8>,
     pub eof: bool,
     pub nop: LiteralCommand<AllocatedMemoryPrefix<u8, AllocU8>>,
     pub codec_traits: CodecTraitSelector,
-    pub crc: SubDigest,
+    pub crc: StreamDigest<D>,
     pub frozen_checksum: Option<u64>,
-    pub deserialized_crc:[u8;8],
+    pub deserialized_crc:[u8;wire::MAX_TRAILER_LENGTH],
     pub deserialized_crc_count: u8,
-    pub skip_checksum: bool,
+    pub checksum_policy: ChecksumPolicy,
     pub state_lit: LiteralState<AllocU8>,
     pub is_populating_ring_buffer: bool,
     pub state_populate_ring_buffer: Command<AllocatedMemoryPrefix<u8, AllocU8>>,
     pub specialization: DecoderSpecialization,
     pub outstanding_buffer_count: usize,
+    // when set, the worker decodes the literals too, from the literal stream buffers handed to it,
+    // and ships their bytes in literal_spans; the literal models are lent to it meanwhile
+    pub literals_on_worker: bool,
+    pub outstanding_literal_buffer_count: usize,
+    pub literal_spans: AllocatedMemoryPrefix<u8, AllocU8>,
+    pub literal_span_offset: usize, // of the next span in the literal being gathered
     pub cmd_buffer: AllocatedMemoryPrefix<StaticCommand, AllocCommand>,
     pub cmd_buffer_offset: usize,
     pub cmd_buffer_contains_eof: bool,
     pub pred_buffer: [PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>;2],
+    pub stream_options: DecodedStreamOptions,
+    pub prediction_mode_decoded: bool,
+    pub stats: DecodeStats,
+    // when set, each decoded command is parked in tapped_command, and decoding pauses with
+    // NeedsMoreOutput until the caller takes it
+    pub tap_commands: bool,
+    pub tapped_command: Option<Command<AllocatedMemoryPrefix<u8, AllocU8>>>,
+    // the command codec runs ahead of the output, so the output side looks at it too
+    pub cancel: CancelToken,
+    // when set, copies reaching before the start of the stream fail, and so do literal models
+    // that no longer hold valid CDFs at the end of it
+    pub paranoid: bool,
+    // when set, takes over hashing crc.crc; settle_crc folds its result back in
+    #[cfg(feature="std")]
+    pub crc_worker: Option<CrcWorker>,
+    #[cfg(feature="trace")]
+    pub trace: TraceLog,
 }
 
 impl<Cdf16:CDF16,
@@ -67,13 +102,14 @@ impl<Cdf16:CDF16,
      AllocCDF16:Allocator<Cdf16>,
      AllocCommand:Allocator<StaticCommand>,
      ArithmeticCoder:ArithmeticEncoderOrDecoder+NewWithAllocator<AllocU8>,
-     LinearInputBytes: Default+StreamDemuxer<AllocU8>> DivansDecoderCodec<Cdf16, AllocU8, AllocCDF16, AllocCommand, ArithmeticCoder, LinearInputBytes> {
+     LinearInputBytes: Default+StreamDemuxer<AllocU8>,
+     D:Hasher+Default> DivansDecoderCodec<Cdf16, AllocU8, AllocCDF16, AllocCommand, ArithmeticCoder, LinearInputBytes, D> {
     pub fn new(main_thread_context: MainThreadContext<Cdf16, AllocU8, AllocCDF16, ArithmeticCoder>,
                mcommand: &mut AllocCommand,
-               crc: SubDigest,
-               skip_checksum: bool) -> Self {
+               crc: StreamDigest<D>,
+               checksum_policy: ChecksumPolicy) -> Self {
         let codec_trait = construct_codec_trait_from_bookkeeping(&main_thread_context.lbk);
-        DivansDecoderCodec::<Cdf16, AllocU8, AllocCDF16, AllocCommand, ArithmeticCoder, LinearInputBytes> {
+        DivansDecoderCodec::<Cdf16, AllocU8, AllocCDF16, AllocCommand, ArithmeticCoder, LinearInputBytes, D> {
             ctx: main_thread_context,
             demuxer: LinearInputBytes::default(),
             codec_traits:codec_trait,
@@ -88,9 +124,13 @@ impl<Cdf16:CDF16,
             state_populate_ring_buffer:Command::nop(),
             specialization:DecoderSpecialization::default(),
             outstanding_buffer_count: 0,
-            deserialized_crc:[0u8;8],
+            literals_on_worker: false,
+            outstanding_literal_buffer_count: 0,
+            literal_spans: AllocatedMemoryPrefix::default(),
+            literal_span_offset: 0,
+            deserialized_crc:[0u8;wire::MAX_TRAILER_LENGTH],
             deserialized_crc_count: 0u8,
-            skip_checksum:skip_checksum,
+            checksum_policy,
             crc:crc,
             eof:false,
             cmd_buffer_offset:0,
@@ -98,21 +138,171 @@ impl<Cdf16:CDF16,
             cmd_buffer_contains_eof:false,
             pred_buffer: [empty_prediction_mode_context_map::<AllocatedMemoryPrefix<u8, AllocU8>>(),
                           empty_prediction_mode_context_map::<AllocatedMemoryPrefix<u8, AllocU8>>()],
+            stream_options: DecodedStreamOptions {
+                checksum_verified: checksum_policy.hashes_stream(),
+                ..DecodedStreamOptions::default()
+            },
+            prediction_mode_decoded: false,
+            stats: DecodeStats::default(),
+            tap_commands: false,
+            tapped_command: None,
+            cancel: CancelToken::default(),
+            paranoid: false,
+            #[cfg(feature="std")]
+            crc_worker: None,
+            #[cfg(feature="trace")]
+            trace: TraceLog::default(),
+        }
+    }
+    pub fn observe_header(&mut self, header: &[u8]) {
+        self.stream_options.window_size = header[5];
+        self.stream_options.stream_features = header[HEADER_FEATURE_OFFSET];
+        self.stream_options.extended_stream_features = extended_stream_features(header);
+        self.stream_options.freeze_adaptation_after = freeze_adaptation_after(header);
+        self.stream_options.priors_reset_interval = priors_reset_interval(header);
+        self.stream_options.extended_stream_features2 = extended_stream_features2(header);
+        self.stream_options.checksum = self.crc.checksum_type();
+    }
+    // counts a command towards freezing the literal models, and resets them if it is due, after the
+    // same command the command codec on the other thread does
+    fn obs_command_bytes(&mut self, len: u64) {
+        if self.literals_on_worker {
+            return; // the worker, holding the literal models, does
+        }
+        self.ctx.lbk.adaptation.obs_command_bytes(len);
+        if self.ctx.lbk.priors_reset.obs_command_bytes(len) {
+            self.ctx.reset_literal_priors();
+        }
+    }
+    fn is_ir_only(&self) -> bool {
+        self.stream_options.extended_stream_features & EXTENDED_FEATURE_IR_ONLY != 0
+    }
+    // None until the first prediction mode command has been decoded
+    pub fn options(&self) -> Option<DecodedStreamOptions> {
+        if self.prediction_mode_decoded {
+            Some(self.stream_options)
+        } else {
+            None
+        }
+    }
+    pub fn stats(&self) -> DecodeStats {
+        self.stats
+    }
+    pub fn take_mixer_snapshots(&mut self, out: &mut [MixerSnapshot]) -> usize {
+        self.ctx.lbk.mixer_snapshots.take(out)
+    }
+    pub fn mixer_snapshots_dropped(&self) -> u64 {
+        self.ctx.lbk.mixer_snapshots.dropped()
+    }
+    pub fn mixer_snapshot(&self) -> MixerSnapshot {
+        self.ctx.lbk.mixer_snapshots.current(self.ctx.lbk.btype_last, &self.ctx.lbk.model_weights)
+    }
+    #[cfg(feature="std")]
+    fn hash_input(&mut self, data: &[u8]) {
+        match self.crc_worker {
+            Some(ref mut crc_worker) => {
+                crc_worker.write(data);
+                self.crc.write_except_crc(data);
+            },
+            None => self.crc.write(data),
+        }
+    }
+    #[cfg(not(feature="std"))]
+    fn hash_input(&mut self, data: &[u8]) {
+        self.crc.write(data);
+    }
+    // waits for the crc worker, if any, and stores its crc; required before crc.crc is read
+    pub fn settle_crc(&mut self) {
+        #[cfg(feature="std")]
+        {
+            if let Some(crc_worker) = self.crc_worker.take() {
+                self.crc.crc = crc_worker.finish();
+            }
+        }
+    }
+    // the crc stored in the trailer of a ChecksumType::Crc32c stream, once it has been read, unless
+    // the policy is TrustCaller
+    pub fn expected_crc(&self) -> Option<u32> {
+        if self.crc.checksum_type() != ChecksumType::Crc32c {
+            return None;
+        }
+        self.expected_checksum().map(|crc| crc as u32)
+    }
+    // expected_crc, but for either ChecksumType
+    pub fn expected_checksum(&self) -> Option<u64> {
+        let crc_len = self.crc.checksum_type().digest_length();
+        if self.checksum_policy == ChecksumPolicy::TrustCaller || usize::from(self.deserialized_crc_count) < crc_len {
+            return None;
+        }
+        let mut crc = [0u8; wire::WIDE_DIGEST_LENGTH];
+        crc[..crc_len].clone_from_slice(&self.deserialized_crc[..crc_len]);
+        Some(wire::u64_from_le(&crc[..]))
+    }
+    // after NeedsMoreOutput, the room the next call needs to get past where this one stopped: all the
+    // decoded bytes waiting in the ring buffer; 0 while a tapped command waits to be taken instead
+    pub fn output_needed(&self) -> usize {
+        if self.tapped_command.is_some() {
+            return 0;
+        }
+        self.ctx.recoder.bytes_pending()
+    }
+    // whether the ring buffer holds the whole stream's final window: the trailer checks out and
+    // every decoded byte has been flushed
+    fn window_complete(&self) -> bool {
+        !self.ctx.recoder.ring_buffer.slice().is_empty() && self.ctx.recoder.bytes_pending() == 0
+            && self.check_trailer() == DivansResult::Success
+    }
+    // the ring buffer, once window_complete; the stream's last byte is at (bytes decoded - 1) & (len - 1).
+    // None after it has been taken
+    pub fn take_window(&mut self) -> Option<AllocU8::AllocatedMemory> {
+        if !self.window_complete() {
+            return None;
+        }
+        Some(core::mem::replace(&mut self.ctx.recoder.ring_buffer, AllocU8::AllocatedMemory::default()))
+    }
+    // the ring buffer, once window_complete, and where the next byte would have gone in it
+    pub fn window(&self) -> Option<(&[u8], usize)> {
+        if !self.window_complete() {
+            return None;
         }
+        let ring_buffer = self.ctx.recoder.ring_buffer.slice();
+        Some((ring_buffer, (self.ctx.recoder.num_bytes_encoded() & (ring_buffer.len() as u64 - 1)) as usize))
+    }
+    // returns a command taken from tapped_command to the allocator it came from
+    pub fn free_tapped_command(&mut self, mut cmd: Command<AllocatedMemoryPrefix<u8, AllocU8>>) {
+        free_cmd(&mut cmd, &mut self.ctx.m8.use_slab_arena::<UninitializedOnAlloc>());
     }
     pub fn free(&mut self, mcommand: &mut AllocCommand) {
         mcommand.free_cell(core::mem::replace(&mut self.cmd_buffer.0,
                                               AllocCommand::AllocatedMemory::default()));
         self.ctx.m8.get_base_alloc().free_cell(core::mem::replace(&mut self.state_lit.lc.data.0,
                                                                   AllocU8::AllocatedMemory::default()));
+        self.ctx.m8.get_base_alloc().free_cell(core::mem::replace(&mut self.literal_spans.0,
+                                                                  AllocU8::AllocatedMemory::default()));
         self.demuxer.free_demux(self.ctx.m8.get_base_alloc());
+        if let Some(mut cmd) = self.tapped_command.take() {
+            free_cmd(&mut cmd, &mut self.ctx.m8.use_slab_arena::<UninitializedOnAlloc>());
+        }
         for item in self.pred_buffer.iter_mut() {
             free_cmd(&mut Command::PredictionMode(core::mem::replace(item,
                                                                      empty_prediction_mode_context_map::<AllocatedMemoryPrefix<u8, AllocU8>>())),
-                     &mut self.ctx.m8.use_cached_allocation::<UninitializedOnAlloc>());
+                     &mut self.ctx.m8.use_slab_arena::<UninitializedOnAlloc>());
         }
         self.ctx.m8.free_ref();
     }
+    pub fn take_digest(&mut self) -> D {
+        self.crc.take_digest()
+    }
+    #[cfg(feature="trace")]
+    #[inline(always)]
+    fn trace_literal(&mut self, output_offset: usize) {
+        let (tag, arg) = self.state_lit.state.trace_code();
+        self.trace.observe(tag, arg, 0, output_offset);
+    }
+    #[cfg(not(feature="trace"))]
+    #[inline(always)]
+    fn trace_literal(&mut self, _output_offset: usize) {
+    }
     pub fn commands_or_data_to_receive(&self) -> bool {
         self.outstanding_buffer_count > 0 || ( // if we have outstanding buffer
             self.demuxer.encountered_eof() && self.demuxer.data_ready(CMD_CODER as StreamID) == 0) // or we have flushed everything we will have
@@ -127,21 +317,35 @@ impl<Cdf16:CDF16,
             let adjusted_input_bytes_offset = self.demuxer.write_linear(
                 adjusted_input_bytes,
                 self.ctx.m8.get_base_alloc());
-            if !self.skip_checksum {
-                self.crc.write(adjusted_input_bytes.split_at(adjusted_input_bytes_offset).0);
+            if self.checksum_policy.hashes_stream() {
+                self.hash_input(adjusted_input_bytes.split_at(adjusted_input_bytes_offset).0);
+            }
+            #[cfg(feature="trace")]
+            {
+                self.trace.input_pos += adjusted_input_bytes_offset as u64;
             }
             *input_offset += adjusted_input_bytes_offset;
         }
-        if self.demuxer.encountered_eof() && usize::from(self.deserialized_crc_count) != self.deserialized_crc.len() {
-            let crc_bytes_remaining = self.deserialized_crc.len() - usize::from(self.deserialized_crc_count);
+        if self.demuxer.encountered_eof() && usize::from(self.deserialized_crc_count) != self.crc.trailer_length() {
+            let crc_bytes_remaining = self.crc.trailer_length() - usize::from(self.deserialized_crc_count);
             let amt_to_copy = core::cmp::min(input.len() - *input_offset, crc_bytes_remaining);
             self.deserialized_crc.split_at_mut(usize::from(self.deserialized_crc_count)).1.split_at_mut(amt_to_copy).0.clone_from_slice(
                 input.split_at(*input_offset).1.split_at(amt_to_copy).0);
             self.deserialized_crc_count += amt_to_copy as u8;
             *input_offset += amt_to_copy;
         }
+        if self.literals_on_worker {
+            if self.demuxer.data_ready(LIT_CODER as StreamID) != 0
+                && worker.push(LIT_CODER as StreamID, self.demuxer.edit(LIT_CODER as StreamID)).is_ok() {
+                self.outstanding_literal_buffer_count += 1;
+            }
+            // the worker may be waiting on literals it has none of
+            if self.outstanding_literal_buffer_count == 0 && !self.eof && !self.demuxer.encountered_eof() {
+                return DivansInputResult::NeedsMoreInput;
+            }
+        }
         if self.demuxer.data_ready(CMD_CODER as StreamID) != 0 {
-            match worker.push(self.demuxer.edit(CMD_CODER as StreamID)) {
+            match worker.push(CMD_CODER as StreamID, self.demuxer.edit(CMD_CODER as StreamID)) {
                 Ok(_) => {
                     self.outstanding_buffer_count += 1;
                 },
@@ -162,18 +366,33 @@ impl<Cdf16:CDF16,
         }
     }
     #[cfg_attr(not(feature="no-inline"), inline(always))]
-    fn populate_ring_buffer(&mut self,
-                            output: &mut [u8],
-                            output_offset: &mut usize) -> DivansOutputResult {
+    fn populate_ring_buffer<Output:DecodeOutput>(&mut self,
+                                                 output: &mut [u8],
+                                                 output_offset: &mut usize) -> DivansOutputResult {
         if !self.is_populating_ring_buffer {
             return DivansOutputResult::Success;
         }
-        match self.ctx.recoder.encode_cmd(&mut self.state_populate_ring_buffer, output, output_offset) {
-            DivansOutputResult::Success => free_cmd(&mut self.state_populate_ring_buffer,
-                                                    &mut self.ctx.m8.use_cached_allocation::<
-                                                            UninitializedOnAlloc>()),
+        // nobody wants the bytes of an IR-only stream whose commands are tapped, and its literal
+        // contexts do not depend on them
+        let result = if self.tap_commands && self.is_ir_only() {
+            DivansOutputResult::Success
+        } else {
+            self.ctx.recoder.encode_cmd(&mut self.state_populate_ring_buffer, output, output_offset)
+        };
+        match Output::out_of_room(result) {
+            DivansOutputResult::Success => {
+                if !self.is_ir_only() && !self.literals_on_worker {
+                    self.ctx.lbk.obs_populated_command(&self.state_populate_ring_buffer, &self.ctx.recoder);
+                }
+                if self.tap_commands {
+                    self.tapped_command = Some(core::mem::replace(&mut self.state_populate_ring_buffer, Command::nop()));
+                } else {
+                    free_cmd(&mut self.state_populate_ring_buffer,
+                             &mut self.ctx.m8.use_slab_arena::<UninitializedOnAlloc>());
+                }
+            },
             DivansOutputResult::Failure(f) => {
-                free_cmd(&mut self.state_populate_ring_buffer, &mut self.ctx.m8.use_cached_allocation::<
+                free_cmd(&mut self.state_populate_ring_buffer, &mut self.ctx.m8.use_slab_arena::<
                         UninitializedOnAlloc>());
                 return DivansOutputResult::Failure(f);
             },
@@ -183,33 +402,53 @@ impl<Cdf16:CDF16,
         DivansOutputResult::Success
     }
     #[cold]
-    fn process_eof(&mut self, output: &mut [u8], output_offset: &mut usize) -> DivansResult {
-        match self.ctx.recoder.flush(output, output_offset) {
+    fn process_eof<Output:DecodeOutput>(&mut self, output: &mut [u8], output_offset: &mut usize) -> DivansResult {
+        self.settle_crc();
+        // an authenticated stream holds back the last of its output until the tag checks out
+        if self.crc.mac.is_some() {
+            match self.check_trailer() {
+                DivansResult::Success => {},
+                res => return res,
+            }
+        }
+        match Output::out_of_room(self.ctx.recoder.flush(output, output_offset)) {
             DivansOutputResult::Success => {},
             need_something => return DivansResult::from(need_something),
         }
-
-        if usize::from(self.deserialized_crc_count) != self.deserialized_crc.len() {
+        match self.check_trailer() {
+            DivansResult::Success if self.paranoid && !self.literals_on_worker && !self.ctx.literal_priors_valid() => {
+                DivansResult::Failure(ErrMsg::InvalidCdf)
+            },
+            res => res, // DONE decoding on Success
+        }
+    }
+    // whether the trailer read so far ends in SPLICE_TAG, so a stream of its own follows; never
+    // for an authenticated stream, whose tag would not cover anything appended after it
+    pub fn another_stream_follows(&self) -> bool {
+        let checksum_len = self.crc.checksum_length();
+        self.crc.mac.is_none() && self.crc.framing.is_none()
+            && usize::from(self.deserialized_crc_count) == checksum_len
+            && self.deserialized_crc[checksum_len - wire::SPLICE_TAG.len()..checksum_len] == wire::SPLICE_TAG
+    }
+    fn check_trailer(&self) -> DivansResult {
+        let (mut trailer, trailer_len) = self.crc.trailer();
+        let checksum_len = self.crc.checksum_length();
+        if self.another_stream_follows() {
+            trailer[checksum_len - wire::SPLICE_TAG.len()..checksum_len].clone_from_slice(&wire::SPLICE_TAG[..]);
+        }
+        if usize::from(self.deserialized_crc_count) != trailer_len {
             return DivansResult::NeedsMoreInput;
         }
-        let crc = self.crc.finish();
-        let checksum = [crc as u8 & 255,
-                        (crc >> 8) as u8 & 255,
-                        (crc >> 16) as u8 & 255,
-                        (crc >> 24) as u8 & 255,
-                        b'a',
-                        b'n',
-                        b's',
-                        b'~'];
-        for (index, (chk, fil)) in checksum.iter().zip(
-            self.deserialized_crc.iter()).enumerate() {
-            if *chk != *fil {
-                if index >= 4 || !self.skip_checksum {
-                    return DivansResult::Failure(ErrMsg::BadChecksum(*chk, *fil));
-                }
-            }
+        match self.checksum_policy.check_trailer(&trailer[..checksum_len], &self.deserialized_crc[..],
+                                                 self.crc.checksum_type().digest_length()) {
+            DivansOpResult::Success => {},
+            DivansOpResult::Failure(m) => return DivansResult::Failure(m),
+        }
+        if !mac::tags_match(&trailer[checksum_len..trailer_len],
+                            &self.deserialized_crc[checksum_len..trailer_len]) {
+            return DivansResult::Failure(ErrMsg::BadAuthenticationTag);
         }
-        return DivansResult::Success; // DONE decoding
+        DivansResult::Success
     }
     /*
     fn interpret_thread_literal(&mut self, lit: LiteralCommand<AllocatedMemoryPrefix<u8, AllocU8>>) {
@@ -218,7 +457,7 @@ impl<Cdf16:CDF16,
             let num_bytes = lit.data.1;
             assert_eq!(self.state_lit.lc.data.0.slice().len(), 0);
             self.state_lit.lc = lit;
-            self.state_lit.lc.data = self.ctx.m8.use_cached_allocation::<UninitializedOnAlloc>().alloc_cell(num_bytes);
+            self.state_lit.lc.data = self.ctx.m8.use_slab_arena::<UninitializedOnAlloc>().alloc_cell(num_bytes);
             DEBUG_TRACK(33);
         } else {
             DEBUG_TRACK(34);
@@ -231,6 +470,27 @@ impl<Cdf16:CDF16,
                                                                 worker:&mut Worker,
                                                                 output: &mut [u8],
                                                                 output_offset: &mut usize) -> DecoderResult{
+        self.decode_process_output_of::<ResumableOutput, Worker>(worker, output, output_offset, None)
+    }
+    // decode_process_output, but each time the worker has no commands ready, waits for them no
+    // longer than pull_timeout (if given) before returning DecoderResult::WouldBlock
+    #[cfg_attr(not(feature="no-inline"), inline(always))]
+    pub fn decode_process_output_within<Worker: MainToThread<AllocU8>+PullAllocatedCommand<AllocU8, AllocCommand>>(&mut self,
+                                                                worker:&mut Worker,
+                                                                output: &mut [u8],
+                                                                output_offset: &mut usize,
+                                                                pull_timeout: Option<Duration>) -> DecoderResult{
+        self.decode_process_output_of::<ResumableOutput, Worker>(worker, output, output_offset, pull_timeout)
+    }
+    // decode_process_output_within, with Output saying whether running out of output pauses the
+    // decode or ends it
+    #[cfg_attr(not(feature="no-inline"), inline(always))]
+    pub fn decode_process_output_of<Output: DecodeOutput,
+                                    Worker: MainToThread<AllocU8>+PullAllocatedCommand<AllocU8, AllocCommand>>(&mut self,
+                                                                worker:&mut Worker,
+                                                                output: &mut [u8],
+                                                                output_offset: &mut usize,
+                                                                pull_timeout: Option<Duration>) -> DecoderResult{
         //{DEBUG_TRACK(18)};
         loop {
             match self.state_lit.state{
@@ -240,7 +500,7 @@ impl<Cdf16:CDF16,
                     match match self.codec_traits {
                         CodecTraitSelector::DefaultTrait(tr) =>
                             self.state_lit.encode_or_decode_content_bytes(
-                                self.ctx.m8.get_base_alloc(),
+                                &mut Some(self.ctx.m8.get_base_alloc()),
                                 &mut self.ctx.lit_coder,
                                 &mut self.ctx.lbk,
                                 &mut self.ctx.lit_high_priors,
@@ -254,7 +514,7 @@ impl<Cdf16:CDF16,
                                 &self.specialization),
                         CodecTraitSelector::MixingTrait(mtr) =>
                             self.state_lit.encode_or_decode_content_bytes(
-                                self.ctx.m8.get_base_alloc(),
+                                &mut Some(self.ctx.m8.get_base_alloc()),
                                 &mut self.ctx.lit_coder,
                                 &mut self.ctx.lbk,
                                 &mut self.ctx.lit_high_priors,
@@ -268,59 +528,80 @@ impl<Cdf16:CDF16,
                                 &self.specialization),                            
                     } { 
                                 DivansResult::Success => {
+                                    self.trace_literal(*output_offset);
                                     assert!(match self.state_lit.state{LiteralSubstate::FullyDecoded => true, _ => false});
+                                    let num_bytes = self.state_lit.lc.data.len();
+                                    self.obs_command_bytes(num_bytes as u64);
                                     self.is_populating_ring_buffer = true;
                                     self.state_populate_ring_buffer = Command::Literal(
                                         core::mem::replace(&mut self.state_lit.lc,
                                                            LiteralCommand::<AllocatedMemoryPrefix<u8, AllocU8>>::nop()));
                                 },
                                 retval => {
+                                    self.trace_literal(*output_offset);
                                     return DecoderResult::Processed(retval);
                                 }
                     }
                 },
             }
-            match self.populate_ring_buffer(output, output_offset) {
+            match self.populate_ring_buffer::<Output>(output, output_offset) {
                 DivansOutputResult::Success => {},
                 need_something => return DecoderResult::Processed(DivansResult::from(need_something)),
             }
+            if self.tapped_command.is_some() {
+                return DecoderResult::Processed(DivansResult::NeedsMoreOutput);
+            }
+            if self.cancel.is_cancelled() {
+                return DecoderResult::Processed(DivansResult::Failure(ErrMsg::Cancelled));
+            }
             if self.eof {
-                return DecoderResult::Processed(self.process_eof(output, output_offset));
+                return DecoderResult::Processed(self.process_eof::<Output>(output, output_offset));
             }
             if self.cmd_buffer_offset >= self.cmd_buffer.1 as usize && !self.cmd_buffer_contains_eof {
                 self.cmd_buffer_offset = 0;
                 self.cmd_buffer.1 = 0; //reset the command buffer to zero
-                let mut consumed_data = [AllocatedMemoryRange::<u8, AllocU8>::default(),
-                                         AllocatedMemoryRange::<u8, AllocU8>::default()];
+                let mut consumed_data = [[AllocatedMemoryRange::<u8, AllocU8>::default(),
+                                          AllocatedMemoryRange::<u8, AllocU8>::default()],
+                                         [AllocatedMemoryRange::<u8, AllocU8>::default(),
+                                          AllocatedMemoryRange::<u8, AllocU8>::default()]];
                 let status;
                 {
                     assert_eq!(self.pred_buffer[0].has_context_speeds(), false);
                     assert_eq!(self.pred_buffer[1].has_context_speeds(), false);
-                    status = worker.pull_command_buf(&mut self.cmd_buffer, &mut consumed_data, &mut self.pred_buffer);
+                    status = match pull_timeout {
+                        Some(timeout) => worker.pull_command_buf_within(&mut self.cmd_buffer, &mut self.literal_spans, &mut consumed_data, &mut self.pred_buffer, timeout),
+                        None => worker.pull_command_buf(&mut self.cmd_buffer, &mut self.literal_spans, &mut consumed_data, &mut self.pred_buffer),
+                    };
                 }
                 let mut need_input = false;
-                for dat in consumed_data.iter_mut() {
+                for (stream_id, dat) in consumed_data.iter_mut().enumerate().flat_map(
+                    |(stream_id, data)| data.iter_mut().map(move |dat| (stream_id as StreamID, dat))) {
                     if dat.0.len() == 0 {
                         continue; //FIXME: should we yield here?
                         // assert_eq!(Worker::COOPERATIVE_MAIN, true);
                     }
-                    self.outstanding_buffer_count -= 1;
-                    match worker.push(self.demuxer.edit(CMD_CODER as StreamID)) {
+                    let outstanding = if stream_id == LIT_CODER as StreamID {
+                        &mut self.outstanding_literal_buffer_count
+                    } else {
+                        &mut self.outstanding_buffer_count
+                    };
+                    *outstanding -= 1;
+                    match worker.push(stream_id, self.demuxer.edit(stream_id)) {
                         Ok(_) => {
-                            self.outstanding_buffer_count += 1;
+                            *outstanding += 1;
                         },
                         Err(_) => {
                             // this is tricky logic:
                             // if there are no outstanding buffers and we have either not encountered the EOf or still have bytes avail to send
-                            // to the cmd stream
+                            // to the stream
                             // then we need to signal to our caller that we need input for the worker
-                            if self.outstanding_buffer_count == 0 && self.eof == false && (
-                                self.demuxer.data_ready(CMD_CODER as StreamID) != 0 || !self.demuxer.encountered_eof()) {
+                            if *outstanding == 0 && self.eof == false && (
+                                self.demuxer.data_ready(stream_id) != 0 || !self.demuxer.encountered_eof()) {
                                 need_input = true;
                             }
                         },
                     }
-                    let possible_replacement = self.demuxer.edit(CMD_CODER as StreamID);
+                    let possible_replacement = self.demuxer.edit(stream_id);
                     let possible_replacement_len = possible_replacement.0.slice().len();
                     if possible_replacement_len == 0 { // FIXME: do we want to replace, if twice as big?
                         core::mem::swap(&mut possible_replacement.0, &mut dat.0);
@@ -329,7 +610,6 @@ impl<Cdf16:CDF16,
                             dat.0.slice_mut()[..possible_replacement_len].clone_from_slice(possible_replacement.0.slice());
                             core::mem::swap(&mut possible_replacement.0, &mut dat.0);
                         }
-                        //self.ctx.m8.use_cached_allocation::<UninitializedOnAlloc>().free_cell(AllocatedMemoryPrefix(dat.0, 0));
                         self.ctx.m8.free_cell(core::mem::replace(&mut dat.0, AllocU8::AllocatedMemory::default()));
                     }
                 }
@@ -337,6 +617,8 @@ impl<Cdf16:CDF16,
                     CommandResult::Ok => {},
                     CommandResult::Err(e) => return DecoderResult::Processed(DivansResult::Failure(e)),
                     CommandResult::Eof => self.cmd_buffer_contains_eof = true,
+                    // with the command buffer left empty, so the next call pulls again
+                    CommandResult::WouldBlock => return DecoderResult::WouldBlock,
                 }
                 if need_input {
                     return DecoderResult::Processed(DivansResult::NeedsMoreInput);
@@ -344,7 +626,7 @@ impl<Cdf16:CDF16,
             }
             if self.cmd_buffer_offset >= self.cmd_buffer.1 as usize{
                 if self.cmd_buffer_contains_eof {
-                    return DecoderResult::Processed(self.process_eof(output, output_offset));
+                    return DecoderResult::Processed(self.process_eof::<Output>(output, output_offset));
                 } else {
                     if Worker::COOPERATIVE_MAIN  {
                         return DecoderResult::Yield;
@@ -356,23 +638,61 @@ impl<Cdf16:CDF16,
             let cur_cmd = &mut self.cmd_buffer.slice_mut()[offt];
             self.cmd_buffer_offset += 1;
             if let &mut Command::Copy(cp) = cur_cmd {
+                if self.paranoid && u64::from(cp.distance) > self.stats.total_bytes() {
+                    return DecoderResult::Processed(DivansResult::Failure(ErrMsg::CopyBeforeStreamStart));
+                }
+                self.stats.copy_commands += 1;
+                self.stats.copy_bytes += u64::from(cp.num_bytes);
+                self.stats.copy_distance_sum += u64::from(cp.distance);
+                self.obs_command_bytes(u64::from(cp.num_bytes));
+                divans_trace!(num_bytes = cp.num_bytes, distance = cp.distance; "decoded copy");
                 self.is_populating_ring_buffer = true;
                 self.state_populate_ring_buffer=Command::Copy(cp);
+            } else if let &mut Command::Literal(LiteralCommand{data:LiteralSpan::Span{start, len, remaining}, high_entropy, ..}) = cur_cmd {
+                // decoded on the worker already: gathered here, a span at a time, for the ring buffer
+                let offset = self.literal_span_offset;
+                if offset == 0 {
+                    let num_bytes = len as usize + remaining as usize;
+                    self.stats.literal_commands += 1;
+                    self.stats.literal_bytes += num_bytes as u64;
+                    divans_trace!(num_bytes = num_bytes, high_entropy = high_entropy; "decoded literal");
+                    self.state_lit.lc.data = self.ctx.m8.use_slab_arena::<UninitializedOnAlloc>().alloc_cell(num_bytes);
+                    self.state_lit.lc.high_entropy = high_entropy;
+                }
+                let (start, len) = (start as usize, len as usize);
+                self.state_lit.lc.data.slice_mut()[offset..offset + len].clone_from_slice(
+                    &self.literal_spans.0.slice()[start..start + len]);
+                if remaining != 0 {
+                    self.literal_span_offset = offset + len;
+                } else {
+                    self.literal_span_offset = 0;
+                    self.is_populating_ring_buffer = true;
+                    self.state_populate_ring_buffer = Command::Literal(
+                        core::mem::replace(&mut self.state_lit.lc,
+                                           LiteralCommand::<AllocatedMemoryPrefix<u8, AllocU8>>::nop()));
+                }
             } else if let &mut Command::Literal(ref lit) = cur_cmd {
                 let num_bytes = lit.data.len();
-                self.state_lit.lc.data = self.ctx.m8.use_cached_allocation::<UninitializedOnAlloc>().alloc_cell(num_bytes);
-                let last_8 = self.ctx.recoder.last_8_literals();
-                self.ctx.lbk.last_8_literals = //FIXME(threading) only should be run in the main thread
-                    u64::from(last_8[0])
-                    | (u64::from(last_8[1])<<0x8)
-                    | (u64::from(last_8[2])<<0x10)
-                    | (u64::from(last_8[3])<<0x18)
-                    | (u64::from(last_8[4])<<0x20)
-                    | (u64::from(last_8[5])<<0x28)
-                    | (u64::from(last_8[6])<<0x30)
-                    | (u64::from(last_8[7])<<0x38);
+                self.stats.literal_commands += 1;
+                self.stats.literal_bytes += num_bytes as u64;
+                divans_trace!(num_bytes = num_bytes, high_entropy = lit.high_entropy; "decoded literal");
+                self.state_lit.lc.data = self.ctx.m8.use_slab_arena::<UninitializedOnAlloc>().alloc_cell(num_bytes);
+                self.state_lit.lc.high_entropy = lit.high_entropy;
+                if !self.is_ir_only() { // otherwise lbk keeps the literals alone
+                    let last_8 = self.ctx.recoder.last_8_literals();
+                    self.ctx.lbk.last_8_literals = //FIXME(threading) only should be run in the main thread
+                        u64::from(last_8[0])
+                        | (u64::from(last_8[1])<<0x8)
+                        | (u64::from(last_8[2])<<0x10)
+                        | (u64::from(last_8[3])<<0x18)
+                        | (u64::from(last_8[4])<<0x20)
+                        | (u64::from(last_8[5])<<0x28)
+                        | (u64::from(last_8[6])<<0x30)
+                        | (u64::from(last_8[7])<<0x38);
+                }
                 let new_state = self.state_lit.get_nibble_code_state(0, &self.state_lit.lc, self.demuxer.read_buffer()[LIT_CODER].bytes_avail());
                 self.state_lit.state = new_state;
+                self.trace_literal(*output_offset);
                 if Worker::COOPERATIVE_MAIN {
                     return DecoderResult::Yield;
                 }
@@ -380,25 +700,50 @@ impl<Cdf16:CDF16,
                 match cur_cmd {
                     &mut Command::PredictionMode(_) => {
                     let mut pred_mode = empty_prediction_mode_context_map::<AllocatedMemoryPrefix<u8, AllocU8>>();
+                        self.stats.prediction_mode_commands += 1;
                         core::mem::swap(&mut pred_mode, &mut self.pred_buffer[1]);
                         core::mem::swap(&mut pred_mode, &mut self.pred_buffer[0]); // shift pred_buffer[1] to pred_buffer[0] and extract [0]
                         
-                        let ret = self.ctx.lbk.obs_prediction_mode_context_map(
-                            &pred_mode,
-                            &mut self.ctx.mcdf16);
-                        match ret {
-                            DivansOpResult::Success => {},
-                            _ => return DecoderResult::Processed(DivansResult::from(ret)),
+                        if !self.literals_on_worker {
+                            let ret = self.ctx.obs_prediction_mode_context_map(&pred_mode);
+                            match ret {
+                                DivansOpResult::Success => {},
+                                _ => return DecoderResult::Processed(DivansResult::from(ret)),
+                            }
+                            self.codec_traits = construct_codec_trait_from_bookkeeping(&self.ctx.lbk);
+                        }
+                        if !self.prediction_mode_decoded {
+                            self.prediction_mode_decoded = true;
+                            self.stream_options.literal_prediction_mode = pred_mode.literal_prediction_mode();
+                            self.stream_options.mixing_level = pred_mode.get_mixing_math();
+                            self.stream_options.context_map = pred_mode.get_mixing_values().iter().any(|v| *v != 4);
+                            self.stream_options.literal_adaptation = literal_adaptation_of(&pred_mode);
+                            self.stream_options.mixer_warm_start = MixerWarmStart::of(&pred_mode);
+                        }
+                        if self.tap_commands {
+                            let m8 = self.ctx.m8.get_base_alloc();
+                            self.tapped_command = Some(Command::PredictionMode(PredictionModeContextMap{
+                                literal_context_map: pred_mode.literal_context_map.duplicate(m8),
+                                predmode_speed_and_distance_context_map: pred_mode.predmode_speed_and_distance_context_map.duplicate(m8),
+                            }));
                         }
-                        self.codec_traits = construct_codec_trait_from_bookkeeping(&self.ctx.lbk);
                         match worker.push_context_map(pred_mode) {
                             Ok(_) => {},
                             Err(_) => panic!("thread unable to accept 2 concurrent context map"),
                         }
                     },
                     &mut Command::BlockSwitchLiteral(new_block_type) => {
-                        self.ctx.lbk.obs_literal_block_switch(new_block_type.clone());
-                        self.codec_traits = construct_codec_trait_from_bookkeeping(&self.ctx.lbk);
+                        self.stats.literal_block_switches += 1;
+                        if self.stream_options.stride.is_none() {
+                            self.stream_options.stride = Some(new_block_type.stride() & 0xf);
+                        }
+                        if !self.literals_on_worker {
+                            self.ctx.lbk.obs_literal_block_switch(new_block_type.clone());
+                            self.codec_traits = construct_codec_trait_from_bookkeeping(&self.ctx.lbk);
+                        }
+                        if self.tap_commands {
+                            self.tapped_command = Some(Command::BlockSwitchLiteral(new_block_type));
+                        }
                     },
                     &mut Command::BlockSwitchCommand(mcc) => {
                         self.is_populating_ring_buffer = true;
@@ -409,6 +754,9 @@ impl<Cdf16:CDF16,
                         self.state_populate_ring_buffer=Command::BlockSwitchDistance(mcc);
                     },
                     &mut Command::Dict(dc) => {
+                        self.stats.dict_commands += 1;
+                        self.stats.dict_bytes += u64::from(dc.final_size);
+                        self.obs_command_bytes(u64::from(dc.final_size));
                         self.is_populating_ring_buffer = true;
                         self.state_populate_ring_buffer=Command::Dict(dc);
                     },  
@@ -429,7 +777,7 @@ impl<Cdf16:CDF16,
         }
         match self.decode_process_output(worker, output, output_offset) {
             DecoderResult::Processed(retval) => retval,
-            DecoderResult::Yield => unreachable!(),
+            DecoderResult::Yield | DecoderResult::WouldBlock => unreachable!(),
         }
     }
 }
@@ -457,6 +805,95 @@ impl Default for SubDigest {
         default_crc()
     }
 }
+
+// The CRC32c that is stored in the trailer alongside a caller-selected digest
+// that is fed exactly the same bytes, so integrators can hash the stream in one pass.
+// finish() always reports what the trailer holds: the CRC32c, or the XXH3-64 of a
+// ChecksumType::Xxh3 stream, which is hashed in place of the CRC32c.
+// An authenticated stream also feeds the keyed mac, whose tag follows the CRC32c in the trailer.
+#[derive(Clone, Copy, Debug)]
+pub struct StreamDigest<D:Hasher+Default=SubDigest> {
+    pub crc: SubDigest,
+    pub wide: Option<Xxh3>, // set for ChecksumType::Xxh3 before anything is written
+    pub digest: D,
+    pub mac: Option<HmacSha256>,
+    pub framing: Option<wire::FramingTag>, // in place of CHECKSUM_TAG in the trailer
+}
+
+impl<D:Hasher+Default> StreamDigest<D> {
+    // for when the crc is hashed elsewhere, e.g. by a CrcWorker
+    pub fn write_except_crc(&mut self, data:&[u8]) {
+        self.digest.write(data);
+        if let Some(ref mut mac) = self.mac {
+            mac.update(data);
+        }
+    }
+    pub fn take_digest(&mut self) -> D {
+        core::mem::replace(&mut self.digest, D::default())
+    }
+    pub fn trailer_length(&self) -> usize {
+        if self.mac.is_some() {
+            self.checksum_length() + wire::MAC_LENGTH
+        } else {
+            self.checksum_length()
+        }
+    }
+    // the bytes of the trailer before any authentication tag
+    pub fn checksum_length(&self) -> usize {
+        wire::checksum_length(self.framing.as_ref(), self.checksum_type())
+    }
+    pub fn set_checksum_type(&mut self, checksum: ChecksumType) {
+        self.wide = match checksum {
+            ChecksumType::Crc32c => None,
+            ChecksumType::Xxh3 => Some(Xxh3::default()),
+        };
+    }
+    pub fn checksum_type(&self) -> ChecksumType {
+        if self.wide.is_some() {
+            ChecksumType::Xxh3
+        } else {
+            ChecksumType::Crc32c
+        }
+    }
+    // the trailer for the bytes written so far, and how much of the array it fills
+    pub fn trailer(&self) -> ([u8; wire::MAX_TRAILER_LENGTH], usize) {
+        self.trailer_for(self.finish())
+    }
+    pub fn trailer_for(&self, crc: u64) -> ([u8; wire::MAX_TRAILER_LENGTH], usize) {
+        wire::stream_trailer(crc, self.checksum_type(), self.framing.as_ref(), self.mac.map(|mac| mac.tag()))
+    }
+}
+
+impl<D:Hasher+Default> Default for StreamDigest<D> {
+    #[inline(always)]
+    fn default() -> Self {
+        StreamDigest::<D> {
+            crc: default_crc(),
+            wide: None,
+            digest: D::default(),
+            mac: None,
+            framing: None,
+        }
+    }
+}
+
+impl<D:Hasher+Default> Hasher for StreamDigest<D> {
+    #[inline(always)]
+    fn write(&mut self, data:&[u8]) {
+        match self.wide {
+            Some(ref mut wide) => wide.write(data),
+            None => self.crc.write(data),
+        }
+        self.write_except_crc(data);
+    }
+    #[inline(always)]
+    fn finish(&self) -> u64 {
+        match self.wide {
+            Some(ref wide) => wide.finish(),
+            None => self.crc.finish(),
+        }
+    }
+}
 pub fn empty_prediction_mode_context_map<ISl:SliceWrapper<u8>+Default>() -> PredictionModeContextMap<ISl> {
     PredictionModeContextMap::<ISl> {
         literal_context_map:ISl::default(),
@@ -464,7 +901,76 @@ pub fn empty_prediction_mode_context_map<ISl:SliceWrapper<u8>+Default>() -> Pred
     }
 }
 
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
 pub enum DecoderResult {
     Processed(DivansResult),
     Yield,
+    WouldBlock, // see decode_process_output_within
+}
+
+// What the decoder does once output is full.  A resumable decode pauses with NeedsMoreOutput, keeping
+// the bytes it could not write in the ring buffer for the next call; a contiguous one was handed room
+// for all the output the stream decodes to, so it fails with OutputTooSmall instead, and is never
+// called again to resume.
+pub trait DecodeOutput {
+    const RESUMABLE: bool;
+    #[inline(always)]
+    fn out_of_room(result: DivansOutputResult) -> DivansOutputResult {
+        match result {
+            DivansOutputResult::NeedsMoreOutput if !Self::RESUMABLE => DivansOutputResult::Failure(ErrMsg::OutputTooSmall),
+            result => result,
+        }
+    }
+}
+pub struct ResumableOutput;
+impl DecodeOutput for ResumableOutput {
+    const RESUMABLE: bool = true;
+}
+pub struct ContiguousOutput;
+impl DecodeOutput for ContiguousOutput {
+    const RESUMABLE: bool = false;
+}
+
+#[cfg(all(test, feature="std", feature="encoder", feature="decoder"))]
+mod test {
+    use conformance::{conformance_commands, conformance_expected_output};
+    use decoded_command_iter::DecodedCommandIter;
+    use interface::{
+        DivansCompressorOptions,
+        HEADER_EXTENDED_FEATURE_OFFSET,
+        EXTENDED_FEATURE_IR_ONLY,
+    };
+    use test_support::{new_decompressor, encode_with_options, decode, decoded_options, assert_same_command};
+
+    #[test]
+    fn test_ir_only_stream() {
+        let cmds = conformance_commands("mixed");
+        let expected = conformance_expected_output(&cmds[..]).unwrap();
+        let mut opts = DivansCompressorOptions::default();
+        opts.extended_stream_features = EXTENDED_FEATURE_IR_ONLY;
+        let stream = encode_with_options(&cmds[..], opts).unwrap();
+        assert_eq!(stream[HEADER_EXTENDED_FEATURE_OFFSET], EXTENDED_FEATURE_IR_ONLY);
+        // the bytes can still be rebuilt by a decoder that wants them
+        assert_eq!(decode(&stream[..]).unwrap(), expected);
+        for multithread in [false, true].iter() {
+            assert_eq!(decoded_options(&stream[..], *multithread).unwrap().extended_stream_features,
+                       EXTENDED_FEATURE_IR_ONLY);
+        }
+        let decompressor = new_decompressor(false, false);
+        let mut remaining = &stream[..];
+        let mut input_buffer = [0u8; 64];
+        let mut iter = DecodedCommandIter::new(decompressor, &mut input_buffer[..], |buf: &mut [u8]| {
+            let len = core::cmp::min(buf.len(), remaining.len());
+            buf[..len].clone_from_slice(&remaining[..len]);
+            remaining = &remaining[len..];
+            len
+        });
+        let mut count = 0usize;
+        while let Some(cmd) = iter.next_borrowed() {
+            assert_same_command(&cmds[count], &cmd.unwrap());
+            count += 1;
+        }
+        assert_eq!(count, cmds.len());
+        iter.into_inner().free();
+    }
 }
@@ -1,10 +1,10 @@
 use core;
-use interface::{DivansResult, ErrMsg, StreamMuxer, StreamDemuxer};
-use alloc::Allocator;
+use interface::{DivansResult, ErrMsg, StreamMuxer, StreamDemuxer, EXTENDED_FEATURE_DICT_TRANSFORM_MODEL};
+use alloc::{Allocator, SliceWrapper};
 use brotli::transform::TransformDictionaryWord;
 use brotli::interface::Nop;
 use ::priors::PriorCollection;
-use brotli::dictionary::{kBrotliMaxDictionaryWordLength, kBrotliDictionary};
+use brotli::dictionary::{kBrotliMaxDictionaryWordLength, kBrotliDictionary, kBrotliDictionaryOffsetsByLength};
 use ::probability::{CDF16, Speed};
 use super::interface::{
     EncoderOrDecoderSpecialization,
@@ -15,8 +15,10 @@ use ::interface::{
     ArithmeticEncoderOrDecoder,
     BillingDesignation,
     DictCommand,
+    FeatureFlagSliceType,
+    LiteralCommand,
 };
-use super::priors::{DictCommandNibblePriorType};
+use super::priors::{DictCommandNibblePriorType, NUM_DICT_WORD_SIZE_BUCKETS};
 
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
@@ -29,6 +31,7 @@ pub enum DictSubstate {
     TransformLow,
     FullyDecoded,
 }
+#[derive(Clone, Copy)]
 pub struct DictState {
    pub dc:DictCommand,
    pub state: DictSubstate,
@@ -38,6 +41,62 @@ const DICT_BITS:[u8;25] = [
     10, 10, 10,  9,  9,  8,  7,  7,  8,  7,
     7,  6,  6,  5,  5];
 
+// buckets of word sizes, finer among the short words most dictionary commands take
+fn quantized_word_size(word_size: u8) -> usize {
+    let bucket = match word_size {
+        0..=4 => 0,
+        5 => 1,
+        6 => 2,
+        7 => 3,
+        8 | 9 => 4,
+        10..=12 => 5,
+        13..=16 => 6,
+        _ => 7,
+    };
+    debug_assert!(bucket < NUM_DICT_WORD_SIZE_BUCKETS);
+    bucket
+}
+
+// the bytes of a dictionary word after its transform, for an encoder to code the word as a literal
+// once the stream has turned dictionary words off (see EXTENDED_FEATURE2_AUTO_NO_DICT)
+#[derive(Clone, Copy)]
+pub struct DictWordBytes {
+    data: [u8; kBrotliMaxDictionaryWordLength as usize + 13],
+    len: u8,
+}
+
+impl Default for DictWordBytes {
+    fn default() -> Self {
+        DictWordBytes {
+            data: [0u8; kBrotliMaxDictionaryWordLength as usize + 13],
+            len: 0,
+        }
+    }
+}
+
+impl SliceWrapper<u8> for DictWordBytes {
+    fn slice(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+}
+
+impl DictWordBytes {
+    pub fn literal(dc: &DictCommand) -> LiteralCommand<DictWordBytes> {
+        let mut ret = DictWordBytes::default();
+        let offset = kBrotliDictionaryOffsetsByLength[dc.word_size as usize] as usize
+            + dc.word_id as usize * dc.word_size as usize;
+        let final_len = TransformDictionaryWord(&mut ret.data[..],
+                                                &kBrotliDictionary[offset..offset + dc.word_size as usize],
+                                                i32::from(dc.word_size),
+                                                i32::from(dc.transform));
+        ret.len = final_len as u8;
+        LiteralCommand {
+            data: ret,
+            prob: FeatureFlagSliceType::<DictWordBytes>::default(),
+            high_entropy: false,
+        }
+    }
+}
 
 impl DictState {
     pub fn begin() -> Self {
@@ -63,6 +122,7 @@ impl DictState {
                                                in_cmd: &DictCommand,
                                                output_bytes:&mut [u8],
                                                output_offset: &mut usize) -> DivansResult {
+let adapt_cdf = superstate.adapt_cdf();
 
         loop {
             match superstate.drain_or_fill_internal_buffer_cmd(output_bytes, output_offset) {
@@ -83,7 +143,7 @@ impl DictState {
                     let ctype = superstate.bk.get_command_block_type();
                     let mut nibble_prob = superstate.bk.dict_priors.get(DictCommandNibblePriorType::SizeBegNib, (ctype,));
                     superstate.coder.get_or_put_nibble(&mut beg_nib, nibble_prob, billing);
-                    if superstate.specialization.adapt_cdf() {
+                    if adapt_cdf {
                         nibble_prob.blend(beg_nib, Speed::MUD);
                     }
 
@@ -99,7 +159,7 @@ impl DictState {
                     let ctype = superstate.bk.get_command_block_type();
                     let mut nibble_prob = superstate.bk.dict_priors.get(DictCommandNibblePriorType::SizeLastNib, (ctype,));
                     superstate.coder.get_or_put_nibble(&mut beg_nib, nibble_prob, billing);
-                    if superstate.specialization.adapt_cdf() {
+                    if adapt_cdf {
                         nibble_prob.blend(beg_nib, Speed::MUD);
                     }
 
@@ -119,7 +179,7 @@ impl DictState {
                     let mut nibble_prob = superstate.bk.dict_priors.get(
                         DictCommandNibblePriorType::Index, (actual_prior, index));
                     superstate.coder.get_or_put_nibble(&mut last_nib, nibble_prob, billing);
-                    if superstate.specialization.adapt_cdf() {
+                    if adapt_cdf {
                         nibble_prob.blend(last_nib, Speed::MUD);
                     }
 
@@ -136,10 +196,17 @@ impl DictState {
                 },
                 DictSubstate::TransformHigh => {
                     let mut high_nib = in_cmd.transform >> 4;
-                    let mut nibble_prob = superstate.bk.dict_priors.get(DictCommandNibblePriorType::Transform,
-                                                                        (0, self.dc.word_size as usize >> 1));
+                    let mut nibble_prob = if superstate.bk.extended_stream_features & EXTENDED_FEATURE_DICT_TRANSFORM_MODEL != 0 {
+                        // the command before this one is in the second highest pair of bits
+                        let last_command_type = ((superstate.bk.last_4_states >> 4) & 3) as usize;
+                        superstate.bk.dict_priors.get(DictCommandNibblePriorType::TransformModelHigh,
+                                                      (last_command_type, quantized_word_size(self.dc.word_size)))
+                    } else {
+                        superstate.bk.dict_priors.get(DictCommandNibblePriorType::Transform,
+                                                      (0, self.dc.word_size as usize >> 1))
+                    };
                     superstate.coder.get_or_put_nibble(&mut high_nib, nibble_prob, billing);
-                    if superstate.specialization.adapt_cdf() {
+                    if adapt_cdf {
                         nibble_prob.blend(high_nib, Speed::FAST);
                     }
                     self.dc.transform = high_nib << 4;
@@ -147,10 +214,15 @@ impl DictState {
                 }
                 DictSubstate::TransformLow => {
                     let mut low_nib = in_cmd.transform & 0xf;
-                    let mut nibble_prob = superstate.bk.dict_priors.get(DictCommandNibblePriorType::Transform,
-                                                                        (1, self.dc.transform as usize >> 4));
+                    let mut nibble_prob = if superstate.bk.extended_stream_features & EXTENDED_FEATURE_DICT_TRANSFORM_MODEL != 0 {
+                        superstate.bk.dict_priors.get(DictCommandNibblePriorType::TransformModelLow,
+                                                      (self.dc.transform as usize >> 4, quantized_word_size(self.dc.word_size)))
+                    } else {
+                        superstate.bk.dict_priors.get(DictCommandNibblePriorType::Transform,
+                                                      (1, self.dc.transform as usize >> 4))
+                    };
                     superstate.coder.get_or_put_nibble(&mut low_nib, nibble_prob, billing);
-                    if superstate.specialization.adapt_cdf() {
+                    if adapt_cdf {
                         nibble_prob.blend(low_nib, Speed::FAST);
                     }
                     self.dc.transform |= low_nib;
@@ -165,6 +237,7 @@ impl DictState {
                                                             i32::from(self.dc.word_size),
                                                             i32::from(self.dc.transform));
                     self.dc.final_size = final_len as u8;// WHA
+                    self.dc.empty = 0;
                     self.state = DictSubstate::FullyDecoded;
                     return DivansResult::Success;
                 }
@@ -175,3 +248,120 @@ impl DictState {
         }
     }
 }
+
+#[cfg(all(test, feature="std", feature="encoder", feature="decoder"))]
+mod test {
+    use conformance::{conformance_commands, conformance_expected_output, ConformanceSlice};
+    use interface::{
+        Command,
+        FeatureFlagSliceType,
+        LiteralCommand,
+        DictCommand,
+        CopyCommand,
+        DivansCompressorOptions,
+        HEADER_EXTENDED_FEATURE_OFFSET,
+        HEADER_EXTENDED_FEATURE2_OFFSET,
+        MAGIC_NUMBER_EXTENDED,
+        EXTENDED_FEATURE_DICT_TRANSFORM_MODEL,
+        EXTENDED_FEATURE2_AUTO_NO_DICT,
+        AUTO_NO_DICT_COMMANDS,
+    };
+    use test_support::{encode_with_options, decode, decode_with, DecodeConfig};
+
+    #[test]
+    fn test_dict_transform_model_roundtrip() {
+        use brotli::dictionary::{kBrotliDictionary, kBrotliDictionaryOffsetsByLength};
+        use brotli::transform::TransformDictionaryWord;
+        // words after a literal take a transform picked by their size, those after a word one above it
+        let mut commands = conformance_commands("dict");
+        for i in 0..1200u32 {
+            let after_literal = i % 3 == 0;
+            if after_literal {
+                commands.push(Command::Literal(LiteralCommand {
+                    data: ConformanceSlice(b" ".to_vec()),
+                    prob: FeatureFlagSliceType::<ConformanceSlice>::default(),
+                    high_entropy: false,
+                }));
+            }
+            let word_size = 4 + (i * 5 % 9) as u8;
+            let word_id = i * 37 % 512;
+            let transform = [0u8, 1, 6, 9, 10, 2, 4, 3, 5][word_size as usize - 4] + if after_literal { 0 } else { 32 };
+            let offset = kBrotliDictionaryOffsetsByLength[word_size as usize] as usize + word_id as usize * word_size as usize;
+            let mut transformed = [0u8; 40];
+            let final_size = TransformDictionaryWord(&mut transformed[..],
+                                                     &kBrotliDictionary[offset..offset + word_size as usize],
+                                                     i32::from(word_size),
+                                                     i32::from(transform));
+            commands.push(Command::Dict(DictCommand {
+                word_size,
+                transform,
+                final_size: final_size as u8,
+                empty: 0,
+                word_id,
+            }));
+        }
+        let expected = conformance_expected_output(&commands[..]).unwrap();
+        let mut opts = DivansCompressorOptions::default();
+        let plain = encode_with_options(&commands[..], opts).unwrap();
+        opts.extended_stream_features = EXTENDED_FEATURE_DICT_TRANSFORM_MODEL;
+        let model = encode_with_options(&commands[..], opts).unwrap();
+        assert_eq!(model[..4], MAGIC_NUMBER_EXTENDED);
+        assert_eq!(model[HEADER_EXTENDED_FEATURE_OFFSET], EXTENDED_FEATURE_DICT_TRANSFORM_MODEL);
+        assert_eq!(decode(&plain[..]).unwrap(), expected);
+        for multithread in [false, true].iter() {
+            assert_eq!(decode_with(&model[..], DecodeConfig::new(*multithread), |_| {}).into_output().unwrap(), expected);
+        }
+        assert!(model.len() < plain.len(), "{} >= {}", model.len(), plain.len());
+    }
+
+    #[test]
+    fn test_auto_no_dict_roundtrip() {
+        let word = DictCommand {
+            word_size: 6,
+            transform: 0,
+            final_size: 6,
+            empty: 0,
+            word_id: 12,
+        };
+        // literals and copies well past AUTO_NO_DICT_COMMANDS
+        let mut binary = conformance_commands("copy");
+        for i in 0..(AUTO_NO_DICT_COMMANDS + 400) {
+            binary.push(Command::Literal(LiteralCommand {
+                data: ConformanceSlice(vec![(i * 7) as u8, (i >> 3) as u8]),
+                prob: FeatureFlagSliceType::<ConformanceSlice>::default(),
+                high_entropy: false,
+            }));
+            binary.push(Command::Copy(CopyCommand {
+                distance: 2 + i % 13,
+                num_bytes: 4,
+            }));
+        }
+        // then words the encoder has to spell out
+        let mut late_words = binary.clone();
+        late_words.push(Command::Dict(word));
+        late_words.push(Command::Dict(word));
+        // words from the start keep them on
+        let mut text = conformance_commands("dict");
+        for _ in 0..AUTO_NO_DICT_COMMANDS {
+            text.push(Command::Dict(word));
+            text.push(Command::Copy(CopyCommand {
+                distance: 7,
+                num_bytes: 5,
+            }));
+        }
+        for (index, commands) in [binary, late_words, text].iter().enumerate() {
+            let expected = conformance_expected_output(&commands[..]).unwrap();
+            let mut opts = DivansCompressorOptions::default();
+            let plain = encode_with_options(&commands[..], opts).unwrap();
+            opts.extended_stream_features2 = EXTENDED_FEATURE2_AUTO_NO_DICT;
+            let auto = encode_with_options(&commands[..], opts).unwrap();
+            assert_eq!(auto[HEADER_EXTENDED_FEATURE2_OFFSET], EXTENDED_FEATURE2_AUTO_NO_DICT);
+            for multithread in [false, true].iter() {
+                assert_eq!(decode_with(&auto[..], DecodeConfig::new(*multithread), |_| {}).into_output().unwrap(), expected);
+            }
+            if index == 0 {
+                assert!(auto.len() <= plain.len(), "{} > {}", auto.len(), plain.len());
+            }
+        }
+    }
+}
@@ -1,12 +1,12 @@
 use core;
 use brotli;
 use brotli::interface::Nop;
-use interface::{DivansOpResult, ErrMsg, StreamMuxer, StreamDemuxer, DivansResult, WritableBytes};
+use interface::{DefaultBitModel, DivansOpResult, ErrMsg, StreamMuxer, StreamDemuxer, DivansResult, WritableBytes};
 use ::cmd_to_raw::{DivansRecodeState, RingBufferSnapshot};
-use ::probability::{CDF16, Speed};
+use ::probability::{CDF16, Speed, ExternalProb};
 use alloc::{SliceWrapper, Allocator, SliceWrapperMut};
 use ::slice_util::AllocatedMemoryPrefix;
-use ::alloc_util::RepurposingAlloc;
+use ::alloc_util::{RepurposingAlloc, duplicate_cell};
 use ::constants;
 use ::interface::{
     ArithmeticEncoderOrDecoder,
@@ -24,22 +24,42 @@ use ::interface::{
     LITERAL_PREDICTION_MODE_MSB6,
     LITERAL_PREDICTION_MODE_LSB6,
     NewWithAllocator,
+    DuplicateWithAllocator,
     MAX_LITERAL_CONTEXT_MAP_SIZE,
     EncoderOrDecoderRecoderSpecialization,
+    STREAM_FEATURE_NO_DICT,
+    EXTENDED_FEATURE2_AUTO_NO_DICT,
+    AUTO_NO_DICT_COMMANDS,
+    STREAM_FEATURE_NO_BLOCK_SWITCH,
+    DistanceBucketing,
+    DISTANCE_POSTFIX_DIRECT,
+    STREAM_FEATURE_COMMAND_TYPE_TREE,
+    LITERAL_BLOCK_SWITCH_LSB_FIRST,
+    MixerWarmStart,
 };
 use super::priors::{
     LiteralNibblePriors,
     LiteralCommandPriors,
     LiteralCommandPriorsCM,
+    LiteralCMPriorType,
+    LiteralMatchPriors,
+    LiteralByteClassPriors,
+    NUM_BYTE_CLASS_CONFIDENCES,
+    BYTE_CLASS,
     CopyCommandPriors,
     DictCommandPriors,
     CrossCommandPriors,
     PredictionModePriors,
     BlockTypePriors,
     NUM_BLOCK_TYPES,
+    block_type_slot,
 };
+use super::static_literal::StaticLiteralModel;
 use ::priors::PriorCollection;
 const LOG_NUM_COPY_TYPE_PRIORS: usize = 4;
+// command type codes decided by a single bit each, in order, when STREAM_FEATURE_COMMAND_TYPE_TREE is set
+pub const COMMAND_TYPE_TREE_CODES: [u8; 2] = [3, 1];
+pub const COMMAND_TYPE_TREE_DEPTH: usize = 2;
 
 
 pub const BLOCK_TYPE_LITERAL_SWITCH:usize=0;
@@ -48,7 +68,8 @@ pub const BLOCK_TYPE_DISTANCE_SWITCH:usize=2;
 pub const NUM_ARITHMETIC_CODERS:usize = 2;
 pub const CMD_CODER: usize = 0;
 pub const LIT_CODER: usize = 1;
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
 #[repr(u8)]
 pub enum StrideSelection {
     PriorDisabled = 0u8,
@@ -91,6 +112,21 @@ pub trait EncoderOrDecoderSpecialization {
     fn get_literal_byte<ISlice:SliceWrapper<u8>>(&self,
                                                    in_cmd: &LiteralCommand<ISlice>,
                                                    index: usize) -> u8;
+    // The distribution to code the given nibble of in_cmd with, if the caller supplied one.
+    // By default it is built from the 4 bit probabilities per nibble in the command's prob slice.
+    #[inline(always)]
+    fn get_external_prob<ISlice:SliceWrapper<u8>>(&self,
+                                                  in_cmd: &LiteralCommand<ISlice>,
+                                                  nibble_index: u32) -> Option<ExternalProb> {
+        let probs = in_cmd.prob.slice();
+        let shift_offset = if (nibble_index & 1) == 0 { 4usize } else { 0usize };
+        let st = ((nibble_index as usize) >> 1) * 8 + shift_offset;
+        if st + 4 <= probs.len() {
+            Some(ExternalProb::from_bit_probabilities(&[probs[st], probs[st + 1], probs[st + 2], probs[st + 3]]))
+        } else {
+            None
+        }
+    }
     fn get_recoder_output<'a>(&'a mut self, passed_in_output_bytes: &'a mut [u8]) -> &'a mut[u8];
     fn get_recoder_output_offset<'a>(&self,
                                      passed_in_output_bytes: &'a mut usize,
@@ -121,14 +157,102 @@ pub enum ContextMapType {
 
 
 const CONTEXT_MAP_CACHE_SIZE: usize = 13;
+// how many times a warm start's seed nibble is observed by each high nibble context mixing prior
+const MIXER_WARM_START_SEED_OBSERVATIONS: usize = 4;
+// the CombinedNibble priors of one actual context, the outermost dimension of the high nibble's
+const NIBBLE_PRIORS_PER_CONTEXT: usize = 3 * 256;
+
+// Counts the output towards the byte count of EXTENDED_FEATURE_FREEZE_ADAPTATION.  Commands count
+// whole as they finish, so the command codec and the literal decoder of a threaded decoder freeze
+// after the same command the encoder did.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AdaptationFreeze {
+    after: Option<u32>,
+    bytes_coded: u64,
+    frozen: bool,
+}
+
+impl AdaptationFreeze {
+    pub fn new(after: Option<u32>) -> Self {
+        AdaptationFreeze {
+            after,
+            bytes_coded: 0,
+            frozen: after == Some(0),
+        }
+    }
+    pub fn after(&self) -> Option<u32> {
+        self.after
+    }
+    #[inline(always)]
+    pub fn frozen(&self) -> bool {
+        self.frozen
+    }
+    #[inline(always)]
+    pub fn obs_command_bytes(&mut self, len: u64) {
+        self.bytes_coded += len;
+        if let Some(after) = self.after {
+            self.frozen = self.bytes_coded >= u64::from(after);
+        }
+    }
+}
+
+// Counts the output towards the resets of DivansCompressorOptions::priors_reset_interval, which
+// follow the command that takes the output past each multiple of the interval.  Like AdaptationFreeze
+// it is kept on both sides of a threaded decoder so that both reset after the same command.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PriorsReset {
+    interval: Option<u32>,
+    bytes_coded: u64,
+}
+
+impl PriorsReset {
+    pub fn new(interval: Option<u32>) -> Self {
+        PriorsReset {
+            interval,
+            bytes_coded: 0,
+        }
+    }
+    pub fn interval(&self) -> Option<u32> {
+        self.interval
+    }
+    // whether the models are due to reset now that a command of len bytes is done
+    #[inline(always)]
+    pub fn obs_command_bytes(&mut self, len: u64) -> bool {
+        let before = self.bytes_coded;
+        self.bytes_coded += len;
+        match self.interval {
+            Some(interval) => before / u64::from(interval) != self.bytes_coded / u64::from(interval),
+            None => false,
+        }
+    }
+}
+
+fn reset_cells<T:Default, Cells:SliceWrapperMut<T>>(cells: &mut Cells) {
+    for cell in cells.slice_mut().iter_mut() {
+        *cell = T::default();
+    }
+}
+
+fn cells_valid<Cdf16:CDF16, Cells:SliceWrapper<Cdf16>>(cells: &Cells) -> bool {
+    cells.slice().iter().all(|cell| cell.valid())
+}
+
+// the speeds the literal priors adapt at under pm: stride, then context map
+pub fn literal_adaptation_of<ISlice:SliceWrapper<u8>>(pm: &PredictionModeContextMap<ISlice>) -> [Speed; 4] {
+    let stride = pm.stride_context_speed_f8();
+    let context_map = pm.context_map_speed_f8();
+    [Speed::from_f8_tuple(stride[0]), Speed::from_f8_tuple(stride[1]),
+     Speed::from_f8_tuple(context_map[0]), Speed::from_f8_tuple(context_map[1])]
+}
 
 pub struct LiteralBookKeeping<Cdf16:CDF16,
                                    AllocU8:Allocator<u8>,
                                    AllocCDF16:Allocator<Cdf16>> {
     pub last_8_literals: u64,
     pub literal_context_map: AllocU8::AllocatedMemory,
-    pub btype_last: u8,
+    pub btype_last: u32,
     pub stride: u8,
+    pub lsb_first: bool, // the literals of the current block type are coded low nibble first
     pub combine_literal_predictions: bool,
     pub literal_prediction_mode: LiteralPredictionModeNibble,
     pub literal_adaptation: [Speed; 4],
@@ -136,7 +260,30 @@ pub struct LiteralBookKeeping<Cdf16:CDF16,
     pub literal_lut1:[u8;256],
     pub mixing_mask:[u8; 8192],
     pub model_weights: [super::weights::Weights;2],
+    // the model_weights of the other literal block types, kept only for EXTENDED_FEATURE_BLOCK_TYPE_MIXER streams
+    pub block_type_weights: super::weights::BlockTypeWeights,
+    pub mixer_snapshots: super::weights::MixerSnapshotQueue,
     pub lit_cm_priors: LiteralCommandPriorsCM<Cdf16, AllocCDF16>,
+    pub static_model: StaticLiteralModel<Cdf16, AllocU8, AllocCDF16>,
+    // actual contexts below the highest any literal context map so far has used; the high nibble
+    // priors of the rest have never been touched
+    pub literal_contexts_seen: usize,
+    // keep only the high nibble priors of literal_contexts_seen, reallocating as it grows
+    pub compact_priors: bool,
+    pub adaptation: AdaptationFreeze,
+    pub priors_reset: PriorsReset,
+    // allocated only for EXTENDED_FEATURE_MATCH_BYTE_CONTEXT streams
+    pub match_priors: LiteralMatchPriors<Cdf16, AllocCDF16>,
+    // mix match_priors with the nibble priors, per nibble as model_weights
+    pub match_weights: [super::weights::Weights; 2],
+    // the byte the last copy would have produced next, until a literal byte follows it
+    pub match_byte: Option<u8>,
+    // allocated only for EXTENDED_FEATURE2_BYTE_CLASS_CONTEXT streams
+    pub class_priors: LiteralByteClassPriors<Cdf16, AllocCDF16>,
+    // mix class_priors with the first nibble priors
+    pub class_weights: super::weights::Weights,
+    // by the two bytes before, the byte that followed them last and its confidence, interleaved
+    pub class_predictions: AllocU8::AllocatedMemory,
 }
 
 pub struct CrossCommandBookKeeping<Cdf16:CDF16,
@@ -152,8 +299,8 @@ pub struct CrossCommandBookKeeping<Cdf16:CDF16,
     pub cmap_lru: [u8; CONTEXT_MAP_CACHE_SIZE],
     pub distance_lru: [u32;4],
     pub btype_priors: BlockTypePriors<Cdf16, AllocCDF16>,
-    pub btype_lru: [[u8;2];3],
-    pub btype_max_seen: [u8;3],
+    pub btype_lru: [[u32;2];3],
+    pub btype_max_seen: [u32;3],
     //pub cm_prior_depth_mask: u8,
     //pub prior_bytes_depth_mask: u8,
     pub last_dlen: u8,
@@ -165,6 +312,20 @@ pub struct CrossCommandBookKeeping<Cdf16:CDF16,
     pub desired_do_context_map: bool,
     pub desired_force_stride: StrideSelection,
     pub desired_context_mixing: u8,
+    pub stream_features: u8,
+    pub extended_stream_features: u8,
+    pub extended_stream_features2: u8,
+    pub auto_no_dict: Option<bool>, // whether EXTENDED_FEATURE2_AUTO_NO_DICT turned dictionary words off, once coded
+    pub command_types_coded: u32,
+    pub dict_commands_coded: u32,
+    pub desired_distance_bucketing: DistanceBucketing,
+    pub distance_bucketing: DistanceBucketing, // from the last prediction mode command
+    pub desired_mixer_warm_start: Option<MixerWarmStart>,
+    pub last_distance_postfix: u8,
+    pub command_type_tree: [[DefaultBitModel; COMMAND_TYPE_TREE_DEPTH]; 1 << LOG_NUM_COPY_TYPE_PRIORS],
+    pub dict_copy_fusion: DefaultBitModel, // whether a copy was fused onto the dictionary word before it
+    pub adaptation: AdaptationFreeze,
+    pub priors_reset: PriorsReset,
 }
 
 #[inline(always)]
@@ -196,7 +357,7 @@ pub struct ByteContext {
   pub prev_byte: u8,
 }
 
-fn get_lut0(lpn: LiteralPredictionModeNibble) -> [u8; 256] {
+pub fn get_lut0(lpn: LiteralPredictionModeNibble) -> [u8; 256] {
     let mut ret = [0u8; 256];
     match lpn.0 {
         LITERAL_PREDICTION_MODE_SIGN =>
@@ -219,7 +380,7 @@ fn get_lut0(lpn: LiteralPredictionModeNibble) -> [u8; 256] {
     }
     ret
 }
-fn get_lut1(lpn: LiteralPredictionModeNibble) -> [u8; 256] {
+pub fn get_lut1(lpn: LiteralPredictionModeNibble) -> [u8; 256] {
     let mut ret = [0u8; 256];
     match lpn.0 {
         LITERAL_PREDICTION_MODE_SIGN =>
@@ -244,10 +405,12 @@ impl<
                                                AllocU8,
                                                AllocCDF16> {
     fn new(literal_context_map:AllocU8::AllocatedMemory) -> Self {
+        let literal_contexts_seen = literal_context_map.slice().iter().max().map_or(0, |c| usize::from(*c) + 1);
         LiteralBookKeeping::<Cdf16, AllocU8, AllocCDF16> {
             combine_literal_predictions: false,
             last_8_literals: 0,
             stride: 0,
+            lsb_first: false,
             literal_adaptation: [default_literal_speed(); 4],
             literal_prediction_mode: LiteralPredictionModeNibble::default(),
             literal_lut0: get_lut0(LiteralPredictionModeNibble::default()),
@@ -257,13 +420,72 @@ impl<
             btype_last:0,
             model_weights:[super::weights::Weights::default(),
                            super::weights::Weights::default()],
+            block_type_weights: super::weights::BlockTypeWeights::default(),
+            mixer_snapshots: super::weights::MixerSnapshotQueue::default(),
             lit_cm_priors: LiteralCommandPriorsCM {
                 priors: AllocCDF16::AllocatedMemory::default()
             },
+            static_model: StaticLiteralModel::default(),
+            literal_contexts_seen,
+            compact_priors: true,
+            adaptation: AdaptationFreeze::default(),
+            priors_reset: PriorsReset::default(),
+            match_priors: LiteralMatchPriors {
+                priors: AllocCDF16::AllocatedMemory::default()
+            },
+            match_weights: [super::weights::Weights::default(),
+                            super::weights::Weights::default()],
+            match_byte: None,
+            class_priors: LiteralByteClassPriors {
+                priors: AllocCDF16::AllocatedMemory::default()
+            },
+            class_weights: super::weights::Weights::default(),
+            class_predictions: AllocU8::AllocatedMemory::default(),
+        }
+    }
+    pub fn duplicate(&self, m8: &mut AllocU8, mcdf16: &mut AllocCDF16) -> Self {
+        LiteralBookKeeping::<Cdf16, AllocU8, AllocCDF16> {
+            combine_literal_predictions: self.combine_literal_predictions,
+            last_8_literals: self.last_8_literals,
+            stride: self.stride,
+            lsb_first: self.lsb_first,
+            literal_adaptation: self.literal_adaptation,
+            literal_prediction_mode: self.literal_prediction_mode,
+            literal_lut0: self.literal_lut0,
+            literal_lut1: self.literal_lut1,
+            mixing_mask: self.mixing_mask,
+            literal_context_map: duplicate_cell(m8, &self.literal_context_map),
+            btype_last: self.btype_last,
+            model_weights: self.model_weights,
+            block_type_weights: self.block_type_weights,
+            mixer_snapshots: self.mixer_snapshots,
+            lit_cm_priors: LiteralCommandPriorsCM {
+                priors: duplicate_cell(mcdf16, &self.lit_cm_priors.priors)
+            },
+            static_model: self.static_model.duplicate(m8, mcdf16),
+            literal_contexts_seen: self.literal_contexts_seen,
+            compact_priors: self.compact_priors,
+            adaptation: self.adaptation,
+            priors_reset: self.priors_reset,
+            match_priors: LiteralMatchPriors {
+                priors: duplicate_cell(mcdf16, &self.match_priors.priors)
+            },
+            match_weights: self.match_weights,
+            match_byte: self.match_byte,
+            class_priors: LiteralByteClassPriors {
+                priors: duplicate_cell(mcdf16, &self.class_priors.priors)
+            },
+            class_weights: self.class_weights,
+            class_predictions: duplicate_cell(m8, &self.class_predictions),
         }
     }
-    pub fn get_literal_block_type(&self) -> u8 {
-        self.btype_last
+    pub fn get_literal_block_type(&self) -> usize {
+        block_type_slot(self.btype_last)
+    }
+    // whether the literal priors learn from the nibble just coded
+    #[inline(always)]
+    pub fn adapt_cdf<Specialization:EncoderOrDecoderSpecialization>(&self, specialization: &Specialization) -> bool {
+        specialization.adapt_cdf() && !self.adaptation.frozen()
     }
     pub fn obs_pred_mode(&mut self, new_mode: LiteralPredictionModeNibble) -> DivansOpResult {
        // self.next_state(); // FIXME removing: but it seems wrong
@@ -279,34 +501,112 @@ impl<
     }
     pub fn push_literal_byte(&mut self, b: u8) {
         //self.num_literals_coded += 1;
+        if !self.class_predictions.slice().is_empty() && !self.adaptation.frozen() {
+            let last_2 = (self.last_8_literals >> 0x30) as usize;
+            self.observe_class_prediction(last_2, b);
+        }
         self.last_8_literals >>= 0x8;
         self.last_8_literals |= u64::from(b) << 0x38;
+        self.match_byte = None;
+    }
+    pub fn enable_match_byte_context(&mut self, mcdf16: &mut AllocCDF16) {
+        if self.match_priors.priors.slice().is_empty() {
+            self.match_priors.priors = mcdf16.alloc_cell(LiteralMatchPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
+        }
+        for weights in self.match_weights.iter_mut() {
+            weights.set_mixing_param(2);
+        }
+    }
+    pub fn enable_byte_class_context(&mut self, m8: &mut AllocU8, mcdf16: &mut AllocCDF16) {
+        if self.class_priors.priors.slice().is_empty() {
+            self.class_priors.priors = mcdf16.alloc_cell(LiteralByteClassPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
+            self.class_predictions = m8.alloc_cell(2 << 16);
+        }
+        self.class_weights.set_mixing_param(2);
+    }
+    // the row of class_priors for the byte after the last two: the class of the byte that
+    // followed them last time and how many times in a row it did
+    #[inline(always)]
+    pub fn predicted_byte_class(&self) -> usize {
+        let slot = (self.last_8_literals >> 0x30) as usize * 2;
+        let prediction = &self.class_predictions.slice()[slot..slot + 2];
+        usize::from(BYTE_CLASS[usize::from(prediction[0])]) * NUM_BYTE_CLASS_CONFIDENCES + usize::from(prediction[1])
+    }
+    // Called once the recoder has the output of cmd: a copy leaves the byte at its distance from the
+    // end of the output for the literal after it, a dictionary word or literal leaves none and the
+    // rest leave the match byte be.
+    pub fn obs_populated_command<SliceType:SliceWrapper<u8>>(&mut self,
+                                                             cmd: &Command<SliceType>,
+                                                             recoder: &DivansRecodeState<AllocU8::AllocatedMemory>) {
+        if !self.class_predictions.slice().is_empty() && !self.adaptation.frozen() {
+            let produced = match *cmd {
+                Command::Copy(ref copy) => copy.num_bytes,
+                Command::Dict(ref dict) => u32::from(dict.final_size),
+                _ => 0,
+            };
+            self.learn_class_predictions(produced, recoder);
+        }
+        if self.match_priors.priors.slice().is_empty() {
+            return;
+        }
+        match *cmd {
+            Command::Copy(ref copy) => self.match_byte = recoder.continuation_byte(copy.distance),
+            Command::Dict(_) | Command::Literal(_) => self.match_byte = None,
+            _ => {},
+        }
+    }
+    // the byte after the two bytes in last_2, the later in the high bits, either confirms the
+    // prediction for them or replaces it
+    #[inline(always)]
+    fn observe_class_prediction(&mut self, last_2: usize, b: u8) {
+        let prediction = &mut self.class_predictions.slice_mut()[last_2 * 2..last_2 * 2 + 2];
+        if prediction[0] == b {
+            prediction[1] = core::cmp::min(prediction[1] + 1, NUM_BYTE_CLASS_CONFIDENCES as u8 - 1);
+        } else {
+            prediction[0] = b;
+            prediction[1] = 0;
+        }
+    }
+    // the bytes a copy or dictionary word produced teach class_predictions as literals would
+    fn learn_class_predictions(&mut self,
+                               produced: u32,
+                               recoder: &DivansRecodeState<AllocU8::AllocatedMemory>) {
+        let mut last_2 = match (recoder.continuation_byte(produced + 2), recoder.continuation_byte(produced + 1)) {
+            (Some(older), Some(old)) => (usize::from(old) << 8) | usize::from(older),
+            _ => return, // the ring buffer does not reach back that far
+        };
+        for distance in (1..=produced).rev() {
+            let b = recoder.continuation_byte(distance).unwrap_or(0);
+            self.observe_class_prediction(last_2, b);
+            last_2 = (usize::from(b) << 8) | (last_2 >> 8);
+        }
     }
     pub fn push_literal_nibble(&mut self, nibble: u8) {
         self.last_8_literals >>= 0x4;
         self.last_8_literals |= u64::from(nibble) << 0x3c;
     }
     pub fn obs_literal_block_switch(&mut self, btype:LiteralBlockSwitch) {
+        self.mixer_snapshots.observe_block_end(self.btype_last, &self.model_weights);
+        self.block_type_weights.switch(self.btype_last, btype.block_type(), &mut self.model_weights);
         self.btype_last = btype.block_type();
-        self.stride = btype.stride();
+        self.stride = btype.stride() & 0xf;
+        self.lsb_first = btype.stride() & LITERAL_BLOCK_SWITCH_LSB_FIRST != 0;
     }
     pub fn obs_prediction_mode_context_map<ISlice:SliceWrapper<u8>>(&mut self,
                                                                     pm: &PredictionModeContextMap<ISlice>,
-                                                                    mcdf16: &mut AllocCDF16) -> DivansOpResult {
+                                                                    mut mcdf16: Option<&mut AllocCDF16>) -> DivansOpResult {
         self.reset_literal_context_map();
         let combined_prediction_mode = pm.literal_prediction_mode();
-        self.obs_dynamic_context_mixing(pm.get_mixing_math(), mcdf16);
+        self.obs_dynamic_context_mixing(pm.get_mixing_math(), mcdf16.as_deref_mut());
         match self.obs_pred_mode(combined_prediction_mode) {
             DivansOpResult::Success => {},
             fail => return fail,
         }
-        for (out_item, in_item) in self.literal_adaptation[..2].iter_mut().zip(pm.stride_context_speed_f8().iter()) {
-            *out_item = Speed::from_f8_tuple(*in_item);
-        }
-        for (out_item, in_item) in self.literal_adaptation[2..].iter_mut().zip(pm.context_map_speed_f8().iter()) {
-            *out_item = Speed::from_f8_tuple(*in_item);
-        }
+        self.literal_adaptation = literal_adaptation_of(pm);
         self.literal_context_map.slice_mut().clone_from_slice(pm.literal_context_map.slice());
+        if let Some(max_context) = self.literal_context_map.slice().iter().max() {
+            self.literal_contexts_seen = core::cmp::max(self.literal_contexts_seen, usize::from(*max_context) + 1);
+        }
         // self.distance_context_map.slice_mut().clone_from_slice(pm.distance_context_map()); // FIXME: this was done during parsing of the pm
         for item in self.literal_context_map.slice().iter() {
             if *item != 0 {
@@ -317,15 +617,58 @@ impl<
             self.clear_mixing_values();
         }
         self.mixing_mask.clone_from_slice(pm.get_mixing_values());
+        if let Some(warm_start) = MixerWarmStart::of(pm) {
+            self.obs_mixer_warm_start(warm_start);
+        }
+        self.static_model.obs_prediction_mode(mcdf16);
         DivansOpResult::Success
     }
-    pub fn obs_dynamic_context_mixing(&mut self, context_mixing: u8, mcdf16: &mut AllocCDF16) {
+    // restarts the mixers and biases the high nibble context mixing priors, which exist only with mixing
+    pub fn obs_mixer_warm_start(&mut self, warm_start: MixerWarmStart) {
+        if warm_start.cm_share != 0 {
+            for weights in self.model_weights.iter_mut() {
+                weights.warm_start(warm_start.cm_share);
+            }
+            self.block_type_weights.forget();
+        }
+        if warm_start.seed_high_nibble == 0 || self.lit_cm_priors.priors.slice().is_empty() {
+            return;
+        }
+        let speed = self.literal_adaptation[3];
+        for actual_context in 0..NUM_BLOCK_TYPES {
+            let prior = self.lit_cm_priors.get(LiteralCMPriorType::FirstNibble, (0, actual_context));
+            for _ in 0..MIXER_WARM_START_SEED_OBSERVATIONS {
+                prior.blend(warm_start.seed_high_nibble, speed);
+            }
+        }
+    }
+    // without mcdf16 the context mixing priors have to be in place already (see LiteralModels)
+    pub fn obs_dynamic_context_mixing(&mut self, context_mixing: u8, mcdf16: Option<&mut AllocCDF16>) {
         self.combine_literal_predictions = (context_mixing != 0) as bool;
-        if context_mixing >= 2 && self.lit_cm_priors.priors.slice().len() == 0 {
+        if let (true, Some(mcdf16)) = (context_mixing >= 2 && self.lit_cm_priors.priors.slice().len() == 0, mcdf16) {
             self.lit_cm_priors.priors = mcdf16.alloc_cell(LiteralCommandPriorsCM::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
         }
         self.model_weights[0].set_mixing_param(context_mixing);
         self.model_weights[1].set_mixing_param(context_mixing);
+        self.block_type_weights.set_mixing_param(context_mixing);
+    }
+    // the literal models the bookkeeping owns back to how they started; the mixing values and
+    // context map are part of the prediction mode rather than learned, so they stay
+    fn reset_priors(&mut self) {
+        reset_cells(&mut self.lit_cm_priors.priors);
+        reset_cells(&mut self.match_priors.priors);
+        reset_cells(&mut self.class_priors.priors);
+        reset_cells(&mut self.class_predictions);
+        for weights in self.model_weights.iter_mut().chain(self.match_weights.iter_mut()) {
+            weights.reset();
+        }
+        self.class_weights.reset();
+        self.block_type_weights.forget();
+    }
+    // whether every literal model the bookkeeping owns still holds a valid CDF, for paranoid decoding
+    fn priors_valid(&self) -> bool {
+        cells_valid(&self.lit_cm_priors.priors) && cells_valid(&self.match_priors.priors)
+            && cells_valid(&self.class_priors.priors) && cells_valid(&self.static_model.priors.priors)
     }
     pub fn clear_mixing_values(&mut self) {
         for item in self.mixing_mask.iter_mut()  {
@@ -398,6 +741,70 @@ impl<
             btype_max_seen:[0;3],
             desired_do_context_map: do_context_map,
             desired_force_stride:force_stride,
+            stream_features: 0,
+            extended_stream_features: 0,
+            extended_stream_features2: 0,
+            auto_no_dict: None,
+            command_types_coded: 0,
+            dict_commands_coded: 0,
+            desired_distance_bucketing: DistanceBucketing::default(),
+            distance_bucketing: DistanceBucketing::default(),
+            desired_mixer_warm_start: None,
+            last_distance_postfix: DISTANCE_POSTFIX_DIRECT,
+            command_type_tree: [[DefaultBitModel::default(); COMMAND_TYPE_TREE_DEPTH]; 1 << LOG_NUM_COPY_TYPE_PRIORS],
+            dict_copy_fusion: DefaultBitModel::default(),
+            adaptation: AdaptationFreeze::default(),
+            priors_reset: PriorsReset::default(),
+        }
+    }
+    pub fn duplicate(&self, m8: &mut AllocU8, mcdf16: &mut AllocCDF16) -> Self {
+        CrossCommandBookKeeping{
+            desired_prior_depth: self.desired_prior_depth,
+            desired_literal_adaptation: self.desired_literal_adaptation,
+            desired_context_mixing: self.desired_context_mixing,
+            last_dlen: self.last_dlen,
+            last_llen: self.last_llen,
+            last_clen: self.last_clen,
+            last_4_states: self.last_4_states,
+            cmap_lru: self.cmap_lru,
+            lit_len_priors: LiteralCommandPriors {
+                priors: duplicate_cell(mcdf16, &self.lit_len_priors.priors),
+            },
+            prediction_priors: PredictionModePriors {
+                priors: duplicate_cell(mcdf16, &self.prediction_priors.priors),
+            },
+            cc_priors: CrossCommandPriors::<Cdf16, AllocCDF16> {
+                priors: duplicate_cell(mcdf16, &self.cc_priors.priors),
+            },
+            copy_priors: CopyCommandPriors {
+                priors: duplicate_cell(mcdf16, &self.copy_priors.priors),
+            },
+            dict_priors: DictCommandPriors {
+                priors: duplicate_cell(mcdf16, &self.dict_priors.priors),
+            },
+            distance_context_map: duplicate_cell(m8, &self.distance_context_map),
+            btype_priors: BlockTypePriors {
+                priors: duplicate_cell(mcdf16, &self.btype_priors.priors),
+            },
+            distance_lru: self.distance_lru,
+            btype_lru: self.btype_lru,
+            btype_max_seen: self.btype_max_seen,
+            desired_do_context_map: self.desired_do_context_map,
+            desired_force_stride: self.desired_force_stride,
+            stream_features: self.stream_features,
+            extended_stream_features: self.extended_stream_features,
+            extended_stream_features2: self.extended_stream_features2,
+            auto_no_dict: self.auto_no_dict,
+            command_types_coded: self.command_types_coded,
+            dict_commands_coded: self.dict_commands_coded,
+            desired_distance_bucketing: self.desired_distance_bucketing,
+            distance_bucketing: self.distance_bucketing,
+            desired_mixer_warm_start: self.desired_mixer_warm_start,
+            last_distance_postfix: self.last_distance_postfix,
+            command_type_tree: self.command_type_tree,
+            dict_copy_fusion: self.dict_copy_fusion,
+            adaptation: self.adaptation,
+            priors_reset: self.priors_reset,
         }
     }
     /* DEPRECATED
@@ -476,13 +883,16 @@ impl<
         15
     }
     pub fn get_command_block_type(&self) -> usize {
-        self.btype_lru[BLOCK_TYPE_COMMAND_SWITCH][0] as usize
+        block_type_slot(self.btype_lru[BLOCK_TYPE_COMMAND_SWITCH][0])
     }
     pub fn get_distance_block_type(&self) -> usize {
-        self.btype_lru[BLOCK_TYPE_DISTANCE_SWITCH][0] as usize
+        block_type_slot(self.btype_lru[BLOCK_TYPE_DISTANCE_SWITCH][0])
     }
     pub fn get_literal_block_type(&self) -> usize {
-        self.btype_lru[BLOCK_TYPE_LITERAL_SWITCH][0] as usize
+        block_type_slot(self.btype_lru[BLOCK_TYPE_LITERAL_SWITCH][0])
+    }
+    pub fn get_command_type_tree_prob(&mut self, node: usize) -> &mut DefaultBitModel {
+        &mut self.command_type_tree[(self.last_4_states as usize) >> (8 - LOG_NUM_COPY_TYPE_PRIORS)][node]
     }
     pub fn get_command_type_prob(&mut self) -> &mut Cdf16 {
         //let last_8 = self.cross_command_state.recoder.last_8_literals();
@@ -490,6 +900,32 @@ impl<
                            ((self.last_4_states as usize) >> (8 - LOG_NUM_COPY_TYPE_PRIORS),
                            0)) // FIXME <-- improve this prior now that we are missing literals
     }
+    // false if the header's feature bits promised the stream never contains this command type
+    pub fn command_type_enabled(&self, command_type_code: u8) -> bool {
+        match command_type_code {
+            2 => self.stream_features & STREAM_FEATURE_NO_DICT == 0 && self.auto_no_dict != Some(true),
+            4 | 5 | 6 => self.stream_features & STREAM_FEATURE_NO_BLOCK_SWITCH == 0,
+            _ => true,
+        }
+    }
+    pub fn obs_command_type(&mut self, command_type_code: u8) {
+        self.command_types_coded = self.command_types_coded.saturating_add(1);
+        if command_type_code == 2 {
+            self.dict_commands_coded = self.dict_commands_coded.saturating_add(1);
+        }
+    }
+    // true once the bit of EXTENDED_FEATURE2_AUTO_NO_DICT is due before the next command type
+    pub fn auto_no_dict_due(&self) -> bool {
+        self.extended_stream_features2 & EXTENDED_FEATURE2_AUTO_NO_DICT != 0
+            && self.auto_no_dict.is_none()
+            && self.command_types_coded == AUTO_NO_DICT_COMMANDS
+    }
+    pub fn obs_auto_no_dict(&mut self, dict_off: bool) {
+        self.auto_no_dict = Some(dict_off);
+        if dict_off {
+            self.seed_command_type_priors();
+        }
+    }
     fn next_state(&mut self) {
         self.last_4_states >>= 2;
     }
@@ -525,7 +961,7 @@ impl<
                                  self.distance_lru[2]];
         }
     }
-    fn _obs_btype_helper(&mut self, btype_type: usize, btype: u8) {
+    fn _obs_btype_helper(&mut self, btype_type: usize, btype: u32) {
         self.next_state();
         self.btype_lru[btype_type] = [btype, self.btype_lru[btype_type][0]];
         self.btype_max_seen[btype_type] = core::cmp::max(self.btype_max_seen[btype_type], btype);
@@ -533,12 +969,52 @@ impl<
     pub fn obs_btypel(&mut self, btype:LiteralBlockSwitch) {
         self._obs_btype_helper(BLOCK_TYPE_LITERAL_SWITCH, btype.block_type());
     }
-    pub fn obs_btypec(&mut self, btype:u8) {
+    pub fn obs_btypec(&mut self, btype:u32) {
         self._obs_btype_helper(BLOCK_TYPE_COMMAND_SWITCH, btype);
     }
-    pub fn obs_btyped(&mut self, btype:u8) {
+    pub fn obs_btyped(&mut self, btype:u32) {
         self._obs_btype_helper(BLOCK_TYPE_DISTANCE_SWITCH, btype);
     }
+    // moves the initial command type probability onto the command types the stream features leave
+    fn seed_command_type_priors(&mut self) {
+        if self.stream_features == 0 && self.auto_no_dict != Some(true) {
+            return;
+        }
+        let mut enabled = [false; 16];
+        for command_type_code in [1u8, 2, 3, 4, 5, 6, 7, 0xf].iter() {
+            enabled[*command_type_code as usize] = self.command_type_enabled(*command_type_code);
+        }
+        if self.stream_features & STREAM_FEATURE_COMMAND_TYPE_TREE != 0 {
+            for command_type_code in COMMAND_TYPE_TREE_CODES.iter() {
+                enabled[*command_type_code as usize] = false; // the nibble is only reached once the tree rules these out
+            }
+        }
+        for index in 0..(1 << LOG_NUM_COPY_TYPE_PRIORS) {
+            let command_type_prob = self.cc_priors.get(CrossCommandBilling::FullSelection, (index, 0));
+            for (command_type_code, _) in enabled.iter().enumerate().filter(|x| *x.1) {
+                command_type_prob.blend(command_type_code as u8, Speed::ROCKET);
+            }
+        }
+    }
+    // the command models back to how they started, for a reset of DivansCompressorOptions::priors_reset_interval;
+    // the lrus and last lengths are history rather than probabilities, so they stay
+    pub fn reset_priors(&mut self) {
+        reset_cells(&mut self.lit_len_priors.priors);
+        reset_cells(&mut self.cc_priors.priors);
+        reset_cells(&mut self.copy_priors.priors);
+        reset_cells(&mut self.dict_priors.priors);
+        reset_cells(&mut self.prediction_priors.priors);
+        reset_cells(&mut self.btype_priors.priors);
+        self.command_type_tree = [[DefaultBitModel::default(); COMMAND_TYPE_TREE_DEPTH]; 1 << LOG_NUM_COPY_TYPE_PRIORS];
+        self.dict_copy_fusion = DefaultBitModel::default();
+        self.seed_command_type_priors();
+    }
+    // whether every command model still holds a valid CDF, for paranoid decoding
+    pub fn priors_valid(&self) -> bool {
+        cells_valid(&self.lit_len_priors.priors) && cells_valid(&self.cc_priors.priors)
+            && cells_valid(&self.copy_priors.priors) && cells_valid(&self.dict_priors.priors)
+            && cells_valid(&self.prediction_priors.priors) && cells_valid(&self.btype_priors.priors)
+    }
 }
 
 pub struct MainThreadContext<Cdf16:CDF16, AllocU8:Allocator<u8>, AllocCDF16:Allocator<Cdf16>, ArithmeticCoder:ArithmeticEncoderOrDecoder> {
@@ -551,6 +1027,42 @@ pub struct MainThreadContext<Cdf16:CDF16, AllocU8:Allocator<u8>, AllocCDF16:Allo
     pub lit_coder: ArithmeticCoder,
 }
 
+// The literal models of a MainThreadContext while a worker decodes the literals with them.
+pub struct LiteralModels<Cdf16:CDF16, AllocU8:Allocator<u8>, AllocCDF16:Allocator<Cdf16>, ArithmeticCoder:ArithmeticEncoderOrDecoder> {
+    pub lbk: LiteralBookKeeping<Cdf16, AllocU8, AllocCDF16>,
+    pub lit_high_priors: LiteralNibblePriors<Cdf16, AllocCDF16>,
+    pub lit_low_priors: LiteralNibblePriors<Cdf16, AllocCDF16>,
+    pub lit_coder: ArithmeticCoder,
+    // the context mixing priors, should a prediction mode turn on the mixing that needs them
+    spare_cm_priors: AllocCDF16::AllocatedMemory,
+}
+
+impl <Cdf16:CDF16, AllocU8:Allocator<u8>, AllocCDF16:Allocator<Cdf16>, ArithmeticCoder:ArithmeticEncoderOrDecoder> LiteralModels<Cdf16, AllocU8, AllocCDF16, ArithmeticCoder> {
+    // MainThreadContext::obs_prediction_mode_context_map, less the fitting the lending did already
+    pub fn obs_prediction_mode_context_map<ISlice:SliceWrapper<u8>>(&mut self,
+                                                                    pm: &PredictionModeContextMap<ISlice>) -> DivansOpResult {
+        if pm.get_mixing_math() >= 2 && self.lbk.lit_cm_priors.priors.slice().is_empty() {
+            self.lbk.lit_cm_priors.priors = core::mem::take(&mut self.spare_cm_priors);
+        }
+        self.lbk.obs_prediction_mode_context_map(pm, None)
+    }
+    // counts a command towards freezing the literal models, and resets them if it is due
+    pub fn obs_command_bytes(&mut self, len: u64) {
+        self.lbk.adaptation.obs_command_bytes(len);
+        if self.lbk.priors_reset.obs_command_bytes(len) {
+            self.reset_priors();
+        }
+    }
+    pub fn reset_priors(&mut self) {
+        reset_cells(&mut self.lit_high_priors.priors);
+        reset_cells(&mut self.lit_low_priors.priors);
+        self.lbk.reset_priors();
+    }
+    pub fn priors_valid(&self) -> bool {
+        cells_valid(&self.lit_high_priors.priors) && cells_valid(&self.lit_low_priors.priors) && self.lbk.priors_valid()
+    }
+}
+
 pub enum ThreadContext<Cdf16:CDF16, AllocU8:Allocator<u8>, AllocCDF16:Allocator<Cdf16>, ArithmeticCoder:ArithmeticEncoderOrDecoder> {
     MainThread(MainThreadContext<Cdf16, AllocU8, AllocCDF16, ArithmeticCoder>),
     Worker,
@@ -590,12 +1102,112 @@ impl <Cdf16:CDF16, AllocU8:Allocator<u8>, AllocCDF16:Allocator<Cdf16>, Arithmeti
         }
     }
 
+    // m8 and mcdf16 supply the copied buffers and then belong to the copy
+    pub fn duplicate(&self, mut m8: AllocU8, mut mcdf16: AllocCDF16) -> Self where ArithmeticCoder: DuplicateWithAllocator<AllocU8> {
+        let mut recoder = DivansRecodeState::<AllocU8::AllocatedMemory>::new(duplicate_cell(&mut m8, &self.recoder.ring_buffer));
+        recoder.restore_ringbuffer_to_snapshot(self.recoder.snapshot_ringbuffer());
+        let lbk = self.lbk.duplicate(&mut m8, &mut mcdf16);
+        let lit_high_priors = LiteralNibblePriors {
+            priors: duplicate_cell(&mut mcdf16, &self.lit_high_priors.priors),
+        };
+        let lit_low_priors = LiteralNibblePriors {
+            priors: duplicate_cell(&mut mcdf16, &self.lit_low_priors.priors),
+        };
+        let lit_coder = self.lit_coder.duplicate(&mut m8);
+        MainThreadContext::<Cdf16, AllocU8, AllocCDF16, ArithmeticCoder> {
+            recoder: recoder,
+            m8: RepurposingAlloc::<u8, AllocU8>::new(m8),
+            mcdf16: mcdf16,
+            lbk: lbk,
+            lit_high_priors: lit_high_priors,
+            lit_low_priors: lit_low_priors,
+            lit_coder: lit_coder,
+        }
+    }
+    // The literal bookkeeping's obs_prediction_mode_context_map, after which the high nibble priors
+    // are fitted to the contexts seen so far when compacting.  Those of later contexts are returned
+    // to mcdf16 and come back, as new, only once a context map reaches them, so the coding does not
+    // change; the low nibble priors are not split by actual context.
+    pub fn obs_prediction_mode_context_map<ISlice:SliceWrapper<u8>>(&mut self,
+                                                                    pm: &PredictionModeContextMap<ISlice>) -> DivansOpResult {
+        let ret = self.lbk.obs_prediction_mode_context_map(pm, Some(&mut self.mcdf16));
+        if let DivansOpResult::Success = ret {
+            self.fit_lit_high_priors();
+        }
+        ret
+    }
+    // the literal models back to how they started, for a reset of DivansCompressorOptions::priors_reset_interval
+    pub fn reset_literal_priors(&mut self) {
+        reset_cells(&mut self.lit_high_priors.priors);
+        reset_cells(&mut self.lit_low_priors.priors);
+        self.lbk.reset_priors();
+    }
+    // whether every literal model still holds a valid CDF, for paranoid decoding
+    pub fn literal_priors_valid(&self) -> bool {
+        cells_valid(&self.lit_high_priors.priors) && cells_valid(&self.lit_low_priors.priors) && self.lbk.priors_valid()
+    }
+    fn fit_lit_high_priors(&mut self) {
+        let contexts = self.lit_high_priors.priors.slice().len() / NIBBLE_PRIORS_PER_CONTEXT;
+        let needed = if self.lbk.compact_priors { self.lbk.literal_contexts_seen } else { NUM_BLOCK_TYPES };
+        if contexts == needed {
+            return;
+        }
+        let mut priors = self.mcdf16.alloc_cell(needed * NIBBLE_PRIORS_PER_CONTEXT);
+        let kept = core::cmp::min(contexts, needed) * NIBBLE_PRIORS_PER_CONTEXT;
+        priors.slice_mut()[..kept].clone_from_slice(&self.lit_high_priors.priors.slice()[..kept]);
+        self.mcdf16.free_cell(core::mem::replace(&mut self.lit_high_priors.priors, priors));
+    }
+    // Moves the literal models out for a worker to decode the literals with, leaving empty ones
+    // behind.  The worker has no allocator, so whatever the models could still grow into is
+    // allocated first: the high nibble priors of every context and the static literal tables.
+    // The priors of contexts not yet seen are new either way, so uncompacting changes nothing.
+    pub fn lend_literal_models(&mut self) -> LiteralModels<Cdf16, AllocU8, AllocCDF16, ArithmeticCoder>
+        where ArithmeticCoder: NewWithAllocator<AllocU8> {
+        self.lbk.compact_priors = false;
+        self.fit_lit_high_priors();
+        if self.lbk.static_model.enabled {
+            self.lbk.static_model.alloc_tables(self.m8.get_base_alloc());
+        }
+        let spare_cm_priors = if self.lbk.lit_cm_priors.priors.slice().is_empty() {
+            self.mcdf16.alloc_cell(LiteralCommandPriorsCM::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS)
+        } else {
+            AllocCDF16::AllocatedMemory::default()
+        };
+        let lit_coder = ArithmeticCoder::new(self.m8.get_base_alloc());
+        LiteralModels {
+            lbk: core::mem::replace(&mut self.lbk, LiteralBookKeeping::new(AllocU8::AllocatedMemory::default())),
+            lit_high_priors: LiteralNibblePriors {
+                priors: core::mem::take(&mut self.lit_high_priors.priors),
+            },
+            lit_low_priors: LiteralNibblePriors {
+                priors: core::mem::take(&mut self.lit_low_priors.priors),
+            },
+            lit_coder: core::mem::replace(&mut self.lit_coder, lit_coder),
+            spare_cm_priors: spare_cm_priors,
+        }
+    }
+    // puts back what lend_literal_models took
+    pub fn return_literal_models(&mut self, models: LiteralModels<Cdf16, AllocU8, AllocCDF16, ArithmeticCoder>)
+        where ArithmeticCoder: NewWithAllocator<AllocU8> {
+        self.mcdf16.free_cell(models.spare_cm_priors);
+        self.lbk = models.lbk;
+        self.lit_high_priors = models.lit_high_priors;
+        self.lit_low_priors = models.lit_low_priors;
+        core::mem::replace(&mut self.lit_coder, models.lit_coder).free(self.m8.get_base_alloc());
+    }
     pub fn free(&mut self) {
+        self.recoder.free_speculation(&mut self.m8);
         self.m8.free_cell(core::mem::replace(&mut self.recoder.ring_buffer, AllocU8::AllocatedMemory::default()));
         self.m8.free_cell(core::mem::replace(&mut self.lbk.literal_context_map, AllocU8::AllocatedMemory::default()));
         self.mcdf16.free_cell(core::mem::replace(&mut self.lit_high_priors.priors, AllocCDF16::AllocatedMemory::default()));
         self.mcdf16.free_cell(core::mem::replace(&mut self.lit_low_priors.priors, AllocCDF16::AllocatedMemory::default()));
         self.mcdf16.free_cell(core::mem::replace(&mut self.lbk.lit_cm_priors.priors, AllocCDF16::AllocatedMemory::default()));
+        self.mcdf16.free_cell(core::mem::take(&mut self.lbk.match_priors.priors));
+        self.mcdf16.free_cell(core::mem::take(&mut self.lbk.class_priors.priors));
+        self.m8.free_cell(core::mem::take(&mut self.lbk.class_predictions));
+        self.m8.free_cell(core::mem::take(&mut self.lbk.static_model.weights));
+        self.m8.free_cell(core::mem::take(&mut self.lbk.static_model.cdfs));
+        self.mcdf16.free_cell(core::mem::take(&mut self.lbk.static_model.priors.priors));
     }
 }
 impl <Cdf16:CDF16, AllocU8:Allocator<u8>, AllocCDF16:Allocator<Cdf16>, ArithmeticCoder:ArithmeticEncoderOrDecoder> ThreadContext<Cdf16, AllocU8, AllocCDF16, ArithmeticCoder> {
@@ -679,6 +1291,12 @@ impl<ArithmeticCoder:ArithmeticEncoderOrDecoder,
                                                     AllocU8,
                                                     AllocCDF16>{
     
+    // whether the command priors learn from the symbols coded; the models only freeze between
+    // commands, so the sub codecs ask once per call rather than with a prior borrowed
+    #[inline(always)]
+    pub fn adapt_cdf(&self) -> bool {
+        self.specialization.adapt_cdf() && !self.bk.adaptation.frozen()
+    }
     #[inline(always)]
     pub fn drain_or_fill_internal_buffer_lit(&mut self, output:&mut[u8], output_offset:&mut usize) -> DivansResult {
         let main = self.thread_ctx.main_thread_mut().unwrap();
@@ -723,8 +1341,20 @@ impl <AllocU8:Allocator<u8>,
                prior_depth:u8,
                literal_adaptation_rate: Option<[Speed;4]>,
                do_context_map:bool,
-               force_stride: StrideSelection) -> Self {
-        let ring_buffer = m8.alloc_cell(1 << ring_buffer_size);
+               force_stride: StrideSelection,
+               window: Option<AllocU8::AllocatedMemory>) -> Self {
+        // a window given by the caller is at least 1 << ring_buffer_size bytes, a power of two,
+        // and may hold what it held before; an empty window is what a fresh stream expects
+        let ring_buffer = match window {
+            Some(mut window) => {
+                debug_assert!(window.slice().len().is_power_of_two() && window.slice().len() >= 1 << ring_buffer_size);
+                for byte in window.slice_mut().iter_mut() {
+                    *byte = 0;
+                }
+                window
+            },
+            None => m8.alloc_cell(1 << ring_buffer_size),
+        };
         let lit_low_priors = mcdf16.alloc_cell(LiteralNibblePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
         let lit_high_priors = mcdf16.alloc_cell(LiteralNibblePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
         let lit_len_priors = mcdf16.alloc_cell(LiteralCommandPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
@@ -773,6 +1403,49 @@ impl <AllocU8:Allocator<u8>,
             ),
         }
     }
+    // an independent copy of all probabilities and bookkeeping, with buffers from m8 and mcdf16
+    pub fn duplicate(&self, mut m8: AllocU8, mut mcdf16: AllocCDF16) -> Self
+        where ArithmeticCoder: DuplicateWithAllocator<AllocU8>,
+              Specialization: Clone,
+              LinearInputBytes: DuplicateWithAllocator<AllocU8>,
+              LinearOutputBytes: DuplicateWithAllocator<AllocU8> {
+        let main = match self.thread_ctx {
+            ThreadContext::MainThread(ref ctx) => ctx,
+            ThreadContext::Worker => panic!("Tried to duplicate a Worker"),
+        };
+        let coder = self.coder.duplicate(&mut m8);
+        let bk = self.bk.duplicate(&mut m8, &mut mcdf16);
+        let demuxer = self.demuxer.duplicate(&mut m8);
+        let muxer = self.muxer.duplicate(&mut m8);
+        CrossCommandState::<ArithmeticCoder,
+                            Specialization,
+                            LinearInputBytes,
+                            LinearOutputBytes,
+                            Cdf16,
+                            AllocU8,
+                            AllocCDF16> {
+            coder: coder,
+            specialization: self.specialization.clone(),
+            thread_ctx: ThreadContext::MainThread(main.duplicate(m8, mcdf16)),
+            demuxer: demuxer,
+            muxer: muxer,
+            bk: bk,
+        }
+    }
+    // must be called before the first command: releases the priors of the disabled commands
+    // and moves the initial command type probability onto the command types that remain
+    pub fn set_stream_features(&mut self, stream_features: u8) {
+        self.bk.stream_features = stream_features;
+        if let Some(mcdf16) = self.thread_ctx.mcdf16() {
+            if stream_features & STREAM_FEATURE_NO_DICT != 0 {
+                mcdf16.free_cell(core::mem::replace(&mut self.bk.dict_priors.priors, AllocCDF16::AllocatedMemory::default()));
+            }
+            if stream_features & STREAM_FEATURE_NO_BLOCK_SWITCH != 0 {
+                mcdf16.free_cell(core::mem::replace(&mut self.bk.btype_priors.priors, AllocCDF16::AllocatedMemory::default()));
+            }
+        }
+        self.bk.seed_command_type_priors();
+    }
     pub fn snapshot_literal_or_copy_state(&self) -> CodecSnapshot {
         let ring_buffer;
         let last_8;
@@ -796,6 +1469,20 @@ impl <AllocU8:Allocator<u8>,
             last_8_literals:last_8,
         }
     }
+    // A snapshot_literal_or_copy_state to trial-encode commands from and restore, as often as wanted,
+    // until end_speculation: restoring also puts back the window bytes the trials wrote over, which
+    // are saved only as they are reached rather than by copying the ring buffer up front.
+    pub fn begin_speculation(&mut self) -> CodecSnapshot {
+        if let ThreadContext::MainThread(ref mut ctx) = self.thread_ctx {
+            ctx.recoder.begin_speculation(&mut ctx.m8);
+        }
+        self.snapshot_literal_or_copy_state()
+    }
+    pub fn end_speculation(&mut self) {
+        if let ThreadContext::MainThread(ref mut ctx) = self.thread_ctx {
+            ctx.recoder.end_speculation();
+        }
+    }
     pub fn restore_literal_or_copy_snapshot(&mut self, cs:CodecSnapshot) {
         self.bk.last_4_states = cs.last_4_states;
         self.bk.distance_lru = cs.distance_lru;
@@ -824,6 +1511,8 @@ impl <AllocU8:Allocator<u8>,
             ctx.lit_high_priors.summarize_speed_costs();
             ctx.lit_low_priors.summarize_speed_costs();
             ctx.lbk.lit_cm_priors.summarize_speed_costs();
+            ctx.lbk.match_priors.summarize_speed_costs();
+            ctx.lbk.class_priors.summarize_speed_costs();
         }
         let cdf16a = core::mem::replace(&mut self.bk.cc_priors.priors, AllocCDF16::AllocatedMemory::default());
         let cdf16b = core::mem::replace(&mut self.bk.copy_priors.priors, AllocCDF16::AllocatedMemory::default());
@@ -934,6 +1623,31 @@ impl CommandArray for EmptyCommandArray {
 }
 
 
+pub fn duplicate_literal_command<AllocU8:Allocator<u8>>(lit: &LiteralCommand<AllocatedMemoryPrefix<u8, AllocU8>>,
+                                                       m8: &mut AllocU8) -> LiteralCommand<AllocatedMemoryPrefix<u8, AllocU8>> {
+    LiteralCommand{
+        data: lit.data.duplicate(m8),
+        prob: FeatureFlagSliceType::default(), // the codec never fills in the probabilities of its own literals
+        high_entropy: lit.high_entropy,
+    }
+}
+
+pub fn duplicate_cmd<AllocU8:Allocator<u8>>(cmd: &Command<AllocatedMemoryPrefix<u8, AllocU8>>,
+                                           m8: &mut AllocU8) -> Command<AllocatedMemoryPrefix<u8, AllocU8>> {
+    match *cmd {
+        Command::Literal(ref lit) => Command::Literal(duplicate_literal_command(lit, m8)),
+        Command::PredictionMode(ref pm) => Command::PredictionMode(PredictionModeContextMap{
+            literal_context_map: pm.literal_context_map.duplicate(m8),
+            predmode_speed_and_distance_context_map: pm.predmode_speed_and_distance_context_map.duplicate(m8),
+        }),
+        Command::Dict(ref d) => Command::Dict(*d),
+        Command::Copy(ref c) => Command::Copy(*c),
+        Command::BlockSwitchCommand(ref c) => Command::BlockSwitchCommand(*c),
+        Command::BlockSwitchLiteral(ref c) => Command::BlockSwitchLiteral(*c),
+        Command::BlockSwitchDistance(ref c) => Command::BlockSwitchDistance(*c),
+    }
+}
+
 pub struct CommandSliceArray<'a, SliceType:SliceWrapper<u8>+'a>(pub &'a [Command<SliceType>]);
 
 impl<'a,SliceType:SliceWrapper<u8>+'a> CommandArray for CommandSliceArray<'a, SliceType> {
@@ -1018,3 +1732,160 @@ pub struct CodecSnapshot {
     distance_lru:[u32;4],
     last_8_literals: u64,
 }
+
+#[cfg(all(test, feature="std", feature="encoder", feature="decoder"))]
+mod test {
+    use std::vec::Vec;
+    use alloc_stdlib::HeapAlloc;
+    use alloc::SliceWrapper;
+    use conformance::{conformance_commands, conformance_expected_output, ConformanceSlice};
+    use divans_decompressor::{DivansDecompressorFactory, DivansDecompressorFactoryStruct, StaticCommand};
+    use interface::{
+        Command,
+        Decompressor,
+        DivansCompressorOptions,
+        CompressorOption,
+        FormatVersion,
+        DivansResult,
+        ErrMsg,
+        Nop,
+        DefaultCDF16,
+        HEADER_PRIORS_RESET_OFFSET,
+        PRIORS_RESET_GRANULARITY,
+        MAGIC_NUMBER_EXTENDED,
+    };
+    use test_support::{encode_with_options, compress_hybrid, decoded_options, decode_with, DecodeConfig};
+
+    // counts the CDFs allocated and not yet freed
+    struct CountingCDF16Alloc {
+        alloc: HeapAlloc<DefaultCDF16>,
+        live: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl alloc::Allocator<DefaultCDF16> for CountingCDF16Alloc {
+        type AllocatedMemory = <HeapAlloc<DefaultCDF16> as alloc::Allocator<DefaultCDF16>>::AllocatedMemory;
+        fn alloc_cell(&mut self, len: usize) -> Self::AllocatedMemory {
+            self.live.fetch_add(len, std::sync::atomic::Ordering::Relaxed);
+            self.alloc.alloc_cell(len)
+        }
+        fn free_cell(&mut self, cell: Self::AllocatedMemory) {
+            self.live.fetch_sub(cell.slice().len(), std::sync::atomic::Ordering::Relaxed);
+            self.alloc.free_cell(cell)
+        }
+    }
+
+    // the CDFs still held once the stream is decoded
+    fn decode_compacting(stream: &[u8], compact: bool, multithread: bool) -> (Vec<u8>, usize) {
+        let live = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut decompressor = DivansDecompressorFactoryStruct::<HeapAlloc<u8>,
+                                                                 CountingCDF16Alloc,
+                                                                 HeapAlloc<StaticCommand>>::new(
+            HeapAlloc::<u8>::new(0),
+            CountingCDF16Alloc {
+                alloc: HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+                live: live.clone(),
+            },
+            HeapAlloc::<StaticCommand>::new(StaticCommand::nop()),
+            false,
+            multithread);
+        decompressor.set_compact_priors(compact).unwrap();
+        let mut ret = Vec::<u8>::new();
+        let mut buffer = [0u8; 4096];
+        let mut input_offset = 0usize;
+        loop {
+            let mut output_offset = 0usize;
+            let res = decompressor.decode(stream, &mut input_offset, &mut buffer[..], &mut output_offset);
+            ret.extend(&buffer[..output_offset]);
+            match res {
+                DivansResult::Success => break,
+                DivansResult::NeedsMoreOutput => {},
+                DivansResult::NeedsMoreInput => panic!("truncated stream"),
+                DivansResult::Failure(m) => panic!("{:?}", m),
+            }
+        }
+        assert_eq!(decompressor.set_compact_priors(compact), Err(ErrMsg::WrongInternalDecoderState));
+        let held = live.load(std::sync::atomic::Ordering::Relaxed);
+        decompressor.free();
+        (ret, held)
+    }
+
+    #[test]
+    fn test_compact_priors() {
+        // the literal context maps reach 7, then 200 and then 7 actual contexts again
+        let mut commands = conformance_commands("prediction_mode");
+        for (index, max_context) in [(0usize, 7usize), (2, 200), (4, 7)].iter() {
+            if let Command::PredictionMode(ref mut pm) = commands[*index] {
+                pm.literal_context_map = ConformanceSlice((0..64).map(|x| (max_context - 1 - x * 13 % max_context) as u8).collect());
+            }
+        }
+        let expected = conformance_expected_output(&commands[..]).unwrap();
+        let mut opts = DivansCompressorOptions::default();
+        let compacted = encode_with_options(&commands[..], opts).unwrap();
+        opts.compact_priors = false;
+        assert_eq!(encode_with_options(&commands[..], opts).unwrap(), compacted);
+        for multithread in [false, true].iter() {
+            let (output, held) = decode_compacting(&compacted[..], true, *multithread);
+            assert_eq!(output, expected);
+            let (output, held_uncompacted) = decode_compacting(&compacted[..], false, *multithread);
+            assert_eq!(output, expected);
+            // the high nibble priors of the 56 contexts never used, which the worker decoding the
+            // literals of a multithreaded decoder has allocated up front
+            assert_eq!(held_uncompacted - held, if *multithread { 0 } else { 56 * 3 * 256 });
+        }
+    }
+
+    #[test]
+    fn test_freeze_adaptation() {
+        use ir_optimize::entropy_map;
+        let mut input = include_bytes!("../../testdata/alice29")[..32768].to_vec();
+        input.extend(b"all work and no play ".repeat(4096));
+        let adapting = compress_hybrid(&input[..], DivansCompressorOptions::default());
+        for after in [0u32, 16384, 1 << 30].iter() {
+            let mut opts = DivansCompressorOptions::default();
+            opts.freeze_adaptation_after = Some(*after);
+            let frozen = compress_hybrid(&input[..], opts);
+            for multithread in [false, true].iter() {
+                assert_eq!(decode_with(&frozen[..], DecodeConfig::new(*multithread), |_| {}).into_output().unwrap(), input);
+                assert_eq!(decoded_options(&frozen[..], *multithread).unwrap().freeze_adaptation_after, Some(*after));
+                assert_eq!(decoded_options(&adapting[..], *multithread).unwrap().freeze_adaptation_after, None);
+            }
+            // the measurements hook: the stream's own map prices it frozen as it was written
+            let bits: f64 = entropy_map(&frozen[..], None, 4096).unwrap().iter().map(|region| region.bits).sum();
+            assert!((bits / 8.0 - frozen.len() as f64).abs() < frozen.len() as f64 * 0.02, "{} vs {}", bits / 8.0, frozen.len());
+            if *after == 16384 {
+                // frozen on the start of the text, the models never learn the repeated phrase as well
+                assert!(frozen.len() > adapting.len(), "{} vs {}", frozen.len(), adapting.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_priors_reset() {
+        use ir_optimize::entropy_map;
+        let input = &include_bytes!("../../testdata/alice29")[..];
+        let plain = compress_hybrid(input, DivansCompressorOptions::default());
+        let mut opts = DivansCompressorOptions::default();
+        opts.priors_reset_interval = Some(PRIORS_RESET_GRANULARITY);
+        let reset = compress_hybrid(input, opts);
+        assert_eq!(reset[..MAGIC_NUMBER_EXTENDED.len()], MAGIC_NUMBER_EXTENDED);
+        assert_eq!(reset[HEADER_PRIORS_RESET_OFFSET..HEADER_PRIORS_RESET_OFFSET + 2], [1, 0]);
+        for multithread in [false, true].iter() {
+            assert_eq!(decode_with(&reset[..], DecodeConfig::new(*multithread), |_| {}).into_output().unwrap(), &input[..]);
+            assert_eq!(decoded_options(&reset[..], *multithread).unwrap().priors_reset_interval, Some(PRIORS_RESET_GRANULARITY));
+            assert_eq!(decoded_options(&plain[..], *multithread).unwrap().priors_reset_interval, None);
+        }
+        // the models relearn the text after each of the two resets
+        assert!(reset.len() > plain.len(), "{} vs {}", reset.len(), plain.len());
+        let bits: f64 = entropy_map(&reset[..], None, 4096).unwrap().iter().map(|region| region.bits).sum();
+        assert!((bits / 8.0 - reset.len() as f64).abs() < reset.len() as f64 * 0.02, "{} vs {}", bits / 8.0, reset.len());
+
+        opts.priors_reset_interval = Some(PRIORS_RESET_GRANULARITY + 1);
+        assert_eq!(opts.validate(), Err(ErrMsg::OptionOutOfRange(CompressorOption::PriorsResetInterval)));
+        opts.priors_reset_interval = Some(0);
+        assert_eq!(opts.validate(), Err(ErrMsg::OptionOutOfRange(CompressorOption::PriorsResetInterval)));
+        opts.priors_reset_interval = Some(PRIORS_RESET_GRANULARITY);
+        opts.format_version = Some(FormatVersion::StreamFeatures);
+        assert_eq!(opts.validate(), Err(ErrMsg::ConflictingOptions(CompressorOption::FormatVersion,
+                                                                   CompressorOption::PriorsResetInterval)));
+    }
+}
@@ -5,6 +5,7 @@ use ::interface::{
     MAX_PREDMODE_SPEED_AND_DISTANCE_CONTEXT_MAP_SIZE,
     MAX_LITERAL_CONTEXT_MAP_SIZE,
     EncoderOrDecoderRecoderSpecialization,
+    DuplicateWithAllocator,
     ErrMsg,
 };
 use codec::interface::CMD_CODER;
@@ -39,6 +40,17 @@ impl<AllocU8:Allocator<u8>, LinearInputBytes:StreamDemuxer<AllocU8>> DemuxerAndR
     }
 }
 
+impl<AllocU8:Allocator<u8>,
+     LinearInputBytes:StreamDemuxer<AllocU8>+DuplicateWithAllocator<AllocU8>> DuplicateWithAllocator<AllocU8> for DemuxerAndRingBuffer<AllocU8, LinearInputBytes> {
+    fn duplicate(&self, m8: &mut AllocU8) -> Self {
+        DemuxerAndRingBuffer::<AllocU8, LinearInputBytes>{
+            input:self.input.duplicate(m8),
+            phantom:core::marker::PhantomData::<AllocU8>::default(),
+            err: self.err,
+        }
+    }
+}
+
 impl<AllocU8:Allocator<u8>, LinearInputBytes:StreamDemuxer<AllocU8>> StreamDemuxer<AllocU8> for DemuxerAndRingBuffer<AllocU8, LinearInputBytes> {
     #[inline(always)]
     fn write_linear(&mut self, data:&[u8], m8: &mut AllocU8) -> usize {
@@ -88,10 +100,10 @@ impl<AllocU8:Allocator<u8>, LinearInputBytes:StreamDemuxer<AllocU8>> ThreadToMai
     fn pull_context_map(&mut self, mut m8: Option<&mut RepurposingAlloc<u8, AllocU8>>) -> Result<PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>, ()> {
         match m8 {
             Some(ref mut m) => {
-                let lit = m.use_cached_allocation::<UninitializedOnAlloc>().alloc_cell(MAX_LITERAL_CONTEXT_MAP_SIZE);
+                let lit = m.use_slab_arena::<UninitializedOnAlloc>().alloc_cell(MAX_LITERAL_CONTEXT_MAP_SIZE);
                 Ok(PredictionModeContextMap::<AllocatedMemoryPrefix<u8, AllocU8>> {
                     literal_context_map:lit,
-                    predmode_speed_and_distance_context_map:m.use_cached_allocation::<UninitializedOnAlloc>().alloc_cell(
+                    predmode_speed_and_distance_context_map:m.use_slab_arena::<UninitializedOnAlloc>().alloc_cell(
                         MAX_PREDMODE_SPEED_AND_DISTANCE_CONTEXT_MAP_SIZE),
                 })
             },
@@ -131,12 +143,12 @@ impl<AllocU8:Allocator<u8>, LinearInputBytes:StreamDemuxer<AllocU8>> ThreadToMai
                                                        tmp_output_offset_bytes);
         match ret {
             DivansOutputResult::Success => {
-                free_cmd(cmd, &mut m8.as_mut().unwrap().use_cached_allocation::<
+                free_cmd(cmd, &mut m8.as_mut().unwrap().use_slab_arena::<
                         UninitializedOnAlloc>());
                 self.err
             },
             DivansOutputResult::Failure(_) => {
-                free_cmd(cmd, &mut m8.as_mut().unwrap().use_cached_allocation::<
+                free_cmd(cmd, &mut m8.as_mut().unwrap().use_slab_arena::<
                         UninitializedOnAlloc>());
                 ret
             }
@@ -1,7 +1,7 @@
 use core;
 use interface::{DivansResult, StreamMuxer, StreamDemuxer};
-use ::probability::{CDF16, Speed, ExternalProbCDF16};
-use super::priors::{LiteralNibblePriorType, LiteralCommandPriorType, LiteralCMPriorType};
+use ::probability::{CDF16, Speed, ExternalProbCDF16, ExternalProb};
+use super::priors::{LiteralNibblePriorType, LiteralCommandPriorType, LiteralCMPriorType, LiteralMatchPriorType, LiteralByteClassPriorType};
 
 use alloc_util::{RepurposingAlloc, AllocatedMemoryPrefix, UninitializedOnAlloc};
 use alloc::{SliceWrapper, Allocator, SliceWrapperMut};
@@ -14,6 +14,7 @@ use super::interface::{
     LIT_CODER,
     CMD_CODER,
     drain_or_fill_static_buffer,
+    duplicate_literal_command,
     ThreadContext,
 };
 use threading::ThreadToMain;
@@ -22,8 +23,10 @@ use ::interface::{
     ArithmeticEncoderOrDecoder,
     BillingDesignation,
     LiteralCommand,
+    MAX_NIBBLE_BATCH,
 };
 use super::priors::LiteralNibblePriors;
+use super::trace;
 use ::priors::PriorCollection;
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum LiteralSubstate {
@@ -39,6 +42,25 @@ pub enum LiteralSubstate {
     FullyDecoded,
 }
 
+impl LiteralSubstate {
+    // the tag and argument identifying this substate in a codec trace
+    pub fn trace_code(&self) -> (u8, u64) {
+        let (code, arg) = match *self {
+            LiteralSubstate::Begin => (0, 0),
+            LiteralSubstate::LiteralCountSmall(high_entropy) => (1, u64::from(high_entropy)),
+            LiteralSubstate::LiteralCountFirst => (2, 0),
+            LiteralSubstate::LiteralCountLengthGreater14Less25 => (3, 0),
+            LiteralSubstate::LiteralCountMantissaNibbles(nibble, len) => (4, (u64::from(nibble) << 32) | u64::from(len)),
+            LiteralSubstate::LiteralNibbleIndex(index) => (5, u64::from(index)),
+            LiteralSubstate::SafeLiteralNibbleIndex(index) => (6, u64::from(index)),
+            LiteralSubstate::LiteralNibbleLowerHalf(index) => (7, u64::from(index)),
+            LiteralSubstate::LiteralNibbleIndexWithECDF(index) => (8, u64::from(index)),
+            LiteralSubstate::FullyDecoded => (9, 0),
+        };
+        (code | trace::LITERAL_SUBSTATE_TAG, arg)
+    }
+}
+
 macro_rules! unwrap_ref {
     ($x: expr) => (match $x { Some(ref mut y) => y, None => unreachable!()});
 }
@@ -49,6 +71,15 @@ pub struct LiteralState<AllocU8:Allocator<u8>> {
     pub state: LiteralSubstate,
 }
 
+impl<AllocU8:Allocator<u8>> LiteralState<AllocU8> {
+    pub fn duplicate(&self, m8: &mut AllocU8) -> Self {
+        LiteralState::<AllocU8> {
+            lc: duplicate_literal_command(&self.lc, m8),
+            state: self.state,
+        }
+    }
+}
+
 
 trait NibbleArrayCallSite {
    const FULLY_SAFE: bool;
@@ -111,16 +142,98 @@ pub fn get_prev_word_context<Cdf16:CDF16,
     };
     assert_eq!(selected_context, selected_contextA);
      */
-    let cmap_index = selected_context as usize + ((lbk.get_literal_block_type() as usize) << 6);
+    let cmap_index = selected_context as usize + (lbk.get_literal_block_type() << 6);
     let actual_context = lbk.literal_context_map.slice()[cmap_index as usize];
     ByteContext{actual_context:actual_context, stride_bytes:lbk.last_8_literals, prev_byte: prev_byte}
 }
 
+// A block coded low nibble first is coded as though each of its bytes had its nibbles swapped,
+// and so are the bytes its priors are indexed by: the first nibble sees the low halves of the
+// bytes before it and the second sees the first.  The actual context still comes from the bytes,
+// as does the previous byte that picks the mixing value.
+#[inline(always)]
+fn nibble_order_context(byte_context: ByteContext, lsb_first: bool) -> ByteContext {
+    if !lsb_first {
+        return byte_context;
+    }
+    let stride_bytes = byte_context.stride_bytes;
+    ByteContext{actual_context: byte_context.actual_context,
+                stride_bytes: ((stride_bytes & 0x0f0f_0f0f_0f0f_0f0f) << 4) | ((stride_bytes >> 4) & 0x0f0f_0f0f_0f0f_0f0f),
+                prev_byte: byte_context.prev_byte}
+}
+
+
+// The mixing mask option for a nibble and the index of its CombinedNibble prior.
+#[inline(always)]
+fn nibble_prior_index<Cdf16:CDF16,
+                      AllocU8:Allocator<u8>,
+                      AllocCDF16:Allocator<Cdf16>,
+                      HTraits:HighTrait>(lbk: &LiteralBookKeeping<Cdf16, AllocU8, AllocCDF16>,
+                                         byte_context: ByteContext,
+                                         cur_byte_prior: u8) -> (u8, (usize, usize, usize)) {
+    // The mixing_mask is a lookup table that determines which priors are most relevant
+    // for a particular actual_context. The table is also indexed by the
+    // upper half of the current nibble, or the upper half of the previous nibble
+    let mut mixing_mask_index = usize::from(byte_context.actual_context);
+    if !HTraits::IS_HIGH {
+        mixing_mask_index |= usize::from(cur_byte_prior & 0xf) << 8;
+        mixing_mask_index |= 4096;
+    } else {
+        mixing_mask_index |= (usize::from(byte_context.prev_byte) >> 4) << 8;
+    }
+    let mm_opts = lbk.mixing_mask[mixing_mask_index];
+    let fast_cm_prior_mask = (-((mm_opts != 3) as i8)) as u8;
+    // if the mixing mask is not zero or 3, the byte, stride distance prior, is a good prior
+    let mm = -((mm_opts != 0 && mm_opts != 3) as isize) as u8;
+    // mix 1 lets us examine just half of the previous byte in addition to the context
+    let opt_1_f_mask = ((-((mm_opts == 1) as i8)) & 0xf) as u8; // if mm_opts == 1 {0xf} else {0x0}
+
+    // Choose the stride b based on the mixing mask. The stride offset is 0, 8, 16, 24 or 56 bits
+    // this translates into actual strides of 1, 2, 3, 4 or 8 bytes
+    let stride_offset = if mm_opts < 4 {0} else {core::cmp::min(7, mm_opts as usize ^ 4) << 3};
+    let index_b: usize;
+    let index_c: usize;
+    // pick the previous byte based on the chosen stride
+    let stride_selected_byte = (byte_context.stride_bytes >> (0x38 - stride_offset)) as u8 & 0xff;
+    if HTraits::IS_HIGH { // high nibble must depend only on the previous bytes
+        index_b = usize::from(stride_selected_byte & mm & (!opt_1_f_mask));
+        index_c = usize::from(byte_context.actual_context);
+    } else { // low nibble can depend on the upper half of the current byte
+        index_b = usize::from((mm & stride_selected_byte) | (!mm & byte_context.actual_context));
+        index_c = usize::from((cur_byte_prior & fast_cm_prior_mask) | ((byte_context.actual_context & opt_1_f_mask) << 4));
+    };
+    (mm_opts, (usize::from((mm >> 7) ^ (opt_1_f_mask >> 2)), index_b, index_c))
+}
+
+// Prefetches the priors code_nibble is going to read for a nibble, so their loads overlap the
+// coder and CDF updates of the nibble before: the high nibble's once the previous byte is known and
+// the low nibble's once the high nibble is.  The prior tables are far too big to stay in cache.
+#[inline(always)]
+fn prefetch_nibble_priors<Cdf16:CDF16,
+                          AllocU8:Allocator<u8>,
+                          AllocCDF16:Allocator<Cdf16>,
+                          CTraits:CodecTraits,
+                          HTraits:HighTrait>(lbk: &LiteralBookKeeping<Cdf16, AllocU8, AllocCDF16>,
+                                             byte_context: ByteContext,
+                                             cur_byte_prior: u8,
+                                             lit_priors: &LiteralNibblePriors<Cdf16, AllocCDF16>,
+                                             _ctraits: &'static CTraits) {
+    let (_, prior_index) = nibble_prior_index::<Cdf16, AllocU8, AllocCDF16, HTraits>(lbk, byte_context, cur_byte_prior);
+    lit_priors.prefetch(LiteralNibblePriorType::CombinedNibble, prior_index);
+    if CTraits::MIXING_PRIORS {
+        if HTraits::IS_HIGH {
+            lbk.lit_cm_priors.prefetch(LiteralCMPriorType::FirstNibble, (0, usize::from(byte_context.actual_context)));
+        } else {
+            lbk.lit_cm_priors.prefetch(LiteralCMPriorType::SecondNibble,
+                                       (0, usize::from(cur_byte_prior), usize::from(byte_context.actual_context)));
+        }
+    }
+}
 
 impl<AllocU8:Allocator<u8>,
                          > LiteralState<AllocU8> {
     pub fn free(&mut self, m8:&mut RepurposingAlloc<u8, AllocU8>) {
-        m8.use_cached_allocation::<UninitializedOnAlloc>().free_cell(
+        m8.use_slab_arena::<UninitializedOnAlloc>().free_cell(
             core::mem::replace(&mut self.lc.data,
                                AllocatedMemoryPrefix::<u8, AllocU8>::default()));
 
@@ -130,26 +243,53 @@ impl<AllocU8:Allocator<u8>,
                        >(&mut self,
                          nibble_index: u32,
                          mut cur_nibble: u8,
-                         _cur_byte_prior: u8,
                          local_coder: &mut ArithmeticCoder,
                          default_nibble_prob: Cdf16,
-                         in_cmd_prob_slice: &[u8]) -> u8 {
-        let high_nibble = (nibble_index & 1) == 0;
-        let mut ecdf = ExternalProbCDF16::default();
-        let shift_offset = if high_nibble { 4usize } else { 0usize };
-        let byte_index = (nibble_index as usize) >> 1;
-        let en = byte_index*8 + shift_offset + 4;
-        if en <= in_cmd_prob_slice.len() {
-            let st = en - 4;
-            let probs = [in_cmd_prob_slice[st], in_cmd_prob_slice[st + 1],
-                             in_cmd_prob_slice[st + 2], in_cmd_prob_slice[st + 3]];
-            ecdf.init(cur_nibble, &probs, &default_nibble_prob);
-            local_coder.get_or_put_nibble(&mut cur_nibble, &ecdf, BillingDesignation::LiteralCommand(LiteralSubstate::LiteralNibbleIndex(nibble_index & 1)));
-        } else {
-            local_coder.get_or_put_nibble(&mut cur_nibble, &ecdf, BillingDesignation::LiteralCommand(LiteralSubstate::LiteralNibbleIndex(nibble_index & 1)));
+                         external_prob: Option<ExternalProb>) -> u8 {
+        let billing = BillingDesignation::LiteralCommand(LiteralSubstate::LiteralNibbleIndex(nibble_index & 1));
+        match external_prob {
+            Some(external_prob) => {
+                let ecdf = ExternalProbCDF16::new(&external_prob, &default_nibble_prob);
+                local_coder.get_or_put_nibble(&mut cur_nibble, &ecdf, billing);
+            },
+            None => {
+                local_coder.get_or_put_nibble(&mut cur_nibble, &default_nibble_prob, billing);
+            },
         }
         cur_nibble
     }
+    // Codes up to MAX_NIBBLE_BATCH nibbles of in_cmd from nibble_index on with one call to the coder:
+    // the CDF of each depends only on its external probability, so they are all known up front.
+    // The batch ends before the first nibble without one.  Returns how many nibbles were coded.
+    pub fn ecdf_write_nibble_batch<ArithmeticCoder:ArithmeticEncoderOrDecoder,
+                                   Specialization:EncoderOrDecoderSpecialization,
+                                   ISlice:SliceWrapper<u8>,
+                                   Cdf16:CDF16,
+                                  >(&self,
+                                    nibble_index: u32,
+                                    in_cmd: &LiteralCommand<ISlice>,
+                                    local_coder: &mut ArithmeticCoder,
+                                    specialization: &Specialization,
+                                    nibbles: &mut [u8; MAX_NIBBLE_BATCH],
+                                    billing: BillingDesignation) -> usize {
+        let nibbles_left = (self.lc.data.slice().len() << 1) - nibble_index as usize;
+        let mut probs = [ExternalProbCDF16::default(); MAX_NIBBLE_BATCH];
+        let mut count = 0;
+        for (nibble, prob) in nibbles.iter_mut().zip(probs.iter_mut()).take(nibbles_left) {
+            let cur_index = nibble_index + count as u32;
+            match specialization.get_external_prob(in_cmd, cur_index) {
+                Some(external_prob) => *prob = ExternalProbCDF16::new(&external_prob, &Cdf16::default()),
+                None => break,
+            }
+            let shift = if (cur_index & 1) == 0 { 4 } else { 0 };
+            *nibble = (specialization.get_literal_byte(in_cmd, (cur_index >> 1) as usize) >> shift) & 0xf;
+            count += 1;
+        }
+        if count == 0 {
+            return 0;
+        }
+        local_coder.get_or_put_nibble_batch(&mut nibbles[..count], &probs[..count], billing)
+    }
     #[cfg_attr(not(feature="no-inline"), inline(always))]
     fn code_nibble<'a, ArithmeticCoder:ArithmeticEncoderOrDecoder,
                    Specialization:EncoderOrDecoderSpecialization,
@@ -169,44 +309,85 @@ impl<AllocU8:Allocator<u8>,
                                                       AllocCDF16>,
                      lit_priors:&'a mut LiteralNibblePriors<Cdf16, AllocCDF16>,
                      specialization:&Specialization) -> (u8, Option<&'a mut Cdf16>) {
-
-        // The mixing_mask is a lookup table that determines which priors are most relevant
-        // for a particular actual_context. The table is also indexed by the
-        // upper half of the current nibble, or the upper half of the previous nibble
-        let mut mixing_mask_index = usize::from(byte_context.actual_context);
-        if !HTraits::IS_HIGH {
-            mixing_mask_index |= usize::from(cur_byte_prior & 0xf) << 8;
-            mixing_mask_index |= 4096;
-        } else {
-            mixing_mask_index |= (usize::from(byte_context.prev_byte) >> 4) << 8;
+        if lbk.static_model.enabled {
+            // the metablock's table codes the nibble and nothing adapts
+            let table = if HTraits::IS_HIGH { 0 } else { 1 + usize::from(cur_byte_prior & 0xf) };
+            let prob = lbk.static_model.cdf(byte_context.actual_context, table);
+            local_coder.get_or_put_nibble(&mut cur_nibble,
+                                          &prob,
+                                          BillingDesignation::LiteralCommand(LiteralSubstate::LiteralNibbleIndex(!HTraits::IS_HIGH as u32)));
+            return (cur_nibble, None);
         }
-        let mm_opts = lbk.mixing_mask[mixing_mask_index];
-        let fast_cm_prior_mask = (-((mm_opts != 3) as i8)) as u8;
-        // if the mixing mask is not zero or 3, the byte, stride distance prior, is a good prior
-        let mm = -((mm_opts != 0 && mm_opts != 3) as isize) as u8;
-        // mix 1 lets us examine just half of the previous byte in addition to the context
-        let opt_1_f_mask = ((-((mm_opts == 1) as i8)) & 0xf) as u8; // if mm_opts == 1 {0xf} else {0x0}
-
-        // Choose the stride b based on the mixing mask. The stride offset is 0, 8, 16, 24 or 56 bits
-        // this translates into actual strides of 1, 2, 3, 4 or 8 bytes
-        let stride_offset = if mm_opts < 4 {0} else {core::cmp::min(7, mm_opts as usize ^ 4) << 3};
-        let index_b: usize;
-        let index_c: usize;
-        // pick the previous byte based on the chosen stride
-        let stride_selected_byte = (byte_context.stride_bytes >> (0x38 - stride_offset)) as u8 & 0xff;
-        if HTraits::IS_HIGH { // high nibble must depend only on the previous bytes
-            index_b = usize::from(stride_selected_byte & mm & (!opt_1_f_mask));
-            index_c = usize::from(byte_context.actual_context);
-        } else { // low nibble can depend on the upper half of the current byte
-            index_b = usize::from((mm & stride_selected_byte) | (!mm & byte_context.actual_context));
-            index_c = usize::from((cur_byte_prior & fast_cm_prior_mask) | ((byte_context.actual_context & opt_1_f_mask) << 4));
-        };
+        let adapt_cdf = lbk.adapt_cdf(specialization);
+        let (mm_opts, prior_index) = nibble_prior_index::<Cdf16, AllocU8, AllocCDF16, HTraits>(lbk, byte_context, cur_byte_prior);
         // select the probability out of a 3x256x256 array of 32 byte nibble-CDFs
-        let nibble_prob = lit_priors.get(LiteralNibblePriorType::CombinedNibble,
-                                         (usize::from((mm >> 7) ^ (opt_1_f_mask >> 2)),
-                                          index_b,
-                                          index_c));
-        //eprintln!("Literal index {} {} {}\n", usize::from((mm >> 7) ^ (opt_1_f_mask >> 2)), index_b, index_c);
+        let nibble_prob = lit_priors.get(LiteralNibblePriorType::CombinedNibble, prior_index);
+        divans_trace!(high_nibble = HTraits::IS_HIGH, actual_context = byte_context.actual_context,
+                      prev_byte = byte_context.prev_byte, mixing = mm_opts; "literal nibble prior");
+        if let Some(match_byte) = lbk.match_byte {
+            // The first literal after a copy, which the copy stopped short of, so likely not its match
+            // byte: what the match byte says of it is mixed in with the usual prior.
+            let match_byte = match_byte.rotate_left(u32::from(lbk.lsb_first) << 2);
+            let (match_type, match_nibble) = if HTraits::IS_HIGH {
+                (LiteralMatchPriorType::FirstNibble, match_byte >> 4)
+            } else if cur_byte_prior & 0xf == match_byte >> 4 {
+                (LiteralMatchPriorType::SecondNibble, match_byte & 0xf)
+            } else {
+                (LiteralMatchPriorType::SecondNibble, 16 | (cur_byte_prior & 0xf))
+            };
+            let match_index = (usize::from(match_nibble), usize::from(byte_context.actual_context));
+            let match_prob = *lbk.match_priors.get(match_type.clone(), match_index);
+            let weights = &mut lbk.match_weights[HTraits::IS_HIGH as usize];
+            let prob = match_prob.average(nibble_prob, weights.norm_weight() as u16 as i32);
+            let weighted_prob_range = local_coder.get_or_put_nibble(
+                &mut cur_nibble,
+                &prob,
+                BillingDesignation::LiteralCommand(LiteralSubstate::LiteralNibbleIndex(!HTraits::IS_HIGH as u32)));
+            if !lbk.adaptation.frozen() {
+                weights.update([match_prob.sym_to_start_and_freq(cur_nibble).range.freq,
+                                nibble_prob.sym_to_start_and_freq(cur_nibble).range.freq],
+                               weighted_prob_range.freq);
+            }
+            if adapt_cdf {
+                let speed = lbk.literal_adaptation[0];
+                lbk.match_priors.get(match_type, match_index).blend(cur_nibble, speed);
+            }
+            return (cur_nibble, if mm_opts == 2 { None } else { Some(nibble_prob) });
+        }
+        if HTraits::IS_HIGH && !lbk.lsb_first && !lbk.class_priors.priors.slice().is_empty() {
+            // What the class of the predicted byte says of the first nibble is mixed in with the
+            // usual prior, and the result stands in for it in the context mixing below.
+            let class_index = (lbk.predicted_byte_class(), usize::from(byte_context.actual_context));
+            let class_prob = *lbk.class_priors.get(LiteralByteClassPriorType::FirstNibble, class_index);
+            let base_prob = if mm_opts == 2 { Cdf16::default() } else { *nibble_prob };
+            let class_mixed = class_prob.average(&base_prob, lbk.class_weights.norm_weight() as u16 as i32);
+            let billing = BillingDesignation::LiteralCommand(LiteralSubstate::LiteralNibbleIndex(0));
+            if CTraits::MIXING_PRIORS {
+                let cm_prob = lbk.lit_cm_priors.get(LiteralCMPriorType::FirstNibble,
+                                                    (0, usize::from(byte_context.actual_context)));
+                let prob = cm_prob.average(&class_mixed, lbk.model_weights[1].norm_weight() as u16 as i32);
+                let weighted_prob_range = local_coder.get_or_put_nibble(&mut cur_nibble, &prob, billing);
+                if !lbk.adaptation.frozen() {
+                    lbk.model_weights[1].update([cm_prob.sym_to_start_and_freq(cur_nibble).range.freq,
+                                                 class_mixed.sym_to_start_and_freq(cur_nibble).range.freq],
+                                                weighted_prob_range.freq);
+                }
+                if adapt_cdf {
+                    cm_prob.blend(cur_nibble, lbk.literal_adaptation[3]);
+                }
+            } else {
+                local_coder.get_or_put_nibble(&mut cur_nibble, &class_mixed, billing);
+            }
+            if !lbk.adaptation.frozen() {
+                lbk.class_weights.update([class_prob.sym_to_start_and_freq(cur_nibble).range.freq,
+                                          base_prob.sym_to_start_and_freq(cur_nibble).range.freq],
+                                         class_mixed.sym_to_start_and_freq(cur_nibble).range.freq);
+            }
+            if adapt_cdf {
+                lbk.class_priors.get(LiteralByteClassPriorType::FirstNibble, class_index).blend(cur_nibble, Speed::FAST);
+            }
+            return (cur_nibble, if mm_opts == 2 { None } else { Some(nibble_prob) });
+        }
         {
             let immutable_prior: Cdf16;
             let coder_prior: &Cdf16;
@@ -225,7 +406,7 @@ impl<AllocU8:Allocator<u8>,
                     lbk.lit_cm_priors.get(LiteralCMPriorType::SecondNibble,
                                                     (0,//(byte_context.selected_context as i8 & -(bk.prior_depth as i8)) as usize,
                                                      usize::from(cur_byte_prior),
-                                                     usize::from(byte_context.actual_context)))
+                                                     0))
                 };
                 let prob = cm_prob.average(nibble_prob, lbk.model_weights[HTraits::IS_HIGH as usize].norm_weight() as u16 as i32);
                 let weighted_prob_range = local_coder.get_or_put_nibble(
@@ -237,8 +418,10 @@ impl<AllocU8:Allocator<u8>,
                     cm_prob.sym_to_start_and_freq(cur_nibble).range.freq,
                     nibble_prob.sym_to_start_and_freq(cur_nibble).range.freq,
                 ];
-                lbk.model_weights[HTraits::IS_HIGH as usize].update(model_probs, weighted_prob_range.freq);
-                if specialization.adapt_cdf() {
+                if !lbk.adaptation.frozen() {
+                    lbk.model_weights[HTraits::IS_HIGH as usize].update(model_probs, weighted_prob_range.freq);
+                }
+                if adapt_cdf {
                     cm_prob.blend(cur_nibble, lbk.literal_adaptation[2 | HTraits::IS_HIGH as usize].clone());
                 }
             } else {
@@ -268,7 +451,7 @@ impl<AllocU8:Allocator<u8>,
                          ISlice: SliceWrapper<u8>,
                          NibbleArrayType: NibbleArrayCallSite,
                          >(&mut self,
-                           m8: &mut AllocU8,
+                           m8: &mut Option<&mut AllocU8>,
                            output_bytes:&mut [u8],
                            output_offset: &mut usize,
                            in_cmd: &LiteralCommand<ISlice>,
@@ -290,10 +473,13 @@ impl<AllocU8:Allocator<u8>,
       let start_byte_index = (start_nibble_index as usize) >> 1;
       let mut retval = DivansResult::Success;
       let mut first = true;
+      // h_nibble and l_nibble below are the first and second nibble coded, swapped for lsb_first
+      let order_rotation = u32::from(lbk.lsb_first) << 2;
+      // the context of the byte about to be coded, worked out as soon as the byte before it is
+      let mut byte_context = nibble_order_context(get_prev_word_context(lbk, ctraits), lbk.lsb_first);
       for (byte_offset, lc_target) in lc_data.slice_mut()[start_byte_index..last_llen as usize].iter_mut().enumerate() {
            let mut byte_to_encode_val = specialization.get_literal_byte(in_cmd,
-                                                                        start_byte_index.wrapping_add(byte_offset));
-           let byte_context = get_prev_word_context(lbk, ctraits);
+                                                                        start_byte_index.wrapping_add(byte_offset)).rotate_left(order_rotation);
            let h_nibble;
            let low_buffer_warning;
            if NibbleArrayType::SECOND_HALF == false || first == false {
@@ -306,17 +492,21 @@ impl<AllocU8:Allocator<u8>,
                                                              lbk,
                                                              lit_high_priors,
                                                              specialization);
+               if !lbk.static_model.enabled {
+                   prefetch_nibble_priors::<Cdf16, AllocU8, AllocCDF16, CTraits, LowNibble>(lbk, byte_context, cur_nibble,
+                                                                                          lit_low_priors, ctraits);
+               }
                let byte_pull_status = drain_or_fill_static_buffer(LIT_CODER,
                                                                   local_coder,
                                                                   demuxer,
                                                                   muxer,
                                                                   output_bytes,
                                                                   output_offset,
-                                                                  &mut Some(m8));
+                                                                  m8);
                low_buffer_warning = demuxer.data_ready(LIT_CODER as u8) < 16;
                h_nibble = cur_nibble;
                if let Some(prob) = cur_prob {
-                   if specialization.adapt_cdf() {
+                   if lbk.adapt_cdf(specialization) {
                        prob.blend(cur_nibble, lbk.literal_adaptation[0]);
                    }
                }
@@ -346,11 +536,16 @@ impl<AllocU8:Allocator<u8>,
                                                      lit_low_priors,
                                                      specialization,
                                                      );
-           let cur_byte = l_nibble | (h_nibble << 4);
+           let cur_byte = (l_nibble | (h_nibble << 4)).rotate_left(order_rotation);
            lbk.push_literal_byte(cur_byte);
            *lc_target = cur_byte;
+           byte_context = nibble_order_context(get_prev_word_context(lbk, ctraits), lbk.lsb_first);
+           if !lbk.static_model.enabled {
+               prefetch_nibble_priors::<Cdf16, AllocU8, AllocCDF16, CTraits, HighNibble>(lbk, byte_context, 0,
+                                                                                      lit_high_priors, ctraits);
+           }
            if let Some(prob) = l_prob {
-               if specialization.adapt_cdf() {
+               if lbk.adapt_cdf(specialization) {
                    prob.blend(l_nibble, lbk.literal_adaptation[0]);
                }
            }
@@ -371,7 +566,7 @@ impl<AllocU8:Allocator<u8>,
                                                                muxer,
                                                                output_bytes,
                                                                output_offset,
-                                                               &mut Some(m8));
+                                                               m8);
             if NibbleArrayType::FULLY_SAFE {
                 debug_assert!(match byte_pull_status {DivansResult::Success => true, _ => false,});
             } else {
@@ -410,7 +605,7 @@ impl<AllocU8:Allocator<u8>,
                             AllocCDF16:Allocator<Cdf16>,
                             CTraits:CodecTraits,
                                           >(&mut self,
-                                            m8: &mut AllocU8,
+                                            m8: &mut Option<&mut AllocU8>,
                                             lit_coder: &mut ArithmeticCoder,
                                             lbk: &mut LiteralBookKeeping<Cdf16, AllocU8, AllocCDF16>,
                                             lit_high_priors: &mut LiteralNibblePriors<Cdf16, AllocCDF16>,
@@ -427,10 +622,20 @@ impl<AllocU8:Allocator<u8>,
                                               lit_coder,
                                               demuxer, muxer,
                                               output_bytes, output_offset,
-                                              &mut Some(m8)) {
+                                              m8) {
                 DivansResult::Success => {},
                 needs_something => return needs_something,
             }
+            if lbk.static_model.tables_pending() {
+                lbk.static_model.code_table_nibble(m8.as_mut().map(|m8| &mut **m8), lit_coder, specialization);
+                // the tables ate into the input the safe state counted on
+                self.state = match self.state {
+                    LiteralSubstate::SafeLiteralNibbleIndex(index) | LiteralSubstate::LiteralNibbleIndex(index) =>
+                        self.state_literal_nibble_index(index, demuxer.data_ready(LIT_CODER as u8)),
+                    state => state,
+                };
+                continue;
+            }
             match self.state {
                 LiteralSubstate::SafeLiteralNibbleIndex(start_nibble_index) => {
                     match self.code_nibble_array(m8, output_bytes, output_offset,
@@ -513,6 +718,7 @@ impl<AllocU8:Allocator<u8>,
                           output_bytes:&mut [u8],
                           output_offset: &mut usize,
                           ctraits: &'static CTraits) -> DivansResult {
+        let adapt_cdf = superstate.adapt_cdf();
         let literal_len = in_cmd.data.slice().len() as u32;
         let serialized_large_literal_len  = literal_len.wrapping_sub(NUM_LITERAL_LENGTH_MNEMONIC + 1);
         let lllen: u8 = (core::mem::size_of_val(&serialized_large_literal_len) as u32 * 8 - serialized_large_literal_len.leading_zeros()) as u8;
@@ -572,7 +778,7 @@ impl<AllocU8:Allocator<u8>,
                     let mut nibble_prob = superstate.bk.lit_len_priors.get(
                         LiteralCommandPriorType::CountSmall, (ctype, index));
                     superstate.coder.get_or_put_nibble(&mut shortcut_nib, nibble_prob, billing);
-                    if superstate.specialization.adapt_cdf() {
+                    if adapt_cdf {
                         nibble_prob.blend(shortcut_nib, Speed::MED);// checked med
                     }
 
@@ -583,11 +789,11 @@ impl<AllocU8:Allocator<u8>,
                         self.state = LiteralSubstate::LiteralCountSmall(true); // right now just 
                     } else {
                         let num_bytes = shortcut_nib as usize + 1;
-                        //eprint!("Literal with {} bytes\n", num_bytes);
+                        divans_trace!(num_bytes = num_bytes; "literal length from mnemonic");
                         superstate.bk.last_llen = num_bytes as u32;
                         match m8 {
                             Some(ref mut m) =>
-                                self.lc.data = m.use_cached_allocation::<UninitializedOnAlloc>().alloc_cell(num_bytes),
+                                self.lc.data = m.use_slab_arena::<UninitializedOnAlloc>().alloc_cell(num_bytes),
                             None => self.lc.data.1 = num_bytes as u32,
                         }
                         self.state = self.get_nibble_code_state(0, in_cmd,
@@ -599,7 +805,7 @@ impl<AllocU8:Allocator<u8>,
                     let ctype = superstate.bk.get_command_block_type();
                     let mut nibble_prob = superstate.bk.lit_len_priors.get(LiteralCommandPriorType::SizeBegNib, (ctype,));
                     superstate.coder.get_or_put_nibble(&mut beg_nib, nibble_prob, billing);
-                    if superstate.specialization.adapt_cdf() {
+                    if adapt_cdf {
                         nibble_prob.blend(beg_nib, Speed::MUD);
                     }
 
@@ -609,7 +815,7 @@ impl<AllocU8:Allocator<u8>,
                         let num_bytes = NUM_LITERAL_LENGTH_MNEMONIC as usize + 1 + beg_nib as usize;
                         match m8 {
                             Some(ref mut m) =>
-                                self.lc.data = m.use_cached_allocation::<UninitializedOnAlloc>().alloc_cell(num_bytes),
+                                self.lc.data = m.use_slab_arena::<UninitializedOnAlloc>().alloc_cell(num_bytes),
                             None => self.lc.data.1 = num_bytes as u32,
                         }
                         self.state = self.get_nibble_code_state(0, in_cmd,
@@ -624,7 +830,7 @@ impl<AllocU8:Allocator<u8>,
                     let ctype = superstate.bk.get_command_block_type();
                     let mut nibble_prob = superstate.bk.lit_len_priors.get(LiteralCommandPriorType::SizeLastNib, (ctype,));
                     superstate.coder.get_or_put_nibble(&mut last_nib, nibble_prob, billing);
-                    if superstate.specialization.adapt_cdf() {
+                    if adapt_cdf {
                         nibble_prob.blend(last_nib, Speed::MUD);
                     }
 
@@ -639,7 +845,7 @@ impl<AllocU8:Allocator<u8>,
                     let ctype = superstate.bk.get_command_block_type();
                     let mut nibble_prob = superstate.bk.lit_len_priors.get(LiteralCommandPriorType::SizeMantissaNib, (ctype,));
                     superstate.coder.get_or_put_nibble(&mut last_nib, nibble_prob, billing);
-                    if superstate.specialization.adapt_cdf() {
+                    if adapt_cdf {
                         nibble_prob.blend(last_nib, Speed::MUD);
                     }
                     let next_decoded_so_far = decoded_so_far | (u32::from(last_nib) << next_len_remaining);
@@ -649,7 +855,7 @@ impl<AllocU8:Allocator<u8>,
                         superstate.bk.last_llen = num_bytes as u32;
                         match m8 {
                             Some(ref mut m) =>
-                                self.lc.data = m.use_cached_allocation::<UninitializedOnAlloc>().alloc_cell(num_bytes),
+                                self.lc.data = m.use_slab_arena::<UninitializedOnAlloc>().alloc_cell(num_bytes),
                             None => self.lc.data.1 = num_bytes as u32,
                         }
                         self.state = self.get_nibble_code_state(0, in_cmd,
@@ -661,39 +867,46 @@ impl<AllocU8:Allocator<u8>,
                 },
                 LiteralSubstate::LiteralNibbleIndexWithECDF(nibble_index) => {
                     superstate.bk.last_llen = self.lc.data.slice().len() as u32;
-                    let byte_index = (nibble_index as usize) >> 1;
-                    let high_nibble = (nibble_index & 1) == 0;
-                    let shift : u8 = if high_nibble { 4 } else { 0 };
-                    let mut cur_nibble = (superstate.specialization.get_literal_byte(in_cmd, byte_index)
-                                          >> shift) & 0xf;
                     assert!(in_cmd.prob.slice().is_empty() || (in_cmd.prob.slice().len() == 8 * in_cmd.data.slice().len()));
-                    {
-                        let prior_nibble;
-                        {
-                            prior_nibble = self.lc.data.slice()[byte_index];
-                        }
-                        cur_nibble = self.ecdf_write_nibble(nibble_index,
+                    let mut nibbles = [0u8; MAX_NIBBLE_BATCH];
+                    let mut num_coded = 0;
+                    if ArithmeticCoder::BATCHES_NIBBLES {
+                        num_coded = self.ecdf_write_nibble_batch::<ArithmeticCoder, Specialization, ISlice, Cdf16>(
+                            nibble_index,
+                            in_cmd,
+                            *unwrap_ref!(lit_coder),
+                            &superstate.specialization,
+                            &mut nibbles,
+                            billing);
+                    }
+                    if num_coded == 0 {
+                        let byte_index = (nibble_index as usize) >> 1;
+                        let shift : u8 = if (nibble_index & 1) == 0 { 4 } else { 0 };
+                        let cur_nibble = (superstate.specialization.get_literal_byte(in_cmd, byte_index)
+                                          >> shift) & 0xf;
+                        nibbles[0] = self.ecdf_write_nibble(nibble_index,
                                                             cur_nibble,
-                                                            prior_nibble >> 4,
                                                             *unwrap_ref!(lit_coder),
                                                             Cdf16::default(),
-                                                            in_cmd.prob.slice());
-                                         
-                        let cur_byte = &mut self.lc.data.slice_mut()[byte_index];
-                        if shift ==0 {
-                            *cur_byte |= cur_nibble << shift;
-                        }else {
-                            *cur_byte = cur_nibble << shift;
-                        }
-                        if !high_nibble {
+                                                            superstate.specialization.get_external_prob(in_cmd, nibble_index));
+                        num_coded = 1;
+                    }
+                    for (offset, cur_nibble) in nibbles[..num_coded].iter().enumerate() {
+                        let cur_index = nibble_index as usize + offset;
+                        let cur_byte = &mut self.lc.data.slice_mut()[cur_index >> 1];
+                        if (cur_index & 1) != 0 {
+                            *cur_byte |= *cur_nibble;
                             unwrap_ref!(lbk).push_literal_byte(*cur_byte);
+                        } else {
+                            *cur_byte = *cur_nibble << 4;
                         }
                     }
-                    if nibble_index + 1 == (self.lc.data.slice().len() << 1) as u32 {
+                    let next_nibble_index = nibble_index + num_coded as u32;
+                    if next_nibble_index == (self.lc.data.slice().len() << 1) as u32 {
                         self.state = LiteralSubstate::FullyDecoded;
                         return DivansResult::Success;
                     } else {
-                        self.state = LiteralSubstate::LiteralNibbleIndexWithECDF(nibble_index + 1);
+                        self.state = LiteralSubstate::LiteralNibbleIndexWithECDF(next_nibble_index);
                     }
                 },
                 LiteralSubstate::LiteralNibbleLowerHalf(_) |
@@ -706,7 +919,7 @@ impl<AllocU8:Allocator<u8>,
                         },
                         Some(mut lc) => {
                             return self.encode_or_decode_content_bytes(
-                                unwrap_ref!(m8).get_base_alloc(),
+                                &mut Some(unwrap_ref!(m8).get_base_alloc()),
                                 lc,
                                 unwrap_ref!(lbk),
                                 unwrap_ref!(lit_high_priors), unwrap_ref!(lit_low_priors),
@@ -740,3 +953,123 @@ impl<AllocU8:Allocator<u8>,
         res
     }
 }
+
+#[cfg(all(test, feature="std", feature="encoder", feature="decoder"))]
+mod test {
+    use std::vec::Vec;
+    use conformance::{conformance_commands, conformance_expected_output};
+    use decoded_command_iter::DecodedCommandIter;
+    use interface::{
+        Command,
+        DivansCompressorOptions,
+        HEADER_EXTENDED_FEATURE_OFFSET,
+        HEADER_EXTENDED_FEATURE2_OFFSET,
+        PRIORS_RESET_GRANULARITY,
+        EXTENDED_FEATURE_LITERAL_NIBBLE_ORDER,
+        EXTENDED_FEATURE_MATCH_BYTE_CONTEXT,
+        EXTENDED_FEATURE2_BYTE_CLASS_CONTEXT,
+        LITERAL_BLOCK_SWITCH_LSB_FIRST,
+    };
+    use test_support::{new_decompressor, encode_with_options, compress_hybrid, decode, decode_with, DecodeConfig, assert_same_command};
+
+    #[test]
+    fn test_literal_nibble_order_roundtrip() {
+        // block types 1 and 0 code their literals low nibble first, block type 2 high nibble first
+        let mut commands = conformance_commands("block_switch_literal");
+        for cmd in commands.iter_mut() {
+            if let Command::BlockSwitchLiteral(ref mut bs) = *cmd {
+                if bs.block_type() != 2 {
+                    let stride = bs.stride();
+                    bs.update_stride(stride | LITERAL_BLOCK_SWITCH_LSB_FIRST);
+                }
+            }
+        }
+        let expected = conformance_expected_output(&commands[..]).unwrap();
+        let mut opts = DivansCompressorOptions::default();
+        opts.extended_stream_features = EXTENDED_FEATURE_LITERAL_NIBBLE_ORDER;
+        let stream = encode_with_options(&commands[..], opts).unwrap();
+        assert_eq!(stream[HEADER_EXTENDED_FEATURE_OFFSET], EXTENDED_FEATURE_LITERAL_NIBBLE_ORDER);
+        for multithread in [false, true].iter() {
+            assert_eq!(decode_with(&stream[..], DecodeConfig::new(*multithread), |_| {}).into_output().unwrap(), expected);
+        }
+        let decompressor = new_decompressor(false, false);
+        let mut remaining = &stream[..];
+        let mut input_buffer = [0u8; 64];
+        let mut iter = DecodedCommandIter::new(decompressor, &mut input_buffer[..], |buf: &mut [u8]| {
+            let len = core::cmp::min(buf.len(), remaining.len());
+            buf[..len].clone_from_slice(&remaining[..len]);
+            remaining = &remaining[len..];
+            len
+        });
+        let mut count = 0usize;
+        while let Some(cmd) = iter.next_borrowed() {
+            assert_same_command(&commands[count], &cmd.unwrap());
+            count += 1;
+        }
+        assert_eq!(count, commands.len());
+        iter.into_inner().free();
+        // without the feature the order is dropped and every literal is coded high nibble first
+        opts.extended_stream_features = 0;
+        let plain = encode_with_options(&commands[..], opts).unwrap();
+        assert_eq!(decode(&plain[..]).unwrap(), expected);
+        assert!(plain[..] != stream[..]);
+        // text, then bytes whose low nibbles count up under noisy high nibbles
+        let mut input = Vec::<u8>::new();
+        input.extend(&include_bytes!("../../testdata/alice29")[..65536]);
+        let mut seed = 0x2545f491u32;
+        for i in 0..65536usize {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            input.push(((seed >> 24) as u8 & 0xf0) | (i & 0xf) as u8);
+        }
+        opts.extended_stream_features = EXTENDED_FEATURE_LITERAL_NIBBLE_ORDER;
+        let ordered = compress_hybrid(&input[..], opts);
+        for multithread in [false, true].iter() {
+            assert_eq!(decode_with(&ordered[..], DecodeConfig::new(*multithread), |_| {}).into_output().unwrap(), input);
+        }
+        opts.extended_stream_features = 0;
+        let adaptive = compress_hybrid(&input[..], opts);
+        assert!(ordered.len() < adaptive.len() * 19 / 20, "{} vs {}", ordered.len(), adaptive.len());
+    }
+
+    #[test]
+    fn test_match_byte_context() {
+        let input = include_bytes!("../../testdata/asyoulik");
+        let mut opts = DivansCompressorOptions::default();
+        let plain = compress_hybrid(&input[..], opts);
+        opts.extended_stream_features = EXTENDED_FEATURE_MATCH_BYTE_CONTEXT;
+        let matched = compress_hybrid(&input[..], opts);
+        assert_eq!(matched[HEADER_EXTENDED_FEATURE_OFFSET], EXTENDED_FEATURE_MATCH_BYTE_CONTEXT);
+        assert!(matched.len() < plain.len(), "{} >= {}", matched.len(), plain.len());
+        opts.extended_stream_features |= EXTENDED_FEATURE_LITERAL_NIBBLE_ORDER;
+        opts.freeze_adaptation_after = Some(65536);
+        let reordered = compress_hybrid(&input[..], opts);
+        for stream in [&matched[..], &reordered[..]].iter() {
+            for multithread in [false, true].iter() {
+                assert_eq!(decode_with(stream, DecodeConfig::new(*multithread), |_| {}).into_output().unwrap(), &input[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_byte_class_context() {
+        let input = include_bytes!("../../testdata/alice29");
+        let mut opts = DivansCompressorOptions::default();
+        let plain = compress_hybrid(&input[..], opts);
+        opts.extended_stream_features2 = EXTENDED_FEATURE2_BYTE_CLASS_CONTEXT;
+        let classed = compress_hybrid(&input[..], opts);
+        assert_eq!(classed[HEADER_EXTENDED_FEATURE2_OFFSET], EXTENDED_FEATURE2_BYTE_CLASS_CONTEXT);
+        assert!(classed.len() < plain.len(), "{} >= {}", classed.len(), plain.len());
+        // with the context mixing priors, and with the models reset and frozen along the way
+        opts.dynamic_context_mixing = Some(2);
+        let mixed = compress_hybrid(&input[..], opts);
+        opts.extended_stream_features = EXTENDED_FEATURE_MATCH_BYTE_CONTEXT | EXTENDED_FEATURE_LITERAL_NIBBLE_ORDER;
+        opts.priors_reset_interval = Some(PRIORS_RESET_GRANULARITY);
+        opts.freeze_adaptation_after = Some(98304);
+        let reset = compress_hybrid(&input[..], opts);
+        for stream in [&classed[..], &mixed[..], &reset[..]].iter() {
+            for multithread in [false, true].iter() {
+                assert_eq!(decode_with(stream, DecodeConfig::new(*multithread), |_| {}).into_output().unwrap(), &input[..]);
+            }
+        }
+    }
+}
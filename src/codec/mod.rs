@@ -16,10 +16,12 @@
 use core;
 use core::hash::Hasher;
 use alloc::{SliceWrapper, Allocator};
-use interface::{DivansResult, DivansOutputResult, DivansOpResult, ErrMsg, StreamMuxer, StreamDemuxer, StreamID, ReadableBytes};
+use interface::{DivansResult, DivansOutputResult, DivansOpResult, ErrMsg, StreamMuxer, StreamDemuxer, StreamID, ReadableBytes, STREAM_FEATURE_COMMAND_TYPE_TREE, EXTENDED_FEATURE_STATIC_LITERAL_MODEL, EXTENDED_FEATURE_IR_ONLY, EXTENDED_FEATURE_MATCH_BYTE_CONTEXT, EXTENDED_FEATURE_BLOCK_TYPE_MIXER, EXTENDED_FEATURE2_DICT_COPY_FUSION, EXTENDED_FEATURE2_BYTE_CLASS_CONTEXT, PRIORS_RESET_GRANULARITY, DistanceBucketing, MixerWarmStart, StallWatchdog, ChecksumPolicy, ChecksumType};
 use ::alloc_util::UninitializedOnAlloc;
 use mux::Mux;
 pub const CMD_BUFFER_SIZE: usize = 16;
+// the longest copy the encoder fuses onto the dictionary word before it (EXTENDED_FEATURE2_DICT_COPY_FUSION)
+const MAX_FUSED_COPY_LEN: u32 = 16;
 use ::alloc_util::RepurposingAlloc;
 use super::interface::{
     BillingDesignation,
@@ -47,16 +49,29 @@ pub use self::interface::{
     EncoderOrDecoderSpecialization,
     CrossCommandState,
     CrossCommandBookKeeping,
+    AdaptationFreeze,
+    PriorsReset,
     NUM_ARITHMETIC_CODERS,
     CMD_CODER,
+    LIT_CODER,
     CommandArray,
-    EmptyCommandArray,
+    COMMAND_TYPE_TREE_CODES,
+    COMMAND_TYPE_TREE_DEPTH,
+};
+#[cfg(feature="decoder")]
+pub use self::interface::EmptyCommandArray;
+#[cfg(feature="encoder")]
+pub use self::interface::{
     CommandSliceArray,
     MainThreadContext,
     get_distance_from_mnemonic_code,
+    get_lut0,
+    get_lut1,
 };
+use self::interface::duplicate_cmd;
 use super::interface::{
     ArithmeticEncoderOrDecoder,
+    DuplicateWithAllocator,
     Command,
     CopyCommand,
     DictCommand,
@@ -68,42 +83,23 @@ pub mod copy;
 pub mod dict;
 pub mod literal;
 pub mod context_map;
+pub mod static_literal;
 pub mod block_type;
 pub mod priors;
 pub mod decoder;
+pub mod trace;
+#[cfg(feature="std")]
+pub mod span;
 pub use self::decoder::{
     DivansDecoderCodec,
     SubDigest,
-    default_crc,
+    StreamDigest,
     empty_prediction_mode_context_map,
 };
+#[cfg(feature="encoder")]
+pub use self::decoder::default_crc;
 
-
-/*
-use std::io::Write;
-macro_rules! println_stderr(
-    ($($val:tt)*) => { {
-        writeln!(&mut ::std::io::stderr(), $($val)*).unwrap();
-    } }
-);
-*/
-use super::probability::{CDF16, Speed};
-
-//#[cfg(feature="billing")]
-//use std::io::Write;
-//#[cfg(feature="billing")]
-//macro_rules! println_stderr(
-//    ($($val:tt)*) => { {
-//        writeln!(&mut ::std::io::stderr(), $($val)*).unwrap();
-//    } }
-//);
-//
-//#[cfg(not(feature="billing"))]
-//macro_rules! println_stderr(
-//    ($($val:tt)*) => { {
-////        writeln!(&mut ::std::io::stderr(), $($val)*).unwrap();
-//    } }
-//);
+use super::probability::{BitModel, CDF16, Speed};
 
 
 
@@ -129,7 +125,11 @@ enum EncodeOrDecodeState {
     WriteChecksum(u8),
 }
 
-const CHECKSUM_LENGTH: usize = 8;
+use ::wire;
+use ::mac::{self, HmacSha256};
+use ::cancel::CancelToken;
+#[cfg(feature="timings")]
+use ::timings::{Clock, EncodeTimings, StageClock};
 
 
 impl Default for EncodeOrDecodeState {
@@ -138,6 +138,29 @@ impl Default for EncodeOrDecodeState {
     }
 }
 
+impl EncodeOrDecodeState {
+    // the tag and argument identifying this state in a codec trace
+    pub fn trace_code(&self) -> (u8, u64) {
+        match *self {
+            EncodeOrDecodeState::Begin => (0, 0),
+            EncodeOrDecodeState::Literal => (1, 0),
+            EncodeOrDecodeState::Dict => (2, 0),
+            EncodeOrDecodeState::Copy => (3, 0),
+            EncodeOrDecodeState::BlockSwitchLiteral => (4, 0),
+            EncodeOrDecodeState::BlockSwitchCommand => (5, 0),
+            EncodeOrDecodeState::BlockSwitchDistance => (6, 0),
+            EncodeOrDecodeState::PredictionMode => (7, 0),
+            EncodeOrDecodeState::PopulateRingBuffer => (8, 0),
+            EncodeOrDecodeState::DivansSuccess => (9, 0),
+            EncodeOrDecodeState::EncodedShutdownNode => (10, 0),
+            EncodeOrDecodeState::ShutdownCoder(index) => (11, u64::from(index)),
+            EncodeOrDecodeState::CoderBufferDrain => (12, 0),
+            EncodeOrDecodeState::MuxDrain => (13, 0),
+            EncodeOrDecodeState::WriteChecksum(count) => (14, u64::from(count)),
+        }
+    }
+}
+
 
 
 pub fn command_type_to_nibble<SliceType:SliceWrapper<u8>>(cmd:&Command<SliceType>,
@@ -163,7 +186,8 @@ pub struct DivansCodec<ArithmeticCoder:ArithmeticEncoderOrDecoder,
                        LinearOutputBytes:StreamMuxer<AllocU8>+Default,
                        Cdf16:CDF16,
                        AllocU8: Allocator<u8>,
-                       AllocCDF16:Allocator<Cdf16>> {
+                       AllocCDF16:Allocator<Cdf16>,
+                       D:Hasher+Default=SubDigest> {
     pub cross_command_state: CrossCommandState<ArithmeticCoder, // needs to be public so we can use items on that struct to free items
                                            Specialization,
                                            LinearInputBytes,
@@ -172,6 +196,10 @@ pub struct DivansCodec<ArithmeticCoder:ArithmeticEncoderOrDecoder,
                                            AllocU8,
                                            AllocCDF16>,
     state: EncodeOrDecodeState,
+    command_type_node: u8, // tree bits already coded for the current command type
+    after_dict: bool, // the fusion bit is due before the next command type
+    dict_literal: Option<DictCommand>, // encoder only: a word coded as a literal after EXTENDED_FEATURE2_AUTO_NO_DICT turned words off
+    speculative_command_type: Option<u8>, // next command type, decoded while the previous command waited to be delivered
     state_lit: literal::LiteralState<AllocU8>,
     state_copy: copy::CopyState,
     state_dict: dict::DictState,
@@ -180,11 +208,26 @@ pub struct DivansCodec<ArithmeticCoder:ArithmeticEncoderOrDecoder,
     state_prediction_mode: context_map::PredictionModeState<AllocU8>,
     state_populate_ring_buffer: Command<AllocatedMemoryPrefix<u8, AllocU8>>,
     codec_traits: CodecTraitSelector,
-    crc: SubDigest,
+    crc: StreamDigest<D>,
     frozen_checksum: Option<u64>,
-    skip_checksum: bool,
+    checksum_policy: ChecksumPolicy,
+    deserialized_trailer: [u8; wire::MAX_TRAILER_LENGTH], // decoder only: the trailer read so far
+    stall_watchdog: StallWatchdog,
+    // (command, distance) block switches decoded; they never leave the worker, so DecodeStats reads them here
+    block_switch_counts: (u64, u64),
+    // decoder only: also pass command and distance block switches on to the worker, so a tapped
+    // literal decoder sees every command of the stream
+    forward_block_switches: bool,
+    cancel: CancelToken, // looked at before each command
+    #[cfg(feature="trace")]
+    trace: trace::TraceLog,
+    #[cfg(feature="timings")]
+    clock: StageClock,
+    #[cfg(feature="timings")]
+    timings: EncodeTimings, // only entropy_code_ns and recode_ns
 }
 
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
 pub enum OneCommandReturn {
     Advance,
     BufferExhausted(DivansResult),
@@ -202,7 +245,8 @@ impl<AllocU8: Allocator<u8>,
      LinearInputBytes:StreamDemuxer<AllocU8>+ThreadToMain<AllocU8>,
      LinearOutputBytes:StreamMuxer<AllocU8>+Default,
      Cdf16:CDF16,
-     AllocCDF16:Allocator<Cdf16>> DivansCodec<ArithmeticCoder, Specialization, LinearInputBytes, LinearOutputBytes, Cdf16, AllocU8, AllocCDF16> {
+     AllocCDF16:Allocator<Cdf16>,
+     D:Hasher+Default> DivansCodec<ArithmeticCoder, Specialization, LinearInputBytes, LinearOutputBytes, Cdf16, AllocU8, AllocCDF16, D> {
     pub fn new(m8:AllocU8,
                mcdf16:AllocCDF16,
                cmd_coder: ArithmeticCoder,
@@ -216,6 +260,25 @@ impl<AllocU8: Allocator<u8>,
                do_context_map: bool,
                force_stride: interface::StrideSelection,
                skip_checksum: bool) -> Self {
+        Self::new_with_window(m8, mcdf16, cmd_coder, lit_coder, specialization, linear_input_bytes, ring_buffer_size,
+                              dynamic_context_mixing, prior_depth, literal_adaptation_rate, do_context_map, force_stride,
+                              skip_checksum, None)
+    }
+    // as new, but decoding into window rather than a ring buffer from m8 (see DivansDecompressor::set_window)
+    pub fn new_with_window(m8:AllocU8,
+               mcdf16:AllocCDF16,
+               cmd_coder: ArithmeticCoder,
+               lit_coder: ArithmeticCoder,
+               specialization: Specialization,
+               linear_input_bytes: LinearInputBytes,
+               ring_buffer_size: usize,
+               dynamic_context_mixing: u8,
+               prior_depth: Option<u8>,
+               literal_adaptation_rate: Option<[Speed;4]>,
+               do_context_map: bool,
+               force_stride: interface::StrideSelection,
+               skip_checksum: bool,
+               window: Option<AllocU8::AllocatedMemory>) -> Self {
         let mut cross_command_state = CrossCommandState::<ArithmeticCoder,
                                                     Specialization,
                                                     LinearInputBytes,
@@ -234,13 +297,18 @@ impl<AllocU8: Allocator<u8>,
                                                                      literal_adaptation_rate,
                                                                      do_context_map,
                                                                      force_stride,
+                                                                     window,
         );
 
         let pm = context_map::PredictionModeState::begin(cross_command_state.thread_ctx.m8().unwrap());
 
-        let mut ret = DivansCodec::<ArithmeticCoder,  Specialization, LinearInputBytes, LinearOutputBytes, Cdf16, AllocU8, AllocCDF16> {
+        let mut ret = DivansCodec::<ArithmeticCoder,  Specialization, LinearInputBytes, LinearOutputBytes, Cdf16, AllocU8, AllocCDF16, D> {
             cross_command_state:cross_command_state,
             state:EncodeOrDecodeState::Begin,
+            command_type_node: 0,
+            after_dict: false,
+            dict_literal: None,
+            speculative_command_type: None,
             codec_traits: CodecTraitSelector::DefaultTrait(&specializations::DEFAULT_TRAIT),
             state_copy: copy::CopyState::begin(),
             state_dict: dict::DictState::begin(),
@@ -252,9 +320,20 @@ impl<AllocU8: Allocator<u8>,
             state_block_switch: block_type::BlockTypeState::begin(),
             state_prediction_mode: pm,
             state_populate_ring_buffer: Command::<AllocatedMemoryPrefix<u8, AllocU8>>::nop(),
-            crc: default_crc(),
+            crc: StreamDigest::<D>::default(),
             frozen_checksum: None,
-            skip_checksum:skip_checksum,
+            checksum_policy: ChecksumPolicy::from_skip_crc(skip_checksum),
+            deserialized_trailer: [0u8; wire::MAX_TRAILER_LENGTH],
+            stall_watchdog: StallWatchdog::default(),
+            block_switch_counts: (0, 0),
+            forward_block_switches: false,
+            cancel: CancelToken::default(),
+            #[cfg(feature="trace")]
+            trace: trace::TraceLog::default(),
+            #[cfg(feature="timings")]
+            clock: StageClock::default(),
+            #[cfg(feature="timings")]
+            timings: EncodeTimings::default(),
         };
         match ret.cross_command_state.thread_ctx.lbk() {
             Some(ref book_keeping) => ret.codec_traits = construct_codec_trait_from_bookkeeping(book_keeping),
@@ -269,21 +348,24 @@ impl<AllocU8: Allocator<u8>,
                                                 AllocCDF16,
                                                 AllocCommand,
                                                 ArithmeticCoder,
-                                                Mux<AllocU8>>,
+                                                Mux<AllocU8>,
+                                                D>,
                 mcommand: &mut AllocCommand) {
 
-        free_cmd(&mut decoder.state_populate_ring_buffer, &mut decoder.ctx.m8.use_cached_allocation::<UninitializedOnAlloc>());
-        self.crc = decoder.crc;
+        free_cmd(&mut decoder.state_populate_ring_buffer, &mut decoder.ctx.m8.use_slab_arena::<UninitializedOnAlloc>());
+        decoder.settle_crc();
+        self.crc = core::mem::replace(&mut decoder.crc, StreamDigest::<D>::default());
+        #[cfg(feature="trace")]
+        self.trace.merge(&mut decoder.trace);
         decoder.free(mcommand);
-        //ctx.m8.use_cached_allocation::<UninitializedOnAlloc>().free_cell(core::mem::replace(&mut self.state_lit.lc.data, AllocatedMemoryPrefix::<u8, AllocU8>::default()));
-        self.skip_checksum = decoder.skip_checksum;
+        self.checksum_policy = decoder.checksum_policy;
         self.frozen_checksum = decoder.frozen_checksum;
         //decoder.demuxer.free(&mut decoder.ctx.m8.get_base_alloc());
         mcommand.free_cell(decoder.cmd_buffer.0);
         let p0 = core::mem::replace(&mut decoder.pred_buffer[0], empty_prediction_mode_context_map());
         let p1 = core::mem::replace(&mut decoder.pred_buffer[1], empty_prediction_mode_context_map());
-        free_cmd(&mut Command::PredictionMode(p0), &mut decoder.ctx.m8.use_cached_allocation::<UninitializedOnAlloc>());
-        free_cmd(&mut Command::PredictionMode(p1), &mut decoder.ctx.m8.use_cached_allocation::<UninitializedOnAlloc>());
+        free_cmd(&mut Command::PredictionMode(p0), &mut decoder.ctx.m8.use_slab_arena::<UninitializedOnAlloc>());
+        free_cmd(&mut Command::PredictionMode(p1), &mut decoder.ctx.m8.use_slab_arena::<UninitializedOnAlloc>());
         let old_thread_context = core::mem::replace(&mut self.cross_command_state.thread_ctx, ThreadContext::MainThread(decoder.ctx));
         match old_thread_context {
             ThreadContext::MainThread(_) => panic!("Tried to join the main thread"),
@@ -295,23 +377,294 @@ impl<AllocU8: Allocator<u8>,
                                                                                                                     AllocCDF16,
                                                                                                                     AllocCommand,
                                                                                                                     ArithmeticCoder,
-                                                                                                                    Mux<AllocU8>> {
-        let skip_checksum = self.skip_checksum;
+                                                                                                                    Mux<AllocU8>,
+                                                                                                                    D> {
+        let checksum_policy = self.checksum_policy;
         if let Some(_) = self.frozen_checksum {
             panic!("Tried to fork() when checksum was already computed");
         }
-        self.skip_checksum = true;
+        self.checksum_policy = ChecksumPolicy::SkipButParse;
         let old_thread_context = core::mem::replace(&mut self.cross_command_state.thread_ctx, ThreadContext::Worker);
         let main_thread_context = match old_thread_context {
             ThreadContext::MainThread(mt) => mt,
             ThreadContext::Worker => panic!("Tried to fork from a Worker"),
         };
-        DivansDecoderCodec::<Cdf16,
+        let mut decoder = DivansDecoderCodec::<Cdf16,
                              AllocU8,
                              AllocCDF16,
                              AllocCommand,
                              ArithmeticCoder,
-                             Mux<AllocU8>>::new(main_thread_context, mcommand, self.crc.clone(), skip_checksum)
+                             Mux<AllocU8>,
+                             D>::new(main_thread_context,
+                                     mcommand,
+                                     StreamDigest::<D> {
+                                         crc: self.crc.crc,
+                                         wide: self.crc.wide,
+                                         digest: self.crc.take_digest(),
+                                         mac: self.crc.mac,
+                                         framing: self.crc.framing,
+                                     },
+                                     checksum_policy);
+        decoder.cancel = self.cancel.clone();
+        #[cfg(feature="trace")]
+        {
+            decoder.trace.input_pos = self.trace.input_pos;
+        }
+        decoder
+    }
+    // An independent copy of the codec, mid stream, so an encoder can trial-encode candidate commands
+    // and keep the cheapest. m8 and mcdf16 supply every copied buffer and are returned by the copy's free.
+    // Unlike fork, which splits off the main thread half, this copies both halves and leaves self untouched.
+    pub fn duplicate(&self, m8: AllocU8, mcdf16: AllocCDF16) -> Self
+        where ArithmeticCoder: DuplicateWithAllocator<AllocU8>,
+              Specialization: Clone,
+              LinearInputBytes: DuplicateWithAllocator<AllocU8>,
+              LinearOutputBytes: DuplicateWithAllocator<AllocU8>,
+              D: Clone {
+        let mut cross_command_state = self.cross_command_state.duplicate(m8, mcdf16);
+        let (state_lit, state_prediction_mode, state_populate_ring_buffer) = {
+            let m8 = cross_command_state.thread_ctx.m8().unwrap().get_base_alloc();
+            (self.state_lit.duplicate(m8),
+             self.state_prediction_mode.duplicate(m8),
+             duplicate_cmd(&self.state_populate_ring_buffer, m8))
+        };
+        DivansCodec::<ArithmeticCoder,  Specialization, LinearInputBytes, LinearOutputBytes, Cdf16, AllocU8, AllocCDF16, D> {
+            cross_command_state: cross_command_state,
+            state: self.state,
+            command_type_node: self.command_type_node,
+            after_dict: self.after_dict,
+            dict_literal: self.dict_literal,
+            speculative_command_type: self.speculative_command_type,
+            codec_traits: self.codec_traits,
+            state_copy: self.state_copy,
+            state_dict: self.state_dict,
+            state_lit: state_lit,
+            state_lit_block_switch: self.state_lit_block_switch,
+            state_block_switch: self.state_block_switch,
+            state_prediction_mode: state_prediction_mode,
+            state_populate_ring_buffer: state_populate_ring_buffer,
+            crc: self.crc.clone(),
+            frozen_checksum: self.frozen_checksum,
+            checksum_policy: self.checksum_policy,
+            deserialized_trailer: self.deserialized_trailer,
+            stall_watchdog: self.stall_watchdog,
+            block_switch_counts: self.block_switch_counts,
+            forward_block_switches: self.forward_block_switches,
+            cancel: self.cancel.clone(),
+            #[cfg(feature="trace")]
+            trace: self.trace.clone(),
+            #[cfg(feature="timings")]
+            clock: self.clock,
+            #[cfg(feature="timings")]
+            timings: self.timings,
+        }
+    }
+    // declares which commands the stream omits (STREAM_FEATURE_* bits); call before the first command
+    pub fn set_stream_features(&mut self, stream_features: u8) {
+        self.cross_command_state.set_stream_features(stream_features);
+    }
+    pub fn stream_features(&self) -> u8 {
+        self.cross_command_state.bk.stream_features
+    }
+    // EXTENDED_FEATURE_* bits, from header byte 7 of MAGIC_NUMBER_EXTENDED streams; call before the first command
+    pub fn set_extended_stream_features(&mut self, extended_stream_features: u8) {
+        self.cross_command_state.bk.extended_stream_features = extended_stream_features;
+        if extended_stream_features & EXTENDED_FEATURE_STATIC_LITERAL_MODEL != 0 {
+            if let Some(ctx) = self.cross_command_state.thread_ctx.main_thread_mut() {
+                ctx.lbk.static_model.enable(&mut ctx.mcdf16);
+            }
+        }
+        if extended_stream_features & EXTENDED_FEATURE_MATCH_BYTE_CONTEXT != 0 {
+            if let Some(ctx) = self.cross_command_state.thread_ctx.main_thread_mut() {
+                ctx.lbk.enable_match_byte_context(&mut ctx.mcdf16);
+            }
+        }
+        if extended_stream_features & EXTENDED_FEATURE_BLOCK_TYPE_MIXER != 0 {
+            if let Some(ctx) = self.cross_command_state.thread_ctx.main_thread_mut() {
+                ctx.lbk.block_type_weights.enable();
+            }
+        }
+    }
+    // encoder only: the weight exponents of the static literal tables (see static_literal.rs) to send
+    // after the next prediction mode command, for the caller to fill in
+    pub fn static_literal_weights_mut(&mut self) -> Option<&mut [u8]> {
+        match self.cross_command_state.thread_ctx.main_thread_mut() {
+            Some(ctx) => Some(ctx.lbk.static_model.weights_mut(ctx.m8.get_base_alloc())),
+            None => None,
+        }
+    }
+    pub fn extended_stream_features(&self) -> u8 {
+        self.cross_command_state.bk.extended_stream_features
+    }
+    // EXTENDED_FEATURE2_* bits, from header byte 14 of MAGIC_NUMBER_EXTENDED streams; call before the first command
+    pub fn set_extended_stream_features2(&mut self, extended_stream_features2: u8) {
+        self.cross_command_state.bk.extended_stream_features2 = extended_stream_features2;
+        if extended_stream_features2 & EXTENDED_FEATURE2_BYTE_CLASS_CONTEXT != 0 {
+            if let Some(ctx) = self.cross_command_state.thread_ctx.main_thread_mut() {
+                ctx.lbk.enable_byte_class_context(ctx.m8.get_base_alloc(), &mut ctx.mcdf16);
+            }
+        }
+    }
+    pub fn extended_stream_features2(&self) -> u8 {
+        self.cross_command_state.bk.extended_stream_features2
+    }
+    // decoder only: call before fork; the skip_checksum given to new picks Verify or SkipButParse
+    pub fn set_checksum_policy(&mut self, policy: ChecksumPolicy) {
+        self.checksum_policy = policy;
+    }
+    // fails the next command with ErrMsg::Cancelled, leaving it to be coded by a later call,
+    // once token is cancelled; see cancel.rs
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        self.cancel = token;
+    }
+    // times encode_or_decode and flush from now on; see timings.rs
+    #[cfg(feature="timings")]
+    pub fn set_clock(&mut self, clock: &'static dyn Clock) {
+        self.clock = StageClock::new(clock);
+    }
+    // the time spent entropy coding and recoding; the codec does not parse or model
+    #[cfg(feature="timings")]
+    pub fn timings(&self) -> EncodeTimings {
+        self.timings
+    }
+    #[cfg(feature="timings")]
+    #[inline(always)]
+    fn start_timing(&self) -> (u64, u64) {
+        (self.clock.now(), self.timings.recode_ns)
+    }
+    // charges the time since start to entropy coding, less what went to recoding meanwhile
+    #[cfg(feature="timings")]
+    #[inline(always)]
+    fn stop_timing(&mut self, start: (u64, u64)) {
+        let recode_ns = self.timings.recode_ns - start.1;
+        self.timings.entropy_code_ns += self.clock.since(start.0).saturating_sub(recode_ns);
+    }
+    // see MainThreadContext::obs_prediction_mode_context_map; on by default.  Turning it off saves
+    // the reallocations at prediction mode commands that reach new contexts.  Call before fork.
+    pub fn set_compact_priors(&mut self, compact: bool) {
+        if let Some(lbk) = self.cross_command_state.thread_ctx.lbk() {
+            lbk.compact_priors = compact;
+        }
+    }
+    pub fn forward_block_switches(&mut self) {
+        self.forward_block_switches = true;
+    }
+    // encoder only: pad the frame, from the header written ahead of the codec
+    // output through the checksum trailer, to a multiple of alignment bytes.
+    // The stream must declare STREAM_FEATURE_MUX_PADDING so that older decoders refuse it.
+    pub fn set_frame_alignment(&mut self, alignment: usize) {
+        let overhead = wire::header_length(self.crc.framing.as_ref()) + self.crc.checksum_length();
+        self.cross_command_state.muxer.set_frame_alignment(alignment, overhead);
+    }
+    // writes (encoder) or expects (decoder) framing in place of the magic number and CHECKSUM_TAG;
+    // call before set_frame_alignment and before the header is written
+    pub fn set_framing_tag(&mut self, framing: Option<wire::FramingTag>) {
+        self.crc.framing = framing;
+    }
+    pub fn framing_tag(&self) -> Option<wire::FramingTag> {
+        self.crc.framing
+    }
+    // the checksum the trailer holds, from header byte 15 of MAGIC_NUMBER_EXTENDED streams;
+    // call before set_frame_alignment and before the header is fed to get_crc()
+    pub fn set_checksum_type(&mut self, checksum: ChecksumType) {
+        self.crc.set_checksum_type(checksum);
+    }
+    pub fn checksum_type(&self) -> ChecksumType {
+        self.crc.checksum_type()
+    }
+    // after flush returns NeedsMoreOutput, the room the next call needs to get past where this one
+    // stopped: the rest of the trailer once it is being written, before that 1
+    pub fn output_needed(&self) -> usize {
+        match self.state {
+            EncodeOrDecodeState::WriteChecksum(count) => self.crc.trailer_length() - usize::from(count),
+            EncodeOrDecodeState::DivansSuccess => 0,
+            _ => 1,
+        }
+    }
+    // whether flush has begun ending the stream, after which it takes no more commands
+    pub fn is_finishing(&self) -> bool {
+        match self.state {
+            EncodeOrDecodeState::EncodedShutdownNode
+            | EncodeOrDecodeState::ShutdownCoder(_)
+            | EncodeOrDecodeState::CoderBufferDrain
+            | EncodeOrDecodeState::MuxDrain
+            | EncodeOrDecodeState::WriteChecksum(_)
+            | EncodeOrDecodeState::DivansSuccess => true,
+            _ => false,
+        }
+    }
+    // the next command type, if it was decoded while the current command waited for output space
+    pub fn speculative_command_type(&self) -> Option<u8> {
+        self.speculative_command_type
+    }
+    // bytes of the header written ahead of the codec output
+    pub fn header_length(&self) -> usize {
+        wire::header_length(self.crc.framing.as_ref())
+    }
+    // appends (encoder) or verifies (decoder) an HMAC-SHA256 tag after the checksum;
+    // call before the header is fed to get_crc() since the tag covers it too
+    pub fn set_authentication_key(&mut self, key: &[u8]) {
+        self.crc.mac = Some(HmacSha256::new(key));
+    }
+    // carried by each prediction mode command when STREAM_FEATURE_DISTANCE_BUCKETING is set
+    pub fn set_distance_bucketing(&mut self, bucketing: DistanceBucketing) {
+        self.cross_command_state.bk.desired_distance_bucketing = bucketing;
+    }
+    // the output length after which no model adapts (see EXTENDED_FEATURE_FREEZE_ADAPTATION), from
+    // header bytes 8 to 11 of a stream with that feature; call before the first command and before fork
+    pub fn set_freeze_adaptation_after(&mut self, after: Option<u32>) {
+        self.cross_command_state.bk.adaptation = AdaptationFreeze::new(after);
+        if let Some(lbk) = self.cross_command_state.thread_ctx.lbk() {
+            lbk.adaptation = AdaptationFreeze::new(after);
+        }
+    }
+    pub fn freeze_adaptation_after(&self) -> Option<u32> {
+        self.cross_command_state.bk.adaptation.after()
+    }
+    // the output length between resets of every model to its initial state, from header bytes 12 and
+    // 13 of an extended stream; an interval that is not a multiple of PRIORS_RESET_GRANULARITY is
+    // rounded down.  Call before the first command and before fork
+    pub fn set_priors_reset_interval(&mut self, interval: Option<u32>) {
+        let interval = interval.map(|bytes| bytes - bytes % PRIORS_RESET_GRANULARITY).filter(|bytes| *bytes != 0);
+        self.cross_command_state.bk.priors_reset = PriorsReset::new(interval);
+        if let Some(lbk) = self.cross_command_state.thread_ctx.lbk() {
+            lbk.priors_reset = PriorsReset::new(interval);
+        }
+    }
+    pub fn priors_reset_interval(&self) -> Option<u32> {
+        self.cross_command_state.bk.priors_reset.interval()
+    }
+    // whether the command models still hold valid CDFs; the literal models are the main thread's
+    pub fn priors_valid(&self) -> bool {
+        self.cross_command_state.bk.priors_valid()
+    }
+    // counts a command just coded towards freezing the models, and resets them if it is due
+    fn obs_command_bytes(&mut self, len: u64) {
+        self.cross_command_state.bk.adaptation.obs_command_bytes(len);
+        if self.cross_command_state.bk.priors_reset.obs_command_bytes(len) {
+            self.cross_command_state.bk.reset_priors();
+        }
+        if let Some(ctx) = self.cross_command_state.thread_ctx.main_thread_mut() {
+            ctx.lbk.adaptation.obs_command_bytes(len);
+            if ctx.lbk.priors_reset.obs_command_bytes(len) {
+                ctx.reset_literal_priors();
+            }
+        }
+    }
+    // every prediction mode command this codec encodes carries warm_start
+    pub fn set_mixer_warm_start(&mut self, warm_start: Option<MixerWarmStart>) {
+        self.cross_command_state.bk.desired_mixer_warm_start = warm_start;
+    }
+    // encoder only: the literal modeling written by the next prediction mode command, so it may
+    // change between metablocks without affecting the decodability of the stream
+    pub fn set_literal_modeling(&mut self, dynamic_context_mixing: u8, use_context_map: bool) {
+        assert_eq!(dynamic_context_mixing >> 3, 0);
+        self.cross_command_state.bk.desired_context_mixing = dynamic_context_mixing;
+        self.cross_command_state.bk.desired_do_context_map = use_context_map;
+    }
+    // the bucketing in effect for the copies that follow the last prediction mode command
+    pub fn distance_bucketing(&self) -> DistanceBucketing {
+        self.cross_command_state.bk.distance_bucketing
     }
     pub fn demuxer(&mut self) -> &mut LinearInputBytes{
         &mut self.cross_command_state.demuxer
@@ -322,7 +675,7 @@ impl<AllocU8: Allocator<u8>,
     }
     pub fn free_ref(&mut self) {
         self.state_prediction_mode.reset(self.cross_command_state.thread_ctx.m8().unwrap());
-        self.cross_command_state.thread_ctx.m8().unwrap().use_cached_allocation::<UninitializedOnAlloc>().free_cell(
+        self.cross_command_state.thread_ctx.m8().unwrap().use_slab_arena::<UninitializedOnAlloc>().free_cell(
             core::mem::replace(&mut self.state_lit.lc,
                                LiteralCommand::<AllocatedMemoryPrefix<u8, AllocU8>>::nop()).data);
 
@@ -331,6 +684,9 @@ impl<AllocU8: Allocator<u8>,
     }
     #[inline(always)]
     fn update_command_state_from_nibble(&mut self, command_type_code:u8, is_end: bool) -> DivansResult{
+        if !self.cross_command_state.bk.command_type_enabled(command_type_code) {
+            return DivansResult::Failure(ErrMsg::CommandDisabledByStreamFeatures(command_type_code));
+        }
         match command_type_code {
             1 => {
                 self.state_copy = copy::CopyState::begin();
@@ -403,15 +759,49 @@ impl<AllocU8: Allocator<u8>,
         &mut self.cross_command_state.specialization
     }
     #[inline(always)]
-    pub fn get_crc(&mut self) -> &mut SubDigest {
+    pub fn get_crc(&mut self) -> &mut StreamDigest<D> {
         &mut self.crc
     }
+    // hands back the secondary digest of the compressed stream, leaving a fresh one in its place
+    pub fn take_digest(&mut self) -> D {
+        self.crc.take_digest()
+    }
+    pub fn block_switch_counts(&self) -> (u64, u64) {
+        self.block_switch_counts
+    }
+    #[cfg(feature="trace")]
+    pub fn trace(&mut self) -> &mut trace::TraceLog {
+        &mut self.trace
+    }
+    #[cfg(feature="trace")]
+    #[inline(always)]
+    fn trace_transition(&mut self, input_offset: usize, output_offset: usize) {
+        let (tag, arg) = self.state.trace_code();
+        self.trace.observe(tag, arg, input_offset, output_offset);
+        if let EncodeOrDecodeState::Literal = self.state {
+            let (tag, arg) = self.state_lit.state.trace_code();
+            self.trace.observe(tag, arg, input_offset, output_offset);
+        }
+    }
+    #[cfg(not(feature="trace"))]
+    #[inline(always)]
+    fn trace_transition(&mut self, _input_offset: usize, _output_offset: usize) {
+    }
     pub fn flush(&mut self,
              output_bytes: &mut [u8],
              output_bytes_offset: &mut usize) -> DivansOutputResult{
         let adjusted_output_bytes = output_bytes.split_at_mut(*output_bytes_offset).1;
         let mut adjusted_output_bytes_offset = 0usize;
+        #[cfg(feature="timings")]
+        let timing = self.start_timing();
         let ret = self.internal_flush(adjusted_output_bytes, &mut adjusted_output_bytes_offset);
+        #[cfg(feature="timings")]
+        self.stop_timing(timing);
+        self.trace_transition(0, adjusted_output_bytes_offset);
+        #[cfg(feature="trace")]
+        {
+            self.trace.output_pos += adjusted_output_bytes_offset as u64;
+        }
         *output_bytes_offset += adjusted_output_bytes_offset;
         match self.frozen_checksum {
             None => if !Specialization::IS_DECODING_FILE {
@@ -421,11 +811,57 @@ impl<AllocU8: Allocator<u8>,
         }
         ret
     }
+    // writes out the coder output completed so far and leaves the stream open; symbols of a
+    // coder chunk still being filled come out later, with the rest of that chunk
+    pub fn sync_flush(&mut self,
+                      output_bytes: &mut [u8],
+                      output_bytes_offset: &mut usize) -> DivansOutputResult {
+        if self.is_finishing() {
+            return DivansOutputResult::Failure(ErrMsg::NotAllowedToEncodeAfterFlush);
+        }
+        let adjusted_output_bytes = output_bytes.split_at_mut(*output_bytes_offset).1;
+        let mut adjusted_output_bytes_offset = 0usize;
+        let ret = self.internal_sync_flush(adjusted_output_bytes, &mut adjusted_output_bytes_offset);
+        *output_bytes_offset += adjusted_output_bytes_offset;
+        if !Specialization::IS_DECODING_FILE {
+            self.crc.write(adjusted_output_bytes.split_at(adjusted_output_bytes_offset).0);
+        }
+        ret
+    }
+    fn internal_sync_flush(&mut self,
+                           output_bytes: &mut [u8],
+                           output_bytes_offset: &mut usize) -> DivansOutputResult {
+        for index in 0..NUM_ARITHMETIC_CODERS {
+            let ret = if index == CMD_CODER {
+                self.cross_command_state.drain_or_fill_internal_buffer_cmd(output_bytes, output_bytes_offset)
+            } else {
+                self.cross_command_state.drain_or_fill_internal_buffer_lit(output_bytes, output_bytes_offset)
+            };
+            match ret {
+                DivansResult::Success => {},
+                DivansResult::NeedsMoreInput => return DivansOutputResult::Failure(ErrMsg::DrainOrFillNeedsInput(2)),
+                DivansResult::NeedsMoreOutput => return DivansOutputResult::NeedsMoreOutput,
+                DivansResult::Failure(m) => return DivansOutputResult::Failure(m),
+            }
+        }
+        loop {
+            let output_loc = output_bytes.split_at_mut(*output_bytes_offset).1;
+            if output_loc.len() == 0 {
+                return DivansOutputResult::NeedsMoreOutput;
+            }
+            let amt = self.cross_command_state.muxer.drain(output_loc);
+            if amt == 0 {
+                return DivansOutputResult::Success;
+            }
+            *output_bytes_offset += amt;
+        }
+    }
     fn internal_flush(&mut self,
                  output_bytes: &mut [u8],
                  output_bytes_offset: &mut usize) -> DivansOutputResult{
         let nop = Command::<AllocU8::AllocatedMemory>::nop();
         loop {
+            self.trace_transition(0, *output_bytes_offset);
             match self.state {
                 EncodeOrDecodeState::Begin => {
                     let mut unused = 0usize;
@@ -521,26 +957,20 @@ impl<AllocU8: Allocator<u8>,
                             if !Specialization::IS_DECODING_FILE {
                                 self.crc.write(output_bytes.split_at(*output_bytes_offset).0);
                             }
-                            self.frozen_checksum = Some(self.crc.finish());
+                            let crc = self.crc.finish();
+                            divans_trace!(crc = crc, authenticated = self.crc.mac.is_some(); "writing stream trailer");
+                            self.frozen_checksum = Some(crc);
                         },
                         _ => {},
                     };
                     let crc = self.frozen_checksum.unwrap();
+                    let (checksum, checksum_len) = self.crc.trailer_for(crc);
                     let bytes_remaining = output_bytes.len() - *output_bytes_offset;
                     let checksum_cur_index = count as usize;
-                    let bytes_needed = CHECKSUM_LENGTH - count as usize;
+                    let bytes_needed = checksum_len - count as usize;
 
                     let count_to_copy = core::cmp::min(bytes_remaining,
                                                        bytes_needed);
-                    assert!(crc <= 0xffffffff);
-                    let checksum = [crc as u8 & 255,
-                                    (crc >> 8) as u8 & 255,
-                                    (crc >> 16) as u8 & 255,
-                                    (crc >> 24) as u8 & 255,
-                                    b'a',
-                                    b'n',
-                                    b's',
-                                    b'~'];
                     output_bytes.split_at_mut(*output_bytes_offset).1.split_at_mut(
                         count_to_copy).0.clone_from_slice(checksum.split_at(checksum_cur_index).1.split_at(count_to_copy).0);
                     *output_bytes_offset += count_to_copy;
@@ -566,6 +996,29 @@ impl<AllocU8: Allocator<u8>,
                                                           output_bytes_offset: &mut usize,
                                                           input_commands: &Commands,
                                                           input_command_offset: &mut usize) -> DivansResult {
+        let start_offsets = (*input_bytes_offset, *output_bytes_offset, *input_command_offset);
+        let had_work = (*input_bytes_offset < input_bytes.len() || *input_command_offset < input_commands.len())
+            && *output_bytes_offset < output_bytes.len();
+        #[cfg(feature="timings")]
+        let timing = self.start_timing();
+        let result = self.encode_or_decode_step(input_bytes,
+                                                input_bytes_offset,
+                                                output_bytes,
+                                                output_bytes_offset,
+                                                input_commands,
+                                                input_command_offset);
+        #[cfg(feature="timings")]
+        self.stop_timing(timing);
+        let progressed = start_offsets != (*input_bytes_offset, *output_bytes_offset, *input_command_offset);
+        self.stall_watchdog.check(result, progressed, had_work)
+    }
+    fn encode_or_decode_step<Commands:CommandArray>(&mut self,
+                                                    input_bytes: &[u8],
+                                                    input_bytes_offset: &mut usize,
+                                                    output_bytes: &mut [u8],
+                                                    output_bytes_offset: &mut usize,
+                                                    input_commands: &Commands,
+                                                    input_command_offset: &mut usize) -> DivansResult {
         let adjusted_output_bytes = output_bytes.split_at_mut(*output_bytes_offset).1;
         let mut adjusted_output_bytes_offset = 0usize;
         if !LinearInputBytes::ISOLATED {
@@ -574,9 +1027,13 @@ impl<AllocU8: Allocator<u8>,
                 let adjusted_input_bytes_offset = self.cross_command_state.demuxer.write_linear(
                     adjusted_input_bytes,
                     m8.get_base_alloc());
-                if Specialization::IS_DECODING_FILE && !self.skip_checksum {
+                if Specialization::IS_DECODING_FILE && self.checksum_policy.hashes_stream() {
                     self.crc.write(adjusted_input_bytes.split_at(adjusted_input_bytes_offset).0);
                 }
+                #[cfg(feature="trace")]
+                {
+                    self.trace.input_pos += adjusted_input_bytes_offset as u64;
+                }
                 *input_bytes_offset += adjusted_input_bytes_offset;
             }
         }
@@ -601,6 +1058,11 @@ impl<AllocU8: Allocator<u8>,
                 self.codec_traits = update;
             }
             if let Some(result) = res.0 {
+                self.trace_transition(0, adjusted_output_bytes_offset);
+                #[cfg(feature="trace")]
+                {
+                    self.trace.output_pos += adjusted_output_bytes_offset as u64;
+                }
                 *output_bytes_offset += adjusted_output_bytes_offset;
                 match self.frozen_checksum {
                     Some(_) => {},
@@ -649,6 +1111,117 @@ impl<AllocU8: Allocator<u8>,
             }
         }
     }
+    // codes the command type nibble, either directly or as literal?/copy? tree bits
+    fn code_command_type<ISl:SliceWrapper<u8>+Default>(&mut self,
+                                                       output_bytes: &mut [u8],
+                                                       output_bytes_offset: &mut usize,
+                                                       input_cmd: &Command<ISl>,
+                                                       is_end: bool) -> Result<u8, DivansResult> {
+        let adapt_cdf = self.cross_command_state.adapt_cdf();
+        loop {
+            match self.cross_command_state.drain_or_fill_internal_buffer_cmd(output_bytes, output_bytes_offset) {
+                DivansResult::Success => {},
+                need_something => return Err(need_something),
+            }
+            if self.cross_command_state.bk.auto_no_dict_due() {
+                // the encoder turns words off if none came so far
+                let mut dict_off = self.cross_command_state.bk.dict_commands_coded == 0;
+                self.cross_command_state.coder.get_or_put_bit(
+                    &mut dict_off,
+                    128,
+                    BillingDesignation::CrossCommand(CrossCommandBilling::FullSelection));
+                self.cross_command_state.bk.obs_auto_no_dict(dict_off);
+                continue; // one symbol per drain_or_fill
+            }
+            let mut command_type_code = command_type_to_nibble(input_cmd, is_end);
+            if command_type_code == 2 && self.cross_command_state.bk.auto_no_dict == Some(true) {
+                command_type_code = 3; // spelled out as a literal (see dict_literal)
+            }
+            if self.after_dict {
+                self.after_dict = false;
+                let mut is_fused = !Specialization::IS_DECODING_FILE && self.fusion_is_cheaper(input_cmd, is_end);
+                {
+                    let fusion_prob = &mut self.cross_command_state.bk.dict_copy_fusion;
+                    self.cross_command_state.coder.get_or_put_bit(
+                        &mut is_fused,
+                        fusion_prob.prob_of_false(),
+                        BillingDesignation::CrossCommand(CrossCommandBilling::FullSelection));
+                    if adapt_cdf {
+                        fusion_prob.blend(is_fused, &Speed::ROCKET);
+                    }
+                }
+                if is_fused {
+                    self.cross_command_state.bk.obs_command_type(1);
+                    return Ok(1);
+                }
+                continue; // one symbol per drain_or_fill
+            }
+            let node = self.command_type_node as usize;
+            if node < COMMAND_TYPE_TREE_DEPTH
+                && self.cross_command_state.bk.stream_features & STREAM_FEATURE_COMMAND_TYPE_TREE != 0 {
+                let mut is_node_code = command_type_code == COMMAND_TYPE_TREE_CODES[node];
+                {
+                    let command_type_bit_prob = self.cross_command_state.bk.get_command_type_tree_prob(node);
+                    self.cross_command_state.coder.get_or_put_bit(
+                        &mut is_node_code,
+                        command_type_bit_prob.prob_of_false(),
+                        BillingDesignation::CrossCommand(CrossCommandBilling::FullSelection));
+                    if adapt_cdf {
+                        command_type_bit_prob.blend(is_node_code, &Speed::ROCKET);
+                    }
+                }
+                if !is_node_code {
+                    self.command_type_node += 1;
+                    continue; // one symbol per drain_or_fill
+                }
+                command_type_code = COMMAND_TYPE_TREE_CODES[node];
+            } else {
+                let command_type_prob = self.cross_command_state.bk.get_command_type_prob();
+                self.cross_command_state.coder.get_or_put_nibble(
+                    &mut command_type_code,
+                    command_type_prob,
+                    BillingDesignation::CrossCommand(CrossCommandBilling::FullSelection));
+                if adapt_cdf {
+                    command_type_prob.blend(command_type_code, Speed::ROCKET);
+                }
+            }
+            self.command_type_node = 0;
+            self.cross_command_state.bk.obs_command_type(command_type_code);
+            return Ok(command_type_code);
+        }
+    }
+    // encoder only: whether the copy about to follow a dictionary word costs less as a set fusion bit
+    // than as a clear one followed by its command type, judged on the current probabilities
+    fn fusion_is_cheaper<ISl:SliceWrapper<u8>>(&mut self, input_cmd: &Command<ISl>, is_end: bool) -> bool {
+        match *input_cmd {
+            Command::Copy(ref cc) if !is_end && cc.num_bytes <= MAX_FUSED_COPY_LEN => {},
+            _ => return false,
+        }
+        let prob_of_false = u64::from(self.cross_command_state.bk.dict_copy_fusion.prob_of_false());
+        // the probability of a copy command type, out of 1 << 16
+        let copy_prob = if self.cross_command_state.bk.stream_features & STREAM_FEATURE_COMMAND_TYPE_TREE != 0 {
+            let not_literal = u64::from(self.cross_command_state.bk.get_command_type_tree_prob(0).prob_of_false());
+            let copy = 256 - u64::from(self.cross_command_state.bk.get_command_type_tree_prob(1).prob_of_false());
+            not_literal * copy
+        } else {
+            let command_type_prob = self.cross_command_state.bk.get_command_type_prob();
+            (command_type_prob.pdf(1).max(0) as u64) * 65536 / core::cmp::max(command_type_prob.max(), 1) as u64
+        };
+        (256 - prob_of_false) * 65536 > prob_of_false * copy_prob
+    }
+    // While a decoded command waits for room downstream, decode the next command type so the
+    // coder is already past it once the caller frees up space. The encoder cannot look ahead.
+    fn speculate_command_type<ISl:SliceWrapper<u8>+Default>(&mut self,
+                                                            output_bytes: &mut [u8],
+                                                            output_bytes_offset: &mut usize,
+                                                            input_cmd: &Command<ISl>) {
+        if Specialization::IS_DECODING_FILE && self.speculative_command_type.is_none() {
+            // a coder that needs input keeps its partial tree progress in command_type_node
+            if let Ok(code) = self.code_command_type(output_bytes, output_bytes_offset, input_cmd, false) {
+                self.speculative_command_type = Some(code);
+            }
+        }
+    }
     fn encode_or_decode_one_command<ISl:SliceWrapper<u8>+Default,
                                     CTraits:CodecTraits>(&mut self,
                                                          checksum_input_info: &mut ReadableBytes,
@@ -658,27 +1231,28 @@ impl<AllocU8: Allocator<u8>,
                                                          ctraits: &'static CTraits,
                                                          is_end: bool) -> CodecTraitResult {
         loop {
+            self.trace_transition(0, *output_bytes_offset);
             match self.state {
                 EncodeOrDecodeState::Begin => {
-                    match self.cross_command_state.drain_or_fill_internal_buffer_cmd(output_bytes, output_bytes_offset) {
-                        DivansResult::Success => {},
-                        need_something => return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(need_something)),
-                    }
-                    let mut command_type_code = command_type_to_nibble(input_cmd, is_end);
-                    {
-                        let command_type_prob = self.cross_command_state.bk.get_command_type_prob();
-                        self.cross_command_state.coder.get_or_put_nibble(
-                            &mut command_type_code,
-                            command_type_prob,
-                            BillingDesignation::CrossCommand(CrossCommandBilling::FullSelection));
-                        if self.cross_command_state.specialization.adapt_cdf() {
-                            command_type_prob.blend(command_type_code, Speed::ROCKET);
-                        }
+                    if self.cancel.is_cancelled() {
+                        return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(DivansResult::Failure(ErrMsg::Cancelled)));
                     }
+                    let command_type_code = match self.speculative_command_type.take() {
+                        Some(staged) => staged,
+                        None => match self.code_command_type(output_bytes, output_bytes_offset, input_cmd, is_end) {
+                            Ok(code) => code,
+                            Err(need_something) => return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(need_something)),
+                        },
+                    };
                     match self.update_command_state_from_nibble(command_type_code, is_end) {
                         DivansResult::Success => {},
                         need_something => return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(need_something)),
                     }
+                    if let Command::Dict(ref dc) = *input_cmd {
+                        if !Specialization::IS_DECODING_FILE && command_type_code == 3 {
+                            self.dict_literal = Some(*dc);
+                        }
+                    }
                     match self.state {
                         EncodeOrDecodeState::Copy => { self.cross_command_state.bk.obs_copy_state(); },
                         EncodeOrDecodeState::Dict => { self.cross_command_state.bk.obs_dict_state(); },
@@ -700,6 +1274,7 @@ impl<AllocU8: Allocator<u8>,
                                                       ) {
                         DivansResult::Success => {
                             self.cross_command_state.bk.obs_distance(&self.state_copy.cc);
+                            self.obs_command_bytes(u64::from(self.state_copy.cc.num_bytes));
                             self.state_populate_ring_buffer = Command::Copy(self.state_copy.cc.clone());
                             self.state = EncodeOrDecodeState::PopulateRingBuffer;
                         },
@@ -710,21 +1285,35 @@ impl<AllocU8: Allocator<u8>,
                 },
                 EncodeOrDecodeState::Literal => {
                     let backing_store = LiteralCommand::nop();
-                    let src_literal_command = self.cross_command_state.specialization.get_source_literal_command(
-                        input_cmd,
-                        &backing_store);
-                    match self.state_lit.encode_or_decode(&mut self.cross_command_state,
-                                                     src_literal_command,
-                                                     output_bytes,
-                                                     output_bytes_offset,
-                                                     ctraits) {
+                    match match self.dict_literal {
+                        Some(ref dc) => self.state_lit.encode_or_decode(&mut self.cross_command_state,
+                                                                        &dict::DictWordBytes::literal(dc),
+                                                                        output_bytes,
+                                                                        output_bytes_offset,
+                                                                        ctraits),
+                        None => {
+                            let src_literal_command = self.cross_command_state.specialization.get_source_literal_command(
+                                input_cmd,
+                                &backing_store);
+                            self.state_lit.encode_or_decode(&mut self.cross_command_state,
+                                                            src_literal_command,
+                                                            output_bytes,
+                                                            output_bytes_offset,
+                                                            ctraits)
+                        },
+                    } {
                         DivansResult::Success => {
+                            self.dict_literal = None;
+                            self.trace_transition(0, *output_bytes_offset);
+                            let num_bytes = self.state_lit.lc.data.len();
+                            self.obs_command_bytes(num_bytes as u64);
                             self.state_populate_ring_buffer = Command::Literal(
                                 core::mem::replace(&mut self.state_lit.lc,
                                                    LiteralCommand::<AllocatedMemoryPrefix<u8, AllocU8>>::nop()));
                             self.state = EncodeOrDecodeState::PopulateRingBuffer;
                         },
                         retval => {
+                            self.trace_transition(0, *output_bytes_offset);
                             return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(retval));
                         }
                     }
@@ -741,26 +1330,38 @@ impl<AllocU8: Allocator<u8>,
                                  self.state = EncodeOrDecodeState::Begin;
                                  return CodecTraitResult::Res(OneCommandReturn::Advance);
                              },
+                             DivansOutputResult::NeedsMoreOutput => {
+                                 self.speculate_command_type(output_bytes, output_bytes_offset, input_cmd);
+                                 return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(DivansResult::NeedsMoreOutput));
+                             },
                              need_something =>  {
                                  return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(DivansResult::from(need_something)));
                              },
                          }
                     } else {
                         match {
+                            #[cfg(feature="timings")]
+                            let recode_start = self.clock.now();
                             let (m8, recoder) = match self.cross_command_state.thread_ctx {
                                 ThreadContext::MainThread(ref mut main_thread_ctx) => (Some(&mut main_thread_ctx.m8), Some(&mut main_thread_ctx.recoder)),
                                 ThreadContext::Worker => (None, None),
                             };
-                            self.cross_command_state.demuxer.push_cmd(&mut self.state_populate_ring_buffer,
-                                                                      m8,
-                                                                      recoder,
-                                                                      &mut self.cross_command_state.specialization,
-                                                                      output_bytes,
-                                                                      output_bytes_offset,
-                            )
+                            let pushed = self.cross_command_state.demuxer.push_cmd(&mut self.state_populate_ring_buffer,
+                                                                                   m8,
+                                                                                   recoder,
+                                                                                   &mut self.cross_command_state.specialization,
+                                                                                   output_bytes,
+                                                                                   output_bytes_offset,
+                            );
+                            #[cfg(feature="timings")]
+                            {
+                                self.timings.recode_ns += self.clock.since(recode_start);
+                            }
+                            pushed
                         } {
                             DivansOutputResult::NeedsMoreOutput => {
                                 if Specialization::DOES_CALLER_WANT_ORIGINAL_FILE_BYTES {
+                                    self.speculate_command_type(output_bytes, output_bytes_offset, input_cmd);
                                     return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(DivansResult::NeedsMoreOutput)); // we need the caller to drain the buffer
                                 }
                             },
@@ -768,8 +1369,10 @@ impl<AllocU8: Allocator<u8>,
                                 return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(DivansResult::Failure(m)));
                             },
                             DivansOutputResult::Success => {
-                                // clobber bk.last_8_literals with the last 8 literals
+                                // clobber bk.last_8_literals with the last 8 literals,
+                                // unless an IR-only stream keeps the literals alone there
                                 match self.cross_command_state.thread_ctx {
+                                    ThreadContext::MainThread(_) if self.cross_command_state.bk.extended_stream_features & EXTENDED_FEATURE_IR_ONLY != 0 => {},
                                     ThreadContext::MainThread(ref mut ctx) => {
                                         let last_8 = ctx.recoder.last_8_literals();
                                         ctx.lbk.last_8_literals = //FIXME(threading) only should be run in the main thread
@@ -781,6 +1384,7 @@ impl<AllocU8: Allocator<u8>,
                                             | (u64::from(last_8[5])<<0x28)
                                             | (u64::from(last_8[6])<<0x30)
                                             | (u64::from(last_8[7])<<0x38);
+                                        ctx.lbk.obs_populated_command(&self.state_populate_ring_buffer, &ctx.recoder);
                                     }
                                     ThreadContext::Worker => {}, // Main thread tracks literals
                              }
@@ -800,6 +1404,8 @@ impl<AllocU8: Allocator<u8>,
                                                       output_bytes_offset
                                                       ) {
                         DivansResult::Success => {
+                            self.obs_command_bytes(u64::from(self.state_dict.dc.final_size));
+                            self.after_dict = self.cross_command_state.bk.extended_stream_features2 & EXTENDED_FEATURE2_DICT_COPY_FUSION != 0;
                             self.state_populate_ring_buffer = Command::Dict(self.state_dict.dc.clone());
                             self.state = EncodeOrDecodeState::PopulateRingBuffer;
                         },
@@ -837,9 +1443,7 @@ impl<AllocU8: Allocator<u8>,
                          DivansResult::Success => {
                              if let ThreadContext::MainThread(ref mut ctx) = self.cross_command_state.thread_ctx {
                                  self.state = EncodeOrDecodeState::Begin;
-                                 let ret = ctx.lbk.obs_prediction_mode_context_map(
-                                     &self.state_prediction_mode.pm,
-                                     &mut ctx.mcdf16);
+                                 let ret = ctx.obs_prediction_mode_context_map(&self.state_prediction_mode.pm);
                                  self.state_prediction_mode.reset(&mut ctx.m8);
                                  if let DivansOpResult::Failure(_) = ret {
                                      return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(DivansResult::from(ret)));
@@ -901,11 +1505,18 @@ impl<AllocU8: Allocator<u8>,
                                                             output_bytes,
                                                             output_bytes_offset) {
                         DivansResult::Success => {
-                            self.cross_command_state.bk.obs_btypec(match self.state_block_switch {
+                            self.block_switch_counts.0 += 1;
+                            let btype = match self.state_block_switch {
                                 block_type::BlockTypeState::FullyDecoded(btype) => btype,
                                 _ => return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(
                                     DivansResult::Failure(ErrMsg::UnintendedCodecState(1)))),
-                            });
+                            };
+                            self.cross_command_state.bk.obs_btypec(btype);
+                            if self.forward_block_switches {
+                                self.state_populate_ring_buffer = Command::BlockSwitchCommand(BlockSwitch::new(btype));
+                                self.state = EncodeOrDecodeState::PopulateRingBuffer;
+                                continue;
+                            }
                             self.state = EncodeOrDecodeState::Begin;
                             return CodecTraitResult::Res(OneCommandReturn::Advance);
                         },
@@ -926,11 +1537,18 @@ impl<AllocU8: Allocator<u8>,
                                                             output_bytes,
                                                             output_bytes_offset) {
                         DivansResult::Success => {
-                            self.cross_command_state.bk.obs_btyped(match self.state_block_switch {
+                            self.block_switch_counts.1 += 1;
+                            let btype = match self.state_block_switch {
                                 block_type::BlockTypeState::FullyDecoded(btype) => btype,
                                 _ => return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(
                                     DivansResult::Failure(ErrMsg::UnintendedCodecState(2)))),
-                            });
+                            };
+                            self.cross_command_state.bk.obs_btyped(btype);
+                            if self.forward_block_switches {
+                                self.state_populate_ring_buffer = Command::BlockSwitchDistance(BlockSwitch::new(btype));
+                                self.state = EncodeOrDecodeState::PopulateRingBuffer;
+                                continue;
+                            }
                             self.state = EncodeOrDecodeState::Begin;
                             return CodecTraitResult::Res(OneCommandReturn::Advance);
                         },
@@ -948,9 +1566,11 @@ impl<AllocU8: Allocator<u8>,
                 },
                 EncodeOrDecodeState::WriteChecksum(count) => {
                     assert!(Specialization::IS_DECODING_FILE);
+                    // an authenticated stream holds back the last of its output until the tag checks out
+                    let authenticated = self.crc.mac.is_some();
                     match self.cross_command_state.thread_ctx {
                         // only main thread can checksum
-                        ThreadContext::MainThread(ref mut main_thread_ctx) => {
+                        ThreadContext::MainThread(ref mut main_thread_ctx) => if !authenticated {
                             match main_thread_ctx.recoder.flush(output_bytes, output_bytes_offset) {
                                 DivansOutputResult::Success => {},
                                 need_something => return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(DivansResult::from(need_something))),
@@ -970,51 +1590,54 @@ impl<AllocU8: Allocator<u8>,
                     if !self.cross_command_state.demuxer.consumed_all_streams_until_eof() {
                         return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(DivansResult::NeedsMoreInput));
                     }
-                    if self.skip_checksum {
+                    if !self.checksum_policy.hashes_stream() {
                         self.frozen_checksum = Some(0);
                     }
                     // decoder only operation
-                    let checksum_cur_index = count;
-                    let bytes_needed = CHECKSUM_LENGTH - count as usize;
-
-                    let to_check = core::cmp::min(checksum_input_info.data.len() - *checksum_input_info.read_offset,
-                                                  bytes_needed);
-                    if to_check == 0 {
-                        return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(DivansResult::NeedsMoreInput));
-                    }
                     match self.frozen_checksum {
                         Some(_) => {},
                         None => {
                             //DO NOT DO AGAIN; self.crc.write(checksum_input_info.data.split_at(*checksum_input_info.read_offset).0); ALREADY DONE
-                            self.frozen_checksum= Some(self.crc.finish());
+                            let crc = self.crc.finish();
+                            divans_trace!(crc = crc, authenticated = authenticated; "checking stream trailer");
+                            self.frozen_checksum = Some(crc);
                         },
                     }
                     let crc = self.frozen_checksum.unwrap();
-                    assert!(crc <= 0xffffffff);
-                    let checksum = [crc as u8 & 255,
-                                    (crc >> 8) as u8 & 255,
-                                    (crc >> 16) as u8 & 255,
-                                    (crc >> 24) as u8 & 255,
-                                    b'a',
-                                    b'n',
-                                    b's',
-                                    b'~'];
-
-                    for (index, (chk, fil)) in checksum.split_at(checksum_cur_index as usize).1.split_at(to_check).0.iter().zip(
-                        checksum_input_info.data.split_at(*checksum_input_info.read_offset).1.split_at(to_check).0.iter()).enumerate() {
-                        if *chk != *fil {
-                            if checksum_cur_index as usize + index >= 4 || !self.skip_checksum {
-                                return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(DivansResult::Failure(
-                                    ErrMsg::BadChecksum(*chk, *fil))));
-                            }
+                    let (checksum, checksum_len) = self.crc.trailer_for(crc);
+                    let checksum_cur_index = count;
+                    let bytes_needed = checksum_len - count as usize;
+                    if bytes_needed != 0 {
+                        let to_check = core::cmp::min(checksum_input_info.data.len() - *checksum_input_info.read_offset,
+                                                      bytes_needed);
+                        if to_check == 0 {
+                            return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(DivansResult::NeedsMoreInput));
                         }
-                    }
-                    *checksum_input_info.read_offset += to_check;
-                    if bytes_needed != to_check {
+                        // compared only once all of it is in, so that no partial match shows in the timing
+                        self.deserialized_trailer[checksum_cur_index as usize..checksum_cur_index as usize + to_check].clone_from_slice(
+                            checksum_input_info.data.split_at(*checksum_input_info.read_offset).1.split_at(to_check).0);
+                        *checksum_input_info.read_offset += to_check;
                         self.state = EncodeOrDecodeState::WriteChecksum(count as u8 + to_check as u8);
-                    } else {
-                        self.state = EncodeOrDecodeState::DivansSuccess;
+                        continue;
+                    }
+                    let crc_and_tag_len = self.crc.checksum_length();
+                    if let DivansOpResult::Failure(m) = self.checksum_policy.check_trailer(&checksum[..crc_and_tag_len], &self.deserialized_trailer[..],
+                                                                            self.crc.checksum_type().digest_length()) {
+                        return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(DivansResult::Failure(m)));
+                    }
+                    if !mac::tags_match(&checksum[crc_and_tag_len..checksum_len], &self.deserialized_trailer[crc_and_tag_len..checksum_len]) {
+                        return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(DivansResult::Failure(
+                            ErrMsg::BadAuthenticationTag)));
+                    }
+                    if authenticated {
+                        if let ThreadContext::MainThread(ref mut main_thread_ctx) = self.cross_command_state.thread_ctx {
+                            match main_thread_ctx.recoder.flush(output_bytes, output_bytes_offset) {
+                                DivansOutputResult::Success => {},
+                                need_something => return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(DivansResult::from(need_something))),
+                            }
+                        }
                     }
+                    self.state = EncodeOrDecodeState::DivansSuccess;
                 },
                 EncodeOrDecodeState::DivansSuccess => {
                     return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(DivansResult::Success));
@@ -1024,3 +1647,111 @@ impl<AllocU8: Allocator<u8>,
     }
 }
 
+#[cfg(all(test, feature="std", feature="encoder", feature="decoder"))]
+mod test {
+    use std::vec::Vec;
+    use conformance::{conformance_commands, conformance_expected_output, ConformanceSlice};
+    use interface::{
+        Command,
+        FeatureFlagSliceType,
+        LiteralCommand,
+        DictCommand,
+        CopyCommand,
+        DivansCompressorOptions,
+        CompressorOption,
+        FormatVersion,
+        ErrMsg,
+        HEADER_FEATURE_OFFSET,
+        HEADER_EXTENDED_FEATURE2_OFFSET,
+        MAGIC_NUMBER_EXTENDED,
+        EXTENDED_FEATURE2_DICT_COPY_FUSION,
+        STREAM_FEATURE_COMMAND_TYPE_TREE,
+        STREAM_FEATURE_CONTEXT_MAP_RLE,
+    };
+    use test_support::{encode, encode_with_options, compress_hybrid, decode, decoded_options, decode_with, DecodeConfig};
+
+    #[test]
+    fn test_dict_copy_fusion_roundtrip() {
+        // templated text: each dictionary word is followed by a short copy of the text before it
+        let mut commands = conformance_commands("dict");
+        for i in 0..1200u32 {
+            commands.push(Command::Literal(LiteralCommand {
+                data: ConformanceSlice(b"<".to_vec()),
+                prob: FeatureFlagSliceType::<ConformanceSlice>::default(),
+                high_entropy: false,
+            }));
+            commands.push(Command::Dict(DictCommand {
+                word_size: 4,
+                transform: 0,
+                final_size: 4,
+                empty: 0,
+                word_id: i * 37 % 512,
+            }));
+            commands.push(Command::Copy(CopyCommand {
+                distance: 10,
+                num_bytes: 3 + i % 4,
+            }));
+        }
+        let expected = conformance_expected_output(&commands[..]).unwrap();
+        for stream_features in [0, STREAM_FEATURE_COMMAND_TYPE_TREE].iter() {
+            let mut opts = DivansCompressorOptions::default();
+            opts.stream_features = *stream_features;
+            let plain = encode_with_options(&commands[..], opts).unwrap();
+            opts.extended_stream_features2 = EXTENDED_FEATURE2_DICT_COPY_FUSION;
+            let fused = encode_with_options(&commands[..], opts).unwrap();
+            assert_eq!(fused[..4], MAGIC_NUMBER_EXTENDED);
+            assert_eq!(fused[HEADER_EXTENDED_FEATURE2_OFFSET], EXTENDED_FEATURE2_DICT_COPY_FUSION);
+            for multithread in [false, true].iter() {
+                assert_eq!(decode_with(&fused[..], DecodeConfig::new(*multithread), |_| {}).into_output().unwrap(), expected);
+            }
+            // every word pays for its fusion bit, so a command type this predictable gains next to nothing
+            assert!(fused.len() <= plain.len() + plain.len() / 100, "{} vs {}", fused.len(), plain.len());
+            // a bit this decoder does not know is refused
+            let mut unknown = fused.clone();
+            unknown[HEADER_EXTENDED_FEATURE2_OFFSET] |= 0x80;
+            assert_eq!(decode(&unknown[..]), Err(ErrMsg::UnsupportedStreamFeatures(0x81)));
+        }
+        let input = &include_bytes!("../../testdata/alice29")[..];
+        let mut opts = DivansCompressorOptions::default();
+        opts.extended_stream_features2 = EXTENDED_FEATURE2_DICT_COPY_FUSION;
+        let hybrid = compress_hybrid(input, opts);
+        for multithread in [false, true].iter() {
+            assert_eq!(decode_with(&hybrid[..], DecodeConfig::new(*multithread), |_| {}).into_output().unwrap(), &input[..]);
+            assert_eq!(decoded_options(&hybrid[..], *multithread).unwrap().extended_stream_features2,
+                       EXTENDED_FEATURE2_DICT_COPY_FUSION);
+        }
+
+        opts.extended_stream_features2 = 0x80;
+        assert_eq!(opts.validate(), Err(ErrMsg::OptionOutOfRange(CompressorOption::ExtendedStreamFeatures2)));
+        opts.extended_stream_features2 = EXTENDED_FEATURE2_DICT_COPY_FUSION;
+        opts.format_version = Some(FormatVersion::StreamFeatures);
+        assert_eq!(opts.validate(), Err(ErrMsg::ConflictingOptions(CompressorOption::FormatVersion,
+                                                                   CompressorOption::ExtendedStreamFeatures2)));
+    }
+
+    #[test]
+    fn test_legacy_command_selection_roundtrip() {
+        // without STREAM_FEATURE_COMMAND_TYPE_TREE the command type is picked with one nibble, as
+        // builds predating the type tree wrote and read it
+        let commands = conformance_commands("mixed");
+        let expected = conformance_expected_output(&commands[..]).unwrap();
+        let legacy = encode(&commands[..], STREAM_FEATURE_CONTEXT_MAP_RLE).unwrap();
+        let tree = encode(&commands[..], STREAM_FEATURE_COMMAND_TYPE_TREE | STREAM_FEATURE_CONTEXT_MAP_RLE).unwrap();
+        assert_eq!(legacy[HEADER_FEATURE_OFFSET], STREAM_FEATURE_CONTEXT_MAP_RLE);
+        assert_eq!(decode(&legacy[..]).unwrap(), expected);
+        assert_eq!(decode(&tree[..]).unwrap(), expected);
+        assert!(legacy != tree);
+        let mut input = Vec::<u8>::new();
+        for i in 0..100000u32 {
+            input.extend(format!("{} {} ", i % 97, i % 13).as_bytes());
+        }
+        let mut opts = DivansCompressorOptions::default();
+        opts.stream_features &= !STREAM_FEATURE_COMMAND_TYPE_TREE;
+        let legacy = compress_hybrid(&input[..], opts);
+        assert_eq!(legacy[HEADER_FEATURE_OFFSET] & STREAM_FEATURE_COMMAND_TYPE_TREE, 0);
+        assert_eq!(decode(&legacy[..]).unwrap(), input);
+        let tree = compress_hybrid(&input[..], DivansCompressorOptions::default());
+        assert!(tree[HEADER_FEATURE_OFFSET] & STREAM_FEATURE_COMMAND_TYPE_TREE != 0);
+        assert_eq!(decode(&tree[..]).unwrap(), input);
+    }
+}
@@ -6,6 +6,12 @@ use ::priors::{PriorCollection, PriorMultiIndex};
 #[cfg(feature="debug_entropy")]
 use ::priors::summarize_prior_billing;
 pub const NUM_BLOCK_TYPES:usize = 256;
+// The row of a block type in the context maps and the priors kept per block type.  Only
+// EXTENDED_FEATURE2_WIDE_BLOCK_TYPES streams have block types past the rows, and those share the last.
+#[inline(always)]
+pub fn block_type_slot(block_type: u32) -> usize {
+    if block_type as usize >= NUM_BLOCK_TYPES { NUM_BLOCK_TYPES - 1 } else { block_type as usize }
+}
 pub const NUM_STRIDES:usize = 8;
 use alloc::{SliceWrapper, Allocator, SliceWrapperMut};
 use probability::{BaseCDF};
@@ -46,6 +52,73 @@ define_prior_struct!(LiteralCommandPriorsCM, LiteralCMPriorType,
                      (LiteralCMPriorType::FirstNibble, 1, NUM_BLOCK_TYPES),
                      (LiteralCMPriorType::SecondNibble, 1, 16, NUM_BLOCK_TYPES));
 
+#[derive(PartialEq, Debug, Clone)]
+pub enum LiteralMatchPriorType {
+    FirstNibble,
+    SecondNibble,
+}
+// the first literal after a copy, by the byte the copy would have produced next
+define_prior_struct!(LiteralMatchPriors, LiteralMatchPriorType,
+                     (LiteralMatchPriorType::FirstNibble, 16, NUM_BLOCK_TYPES), // (the match byte's first nibble, actual context)
+                     // (its second nibble if the first nibbles agree, else 16 + the literal's first nibble, actual context)
+                     (LiteralMatchPriorType::SecondNibble, 32, NUM_BLOCK_TYPES));
+
+// The classes of byte that the first nibble prior of EXTENDED_FEATURE2_BYTE_CLASS_CONTEXT streams
+// tells apart in the byte predicted for a literal: bytes of a class tend to be followed alike.
+pub const NUM_BYTE_CLASSES: usize = 14;
+// how many literals in a row a prediction held for, saturating
+pub const NUM_BYTE_CLASS_CONFIDENCES: usize = 4;
+const fn byte_class(byte: u8) -> u8 {
+    match byte {
+        b'\t' | b'\n' | b'\r' | b' ' => 1,
+        b'.' | b',' | b';' | b':' | b'!' | b'?' => 2,
+        b'\'' | b'"' | b'(' | b')' | b'[' | b']' | b'{' | b'}' | b'<' | b'>' => 3,
+        b'0'..=b'9' => 4,
+        b'A' | b'E' | b'I' | b'O' | b'U' => 5,
+        b'A'..=b'Z' => 6,
+        b'a' | b'e' | b'i' | b'o' | b'u' | b'y' => 7,
+        b'b'..=b'o' => 8,
+        b'p'..=b'z' => 9,
+        0x21..=0x7e => 10, // the other symbols
+        0x80..=0xbf => 11, // utf-8 continuation bytes
+        0xc0..=0xff => 12, // utf-8 lead bytes
+        0x7f => 13,
+        _ => 0, // control bytes
+    }
+}
+const fn byte_classes() -> [u8; 256] {
+    let mut classes = [0u8; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        classes[byte] = byte_class(byte as u8);
+        byte += 1;
+    }
+    classes
+}
+pub const BYTE_CLASS: [u8; 256] = byte_classes();
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum LiteralByteClassPriorType {
+    FirstNibble,
+}
+// (the predicted byte's class * NUM_BYTE_CLASS_CONFIDENCES + its confidence, actual context)
+define_prior_struct!(LiteralByteClassPriors, LiteralByteClassPriorType,
+                     (LiteralByteClassPriorType::FirstNibble, NUM_BYTE_CLASSES * NUM_BYTE_CLASS_CONFIDENCES, NUM_BLOCK_TYPES));
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum StaticLiteralTablePriorType {
+    Presence,
+    LowTableMask,
+    HighWeight,
+    LowWeight,
+}
+// the weights are indexed by the weight before them in the table, 16 for the first
+define_prior_struct!(StaticLiteralTablePriors, StaticLiteralTablePriorType,
+                     (StaticLiteralTablePriorType::Presence, 1),
+                     (StaticLiteralTablePriorType::LowTableMask, 4),
+                     (StaticLiteralTablePriorType::HighWeight, 17),
+                     (StaticLiteralTablePriorType::LowWeight, 17));
+
 #[derive(PartialEq, Debug, Clone)]
 pub enum RandLiteralNibblePriorType {
     CountSmall,
@@ -66,6 +139,7 @@ pub enum CopyCommandNibblePriorType {
     DistanceMnemonic,
     DistanceMnemonicTwo,
     DistanceMantissaNib,
+    DistancePostfix,
     CountSmall,
     CountBegNib,
     CountLastNib,
@@ -77,6 +151,7 @@ define_prior_struct!(CopyCommandPriors, CopyCommandNibblePriorType,
                      (CopyCommandNibblePriorType::DistanceMnemonic, NUM_BLOCK_TYPES, 2),
                      (CopyCommandNibblePriorType::DistanceLastNib, NUM_BLOCK_TYPES, 1),
                      (CopyCommandNibblePriorType::DistanceMantissaNib, NUM_BLOCK_TYPES, 5),
+                     (CopyCommandNibblePriorType::DistancePostfix, NUM_BLOCK_TYPES, 9), // by the previous postfix, 8 if none
                      (CopyCommandNibblePriorType::CountSmall, NUM_BLOCK_TYPES, NUM_COPY_COMMAND_ORGANIC_PRIORS),
                      (CopyCommandNibblePriorType::CountBegNib, NUM_BLOCK_TYPES, NUM_COPY_COMMAND_ORGANIC_PRIORS),
                      (CopyCommandNibblePriorType::CountLastNib, NUM_BLOCK_TYPES, NUM_COPY_COMMAND_ORGANIC_PRIORS),
@@ -87,14 +162,20 @@ pub enum DictCommandNibblePriorType {
     SizeLastNib,
     Index,
     Transform,
+    TransformModelHigh,
+    TransformModelLow,
 }
 
 const NUM_ORGANIC_DICT_DISTANCE_PRIORS: usize = 5;
+pub const NUM_DICT_WORD_SIZE_BUCKETS: usize = 8;
 define_prior_struct!(DictCommandPriors, DictCommandNibblePriorType,
                      (DictCommandNibblePriorType::SizeBegNib, NUM_BLOCK_TYPES),
                      (DictCommandNibblePriorType::SizeLastNib, NUM_BLOCK_TYPES),
                      (DictCommandNibblePriorType::Index, NUM_BLOCK_TYPES, NUM_ORGANIC_DICT_DISTANCE_PRIORS),
-                     (DictCommandNibblePriorType::Transform, 2, 25));
+                     (DictCommandNibblePriorType::Transform, 2, 25),
+                     // (the command type before this one, quantized word size)
+                     (DictCommandNibblePriorType::TransformModelHigh, 4, NUM_DICT_WORD_SIZE_BUCKETS),
+                     (DictCommandNibblePriorType::TransformModelLow, 16, NUM_DICT_WORD_SIZE_BUCKETS)); // (high nibble, quantized word size)
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum BlockTypePriorType {
@@ -102,12 +183,18 @@ pub enum BlockTypePriorType {
     FirstNibble,
     SecondNibble,
     StrideNibble,
+    NibbleOrder,
+    WideNibble,
 }
+// the nibbles of the varint past a wide block type escape get a prior each up to the last
+pub const NUM_WIDE_BLOCK_TYPE_NIBBLE_PRIORS: usize = 4;
 define_prior_struct!(BlockTypePriors, BlockTypePriorType,
                      (BlockTypePriorType::Mnemonic, 3), // 3 for each of ltype, ctype, dtype switches.
                      (BlockTypePriorType::FirstNibble, 3),
                      (BlockTypePriorType::SecondNibble, 3),
-                     (BlockTypePriorType::StrideNibble, 1));
+                     (BlockTypePriorType::StrideNibble, 1),
+                     (BlockTypePriorType::NibbleOrder, 1),
+                     (BlockTypePriorType::WideNibble, 3, NUM_WIDE_BLOCK_TYPE_NIBBLE_PRIORS));
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum PredictionModePriorType {
@@ -120,6 +207,13 @@ pub enum PredictionModePriorType {
     FirstNibble,
     SecondNibble,
     ContextMapSpeedPalette,
+    DistanceBucketing,
+    ContextMapRunLength,
+    ContextMapSparse,
+    MixerWarmStart,
+    ContextMapModelMnemonic,
+    ContextMapModelFirstNibble,
+    ContextMapModelSecondNibble,
 }
 
 define_prior_struct!(PredictionModePriors, PredictionModePriorType,
@@ -129,5 +223,14 @@ define_prior_struct!(PredictionModePriors, PredictionModePriorType,
                      (PredictionModePriorType::SecondNibble, 2),
                      (PredictionModePriorType::Mnemonic, 4),
                      (PredictionModePriorType::PriorMixingValue, 17),
+                     (PredictionModePriorType::DistanceBucketing, 2), // must precede the last entry, which unlisted types share
+                     (PredictionModePriorType::ContextMapRunLength, 5, 2), // (nibble count or value nibble, context map type)
+                     (PredictionModePriorType::ContextMapSparse, 7), // default nibbles, gap nibble count, gap nibbles
+                     (PredictionModePriorType::MixerWarmStart, 2), // cm share, seed nibble
+                     // (the previous block type's entry: its lru slot, 13 if evicted or 14 if none, context map type)
+                     (PredictionModePriorType::ContextMapModelMnemonic, 15, 2),
+                     // (the previous block type's entry's high nibble or 16 if none, context map type)
+                     (PredictionModePriorType::ContextMapModelFirstNibble, 17, 2),
+                     (PredictionModePriorType::ContextMapModelSecondNibble, 16, 2), // (first nibble, context map type)
                      (PredictionModePriorType::ContextMapSpeedPalette, 4)
                      );
@@ -0,0 +1,399 @@
+// This file contains the worker half of a threaded decoder that decodes the literals on the worker
+#![cfg(feature="std")]
+use core;
+use core::hash::Hasher;
+use alloc::{Allocator, SliceWrapper};
+use ::alloc_util::{RepurposingAlloc, duplicate_cell};
+use ::cmd_to_raw::DivansRecodeState;
+use ::divans_to_raw::DecoderSpecialization;
+use ::interface::{
+    ArithmeticEncoderOrDecoder,
+    Command,
+    DivansOpResult,
+    DivansOutputResult,
+    DivansResult,
+    EncoderOrDecoderRecoderSpecialization,
+    ErrMsg,
+    FeatureFlagSliceType,
+    LiteralCommand,
+    NewWithAllocator,
+    Nop,
+    PredictionModeContextMap,
+    ReadableBytes,
+    StreamDemuxer,
+    StreamID,
+    NUM_STREAMS,
+    EXTENDED_FEATURE_IR_ONLY,
+};
+use mux::{DevNull, Mux};
+use ::probability::CDF16;
+use ::slice_util::{AllocatedMemoryPrefix, AllocatedMemoryRange, SliceReference};
+use multithreading::BufferedMultiWorker;
+use threading::{ThreadToMain, ThreadData, StaticCommand};
+use super::weights::MixerSnapshot;
+use super::decoder::DivansDecoderCodec;
+use super::literal::{LiteralState, LiteralSubstate};
+use super::interface::{LiteralModels, LIT_CODER};
+use super::specializations::{
+    construct_codec_trait_from_bookkeeping,
+    CodecTraitSelector,
+    DEFAULT_TRAIT,
+};
+
+// the most decoded literal bytes a batch of commands carries to the main thread
+pub const LITERAL_SPAN_BUFFER_SIZE: usize = 1 << 16;
+
+// The literal stream as the worker sees it: the last buffer of it the main thread handed over.
+struct LiteralInput<AllocU8:Allocator<u8>> {
+    slice: AllocatedMemoryRange<u8, AllocU8>,
+    unused: usize,
+}
+
+impl<AllocU8:Allocator<u8>> Default for LiteralInput<AllocU8> {
+    fn default() -> Self {
+        LiteralInput::<AllocU8> {
+            slice: AllocatedMemoryRange::<u8, AllocU8>::default(),
+            unused: 0,
+        }
+    }
+}
+
+impl<AllocU8:Allocator<u8>> StreamDemuxer<AllocU8> for LiteralInput<AllocU8> {
+    fn write_linear(&mut self, _data:&[u8], _m8: &mut AllocU8) -> usize {
+        unimplemented!();
+    }
+    #[inline(always)]
+    fn read_buffer(&mut self) -> [ReadableBytes; NUM_STREAMS] {
+        let data = self.slice.0.slice().split_at(self.slice.1.end).0;
+        [ReadableBytes{data:&[], read_offset:&mut self.unused},
+         ReadableBytes{data:data, read_offset:&mut self.slice.1.start},
+         ]
+    }
+    #[inline(always)]
+    fn data_ready(&self, stream_id:StreamID) -> usize {
+        if stream_id != LIT_CODER as StreamID {
+            return 0;
+        }
+        self.slice.slice().len()
+    }
+    #[inline(always)]
+    fn peek(&self, stream_id: StreamID) -> &[u8] {
+        assert_eq!(stream_id, LIT_CODER as StreamID);
+        self.slice.slice()
+    }
+    #[inline(always)]
+    fn edit(&mut self, stream_id: StreamID) -> &mut AllocatedMemoryRange<u8, AllocU8> {
+        assert_eq!(stream_id, LIT_CODER as StreamID);
+        &mut self.slice
+    }
+    #[inline(always)]
+    fn consume(&mut self, stream_id: StreamID, count: usize) {
+        assert_eq!(stream_id, LIT_CODER as StreamID);
+        self.slice.1.start += count;
+    }
+    fn consumed_all_streams_until_eof(&self) -> bool {
+        false
+    }
+    fn encountered_eof(&self) -> bool {
+        false
+    }
+    fn free_demux(&mut self, m8: &mut AllocU8) {
+        m8.free_cell(core::mem::replace(&mut self.slice.0, AllocU8::AllocatedMemory::default()));
+    }
+}
+
+// The worker's end of the queue to the main thread, for a worker that decodes the literals as well
+// as the commands.  Once hand_literals_to_worker has lent it the literal models, each literal is
+// decoded here, from the literal stream buffers the main thread pushes, and shipped as spans; the
+// main thread only populates the ring buffer.  The literal contexts need the bytes before each
+// literal, so a copy of the ring buffer is kept here too, replaying every command into it.
+// Until then it is a BufferedMultiWorker.
+pub struct SpanWorker<Cdf16:CDF16,
+                      AllocU8:Allocator<u8>,
+                      AllocCDF16:Allocator<Cdf16>,
+                      AllocCommand:Allocator<StaticCommand>,
+                      ArithmeticCoder:ArithmeticEncoderOrDecoder> {
+    pub worker: BufferedMultiWorker<AllocU8, AllocCommand>,
+    models: Option<LiteralModels<Cdf16, AllocU8, AllocCDF16, ArithmeticCoder>>,
+    recoder: DivansRecodeState<AllocU8::AllocatedMemory>,
+    state_lit: LiteralState<AllocU8>, // decodes a span at a time into lc.data
+    input: LiteralInput<AllocU8>,
+    devnull: DevNull<AllocU8>,
+    nop: LiteralCommand<AllocatedMemoryPrefix<u8, AllocU8>>,
+    codec_traits: CodecTraitSelector,
+    specialization: DecoderSpecialization,
+    ir_only: bool,
+    paranoid: bool,
+}
+
+impl<Cdf16:CDF16,
+     AllocU8:Allocator<u8>,
+     AllocCDF16:Allocator<Cdf16>,
+     AllocCommand:Allocator<StaticCommand>,
+     ArithmeticCoder:ArithmeticEncoderOrDecoder+NewWithAllocator<AllocU8>> SpanWorker<Cdf16, AllocU8, AllocCDF16, AllocCommand, ArithmeticCoder> {
+    pub fn new(worker: BufferedMultiWorker<AllocU8, AllocCommand>) -> Self {
+        SpanWorker::<Cdf16, AllocU8, AllocCDF16, AllocCommand, ArithmeticCoder> {
+            worker: worker,
+            models: None,
+            recoder: DivansRecodeState::<AllocU8::AllocatedMemory>::default(),
+            state_lit: LiteralState {
+                lc: LiteralCommand::<AllocatedMemoryPrefix<u8, AllocU8>>::nop(),
+                state: LiteralSubstate::FullyDecoded,
+            },
+            input: LiteralInput::<AllocU8>::default(),
+            devnull: DevNull::default(),
+            nop: LiteralCommand::<AllocatedMemoryPrefix<u8, AllocU8>>::nop(),
+            codec_traits: CodecTraitSelector::DefaultTrait(&DEFAULT_TRAIT),
+            specialization: DecoderSpecialization::default(),
+            ir_only: false,
+            paranoid: false,
+        }
+    }
+    // Lends the literal models of decoder, which is about to decode its first command, to this
+    // worker, and allocates what it needs to decode the literals from decoder's allocator.
+    pub fn hand_literals_to_worker<D:Hasher+Default>(&mut self,
+                                                     decoder: &mut DivansDecoderCodec<Cdf16, AllocU8, AllocCDF16, AllocCommand, ArithmeticCoder, Mux<AllocU8>, D>) {
+        let models = decoder.ctx.lend_literal_models();
+        self.codec_traits = construct_codec_trait_from_bookkeeping(&models.lbk);
+        self.models = Some(models);
+        let m8 = decoder.ctx.m8.get_base_alloc();
+        self.recoder = DivansRecodeState::<AllocU8::AllocatedMemory>::new(duplicate_cell(m8, &decoder.ctx.recoder.ring_buffer));
+        self.recoder.restore_ringbuffer_to_snapshot(decoder.ctx.recoder.snapshot_ringbuffer());
+        self.state_lit.lc.data = AllocatedMemoryPrefix::realloc(m8.alloc_cell(LITERAL_SPAN_BUFFER_SIZE), 0);
+        self.worker.alloc_literal_spans(m8, LITERAL_SPAN_BUFFER_SIZE);
+        decoder.literal_spans = AllocatedMemoryPrefix::realloc(m8.alloc_cell(LITERAL_SPAN_BUFFER_SIZE), 0);
+        decoder.literals_on_worker = true;
+        self.ir_only = decoder.stream_options.extended_stream_features & EXTENDED_FEATURE_IR_ONLY != 0;
+        self.paranoid = decoder.paranoid;
+    }
+    // Gives decoder back the literal models and frees what hand_literals_to_worker allocated,
+    // once the worker is done.
+    pub fn take_literals_back<D:Hasher+Default>(&mut self,
+                                                decoder: &mut DivansDecoderCodec<Cdf16, AllocU8, AllocCDF16, AllocCommand, ArithmeticCoder, Mux<AllocU8>, D>) {
+        if let Some(models) = self.models.take() {
+            decoder.ctx.return_literal_models(models);
+            let m8 = decoder.ctx.m8.get_base_alloc();
+            m8.free_cell(core::mem::replace(&mut self.recoder.ring_buffer, AllocU8::AllocatedMemory::default()));
+            m8.free_cell(core::mem::replace(&mut self.state_lit.lc.data.0, AllocU8::AllocatedMemory::default()));
+            self.input.free_demux(m8);
+            decoder.literals_on_worker = false;
+        }
+    }
+    // the mixer snapshot of the literal models, while they are lent to this worker
+    pub fn mixer_snapshot(&self) -> Option<MixerSnapshot> {
+        self.models.as_ref().map(|models| models.lbk.mixer_snapshots.current(models.lbk.btype_last, &models.lbk.model_weights))
+    }
+    pub fn free(&mut self, m8: &mut RepurposingAlloc<u8, AllocU8>, mc: &mut AllocCommand) {
+        self.worker.free(m8, mc);
+    }
+    // what the main thread would have observed of cmd in the literal models and the ring buffer;
+    // literals are observed as they are decoded
+    fn observe_cmd(&mut self, cmd: &Command<AllocatedMemoryPrefix<u8, AllocU8>>) -> DivansOutputResult {
+        let SpanWorker{ref mut worker, ref mut models, ref mut recoder, ref mut codec_traits, ir_only, ..} = *self;
+        let models = match *models {
+            Some(ref mut models) => models,
+            None => return DivansOutputResult::Success,
+        };
+        match *cmd {
+            Command::Copy(ref copy) => models.obs_command_bytes(u64::from(copy.num_bytes)),
+            Command::Dict(ref dict) => models.obs_command_bytes(u64::from(dict.final_size)),
+            Command::BlockSwitchCommand(_) | Command::BlockSwitchDistance(_) => {},
+            Command::PredictionMode(ref pm) => {
+                if let DivansOpResult::Failure(m) = models.obs_prediction_mode_context_map(pm) {
+                    return DivansOutputResult::Failure(m);
+                }
+                *codec_traits = construct_codec_trait_from_bookkeeping(&models.lbk);
+                return DivansOutputResult::Success;
+            },
+            Command::BlockSwitchLiteral(ref block_switch) => {
+                models.lbk.obs_literal_block_switch(*block_switch);
+                *codec_traits = construct_codec_trait_from_bookkeeping(&models.lbk);
+                worker.worker.push_mixer_snapshots(&mut models.lbk.mixer_snapshots);
+                return DivansOutputResult::Success;
+            },
+            Command::Literal(_) => return DivansOutputResult::Success,
+        }
+        if !ir_only {
+            match recoder.replay_cmd(cmd) {
+                DivansOutputResult::Success => {},
+                res => return res,
+            }
+            models.lbk.obs_populated_command(cmd, recoder);
+        }
+        DivansOutputResult::Success
+    }
+    // decodes a literal of len bytes, shipping it in as many spans as the span buffers need
+    fn decode_literal(&mut self, len: usize, high_entropy: bool) -> DivansOutputResult {
+        let SpanWorker{ref mut worker, ref mut models, ref mut recoder, ref mut state_lit, ref mut input,
+                       ref mut devnull, ref nop, codec_traits, ref specialization, ir_only, ..} = *self;
+        let models = models.as_mut().unwrap();
+        if !ir_only {
+            models.lbk.last_8_literals = u64::from_le_bytes(recoder.last_8_literals());
+        }
+        let mut remaining = len;
+        loop {
+            let span_len = core::cmp::min(remaining, worker.span_space());
+            state_lit.lc.data.1 = span_len as u32;
+            state_lit.state = state_lit.get_nibble_code_state(0, &state_lit.lc, input.data_ready(LIT_CODER as StreamID));
+            loop {
+                match match codec_traits {
+                    CodecTraitSelector::DefaultTrait(tr) =>
+                        state_lit.encode_or_decode_content_bytes(
+                            &mut None,
+                            &mut models.lit_coder,
+                            &mut models.lbk,
+                            &mut models.lit_high_priors,
+                            &mut models.lit_low_priors,
+                            input,
+                            devnull,
+                            nop,
+                            &mut [],
+                            &mut 0usize,
+                            tr,
+                            specialization),
+                    CodecTraitSelector::MixingTrait(mtr) =>
+                        state_lit.encode_or_decode_content_bytes(
+                            &mut None,
+                            &mut models.lit_coder,
+                            &mut models.lbk,
+                            &mut models.lit_high_priors,
+                            &mut models.lit_low_priors,
+                            input,
+                            devnull,
+                            nop,
+                            &mut [],
+                            &mut 0usize,
+                            mtr,
+                            specialization),
+                } {
+                    DivansResult::Success => break,
+                    DivansResult::NeedsMoreInput => match worker.pull_literal_data(&mut input.slice) {
+                        ThreadData::Data(data) => input.slice = data,
+                        ThreadData::Err(e) => return DivansOutputResult::Failure(e),
+                        ThreadData::Eof | ThreadData::Yield => return DivansOutputResult::Failure(ErrMsg::UnexpectedEof),
+                    },
+                    DivansResult::NeedsMoreOutput => unreachable!(), // nothing is written out
+                    DivansResult::Failure(m) => return DivansOutputResult::Failure(m),
+                }
+            }
+            let span = state_lit.lc.data.slice();
+            if !ir_only {
+                match recoder.replay_cmd(&Command::Literal(LiteralCommand{
+                    data: SliceReference::new(span, 0, span.len()),
+                    prob: FeatureFlagSliceType::default(),
+                    high_entropy: high_entropy,
+                })) {
+                    DivansOutputResult::Success => {},
+                    res => return res,
+                }
+            }
+            remaining -= span.len();
+            match worker.push_literal_span(span, high_entropy, remaining) {
+                DivansOutputResult::Success => {},
+                res => return res,
+            }
+            if remaining == 0 {
+                break;
+            }
+        }
+        models.obs_command_bytes(len as u64);
+        if !ir_only {
+            models.lbk.obs_populated_command(&Command::Literal(LiteralCommand::<SliceReference<u8>>::nop()), recoder);
+        }
+        DivansOutputResult::Success
+    }
+}
+
+impl<Cdf16:CDF16,
+     AllocU8:Allocator<u8>,
+     AllocCDF16:Allocator<Cdf16>,
+     AllocCommand:Allocator<StaticCommand>,
+     ArithmeticCoder:ArithmeticEncoderOrDecoder+NewWithAllocator<AllocU8>> ThreadToMain<AllocU8> for SpanWorker<Cdf16, AllocU8, AllocCDF16, AllocCommand, ArithmeticCoder> {
+    const COOPERATIVE:bool = false;
+    const ISOLATED:bool = true;
+    #[inline(always)]
+    fn pull_data(&mut self) -> ThreadData<AllocU8> {
+        self.worker.pull_data()
+    }
+    #[inline(always)]
+    fn pull_context_map(&mut self,
+                        m8: Option<&mut RepurposingAlloc<u8, AllocU8>>) -> Result<PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>, ()> {
+        self.worker.pull_context_map(m8)
+    }
+    #[inline(always)]
+    fn push_cmd<Specialization:EncoderOrDecoderRecoderSpecialization>(
+        &mut self,
+        cmd:&mut Command<AllocatedMemoryPrefix<u8, AllocU8>>,
+        m8: Option<&mut RepurposingAlloc<u8, AllocU8>>,
+        recoder: Option<&mut DivansRecodeState<AllocU8::AllocatedMemory>>,
+        specialization: &mut Specialization,
+        output:&mut [u8],
+        output_offset: &mut usize,
+    ) -> DivansOutputResult {
+        if self.models.is_some() {
+            match self.observe_cmd(cmd) {
+                DivansOutputResult::Success => {},
+                res => return res,
+            }
+            if let Command::Literal(ref lit) = *cmd {
+                return self.decode_literal(lit.data.len(), lit.high_entropy);
+            }
+        }
+        self.worker.push_cmd(cmd, m8, recoder, specialization, output, output_offset)
+    }
+    #[inline(always)]
+    fn push_consumed_data(&mut self,
+                    data:&mut AllocatedMemoryRange<u8, AllocU8>,
+                    m8: Option<&mut RepurposingAlloc<u8, AllocU8>>,
+    ) -> DivansOutputResult {
+        self.worker.push_consumed_data(data, m8)
+    }
+    #[inline(always)]
+    fn push_eof(&mut self) -> DivansOutputResult {
+        if let Some(ref models) = self.models {
+            if self.paranoid && !models.priors_valid() {
+                return DivansOutputResult::Failure(ErrMsg::InvalidCdf);
+            }
+        }
+        self.worker.push_eof()
+    }
+    fn broadcast_err(&mut self, err: ErrMsg) {
+        self.worker.broadcast_err(err);
+    }
+}
+
+#[cfg(all(test, feature="encoder", feature="decoder"))]
+mod test {
+    use std::vec::Vec;
+    use conformance::{conformance_commands, conformance_expected_output, ConformanceSlice};
+    use interface::{Command, CopyCommand, FeatureFlagSliceType, LiteralCommand};
+    use test_support::{encode, decode_with, DecodeConfig};
+    use super::LITERAL_SPAN_BUFFER_SIZE;
+
+    #[test]
+    fn test_literal_spans() {
+        let mut commands = conformance_commands("mixed");
+        // a literal that takes several span buffers, between commands that read what it left behind
+        let mut state = 11u32;
+        let data: Vec<u8> = (0..3 * LITERAL_SPAN_BUFFER_SIZE + 1234).map(|index| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            if index % 3 == 0 { (state >> 16) as u8 } else { b"spans"[index % 5] }
+        }).collect();
+        commands.push(Command::Literal(LiteralCommand {
+            data: ConformanceSlice(data),
+            prob: FeatureFlagSliceType::<ConformanceSlice>::default(),
+            high_entropy: false,
+        }));
+        commands.push(Command::Copy(CopyCommand{distance: 1000, num_bytes: 300}));
+        commands.push(Command::Literal(LiteralCommand {
+            data: ConformanceSlice(b"after the spans".to_vec()),
+            prob: FeatureFlagSliceType::<ConformanceSlice>::default(),
+            high_entropy: false,
+        }));
+        let expected = conformance_expected_output(&commands[..]).unwrap();
+        let stream = encode(&commands[..], 0).unwrap();
+        for multithread in [false, true].iter() {
+            assert_eq!(decode_with(&stream[..], DecodeConfig::new(*multithread), |_| {}).into_output().unwrap(), expected);
+        }
+    }
+}
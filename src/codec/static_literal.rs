@@ -0,0 +1,234 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// The static literal model of EXTENDED_FEATURE_STATIC_LITERAL_MODEL streams.
+// Literals are coded with tables the encoder chooses for the metablock, which stay put while it is
+// decoded: no blending, no mixing and no model weights, in exchange for some ratio.
+// Every actual context has STATIC_LITERAL_TABLES_PER_CONTEXT tables, one for the high nibble and
+// one for the low nibble after each high nibble, each sent as the weight exponents of a StaticCDF16.
+// The prediction mode command travels to the main thread without them, so they head the literal
+// stream at the first literal after it.  For each actual context in turn:
+//   presence nibble: 0 if all its tables are uniform, and nothing more follows for it
+//   4 nibbles: which low nibble tables are sent, 4 tables a nibble, least significant bit first
+//   16 weight exponents for the high nibble table and for each low nibble table sent
+// A table that is not sent is uniform.
+use alloc::{Allocator, SliceWrapper, SliceWrapperMut};
+use alloc_util::duplicate_cell;
+use interface::{ArithmeticEncoderOrDecoder, BillingDesignation};
+use ::priors::PriorCollection;
+use ::probability::{CDF16, Speed, StaticCDF16, STATIC_CDF_BYTES};
+use super::interface::EncoderOrDecoderSpecialization;
+use super::priors::{StaticLiteralTablePriors, StaticLiteralTablePriorType, NUM_BLOCK_TYPES};
+
+pub const STATIC_LITERAL_TABLES_PER_CONTEXT: usize = 17;
+const NUM_STATIC_LITERAL_TABLES: usize = NUM_BLOCK_TYPES * STATIC_LITERAL_TABLES_PER_CONTEXT;
+// 16 weight exponents per table, table t of actual context c at (c * 17 + t) * 16
+pub const STATIC_LITERAL_WEIGHT_BYTES: usize = NUM_STATIC_LITERAL_TABLES * 16;
+const LOW_TABLE_GROUPS: u8 = 4;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StaticLiteralTableState {
+    Idle,
+    Presence(u8), // of the actual context
+    LowTableMask(u8, u8), // actual context and group of 4 low nibble tables
+    Weight(u8, u8, u8), // actual context, table and index in the table
+}
+
+pub struct StaticLiteralModel<Cdf16:CDF16, AllocU8:Allocator<u8>, AllocCDF16:Allocator<Cdf16>> {
+    pub enabled: bool,
+    pub state: StaticLiteralTableState,
+    pub sent_tables: u32, // of the actual context being coded; bit 0 is the high nibble table
+    // the encoder writes its choice here before the prediction mode command; the decoder reads into it
+    pub weights: AllocU8::AllocatedMemory,
+    pub cdfs: AllocU8::AllocatedMemory, // STATIC_CDF_BYTES per table
+    pub priors: StaticLiteralTablePriors<Cdf16, AllocCDF16>,
+}
+
+// a table with equal weights is uniform
+fn is_uniform(weights: &[u8]) -> bool {
+    weights.iter().all(|w| *w == weights[0])
+}
+
+fn table_offset(actual_context: u8, table: usize) -> usize {
+    usize::from(actual_context) * STATIC_LITERAL_TABLES_PER_CONTEXT + table
+}
+
+impl<Cdf16:CDF16, AllocU8:Allocator<u8>, AllocCDF16:Allocator<Cdf16>> Default for StaticLiteralModel<Cdf16, AllocU8, AllocCDF16> {
+    fn default() -> Self {
+        StaticLiteralModel {
+            enabled: false,
+            state: StaticLiteralTableState::Idle,
+            sent_tables: 0,
+            weights: AllocU8::AllocatedMemory::default(),
+            cdfs: AllocU8::AllocatedMemory::default(),
+            priors: StaticLiteralTablePriors {
+                priors: AllocCDF16::AllocatedMemory::default(),
+            },
+        }
+    }
+}
+
+impl<Cdf16:CDF16, AllocU8:Allocator<u8>, AllocCDF16:Allocator<Cdf16>> StaticLiteralModel<Cdf16, AllocU8, AllocCDF16> {
+    pub fn duplicate(&self, m8: &mut AllocU8, mcdf16: &mut AllocCDF16) -> Self {
+        StaticLiteralModel {
+            enabled: self.enabled,
+            state: self.state,
+            sent_tables: self.sent_tables,
+            weights: duplicate_cell(m8, &self.weights),
+            cdfs: duplicate_cell(m8, &self.cdfs),
+            priors: StaticLiteralTablePriors {
+                priors: duplicate_cell(mcdf16, &self.priors.priors),
+            },
+        }
+    }
+    // call before the first command: the first literal is preceded by tables even without a
+    // prediction mode command before it
+    pub fn enable(&mut self, mcdf16: &mut AllocCDF16) {
+        self.enabled = true;
+        self.obs_prediction_mode(Some(mcdf16));
+    }
+    // the next literal starts with the tables of the new metablock; mcdf16 may be left out once
+    // enable has allocated the priors
+    pub fn obs_prediction_mode(&mut self, mcdf16: Option<&mut AllocCDF16>) {
+        if !self.enabled {
+            return;
+        }
+        if let (false, Some(mcdf16)) = (self.priors.initialized(), mcdf16) {
+            self.priors.priors = mcdf16.alloc_cell(StaticLiteralTablePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
+        }
+        self.state = StaticLiteralTableState::Presence(0);
+    }
+    pub fn tables_pending(&self) -> bool {
+        self.state != StaticLiteralTableState::Idle
+    }
+    // encoder only: where to put the weights of the tables to send after the next prediction mode command
+    pub fn weights_mut(&mut self, m8: &mut AllocU8) -> &mut [u8] {
+        self.alloc_tables(m8);
+        self.weights.slice_mut()
+    }
+    pub fn alloc_tables(&mut self, m8: &mut AllocU8) {
+        if self.weights.slice().is_empty() {
+            self.weights = m8.alloc_cell(STATIC_LITERAL_WEIGHT_BYTES);
+            self.cdfs = m8.alloc_cell(NUM_STATIC_LITERAL_TABLES * STATIC_CDF_BYTES);
+        }
+    }
+    #[inline(always)]
+    pub fn cdf(&self, actual_context: u8, table: usize) -> StaticCDF16 {
+        let offset = table_offset(actual_context, table) * STATIC_CDF_BYTES;
+        StaticCDF16::from_bytes(&self.cdfs.slice()[offset..offset + STATIC_CDF_BYTES])
+    }
+    fn table_weights(&self, actual_context: u8, table: usize) -> &[u8] {
+        let offset = table_offset(actual_context, table) * 16;
+        &self.weights.slice()[offset..offset + 16]
+    }
+    // whether the encoder has anything but uniform tables for the actual context
+    fn context_sent(&self, actual_context: u8) -> bool {
+        (0..STATIC_LITERAL_TABLES_PER_CONTEXT).any(|table| !is_uniform(self.table_weights(actual_context, table)))
+    }
+    // clears the weights of the tables that were not sent and works out the CDFs of all of them
+    fn finish_context(&mut self, actual_context: u8) {
+        for table in 0..STATIC_LITERAL_TABLES_PER_CONTEXT {
+            let offset = table_offset(actual_context, table);
+            let weights = &mut self.weights.slice_mut()[offset * 16..offset * 16 + 16];
+            if (self.sent_tables >> table) & 1 == 0 {
+                for w in weights.iter_mut() {
+                    *w = 0;
+                }
+            }
+            StaticCDF16::from_weight_exponents(weights).write_bytes(
+                &mut self.cdfs.slice_mut()[offset * STATIC_CDF_BYTES..(offset + 1) * STATIC_CDF_BYTES]);
+        }
+        self.state = if usize::from(actual_context) + 1 == NUM_BLOCK_TYPES {
+            StaticLiteralTableState::Idle
+        } else {
+            StaticLiteralTableState::Presence(actual_context + 1)
+        };
+    }
+    // codes the next nibble of the tables
+    pub fn code_table_nibble<ArithmeticCoder:ArithmeticEncoderOrDecoder,
+                             Specialization:EncoderOrDecoderSpecialization>(&mut self,
+                                                                            m8: Option<&mut AllocU8>,
+                                                                            coder: &mut ArithmeticCoder,
+                                                                            specialization: &Specialization) {
+        // without an allocator, as on a worker thread, the tables have to be in place already
+        if let Some(m8) = m8 {
+            self.alloc_tables(m8);
+        }
+        let billing = BillingDesignation::StaticLiteralTables;
+        match self.state {
+            StaticLiteralTableState::Idle => {},
+            StaticLiteralTableState::Presence(actual_context) => {
+                let mut nibble = self.context_sent(actual_context) as u8;
+                let prior = self.priors.get(StaticLiteralTablePriorType::Presence, (0,));
+                coder.get_or_put_nibble(&mut nibble, prior, billing);
+                if specialization.adapt_cdf() {
+                    prior.blend(nibble, Speed::MED);
+                }
+                if nibble != 0 {
+                    self.sent_tables = 1;
+                    self.state = StaticLiteralTableState::LowTableMask(actual_context, 0);
+                } else {
+                    self.sent_tables = 0;
+                    self.finish_context(actual_context);
+                }
+            },
+            StaticLiteralTableState::LowTableMask(actual_context, group) => {
+                let mut nibble = 0u8;
+                for bit in 0..4 {
+                    let table = 1 + usize::from(group) * 4 + bit;
+                    nibble |= (!is_uniform(self.table_weights(actual_context, table)) as u8) << bit;
+                }
+                let prior = self.priors.get(StaticLiteralTablePriorType::LowTableMask, (usize::from(group),));
+                coder.get_or_put_nibble(&mut nibble, prior, billing);
+                if specialization.adapt_cdf() {
+                    prior.blend(nibble, Speed::MED);
+                }
+                self.sent_tables |= u32::from(nibble & 0xf) << (1 + 4 * group);
+                self.state = if group + 1 == LOW_TABLE_GROUPS {
+                    StaticLiteralTableState::Weight(actual_context, 0, 0)
+                } else {
+                    StaticLiteralTableState::LowTableMask(actual_context, group + 1)
+                };
+            },
+            StaticLiteralTableState::Weight(actual_context, table, index) => {
+                let offset = table_offset(actual_context, usize::from(table)) * 16 + usize::from(index);
+                let mut nibble = self.weights.slice()[offset];
+                let prev = if index == 0 { 16 } else { usize::from(self.weights.slice()[offset - 1]) };
+                let prior_type = if table == 0 {
+                    StaticLiteralTablePriorType::HighWeight
+                } else {
+                    StaticLiteralTablePriorType::LowWeight
+                };
+                let prior = self.priors.get(prior_type, (prev,));
+                coder.get_or_put_nibble(&mut nibble, prior, billing);
+                if specialization.adapt_cdf() {
+                    prior.blend(nibble, Speed::MED);
+                }
+                self.weights.slice_mut()[offset] = nibble;
+                if index != 15 {
+                    self.state = StaticLiteralTableState::Weight(actual_context, table, index + 1);
+                } else {
+                    let later_tables = self.sent_tables >> (table + 1);
+                    if later_tables != 0 {
+                        self.state = StaticLiteralTableState::Weight(actual_context,
+                                                                     table + 1 + later_tables.trailing_zeros() as u8,
+                                                                     0);
+                    } else {
+                        self.finish_context(actual_context);
+                    }
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,274 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Compact binary trace of the codec state machine, for debugging interop with other implementations.
+// Recording requires the "trace" feature; the reader is always available so tools can explain a trace.
+//
+// Layout: the 5 byte TRACE_MAGIC followed by one record per transition:
+//   tag:u8, varint seq delta, varint arg, zigzag varint input offset delta, zigzag varint output offset delta
+// Tags below LITERAL_SUBSTATE_TAG are EncodeOrDecodeState variants; tags at or above it are LiteralSubstate variants.
+// A record is emitted whenever the variant changes, not when only its argument advances (eg. the nibble index).
+#[cfg(feature="trace")]
+use core;
+#[cfg(feature="trace")]
+use std::vec::Vec;
+
+pub const TRACE_MAGIC: [u8; 5] = [b'd', b'v', b't', b'r', 1];
+pub const LITERAL_SUBSTATE_TAG: u8 = 0x80;
+
+static STATE_NAMES: [&'static str; 15] = [
+    "Begin",
+    "Literal",
+    "Dict",
+    "Copy",
+    "BlockSwitchLiteral",
+    "BlockSwitchCommand",
+    "BlockSwitchDistance",
+    "PredictionMode",
+    "PopulateRingBuffer",
+    "DivansSuccess",
+    "EncodedShutdownNode",
+    "ShutdownCoder",
+    "CoderBufferDrain",
+    "MuxDrain",
+    "WriteChecksum",
+];
+
+static LITERAL_SUBSTATE_NAMES: [&'static str; 10] = [
+    "Lit::Begin",
+    "Lit::LiteralCountSmall",
+    "Lit::LiteralCountFirst",
+    "Lit::LiteralCountLengthGreater14Less25",
+    "Lit::LiteralCountMantissaNibbles",
+    "Lit::LiteralNibbleIndex",
+    "Lit::SafeLiteralNibbleIndex",
+    "Lit::LiteralNibbleLowerHalf",
+    "Lit::LiteralNibbleIndexWithECDF",
+    "Lit::FullyDecoded",
+];
+
+pub fn tag_name(tag: u8) -> &'static str {
+    let names: &[&'static str] = if tag >= LITERAL_SUBSTATE_TAG {
+        &LITERAL_SUBSTATE_NAMES[..]
+    } else {
+        &STATE_NAMES[..]
+    };
+    match names.get((tag & !LITERAL_SUBSTATE_TAG) as usize) {
+        Some(name) => name,
+        None => "Unknown",
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub seq: u64, // global order of the transition, so traces from the command and literal codecs can be merged
+    pub tag: u8,
+    pub arg: u64,
+    pub input_offset: u64, // total bytes accepted by the codec when the transition happened
+    pub output_offset: u64, // total bytes produced by the codec when the transition happened
+}
+
+fn read_varint(data: &[u8], offset: &mut usize) -> Option<u64> {
+    let mut ret = 0u64;
+    let mut shift = 0u32;
+    loop {
+        let byte = match data.get(*offset) {
+            Some(b) => *b,
+            None => return None,
+        };
+        *offset += 1;
+        if shift >= 64 {
+            return None;
+        }
+        ret |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(ret);
+        }
+        shift += 7;
+    }
+}
+
+fn unzigzag(val: u64) -> u64 {
+    (val >> 1) ^ (0u64.wrapping_sub(val & 1))
+}
+
+pub struct TraceReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+    prev: TraceRecord,
+    truncated: bool,
+}
+
+impl<'a> TraceReader<'a> {
+    // returns None if the buffer does not start with TRACE_MAGIC
+    pub fn new(data: &'a [u8]) -> Option<Self> {
+        if data.len() < TRACE_MAGIC.len() || data[..TRACE_MAGIC.len()] != TRACE_MAGIC[..] {
+            return None;
+        }
+        Some(TraceReader {
+            data: data,
+            offset: TRACE_MAGIC.len(),
+            prev: TraceRecord::default(),
+            truncated: false,
+        })
+    }
+    // true if iteration stopped on a partial record rather than the end of the trace
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+impl<'a> Iterator for TraceReader<'a> {
+    type Item = TraceRecord;
+    fn next(&mut self) -> Option<TraceRecord> {
+        if self.offset >= self.data.len() || self.truncated {
+            return None;
+        }
+        let mut offset = self.offset + 1;
+        let tag = self.data[self.offset];
+        let fields = (read_varint(self.data, &mut offset),
+                      read_varint(self.data, &mut offset),
+                      read_varint(self.data, &mut offset),
+                      read_varint(self.data, &mut offset));
+        match fields {
+            (Some(seq), Some(arg), Some(input_delta), Some(output_delta)) => {
+                self.offset = offset;
+                self.prev = TraceRecord {
+                    seq: self.prev.seq.wrapping_add(seq),
+                    tag: tag,
+                    arg: arg,
+                    input_offset: self.prev.input_offset.wrapping_add(unzigzag(input_delta)),
+                    output_offset: self.prev.output_offset.wrapping_add(unzigzag(output_delta)),
+                };
+                Some(self.prev)
+            },
+            _ => {
+                self.truncated = true;
+                None
+            },
+        }
+    }
+}
+
+#[cfg(feature="trace")]
+static TRACE_CLOCK: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+#[cfg(feature="trace")]
+fn write_varint(mut val: u64, out: &mut Vec<u8>) {
+    while val >= 0x80 {
+        out.push((val as u8) | 0x80);
+        val >>= 7;
+    }
+    out.push(val as u8);
+}
+
+#[cfg(feature="trace")]
+fn zigzag_delta(cur: u64, prev: u64) -> u64 {
+    let delta = cur.wrapping_sub(prev) as i64;
+    ((delta << 1) ^ (delta >> 63)) as u64
+}
+
+#[cfg(feature="trace")]
+#[derive(Clone, Default)]
+pub struct TraceLog {
+    records: Vec<TraceRecord>,
+    last_tag: [Option<u8>; 2],
+    // totals at the start of the current call; the owner advances these as it consumes and produces bytes
+    pub input_pos: u64,
+    pub output_pos: u64,
+}
+
+#[cfg(feature="trace")]
+impl TraceLog {
+    #[inline(always)]
+    pub fn observe(&mut self, tag: u8, arg: u64, input_offset: usize, output_offset: usize) {
+        let class = (tag >= LITERAL_SUBSTATE_TAG) as usize;
+        if self.last_tag[class] == Some(tag) {
+            return;
+        }
+        self.last_tag[class] = Some(tag);
+        self.records.push(TraceRecord {
+            seq: TRACE_CLOCK.fetch_add(1, core::sync::atomic::Ordering::Relaxed) as u64,
+            tag: tag,
+            arg: arg,
+            input_offset: self.input_pos.wrapping_add(input_offset as u64),
+            output_offset: self.output_pos.wrapping_add(output_offset as u64),
+        });
+    }
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+    // moves the records of other (eg. the literal decoder's log) into this log, keeping transition order
+    pub fn merge(&mut self, other: &mut TraceLog) {
+        self.records.extend(other.records.drain(..));
+        self.records.sort_by_key(|r| r.seq);
+    }
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ret = Vec::<u8>::with_capacity(TRACE_MAGIC.len() + self.records.len() * 4);
+        ret.extend(TRACE_MAGIC.iter());
+        let mut prev = TraceRecord::default();
+        for rec in self.records.iter() {
+            ret.push(rec.tag);
+            write_varint(rec.seq.wrapping_sub(prev.seq), &mut ret);
+            write_varint(rec.arg, &mut ret);
+            write_varint(zigzag_delta(rec.input_offset, prev.input_offset), &mut ret);
+            write_varint(zigzag_delta(rec.output_offset, prev.output_offset), &mut ret);
+            prev = *rec;
+        }
+        ret
+    }
+    // serializes and clears the recorded transitions
+    pub fn take(&mut self) -> Vec<u8> {
+        let ret = self.serialize();
+        self.records.clear();
+        ret
+    }
+}
+
+#[cfg(all(test, feature="trace"))]
+mod test {
+    use super::{TraceLog, TraceReader, LITERAL_SUBSTATE_TAG, tag_name};
+    #[test]
+    fn test_trace_roundtrip() {
+        let mut lit_log = TraceLog::default();
+        let mut log = TraceLog::default();
+        log.observe(0, 0, 0, 0);
+        log.observe(0, 0, 1, 1); // same variant: not recorded
+        log.input_pos = 1 << 40;
+        log.observe(14, 3, 7, 0);
+        lit_log.output_pos = 5;
+        lit_log.observe(LITERAL_SUBSTATE_TAG | 5, 0xffffffff, 0, 9);
+        log.observe(9, 0, 2, 0);
+        log.merge(&mut lit_log);
+        assert_eq!(log.len(), 4);
+        let data = log.take();
+        assert_eq!(log.len(), 0);
+        let mut reader = TraceReader::new(&data[..]).unwrap();
+        let recs: ::std::vec::Vec<_> = reader.by_ref().collect();
+        assert!(!reader.is_truncated());
+        assert_eq!(recs.len(), 4);
+        assert_eq!(tag_name(recs[1].tag), "WriteChecksum");
+        assert_eq!(recs[1].arg, 3);
+        assert_eq!(recs[1].input_offset, (1 << 40) + 7);
+        assert_eq!(tag_name(recs[2].tag), "Lit::LiteralNibbleIndex");
+        assert_eq!(recs[2].output_offset, 14);
+        assert_eq!(recs[2].input_offset, 0);
+        assert_eq!(recs[3].input_offset, (1 << 40) + 2);
+        assert!(recs[0].seq < recs[1].seq && recs[1].seq < recs[2].seq && recs[2].seq < recs[3].seq);
+        let mut truncated = TraceReader::new(&data[..data.len() - 1]).unwrap();
+        assert_eq!(truncated.by_ref().count(), 3);
+        assert!(truncated.is_truncated());
+        assert!(TraceReader::new(&data[1..]).is_none());
+    }
+}
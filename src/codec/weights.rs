@@ -1,6 +1,7 @@
 use core;
 use ::probability::{Prob, BLEND_FIXED_POINT_PRECISION, LOG2_SCALE};
 
+#[derive(Clone, Copy)]
 pub struct Weights {
     model_weights: [i32;2],
     mixing_param: u8,
@@ -36,6 +37,19 @@ impl Weights {
         self.model_weights = [w0new, w1new];
         self.normalized_weight = compute_normalized_weight(self.model_weights);
     }
+    // forgets what the weights learned, keeping the mixing parameter
+    pub fn reset(&mut self) {
+        *self = Weights {
+            mixing_param: self.mixing_param,
+            ..Weights::new()
+        };
+    }
+    // cm_share sixteenths of the weight on the first model, at the magnitude converged weights reach
+    pub fn warm_start(&mut self, cm_share: u8) {
+        debug_assert!(cm_share > 0 && cm_share < 16);
+        self.model_weights = [i32::from(cm_share) << 16, i32::from(16 - cm_share) << 16];
+        self.normalized_weight = compute_normalized_weight(self.model_weights);
+    }
     #[inline(always)]
     pub fn set_mixing_param(&mut self, param: u8) {
         self.mixing_param = param;
@@ -48,6 +62,160 @@ impl Weights {
     pub fn norm_weight(&self) -> i16 {
         self.normalized_weight
     }
+    pub fn model_weights(&self) -> [i32;2] {
+        self.model_weights
+    }
+    pub fn mixing_param(&self) -> u8 {
+        self.mixing_param
+    }
+}
+
+// Block types past this many share the mixer of the last
+pub const MIXER_BLOCK_TYPES: usize = 16;
+
+// The literal mixers of each block type, for EXTENDED_FEATURE_BLOCK_TYPE_MIXER streams: the weights
+// in use are put away at a literal block switch and those of the new block type taken out.  A block
+// type switched to for the first time starts from the weights of the one before it.
+#[derive(Clone, Copy)]
+pub struct BlockTypeWeights {
+    weights: [[Weights;2]; MIXER_BLOCK_TYPES],
+    seen: u16, // a bit per slot of weights holding some
+    enabled: bool,
+}
+
+impl Default for BlockTypeWeights {
+    fn default() -> Self {
+        BlockTypeWeights {
+            weights: [[Weights::default(); 2]; MIXER_BLOCK_TYPES],
+            seen: 0,
+            enabled: false,
+        }
+    }
+}
+
+impl BlockTypeWeights {
+    pub fn enable(&mut self) {
+        self.enabled = true;
+    }
+    #[inline(always)]
+    fn slot(block_type: u32) -> usize {
+        core::cmp::min(block_type, MIXER_BLOCK_TYPES as u32 - 1) as usize
+    }
+    pub fn switch(&mut self, old_block_type: u32, new_block_type: u32, active: &mut [Weights;2]) {
+        if !self.enabled {
+            return;
+        }
+        let (old, new) = (Self::slot(old_block_type), Self::slot(new_block_type));
+        self.weights[old] = *active;
+        self.seen |= 1 << old;
+        if self.seen & (1 << new) != 0 {
+            *active = self.weights[new];
+        }
+    }
+    // a warm start restarts every block type from the weights in use
+    pub fn forget(&mut self) {
+        self.seen = 0;
+    }
+    pub fn set_mixing_param(&mut self, param: u8) {
+        for weights in self.weights.iter_mut() {
+            weights[0].set_mixing_param(param);
+            weights[1].set_mixing_param(param);
+        }
+    }
+}
+
+// The literal mixer as it stood when a literal block ended, for offline analysis of whether the
+// weights converge.  Encoder and decoder evolve the weights identically, so either may record them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MixerSnapshot {
+    // literal blocks before this one in the stream
+    pub block_index: u64,
+    pub block_type: u32,
+    pub mixing_param: u8,
+    // indexed by nibble: [low, high], each holding the weights of the two mixed models
+    pub model_weights: [[i32;2];2],
+    pub norm_weights: [i16;2],
+}
+
+impl MixerSnapshot {
+    pub fn new(block_index: u64, block_type: u32, weights: &[Weights;2]) -> Self {
+        MixerSnapshot {
+            block_index,
+            block_type,
+            mixing_param: weights[0].mixing_param(),
+            model_weights: [weights[0].model_weights(), weights[1].model_weights()],
+            norm_weights: [weights[0].norm_weight(), weights[1].norm_weight()],
+        }
+    }
+}
+
+pub const MIXER_SNAPSHOT_QUEUE_SIZE: usize = 64;
+
+// Snapshots taken at literal block switches, waiting for the caller to take them.
+// When the caller falls behind, the newest snapshots are dropped and counted.
+#[derive(Clone, Copy)]
+pub struct MixerSnapshotQueue {
+    queue: [MixerSnapshot; MIXER_SNAPSHOT_QUEUE_SIZE],
+    start: usize,
+    len: usize,
+    blocks_ended: u64,
+    dropped: u64,
+}
+
+impl Default for MixerSnapshotQueue {
+    fn default() -> Self {
+        MixerSnapshotQueue {
+            queue: [MixerSnapshot::default(); MIXER_SNAPSHOT_QUEUE_SIZE],
+            start: 0,
+            len: 0,
+            blocks_ended: 0,
+            dropped: 0,
+        }
+    }
+}
+
+impl MixerSnapshotQueue {
+    pub fn observe_block_end(&mut self, block_type: u32, weights: &[Weights;2]) {
+        let snapshot = MixerSnapshot::new(self.blocks_ended, block_type, weights);
+        self.blocks_ended = self.blocks_ended.wrapping_add(1);
+        self.push(snapshot);
+    }
+    fn push(&mut self, snapshot: MixerSnapshot) {
+        if self.len == MIXER_SNAPSHOT_QUEUE_SIZE {
+            self.dropped = self.dropped.wrapping_add(1);
+            return;
+        }
+        self.queue[(self.start + self.len) % MIXER_SNAPSHOT_QUEUE_SIZE] = snapshot;
+        self.len += 1;
+    }
+    // hands the waiting snapshots, and the count of those dropped, on to a queue the caller reads
+    pub fn move_into(&mut self, other: &mut MixerSnapshotQueue) {
+        while self.len != 0 {
+            other.push(self.queue[self.start]);
+            self.start = (self.start + 1) % MIXER_SNAPSHOT_QUEUE_SIZE;
+            self.len -= 1;
+        }
+        other.dropped = other.dropped.wrapping_add(self.dropped);
+        other.blocks_ended = self.blocks_ended;
+        self.dropped = 0;
+    }
+    // the block currently being coded, as a snapshot of where its weights stand so far
+    pub fn current(&self, block_type: u32, weights: &[Weights;2]) -> MixerSnapshot {
+        MixerSnapshot::new(self.blocks_ended, block_type, weights)
+    }
+    // moves the oldest waiting snapshots into out and returns how many
+    pub fn take(&mut self, out: &mut [MixerSnapshot]) -> usize {
+        let count = core::cmp::min(out.len(), self.len);
+        for item in out[..count].iter_mut() {
+            *item = self.queue[self.start];
+            self.start = (self.start + 1) % MIXER_SNAPSHOT_QUEUE_SIZE;
+        }
+        self.len -= count;
+        count
+    }
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
 }
 
 #[inline(always)]
@@ -131,3 +299,144 @@ fn compute_new_weight(probs: [Prob; 2],
     //print!("{} -> {} due to {:?} vs {}\n", wi as f64 / (weights[0] + weights[1]) as f64, (wi + new_weight_adj) as f64 /(weights[0] as i64 + new_weight_adj as i64 + weights[1] as i64) as f64, probs[index], weighted_prob);
     core::cmp::max(1,wi.wrapping_add(new_weight_adj) as i32)
 }
+
+#[cfg(all(test, feature="std", feature="encoder", feature="decoder"))]
+mod test {
+    use std::vec::Vec;
+    use conformance::{conformance_commands, conformance_expected_output};
+    use super::{BlockTypeWeights, Weights};
+    use interface::{
+        Command,
+        MixerWarmStart,
+        Decompressor,
+        DivansCompressorOptions,
+        CompressorOption,
+        DivansResult,
+        ErrMsg,
+        HEADER_LENGTH,
+        HEADER_EXTENDED_FEATURE_OFFSET,
+        EXTENDED_FEATURE_BLOCK_TYPE_MIXER,
+    };
+    use super::MixerSnapshot;
+    use test_support::{new_decompressor, encode_with_options, compress_hybrid, decode, decoded_options, decode_with, DecodeConfig};
+
+    fn decode_mixer_snapshots(stream: &[u8], multithread: bool) -> Vec<MixerSnapshot> {
+        let mut decompressor = new_decompressor(false, multithread);
+        let mut ret = Vec::<MixerSnapshot>::new();
+        let mut snapshots = [MixerSnapshot::default(); 2];
+        let mut buffer = [0u8; 64];
+        let mut input_offset = 0usize;
+        loop {
+            let mut output_offset = 0usize;
+            let res = decompressor.decode(stream, &mut input_offset, &mut buffer[..], &mut output_offset);
+            // a small out buffer just takes several calls
+            loop {
+                let count = decompressor.take_mixer_snapshots(&mut snapshots[..]);
+                if count == 0 {
+                    break;
+                }
+                ret.extend_from_slice(&snapshots[..count]);
+            }
+            match res {
+                DivansResult::Success => break,
+                DivansResult::NeedsMoreOutput => {},
+                res => panic!("{:?}", res),
+            }
+        }
+        assert_eq!(decompressor.mixer_snapshots_dropped(), 0);
+        ret.push(decompressor.mixer_snapshot());
+        decompressor.free();
+        ret
+    }
+
+    #[test]
+    fn test_mixer_snapshots() {
+        let commands = conformance_commands("mixed");
+        let mut block_types = vec![0u32];
+        for command in commands.iter() {
+            if let Command::BlockSwitchLiteral(bs) = *command {
+                block_types.push(bs.block_type());
+            }
+        }
+        let mut opts = DivansCompressorOptions::default();
+        opts.dynamic_context_mixing = Some(2);
+        let stream = encode_with_options(&commands[..], opts).unwrap();
+        let serial = decode_mixer_snapshots(&stream[..], false);
+        assert_eq!(serial.len(), block_types.len());
+        for (index, (snapshot, block_type)) in serial.iter().zip(block_types.iter()).enumerate() {
+            assert_eq!(snapshot.block_index, index as u64);
+            assert_eq!(snapshot.block_type, *block_type);
+            assert_eq!(snapshot.mixing_param, 2);
+        }
+        // the literals moved the weights off where they started
+        assert!(serial.iter().any(|snapshot| snapshot.model_weights != [[1, 1], [1, 1]]));
+        assert_eq!(decode_mixer_snapshots(&stream[..], true), serial);
+    }
+
+    #[test]
+    fn test_mixer_warm_start() {
+        let commands = conformance_commands("mixed");
+        let expected = conformance_expected_output(&commands[..]).unwrap();
+        let mut opts = DivansCompressorOptions::default();
+        opts.dynamic_context_mixing = Some(2);
+        let cold = encode_with_options(&commands[..], opts).unwrap();
+        opts.mixer_warm_start = Some(MixerWarmStart::new(12, 6).unwrap());
+        let warm = encode_with_options(&commands[..], opts).unwrap();
+        for multithread in [false, true].iter() {
+            assert_eq!(decode(&warm[..]).unwrap(), expected);
+            let options = decoded_options(&warm[..], *multithread).unwrap();
+            assert_eq!(options.mixer_warm_start, opts.mixer_warm_start);
+            assert_eq!(decoded_options(&cold[..], *multithread).unwrap().mixer_warm_start, None);
+        }
+        // the mixers start elsewhere, so they end their first block elsewhere too
+        assert!(decode_mixer_snapshots(&warm[..], false)[0] != decode_mixer_snapshots(&cold[..], false)[0]);
+        assert_eq!(MixerWarmStart::new(0, 0), Err(ErrMsg::OptionOutOfRange(CompressorOption::MixerWarmStart)));
+        assert_eq!(MixerWarmStart::new(16, 1), Err(ErrMsg::OptionOutOfRange(CompressorOption::MixerWarmStart)));
+        opts.dynamic_context_mixing = Some(1);
+        assert_eq!(opts.validate(), Err(ErrMsg::ConflictingOptions(CompressorOption::MixerWarmStart,
+                                                                   CompressorOption::DynamicContextMixing)));
+    }
+
+    #[test]
+    fn test_block_type_mixer() {
+        let mut weights = BlockTypeWeights::default();
+        let mut active = [Weights::default(), Weights::default()];
+        active[0].set_mixing_param(2);
+        active[1].set_mixing_param(2);
+        weights.switch(1, 2, &mut active);
+        assert_eq!(active[0].model_weights(), [1, 1]); // nothing kept while disabled
+        weights.enable();
+        active[0].update([1000, 30000], 20000);
+        let first = active[0].model_weights();
+        weights.switch(1, 2, &mut active);
+        assert_eq!(active[0].model_weights(), first); // a new block type starts where the last stood
+        active[0].update([30000, 1000], 20000);
+        let second = active[0].model_weights();
+        weights.switch(2, 1, &mut active);
+        assert_eq!(active[0].model_weights(), first);
+        weights.switch(1, 2, &mut active);
+        assert_eq!(active[0].model_weights(), second);
+
+        // text alternating with utf-8 of another script, a literal block type for each
+        let text = include_bytes!("../../testdata/alice29");
+        let other = include_bytes!("../../testdata/random_then_unicode");
+        let unicode = &other[other.len() - 65536..];
+        let mut input = Vec::<u8>::new();
+        for part in 0..16 {
+            input.extend(&text[part * 4096..(part + 1) * 4096]);
+            input.extend(&unicode[part * 4096..(part + 1) * 4096]);
+        }
+        let mut opts = DivansCompressorOptions::default();
+        opts.dynamic_context_mixing = Some(2);
+        opts.quality = Some(10);
+        let shared = compress_hybrid(&input[..], opts);
+        opts.extended_stream_features = EXTENDED_FEATURE_BLOCK_TYPE_MIXER;
+        let per_type = compress_hybrid(&input[..], opts);
+        assert_eq!(per_type[HEADER_EXTENDED_FEATURE_OFFSET], EXTENDED_FEATURE_BLOCK_TYPE_MIXER);
+        assert!(per_type[HEADER_LENGTH..] != shared[HEADER_LENGTH..]);
+        for multithread in [false, true].iter() {
+            assert_eq!(decode_with(&per_type[..], DecodeConfig::new(*multithread), |_| {}).into_output().unwrap(), &input[..]);
+        }
+        assert_eq!(decode_mixer_snapshots(&per_type[..], true), decode_mixer_snapshots(&per_type[..], false));
+    }
+}
@@ -0,0 +1,408 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Generates a matrix of valid divans streams, with the bytes they must decode to,
+// for people implementing the format in other languages.
+// Each vector is one command scenario encoded with one stride, literal speed and prior depth setting.
+// The expected output is produced by replaying the commands through the raw recoder,
+// independently of the entropy coder, so the vectors also check the reference decoder.
+#![cfg(feature="std")]
+use std::vec::Vec;
+use std::string::String;
+use alloc::{SliceWrapper, SliceWrapperMut};
+use alloc_stdlib::HeapAlloc;
+use brotli::dictionary::{kBrotliDictionary, kBrotliDictionaryOffsetsByLength, kBrotliMaxDictionaryWordLength};
+use brotli::TransformDictionaryWord;
+use cmd_to_raw::DivansRecodeState;
+use divans_compressor::DivansCompressorFactoryStruct;
+use interface::{
+    BlockSwitch,
+    Command,
+    Compressor,
+    CopyCommand,
+    DictCommand,
+    DivansCompressorFactory,
+    DivansCompressorOptions,
+    DivansOutputResult,
+    ErrMsg,
+    FeatureFlagSliceType,
+    LiteralBlockSwitch,
+    LiteralCommand,
+    LiteralPredictionModeNibble,
+    PredictionModeContextMap,
+    DefaultCDF16,
+};
+use codec::StrideSelection;
+use probability::Speed;
+
+pub const CONFORMANCE_WINDOW_SIZE: i32 = 16;
+
+pub static CONFORMANCE_SCENARIOS: [&'static str; 8] = [
+    "literal",
+    "copy",
+    "dict",
+    "block_switch_literal",
+    "block_switch_command",
+    "block_switch_distance",
+    "prediction_mode",
+    "mixed",
+];
+
+pub static CONFORMANCE_STRIDES: [StrideSelection; 10] = [
+    StrideSelection::PriorDisabled,
+    StrideSelection::Stride1,
+    StrideSelection::Stride2,
+    StrideSelection::Stride3,
+    StrideSelection::Stride4,
+    StrideSelection::Stride5,
+    StrideSelection::Stride6,
+    StrideSelection::Stride7,
+    StrideSelection::Stride8,
+    StrideSelection::UseBrotliRec,
+];
+
+// None leaves the encoder defaults in place
+pub static CONFORMANCE_SPEEDS: [(&'static str, Option<Speed>); 3] = [
+    ("default", None),
+    ("mud", Some(Speed::MUD)),
+    ("rocket", Some(Speed::ROCKET)),
+];
+
+pub static CONFORMANCE_PRIOR_DEPTHS: [u8; 3] = [0, 3, 7];
+
+pub struct ConformanceVector {
+    pub name: String,
+    pub scenario: &'static str,
+    pub stride: StrideSelection,
+    pub literal_adaptation: Option<[Speed; 4]>,
+    pub prior_depth: u8,
+    pub compressed: Vec<u8>,
+    pub expected: Vec<u8>,
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct ConformanceSlice(pub Vec<u8>);
+
+impl SliceWrapper<u8> for ConformanceSlice {
+    fn slice(&self) -> &[u8] {
+        &self.0[..]
+    }
+}
+
+impl SliceWrapperMut<u8> for ConformanceSlice {
+    fn slice_mut(&mut self) -> &mut [u8] {
+        &mut self.0[..]
+    }
+}
+
+fn lit(data: &[u8], high_entropy: bool) -> Command<ConformanceSlice> {
+    Command::Literal(LiteralCommand::<ConformanceSlice> {
+        data: ConformanceSlice(data.to_vec()),
+        prob: FeatureFlagSliceType::<ConformanceSlice>::default(),
+        high_entropy: high_entropy,
+    })
+}
+
+fn copy(distance: u32, num_bytes: u32) -> Command<ConformanceSlice> {
+    Command::Copy(CopyCommand{distance: distance, num_bytes: num_bytes})
+}
+
+fn dict(word_size: u8, word_id: u32, transform: u8) -> Command<ConformanceSlice> {
+    let offset = kBrotliDictionaryOffsetsByLength[word_size as usize] as usize + word_id as usize * word_size as usize;
+    let mut transformed = [0u8; kBrotliMaxDictionaryWordLength as usize + 13];
+    let final_size = TransformDictionaryWord(&mut transformed[..],
+                                             &kBrotliDictionary[offset..offset + word_size as usize],
+                                             i32::from(word_size),
+                                             i32::from(transform));
+    Command::Dict(DictCommand {
+        word_size: word_size,
+        transform: transform,
+        final_size: final_size as u8,
+        empty: 0,
+        word_id: word_id,
+    })
+}
+
+fn prediction_mode(mode: LiteralPredictionModeNibble, num_block_types: usize) -> Command<ConformanceSlice> {
+    let mut ret = PredictionModeContextMap::<ConformanceSlice> {
+        literal_context_map: ConformanceSlice((0..64 * num_block_types).map(|x| (x % 7) as u8).collect()),
+        predmode_speed_and_distance_context_map: ConformanceSlice(
+            vec![0u8; PredictionModeContextMap::<ConformanceSlice>::size_of_combined_array(4 * num_block_types)]),
+    };
+    ret.set_literal_prediction_mode(mode);
+    ret.set_context_map_speed([(32, 4096), (32, 4096)]);
+    ret.set_stride_context_speed([(16, 8192), (16, 8192)]);
+    ret.set_combined_stride_context_speed([(48, 16384), (48, 16384)]);
+    for (index, val) in ret.distance_context_map_mut().iter_mut().enumerate() {
+        *val = (index & 1) as u8;
+    }
+    Command::PredictionMode(ret)
+}
+
+fn pseudorandom(len: usize, seed: u32) -> Vec<u8> {
+    let mut state = seed;
+    (0..len).map(|_| {
+        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        (state >> 16) as u8
+    }).collect()
+}
+
+const TEXT: &'static [u8] = b"It was the best of times, it was the worst of times, it was the age of wisdom, \
+it was the age of foolishness, it was the epoch of belief, it was the epoch of incredulity.";
+
+pub fn conformance_commands(scenario: &str) -> Vec<Command<ConformanceSlice>> {
+    match scenario {
+        // every literal length bucket: short mnemonics, the 15-25 range, long mantissas and raw bytes
+        "literal" => vec![lit(b"A", false),
+                          lit(&TEXT[..14], false),
+                          lit(&TEXT[14..29], false),
+                          lit(&TEXT[29..54], false),
+                          lit(&TEXT[..], false),
+                          lit(&pseudorandom(300, 1)[..], true),
+                          lit(&pseudorandom(1000, 2)[..], false)],
+        "copy" => vec![lit(&TEXT[..64], false),
+                       copy(1, 3),
+                       copy(64, 64),
+                       copy(7, 200),
+                       lit(b"xyz", false),
+                       copy(3, 1),
+                       copy(130, 2),
+                       copy(331, 4000)],
+        "dict" => vec![lit(&TEXT[..20], false),
+                       dict(4, 0, 0),
+                       dict(4, 1023, 0),
+                       dict(7, 100, 9),
+                       lit(b" ", false),
+                       dict(10, 17, 1),
+                       copy(5, 5),
+                       dict(24, 0, 120)],
+        "block_switch_literal" => vec![lit(&TEXT[..40], false),
+                                       Command::BlockSwitchLiteral(LiteralBlockSwitch::new(1, 0)),
+                                       lit(&pseudorandom(64, 3)[..], false),
+                                       Command::BlockSwitchLiteral(LiteralBlockSwitch::new(2, 3)),
+                                       lit(&TEXT[40..], false),
+                                       Command::BlockSwitchLiteral(LiteralBlockSwitch::new(0, 8)),
+                                       lit(&TEXT[..30], false)],
+        "block_switch_command" => vec![lit(&TEXT[..40], false),
+                                       Command::BlockSwitchCommand(BlockSwitch::new(1)),
+                                       copy(40, 40),
+                                       Command::BlockSwitchCommand(BlockSwitch::new(2)),
+                                       lit(b"abc", false),
+                                       copy(3, 9),
+                                       Command::BlockSwitchCommand(BlockSwitch::new(0)),
+                                       copy(52, 17)],
+        "block_switch_distance" => vec![lit(&TEXT[..40], false),
+                                        Command::BlockSwitchDistance(BlockSwitch::new(1)),
+                                        copy(40, 40),
+                                        copy(13, 13),
+                                        Command::BlockSwitchDistance(BlockSwitch::new(2)),
+                                        copy(80, 20),
+                                        Command::BlockSwitchDistance(BlockSwitch::new(0)),
+                                        copy(1, 10)],
+        "prediction_mode" => vec![prediction_mode(LiteralPredictionModeNibble::utf8(), 1),
+                                  lit(&TEXT[..], false),
+                                  prediction_mode(LiteralPredictionModeNibble::signed(), 1),
+                                  lit(&pseudorandom(100, 4)[..], false),
+                                  prediction_mode(LiteralPredictionModeNibble::lsb6(), 1),
+                                  lit(&TEXT[..50], false),
+                                  prediction_mode(LiteralPredictionModeNibble::msb6(), 1),
+                                  lit(&pseudorandom(100, 5)[..], false)],
+        "mixed" => vec![prediction_mode(LiteralPredictionModeNibble::utf8(), 3),
+                        lit(&TEXT[..], false),
+                        copy(50, 30),
+                        Command::BlockSwitchLiteral(LiteralBlockSwitch::new(1, 1)),
+                        dict(5, 40, 0),
+                        Command::BlockSwitchCommand(BlockSwitch::new(1)),
+                        lit(&pseudorandom(40, 6)[..], true),
+                        Command::BlockSwitchDistance(BlockSwitch::new(1)),
+                        copy(90, 90),
+                        Command::BlockSwitchLiteral(LiteralBlockSwitch::new(2, 0)),
+                        dict(8, 3, 3),
+                        lit(&TEXT[..10], false)],
+        _ => Vec::new(),
+    }
+}
+
+// replays the commands without any entropy coding to get the bytes a decoder must produce
+pub fn conformance_expected_output(commands: &[Command<ConformanceSlice>]) -> Result<Vec<u8>, ErrMsg> {
+    let mut recoder = DivansRecodeState::<ConformanceSlice>::new(
+        ConformanceSlice(vec![0u8; 1 << CONFORMANCE_WINDOW_SIZE]));
+    let mut ret = Vec::<u8>::new();
+    let mut buffer = [0u8; 4096];
+    for cmd in commands.iter() {
+        loop {
+            let mut offset = 0usize;
+            let res = recoder.encode_cmd(cmd, &mut buffer[..], &mut offset);
+            ret.extend(&buffer[..offset]);
+            match res {
+                DivansOutputResult::Success => break,
+                DivansOutputResult::NeedsMoreOutput => {},
+                DivansOutputResult::Failure(m) => return Err(m),
+            }
+        }
+    }
+    loop {
+        let mut offset = 0usize;
+        let res = recoder.flush(&mut buffer[..], &mut offset);
+        ret.extend(&buffer[..offset]);
+        match res {
+            DivansOutputResult::Success => return Ok(ret),
+            DivansOutputResult::NeedsMoreOutput => {},
+            DivansOutputResult::Failure(m) => return Err(m),
+        }
+    }
+}
+
+pub fn conformance_encode(commands: &[Command<ConformanceSlice>],
+                          stride: StrideSelection,
+                          literal_adaptation: Option<[Speed; 4]>,
+                          prior_depth: u8) -> Result<Vec<u8>, ErrMsg> {
+    let mut opts = DivansCompressorOptions::default();
+    opts.window_size = Some(CONFORMANCE_WINDOW_SIZE);
+    opts.force_stride_value = stride;
+    opts.literal_adaptation = literal_adaptation;
+    opts.prior_depth = Some(prior_depth);
+    let mut compressor = DivansCompressorFactoryStruct::<HeapAlloc<u8>, HeapAlloc<DefaultCDF16>>::new(
+        HeapAlloc::<u8>::new(0),
+        HeapAlloc::<u32>::new(0),
+        HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+        opts,
+        ());
+    let mut ret = Vec::<u8>::new();
+    let mut buffer = [0u8; 4096];
+    let mut command_offset = 0usize;
+    let mut result = Ok(());
+    while result.is_ok() && command_offset != commands.len() {
+        let mut offset = 0usize;
+        let res = compressor.encode_commands(commands, &mut command_offset, &mut buffer[..], &mut offset);
+        ret.extend(&buffer[..offset]);
+        match res {
+            DivansOutputResult::Success | DivansOutputResult::NeedsMoreOutput => {},
+            DivansOutputResult::Failure(m) => result = Err(m),
+        }
+    }
+    while result.is_ok() {
+        let mut offset = 0usize;
+        let res = compressor.flush(&mut buffer[..], &mut offset);
+        ret.extend(&buffer[..offset]);
+        match res {
+            DivansOutputResult::Success => break,
+            DivansOutputResult::NeedsMoreOutput => {},
+            DivansOutputResult::Failure(m) => result = Err(m),
+        }
+    }
+    compressor.free();
+    result.map(|_| ret)
+}
+
+fn stride_name(stride: StrideSelection) -> &'static str {
+    match stride {
+        StrideSelection::PriorDisabled => "noprior",
+        StrideSelection::Stride1 => "stride1",
+        StrideSelection::Stride2 => "stride2",
+        StrideSelection::Stride3 => "stride3",
+        StrideSelection::Stride4 => "stride4",
+        StrideSelection::Stride5 => "stride5",
+        StrideSelection::Stride6 => "stride6",
+        StrideSelection::Stride7 => "stride7",
+        StrideSelection::Stride8 => "stride8",
+        StrideSelection::UseBrotliRec => "brotlistride",
+    }
+}
+
+// every combination of CONFORMANCE_SCENARIOS, CONFORMANCE_STRIDES, CONFORMANCE_SPEEDS and CONFORMANCE_PRIOR_DEPTHS
+pub fn generate_conformance_vectors() -> Result<Vec<ConformanceVector>, ErrMsg> {
+    let mut ret = Vec::<ConformanceVector>::new();
+    for scenario in CONFORMANCE_SCENARIOS.iter() {
+        let commands = conformance_commands(scenario);
+        let expected = conformance_expected_output(&commands[..])?;
+        for stride in CONFORMANCE_STRIDES.iter() {
+            for &(speed_name, speed) in CONFORMANCE_SPEEDS.iter() {
+                let literal_adaptation = speed.map(|s| [s, s, s, s]);
+                for prior_depth in CONFORMANCE_PRIOR_DEPTHS.iter() {
+                    ret.push(ConformanceVector {
+                        name: format!("{}-{}-{}-depth{}", scenario, stride_name(*stride), speed_name, prior_depth),
+                        scenario: scenario,
+                        stride: *stride,
+                        literal_adaptation: literal_adaptation,
+                        prior_depth: *prior_depth,
+                        compressed: conformance_encode(&commands[..], *stride, literal_adaptation, *prior_depth)?,
+                        expected: expected.clone(),
+                    });
+                }
+            }
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use std::vec::Vec;
+    use alloc_stdlib::HeapAlloc;
+    use divans_decompressor::{DivansDecompressorFactory, DivansDecompressorFactoryStruct, StaticCommand};
+    use interface::{Decompressor, DivansResult, Nop, DefaultCDF16};
+    use codec::StrideSelection;
+    use super::{conformance_encode, conformance_expected_output, copy, lit};
+    use super::{generate_conformance_vectors, CONFORMANCE_SCENARIOS, CONFORMANCE_STRIDES, CONFORMANCE_SPEEDS, CONFORMANCE_PRIOR_DEPTHS};
+
+    fn decode(stream: &[u8]) -> Vec<u8> {
+        let mut decompressor = DivansDecompressorFactoryStruct::<HeapAlloc<u8>,
+                                                                 HeapAlloc<DefaultCDF16>,
+                                                                 HeapAlloc<StaticCommand>>::new(
+            HeapAlloc::<u8>::new(0),
+            HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+            HeapAlloc::<StaticCommand>::new(StaticCommand::nop()),
+            false,
+            false);
+        let mut ret = Vec::<u8>::new();
+        let mut buffer = [0u8; 4096];
+        let mut input_offset = 0usize;
+        loop {
+            let mut output_offset = 0usize;
+            let res = decompressor.decode(stream, &mut input_offset, &mut buffer[..], &mut output_offset);
+            ret.extend(&buffer[..output_offset]);
+            match res {
+                DivansResult::Success => break,
+                DivansResult::NeedsMoreOutput => {},
+                DivansResult::NeedsMoreInput => panic!("truncated conformance vector"),
+                DivansResult::Failure(m) => panic!("{:?}", m),
+            }
+        }
+        decompressor.free();
+        ret
+    }
+
+    #[test]
+    fn test_conformance_vectors_decode() {
+        let vectors = generate_conformance_vectors().unwrap();
+        assert_eq!(vectors.len(), CONFORMANCE_SCENARIOS.len() * CONFORMANCE_STRIDES.len()
+                   * CONFORMANCE_SPEEDS.len() * CONFORMANCE_PRIOR_DEPTHS.len());
+        for vector in vectors.iter() {
+            assert!(vector.expected.len() != 0, "{}", vector.name);
+            assert_eq!(decode(&vector.compressed[..]), vector.expected, "{}", vector.name);
+        }
+    }
+
+    #[test]
+    fn test_copy_longer_than_metablock() {
+        // the copy count coding reaches 32 bit lengths, so a single copy may span many metablocks
+        let commands = [lit(b"abc", false), copy(3, (1 << 24) + 12345)];
+        let expected = conformance_expected_output(&commands[..]).unwrap();
+        assert_eq!(expected.len(), (1 << 24) + 12348);
+        let compressed = conformance_encode(&commands[..], StrideSelection::UseBrotliRec, None, 0).unwrap();
+        assert!(compressed.len() < 128, "{}", compressed.len());
+        assert!(decode(&compressed[..]) == expected);
+    }
+}
@@ -0,0 +1,243 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Pull-model decoding to IR: rather than the bytes a stream decodes to, a DecodedCommandIter
+// yields the commands themselves, for transcoders and analyzers.  Compressed input is pulled
+// through a refill closure whenever the decoder has used up what it was given.
+// next_borrowed lends each command instead, out of buffers the decoder recycles on the next pull.
+use core::marker::PhantomData;
+use alloc::{Allocator, SliceWrapper};
+use slice_util::{AllocatedMemoryPrefix, SliceReference};
+use interface::{Command, Decompressor, DivansResult, ErrMsg, FeatureFlagSliceType, LiteralCommand, PredictionModeContextMap};
+
+// a decompressor able to hand over the commands it decodes
+pub trait CommandTap<AllocU8:Allocator<u8>> {
+    // call before the first decode: from then on decode pauses with NeedsMoreOutput
+    // whenever a decoded command waits to be taken
+    fn tap_commands(&mut self);
+    // the next decoded command in stream order, if one is waiting
+    fn take_command(&mut self) -> Option<Command<AllocatedMemoryPrefix<u8, AllocU8>>>;
+    // returns a taken command's buffers to the decompressor's allocator
+    fn free_command(&mut self, cmd: Command<AllocatedMemoryPrefix<u8, AllocU8>>);
+}
+
+// bytes of decoded output produced per decode call; the iterator discards them
+const SCRATCH_OUTPUT_SIZE: usize = 1024;
+
+pub struct DecodedCommandIter<'a,
+                              AllocU8:Allocator<u8>,
+                              Dec:Decompressor+CommandTap<AllocU8>,
+                              Refill:FnMut(&mut [u8]) -> usize> {
+    decompressor: Dec,
+    // fills the buffer it is given with compressed bytes and returns how many; 0 at the end of input
+    refill: Refill,
+    input: &'a mut [u8],
+    input_offset: usize,
+    input_len: usize,
+    input_exhausted: bool,
+    done: bool,
+    // the command the last next_borrowed lent out, returned to the decompressor on the next pull
+    lent: Option<Command<AllocatedMemoryPrefix<u8, AllocU8>>>,
+    p: PhantomData<AllocU8>,
+}
+
+fn slice_reference(slice: &[u8]) -> SliceReference<'_, u8> {
+    SliceReference::new(slice, 0, slice.len())
+}
+
+// a view of cmd whose literal and context map bytes are those of cmd
+fn borrow_command<SliceType:SliceWrapper<u8>>(cmd: &Command<SliceType>) -> Command<SliceReference<'_, u8>> {
+    match *cmd {
+        Command::Literal(ref lit) => Command::Literal(LiteralCommand {
+            data: slice_reference(lit.data.slice()),
+            prob: FeatureFlagSliceType::default(), // the decoder never fills in literal probabilities
+            high_entropy: lit.high_entropy,
+        }),
+        Command::PredictionMode(ref pm) => Command::PredictionMode(PredictionModeContextMap {
+            literal_context_map: slice_reference(pm.literal_context_map.slice()),
+            predmode_speed_and_distance_context_map: slice_reference(pm.predmode_speed_and_distance_context_map.slice()),
+        }),
+        Command::Copy(copy) => Command::Copy(copy),
+        Command::Dict(dict) => Command::Dict(dict),
+        Command::BlockSwitchCommand(bs) => Command::BlockSwitchCommand(bs),
+        Command::BlockSwitchLiteral(bs) => Command::BlockSwitchLiteral(bs),
+        Command::BlockSwitchDistance(bs) => Command::BlockSwitchDistance(bs),
+    }
+}
+
+impl<'a,
+     AllocU8:Allocator<u8>,
+     Dec:Decompressor+CommandTap<AllocU8>,
+     Refill:FnMut(&mut [u8]) -> usize> DecodedCommandIter<'a, AllocU8, Dec, Refill> {
+    // decompressor must not have decoded anything yet; input_buffer is where refill writes
+    pub fn new(mut decompressor: Dec, input_buffer: &'a mut [u8], refill: Refill) -> Self {
+        assert!(!input_buffer.is_empty());
+        decompressor.tap_commands();
+        DecodedCommandIter {
+            decompressor,
+            refill,
+            input: input_buffer,
+            input_offset: 0,
+            input_len: 0,
+            input_exhausted: false,
+            done: false,
+            lent: None,
+            p: PhantomData,
+        }
+    }
+    // returns a yielded command's buffers to the decompressor's allocator
+    pub fn free_command(&mut self, cmd: Command<AllocatedMemoryPrefix<u8, AllocU8>>) {
+        self.decompressor.free_command(cmd)
+    }
+    // Like next, but lends the command rather than handing it over: it stays valid until the next
+    // pull, which returns its buffers to the decompressor's allocator cache for the commands after it.
+    // Nothing is copied out and the caller has nothing to free.
+    pub fn next_borrowed(&mut self) -> Option<Result<Command<SliceReference<'_, u8>>, ErrMsg>> {
+        if let Some(cmd) = self.lent.take() {
+            self.decompressor.free_command(cmd);
+        }
+        match self.next() {
+            Some(Ok(cmd)) => {
+                self.lent = Some(cmd);
+                self.lent.as_ref().map(|cmd| Ok(borrow_command(cmd)))
+            },
+            Some(Err(m)) => Some(Err(m)),
+            None => None,
+        }
+    }
+    pub fn decompressor(&mut self) -> &mut Dec {
+        &mut self.decompressor
+    }
+    pub fn into_inner(mut self) -> Dec {
+        if let Some(cmd) = self.lent.take() {
+            self.decompressor.free_command(cmd);
+        }
+        self.decompressor
+    }
+}
+
+impl<'a,
+     AllocU8:Allocator<u8>,
+     Dec:Decompressor+CommandTap<AllocU8>,
+     Refill:FnMut(&mut [u8]) -> usize> Iterator for DecodedCommandIter<'a, AllocU8, Dec, Refill> {
+    type Item = Result<Command<AllocatedMemoryPrefix<u8, AllocU8>>, ErrMsg>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut scratch = [0u8; SCRATCH_OUTPUT_SIZE];
+        loop {
+            if let Some(cmd) = self.decompressor.take_command() {
+                return Some(Ok(cmd));
+            }
+            if self.done {
+                return None;
+            }
+            if self.input_offset == self.input_len && !self.input_exhausted {
+                self.input_offset = 0;
+                self.input_len = (self.refill)(self.input);
+                self.input_exhausted = self.input_len == 0;
+            }
+            let mut scratch_offset = 0usize;
+            match self.decompressor.decode(&self.input[..self.input_len], &mut self.input_offset,
+                                           &mut scratch[..], &mut scratch_offset) {
+                DivansResult::Success => self.done = true,
+                DivansResult::NeedsMoreOutput => {},
+                DivansResult::NeedsMoreInput => if self.input_exhausted {
+                    self.done = true;
+                    return Some(Err(ErrMsg::TruncatedInput));
+                },
+                DivansResult::Failure(m) => {
+                    self.done = true;
+                    return Some(Err(m));
+                },
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature="std", feature="encoder"))]
+mod test {
+    use std::vec::Vec;
+    use conformance::{conformance_commands, conformance_expected_output};
+    use super::DecodedCommandIter;
+    use interface::{
+        DivansCompressorOptions,
+        ErrMsg,
+    };
+    use test_support::{new_decompressor, encode_with_options, decode, assert_same_command};
+
+    #[test]
+    fn test_decoded_command_iter() {
+        let cmds = conformance_commands("mixed");
+        let stream = encode_with_options(&cmds[..], DivansCompressorOptions::default()).unwrap();
+        let decompressor = new_decompressor(false, true);
+        // the compressed stream trickles in 3 bytes at a time
+        let mut remaining = &stream[..];
+        let mut input_buffer = [0u8; 3];
+        let mut iter = DecodedCommandIter::new(decompressor, &mut input_buffer[..], |buf: &mut [u8]| {
+            let len = core::cmp::min(buf.len(), remaining.len());
+            buf[..len].clone_from_slice(&remaining[..len]);
+            remaining = &remaining[len..];
+            len
+        });
+        let mut decoded = Vec::new();
+        for cmd in iter.by_ref() {
+            decoded.push(cmd.unwrap());
+        }
+        assert_eq!(decoded.len(), cmds.len());
+        for (orig, dec) in cmds.iter().zip(decoded.iter()) {
+            assert_same_command(orig, dec);
+        }
+        let expected = conformance_expected_output(&cmds[..]).unwrap();
+        assert_eq!(decode(&encode_with_options(&decoded[..], DivansCompressorOptions::default()).unwrap()[..]).unwrap(), expected);
+        for cmd in decoded.drain(..) {
+            iter.free_command(cmd);
+        }
+        iter.into_inner().free();
+
+        let decompressor = new_decompressor(false, false);
+        let mut truncated = &stream[..stream.len() / 2];
+        let mut input_buffer = [0u8; 64];
+        let iter = DecodedCommandIter::new(decompressor, &mut input_buffer[..], |buf: &mut [u8]| {
+            let len = core::cmp::min(buf.len(), truncated.len());
+            buf[..len].clone_from_slice(&truncated[..len]);
+            truncated = &truncated[len..];
+            len
+        });
+        match iter.last() {
+            Some(Err(ErrMsg::TruncatedInput)) => {},
+            res => panic!("{:?}", res.map(|cmd| cmd.is_ok())),
+        }
+    }
+
+    #[test]
+    fn test_decoded_command_iter_borrowed() {
+        let cmds = conformance_commands("mixed");
+        let stream = encode_with_options(&cmds[..], DivansCompressorOptions::default()).unwrap();
+        let decompressor = new_decompressor(false, false);
+        let mut remaining = &stream[..];
+        let mut input_buffer = [0u8; 64];
+        let mut iter = DecodedCommandIter::new(decompressor, &mut input_buffer[..], |buf: &mut [u8]| {
+            let len = core::cmp::min(buf.len(), remaining.len());
+            buf[..len].clone_from_slice(&remaining[..len]);
+            remaining = &remaining[len..];
+            len
+        });
+        let mut count = 0usize;
+        while let Some(cmd) = iter.next_borrowed() {
+            assert_same_command(&cmds[count], &cmd.unwrap());
+            count += 1;
+        }
+        assert_eq!(count, cmds.len());
+        iter.into_inner().free();
+    }
+}
@@ -17,6 +17,8 @@
 use core;
 use core::marker::PhantomData;
 use core::hash::Hasher;
+#[cfg(feature="trace")]
+use std::vec::Vec;
 use super::mux::{Mux,DevNull};
 
 
@@ -25,9 +27,8 @@ use super::slice_util;
 use super::alloc_util::RepurposingAlloc;
 pub use super::alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut, StackAllocator};
 use codec::io::DemuxerAndRingBuffer;
-use brotli;
 use brotli::InputReference;
-use brotli::interface::Freezable;
+use brotli::interface::{Freezable, Unfreezable};
 pub use super::interface::{
     BlockSwitch,
     LiteralBlockSwitch,
@@ -47,16 +48,25 @@ pub use super::interface::{
     };
 
 pub use super::cmd_to_divans::EncoderSpecialization;
-pub use codec::{EncoderOrDecoderSpecialization, DivansCodec, StrideSelection, default_crc, CommandArray, CommandSliceArray};
+pub use codec::{EncoderOrDecoderSpecialization, DivansCodec, StrideSelection, default_crc, SubDigest, CommandArray, CommandSliceArray};
 use super::interface;
 use super::interface::{DivansOutputResult, DivansResult, ErrMsg};
+use super::cancel::CancelToken;
+#[cfg(feature="std")]
+use std::sync::Arc;
+#[cfg(feature="timings")]
+use super::timings::{Clock, EncodeTimings, StageClock};
+use super::wire;
+use super::stored_frame;
+use super::stored_frame::{ModeledCompressor, TinyInput};
 const COMPRESSOR_CMD_BUFFER_SIZE : usize = 16;
 pub struct DivansCompressor<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                             AllocU8:Allocator<u8>,
                             AllocU32:Allocator<u32>,
-                            AllocCDF16:Allocator<interface::DefaultCDF16>> {
+                            AllocCDF16:Allocator<interface::DefaultCDF16>,
+                            D:Hasher+Default=SubDigest> {
     m32: AllocU32,
-    codec: DivansCodec<DefaultEncoder, EncoderSpecialization, DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>, Mux<AllocU8>, interface::DefaultCDF16, AllocU8, AllocCDF16>,
+    codec: DivansCodec<DefaultEncoder, EncoderSpecialization, DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>, Mux<AllocU8>, interface::DefaultCDF16, AllocU8, AllocCDF16, D>,
     header_progress: usize,
     window_size: u8,
     literal_context_map_backing: AllocU8::AllocatedMemory,
@@ -65,38 +75,48 @@ pub struct DivansCompressor<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWith
     freeze_dried_cmd_array: [Command<slice_util::SliceReference<'static,u8>>; COMPRESSOR_CMD_BUFFER_SIZE],
     freeze_dried_cmd_start: usize,
     freeze_dried_cmd_end: usize,
+    // what encoding fails with, before the header, when the options need a newer format than they allow
+    format_error: Option<ErrMsg>,
+    tiny: TinyInput,
+    #[cfg(feature="timings")]
+    clock: StageClock,
+    #[cfg(feature="timings")]
+    parse_ns: u64,
 }
 
 
 pub struct DivansCompressorFactoryStruct
     <AllocU8:Allocator<u8>, 
-     AllocCDF16:Allocator<interface::DefaultCDF16>> {
+     AllocCDF16:Allocator<interface::DefaultCDF16>,
+     D:Hasher+Default=SubDigest> {
     p1: PhantomData<AllocU8>,
     p2: PhantomData<AllocCDF16>,
+    p3: PhantomData<D>,
 }
 
 impl<AllocU8:Allocator<u8>,
      AllocU32:Allocator<u32>,
-     AllocCDF16:Allocator<interface::DefaultCDF16>> interface::DivansCompressorFactory<AllocU8,
-                                                                                       AllocU32,
-                                                                                       AllocCDF16>
-    for DivansCompressorFactoryStruct<AllocU8, AllocCDF16> {
+     AllocCDF16:Allocator<interface::DefaultCDF16>,
+     D:Hasher+Default> interface::DivansCompressorFactory<AllocU8,
+                                                          AllocU32,
+                                                          AllocCDF16>
+    for DivansCompressorFactoryStruct<AllocU8, AllocCDF16, D> {
      type DefaultEncoder = DefaultEncoderType!();
-     type ConstructedCompressor = DivansCompressor<Self::DefaultEncoder, AllocU8, AllocU32, AllocCDF16>;
+     type ConstructedCompressor = DivansCompressor<Self::DefaultEncoder, AllocU8, AllocU32, AllocCDF16, D>;
      type AdditionalArgs = ();
      fn new(mut m8: AllocU8, mut m32: AllocU32, mcdf16:AllocCDF16,
             opts: super::interface::DivansCompressorOptions,
-            _additional_args: ()) -> DivansCompressor<Self::DefaultEncoder, AllocU8, AllocU32, AllocCDF16> {
+            _additional_args: ()) -> DivansCompressor<Self::DefaultEncoder, AllocU8, AllocU32, AllocCDF16, D> {
          let window_size = core::cmp::min(24, core::cmp::max(10, opts.window_size.unwrap_or(22)));
          let ring_buffer = m8.alloc_cell(1<<window_size);
          let prediction_mode_backing = m8.alloc_cell(interface::MAX_PREDMODE_SPEED_AND_DISTANCE_CONTEXT_MAP_SIZE);
          let literal_context_map = m8.alloc_cell(interface::MAX_LITERAL_CONTEXT_MAP_SIZE);
          let cmd_enc = Self::DefaultEncoder::new(&mut m8);
          let lit_enc = Self::DefaultEncoder::new(&mut m8);
+         // the dictionary index is built on the first encode, unless a prepared one is set before then
          let assembler = raw_to_cmd::RawToCmdState::new(&mut m32, ring_buffer);
-         DivansCompressor::<Self::DefaultEncoder, AllocU8, AllocU32, AllocCDF16> {
-            m32 :m32,
-            codec:DivansCodec::<Self::DefaultEncoder, EncoderSpecialization, DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>, Mux<AllocU8>, interface::DefaultCDF16, AllocU8, AllocCDF16>::new(
+         #[cfg_attr(not(feature="trace"), allow(unused_mut))]
+         let mut codec = DivansCodec::<Self::DefaultEncoder, EncoderSpecialization, DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>, Mux<AllocU8>, interface::DefaultCDF16, AllocU8, AllocCDF16, D>::new(
                 m8,
                 mcdf16,
                 cmd_enc,
@@ -110,12 +130,52 @@ impl<AllocU8:Allocator<u8>,
                 opts.use_context_map,
                 opts.force_stride_value,
                 false,
-            ),
+         );
+         let mut stream_features = opts.stream_features;
+         codec.set_framing_tag(opts.framing_tag);
+         codec.set_checksum_type(opts.checksum);
+         if let Some(alignment) = opts.frame_alignment {
+             stream_features |= interface::STREAM_FEATURE_MUX_PADDING;
+             codec.set_frame_alignment(alignment as usize);
+         }
+         if let Some(key) = opts.authentication_key {
+             stream_features |= interface::STREAM_FEATURE_AUTHENTICATED;
+             codec.set_authentication_key(&key[..]);
+         }
+         if opts.distance_bucketing.is_identity() {
+             codec.set_stream_features(stream_features);
+         } else {
+             codec.set_stream_features(stream_features | interface::STREAM_FEATURE_DISTANCE_BUCKETING);
+             codec.set_distance_bucketing(opts.distance_bucketing);
+         }
+         codec.set_mixer_warm_start(opts.mixer_warm_start);
+         codec.set_compact_priors(opts.compact_priors);
+         if opts.freeze_adaptation_after.is_some() {
+             codec.set_extended_stream_features(opts.extended_stream_features | interface::EXTENDED_FEATURE_FREEZE_ADAPTATION);
+         } else {
+             codec.set_extended_stream_features(opts.extended_stream_features);
+         }
+         codec.set_freeze_adaptation_after(opts.freeze_adaptation_after);
+         codec.set_priors_reset_interval(opts.priors_reset_interval);
+         codec.set_extended_stream_features2(opts.extended_stream_features2);
+         #[cfg(feature="trace")]
+         {
+             codec.trace().output_pos = codec.header_length() as u64;
+         }
+         DivansCompressor::<Self::DefaultEncoder, AllocU8, AllocU32, AllocCDF16, D> {
+            m32 :m32,
+            codec:codec,
             literal_context_map_backing: literal_context_map,
             prediction_mode_backing: prediction_mode_backing,
             freeze_dried_cmd_array:[interface::Command::<slice_util::SliceReference<'static, u8>>::default(); COMPRESSOR_CMD_BUFFER_SIZE],
             freeze_dried_cmd_start:0,
             freeze_dried_cmd_end:0,
+            format_error: opts.check_format_version().err(),
+            tiny: TinyInput::new(opts.stores_tiny_inputs()),
+            #[cfg(feature="timings")]
+            clock: StageClock::default(),
+            #[cfg(feature="timings")]
+            parse_ns: 0,
             cmd_assembler:assembler,
             header_progress: 0,
             window_size: window_size as u8,
@@ -123,16 +183,49 @@ impl<AllocU8:Allocator<u8>,
      }
 }
 
-pub fn make_header(window_size: u8) -> [u8; interface::HEADER_LENGTH] {
+pub fn make_header(window_size: u8, stream_features: u8, extended_stream_features: u8,
+                   freeze_adaptation_after: Option<u32>, priors_reset_interval: Option<u32>,
+                   extended_stream_features2: u8, checksum: interface::ChecksumType) -> [u8; interface::HEADER_LENGTH] {
     let mut retval = [0u8; interface::HEADER_LENGTH];
-    retval[0..interface::MAGIC_NUMBER.len()].clone_from_slice(&interface::MAGIC_NUMBER[..]);
+    if extended_stream_features != 0 || priors_reset_interval.is_some() || extended_stream_features2 != 0
+        || checksum != interface::ChecksumType::Crc32c {
+        retval[0..interface::MAGIC_NUMBER_EXTENDED.len()].clone_from_slice(&interface::MAGIC_NUMBER_EXTENDED[..]);
+        retval[interface::HEADER_EXTENDED_FEATURE_OFFSET] = extended_stream_features;
+        if let Some(after) = freeze_adaptation_after {
+            for (index, byte) in retval[interface::HEADER_FREEZE_ADAPTATION_OFFSET..interface::HEADER_FREEZE_ADAPTATION_OFFSET + 4].iter_mut().enumerate() {
+                *byte = (after >> (8 * index)) as u8;
+            }
+        }
+        if let Some(interval) = priors_reset_interval {
+            let units = interval / interface::PRIORS_RESET_GRANULARITY;
+            retval[interface::HEADER_PRIORS_RESET_OFFSET] = units as u8;
+            retval[interface::HEADER_PRIORS_RESET_OFFSET + 1] = (units >> 8) as u8;
+        }
+        retval[interface::HEADER_EXTENDED_FEATURE2_OFFSET] = extended_stream_features2;
+        retval[interface::HEADER_CHECKSUM_OFFSET] = checksum.header_byte();
+    } else {
+        retval[0..interface::MAGIC_NUMBER.len()].clone_from_slice(&interface::MAGIC_NUMBER[..]);
+    }
+    retval[interface::HEADER_CODER_ID_OFFSET] = interface::CODER_ID;
     retval[5] = window_size;
+    retval[interface::HEADER_FEATURE_OFFSET] = stream_features;
     retval
 }
-fn thaw_commands<'a>(input: &[Command<slice_util::SliceReference<'static, u8>>], ring_buffer: &'a[u8], start_index:  usize, end_index: usize) -> [Command<InputReference<'a>>; COMPRESSOR_CMD_BUFFER_SIZE] {
+// the context maps of a prediction mode command live in their backings rather than the ring buffer
+fn thaw_commands<'a>(input: &[Command<slice_util::SliceReference<'static, u8>>],
+                     ring_buffer: &'a[u8],
+                     literal_context_map_backing: &'a[u8],
+                     prediction_mode_backing: &'a[u8],
+                     start_index:  usize, end_index: usize) -> [Command<InputReference<'a>>; COMPRESSOR_CMD_BUFFER_SIZE] {
    let mut ret : [Command<InputReference<'a>>; COMPRESSOR_CMD_BUFFER_SIZE] = [Command::<InputReference>::default(); COMPRESSOR_CMD_BUFFER_SIZE];
    for (thawed, frozen) in ret[start_index..end_index].iter_mut().zip(input[start_index..end_index].iter()) {
-      *thawed = brotli::interface::thaw(frozen, ring_buffer);
+      *thawed = match *frozen {
+          Command::PredictionMode(ref pm) => Command::PredictionMode(PredictionModeContextMap::<InputReference<'a>> {
+              literal_context_map: Unfreezable::thaw(&pm.literal_context_map, literal_context_map_backing),
+              predmode_speed_and_distance_context_map: Unfreezable::thaw(&pm.predmode_speed_and_distance_context_map, prediction_mode_backing),
+          }),
+          _ => interface::thaw(frozen, ring_buffer),
+      };
    }
    ret
 }
@@ -148,14 +241,15 @@ fn freeze<'a>(item: &FeatureFlagSliceType<InputReference<'a>>) -> FeatureFlagSli
 }
 
 pub fn write_header<CRC:Hasher>(header_progress: &mut usize,
-                                window_size: u8,
+                                header: &[u8; interface::HEADER_LENGTH],
+                                framing: Option<wire::FramingTag>,
                                 output: &mut[u8],
                                 output_offset:&mut usize,
                                 crc: &mut CRC) -> DivansOutputResult {
+    let (header, header_len) = wire::frame_header(header, framing.as_ref());
     let bytes_avail = output.len() - *output_offset;
-    if bytes_avail + *header_progress < interface::HEADER_LENGTH {
-        let to_write = &make_header(window_size)[*header_progress..
-                                                 (*header_progress + bytes_avail)];
+    if bytes_avail + *header_progress < header_len {
+        let to_write = &header[*header_progress..(*header_progress + bytes_avail)];
         crc.write(to_write);
         output.split_at_mut(*output_offset).1.clone_from_slice(
             to_write);
@@ -163,12 +257,12 @@ pub fn write_header<CRC:Hasher>(header_progress: &mut usize,
         *header_progress += bytes_avail;
         return DivansOutputResult::NeedsMoreOutput;
     }
-    let to_write = &make_header(window_size)[*header_progress..];
-    output[*output_offset..(*output_offset + interface::HEADER_LENGTH - *header_progress)].clone_from_slice(
+    let to_write = &header[*header_progress..header_len];
+    output[*output_offset..(*output_offset + header_len - *header_progress)].clone_from_slice(
         to_write);
     crc.write(to_write);
-    *output_offset += interface::HEADER_LENGTH - *header_progress;
-    *header_progress = interface::HEADER_LENGTH;
+    *output_offset += header_len - *header_progress;
+    *header_progress = header_len;
     DivansOutputResult::Success
 
 }
@@ -184,12 +278,87 @@ impl<'a> CommandArray for InputReferenceCommandArray<'a> {
     }
 }
 
-impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>, AllocU8:Allocator<u8>, AllocU32:Allocator<u32>, AllocCDF16:Allocator<interface::DefaultCDF16>> 
-    DivansCompressor<DefaultEncoder, AllocU8, AllocU32, AllocCDF16> {
+impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>, AllocU8:Allocator<u8>, AllocU32:Allocator<u32>, AllocCDF16:Allocator<interface::DefaultCDF16>, D:Hasher+Default>
+    DivansCompressor<DefaultEncoder, AllocU8, AllocU32, AllocCDF16, D> {
+    // writes the header if it is not out yet and encodes the input the assembler still holds,
+    // ahead of ending the stream or of writing out what there is so far
+    fn model_pending_input(&mut self, output: &mut [u8], output_offset: &mut usize) -> DivansOutputResult {
+        if self.header_progress != self.codec.header_length() {
+            if let Some(m) = self.format_error {
+                return DivansOutputResult::Failure(m);
+            }
+            match write_header(&mut self.header_progress, &make_header(self.window_size, self.codec.stream_features(), self.codec.extended_stream_features(), self.codec.freeze_adaptation_after(), self.codec.priors_reset_interval(), self.codec.extended_stream_features2(), self.codec.checksum_type()), self.codec.framing_tag(), output, output_offset,
+                               self.codec.get_crc()) {
+                DivansOutputResult::Success => {},
+                res => return res,
+            }
+        }
+        match self.flush_freeze_dried_cmds(output, output_offset) {
+               DivansOutputResult::Success => {},
+               res => return res,
+        }
+        self.enable_dictionary();
+        loop {
+            let literal_context_map_backing = self.literal_context_map_backing.slice_mut();
+            let prediction_mode_backing = self.prediction_mode_backing.slice_mut();
+            let mut temp_bs: [interface::Command<InputReference>;COMPRESSOR_CMD_BUFFER_SIZE] =
+                [interface::Command::<InputReference>::default();COMPRESSOR_CMD_BUFFER_SIZE];
+            let mut temp_cmd_offset = 0;
+            #[cfg(feature="timings")]
+            let parse_start = self.clock.now();
+            let command_flush_ret = self.cmd_assembler.flush(&mut temp_bs[..], &mut temp_cmd_offset, literal_context_map_backing, prediction_mode_backing);
+            #[cfg(feature="timings")]
+            {
+                self.parse_ns += self.clock.since(parse_start);
+            }
+            match command_flush_ret {
+                DivansOutputResult::Success => {
+                    if temp_cmd_offset == 0 {
+                        break; // no output from the cmd_assembler, just plain flush the codec
+                    }
+                },
+                DivansOutputResult::Failure(m) => {
+                    return DivansOutputResult::Failure(m); // we are never done
+                },
+                DivansOutputResult::NeedsMoreOutput => {},
+            }
+            let mut out_cmd_offset = 0;
+            let mut zero: usize = 0;
+            let codec_ret = self.codec.encode_or_decode(&[],
+                                                        &mut zero,
+                                                        output,
+                                                        output_offset,
+                                                        &InputReferenceCommandArray(temp_bs.split_at(temp_cmd_offset).0),
+                                                        &mut out_cmd_offset);
+            match codec_ret {
+                DivansResult::Success | DivansResult::NeedsMoreInput => {
+                    assert_eq!(temp_cmd_offset, out_cmd_offset); // must have consumed all commands
+                    if let DivansOutputResult::Success = command_flush_ret {
+                         break; // we've exhausted all commands and all input
+                    }
+                },
+                DivansResult::NeedsMoreOutput | DivansResult::Failure(_) => {
+                    Self::freeze_dry(
+                        &mut self.freeze_dried_cmd_array,
+                        &mut self.freeze_dried_cmd_start,
+                        &mut self.freeze_dried_cmd_end,
+                        &temp_bs[out_cmd_offset..temp_cmd_offset]);
+                    match codec_ret {
+                        DivansResult::Success | DivansResult::NeedsMoreInput => return DivansOutputResult::Failure(
+                            ErrMsg::WrongInternalEncoderState(0)),
+                        DivansResult::NeedsMoreOutput => return DivansOutputResult::NeedsMoreOutput,
+                        DivansResult::Failure(m) => return DivansOutputResult::Failure(m),
+                    }
+                }
+            }
+        }
+        DivansOutputResult::Success
+    }
     fn flush_freeze_dried_cmds(&mut self, output: &mut [u8], output_offset: &mut usize) -> interface::DivansOutputResult {
         if self.freeze_dried_cmd_start != self.freeze_dried_cmd_end { // we have some freeze dried items
             let thawed_buffer = thaw_commands(&self.freeze_dried_cmd_array[..], self.cmd_assembler.ring_buffer.slice(),
-                                                  self.freeze_dried_cmd_start, self.freeze_dried_cmd_end);
+                                              self.literal_context_map_backing.slice(), self.prediction_mode_backing.slice(),
+                                              self.freeze_dried_cmd_start, self.freeze_dried_cmd_end);
             let mut unused: usize = 0;
             match self.codec.encode_or_decode(&[],
                                     &mut unused,
@@ -247,6 +416,61 @@ impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>, All
     pub fn get_m8(&mut self) -> Option<&mut RepurposingAlloc<u8, AllocU8>> {
        self.codec.get_m8()
     }
+    // tells the match finder that the input at offset, counted from the first byte passed to encode,
+    // repeats the input distance bytes before it for len bytes (see RawToCmdState::hint_repeat)
+    pub fn hint_repeat(&mut self, offset: u64, len: u32, distance: u32) -> Result<(), ErrMsg> {
+        self.cmd_assembler.hint_repeat(&mut self.m32, offset, len, distance)
+    }
+    // matches dictionary words against dict, which may be shared with other compressors, rather
+    // than building an index of this compressor's own; set it before the first encode to skip that
+    #[cfg(feature="std")]
+    pub fn set_prepared_dictionary(&mut self, dict: Arc<raw_to_cmd::PreparedDictionary>) {
+        self.cmd_assembler.use_prepared_dictionary(&mut self.m32, dict);
+    }
+    fn enable_dictionary(&mut self) {
+        if self.codec.stream_features() & interface::STREAM_FEATURE_NO_DICT == 0 {
+            self.cmd_assembler.enable_dictionary(&mut self.m32);
+        }
+    }
+    // encode and flush fail with ErrMsg::Cancelled before the next command once token is
+    // cancelled, and carry on from there when called again after it is reset
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        self.codec.set_cancel_token(token);
+    }
+    // times the stages of encode and flush from now on; see timings.rs
+    #[cfg(feature="timings")]
+    pub fn set_clock(&mut self, clock: &'static dyn Clock) {
+        self.clock = StageClock::new(clock);
+        self.codec.set_clock(clock);
+    }
+    // the match finder is all there is to parse, and nothing is modeled
+    #[cfg(feature="timings")]
+    pub fn timings(&self) -> EncodeTimings {
+        EncodeTimings {
+            parse_ns: self.parse_ns,
+            ..self.codec.timings()
+        }
+    }
+    // once encode or flush has returned NeedsMoreOutput, the room the next call needs to get past
+    // where this one stopped: the rest of the header, the trailer or a stored frame, else 1
+    pub fn output_needed(&self) -> usize {
+        if let Some(remaining) = self.tiny.frame_remaining() {
+            return remaining;
+        }
+        if self.header_progress != self.codec.header_length() {
+            return self.codec.header_length() - self.header_progress;
+        }
+        self.codec.output_needed()
+    }
+    // the secondary digest of every compressed byte emitted so far, header included
+    pub fn take_digest(&mut self) -> D {
+        self.codec.take_digest()
+    }
+    // the serialized state machine trace recorded so far (see codec::trace)
+    #[cfg(feature="trace")]
+    pub fn take_trace(&mut self) -> Vec<u8> {
+        self.codec.trace().take()
+    }
     pub fn free_ref(&mut self) {
         self.cmd_assembler.free(&mut self.m32);
         self.codec.get_m8().as_mut().unwrap().get_base_alloc().free_cell(core::mem::replace(&mut self.cmd_assembler.ring_buffer, AllocU8::AllocatedMemory::default()));
@@ -269,17 +493,28 @@ impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>, All
 impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
      AllocU8:Allocator<u8>,
      AllocU32:Allocator<u32>,
-     AllocCDF16:Allocator<interface::DefaultCDF16>> Compressor for DivansCompressor<DefaultEncoder,
-                                                                                    AllocU8,
-                                                                                    AllocU32,
-                                                                                    AllocCDF16> {
-    fn encode(&mut self,
+     AllocCDF16:Allocator<interface::DefaultCDF16>,
+     D:Hasher+Default> ModeledCompressor for DivansCompressor<DefaultEncoder,
+                                                              AllocU8,
+                                                              AllocU32,
+                                                              AllocCDF16,
+                                                              D> {
+    fn tiny_input(&mut self) -> &mut TinyInput {
+        &mut self.tiny
+    }
+    fn encode_modeled(&mut self,
               input: &[u8],
               input_offset: &mut usize,
               output: &mut [u8],
               output_offset: &mut usize) -> DivansResult {
-        if self.header_progress != interface::HEADER_LENGTH {
-            match write_header(&mut self.header_progress, self.window_size, output, output_offset,
+        if self.codec.is_finishing() {
+            return DivansResult::Failure(ErrMsg::NotAllowedToEncodeAfterFlush);
+        }
+        if self.header_progress != self.codec.header_length() {
+            if let Some(m) = self.format_error {
+                return DivansResult::Failure(m);
+            }
+            match write_header(&mut self.header_progress, &make_header(self.window_size, self.codec.stream_features(), self.codec.extended_stream_features(), self.codec.freeze_adaptation_after(), self.codec.priors_reset_interval(), self.codec.extended_stream_features2(), self.codec.checksum_type()), self.codec.framing_tag(), output, output_offset,
                                self.codec.get_crc()) {
                 DivansOutputResult::Success => {},
                 res => return DivansResult::from(res),
@@ -289,15 +524,22 @@ impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
             DivansOutputResult::Success => {},
             res => return DivansResult::from(res),
         }
+        self.enable_dictionary();
         let literal_context_map = self.literal_context_map_backing.slice_mut();
         let prediction_mode_backing = self.prediction_mode_backing.slice_mut();
         loop {
             let mut temp_bs: [interface::Command<InputReference>;COMPRESSOR_CMD_BUFFER_SIZE] =
                 [interface::Command::<InputReference>::default();COMPRESSOR_CMD_BUFFER_SIZE];
             let mut temp_cmd_offset = 0;
+            #[cfg(feature="timings")]
+            let parse_start = self.clock.now();
             let command_decode_ret = self.cmd_assembler.stream(input, input_offset,
                                                                &mut temp_bs[..], &mut temp_cmd_offset,
                                                                literal_context_map, prediction_mode_backing);
+            #[cfg(feature="timings")]
+            {
+                self.parse_ns += self.clock.since(parse_start);
+            }
             match command_decode_ret {
                 DivansResult::NeedsMoreInput => {
                     if temp_cmd_offset == 0 {
@@ -335,14 +577,61 @@ impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
             }
         }
     }
+    fn flush_modeled(&mut self,
+                     output: &mut [u8],
+                     output_offset: &mut usize) -> DivansOutputResult {
+        match self.model_pending_input(output, output_offset) {
+            DivansOutputResult::Success => {},
+            res => return res,
+        }
+        self.codec.flush(output, output_offset)
+    }
+    fn sync_flush_modeled(&mut self,
+                          output: &mut [u8],
+                          output_offset: &mut usize) -> DivansOutputResult {
+        if self.codec.is_finishing() {
+            return DivansOutputResult::Failure(ErrMsg::NotAllowedToEncodeAfterFlush);
+        }
+        match self.model_pending_input(output, output_offset) {
+            DivansOutputResult::Success => {},
+            res => return res,
+        }
+        self.codec.sync_flush(output, output_offset)
+    }
+}
+
+impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
+     AllocU8:Allocator<u8>,
+     AllocU32:Allocator<u32>,
+     AllocCDF16:Allocator<interface::DefaultCDF16>,
+     D:Hasher+Default> Compressor for DivansCompressor<DefaultEncoder,
+                                                       AllocU8,
+                                                       AllocU32,
+                                                       AllocCDF16,
+                                                       D> {
+    fn encode(&mut self,
+              input: &[u8],
+              input_offset: &mut usize,
+              output: &mut [u8],
+              output_offset: &mut usize) -> DivansResult {
+        stored_frame::encode(self, input, input_offset, output, output_offset)
+    }
     fn encode_commands<SliceType:SliceWrapper<u8>+Default>(&mut self,
                                           input:&[Command<SliceType>],
                                           input_offset : &mut usize,
                                           output :&mut[u8],
                                           output_offset: &mut usize) -> DivansOutputResult{
+        match stored_frame::release_held_input(self, output, output_offset) {
+            DivansResult::NeedsMoreInput | DivansResult::Success => {},
+            DivansResult::NeedsMoreOutput => return DivansOutputResult::NeedsMoreOutput,
+            DivansResult::Failure(m) => return DivansOutputResult::Failure(m),
+        }
         self.cmd_assembler.raw_input_ir_mode();
-        if self.header_progress != interface::HEADER_LENGTH {
-            match write_header(&mut self.header_progress, self.window_size, output, output_offset,
+        if self.header_progress != self.codec.header_length() {
+            if let Some(m) = self.format_error {
+                return DivansOutputResult::Failure(m);
+            }
+            match write_header(&mut self.header_progress, &make_header(self.window_size, self.codec.stream_features(), self.codec.extended_stream_features(), self.codec.freeze_adaptation_after(), self.codec.priors_reset_interval(), self.codec.extended_stream_features2(), self.codec.checksum_type()), self.codec.framing_tag(), output, output_offset,
                                self.codec.get_crc()) {
                 DivansOutputResult::Success => {},
                 res => return res,
@@ -363,66 +652,199 @@ impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
     fn flush(&mut self,
              output: &mut [u8],
              output_offset: &mut usize) -> DivansOutputResult {
-        if self.header_progress != interface::HEADER_LENGTH {
-            match write_header(&mut self.header_progress, self.window_size, output, output_offset,
-                               self.codec.get_crc()) {
-                DivansOutputResult::Success => {},
-                res => return res,
+        stored_frame::flush(self, output, output_offset)
+    }
+    fn sync_flush(&mut self,
+                  output: &mut [u8],
+                  output_offset: &mut usize) -> DivansOutputResult {
+        stored_frame::sync_flush(self, output, output_offset)
+    }
+}
+
+#[cfg(all(test, feature="std", feature="decoder"))]
+mod test {
+    use std::vec::Vec;
+    use alloc_stdlib::HeapAlloc;
+    use conformance::{conformance_commands, conformance_expected_output};
+    use std::io::Write;
+    use error::Error;
+    use writer::{new_brotli_hybrid_compressor, DivansBrotliHybridCompressorWriter};
+    use wire::FramingTag;
+    use super::DivansCompressorFactoryStruct;
+    use interface::{
+        Compressor,
+        MixerWarmStart,
+        Decompressor,
+        DivansCompressorFactory,
+        DivansCompressorOptions,
+        CompressorOption,
+        FormatVersion,
+        DivansOutputResult,
+        DivansResult,
+        ErrMsg,
+        DefaultCDF16,
+        HEADER_LENGTH,
+        HEADER_FEATURE_OFFSET,
+        MAGIC_NUMBER,
+        EXTENDED_FEATURE_CONTEXT_MAP_MODEL,
+        STREAM_FEATURE_COMMAND_TYPE_TREE,
+    };
+    use test_support::{new_decompressor, encode_with_options, compress_hybrid, decode};
+
+    #[test]
+    fn test_pinned_format_version() {
+        let commands = conformance_commands("mixed");
+        let expected = conformance_expected_output(&commands[..]).unwrap();
+        let mut opts = DivansCompressorOptions::default();
+        assert_eq!(opts.written_format_version(), FormatVersion::StreamFeatures);
+        opts.format_version = Some(FormatVersion::Original);
+        let conflict = ErrMsg::ConflictingOptions(CompressorOption::FormatVersion, CompressorOption::StreamFeatures);
+        assert_eq!(opts.validate(), Err(conflict));
+        assert_eq!(encode_with_options(&commands[..], opts), Err(conflict));
+        let mut output = Vec::<u8>::new();
+        let err = {
+            let mut writer = DivansBrotliHybridCompressorWriter::new(&mut output, opts, 0);
+            writer.write_all(b"too new").and_then(|_| writer.flush()).unwrap_err()
+        };
+        assert_eq!(Error::from(err).code(), Some(conflict));
+        assert!(output.is_empty());
+        // the original format: the full command alphabet and its nibble coding
+        opts.stream_features = 0;
+        assert_eq!(opts.written_format_version(), FormatVersion::Original);
+        assert!(opts.validate().is_ok());
+        let original = encode_with_options(&commands[..], opts).unwrap();
+        assert_eq!(&original[..MAGIC_NUMBER.len()], &MAGIC_NUMBER[..]);
+        assert_eq!(original[HEADER_FEATURE_OFFSET], 0);
+        assert_eq!(decode(&original[..]).unwrap(), expected);
+        let input = conformance_expected_output(&conformance_commands("mixed")[..]).unwrap().repeat(8);
+        let hybrid = compress_hybrid(&input[..], opts);
+        assert_eq!(hybrid[HEADER_FEATURE_OFFSET], 0);
+        assert_eq!(decode(&hybrid[..]).unwrap(), input);
+        opts.mixer_warm_start = Some(MixerWarmStart::new(8, 0).unwrap());
+        opts.dynamic_context_mixing = Some(2);
+        assert_eq!(opts.validate(), Err(ErrMsg::ConflictingOptions(CompressorOption::FormatVersion, CompressorOption::MixerWarmStart)));
+        // stream features, but no extended header
+        opts.format_version = Some(FormatVersion::StreamFeatures);
+        opts.stream_features = STREAM_FEATURE_COMMAND_TYPE_TREE;
+        assert!(opts.validate().is_ok());
+        assert_eq!(decode(&encode_with_options(&commands[..], opts).unwrap()[..]).unwrap(), expected);
+        opts.extended_stream_features = EXTENDED_FEATURE_CONTEXT_MAP_MODEL;
+        assert_eq!(opts.written_format_version(), FormatVersion::Extended);
+        assert_eq!(encode_with_options(&commands[..], opts),
+                   Err(ErrMsg::ConflictingOptions(CompressorOption::FormatVersion, CompressorOption::ExtendedStreamFeatures)));
+        opts.extended_stream_features = 0;
+        opts.framing_tag = Some(FramingTag::new(b"tag").unwrap());
+        assert_eq!(opts.validate(), Err(ErrMsg::ConflictingOptions(CompressorOption::FormatVersion, CompressorOption::FramingTag)));
+        opts.format_version = Some(FormatVersion::LATEST);
+        assert!(opts.validate().is_ok());
+    }
+
+    // encodes each part and sync flushes after it, noting how much stream was out by then, then finishes
+    fn encode_sync_flushed<C:Compressor>(compressor: &mut C, parts: &[&[u8]]) -> (Vec<u8>, Vec<usize>) {
+        let mut stream = Vec::<u8>::new();
+        let mut flushed_at = Vec::<usize>::new();
+        let mut buffer = [0u8; 512];
+        for part in parts.iter() {
+            let mut input_offset = 0usize;
+            while input_offset != part.len() {
+                let mut output_offset = 0usize;
+                let res = compressor.encode(part, &mut input_offset, &mut buffer[..], &mut output_offset);
+                stream.extend(&buffer[..output_offset]);
+                if let DivansResult::Failure(m) = res {
+                    panic!("{:?}", m);
+                }
             }
-        }
-        match self.flush_freeze_dried_cmds(output, output_offset) {
-               DivansOutputResult::Success => {},
-               res => return res,
+            loop {
+                let mut output_offset = 0usize;
+                let res = compressor.sync_flush(&mut buffer[..], &mut output_offset);
+                stream.extend(&buffer[..output_offset]);
+                match res {
+                    DivansOutputResult::Success => break,
+                    DivansOutputResult::NeedsMoreOutput => {},
+                    DivansOutputResult::Failure(m) => panic!("{:?}", m),
+                }
+            }
+            flushed_at.push(stream.len());
         }
         loop {
-            let literal_context_map_backing = self.literal_context_map_backing.slice_mut();
-            let prediction_mode_backing = self.prediction_mode_backing.slice_mut();
-            let mut temp_bs: [interface::Command<InputReference>;COMPRESSOR_CMD_BUFFER_SIZE] =
-                [interface::Command::<InputReference>::default();COMPRESSOR_CMD_BUFFER_SIZE];
-            let mut temp_cmd_offset = 0;
-            let command_flush_ret = self.cmd_assembler.flush(&mut temp_bs[..], &mut temp_cmd_offset, literal_context_map_backing, prediction_mode_backing);
-            match command_flush_ret {
-                DivansOutputResult::Success => {
-                    if temp_cmd_offset == 0 {
-                        break; // no output from the cmd_assembler, just plain flush the codec
-                    }
-                },
-                DivansOutputResult::Failure(m) => {
-                    return DivansOutputResult::Failure(m); // we are never done
-                },
+            let mut output_offset = 0usize;
+            let res = compressor.finish(&mut buffer[..], &mut output_offset);
+            stream.extend(&buffer[..output_offset]);
+            match res {
+                DivansOutputResult::Success => break,
                 DivansOutputResult::NeedsMoreOutput => {},
+                DivansOutputResult::Failure(m) => panic!("{:?}", m),
             }
-            let mut out_cmd_offset = 0;
-            let mut zero: usize = 0;
-            let codec_ret = self.codec.encode_or_decode(&[],
-                                                        &mut zero,
-                                                        output,
-                                                        output_offset,
-                                                        &InputReferenceCommandArray(temp_bs.split_at(temp_cmd_offset).0),
-                                                        &mut out_cmd_offset);
-            match codec_ret {
-                DivansResult::Success | DivansResult::NeedsMoreInput => {
-                    assert_eq!(temp_cmd_offset, out_cmd_offset); // must have consumed all commands
-                    if let DivansOutputResult::Success = command_flush_ret {
-                         break; // we've exhausted all commands and all input
-                    }
-                },
-                DivansResult::NeedsMoreOutput | DivansResult::Failure(_) => {
-                    Self::freeze_dry(
-                        &mut self.freeze_dried_cmd_array,
-                        &mut self.freeze_dried_cmd_start,
-                        &mut self.freeze_dried_cmd_end,
-                        &temp_bs[out_cmd_offset..temp_cmd_offset]);
-                    match codec_ret {
-                        DivansResult::Success | DivansResult::NeedsMoreInput => return DivansOutputResult::Failure(
-                            ErrMsg::WrongInternalEncoderState(0)),
-                        DivansResult::NeedsMoreOutput => return DivansOutputResult::NeedsMoreOutput,
-                        DivansResult::Failure(m) => return DivansOutputResult::Failure(m),
-                    }
-                }
+        }
+        (stream, flushed_at)
+    }
+
+    fn refused_after_finish<C:Compressor>(compressor: &mut C) {
+        let mut buffer = [0u8; 64];
+        let (mut input_offset, mut output_offset) = (0usize, 0usize);
+        assert_eq!(compressor.encode(b"more", &mut input_offset, &mut buffer[..], &mut output_offset),
+                   DivansResult::Failure(ErrMsg::NotAllowedToEncodeAfterFlush));
+        assert_eq!(compressor.sync_flush(&mut buffer[..], &mut output_offset),
+                   DivansOutputResult::Failure(ErrMsg::NotAllowedToEncodeAfterFlush));
+        assert_eq!((input_offset, output_offset), (0, 0));
+    }
+
+    // the output decoded from a stream cut short, which must stop only for want of input
+    fn decode_prefix(stream: &[u8]) -> Vec<u8> {
+        let mut decompressor = new_decompressor(false, false);
+        let mut out = Vec::<u8>::new();
+        let mut buffer = [0u8; 4096];
+        let mut input_offset = 0usize;
+        loop {
+            let mut output_offset = 0usize;
+            let res = decompressor.decode(stream, &mut input_offset, &mut buffer[..], &mut output_offset);
+            out.extend(&buffer[..output_offset]);
+            match res {
+                DivansResult::NeedsMoreOutput => {},
+                DivansResult::NeedsMoreInput => break,
+                res => panic!("{:?}", res),
             }
         }
-        self.codec.flush(output, output_offset)
+        decompressor.free();
+        out
     }
-}
 
+    #[test]
+    fn test_sync_flush_then_finish() {
+        let input = &include_bytes!("../testdata/alice29")[..];
+        // the first part fills a literal coder chunk, the second does not
+        let parts = [&input[..100000], &input[100000..100001], &input[100001..]];
+        let opts = DivansCompressorOptions::default();
+        let mut hybrid = new_brotli_hybrid_compressor(opts);
+        let (stream, flushed_at) = encode_sync_flushed(&mut hybrid, &parts[..]);
+        refused_after_finish(&mut hybrid);
+        hybrid.free();
+        assert_eq!(decode(&stream[..]).unwrap(), input);
+        assert!(flushed_at[0] > HEADER_LENGTH && flushed_at[0] <= flushed_at[1] && flushed_at[2] < stream.len(),
+                "{:?}", flushed_at);
+        // what a sync flush wrote is a well formed start of the stream: the decoder waits for more
+        let prefix = decode_prefix(&stream[..flushed_at[0]]);
+        assert_eq!(&prefix[..], &input[..prefix.len()]);
+
+        let mut compressor = DivansCompressorFactoryStruct::<HeapAlloc<u8>, HeapAlloc<DefaultCDF16>>::new(
+            HeapAlloc::<u8>::new(0),
+            HeapAlloc::<u32>::new(0),
+            HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+            opts,
+            ());
+        let (stream, flushed_at) = encode_sync_flushed(&mut compressor, &parts[..]);
+        refused_after_finish(&mut compressor);
+        compressor.free();
+        assert_eq!(decode(&stream[..]).unwrap(), input);
+        assert!(flushed_at[0] > HEADER_LENGTH, "{:?}", flushed_at);
+        let prefix = decode_prefix(&stream[..flushed_at[0]]);
+        assert_eq!(&prefix[..], &input[..prefix.len()]);
+
+        // a tiny input finished as a stored frame takes no more either
+        let mut tiny = new_brotli_hybrid_compressor(opts);
+        let (stream, _) = encode_sync_flushed(&mut tiny, &[&input[..3]]);
+        refused_after_finish(&mut tiny);
+        tiny.free();
+        assert_eq!(decode(&stream[..]).unwrap(), &input[..3]);
+    }
+}
@@ -2,54 +2,225 @@ use core;
 use core::marker::PhantomData;
 use core::hash::Hasher;
 use ::interface;
-use ::interface::{NewWithAllocator, Decompressor};
+use ::interface::{NewWithAllocator, Decompressor, Command};
+use ::slice_util::AllocatedMemoryPrefix;
 use ::DecoderSpecialization;
 use ::codec;
+use ::wire;
 use super::mux::{Mux,DevNull};
-use codec::decoder::{DecoderResult, DivansDecoderCodec};
+use codec::decoder::{DecoderResult, DivansDecoderCodec, SubDigest, DecodeOutput, ResumableOutput, ContiguousOutput};
+use codec::weights::MixerSnapshot;
 use threading::{ThreadToMainDemuxer, SerialWorker};
+use decoded_command_iter::CommandTap;
+use cancel::CancelToken;
+use output_digest::OutputDigest;
 
 
-use ::interface::{DivansResult, DivansOpResult, DivansInputResult, ErrMsg};
+use ::interface::{DivansResult, DivansOpResult, DivansInputResult, ErrMsg, DecodedStreamOptions, DecodeStats, StallWatchdog, ChecksumPolicy, OutputDigestType, TrailingBytesPolicy};
 use ::ArithmeticEncoderOrDecoder;
-use ::alloc::{Allocator};
+use ::alloc::{Allocator, SliceWrapper};
 pub use threading::StaticCommand;
+#[cfg(feature="trace")]
+use std::vec::Vec;
+#[cfg(feature="trace")]
+use codec::trace::TraceLog;
 
 #[cfg(feature="std")]
 use parallel_decompressor::{ParallelDivansProcess};
+#[cfg(feature="std")]
+use multithreading::CrcWorker;
+#[cfg(feature="std")]
+use std::time::Duration;
 #[cfg(not(feature="std"))]
 use stub_parallel_decompressor::{ParallelDivansProcess};
 
 pub struct HeaderParser<AllocU8:Allocator<u8>,
                         AllocCDF16:Allocator<interface::DefaultCDF16>,
                         AllocCommand:Allocator<StaticCommand>> {
-    pub header:[u8;interface::HEADER_LENGTH],
+    pub header:[u8;wire::MAX_HEADER_LENGTH], // as read, so framed if framing is set
     pub read_offset: usize,
     pub m8: Option<AllocU8>,
     pub mcdf16: Option<AllocCDF16>,
     pub mcommand: Option<AllocCommand>,
-    pub skip_crc: bool,
+    pub checksum_policy: ChecksumPolicy,
     pub multithread: bool,
+    pub authentication_key: Option<[u8; interface::AUTHENTICATION_KEY_LENGTH]>,
+    pub tap_commands: bool,
+    pub stop_between_streams: bool,
+    pub framing: Option<wire::FramingTag>,
+    pub cancel: CancelToken,
+    pub compact_priors: bool,
+    pub paranoid: bool,
+    #[cfg(feature="std")]
+    pub threaded_crc: bool,
+    #[cfg(feature="std")]
+    pub pull_timeout: Option<Duration>,
+    // of the decompressed output, carried from stream to stream (see set_output_digest)
+    pub output_digest: Option<OutputDigest>,
+    // for the ring buffer of this stream rather than a cell from m8 (see set_window)
+    pub window: Option<AllocU8::AllocatedMemory>,
+    pub trailing_bytes_policy: TrailingBytesPolicy,
+    // dropped after the last stream so far; kept with the settings, which outlive it
+    pub trailing_bytes: u64,
 }
 
 impl<AllocU8:Allocator<u8>,
      AllocCDF16:Allocator<interface::DefaultCDF16>,
      AllocCommand:Allocator<StaticCommand>>HeaderParser<AllocU8, AllocCDF16, AllocCommand> {
+    pub fn header_length(&self) -> usize {
+        wire::header_length(self.framing.as_ref())
+    }
+    // the header with the magic number it would carry without framing
+    pub fn canonical_header(&self) -> [u8; interface::HEADER_LENGTH] {
+        let mut ret = [0u8; interface::HEADER_LENGTH];
+        let header_len = self.header_length();
+        ret[wire::MAGIC_NUMBER_LENGTH..].clone_from_slice(
+            &self.header[header_len - (interface::HEADER_LENGTH - wire::MAGIC_NUMBER_LENGTH)..header_len]);
+        match self.framing {
+            // a framed stream has no magic number to tell whether byte 7 holds extended features,
+            // but make_header leaves it zero otherwise
+            Some(_) => if ret[interface::HEADER_EXTENDED_FEATURE_OFFSET] != 0 {
+                ret[..wire::MAGIC_NUMBER_LENGTH].clone_from_slice(&interface::MAGIC_NUMBER_EXTENDED[..]);
+            } else {
+                ret[..wire::MAGIC_NUMBER_LENGTH].clone_from_slice(&interface::MAGIC_NUMBER[..]);
+            },
+            None => ret[..wire::MAGIC_NUMBER_LENGTH].clone_from_slice(&self.header[..wire::MAGIC_NUMBER_LENGTH]),
+        }
+        ret
+    }
     pub fn parse_header(&mut self)->Result<usize, DivansOpResult>{
-        if self.header[0] != interface::MAGIC_NUMBER[0] ||
-            self.header[1] != interface::MAGIC_NUMBER[1] {
-                return Err(DivansOpResult::Failure(ErrMsg::MagicNumberWrongA(self.header[0], self.header[1])));
+        match self.framing {
+            Some(framing) => if self.header[..framing.bytes().len()] != *framing.bytes() {
+                return Err(DivansOpResult::Failure(ErrMsg::FramingTagMismatch));
+            },
+            None => {
+                if self.header[0] != interface::MAGIC_NUMBER[0] ||
+                    self.header[1] != interface::MAGIC_NUMBER[1] {
+                        return Err(DivansOpResult::Failure(ErrMsg::MagicNumberWrongA(self.header[0], self.header[1])));
+                }
+                if self.header[2..4] != interface::MAGIC_NUMBER[2..] &&
+                    self.header[2..4] != interface::MAGIC_NUMBER_EXTENDED[2..] {
+                        return Err(DivansOpResult::Failure(ErrMsg::MagicNumberWrongB(self.header[2], self.header[3])));
+                }
+            },
         }
-        if self.header[2] != interface::MAGIC_NUMBER[2] ||
-            self.header[3] != interface::MAGIC_NUMBER[3] {
-                return Err(DivansOpResult::Failure(ErrMsg::MagicNumberWrongB(self.header[2], self.header[3])));
+        let header = self.canonical_header();
+        if header[interface::HEADER_CODER_ID_OFFSET] != interface::CODER_ID {
+            return Err(DivansOpResult::Failure(ErrMsg::UnsupportedCoder(header[interface::HEADER_CODER_ID_OFFSET])));
         }
-        let window_size = self.header[5] as usize;
+        let window_size = header[5] as usize;
         if window_size < 10 || window_size >= 25 {
             return Err(DivansOpResult::Failure(ErrMsg::BadWindowSize(window_size as u8)));
         }
+        // every bit of the feature byte, and now of the extended feature byte after it, is assigned
+        // (see STREAM_FEATURES_SUPPORTED and EXTENDED_FEATURES_SUPPORTED), so any value is accepted;
+        // further features go in the second extended feature byte, whose unassigned bits are refused
+        let stream_features = header[interface::HEADER_FEATURE_OFFSET];
+        let extended_features2 = interface::extended_stream_features2(&header[..]);
+        if extended_features2 & !interface::EXTENDED_FEATURES2_SUPPORTED != 0 {
+            return Err(DivansOpResult::Failure(ErrMsg::UnsupportedStreamFeatures(extended_features2)));
+        }
+        if interface::checksum_type(&header[..]).is_none() {
+            return Err(DivansOpResult::Failure(ErrMsg::UnsupportedChecksum(header[interface::HEADER_CHECKSUM_OFFSET])));
+        }
+        // a decoder holding a key refuses untagged streams, so the tag cannot simply be stripped
+        match (stream_features & interface::STREAM_FEATURE_AUTHENTICATED != 0, self.authentication_key.is_some()) {
+            (true, false) => return Err(DivansOpResult::Failure(ErrMsg::MissingAuthenticationKey)),
+            (false, true) => return Err(DivansOpResult::Failure(ErrMsg::StreamNotAuthenticated)),
+            _ => {},
+        }
         Ok(window_size)
     }
+    // the settings to decode a stream spliced on after this one with; the allocators follow later.
+    // An authenticated stream is never followed by another, so no key is carried over
+    pub fn next_stream(&self) -> Self {
+        HeaderParser{header:[0u8;wire::MAX_HEADER_LENGTH], read_offset:0,
+                     m8:None, mcdf16:None, mcommand:None,
+                     checksum_policy:self.checksum_policy,
+                     multithread:self.multithread,
+                     authentication_key:None,
+                     tap_commands:self.tap_commands,
+                     stop_between_streams:self.stop_between_streams,
+                     framing:self.framing,
+                     cancel:self.cancel.clone(),
+                     compact_priors:self.compact_priors,
+                     paranoid:self.paranoid,
+                     #[cfg(feature="std")]
+                     threaded_crc:self.threaded_crc,
+                     #[cfg(feature="std")]
+                     pull_timeout:self.pull_timeout,
+                     output_digest:self.output_digest,
+                     window:None,
+                     trailing_bytes_policy:self.trailing_bytes_policy,
+                     trailing_bytes:0,
+        }
+    }
+    // the window set_window gave, if it can hold a window of 1 << window_size bytes; one too small
+    // goes back to m8, and the codec allocates its own
+    pub fn take_window(&mut self, window_size: usize) -> Option<AllocU8::AllocatedMemory> {
+        match self.window.take() {
+            Some(window) => if window.slice().len() >= 1 << window_size {
+                Some(window)
+            } else {
+                if let Some(ref mut m8) = self.m8 {
+                    m8.free_cell(window);
+                }
+                None
+            },
+            None => None,
+        }
+    }
+    // Copies out the input held by a stored frame (see interface::STORED_MAGIC), which takes the
+    // place of a stream; None once the input turns out to start otherwise, with the bytes read so far
+    // left in header.  Past the magic and the length, read_offset also counts the bytes copied.
+    pub fn decode_stored_frame(&mut self,
+                               input:&[u8],
+                               input_offset:&mut usize,
+                               output:&mut [u8],
+                               output_offset:&mut usize) -> Option<DivansResult> {
+        if self.framing.is_some() {
+            return None;
+        }
+        loop {
+            let magic_read = core::cmp::min(self.read_offset, interface::STORED_MAGIC.len());
+            if self.header[..magic_read] != interface::STORED_MAGIC[..magic_read] {
+                return None;
+            }
+            if self.read_offset >= interface::STORED_FRAME_HEADER_LENGTH {
+                break;
+            }
+            if *input_offset == input.len() {
+                return Some(DivansResult::NeedsMoreInput);
+            }
+            self.header[self.read_offset] = input[*input_offset];
+            *input_offset += 1;
+            self.read_offset += 1;
+        }
+        let stored_len = usize::from(self.header[interface::STORED_MAGIC.len()]);
+        let copied = self.read_offset - interface::STORED_FRAME_HEADER_LENGTH;
+        let to_copy = core::cmp::min(stored_len - copied,
+                                     core::cmp::min(input.len() - *input_offset, output.len() - *output_offset));
+        output[*output_offset..*output_offset + to_copy].clone_from_slice(&input[*input_offset..*input_offset + to_copy]);
+        *input_offset += to_copy;
+        *output_offset += to_copy;
+        self.read_offset += to_copy;
+        Some(if copied + to_copy == stored_len {
+            DivansResult::Success
+        } else if *output_offset == output.len() {
+            DivansResult::NeedsMoreOutput
+        } else {
+            DivansResult::NeedsMoreInput
+        })
+    }
+    // the bytes of a stored frame left to copy out, once its length has been read
+    pub fn stored_frame_remaining(&self) -> usize {
+        if self.framing.is_some() || self.read_offset < interface::STORED_FRAME_HEADER_LENGTH
+            || self.header[..interface::STORED_MAGIC.len()] != interface::STORED_MAGIC[..] {
+            return 0;
+        }
+        let stored_len = usize::from(self.header[interface::STORED_MAGIC.len()]);
+        stored_len - (self.read_offset - interface::STORED_FRAME_HEADER_LENGTH)
+    }
     pub fn decode(&mut self,
                   input:&[u8],
                   input_offset:&mut usize) -> (usize, bool, DivansInputResult) {
@@ -57,9 +228,10 @@ impl<AllocU8:Allocator<u8>,
         let window_size: usize;
         let is_multi: bool;
         let remaining = input.len() - *input_offset;
-        let header_left = header_parser.header.len() - header_parser.read_offset;
+        let header_len = header_parser.header_length();
+        let header_left = header_len - header_parser.read_offset;
         if remaining >= header_left {
-            header_parser.header[header_parser.read_offset..].clone_from_slice(
+            header_parser.header[header_parser.read_offset..header_len].clone_from_slice(
                 input.split_at(*input_offset).1.split_at(header_left).0);
             *input_offset += header_left;
             match header_parser.parse_header() {
@@ -84,22 +256,27 @@ impl<AllocU8:Allocator<u8>,
 pub struct DivansProcess<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                      AllocU8:Allocator<u8>,
                          AllocCDF16:Allocator<interface::DefaultCDF16>,
-                         AllocCommand:Allocator<StaticCommand>> {
+                         AllocCommand:Allocator<StaticCommand>,
+                         D:Hasher+Default=SubDigest> {
     codec: Option<codec::DivansCodec<DefaultDecoder,
                               DecoderSpecialization,
                               ThreadToMainDemuxer<AllocU8, SerialWorker<AllocU8, AllocCommand>>,
                               DevNull<AllocU8>,
                               interface::DefaultCDF16,
                               AllocU8,
-                                     AllocCDF16>>,
+                                     AllocCDF16,
+                                     D>>,
     literal_decoder: Option<DivansDecoderCodec<interface::DefaultCDF16,
                                                AllocU8,
                                                AllocCDF16,
                                                AllocCommand,
                                                DefaultDecoder,
-                                               Mux<AllocU8>>>,
-    bytes_encoded: usize,
+                                               Mux<AllocU8>,
+                                               D>>,
+    bytes_encoded: u64,
     mcommand: AllocCommand,
+    stall_watchdog: StallWatchdog,
+    next_stream: Option<HeaderParser<AllocU8, AllocCDF16, AllocCommand>>,
 }
 
 
@@ -107,15 +284,47 @@ pub struct DivansProcess<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAll
 impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + interface::BillingCapability,
      AllocU8:Allocator<u8>,
      AllocCDF16:Allocator<interface::DefaultCDF16>,
-     AllocCommand:Allocator<StaticCommand>> DivansProcess<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand> {
-    fn decode(&mut self,
+     AllocCommand:Allocator<StaticCommand>,
+     D:Hasher+Default> DivansProcess<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, D> {
+    fn decode<Output:DecodeOutput>(&mut self,
+              input:&[u8],
+              input_offset:&mut usize,
+              output:&mut [u8],
+              output_offset: &mut usize) -> DivansResult {
+        let start_offsets = (*input_offset, *output_offset);
+        let had_work = *input_offset < input.len() && *output_offset < output.len();
+        #[cfg(feature="std")]
+        let start = std::time::Instant::now();
+        let result = self.decode_step::<Output>(input, input_offset, output, output_offset);
+        #[cfg(feature="std")]
+        {
+            if let Some(ref mut literal_decoder) = self.literal_decoder {
+                literal_decoder.stats.add_decode_time(&start);
+            }
+        }
+        // parking a decoded command for the caller is progress even when it wrote no bytes
+        let progressed = start_offsets != (*input_offset, *output_offset)
+            || self.literal_decoder.as_ref().map(|ld| ld.tapped_command.is_some()).unwrap_or(false);
+        let result = match result {
+            DivansResult::Success if self.literal_decoder.as_ref().map(|ld| ld.paranoid).unwrap_or(false)
+                && !self.codec.as_ref().map(|codec| codec.priors_valid()).unwrap_or(true) => {
+                DivansResult::Failure(ErrMsg::InvalidCdf)
+            },
+            result => result,
+        };
+        if !Output::RESUMABLE {
+            return result; // a single call, so there is no run of calls to stall
+        }
+        self.stall_watchdog.check(result, progressed, had_work)
+    }
+    fn decode_step<Output:DecodeOutput>(&mut self,
               input:&[u8],
               input_offset:&mut usize,
               output:&mut [u8],
               output_offset: &mut usize) -> DivansResult {
         let process = self;
         let mut unused:usize = 0;
-        let old_output_offset = *output_offset;
+        let mut old_output_offset = *output_offset;
         loop {
             match process.literal_decoder.as_mut().unwrap().decode_process_input(process.codec.as_mut().unwrap().demuxer().get_main_to_thread(),
                                                                                  input,
@@ -123,6 +332,8 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
                 DivansInputResult::Success => {},
                 need_something => return DivansResult::from(need_something),
             }
+            #[cfg(feature="trace")]
+            process.sync_trace_positions(old_output_offset, *output_offset);
             let mut unused_out = 0usize;
             let mut unused_in = 0usize;
             match process.codec.as_mut().unwrap().encode_or_decode(
@@ -145,20 +356,149 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
                 },
                 DivansResult::NeedsMoreOutput => {}, // lets make room for more output
             }
-            let retval = process.literal_decoder.as_mut().unwrap().decode_process_output(
+            let retval = process.literal_decoder.as_mut().unwrap().decode_process_output_of::<Output, _>(
                 process.codec.as_mut().unwrap().demuxer().get_main_to_thread(),
                 output,
-                output_offset);
-            process.bytes_encoded += *output_offset - old_output_offset;
+                output_offset,
+                None);
+            process.bytes_encoded += (*output_offset - old_output_offset) as u64;
+            old_output_offset = *output_offset;
             match retval {
                 DecoderResult::Processed(divans_retval) => {
                     return divans_retval;
                 },
                 DecoderResult::Yield => {
                 },
+                DecoderResult::WouldBlock => unreachable!(), // the serial worker never waits
             }
         }
     }
+    pub fn options(&self) -> Option<DecodedStreamOptions> {
+        match self.literal_decoder {
+            Some(ref literal_decoder) => literal_decoder.options(),
+            None => None,
+        }
+    }
+    pub fn stats(&self) -> DecodeStats {
+        let mut ret = match self.literal_decoder {
+            Some(ref literal_decoder) => literal_decoder.stats(),
+            None => DecodeStats::default(),
+        };
+        if let Some(ref codec) = self.codec {
+            let (command_block_switches, distance_block_switches) = codec.block_switch_counts();
+            ret.command_block_switches = command_block_switches;
+            ret.distance_block_switches = distance_block_switches;
+        }
+        ret
+    }
+    pub fn take_mixer_snapshots(&mut self, out: &mut [MixerSnapshot]) -> usize {
+        match self.literal_decoder {
+            Some(ref mut literal_decoder) => literal_decoder.take_mixer_snapshots(out),
+            None => 0,
+        }
+    }
+    pub fn mixer_snapshots_dropped(&self) -> u64 {
+        match self.literal_decoder {
+            Some(ref literal_decoder) => literal_decoder.mixer_snapshots_dropped(),
+            None => 0,
+        }
+    }
+    pub fn mixer_snapshot(&self) -> MixerSnapshot {
+        match self.literal_decoder {
+            Some(ref literal_decoder) => literal_decoder.mixer_snapshot(),
+            None => MixerSnapshot::default(),
+        }
+    }
+    pub fn expected_crc(&self) -> Option<u32> {
+        match self.literal_decoder {
+            Some(ref literal_decoder) => literal_decoder.expected_crc(),
+            None => None,
+        }
+    }
+    pub fn expected_checksum(&self) -> Option<u64> {
+        match self.literal_decoder {
+            Some(ref literal_decoder) => literal_decoder.expected_checksum(),
+            None => None,
+        }
+    }
+    pub fn output_needed(&self) -> usize {
+        match self.literal_decoder {
+            Some(ref literal_decoder) => literal_decoder.output_needed(),
+            None => 0,
+        }
+    }
+    pub fn take_window(&mut self) -> Option<AllocU8::AllocatedMemory> {
+        match self.literal_decoder {
+            Some(ref mut literal_decoder) => literal_decoder.take_window(),
+            None => None,
+        }
+    }
+    pub fn window(&self) -> Option<(&[u8], usize)> {
+        match self.literal_decoder {
+            Some(ref literal_decoder) => literal_decoder.window(),
+            None => None,
+        }
+    }
+    // once decode has returned Success, the settings for the stream spliced on after this one, if any
+    pub fn take_next_stream(&mut self) -> Option<HeaderParser<AllocU8, AllocCDF16, AllocCommand>> {
+        match self.literal_decoder {
+            Some(ref literal_decoder) if literal_decoder.another_stream_follows() => self.next_stream.take(),
+            _ => None,
+        }
+    }
+    // the output digest is kept with the settings for the next stream, to be carried on with them
+    pub fn output_digest(&self) -> Option<&OutputDigest> {
+        self.next_stream.as_ref().and_then(|next_stream| next_stream.output_digest.as_ref())
+    }
+    pub fn output_digest_mut(&mut self) -> Option<&mut OutputDigest> {
+        self.next_stream.as_mut().and_then(|next_stream| next_stream.output_digest.as_mut())
+    }
+    pub fn next_stream(&self) -> Option<&HeaderParser<AllocU8, AllocCDF16, AllocCommand>> {
+        self.next_stream.as_ref()
+    }
+    pub fn next_stream_mut(&mut self) -> Option<&mut HeaderParser<AllocU8, AllocCDF16, AllocCommand>> {
+        self.next_stream.as_mut()
+    }
+    pub fn take_command(&mut self) -> Option<Command<AllocatedMemoryPrefix<u8, AllocU8>>> {
+        match self.literal_decoder {
+            Some(ref mut literal_decoder) => literal_decoder.tapped_command.take(),
+            None => None,
+        }
+    }
+    pub fn free_command(&mut self, cmd: Command<AllocatedMemoryPrefix<u8, AllocU8>>) {
+        if let Some(ref mut literal_decoder) = self.literal_decoder {
+            literal_decoder.free_tapped_command(cmd);
+        }
+    }
+    pub fn take_digest(&mut self) -> D {
+        if let Some(ref mut literal_decoder) = self.literal_decoder {
+            return literal_decoder.take_digest();
+        }
+        match self.codec {
+            Some(ref mut codec) => codec.take_digest(),
+            None => D::default(),
+        }
+    }
+    // the command codec only sees the main thread's buffers, so it borrows the literal decoder's byte counts
+    #[cfg(feature="trace")]
+    fn sync_trace_positions(&mut self, old_output_offset: usize, output_offset: usize) {
+        if let (Some(ref mut codec), Some(ref mut literal_decoder)) = (self.codec.as_mut(), self.literal_decoder.as_mut()) {
+            literal_decoder.trace.output_pos = self.bytes_encoded.wrapping_sub(old_output_offset as u64);
+            codec.trace().input_pos = literal_decoder.trace.input_pos;
+            codec.trace().output_pos = literal_decoder.trace.output_pos.wrapping_add(output_offset as u64);
+        }
+    }
+    #[cfg(feature="trace")]
+    pub fn take_trace(&mut self) -> Vec<u8> {
+        let mut log = TraceLog::default();
+        if let Some(ref mut codec) = self.codec {
+            log.merge(codec.trace());
+        }
+        if let Some(ref mut literal_decoder) = self.literal_decoder {
+            log.merge(&mut literal_decoder.trace);
+        }
+        log.take()
+    }
     pub fn free(mut self) -> (AllocU8, AllocCDF16, AllocCommand) {
         use codec::NUM_ARITHMETIC_CODERS;
         if let Some(mut codec) = core::mem::replace(&mut self.codec, None) {
@@ -191,23 +531,27 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
 pub enum DivansDecompressor<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                             AllocU8:Allocator<u8>,
                             AllocCDF16:Allocator<interface::DefaultCDF16>,
-                            AllocCommand:Allocator<StaticCommand>> {
+                            AllocCommand:Allocator<StaticCommand>,
+                            D:Hasher+Default=SubDigest> {
     Header(HeaderParser<AllocU8, AllocCDF16, AllocCommand>),
     Decode(DivansProcess<DefaultDecoder,
            AllocU8,
            AllocCDF16,
-           AllocCommand>),
+           AllocCommand,
+           D>),
     MultiDecode(ParallelDivansProcess<DefaultDecoder,
                                       AllocU8,
                                       AllocCDF16,
-                                      AllocCommand>),
+                                      AllocCommand,
+                                      D>),
 }
 
 impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
      AllocU8:Allocator<u8>,
      AllocCDF16:Allocator<interface::DefaultCDF16>,
-     AllocCommand:Allocator<StaticCommand>>
-    DivansDecompressor<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand> {
+     AllocCommand:Allocator<StaticCommand>,
+     D:Hasher+Default>
+    DivansDecompressor<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, D> {
 
     fn finish_parsing_header_serial(&mut self, window_size: usize) -> DivansResult {
         if window_size < 10 {
@@ -219,16 +563,42 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
         let mut m8:AllocU8;
         let mcdf16:AllocCDF16;
         let raw_header:[u8; interface::HEADER_LENGTH];
-        let skip_crc:bool;
+        let framed_header:[u8; wire::MAX_HEADER_LENGTH];
+        let framed_header_len;
+        let framing;
+        let next_stream;
+        let checksum_policy;
+        let authentication_key;
+        let tap_commands;
+        let cancel;
+        let compact_priors;
+        let paranoid;
+        #[cfg(feature="std")]
+        let threaded_crc;
+        let window;
         let mut mcommand:AllocCommand;
         match *self {
             DivansDecompressor::Header(ref mut header) => {
+                window = header.take_window(window_size);
                 m8 = match core::mem::replace(&mut header.m8, None) {
                     None => return DivansResult::Failure(ErrMsg::MissingAllocator(8)),
                     Some(m) => m,
                 };
-                raw_header = header.header;
-                skip_crc = header.skip_crc;
+                raw_header = header.canonical_header();
+                framed_header = header.header;
+                framed_header_len = header.header_length();
+                framing = header.framing;
+                next_stream = header.next_stream();
+                checksum_policy = header.checksum_policy;
+                authentication_key = header.authentication_key;
+                tap_commands = header.tap_commands;
+                cancel = header.cancel.clone();
+                compact_priors = header.compact_priors;
+                paranoid = header.paranoid;
+                #[cfg(feature="std")]
+                {
+                    threaded_crc = header.threaded_crc;
+                }
             },
             _ => return DivansResult::Failure(ErrMsg::WrongInternalDecoderState),
         }
@@ -261,7 +631,8 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                                              DevNull<AllocU8>,
                                              interface::DefaultCDF16,
                                              AllocU8,
-                                             AllocCDF16>::new(m8,
+                                             AllocCDF16,
+                                             D>::new_with_window(m8,
                                                               mcdf16,
                                                               cmd_decoder,
                                                               lit_decoder,
@@ -273,19 +644,54 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                                                               None,
                                                           true,
                                                               codec::StrideSelection::UseBrotliRec,
-                                                              skip_crc);
-        if !skip_crc {
-            codec.get_crc().write(&raw_header[..]);
+                                                              !checksum_policy.hashes_stream(),
+                                                              window);
+        codec.set_checksum_policy(checksum_policy);
+        codec.set_stream_features(raw_header[interface::HEADER_FEATURE_OFFSET]);
+        codec.set_extended_stream_features(interface::extended_stream_features(&raw_header[..]));
+        codec.set_freeze_adaptation_after(interface::freeze_adaptation_after(&raw_header[..]));
+        codec.set_priors_reset_interval(interface::priors_reset_interval(&raw_header[..]));
+        codec.set_extended_stream_features2(interface::extended_stream_features2(&raw_header[..]));
+        codec.set_framing_tag(framing);
+        // parse_header refused checksums this build does not know
+        if let Some(checksum) = interface::checksum_type(&raw_header[..]) {
+            codec.set_checksum_type(checksum);
+        }
+        codec.set_cancel_token(cancel);
+        codec.set_compact_priors(compact_priors);
+        if let Some(key) = authentication_key {
+            codec.set_authentication_key(&key[..]);
+        }
+        if checksum_policy.hashes_stream() {
+            codec.get_crc().write(&framed_header[..framed_header_len]);
         }
-        let main_thread_codec = codec.fork(&mut mcommand);
-        assert_eq!(*codec.get_crc(), main_thread_codec.crc);
+        #[cfg(feature="trace")]
+        {
+            codec.trace().input_pos = framed_header_len as u64;
+        }
+        let mut main_thread_codec = codec.fork(&mut mcommand);
+        main_thread_codec.observe_header(&raw_header[..]);
+        main_thread_codec.tap_commands = tap_commands;
+        main_thread_codec.paranoid = paranoid;
+        #[cfg(feature="std")]
+        {
+            if threaded_crc && checksum_policy.hashes_stream() && main_thread_codec.crc.wide.is_none() {
+                main_thread_codec.crc_worker = Some(CrcWorker::new(main_thread_codec.crc.crc));
+            }
+        }
+        if tap_commands {
+            codec.forward_block_switches();
+        }
+        assert_eq!(codec.get_crc().crc, main_thread_codec.crc.crc);
         core::mem::replace(self,
                            DivansDecompressor::Decode(
-                               DivansProcess::<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand> {
+                               DivansProcess::<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, D> {
                                    codec:Some(codec),
                                    literal_decoder:Some(main_thread_codec),
                                    bytes_encoded:0,
                                    mcommand:mcommand,
+                                   stall_watchdog: StallWatchdog::default(),
+                                   next_stream: Some(next_stream),
                                }));
         DivansResult::Success
     }
@@ -294,6 +700,407 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
 
 macro_rules! free_body {
     () => {
+    // how the stream was encoded; None until the header and first prediction mode command are decoded
+    pub fn options(&self) -> Option<DecodedStreamOptions> {
+        match *self {
+            DivansDecompressor::Header(_) => None,
+            DivansDecompressor::MultiDecode(ref process) => process.options(),
+            DivansDecompressor::Decode(ref process) => process.options(),
+        }
+    }
+    // what the stream decoded to so far; complete once decode has returned Success
+    pub fn stats(&self) -> DecodeStats {
+        match *self {
+            DivansDecompressor::Header(_) => DecodeStats::default(),
+            DivansDecompressor::MultiDecode(ref process) => process.stats(),
+            DivansDecompressor::Decode(ref process) => process.stats(),
+        }
+    }
+    // Moves the literal mixer snapshots taken at each literal block switch into out, oldest first,
+    // and returns how many.  Up to MIXER_SNAPSHOT_QUEUE_SIZE wait between calls; the rest are dropped.
+    pub fn take_mixer_snapshots(&mut self, out: &mut [MixerSnapshot]) -> usize {
+        match *self {
+            DivansDecompressor::Header(_) => 0,
+            DivansDecompressor::MultiDecode(ref mut process) => process.take_mixer_snapshots(out),
+            DivansDecompressor::Decode(ref mut process) => process.take_mixer_snapshots(out),
+        }
+    }
+    // snapshots lost because take_mixer_snapshots was not called often enough
+    pub fn mixer_snapshots_dropped(&self) -> u64 {
+        match *self {
+            DivansDecompressor::Header(_) => 0,
+            DivansDecompressor::MultiDecode(ref process) => process.mixer_snapshots_dropped(),
+            DivansDecompressor::Decode(ref process) => process.mixer_snapshots_dropped(),
+        }
+    }
+    // the mixer within the current literal block, eg. to record the final block once decode succeeds
+    pub fn mixer_snapshot(&self) -> MixerSnapshot {
+        match *self {
+            DivansDecompressor::Header(_) => MixerSnapshot::default(),
+            DivansDecompressor::MultiDecode(ref process) => process.mixer_snapshot(),
+            DivansDecompressor::Decode(ref process) => process.mixer_snapshot(),
+        }
+    }
+    // replaces the policy the constructor's skip_crc picked; only possible before the first decode
+    pub fn set_checksum_policy(&mut self, policy: ChecksumPolicy) -> Result<(), ErrMsg> {
+        match *self {
+            DivansDecompressor::Header(ref mut header) if header.read_offset == 0 => {
+                header.checksum_policy = policy;
+                Ok(())
+            },
+            _ => Err(ErrMsg::WrongInternalDecoderState),
+        }
+    }
+    // Makes decode return Success at the end of each spliced stream too, with input_offset just past
+    // its trailer and the decompressor ready for the next; only possible before the first decode
+    pub fn set_stop_between_streams(&mut self, stop: bool) -> Result<(), ErrMsg> {
+        match *self {
+            DivansDecompressor::Header(ref mut header) if header.read_offset == 0 => {
+                header.stop_between_streams = stop;
+                Ok(())
+            },
+            _ => Err(ErrMsg::WrongInternalDecoderState),
+        }
+    }
+    // Expects framing in place of the magic number and the checksum tag (see wire::FramingTag), in
+    // this stream and any spliced after it; only possible before the first decode
+    pub fn set_framing_tag(&mut self, framing: Option<wire::FramingTag>) -> Result<(), ErrMsg> {
+        match *self {
+            DivansDecompressor::Header(ref mut header) if header.read_offset == 0 => {
+                header.framing = framing;
+                Ok(())
+            },
+            _ => Err(ErrMsg::WrongInternalDecoderState),
+        }
+    }
+    // Fails decode with ErrMsg::Cancelled before the next command once token is cancelled, including on
+    // the worker thread of a multithreaded decompressor; only possible before the first decode.
+    // A single threaded decompressor carries on when called again after the token is reset;
+    // a multithreaded one has lost its worker and can only be freed.
+    pub fn set_cancel_token(&mut self, token: CancelToken) -> Result<(), ErrMsg> {
+        match *self {
+            DivansDecompressor::Header(ref mut header) if header.read_offset == 0 => {
+                header.cancel = token;
+                Ok(())
+            },
+            _ => Err(ErrMsg::WrongInternalDecoderState),
+        }
+    }
+    // Keeps the high nibble literal priors of just the contexts the stream has used so far, returning the
+    // rest to mcdf16 (see MainThreadContext::obs_prediction_mode_context_map); on by default.  Turning it
+    // off trades the memory for never reallocating mid stream.  Only possible before the first decode.
+    // A multithreaded decoder never compacts: its worker, which decodes the literals, cannot allocate.
+    pub fn set_compact_priors(&mut self, compact: bool) -> Result<(), ErrMsg> {
+        match *self {
+            DivansDecompressor::Header(ref mut header) if header.read_offset == 0 => {
+                header.compact_priors = compact;
+                Ok(())
+            },
+            _ => Err(ErrMsg::WrongInternalDecoderState),
+        }
+    }
+    // Checks what decoding alone would take on trust: that no copy reaches before the start of its
+    // stream, and that every model still holds a valid CDF at the end of it, failing with
+    // ErrMsg::CopyBeforeStreamStart or ErrMsg::InvalidCdf.  The command models are only checked single
+    // threaded.  Off by default; only possible before the first decode.
+    pub fn set_paranoid(&mut self, paranoid: bool) -> Result<(), ErrMsg> {
+        match *self {
+            DivansDecompressor::Header(ref mut header) if header.read_offset == 0 => {
+                header.paranoid = paranoid;
+                Ok(())
+            },
+            _ => Err(ErrMsg::WrongInternalDecoderState),
+        }
+    }
+    // Hashes the stream for the crc on a thread of its own (ChecksumPolicy::Verify only), overlapping it
+    // with decoding; only possible before the first decode.  The digest D and any mac stay on the decoding thread.
+    #[cfg(feature="std")]
+    pub fn set_threaded_crc(&mut self, threaded: bool) -> Result<(), ErrMsg> {
+        match *self {
+            DivansDecompressor::Header(ref mut header) if header.read_offset == 0 => {
+                header.threaded_crc = threaded;
+                Ok(())
+            },
+            _ => Err(ErrMsg::WrongInternalDecoderState),
+        }
+    }
+    // Bounds how long a multithreaded decode waits on its worker thread for commands: once none have
+    // come for timeout, decode returns NeedsMoreOutput, perhaps with nothing written, and is to be called
+    // again later, eg. by an async runtime with no thread to block.  Zero never waits; None, the default,
+    // waits as long as it takes.  Only possible before the first decode.
+    #[cfg(feature="std")]
+    pub fn set_pull_timeout(&mut self, timeout: Option<Duration>) -> Result<(), ErrMsg> {
+        match *self {
+            DivansDecompressor::Header(ref mut header) if header.read_offset == 0 => {
+                header.pull_timeout = timeout;
+                Ok(())
+            },
+            _ => Err(ErrMsg::WrongInternalDecoderState),
+        }
+    }
+    // Hashes the decompressed output with kind as decode writes it, across any spliced streams and
+    // stored frames; only possible before the first decode.  None turns it back off.
+    pub fn set_output_digest(&mut self, kind: Option<OutputDigestType>) -> Result<(), ErrMsg> {
+        match *self {
+            DivansDecompressor::Header(ref mut header) if header.read_offset == 0 => {
+                header.output_digest = kind.map(OutputDigest::new);
+                Ok(())
+            },
+            _ => Err(ErrMsg::WrongInternalDecoderState),
+        }
+    }
+    // Decodes into window, a cell of the caller's, rather than a ring buffer allocated from m8, so a
+    // proxy whose allocator carves cells from a shared arena can pick where each decoder's window lives.
+    // Its length is a power of two at least as large as the stream's window, or it goes back to m8 and
+    // the decoder allocates as usual; what it holds is cleared.  Only the first of spliced streams decodes
+    // into it.  Only possible before the first decode: otherwise window is handed straight back.
+    pub fn set_window(&mut self, window: AllocU8::AllocatedMemory) -> Result<(), AllocU8::AllocatedMemory> {
+        match *self {
+            DivansDecompressor::Header(ref mut header) if header.read_offset == 0 && header.window.is_none()
+                && window.slice().len().is_power_of_two() => {
+                header.window = Some(window);
+                Ok(())
+            },
+            _ => Err(window),
+        }
+    }
+    // What decode does with input past the trailer of the last stream (see TrailingBytesPolicy);
+    // Reject unless set.  Only possible before the first decode.
+    pub fn set_trailing_bytes_policy(&mut self, policy: TrailingBytesPolicy) -> Result<(), ErrMsg> {
+        match *self {
+            DivansDecompressor::Header(ref mut header) if header.read_offset == 0 => {
+                header.trailing_bytes_policy = policy;
+                Ok(())
+            },
+            _ => Err(ErrMsg::WrongInternalDecoderState),
+        }
+    }
+    // Under TrailingBytesPolicy::Count, the bytes dropped past the last stream so far; None under
+    // the other policies.  0 until decode has returned Success for the last stream.
+    pub fn trailing_bytes(&self) -> Option<u64> {
+        let settings = match *self {
+            DivansDecompressor::Header(ref header) => Some(header),
+            DivansDecompressor::MultiDecode(ref process) => process.next_stream(),
+            DivansDecompressor::Decode(ref process) => process.next_stream(),
+        };
+        match settings {
+            Some(settings) if settings.trailing_bytes_policy == TrailingBytesPolicy::Count => Some(settings.trailing_bytes),
+            _ => None,
+        }
+    }
+    // once decode has returned Success for the last stream, consumes what follows it unless rejected
+    fn drop_trailing_bytes(&mut self, input:&[u8], input_offset:&mut usize) {
+        let settings = match *self {
+            // between spliced streams the next one follows, not trailing bytes
+            DivansDecompressor::Header(ref mut header) if header.read_offset == 0 => None,
+            DivansDecompressor::Header(ref mut header) => Some(header),
+            DivansDecompressor::MultiDecode(ref mut process) => process.next_stream_mut(),
+            DivansDecompressor::Decode(ref mut process) => process.next_stream_mut(),
+        };
+        if let Some(settings) = settings {
+            if settings.trailing_bytes_policy != TrailingBytesPolicy::Reject {
+                settings.trailing_bytes += (input.len() - *input_offset) as u64;
+                *input_offset = input.len();
+            }
+        }
+    }
+    // Once decode has returned Success for the last stream, takes its ring buffer, the window of set_window
+    // or one from m8, with no copy; the caller frees it to m8 or where it came from.  The stream's last
+    // byte is at (bytes decoded - 1) & (len - 1).  None before then, and after it has been taken.
+    pub fn take_window(&mut self) -> Option<AllocU8::AllocatedMemory> {
+        match *self {
+            DivansDecompressor::Header(_) => None,
+            DivansDecompressor::MultiDecode(ref mut process) => process.take_window(),
+            DivansDecompressor::Decode(ref mut process) => process.take_window(),
+        }
+    }
+    // Once decode has returned Success for the last stream, its final window in place, and the index
+    // the next byte would have been written at: the output's last min(len, bytes decoded) bytes end
+    // just before it, wrapping around the end, eg. to seed a related stream decoded or encoded next.
+    // None for a stored frame, before Success, and once take_window has taken it.
+    pub fn window(&self) -> Option<(&[u8], usize)> {
+        match *self {
+            DivansDecompressor::Header(_) => None,
+            DivansDecompressor::MultiDecode(ref process) => process.window(),
+            DivansDecompressor::Decode(ref process) => process.window(),
+        }
+    }
+    // the digest of the output written so far, complete once decode has returned Success;
+    // None unless set_output_digest picked one
+    pub fn output_digest(&self) -> Option<u64> {
+        let digest = match *self {
+            DivansDecompressor::Header(ref header) => header.output_digest.as_ref(),
+            DivansDecompressor::MultiDecode(ref process) => process.output_digest(),
+            DivansDecompressor::Decode(ref process) => process.output_digest(),
+        };
+        digest.map(OutputDigest::finish)
+    }
+    fn output_digest_mut(&mut self) -> Option<&mut OutputDigest> {
+        match *self {
+            DivansDecompressor::Header(ref mut header) => header.output_digest.as_mut(),
+            DivansDecompressor::MultiDecode(ref mut process) => process.output_digest_mut(),
+            DivansDecompressor::Decode(ref mut process) => process.output_digest_mut(),
+        }
+    }
+    // the crc stored in the stream's trailer, once decode has read it; None under TrustCaller.
+    // Under SkipButParse the caller may check it against a crc32c (SubDigest) of the header and
+    // the compressed bytes before the trailer.  None for a stream of another ChecksumType.
+    pub fn expected_crc(&self) -> Option<u32> {
+        match *self {
+            DivansDecompressor::Header(_) => None,
+            DivansDecompressor::MultiDecode(ref process) => process.expected_crc(),
+            DivansDecompressor::Decode(ref process) => process.expected_crc(),
+        }
+    }
+    // expected_crc, or the XXH3-64 (Xxh3) a ChecksumType::Xxh3 stream stores in its place
+    pub fn expected_checksum(&self) -> Option<u64> {
+        match *self {
+            DivansDecompressor::Header(_) => None,
+            DivansDecompressor::MultiDecode(ref process) => process.expected_checksum(),
+            DivansDecompressor::Decode(ref process) => process.expected_checksum(),
+        }
+    }
+    // Once decode has returned NeedsMoreOutput, the room in output the next call needs to get past
+    // where this one stopped: the decoded bytes waiting to be written, or the rest of a stored frame.
+    // Smaller buffers still make progress, a piece at a time.  0 when room is not what decode is
+    // waiting on: a tapped command to take (see CommandTap) or a pull timeout to retry after.
+    pub fn output_needed(&self) -> usize {
+        match *self {
+            DivansDecompressor::Header(ref header) => header.stored_frame_remaining(),
+            DivansDecompressor::MultiDecode(ref process) => process.output_needed(),
+            DivansDecompressor::Decode(ref process) => process.output_needed(),
+        }
+    }
+    // the secondary digest of the compressed bytes consumed so far (the crc trailer is unaffected)
+    pub fn take_digest(&mut self) -> D {
+        match *self {
+            DivansDecompressor::Header(_) => D::default(),
+            DivansDecompressor::MultiDecode(ref mut process) => process.take_digest(),
+            DivansDecompressor::Decode(ref mut process) => process.take_digest(),
+        }
+    }
+    // the serialized state machine trace recorded so far (see codec::trace)
+    #[cfg(feature="trace")]
+    pub fn take_trace(&mut self) -> Vec<u8> {
+        match *self {
+            DivansDecompressor::Header(_) => TraceLog::default().take(),
+            DivansDecompressor::MultiDecode(ref mut process) => process.take_trace(),
+            DivansDecompressor::Decode(ref mut process) => process.take_trace(),
+        }
+    }
+    // moves on from a stream whose trailer says another follows to the header of that one,
+    // keeping the allocators; what options and stats report starts over with it
+    fn start_next_stream(&mut self, next_stream: HeaderParser<AllocU8, AllocCDF16, AllocCommand>) {
+        let (m8, mcdf16, mcommand) = core::mem::replace(self, DivansDecompressor::Header(next_stream)).free();
+        if let DivansDecompressor::Header(ref mut header) = *self {
+            header.m8 = Some(m8);
+            header.mcdf16 = Some(mcdf16);
+            header.mcommand = Some(mcommand);
+        }
+    }
+    // decode for a stream spliced on after one whose trailer was just checked
+    fn decode_next_stream<Output:DecodeOutput>(&mut self,
+                          next_stream: HeaderParser<AllocU8, AllocCDF16, AllocCommand>,
+                          input:&[u8],
+                          input_offset:&mut usize,
+                          output:&mut [u8],
+                          output_offset: &mut usize) -> DivansResult {
+        let stop = next_stream.stop_between_streams;
+        self.start_next_stream(next_stream);
+        if stop {
+            return DivansResult::Success;
+        }
+        self.decode_streams::<Output>(input, input_offset, output, output_offset)
+    }
+    // decode, less the output digest, which is fed once at the end of each call
+    fn decode_streams<Output:DecodeOutput>(&mut self,
+                      input:&[u8],
+                      input_offset:&mut usize,
+                      output:&mut [u8],
+                      output_offset: &mut usize) -> DivansResult {
+        let window_size: usize;
+        let is_multi: bool;
+        match *self  {
+            DivansDecompressor::Header(ref mut header_parser) => {
+                match header_parser.decode_stored_frame(input, input_offset, output, output_offset) {
+                    Some(DivansResult::NeedsMoreOutput) if !Output::RESUMABLE => {
+                        return DivansResult::Failure(ErrMsg::OutputTooSmall);
+                    },
+                    Some(res) => return res,
+                    None => {},
+                }
+                let (ws, mul, ret) = header_parser.decode(input, input_offset);
+                if let DivansInputResult::Success = ret {
+                    window_size = ws;
+                    is_multi = mul;
+                } else {
+                    return DivansResult::from(ret);
+                }
+            },
+            DivansDecompressor::MultiDecode(ref mut process) => {
+                match process.decode(input, input_offset, output, output_offset) {
+                    DivansResult::Success => match process.take_next_stream() {
+                        Some(next_stream) => return self.decode_next_stream::<Output>(next_stream, input, input_offset,
+                                                                            output, output_offset),
+                        None => return DivansResult::Success,
+                    },
+                    res => return res,
+                }
+            },
+            DivansDecompressor::Decode(ref mut process) => {
+                match process.decode::<Output>(input, input_offset, output, output_offset) {
+                    DivansResult::Success => match process.take_next_stream() {
+                        Some(next_stream) => return self.decode_next_stream::<Output>(next_stream, input, input_offset,
+                                                                            output, output_offset),
+                        None => return DivansResult::Success,
+                    },
+                    res => return res,
+                }
+            },
+        }
+        if is_multi {
+            let par_proc;
+            {
+                if let DivansDecompressor::Header(ref mut header) = *self {
+                    par_proc = ParallelDivansProcess::<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, D>::new(header, window_size);
+                } else {
+                    return DivansResult::Failure(ErrMsg::WrongInternalDecoderState);
+                }
+            }
+            *self = DivansDecompressor::MultiDecode(par_proc);
+        } else {
+            self.finish_parsing_header_serial(window_size);
+        }
+        if *input_offset < input.len() {
+            return self.decode_streams::<Output>(input, input_offset, output, output_offset);
+        }
+        DivansResult::NeedsMoreInput
+    }
+    // decode for a caller with room for all the output the rest of input decodes to, as
+    // decompress_into has: rather than pausing with NeedsMoreOutput to resume on the next call, the
+    // serial decoder fails with OutputTooSmall once output is full
+    pub fn decode_contiguous(&mut self,
+                             input:&[u8],
+                             input_offset:&mut usize,
+                             output:&mut [u8],
+                             output_offset: &mut usize) -> DivansResult {
+        self.decode_with::<ContiguousOutput>(input, input_offset, output, output_offset)
+    }
+    fn decode_with<Output:DecodeOutput>(&mut self,
+                                        input:&[u8],
+                                        input_offset:&mut usize,
+                                        output:&mut [u8],
+                                        output_offset: &mut usize) -> DivansResult {
+        let output_start = *output_offset;
+        let ret = self.decode_streams::<Output>(input, input_offset, output, output_offset);
+        if let Some(digest) = self.output_digest_mut() {
+            digest.write(&output[output_start..*output_offset]);
+        }
+        if let DivansResult::Success = ret {
+            self.drop_trailing_bytes(input, input_offset);
+        }
+        ret
+    }
     pub fn free_ref(&mut self) {
         match self {
             DivansDecompressor::Header(_parser) => {},
@@ -307,7 +1114,10 @@ macro_rules! free_body {
     }
     pub fn free(self) -> (AllocU8, AllocCDF16, AllocCommand) {
         match self {
-            DivansDecompressor::Header(parser) => {
+            DivansDecompressor::Header(mut parser) => {
+                if let Some(window) = parser.window.take() {
+                    parser.m8.as_mut().unwrap().free_cell(window);
+                }
                 (parser.m8.unwrap(),
                  parser.mcdf16.unwrap(),
                  parser.mcommand.unwrap(),
@@ -327,10 +1137,12 @@ macro_rules! free_body {
 impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + interface::BillingCapability,
      AllocU8:Allocator<u8>,
      AllocCDF16:Allocator<interface::DefaultCDF16>,
-     AllocCommand:Allocator<StaticCommand>>
-    DivansDecompressor<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand>
+     AllocCommand:Allocator<StaticCommand>,
+     D:Hasher+Default>
+    DivansDecompressor<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, D>
     where
         DefaultDecoder: Send + 'static,  // fixme: only demand send if not no-stdlib
+        D: Send + 'static,
         AllocCommand : Send + 'static,
         AllocCDF16 : Send + 'static,
         AllocU8 : Send + 'static,
@@ -345,8 +1157,9 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
 impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + interface::BillingCapability,
      AllocU8:Allocator<u8>,
      AllocCDF16:Allocator<interface::DefaultCDF16>,
-     AllocCommand:Allocator<StaticCommand>>
-    DivansDecompressor<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand> {
+     AllocCommand:Allocator<StaticCommand>,
+     D:Hasher+Default>
+    DivansDecompressor<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, D> {
         free_body!();
 }
 
@@ -358,44 +1171,8 @@ macro_rules! decode_body {
               input_offset:&mut usize,
               output:&mut [u8],
               output_offset: &mut usize) -> DivansResult {
-        let window_size: usize;
-        let is_multi: bool;
-        match *self  {
-            DivansDecompressor::Header(ref mut header_parser) => {
-                let (ws, mul, ret) = header_parser.decode(input, input_offset);
-                if let DivansInputResult::Success = ret {
-                    window_size = ws;
-                    is_multi = mul;
-                } else {
-                    return DivansResult::from(ret);
-                }
-            },
-            DivansDecompressor::MultiDecode(ref mut process) => {
-                return process.decode(input, input_offset, output, output_offset);
-            },
-            DivansDecompressor::Decode(ref mut process) => {
-                return process.decode(input, input_offset, output, output_offset);
-            },
-        }
-        if is_multi {
-            let par_proc;
-            {
-                if let DivansDecompressor::Header(ref mut header) = *self {
-                    par_proc = ParallelDivansProcess::<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand>::new(header, window_size);
-                } else {
-                    return DivansResult::Failure(ErrMsg::WrongInternalDecoderState);
-                }
-            }
-            *self = DivansDecompressor::MultiDecode(par_proc);
-        } else {
-            self.finish_parsing_header_serial(window_size);
-        }
-        if *input_offset < input.len() {
-            return self.decode(input, input_offset, output, output_offset);
-        }
-        DivansResult::NeedsMoreInput
+        self.decode_with::<ResumableOutput>(input, input_offset, output, output_offset)
     }
-        
     }
 }
 
@@ -403,12 +1180,15 @@ macro_rules! decode_body {
 impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + interface::BillingCapability,
      AllocU8:Allocator<u8>,
      AllocCDF16:Allocator<interface::DefaultCDF16>,
-     AllocCommand:Allocator<StaticCommand>> Decompressor for DivansDecompressor<DefaultDecoder,
-                                                                                AllocU8,
-                                                                                AllocCDF16,
-                                                                                AllocCommand>
+     AllocCommand:Allocator<StaticCommand>,
+     D:Hasher+Default> Decompressor for DivansDecompressor<DefaultDecoder,
+                                                           AllocU8,
+                                                           AllocCDF16,
+                                                           AllocCommand,
+                                                           D>
     where
         DefaultDecoder: Send + 'static,  // fixme: only demand send if not no-stdlib
+        D: Send + 'static,
         AllocCommand : Send + 'static,
         AllocCDF16 : Send + 'static,
         AllocU8 : Send + 'static,
@@ -423,13 +1203,44 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
 impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + interface::BillingCapability,
      AllocU8:Allocator<u8>,
      AllocCDF16:Allocator<interface::DefaultCDF16>,
-     AllocCommand:Allocator<StaticCommand>> Decompressor for DivansDecompressor<DefaultDecoder,
-                                                                                AllocU8,
-                                                                                AllocCDF16,
-                                                                                AllocCommand> {
+     AllocCommand:Allocator<StaticCommand>,
+     D:Hasher+Default> Decompressor for DivansDecompressor<DefaultDecoder,
+                                                           AllocU8,
+                                                           AllocCDF16,
+                                                           AllocCommand,
+                                                           D> {
     decode_body!();
 }
 
+impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + interface::BillingCapability,
+     AllocU8:Allocator<u8>,
+     AllocCDF16:Allocator<interface::DefaultCDF16>,
+     AllocCommand:Allocator<StaticCommand>,
+     D:Hasher+Default> CommandTap<AllocU8> for DivansDecompressor<DefaultDecoder,
+                                                                  AllocU8,
+                                                                  AllocCDF16,
+                                                                  AllocCommand,
+                                                                  D> {
+    // only the serial decoder can tap its commands, so a multithreaded decompressor is switched to it
+    fn tap_commands(&mut self) {
+        if let DivansDecompressor::Header(ref mut header) = *self {
+            header.tap_commands = true;
+            header.multithread = false;
+        }
+    }
+    fn take_command(&mut self) -> Option<Command<AllocatedMemoryPrefix<u8, AllocU8>>> {
+        match *self {
+            DivansDecompressor::Decode(ref mut process) => process.take_command(),
+            _ => None,
+        }
+    }
+    fn free_command(&mut self, cmd: Command<AllocatedMemoryPrefix<u8, AllocU8>>) {
+        if let DivansDecompressor::Decode(ref mut process) = *self {
+            process.free_command(cmd);
+        }
+    }
+}
+
 pub trait DivansDecompressorFactory<
      AllocU8:Allocator<u8>,
     AllocCDF16:Allocator<interface::DefaultCDF16>,
@@ -440,10 +1251,62 @@ pub trait DivansDecompressorFactory<
            mc: AllocCommand,
            skip_crc:bool,
            multithread:bool) -> DivansDecompressor<Self::DefaultDecoder, AllocU8, AllocCDF16, AllocCommand> {
-        DivansDecompressor::Header(HeaderParser{header:[0u8;interface::HEADER_LENGTH], read_offset:0,
+        Self::new_with_digest::<SubDigest>(m8, mcdf16, mc, skip_crc, multithread)
+    }
+    // like new() but additionally feeds every byte of the compressed stream to a D,
+    // retrievable with DivansDecompressor::take_digest()
+    fn new_with_digest<D:Hasher+Default>(m8: AllocU8,
+                                         mcdf16:AllocCDF16,
+                                         mc: AllocCommand,
+                                         skip_crc:bool,
+                                         multithread:bool) -> DivansDecompressor<Self::DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, D> {
+        DivansDecompressor::Header(HeaderParser{header:[0u8;wire::MAX_HEADER_LENGTH], read_offset:0,
+                                                m8:Some(m8), mcdf16:Some(mcdf16), mcommand:Some(mc),
+                                                checksum_policy:ChecksumPolicy::from_skip_crc(skip_crc),
+                                                multithread:multithread,
+                                                authentication_key:None,
+                                                tap_commands:false,
+                                                stop_between_streams:false,
+                                                framing:None,
+                                                cancel:CancelToken::default(),
+                                                compact_priors:true,
+                                                paranoid:false,
+                                                #[cfg(feature="std")]
+                                                threaded_crc:false,
+                                                #[cfg(feature="std")]
+                                                pull_timeout:None,
+                                                output_digest:None,
+                                                window:None,
+                                                trailing_bytes_policy:TrailingBytesPolicy::Reject,
+                                                trailing_bytes:0,
+        })
+    }
+    // decodes only STREAM_FEATURE_AUTHENTICATED streams tagged under key, and holds back the
+    // final output until the tag is verified; the crc is always checked
+    fn new_authenticated(m8: AllocU8,
+                         mcdf16:AllocCDF16,
+                         mc: AllocCommand,
+                         key: [u8; interface::AUTHENTICATION_KEY_LENGTH],
+                         multithread:bool) -> DivansDecompressor<Self::DefaultDecoder, AllocU8, AllocCDF16, AllocCommand> {
+        DivansDecompressor::Header(HeaderParser{header:[0u8;wire::MAX_HEADER_LENGTH], read_offset:0,
                                                 m8:Some(m8), mcdf16:Some(mcdf16), mcommand:Some(mc),
-                                                skip_crc:skip_crc,
+                                                checksum_policy:ChecksumPolicy::Verify,
                                                 multithread:multithread,
+                                                authentication_key:Some(key),
+                                                tap_commands:false,
+                                                stop_between_streams:false,
+                                                framing:None,
+                                                cancel:CancelToken::default(),
+                                                compact_priors:true,
+                                                paranoid:false,
+                                                #[cfg(feature="std")]
+                                                threaded_crc:false,
+                                                #[cfg(feature="std")]
+                                                pull_timeout:None,
+                                                output_digest:None,
+                                                window:None,
+                                                trailing_bytes_policy:TrailingBytesPolicy::Reject,
+                                                trailing_bytes:0,
         })
     }
 }
@@ -467,3 +1330,573 @@ impl<AllocU8:Allocator<u8>,
     for DivansDecompressorFactoryStruct<AllocU8, AllocCDF16, AllocCommand> {
      type DefaultDecoder = DefaultDecoderType!();
 }
+
+#[cfg(all(test, feature="std", feature="encoder"))]
+mod test {
+    use std::vec::Vec;
+    use alloc_stdlib::HeapAlloc;
+    use alloc::SliceWrapper;
+    use conformance::{conformance_commands, conformance_expected_output};
+    use codec::SubDigest;
+    use splice::splice;
+    use std::io::{Cursor, Read, Write};
+    use error::Error;
+    use reader::DivansDecompressorReader;
+    use writer::{new_brotli_hybrid_compressor, DivansDecompressorWriter};
+    use wire::{u32_from_le, u64_from_le, CHECKSUM_LENGTH};
+    use core::hash::Hasher;
+    use divans_compressor::DivansCompressorFactoryStruct;
+    use alloc::{Allocator, SliceWrapperMut};
+    use super::{DivansDecompressorFactory, DivansDecompressorFactoryStruct, StaticCommand};
+    use interface::{
+        Command,
+        Compressor,
+        Decompressor,
+        DivansCompressorFactory,
+        DivansCompressorOptions,
+        DivansOutputResult,
+        DivansResult,
+        ErrMsg,
+        ChecksumType,
+        ChecksumPolicy,
+        TrailingBytesPolicy,
+        DecodeStats,
+        LiteralPredictionModeNibble,
+        Nop,
+        DefaultCDF16,
+        HEADER_LENGTH,
+        HEADER_FEATURE_OFFSET,
+    };
+    use test_support::{new_decompressor, encode, compress_hybrid, decode, decoded_options, decode_with, DecodeConfig};
+
+    #[test]
+    fn test_decoded_stream_options() {
+        let commands = conformance_commands("mixed");
+        let stream = encode(&commands[..], 0).unwrap();
+        for multithread in [false, true].iter() {
+            let decompressor = new_decompressor(true, *multithread);
+            assert_eq!(decompressor.options(), None);
+            decompressor.free();
+            let options = decoded_options(&stream[..], *multithread).unwrap();
+            assert_eq!(options.window_size, 16);
+            assert_eq!(options.stream_features, stream[HEADER_FEATURE_OFFSET]);
+            assert_eq!(options.checksum, ChecksumType::Crc32c);
+            assert!(!options.checksum_verified);
+            assert_eq!(options.dictionary_id, None);
+            assert_eq!(options.literal_prediction_mode, LiteralPredictionModeNibble::utf8());
+            assert_eq!(options.mixing_level, 1);
+            assert!(options.context_map);
+            assert_eq!(options.stride, Some(1));
+        }
+        // without a prediction mode command there is nothing to report beyond the header
+        let stream = encode(&conformance_commands("literal")[..], 0).unwrap();
+        assert_eq!(decoded_options(&stream[..], false), None);
+    }
+
+    #[test]
+    fn test_decode_stats() {
+        let commands = conformance_commands("mixed");
+        let stream = encode(&commands[..], 0).unwrap();
+        let mut expected = DecodeStats::default();
+        for command in commands.iter() {
+            match *command {
+                Command::Literal(ref lit) => {
+                    expected.literal_commands += 1;
+                    expected.literal_bytes += lit.data.slice().len() as u64;
+                },
+                Command::Copy(ref copy) => {
+                    expected.copy_commands += 1;
+                    expected.copy_bytes += u64::from(copy.num_bytes);
+                    expected.copy_distance_sum += u64::from(copy.distance);
+                },
+                Command::Dict(ref dict) => {
+                    expected.dict_commands += 1;
+                    expected.dict_bytes += u64::from(dict.final_size);
+                },
+                Command::PredictionMode(_) => expected.prediction_mode_commands += 1,
+                Command::BlockSwitchLiteral(_) => expected.literal_block_switches += 1,
+                Command::BlockSwitchCommand(_) => expected.command_block_switches += 1,
+                Command::BlockSwitchDistance(_) => expected.distance_block_switches += 1,
+            }
+        }
+        assert!(expected.copy_commands != 0 && expected.dict_commands != 0);
+        for multithread in [false, true].iter() {
+            let decoded = decode_with(&stream[..], DecodeConfig::new(*multithread), |_| {});
+            assert_eq!(decoded.result, Ok(()));
+            let mut stats = decoded.stats;
+            assert!(stats.decode_nanos != 0);
+            assert!(stats.bytes_per_ms().unwrap() > 0.0);
+            stats.decode_nanos = 0;
+            assert_eq!(stats, expected);
+            assert_eq!(stats.total_bytes(), conformance_expected_output(&commands[..]).unwrap().len() as u64);
+            assert_eq!(stats.avg_copy_length(), Some(expected.copy_bytes as f64 / expected.copy_commands as f64));
+            assert_eq!(stats.bytes_per_ms(), None);
+        }
+    }
+
+    fn decode_with_policy(stream: &[u8], policy: ChecksumPolicy, multithread: bool) -> (Result<(), ErrMsg>, Option<u64>) {
+        let mut config = DecodeConfig::new(multithread);
+        config.checksum_policy = Some(policy);
+        let decoded = decode_with(stream, config, |decompressor| {
+            // the policy is fixed once decoding has begun
+            assert_eq!(decompressor.set_checksum_policy(ChecksumPolicy::Verify), Err(ErrMsg::WrongInternalDecoderState));
+        });
+        if let Some(crc) = decoded.expected_crc {
+            assert_eq!(decoded.expected_checksum, Some(u64::from(crc)));
+        }
+        (decoded.result, decoded.expected_checksum)
+    }
+
+    #[test]
+    fn test_checksum_policy() {
+        let stream = encode(&conformance_commands("mixed")[..], 0).unwrap();
+        let trailer = stream.len() - CHECKSUM_LENGTH;
+        let crc = u64::from(u32_from_le(&stream[trailer..trailer + 4]));
+        // what a caller checking later computes
+        let mut digest = SubDigest::default();
+        digest.write(&stream[..trailer]);
+        assert_eq!(digest.finish(), crc);
+        let mut bad_crc = stream.clone();
+        bad_crc[trailer] ^= 1;
+        let mut bad_tag = stream.clone();
+        bad_tag[trailer + 4] ^= 1;
+        for multithread in [false, true].iter() {
+            assert_eq!(decode_with_policy(&stream[..], ChecksumPolicy::Verify, *multithread), (Ok(()), Some(crc)));
+            assert_eq!(decode_with_policy(&stream[..], ChecksumPolicy::SkipButParse, *multithread), (Ok(()), Some(crc)));
+            assert_eq!(decode_with_policy(&stream[..], ChecksumPolicy::TrustCaller, *multithread), (Ok(()), None));
+            match decode_with_policy(&bad_crc[..], ChecksumPolicy::Verify, *multithread).0 {
+                Err(ErrMsg::BadChecksum(computed, stored)) => {
+                    assert_eq!(computed, u64_from_le(&stream[trailer..]));
+                    assert_eq!(stored, computed ^ 1);
+                },
+                res => panic!("{:?}", res),
+            }
+            assert_eq!(decode_with_policy(&bad_crc[..], ChecksumPolicy::SkipButParse, *multithread), (Ok(()), Some(crc ^ 1)));
+            assert_eq!(decode_with_policy(&bad_crc[..], ChecksumPolicy::TrustCaller, *multithread), (Ok(()), None));
+            for policy in [ChecksumPolicy::Verify, ChecksumPolicy::SkipButParse].iter() {
+                match decode_with_policy(&bad_tag[..], *policy, *multithread).0 {
+                    Err(ErrMsg::BadChecksum(computed, stored)) => {
+                        assert_eq!(computed, u64_from_le(&stream[trailer..]));
+                        assert_eq!(stored, computed ^ (1 << 32));
+                    },
+                    res => panic!("{:?}", res),
+                }
+            }
+            // an unchecked crc does not hide a bad tag, and shows as the stored one
+            let mut bad_both = bad_crc.clone();
+            bad_both[trailer + 4] ^= 1;
+            match decode_with_policy(&bad_both[..], ChecksumPolicy::SkipButParse, *multithread).0 {
+                Err(ErrMsg::BadChecksum(computed, stored)) => {
+                    assert_eq!(computed, u64_from_le(&bad_crc[trailer..]));
+                    assert_eq!(stored, u64_from_le(&bad_both[trailer..]));
+                },
+                res => panic!("{:?}", res),
+            }
+            assert_eq!(decode_with_policy(&bad_tag[..], ChecksumPolicy::TrustCaller, *multithread), (Ok(()), None));
+        }
+    }
+
+    // past 4GB, where 32 bit byte counters would wrap
+    #[cfg(feature="stress")]
+    const STRESS_BYTES: u64 = (1 << 32) + (1 << 28);
+
+    #[cfg(feature="stress")]
+    const STRESS_BLOCK: usize = 1 << 16;
+
+    // block index of the synthesized stress input: skewed text from one of a few seeds, with the
+    // index stamped in front so that blocks repeat but never exactly
+    #[cfg(feature="stress")]
+    fn stress_block(index: u64, block: &mut [u8]) {
+        let alphabet = b"eeeeeeeetttttaaaaooooiiinnnsshhrrdl  ";
+        let mut seed = 0x2545f491u32 ^ (index.wrapping_mul(0x9e3779b97f4a7c15) >> 61) as u32;
+        for item in block.iter_mut() {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            *item = alphabet[(seed >> 16) as usize % alphabet.len()];
+        }
+        for (item, shift) in block.iter_mut().zip((0..64).step_by(8)) {
+            *item = (index >> shift) as u8;
+        }
+    }
+
+    #[cfg(feature="stress")]
+    #[test]
+    fn test_stress_stream_past_4gb() {
+        let mut compressor = DivansCompressorFactoryStruct::<HeapAlloc<u8>, HeapAlloc<DefaultCDF16>>::new(
+            HeapAlloc::<u8>::new(0),
+            HeapAlloc::<u32>::new(0),
+            HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+            DivansCompressorOptions::default(),
+            ());
+        let mut block = vec![0u8; STRESS_BLOCK];
+        let mut buffer = vec![0u8; STRESS_BLOCK];
+        let mut stream = Vec::<u8>::new();
+        for index in 0..STRESS_BYTES / STRESS_BLOCK as u64 {
+            stress_block(index, &mut block[..]);
+            let mut input_offset = 0usize;
+            while input_offset != block.len() {
+                let mut offset = 0usize;
+                if let DivansResult::Failure(m) = compressor.encode(&block[..], &mut input_offset, &mut buffer[..], &mut offset) {
+                    panic!("{:?}", m);
+                }
+                stream.extend(&buffer[..offset]);
+            }
+        }
+        loop {
+            let mut offset = 0usize;
+            let res = compressor.flush(&mut buffer[..], &mut offset);
+            stream.extend(&buffer[..offset]);
+            match res {
+                DivansOutputResult::Success => break,
+                DivansOutputResult::NeedsMoreOutput => {},
+                DivansOutputResult::Failure(m) => panic!("{:?}", m),
+            }
+        }
+        compressor.free();
+        let mut decompressor = new_decompressor(false, false);
+        let mut input_offset = 0usize;
+        let mut produced = 0u64;
+        loop {
+            let mut offset = 0usize;
+            let res = decompressor.decode(&stream[..], &mut input_offset, &mut buffer[..], &mut offset);
+            for item in buffer[..offset].iter() {
+                if produced % STRESS_BLOCK as u64 == 0 {
+                    stress_block(produced / STRESS_BLOCK as u64, &mut block[..]);
+                }
+                assert_eq!(*item, block[(produced % STRESS_BLOCK as u64) as usize], "at {}", produced);
+                produced += 1;
+            }
+            match res {
+                DivansResult::Success => break,
+                DivansResult::NeedsMoreOutput => {},
+                DivansResult::NeedsMoreInput => panic!("truncated stream"),
+                DivansResult::Failure(m) => panic!("{:?}", m),
+            }
+        }
+        assert_eq!(produced, STRESS_BYTES);
+        let stats = decompressor.stats();
+        assert_eq!(stats.literal_bytes + stats.copy_bytes + stats.dict_bytes, STRESS_BYTES);
+        decompressor.free();
+    }
+
+    // decodes with an output buffer sized by output_needed after each NeedsMoreOutput, checking that
+    // a buffer of that size is always filled
+    fn decode_by_output_needed(stream: &[u8], multithread: bool) -> Vec<u8> {
+        let mut decompressor = new_decompressor(false, multithread);
+        let mut ret = Vec::<u8>::new();
+        let mut buffer = vec![0u8; 1];
+        let mut input_offset = 0usize;
+        loop {
+            let mut output_offset = 0usize;
+            let res = decompressor.decode(stream, &mut input_offset, &mut buffer[..], &mut output_offset);
+            ret.extend(&buffer[..output_offset]);
+            match res {
+                DivansResult::Success => break,
+                DivansResult::NeedsMoreOutput => {},
+                res => panic!("{:?}", res),
+            }
+            assert_eq!(output_offset, buffer.len());
+            buffer = vec![0u8; core::cmp::max(1, decompressor.output_needed())];
+        }
+        decompressor.free();
+        ret
+    }
+
+    #[test]
+    fn test_output_needed() {
+        let input = &include_bytes!("../testdata/alice29")[..16384];
+        let stream = compress_hybrid(input, DivansCompressorOptions::default());
+        for multithread in [false, true].iter() {
+            assert_eq!(decode_by_output_needed(&stream[..], *multithread), input);
+        }
+        // a stored frame asks for the rest of its bytes
+        let stored = compress_hybrid(&input[..5], DivansCompressorOptions::default());
+        let mut decompressor = new_decompressor(false, false);
+        let (mut input_offset, mut output_offset) = (0usize, 0usize);
+        let mut buffer = [0u8; 5];
+        assert_eq!(decompressor.decode(&stored[..], &mut input_offset, &mut buffer[..1], &mut output_offset),
+                   DivansResult::NeedsMoreOutput);
+        assert_eq!(decompressor.output_needed(), 4);
+        assert_eq!(decompressor.decode(&stored[..], &mut input_offset, &mut buffer[..], &mut output_offset),
+                   DivansResult::Success);
+        assert_eq!(&buffer[..], &input[..5]);
+        decompressor.free();
+
+        // the hybrid compressor has the whole stream encoded once flush first needs more output
+        let mut compressor = new_brotli_hybrid_compressor(DivansCompressorOptions::default());
+        let mut offset = 0usize;
+        let mut unused = 0usize;
+        assert_eq!(compressor.encode(input, &mut offset, &mut [], &mut unused), DivansResult::NeedsMoreInput);
+        let mut flushed = vec![0u8; 16];
+        let mut flushed_len = 0usize;
+        assert_eq!(compressor.flush(&mut flushed[..], &mut flushed_len), DivansOutputResult::NeedsMoreOutput);
+        flushed.resize(flushed_len + compressor.output_needed(), 0);
+        assert_eq!(compressor.flush(&mut flushed[..], &mut flushed_len), DivansOutputResult::Success);
+        assert_eq!(flushed_len, flushed.len());
+        assert_eq!(flushed, stream);
+        compressor.free();
+
+        // the command compressor asks for the header up front and for the rest of the trailer at the end
+        let commands = conformance_commands("mixed");
+        let mut opts = DivansCompressorOptions::default();
+        opts.window_size = Some(16);
+        let mut compressor = DivansCompressorFactoryStruct::<HeapAlloc<u8>, HeapAlloc<DefaultCDF16>>::new(
+            HeapAlloc::<u8>::new(0),
+            HeapAlloc::<u32>::new(0),
+            HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+            opts,
+            ());
+        assert_eq!(compressor.output_needed(), HEADER_LENGTH);
+        let mut command_offset = 0usize;
+        let mut ret = Vec::<u8>::new();
+        let mut buffer = [0u8; 4096];
+        while command_offset != commands.len() {
+            let mut offset = 0usize;
+            let res = compressor.encode_commands(&commands[..], &mut command_offset, &mut buffer[..], &mut offset);
+            ret.extend(&buffer[..offset]);
+            if let DivansOutputResult::Failure(m) = res {
+                panic!("{:?}", m);
+            }
+        }
+        let mut room = 1usize;
+        loop {
+            let mut offset = 0usize;
+            let res = compressor.flush(&mut buffer[..room], &mut offset);
+            ret.extend(&buffer[..offset]);
+            match res {
+                DivansOutputResult::Success => break,
+                DivansOutputResult::NeedsMoreOutput => {},
+                DivansOutputResult::Failure(m) => panic!("{:?}", m),
+            }
+            room = compressor.output_needed();
+        }
+        // the last flush wrote the whole trailer
+        assert_eq!(room, CHECKSUM_LENGTH);
+        assert_eq!(compressor.output_needed(), 0);
+        compressor.free();
+        assert_eq!(decode(&ret[..]).unwrap(), conformance_expected_output(&commands[..]).unwrap());
+    }
+
+    // decodes stream into a window of window_len bytes, first filled with garbage, and returns the output
+    // with the ring buffer take_window gave back, and whether it was that window
+    fn decode_into_window(stream: &[u8], window_len: usize, multithread: bool) -> (Vec<u8>, Vec<u8>, bool) {
+        let mut m8 = HeapAlloc::<u8>::new(0);
+        let mut window = m8.alloc_cell(window_len);
+        for byte in window.slice_mut().iter_mut() {
+            *byte = 0xa5;
+        }
+        let window_ptr = window.slice().as_ptr();
+        let mut decompressor = DivansDecompressorFactoryStruct::<HeapAlloc<u8>,
+                                                                 HeapAlloc<DefaultCDF16>,
+                                                                 HeapAlloc<StaticCommand>>::new(
+            m8,
+            HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+            HeapAlloc::<StaticCommand>::new(StaticCommand::nop()),
+            false,
+            multithread);
+        assert!(decompressor.take_window().is_none());
+        decompressor.set_window(window).ok().unwrap();
+        let mut output = Vec::<u8>::new();
+        let mut buffer = [0u8; 4096];
+        let mut input_offset = 0usize;
+        loop {
+            let mut output_offset = 0usize;
+            let res = decompressor.decode(stream, &mut input_offset, &mut buffer[..], &mut output_offset);
+            output.extend(&buffer[..output_offset]);
+            match res {
+                DivansResult::Success => break,
+                DivansResult::NeedsMoreOutput => {},
+                res => panic!("{:?}", res),
+            }
+            assert!(decompressor.take_window().is_none());
+        }
+        let taken = decompressor.take_window().unwrap();
+        assert!(decompressor.take_window().is_none());
+        let ret = (output, taken.slice().to_vec(), taken.slice().as_ptr() == window_ptr);
+        let (mut m8, _, _) = decompressor.free();
+        m8.free_cell(taken);
+        ret
+    }
+
+    #[test]
+    fn test_external_window() {
+        let input = &include_bytes!("../testdata/alice29")[..40000];
+        let mut opts = DivansCompressorOptions::default();
+        opts.window_size = Some(14);
+        let stream = compress_hybrid(input, opts);
+        for multithread in [false, true].iter() {
+            for window_len in [1usize << 14, 1 << 16].iter() {
+                let (output, window, same) = decode_into_window(&stream[..], *window_len, *multithread);
+                assert_eq!(&output[..], input);
+                assert!(same);
+                // the window ends with the output, wrapped around where it began
+                let end = input.len() & (window_len - 1);
+                let reach = core::cmp::min(*window_len, input.len());
+                let mut wrapped = window[end..].to_vec();
+                wrapped.extend(&window[..end]);
+                assert_eq!(&wrapped[window_len - reach..], &input[input.len() - reach..]);
+            }
+            // too small for the stream: the decoder allocates its own
+            let (output, window, same) = decode_into_window(&stream[..], 1 << 12, *multithread);
+            assert_eq!(&output[..], input);
+            assert!(!same);
+            assert_eq!(window.len(), 1 << 14);
+        }
+        // a short stream leaves the rest of the window cleared
+        let (output, window, _) = decode_into_window(&compress_hybrid(&input[..300], opts)[..], 1 << 14, false);
+        assert_eq!(&output[..], &input[..300]);
+        assert_eq!(&window[..300], &input[..300]);
+        assert!(window[300..].iter().all(|byte| *byte == 0));
+
+        let mut m8 = HeapAlloc::<u8>::new(0);
+        let mut decompressor = new_decompressor(false, false);
+        // not a power of two
+        let refused = decompressor.set_window(m8.alloc_cell(3 << 12)).unwrap_err();
+        m8.free_cell(refused);
+        let (mut input_offset, mut output_offset) = (0usize, 0usize);
+        let mut buffer = [0u8; 16];
+        decompressor.decode(&stream[..], &mut input_offset, &mut buffer[..], &mut output_offset);
+        let refused = decompressor.set_window(m8.alloc_cell(1 << 14)).unwrap_err();
+        m8.free_cell(refused);
+        decompressor.free();
+    }
+
+    // decodes stream, handed over in pieces of piece_len bytes, under policy; the output, or the failure,
+    // and the decompressor's trailing_bytes
+    fn decode_with_trailing(stream: &[u8], policy: TrailingBytesPolicy, multithread: bool,
+                            piece_len: usize) -> (Result<Vec<u8>, ErrMsg>, Option<u64>) {
+        let mut decompressor = new_decompressor(false, multithread);
+        decompressor.set_trailing_bytes_policy(policy).unwrap();
+        let mut out = Vec::<u8>::new();
+        let mut buffer = [0u8; 4096];
+        let mut input_offset = 0usize;
+        let mut input_end = core::cmp::min(piece_len, stream.len());
+        let result = loop {
+            let mut output_offset = 0usize;
+            let res = decompressor.decode(&stream[..input_end], &mut input_offset, &mut buffer[..], &mut output_offset);
+            out.extend(&buffer[..output_offset]);
+            match res {
+                DivansResult::Success => if input_offset != input_end {
+                    break Err(ErrMsg::TrailingInput(stream[input_offset]));
+                } else if input_end == stream.len() {
+                    break Ok(out);
+                },
+                DivansResult::NeedsMoreOutput => continue,
+                DivansResult::NeedsMoreInput => if input_end == stream.len() {
+                    break Err(ErrMsg::TruncatedInput);
+                },
+                DivansResult::Failure(m) => break Err(m),
+            }
+            input_end = core::cmp::min(input_end + piece_len, stream.len());
+        };
+        let trailing = decompressor.trailing_bytes();
+        decompressor.free();
+        (result, trailing)
+    }
+
+    #[test]
+    fn test_trailing_bytes_policy() {
+        let input = &include_bytes!("../testdata/alice29")[..20000];
+        let opts = DivansCompressorOptions::default();
+        // padded out to a 512 byte block as a container might, a stored frame and a spliced stream too
+        let spliced = splice(&compress_hybrid(&input[..5000], opts), &compress_hybrid(&input[5000..], opts)).unwrap();
+        let streams = [(compress_hybrid(input, opts), input), (compress_hybrid(&input[..3], opts), &input[..3]),
+                       (spliced, input)];
+        for &(ref stream, expected) in streams.iter() {
+            let mut padded = stream.clone();
+            padded.resize((stream.len() / 512 + 1) * 512, 0);
+            let pad = (padded.len() - stream.len()) as u64;
+            for multithread in [false, true].iter() {
+                for piece_len in [7usize, 100000].iter() {
+                    assert_eq!(decode_with_trailing(&padded[..], TrailingBytesPolicy::Reject, *multithread, *piece_len),
+                               (Err(ErrMsg::TrailingInput(0)), None));
+                    assert_eq!(decode_with_trailing(&padded[..], TrailingBytesPolicy::Ignore, *multithread, *piece_len),
+                               (Ok(expected.to_vec()), None));
+                    // the padding arrives across calls once it is split over pieces
+                    assert_eq!(decode_with_trailing(&padded[..], TrailingBytesPolicy::Count, *multithread, *piece_len),
+                               (Ok(expected.to_vec()), Some(pad)));
+                    assert_eq!(decode_with_trailing(&stream[..], TrailingBytesPolicy::Count, *multithread, *piece_len),
+                               (Ok(expected.to_vec()), Some(0)));
+                }
+            }
+            let mut reader = DivansDecompressorReader::new(Cursor::new(&padded[..]), 0, false, false);
+            let err = Error::from(reader.read_to_end(&mut Vec::new()).unwrap_err());
+            assert_eq!(err.code(), Some(ErrMsg::TrailingInput(0)));
+            let mut reader = DivansDecompressorReader::new(Cursor::new(&padded[..]), 0, false, false);
+            reader.set_trailing_bytes_policy(TrailingBytesPolicy::Count).unwrap();
+            let mut out = Vec::<u8>::new();
+            reader.read_to_end(&mut out).unwrap();
+            assert_eq!((&out[..], reader.trailing_bytes()), (expected, Some(pad)));
+
+            let mut out = Vec::<u8>::new();
+            {
+                let mut writer = DivansDecompressorWriter::new(&mut out, 0, false, false);
+                let err = Error::from(writer.write_all(&padded[..]).unwrap_err());
+                assert_eq!(err.code(), Some(ErrMsg::TrailingInput(0)));
+            }
+            let mut out = Vec::<u8>::new();
+            {
+                let mut writer = DivansDecompressorWriter::new(&mut out, 0, false, false);
+                writer.set_trailing_bytes_policy(TrailingBytesPolicy::Ignore).unwrap();
+                writer.write_all(&padded[..]).unwrap();
+                writer.flush().unwrap();
+                assert_eq!(writer.trailing_bytes(), None);
+            }
+            assert_eq!(&out[..], expected);
+        }
+        // like the other settings, only before the first decode
+        let mut decompressor = new_decompressor(false, false);
+        let (mut input_offset, mut output_offset) = (0usize, 0usize);
+        decompressor.decode(&streams[0].0[..8], &mut input_offset, &mut [0u8; 16][..], &mut output_offset);
+        assert_eq!(decompressor.set_trailing_bytes_policy(TrailingBytesPolicy::Ignore), Err(ErrMsg::WrongInternalDecoderState));
+        decompressor.free();
+    }
+
+    #[test]
+    fn test_window_after_decode() {
+        let input = &include_bytes!("../testdata/alice29")[..40000];
+        let mut opts = DivansCompressorOptions::default();
+        opts.window_size = Some(14);
+        // the window is the last stream's, and a stored frame has none
+        let spliced = splice(&compress_hybrid(&input[..1000], opts), &compress_hybrid(&input[1000..], opts)).unwrap();
+        let streams = [(compress_hybrid(input, opts), Some(input)), (spliced, Some(&input[1000..])),
+                       (compress_hybrid(&input[..3], opts), None)];
+        for &(ref stream, last_stream_output) in streams.iter() {
+            for multithread in [false, true].iter() {
+                let mut decompressor = new_decompressor(false, *multithread);
+                let mut buffer = [0u8; 4096];
+                let mut input_offset = 0usize;
+                loop {
+                    assert!(decompressor.window().is_none());
+                    let mut output_offset = 0usize;
+                    match decompressor.decode(&stream[..], &mut input_offset, &mut buffer[..], &mut output_offset) {
+                        DivansResult::Success => break,
+                        DivansResult::NeedsMoreOutput => {},
+                        res => panic!("{:?}", res),
+                    }
+                }
+                match last_stream_output {
+                    Some(output) => {
+                        let ring = {
+                            let (window, position) = decompressor.window().unwrap();
+                            assert_eq!(window.len(), 1 << 14);
+                            assert_eq!(position, output.len() & (window.len() - 1));
+                            let mut ordered = window[position..].to_vec();
+                            ordered.extend(&window[..position]);
+                            assert_eq!(&ordered[..], &output[output.len() - window.len()..]);
+                            window.to_vec()
+                        };
+                        let taken = decompressor.take_window().unwrap();
+                        assert_eq!(taken.slice(), &ring[..]);
+                        assert!(decompressor.window().is_none());
+                        let (mut m8, _, _) = decompressor.free();
+                        m8.free_cell(taken);
+                    },
+                    None => {
+                        assert!(decompressor.window().is_none());
+                        decompressor.free();
+                    },
+                }
+            }
+        }
+    }
+}
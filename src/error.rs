@@ -0,0 +1,241 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// The error of the std conveniences: splicing, archives, budgeted encoding, and the readers and
+// writers, whose io::Errors carry one.  It wraps the ErrMsg the codec failed with, or the io::Error
+// underneath, with what was being done and how far into the input and output it got.
+#![cfg(feature="std")]
+use core;
+use std::error;
+use std::fmt;
+use std::io;
+use interface::ErrMsg;
+
+// what went wrong, broadly, so callers can tell bad input from bad luck
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    InvalidInput, // options, arguments or calls the API cannot honor
+    Corrupt, // the bytes are not a stream, or something damaged it
+    Truncated, // the stream ends early
+    Integrity, // a checksum, authentication tag or recorded size does not match
+    Unsupported, // the stream needs features this build does not have
+    Internal, // the codec reached a state it should not
+    Io, // the reader or writer underneath failed otherwise
+    Cancelled, // by a CancelToken
+}
+
+impl ErrorKind {
+    pub fn of(code: ErrMsg) -> ErrorKind {
+        match code {
+            ErrMsg::NotAllowedToFlushIfPreviousCommandPartial | ErrMsg::NotAllowedToEncodeAfterFlush
+            | ErrMsg::Distance0NotAllowed | ErrMsg::InputOffsetOutOfBounds | ErrMsg::InputChangedAfterContinuation
+            | ErrMsg::ExternalProbZero(_) | ErrMsg::ExternalProbNotNormalized(_) | ErrMsg::MissingAuthenticationKey
+            | ErrMsg::SpliceAuthenticated | ErrMsg::OptionOutOfRange(_) | ErrMsg::ConflictingOptions(_, _)
+            | ErrMsg::RepeatHintRejected | ErrMsg::OutputTooSmall | ErrMsg::WideBlockTypesDisabled => ErrorKind::InvalidInput,
+            ErrMsg::UnexpectedEof | ErrMsg::TruncatedInput => ErrorKind::Truncated,
+            ErrMsg::BadChecksum(_, _) | ErrMsg::StreamNotAuthenticated | ErrMsg::BadAuthenticationTag
+            | ErrMsg::ArchiveSizeMismatch => ErrorKind::Integrity,
+            ErrMsg::UnsupportedStreamFeatures(_) | ErrMsg::UnsupportedCoder(_) | ErrMsg::UnsupportedChecksum(_)
+            | ErrMsg::UnsupportedPriorsModel(_, _) => ErrorKind::Unsupported,
+            ErrMsg::Cancelled => ErrorKind::Cancelled,
+            ErrMsg::TrailingInput(_) | ErrMsg::DistanceGreaterRingBuffer | ErrMsg::DictTransformDiffersFromExpectedSize
+            | ErrMsg::CommandCodeOutOfBounds(_) | ErrMsg::CopyDistanceMnemonicCodeBad(_, _)
+            | ErrMsg::IndexBeyondContextMapSize(_, _) | ErrMsg::PredictionModeOutOfBounds(_)
+            | ErrMsg::DictWordSizeTooLarge(_) | ErrMsg::DictTransformIndexUndefined(_) | ErrMsg::MagicNumberWrongA(_, _)
+            | ErrMsg::MagicNumberWrongB(_, _) | ErrMsg::BadWindowSize(_) | ErrMsg::AdvContextMapNotBoolean(_)
+            | ErrMsg::CommandDisabledByStreamFeatures(_) | ErrMsg::InvalidDistanceBucketing(_, _)
+            | ErrMsg::DistancePostfixOutOfRange(_) | ErrMsg::Stalled | ErrMsg::FramingTagMismatch
+            | ErrMsg::PriorsModelMalformed | ErrMsg::BrotliDecompressStreamFail | ErrMsg::InvalidCdf
+            | ErrMsg::CopyBeforeStreamStart | ErrMsg::BlockTypeOutOfRange => ErrorKind::Corrupt,
+            _ => ErrorKind::Internal,
+        }
+    }
+    fn io_kind(&self) -> io::ErrorKind {
+        match *self {
+            ErrorKind::InvalidInput => io::ErrorKind::InvalidInput,
+            ErrorKind::Truncated => io::ErrorKind::UnexpectedEof,
+            ErrorKind::Corrupt | ErrorKind::Integrity | ErrorKind::Unsupported => io::ErrorKind::InvalidData,
+            // not Interrupted, which io::copy and read_to_end retry
+            ErrorKind::Internal | ErrorKind::Io | ErrorKind::Cancelled => io::ErrorKind::Other,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    code: Option<ErrMsg>, // None for io errors
+    io: Option<io::Error>,
+    state: Option<&'static str>,
+    input_offset: Option<u64>,
+    output_offset: Option<u64>,
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+    pub fn code(&self) -> Option<ErrMsg> {
+        self.code
+    }
+    // eg. "decompressing"; the innermost that was given
+    pub fn state(&self) -> Option<&'static str> {
+        self.state
+    }
+    // bytes of input consumed and of output produced before the failure, where known
+    pub fn input_offset(&self) -> Option<u64> {
+        self.input_offset
+    }
+    pub fn output_offset(&self) -> Option<u64> {
+        self.output_offset
+    }
+    pub fn in_state(mut self, state: &'static str) -> Self {
+        if self.state.is_none() {
+            self.state = Some(state);
+        }
+        self
+    }
+    pub fn at(mut self, input_offset: u64, output_offset: u64) -> Self {
+        self.input_offset = Some(input_offset);
+        self.output_offset = Some(output_offset);
+        self
+    }
+    // moves offsets taken within a part of the input and output to the whole
+    pub fn shifted(mut self, input_start: u64, output_start: u64) -> Self {
+        self.input_offset = self.input_offset.map(|offset| offset + input_start);
+        self.output_offset = self.output_offset.map(|offset| offset + output_start);
+        self
+    }
+}
+
+impl From<ErrMsg> for Error {
+    fn from(code: ErrMsg) -> Self {
+        Error {
+            kind: ErrorKind::of(code),
+            code: Some(code),
+            io: None,
+            state: None,
+            input_offset: None,
+            output_offset: None,
+        }
+    }
+}
+
+// unwraps the Error or ErrMsg of a reader or writer rather than burying it as an Io error
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        if let Some(code) = err.get_ref().and_then(|inner| inner.downcast_ref::<ErrMsg>()) {
+            return Error::from(*code);
+        }
+        if err.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            let inner = err.into_inner().and_then(|inner| inner.downcast::<Error>().ok());
+            return *inner.expect("holds an Error");
+        }
+        let kind = match err.kind() {
+            io::ErrorKind::InvalidInput => ErrorKind::InvalidInput,
+            io::ErrorKind::InvalidData => ErrorKind::Corrupt,
+            io::ErrorKind::UnexpectedEof => ErrorKind::Truncated,
+            _ => ErrorKind::Io,
+        };
+        Error {
+            kind,
+            code: None,
+            io: Some(err),
+            state: None,
+            input_offset: None,
+            output_offset: None,
+        }
+    }
+}
+
+// a bare io error goes back as it came
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error{code: None, io: Some(io), state: None, input_offset: None, ..} => io,
+            err => io::Error::new(err.kind.io_kind(), err),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> core::result::Result<(), fmt::Error> {
+        match (self.code, self.io.as_ref()) {
+            (Some(code), _) => write!(f, "{}", code)?,
+            (None, Some(io)) => write!(f, "{}", io)?,
+            (None, None) => write!(f, "{:?}", self.kind)?,
+        }
+        if let Some(state) = self.state {
+            write!(f, " while {}", state)?;
+        }
+        match (self.input_offset, self.output_offset) {
+            (Some(input), Some(output)) => write!(f, " at input byte {}, output byte {}", input, output),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.io.as_ref().map(|io| io as &(dyn error::Error + 'static))
+    }
+}
+
+#[cfg(all(test, feature="encoder", feature="decoder"))]
+mod test {
+    use std::vec::Vec;
+    use conformance::{conformance_commands, conformance_expected_output};
+    use splice::{compress_parallel, decompress_parallel};
+    use std::io;
+    use std::io::Read;
+    use super::{Error, ErrorKind};
+    use reader::DivansDecompressorReader;
+    use interface::{
+        DivansCompressorOptions,
+        ErrMsg,
+    };
+
+    #[test]
+    fn test_error_context() {
+        let mut input = Vec::<u8>::new();
+        for name in ["mixed", "dict", "copy"].iter() {
+            input.extend(conformance_expected_output(&conformance_commands(name)[..]).unwrap());
+        }
+        let (mut stream, layout) = compress_parallel(&input[..], input.len() / 2 + 1, 2, DivansCompressorOptions::default()).unwrap();
+        // damage the second stream: the offsets are of the whole stream and output
+        let damaged = (layout[1].input.start + layout[1].input.end) / 2;
+        stream[damaged] ^= 0x55;
+        let err = decompress_parallel(&stream[..], &layout[..], 2).unwrap_err();
+        assert!(err.kind() == ErrorKind::Corrupt || err.kind() == ErrorKind::Integrity);
+        assert_eq!(err.state(), Some("decompressing"));
+        assert!(err.input_offset().unwrap() > layout[1].input.start as u64);
+        assert!(err.output_offset().unwrap() >= layout[1].output.start as u64);
+        // a reader's io::Error carries the Error, which comes back out of it
+        let truncated = &stream[..layout[0].input.end / 2];
+        let mut out = Vec::<u8>::new();
+        let io_err = DivansDecompressorReader::new(truncated, 0, false, false).read_to_end(&mut out).unwrap_err();
+        assert_eq!(io_err.kind(), io::ErrorKind::UnexpectedEof);
+        let err = Error::from(io_err);
+        assert_eq!(err.code(), Some(ErrMsg::UnexpectedEof));
+        assert_eq!(err.kind(), ErrorKind::Truncated);
+        assert_eq!(err.input_offset(), Some(truncated.len() as u64));
+        assert_eq!(err.output_offset(), Some(out.len() as u64));
+        assert_eq!(format!("{}", err), format!("{} while decompressing at input byte {}, output byte {}",
+                                               ErrMsg::UnexpectedEof, truncated.len(), out.len()));
+        // bare io errors pass through untouched
+        let err = Error::from(io::Error::new(io::ErrorKind::PermissionDenied, "denied"));
+        assert_eq!((err.kind(), err.code()), (ErrorKind::Io, None));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::PermissionDenied);
+    }
+}
@@ -70,6 +70,9 @@ impl<Ty:Sized+Default+Clone> SubclassableAllocator<Ty> {
 impl<Ty:Sized+Default+Clone> alloc::Allocator<Ty> for SubclassableAllocator<Ty> {
     type AllocatedMemory = MemoryBlock<Ty>;
     fn alloc_cell(&mut self, size:usize) ->MemoryBlock<Ty>{
+        if size == 0 { // no call to alloc_func, whose answer to 0 bytes may be NULL, and none to free_func
+            return MemoryBlock::default();
+        }
         if let Some(alloc_fn) = self.alloc.alloc_func {
             let ptr = alloc_fn(self.alloc.opaque, size * core::mem::size_of::<Ty>());
             let typed_ptr = unsafe {core::mem::transmute::<*mut c_void, *mut Ty>(ptr)};
@@ -106,15 +109,14 @@ impl<Ty:Sized+Default+Clone> alloc::Allocator<Ty> for SubclassableAllocator<Ty>
 
 
 
-#[cfg(not(feature="std"))]
-static mut G_SLICE:&mut[u8] = &mut[];
 #[cfg(not(feature="std"))]
 #[derive(Debug)]
 pub struct MemoryBlock<Ty:Sized+Default>(*mut[Ty]);
 #[cfg(not(feature="std"))]
 impl<Ty:Sized+Default> Default for MemoryBlock<Ty> {
+    // empty, and pointing nowhere rather than at a static the instances would share
     fn default() -> Self {
-        MemoryBlock(unsafe{core::mem::transmute::<*mut [u8], *mut[Ty]>(G_SLICE.as_mut())})
+        MemoryBlock(core::ptr::slice_from_raw_parts_mut(core::ptr::NonNull::<Ty>::dangling().as_ptr(), 0))
     }
 }
 #[cfg(not(feature="std"))]
@@ -169,6 +171,9 @@ impl<Ty:Sized+Default> core::ops::IndexMut<usize> for MemoryBlock<Ty> {
 impl<Ty:Sized+Default+Clone> alloc::Allocator<Ty> for SubclassableAllocator<Ty> {
     type AllocatedMemory = MemoryBlock<Ty>;
     fn alloc_cell(&mut self, size:usize) ->MemoryBlock<Ty>{
+        if size == 0 { // no call to alloc_func, whose answer to 0 bytes may be NULL, and none to free_func
+            return MemoryBlock::default();
+        }
         if let Some(alloc_fn) = self.alloc.alloc_func {
             let ptr = alloc_fn(self.alloc.opaque, size * core::mem::size_of::<Ty>());
             let typed_ptr = unsafe {core::mem::transmute::<*mut c_void, *mut Ty>(ptr)};
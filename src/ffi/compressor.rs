@@ -158,6 +158,21 @@ impl CompressorState {
                 DIVANS_OPTION_IR_OPTIMIZER => {
                     opts.divans_ir_optimizer = value as u8;
                 },
+                DIVANS_OPTION_LITERAL_PRESCAN => {
+                    opts.literal_prescan = value != 0;
+                },
+                DIVANS_OPTION_DISTANCE_POSTFIX_BITS => {
+                    if value > u32::from(::interface::MAX_DISTANCE_POSTFIX_BITS) {
+                        return DIVANS_FAILURE;
+                    }
+                    opts.distance_bucketing.postfix_bits = value as u8;
+                },
+                DIVANS_OPTION_NUM_DIRECT_DISTANCES => {
+                    if value > 15 << ::interface::MAX_DISTANCE_POSTFIX_BITS {
+                        return DIVANS_FAILURE;
+                    }
+                    opts.distance_bucketing.num_direct = value as u8;
+                },
                 _ => return DIVANS_FAILURE,
             }
             return DIVANS_SUCCESS;
@@ -13,28 +13,55 @@ pub const DIVANS_FAILURE: DivansReturnCode = 3;
 
 
 
+#[cfg(feature="encoder")]
 pub type DivansOptionSelect = u8;
 
+#[cfg(feature="encoder")]
 pub const DIVANS_OPTION_QUALITY:DivansOptionSelect = 1;
+#[cfg(feature="encoder")]
 pub const DIVANS_OPTION_WINDOW_SIZE:DivansOptionSelect = 2;
+#[cfg(feature="encoder")]
 pub const DIVANS_OPTION_LGBLOCK:DivansOptionSelect = 3;
+#[cfg(feature="encoder")]
 pub const DIVANS_OPTION_DYNAMIC_CONTEXT_MIXING:DivansOptionSelect = 4;
+#[cfg(feature="encoder")]
 pub const DIVANS_OPTION_USE_BROTLI_COMMAND_SELECTION:DivansOptionSelect = 5;
+#[cfg(feature="encoder")]
 pub const DIVANS_OPTION_USE_BROTLI_BITSTREAM:DivansOptionSelect = 6;
+#[cfg(feature="encoder")]
 pub const DIVANS_OPTION_USE_CONTEXT_MAP:DivansOptionSelect = 7;
+#[cfg(feature="encoder")]
 pub const DIVANS_OPTION_LITERAL_ADAPTATION_CM_HIGH:DivansOptionSelect = 8;
+#[cfg(feature="encoder")]
 pub const DIVANS_OPTION_FORCE_STRIDE_VALUE:DivansOptionSelect = 9;
+#[cfg(feature="encoder")]
 pub const DIVANS_OPTION_STRIDE_DETECTION_QUALITY:DivansOptionSelect = 10;
+#[cfg(feature="encoder")]
 pub const DIVANS_OPTION_PRIOR_DEPTH:DivansOptionSelect = 11;
+#[cfg(feature="encoder")]
 pub const DIVANS_OPTION_LITERAL_ADAPTATION_STRIDE_HIGH:DivansOptionSelect = 12;
+#[cfg(feature="encoder")]
 pub const DIVANS_OPTION_LITERAL_ADAPTATION_CM_LOW:DivansOptionSelect = 13;
+#[cfg(feature="encoder")]
 pub const DIVANS_OPTION_LITERAL_ADAPTATION_STRIDE_LOW:DivansOptionSelect = 14;
+#[cfg(feature="encoder")]
 pub const DIVANS_OPTION_BROTLI_LITERAL_BYTE_SCORE:DivansOptionSelect = 15;
+#[cfg(feature="encoder")]
 pub const DIVANS_OPTION_SPEED_DETECTION_QUALITY:DivansOptionSelect = 16;
+#[cfg(feature="encoder")]
 pub const DIVANS_OPTION_PRIOR_BITMASK_DETECTION:DivansOptionSelect = 17;
+#[cfg(feature="encoder")]
 pub const DIVANS_OPTION_Q9_5:DivansOptionSelect = 18;
+#[cfg(feature="encoder")]
 pub const DIVANS_OPTION_FORCE_LITERAL_CONTEXT_MODE:DivansOptionSelect = 19;
+#[cfg(feature="encoder")]
 pub const DIVANS_OPTION_IR_OPTIMIZER:DivansOptionSelect = 20;
+#[cfg(feature="encoder")]
+pub const DIVANS_OPTION_DISTANCE_POSTFIX_BITS:DivansOptionSelect = 21;
+#[cfg(feature="encoder")]
+pub const DIVANS_OPTION_NUM_DIRECT_DISTANCES:DivansOptionSelect = 22;
+#[cfg(feature="encoder")]
+pub const DIVANS_OPTION_LITERAL_PRESCAN:DivansOptionSelect = 23;
 
 
 #[repr(C)]
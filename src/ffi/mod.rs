@@ -3,17 +3,30 @@
 #[no_mangle]
 use core;
 use core::slice;
+#[cfg(feature="decoder")]
 use ::StaticCommand;
+#[cfg(feature="decoder")]
 use super::DivansDecompressorFactory;
+#[cfg(feature="decoder")]
 use super::interface::Decompressor;
 pub mod interface;
 pub mod alloc_util;
+#[cfg(feature="decoder")]
 use self::alloc_util::SubclassableAllocator;
+#[cfg(feature="encoder")]
 mod compressor;
+#[cfg(feature="decoder")]
 mod decompressor;
+#[cfg(feature="encoder")]
 use self::compressor::DivansCompressorState;
+#[cfg(feature="decoder")]
 use self::decompressor::DivansDecompressorState;
-use self::interface::{CAllocator, c_void, DivansOptionSelect, DivansReturnCode, DIVANS_FAILURE, DIVANS_SUCCESS, DIVANS_NEEDS_MORE_INPUT, DIVANS_NEEDS_MORE_OUTPUT};
+use self::interface::{CAllocator, c_void, DivansReturnCode, DIVANS_FAILURE};
+#[cfg(feature="decoder")]
+use self::interface::{DIVANS_SUCCESS, DIVANS_NEEDS_MORE_INPUT, DIVANS_NEEDS_MORE_OUTPUT};
+#[cfg(feature="encoder")]
+use self::interface::DivansOptionSelect;
+#[cfg(feature="encoder")]
 #[no_mangle]
 pub extern fn divans_new_compressor() -> *mut compressor::DivansCompressorState{
     unsafe {
@@ -27,14 +40,17 @@ pub extern fn divans_new_compressor() -> *mut compressor::DivansCompressorState{
 
 
 
+#[cfg(feature="encoder")]
 #[cfg(not(feature="std"))]
 fn divans_new_compressor_without_custom_alloc(_to_box: DivansCompressorState) -> *mut DivansCompressorState{
     panic!("Must supply allocators if calling divans when compiled without features=std");
 }
+#[cfg(feature="encoder")]
 #[cfg(feature="std")]
 fn divans_new_compressor_without_custom_alloc(to_box: DivansCompressorState) -> *mut DivansCompressorState{
     alloc_util::Box::<DivansCompressorState>::into_raw(alloc_util::Box::<DivansCompressorState>::new(to_box))
 }
+#[cfg(feature="encoder")]
 #[no_mangle]
 pub unsafe extern fn divans_new_compressor_with_custom_alloc(allocators:CAllocator) -> *mut DivansCompressorState{
     let to_box = DivansCompressorState{
@@ -54,6 +70,7 @@ pub unsafe extern fn divans_new_compressor_with_custom_alloc(allocators:CAllocat
 
 
 
+#[cfg(feature="encoder")]
 #[no_mangle]
 pub unsafe extern fn divans_set_option(state_ptr: *mut DivansCompressorState,
                                        selector: DivansOptionSelect,
@@ -66,6 +83,7 @@ pub unsafe extern fn divans_set_option(state_ptr: *mut DivansCompressorState,
     }
 }
      
+#[cfg(feature="encoder")]
 #[no_mangle]
 pub unsafe extern fn divans_encode(state_ptr: *mut DivansCompressorState,
                                    input_buf_ptr: *const u8, input_size: usize, input_offset_ptr: *mut usize,
@@ -90,6 +108,7 @@ pub unsafe extern fn divans_encode(state_ptr: *mut DivansCompressorState,
     }
 }
 
+#[cfg(feature="encoder")]
 #[no_mangle]
 pub unsafe extern fn divans_encode_flush(state_ptr: *mut DivansCompressorState,
                                          output_buf_ptr: *mut u8, output_size: usize, output_offset_ptr: *mut usize) -> DivansReturnCode {
@@ -107,8 +126,12 @@ pub unsafe extern fn divans_encode_flush(state_ptr: *mut DivansCompressorState,
     }
 }
 
+#[cfg(feature="encoder")]
 #[no_mangle]
 pub unsafe extern fn divans_compressor_malloc_u8(state_ptr: *mut DivansCompressorState, size: usize) -> *mut u8 {
+    if state_ptr.is_null() {
+        return core::ptr::null_mut();
+    }
     if let Some(alloc_fn) = (*state_ptr).custom_allocator.alloc_func {
             return core::mem::transmute::<*mut c_void, *mut u8>(alloc_fn((*state_ptr).custom_allocator.opaque, size));
     } else {
@@ -116,8 +139,12 @@ pub unsafe extern fn divans_compressor_malloc_u8(state_ptr: *mut DivansCompresso
     }
 }
 
+#[cfg(feature="encoder")]
 #[no_mangle]
 pub unsafe extern fn divans_compressor_free_u8(state_ptr: *mut DivansCompressorState, data: *mut u8, size: usize) {
+    if state_ptr.is_null() {
+        return;
+    }
     if let Some(free_fn) = (*state_ptr).custom_allocator.free_func {
         free_fn((*state_ptr).custom_allocator.opaque, core::mem::transmute::<*mut u8, *mut c_void>(data));
     } else {
@@ -126,8 +153,12 @@ pub unsafe extern fn divans_compressor_free_u8(state_ptr: *mut DivansCompressorS
 }
 
 
+#[cfg(feature="encoder")]
 #[no_mangle]
 pub unsafe extern fn divans_compressor_malloc_usize(state_ptr: *mut DivansCompressorState, size: usize) -> *mut usize {
+    if state_ptr.is_null() {
+        return core::ptr::null_mut();
+    }
     if let Some(alloc_fn) = (*state_ptr).custom_allocator.alloc_func {
         return core::mem::transmute::<*mut c_void, *mut usize>(alloc_fn((*state_ptr).custom_allocator.opaque,
                                                                          size * core::mem::size_of::<usize>()));
@@ -135,8 +166,12 @@ pub unsafe extern fn divans_compressor_malloc_usize(state_ptr: *mut DivansCompre
         return alloc_util::alloc_stdlib(size);
     }
 }
+#[cfg(feature="encoder")]
 #[no_mangle]
 pub unsafe extern fn divans_compressor_free_usize(state_ptr: *mut DivansCompressorState, data: *mut usize, size: usize) {
+    if state_ptr.is_null() {
+        return;
+    }
     if let Some(free_fn) = (*state_ptr).custom_allocator.free_func {
         free_fn((*state_ptr).custom_allocator.opaque, core::mem::transmute::<*mut usize, *mut c_void>(data));
     } else {
@@ -145,18 +180,24 @@ pub unsafe extern fn divans_compressor_free_usize(state_ptr: *mut DivansCompress
 }
 
 
+#[cfg(feature="encoder")]
 #[cfg(feature="std")]
 unsafe fn free_compressor_no_custom_alloc(state_ptr: *mut DivansCompressorState) {
     let _state = alloc_util::Box::from_raw(state_ptr);
 }
 
+#[cfg(feature="encoder")]
 #[cfg(not(feature="std"))]
 unsafe fn free_compressor_no_custom_alloc(_state_ptr: *mut DivansCompressorState) {
     unreachable!();
 }
 
+#[cfg(feature="encoder")]
 #[no_mangle]
 pub unsafe extern fn divans_free_compressor(state_ptr: *mut DivansCompressorState) {
+    if state_ptr.is_null() {
+        return;
+    }
     if let Some(_) = (*state_ptr).custom_allocator.alloc_func {
         if let Some(free_fn) = (*state_ptr).custom_allocator.free_func {
             let _to_free = core::ptr::read(state_ptr);
@@ -174,6 +215,7 @@ pub unsafe extern fn divans_free_compressor(state_ptr: *mut DivansCompressorStat
 
 
 
+#[cfg(feature="decoder")]
 #[no_mangle]
 pub extern fn divans_new_decompressor() -> *mut DivansDecompressorState{
     unsafe {
@@ -186,6 +228,7 @@ pub extern fn divans_new_decompressor() -> *mut DivansDecompressorState{
 }
 
 
+#[cfg(feature="decoder")]
 #[no_mangle]
 pub extern fn divans_new_serial_decompressor() -> *mut DivansDecompressorState{
     unsafe {
@@ -198,19 +241,30 @@ pub extern fn divans_new_serial_decompressor() -> *mut DivansDecompressorState{
 }
 
 
+#[cfg(feature="decoder")]
 #[cfg(not(feature="std"))]
 fn divans_new_decompressor_without_custom_alloc(_to_box: DivansDecompressorState) -> *mut DivansDecompressorState{
     panic!("Must supply allocators if calling divans when compiled without features=std");
 }
 
+#[cfg(feature="decoder")]
 #[cfg(feature="std")]
 fn divans_new_decompressor_without_custom_alloc(to_box: DivansDecompressorState) -> *mut DivansDecompressorState{
     alloc_util::Box::<DivansDecompressorState>::into_raw(alloc_util::Box::<DivansDecompressorState>::new(to_box))
 }
 
 
+// Every cell of the decompressor, and the state itself, comes from allocators if they are given, so
+// nothing but their opaque is shared between instances.  The worker thread of a multithreaded decoder
+// and the queues it shares with the caller's come from the Rust allocator, so asking for one along
+// with allocators returns null.
+#[cfg(feature="decoder")]
 #[no_mangle]
 pub unsafe extern fn divans_new_decompressor_with_custom_alloc(allocators:CAllocator, skip_crc:u8, multithread: u8) -> *mut DivansDecompressorState{
+    if multithread != 0 && allocators.alloc_func.is_some() {
+        return core::ptr::null_mut();
+    }
+    let multithread = multithread != 0;
     let to_box = DivansDecompressorState{
         custom_allocator:allocators.clone(),
         decompressor:decompressor::DecompressorFactory::new(
@@ -218,7 +272,7 @@ pub unsafe extern fn divans_new_decompressor_with_custom_alloc(allocators:CAlloc
             SubclassableAllocator::<super::DefaultCDF16>::new(allocators.clone()),
             SubclassableAllocator::<StaticCommand>::new(allocators.clone()),
             skip_crc != 0,
-            multithread != 0,
+            multithread,
         ),
     };
     if let Some(alloc_fn) = allocators.alloc_func {
@@ -232,6 +286,7 @@ pub unsafe extern fn divans_new_decompressor_with_custom_alloc(allocators:CAlloc
 }
 
 
+#[cfg(feature="decoder")]
 #[no_mangle]
 pub unsafe extern fn divans_decode(state_ptr: *mut DivansDecompressorState,
                                    input_buf_ptr: *const u8, input_size: usize, input_offset_ptr: *mut usize,
@@ -261,19 +316,25 @@ pub unsafe extern fn divans_decode(state_ptr: *mut DivansDecompressorState,
     }
 }
 
+#[cfg(feature="decoder")]
 #[cfg(feature="std")]
 unsafe fn free_decompressor_no_custom_alloc(state_ptr: *mut DivansDecompressorState) {
     let _state = alloc_util::Box::from_raw(state_ptr);
 }
 
+#[cfg(feature="decoder")]
 #[cfg(not(feature="std"))]
 unsafe fn free_decompressor_no_custom_alloc(_state_ptr: *mut DivansDecompressorState) {
     unreachable!();
 }
 
 
+#[cfg(feature="decoder")]
 #[no_mangle]
 pub unsafe extern fn divans_decompressor_malloc_u8(state_ptr: *mut DivansDecompressorState, size: usize) -> *mut u8 {
+    if state_ptr.is_null() {
+        return core::ptr::null_mut();
+    }
     if let Some(alloc_fn) = (*state_ptr).custom_allocator.alloc_func {
         return core::mem::transmute::<*mut c_void, *mut u8>(alloc_fn((*state_ptr).custom_allocator.opaque, size));
     } else {
@@ -281,8 +342,12 @@ pub unsafe extern fn divans_decompressor_malloc_u8(state_ptr: *mut DivansDecompr
     }
 }
 
+#[cfg(feature="decoder")]
 #[no_mangle]
 pub unsafe extern fn divans_decompressor_free_u8(state_ptr: *mut DivansDecompressorState, data: *mut u8, size: usize) {
+    if state_ptr.is_null() {
+        return;
+    }
     if let Some(free_fn) = (*state_ptr).custom_allocator.free_func {
         free_fn((*state_ptr).custom_allocator.opaque, core::mem::transmute::<*mut u8, *mut c_void>(data));
     } else {
@@ -290,8 +355,12 @@ pub unsafe extern fn divans_decompressor_free_u8(state_ptr: *mut DivansDecompres
     }
 }
 
+#[cfg(feature="decoder")]
 #[no_mangle]
 pub unsafe extern fn divans_decompressor_malloc_usize(state_ptr: *mut DivansDecompressorState, size: usize) -> *mut usize {
+    if state_ptr.is_null() {
+        return core::ptr::null_mut();
+    }
     if let Some(alloc_fn) = (*state_ptr).custom_allocator.alloc_func {
         return core::mem::transmute::<*mut c_void, *mut usize>(alloc_fn((*state_ptr).custom_allocator.opaque,
                                                                          size * core::mem::size_of::<usize>()));
@@ -299,8 +368,12 @@ pub unsafe extern fn divans_decompressor_malloc_usize(state_ptr: *mut DivansDeco
         return alloc_util::alloc_stdlib(size);
     }
 }
+#[cfg(feature="decoder")]
 #[no_mangle]
 pub unsafe extern fn divans_decompressor_free_usize(state_ptr: *mut DivansDecompressorState, data: *mut usize, size: usize) {
+    if state_ptr.is_null() {
+        return;
+    }
     if let Some(free_fn) = (*state_ptr).custom_allocator.free_func {
         free_fn((*state_ptr).custom_allocator.opaque, core::mem::transmute::<*mut usize, *mut c_void>(data));
     } else {
@@ -308,8 +381,12 @@ pub unsafe extern fn divans_decompressor_free_usize(state_ptr: *mut DivansDecomp
     }
 }
 
+#[cfg(feature="decoder")]
 #[no_mangle]
 pub unsafe extern fn divans_free_decompressor(state_ptr: *mut DivansDecompressorState) {
+    if state_ptr.is_null() {
+        return;
+    }
     if let Some(_) = (*state_ptr).custom_allocator.alloc_func {
         if let Some(free_fn) = (*state_ptr).custom_allocator.free_func {
             //(*state_ptr).drop();
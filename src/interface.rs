@@ -12,10 +12,15 @@
 //   See the License for the specific language governing permissions and
 //   limitations under the License.
 
+use core;
+#[cfg(feature="std")]
+use std::vec::Vec;
 use alloc::{SliceWrapper, Allocator};
+use brotli;
 use super::slice_util;
 use super::probability::interface::{CDF16, ProbRange};
 use super::probability;
+use super::{mac, wire};
 use super::codec::copy::CopySubstate;
 use super::codec::dict::DictSubstate;
 use super::codec::literal::LiteralSubstate;
@@ -24,7 +29,7 @@ use super::codec::block_type::BlockTypeState;
 pub use super::codec::StrideSelection;
 pub use brotli::enc::interface::*;
 
-#[derive(Copy,Clone,Debug)]
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
 pub enum ErrMsg {
     PredictionModeFail(()),
     ShutdownCoderNeedsInput,
@@ -44,12 +49,13 @@ pub enum ErrMsg {
     InputOffsetOutOfBounds,
     CommandCodeOutOfBounds(u8),
     CopyDistanceMnemonicCodeBad(u8, u8),
-    BadChecksum(u8, u8),
+    BadChecksum(u64, u64), // computed and stored trailers, each the checksum with the tag after it folded into its high 4 bytes
     IndexBeyondContextMapSize(u8, u8),
     PredictionModeOutOfBounds(u8),
     DictWordSizeTooLarge(u8),
     DictTransformIndexUndefined(u8),
     BrotliCompressStreamFail(u8, u8),
+    BrotliDecompressStreamFail, // the brotli stream a UniversalDecoder was handed is corrupt
     BrotliInternalEncodeStreamNeedsOutputWithoutFlush,
     MagicNumberWrongA(u8, u8),
     MagicNumberWrongB(u8, u8),
@@ -61,10 +67,69 @@ pub enum ErrMsg {
     MainFunctionCalledFromThread(u8),
     DecodingDecoderAlreadyFreed,
     AdvContextMapNotBoolean(u8),
+    UnsupportedStreamFeatures(u8),
+    UnsupportedCoder(u8), // the CODER_ID of the stream
+    UnsupportedChecksum(u8), // the HEADER_CHECKSUM_OFFSET byte of the stream
+    CommandDisabledByStreamFeatures(u8),
+    InvalidDistanceBucketing(u8, u8),
+    DistancePostfixOutOfRange(u8),
+    Stalled,
+    ExternalProbZero(u8), // the nibble value given no probability
+    ExternalProbNotNormalized(u32), // the sum of the entries
+    MissingAuthenticationKey, // the stream is authenticated but the decoder was given no key
+    StreamNotAuthenticated, // the decoder was given a key but the stream carries no tag
+    BadAuthenticationTag,
+    TruncatedInput, // the input ran out before the end of the stream
+    SpliceAuthenticated, // the tag of an authenticated stream also vouches for where it ends
+    ArchiveSizeMismatch, // an archive entry decoded to some other size than its record gives
+    OptionOutOfRange(CompressorOption),
+    ConflictingOptions(CompressorOption, CompressorOption),
+    FramingTagMismatch, // the stream does not start with the framing tag the decoder was given
+    RepeatHintRejected, // a repeat hint came out of input order, for input already parsed, or with too many waiting
+    Cancelled, // the CancelToken was cancelled; the codec may be resumed once it is reset
+    PriorsModelMalformed, // the bytes are not a priors model, or their tables do not hold valid CDFs
+    UnsupportedPriorsModel(u8, u8), // the format version and CDF backend of a model this build cannot load
+    NondeterministicOutput, // compressing the same chunk again gave another stream
+    WorkerPanicked, // the worker thread of a multithreaded decompressor died; it can only be freed
+    OutputTooSmall, // the stream decodes to more than the output buffer decompress_into was given holds
+    InvalidCdf, // paranoid decoding found a probability table holding no valid CDF
+    CopyBeforeStreamStart, // paranoid decoding found a copy reaching back before the first byte of the stream
+    WideBlockTypesDisabled, // a block switch names a block type past 255 but EXTENDED_FEATURE2_WIDE_BLOCK_TYPES is off
+    BlockTypeOutOfRange, // the varint of a wide block type runs past u32::MAX
 }
 
+// the variant names double as the messages, so logs and error codes match across builds with and without std
+impl core::fmt::Display for ErrMsg {
+    fn fmt(&self, f:&mut core::fmt::Formatter) -> core::result::Result<(), core::fmt::Error> {
+        <Self as core::fmt::Debug>::fmt(self, f)
+    }
+}
+
+// names a DivansCompressorOptions field in the errors of DivansCompressorOptions::validate
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum CompressorOption {
+    WindowSize,
+    DynamicContextMixing,
+    UseContextMap,
+    ForceStrideValue,
+    PriorDepth,
+    LiteralAdaptation,
+    MixerWarmStart,
+    ExtendedStreamFeatures,
+    StreamFeatures,
+    DistanceBucketing,
+    FrameAlignment,
+    AuthenticationKey,
+    FramingTag,
+    FormatVersion,
+    FreezeAdaptationAfter,
+    PriorsResetInterval,
+    ExtendedStreamFeatures2,
+    Checksum,
+}
 
-#[derive(Copy,Clone,Debug)]
+
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
 pub enum DivansOpResult {
     Failure(ErrMsg),
     Success,
@@ -100,7 +165,7 @@ impl From<DivansOpResult> for DivansOutputResult {
     }
 }
 
-#[derive(Copy,Clone,Debug)]
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
 pub enum DivansResult {
     Failure(ErrMsg),
     Success,
@@ -109,7 +174,7 @@ pub enum DivansResult {
 }
 
 
-#[derive(Copy,Clone,Debug)]
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
 pub enum DivansInputResult {
     Failure(ErrMsg),
     Success,
@@ -125,7 +190,7 @@ impl From<DivansInputResult> for DivansResult {
         }
     }
 }
-#[derive(Copy,Clone,Debug)]
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
 pub enum DivansOutputResult {
     Failure(ErrMsg),
     Success,
@@ -142,6 +207,39 @@ impl From<DivansOutputResult> for DivansResult {
     }
 }
 
+// number of consecutive calls that may return without consuming input or producing output
+pub const MAX_STALLED_CALLS: u8 = 2;
+
+// Catches a coder that keeps asking for more input or output while ignoring the buffers it was handed,
+// which would otherwise leave callers spinning forever
+#[derive(Copy,Clone,Debug,Default)]
+pub struct StallWatchdog {
+    stalled_calls: u8,
+}
+
+impl StallWatchdog {
+    // progressed: any offset moved during the call; had_work: there was input to read and room to write
+    pub fn check(&mut self, result: DivansResult, progressed: bool, had_work: bool) -> DivansResult {
+        match result {
+            DivansResult::NeedsMoreInput | DivansResult::NeedsMoreOutput => {
+                if progressed || !had_work {
+                    self.stalled_calls = 0;
+                    return result;
+                }
+                self.stalled_calls += 1;
+                if self.stalled_calls >= MAX_STALLED_CALLS {
+                    return DivansResult::Failure(ErrMsg::Stalled);
+                }
+                result
+            },
+            _ => {
+                self.stalled_calls = 0;
+                result
+            },
+        }
+    }
+}
+
 // The choice of CDF16 struct is controlled by feature flags.
 #[cfg(feature="blend")]
 pub type DefaultInternalCDF16 = probability::BlendCDF16;
@@ -152,6 +250,25 @@ pub type DefaultInternalCDF16 = probability::SIMDFrequentistCDF16;
 #[cfg(all(not(any(feature="blend", feature="uncached_frequentist", feature="simd"))))]
 pub type DefaultInternalCDF16 = probability::OptFrequentistCDF16;
 
+#[cfg(feature="blend")]
+const CDF_STRUCT: u8 = 3;
+#[cfg(all(not(any(feature="blend")), feature="uncached_frequentist"))]
+const CDF_STRUCT: u8 = 2;
+#[cfg(all(not(any(feature="blend", feature="uncached_frequentist")), feature="simd"))]
+const CDF_STRUCT: u8 = 1;
+#[cfg(not(any(feature="blend", feature="uncached_frequentist", feature="simd")))]
+const CDF_STRUCT: u8 = 0;
+// names the DefaultInternalCDF16 of this build and the precision it counts at in files holding
+// CDF state, such as a priors model
+pub const CDF_BACKEND: u8 = CDF_STRUCT | (CODER_ID << 4);
+
+// The precision the stream is entropy coded at, probability::CDF_BITS, held in header byte
+// HEADER_CODER_ID_OFFSET.  Decoders refuse streams of another precision.
+#[cfg(not(feature="cdf12"))]
+pub const CODER_ID: u8 = 0; // 15 bits
+#[cfg(feature="cdf12")]
+pub const CODER_ID: u8 = 1; // 12 bits
+
 #[cfg(feature="debug_entropy")]
 #[cfg(not(feature="findspeed"))]
 pub type DefaultCDF16 = probability::DebugWrapperCDF16<DefaultInternalCDF16>;
@@ -160,38 +277,313 @@ pub type DefaultCDF16 = DefaultInternalCDF16;
 #[cfg(feature="findspeed")]
 pub type DefaultCDF16 = probability::VariantSpeedCDF<DefaultInternalCDF16>;
 
+// The model for the command type tree's binary decisions, also chosen by feature flag.
+// Encoder and decoder must be built alike: the two models code the same stream differently.
+#[cfg(feature="lzma-bit-counter")]
+pub type DefaultBitModel = probability::BitCounter12;
+#[cfg(not(feature="lzma-bit-counter"))]
+pub type DefaultBitModel = probability::CDF2;
+
 pub const HEADER_LENGTH: usize = 16;
 pub const MAGIC_NUMBER:[u8;4] = [0xff, 0xe5,0x8c, 0x9f];
+pub const HEADER_CODER_ID_OFFSET: usize = 4;
+// header byte holding the STREAM_FEATURE_* bits; zero in streams that use the full command alphabet
+pub const HEADER_FEATURE_OFFSET: usize = 6;
+// the stream contains no dict commands
+pub const STREAM_FEATURE_NO_DICT: u8 = 1;
+// the stream contains no literal, command or distance block switches
+pub const STREAM_FEATURE_NO_BLOCK_SWITCH: u8 = 2;
+// command types are coded as literal?/copy? bits before falling back to the command type nibble
+pub const STREAM_FEATURE_COMMAND_TYPE_TREE: u8 = 4;
+// each prediction mode command carries DistanceBucketing parameters for the copies that follow it
+pub const STREAM_FEATURE_DISTANCE_BUCKETING: u8 = 8;
+// a context map entry repeating the previous one is followed by the count of further repeats
+pub const STREAM_FEATURE_CONTEXT_MAP_RLE: u8 = 16;
+// the mux may hold filler bytes before its eof marker, so the frame ends on an alignment boundary
+pub const STREAM_FEATURE_MUX_PADDING: u8 = 32;
+// the checksum trailer is followed by an HMAC-SHA256 tag of the header and compressed bytes
+pub const STREAM_FEATURE_AUTHENTICATED: u8 = 64;
+// the literal context map is coded as a default class plus the entries that differ from it
+pub const STREAM_FEATURE_SPARSE_CONTEXT_MAP: u8 = 128;
+pub const STREAM_FEATURES_SUPPORTED: u8 = STREAM_FEATURE_NO_DICT | STREAM_FEATURE_NO_BLOCK_SWITCH | STREAM_FEATURE_COMMAND_TYPE_TREE
+    | STREAM_FEATURE_DISTANCE_BUCKETING | STREAM_FEATURE_CONTEXT_MAP_RLE | STREAM_FEATURE_MUX_PADDING
+    | STREAM_FEATURE_AUTHENTICATED | STREAM_FEATURE_SPARSE_CONTEXT_MAP;
+// the magic of a stream whose header byte 7 holds EXTENDED_FEATURE_* bits, which older decoders reject
+pub const MAGIC_NUMBER_EXTENDED:[u8;4] = [0xff, 0xe5,0x8c, 0xa0];
+// The magic of a stored frame, which holds an input of under STORED_INPUT_LIMIT bytes as is, in
+// place of a stream: the magic, the length of the input (u8) and the input.  No header, no trailer.
+pub const STORED_MAGIC:[u8;4] = [0xff, 0xe5,0x8c, 0xa1];
+pub const STORED_FRAME_HEADER_LENGTH: usize = 5;
+pub const STORED_INPUT_LIMIT: usize = 64;
+pub const HEADER_EXTENDED_FEATURE_OFFSET: usize = 7;
+// context map entries are coded conditioned on the same entry one block type back
+pub const EXTENDED_FEATURE_CONTEXT_MAP_MODEL: u8 = 1;
+// literals are coded with static tables sent after each prediction mode command, without adaptation
+pub const EXTENDED_FEATURE_STATIC_LITERAL_MODEL: u8 = 2;
+// the stream is meant for its commands rather than its bytes: literal contexts see only the literals
+// before them, never bytes copied or taken from the dictionary, so a decoder handing the commands
+// to the caller need not rebuild the output at all
+pub const EXTENDED_FEATURE_IR_ONLY: u8 = 4;
+// each literal block switch says whether the literals of its block type are coded low nibble first
+pub const EXTENDED_FEATURE_LITERAL_NIBBLE_ORDER: u8 = 8;
+// dictionary transforms are coded conditioned on the quantized word size and the command type before
+pub const EXTENDED_FEATURE_DICT_TRANSFORM_MODEL: u8 = 16;
+// no probability or mixer weight adapts after the command that brings the output to the byte count
+// held little endian in the 4 header bytes from HEADER_FREEZE_ADAPTATION_OFFSET
+pub const EXTENDED_FEATURE_FREEZE_ADAPTATION: u8 = 32;
+// the first literal after a copy is coded knowing the byte the copy would have produced next;
+// IR-only streams, whose literals never see copied bytes, code it as any other
+pub const EXTENDED_FEATURE_MATCH_BYTE_CONTEXT: u8 = 64;
+// each literal block type keeps literal mixer weights of its own (see codec::weights::BlockTypeWeights)
+pub const EXTENDED_FEATURE_BLOCK_TYPE_MIXER: u8 = 128;
+pub const EXTENDED_FEATURES_SUPPORTED: u8 = EXTENDED_FEATURE_CONTEXT_MAP_MODEL | EXTENDED_FEATURE_STATIC_LITERAL_MODEL
+    | EXTENDED_FEATURE_IR_ONLY | EXTENDED_FEATURE_LITERAL_NIBBLE_ORDER | EXTENDED_FEATURE_DICT_TRANSFORM_MODEL
+    | EXTENDED_FEATURE_FREEZE_ADAPTATION | EXTENDED_FEATURE_MATCH_BYTE_CONTEXT | EXTENDED_FEATURE_BLOCK_TYPE_MIXER;
+pub const HEADER_FREEZE_ADAPTATION_OFFSET: usize = 8;
+// The 2 header bytes from here hold, little endian, how many PRIORS_RESET_GRANULARITY byte units of
+// output pass between resets of every probability and mixer weight to its initial state; zero never
+// resets.  Only MAGIC_NUMBER_EXTENDED streams carry them, as the extended feature bits are all taken.
+pub const HEADER_PRIORS_RESET_OFFSET: usize = 12;
+pub const PRIORS_RESET_GRANULARITY: u32 = 1 << 16;
+// the header byte of MAGIC_NUMBER_EXTENDED streams holding the EXTENDED_FEATURE2_* bits, for features
+// that came after the extended feature byte filled up; unlike that byte, decoders reject unknown bits
+pub const HEADER_EXTENDED_FEATURE2_OFFSET: usize = 14;
+// a copy right after a dictionary word may be coded without its command type, as the dictionary
+// word plus window copy pairs of templated text are: a bit in its place says whether it was
+pub const EXTENDED_FEATURE2_DICT_COPY_FUSION: u8 = 1;
+// after the first AUTO_NO_DICT_COMMANDS command types, a bit says whether dictionary words are off
+// for the rest of the stream, as they usually are for binary input that had none by then; an encoder
+// handed a word after turning them off codes its bytes as a literal
+pub const EXTENDED_FEATURE2_AUTO_NO_DICT: u8 = 2;
+pub const AUTO_NO_DICT_COMMANDS: u32 = 1024;
+// the first nibble of a literal is also coded knowing the class of the byte that followed the two
+// bytes before it last time (see codec::priors::BYTE_CLASS); blocks coded low nibble first and
+// literals after a copy, under EXTENDED_FEATURE_MATCH_BYTE_CONTEXT, code it as before
+pub const EXTENDED_FEATURE2_BYTE_CLASS_CONTEXT: u8 = 4;
+// block switches may name block types past 255: the two nibble block type 255 escapes to a varint of
+// 3 bit groups, least significant first, holding how far past 255 it is.  The context maps and the
+// priors kept per block type have 256 rows, so the block types past them share the last.
+pub const EXTENDED_FEATURE2_WIDE_BLOCK_TYPES: u8 = 8;
+pub const EXTENDED_FEATURES2_SUPPORTED: u8 = EXTENDED_FEATURE2_DICT_COPY_FUSION | EXTENDED_FEATURE2_AUTO_NO_DICT
+    | EXTENDED_FEATURE2_BYTE_CLASS_CONTEXT | EXTENDED_FEATURE2_WIDE_BLOCK_TYPES;
+// the header byte of MAGIC_NUMBER_EXTENDED streams naming the checksum of the trailer (see ChecksumType);
+// decoders reject values they do not know
+pub const HEADER_CHECKSUM_OFFSET: usize = 15;
+// set above the stride of a LiteralBlockSwitch whose literals are coded low nibble first, which
+// suits little endian numbers; only EXTENDED_FEATURE_LITERAL_NIBBLE_ORDER streams carry it, and
+// literals with external probabilities ignore it
+pub const LITERAL_BLOCK_SWITCH_LSB_FIRST: u8 = 0x10;
+pub const AUTHENTICATION_KEY_LENGTH: usize = 32;
 
-// Commands that can instantiate as a no-op should implement this.
-/*
-#[derive(Debug)]
-pub struct LiteralCommand<SliceType:SliceWrapper<u8>> {
-    pub data: SliceType,
-    pub prob: SliceType,
+// The generations of the stream format, oldest first.  A decoder reads the streams of its own
+// generation and of every one before it, so a service pinning DivansCompressorOptions::format_version
+// keeps writing streams that decoders not yet upgraded can read.
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FormatVersion {
+    Original, // MAGIC_NUMBER, no STREAM_FEATURE_* bits
+    StreamFeatures, // STREAM_FEATURE_* bits, and mixer warm starts in prediction mode commands
+    Extended, // MAGIC_NUMBER_EXTENDED and its EXTENDED_FEATURE_* bits, and framing tags
+    StoredFrames, // tiny inputs that would grow as a stream are written as STORED_MAGIC frames
+}
+
+impl FormatVersion {
+    pub const LATEST: FormatVersion = FormatVersion::StoredFrames;
 }
 
-impl<SliceType:SliceWrapper<u8>+Default> Nop<LiteralCommand<SliceType>> for LiteralCommand<SliceType> {
-    fn nop() -> Self {
-        LiteralCommand {
-            data: SliceType::default(),
-            prob: SliceType::default(),
+// What the caller knows of the input, for the literal modeling of the brotli hybrid compressor.
+// Only the mixing values and block switch strides it writes depend on it, so any decoder reads the
+// result and a wrong hint costs size, never correctness.
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ContentHint {
+    #[default]
+    Unknown, // each metablock is sniffed for the layouts below
+    Utf16, // 2 byte code units of either byte order, eg. Windows registry and string dumps
+    Opaque, // neither sniffed nor treated as any particular layout
+}
+
+// the EXTENDED_FEATURE_* bits of a header, none unless it carries MAGIC_NUMBER_EXTENDED
+pub fn extended_stream_features(header: &[u8]) -> u8 {
+    if header[..MAGIC_NUMBER_EXTENDED.len()] == MAGIC_NUMBER_EXTENDED {
+        header[HEADER_EXTENDED_FEATURE_OFFSET]
+    } else {
+        0
+    }
+}
+
+// the EXTENDED_FEATURE2_* bits of a header, none unless it carries MAGIC_NUMBER_EXTENDED
+pub fn extended_stream_features2(header: &[u8]) -> u8 {
+    if header[..MAGIC_NUMBER_EXTENDED.len()] == MAGIC_NUMBER_EXTENDED {
+        header[HEADER_EXTENDED_FEATURE2_OFFSET]
+    } else {
+        0
+    }
+}
+
+// the checksum of the trailer of a header, None if this build does not know it
+pub fn checksum_type(header: &[u8]) -> Option<ChecksumType> {
+    if header[..MAGIC_NUMBER_EXTENDED.len()] == MAGIC_NUMBER_EXTENDED {
+        ChecksumType::from_header_byte(header[HEADER_CHECKSUM_OFFSET])
+    } else {
+        Some(ChecksumType::Crc32c)
+    }
+}
+
+// the output length at which the models of the stream stop adapting, if they do
+pub fn freeze_adaptation_after(header: &[u8]) -> Option<u32> {
+    if extended_stream_features(header) & EXTENDED_FEATURE_FREEZE_ADAPTATION == 0 {
+        return None;
+    }
+    let bytes = &header[HEADER_FREEZE_ADAPTATION_OFFSET..HEADER_FREEZE_ADAPTATION_OFFSET + 4];
+    Some(u32::from(bytes[0]) | (u32::from(bytes[1]) << 8) | (u32::from(bytes[2]) << 16) | (u32::from(bytes[3]) << 24))
+}
+
+// the output length between resets of the models of the stream, if they reset
+pub fn priors_reset_interval(header: &[u8]) -> Option<u32> {
+    if header[..MAGIC_NUMBER_EXTENDED.len()] != MAGIC_NUMBER_EXTENDED {
+        return None;
+    }
+    let units = u32::from(header[HEADER_PRIORS_RESET_OFFSET]) | (u32::from(header[HEADER_PRIORS_RESET_OFFSET + 1]) << 8);
+    if units == 0 {
+        None
+    } else {
+        Some(units * PRIORS_RESET_GRANULARITY)
+    }
+}
+
+pub const MAX_DISTANCE_POSTFIX_BITS: u8 = 3;
+// postfix symbol for a distance coded without bucketing (eg. one of the direct distances)
+pub const DISTANCE_POSTFIX_DIRECT: u8 = 15;
+
+// Brotli style distance buckets (NPOSTFIX/NDIRECT). Distances up to num_direct are coded as is;
+// larger ones code the low postfix_bits of distance - num_direct - 1 as a separate symbol
+// and the remaining high bits with the usual length and mantissa nibbles.
+// Records of a fixed, aligned size tend to repeat the postfix, so it gets its own priors.
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DistanceBucketing {
+    pub postfix_bits: u8,
+    pub num_direct: u8, // a multiple of 1 << postfix_bits, at most 15 << postfix_bits
+}
+
+impl DistanceBucketing {
+    pub fn new(postfix_bits: u8, num_direct: u8) -> Result<Self, ErrMsg> {
+        if postfix_bits > MAX_DISTANCE_POSTFIX_BITS
+            || u32::from(num_direct) > 15u32 << postfix_bits
+            || num_direct & ((1 << postfix_bits) - 1) != 0 {
+            return Err(ErrMsg::InvalidDistanceBucketing(postfix_bits, num_direct));
         }
+        Ok(DistanceBucketing{postfix_bits: postfix_bits, num_direct: num_direct})
+    }
+    pub fn is_identity(&self) -> bool {
+        self.postfix_bits == 0 && self.num_direct == 0
+    }
+    // returns the postfix symbol and the value left for the length and mantissa nibbles
+    pub fn split(&self, distance: u32) -> (u8, u32) {
+        if distance <= u32::from(self.num_direct) {
+            return (DISTANCE_POSTFIX_DIRECT, distance);
+        }
+        let offset = distance - u32::from(self.num_direct) - 1;
+        ((offset & ((1 << self.postfix_bits) - 1)) as u8, (offset >> self.postfix_bits) + 1)
+    }
+    pub fn join(&self, postfix: u8, coded: u32) -> u32 {
+        if postfix == DISTANCE_POSTFIX_DIRECT {
+            return coded;
+        }
+        ((coded.wrapping_sub(1) << self.postfix_bits) | u32::from(postfix)).wrapping_add(u32::from(self.num_direct) + 1)
     }
 }
 
-impl<SliceType:SliceWrapper<u8>+Default+Clone> Clone for LiteralCommand<SliceType> {
-    fn clone(&self) -> Self {
-        LiteralCommand {
-            data: self.data.clone(),
-            prob: self.prob.clone(),
+// Where the literal mixers start, so a short stream need not spend its first literals learning it.
+// A prediction mode command carries it in the byte brotli reserves after the mode (see
+// MIXER_WARM_START_OFFSET) and codes it after a prediction mode nibble with MIXER_WARM_START_FLAG set;
+// decoders predating it reject that nibble as an out of range prediction mode.
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MixerWarmStart {
+    pub cm_share: u8, // sixteenths of the weight on the context mixing model, 0 for the usual even split
+    pub seed_high_nibble: u8, // the high nibble the context mixing priors start out favoring, 0 for none
+}
+
+pub const MIXER_WARM_START_OFFSET: usize = RESERVED_OFFSET;
+pub const MIXER_WARM_START_FLAG: u8 = 4;
+
+impl MixerWarmStart {
+    pub fn new(cm_share: u8, seed_high_nibble: u8) -> Result<Self, ErrMsg> {
+        if cm_share > 15 || seed_high_nibble > 15 || (cm_share | seed_high_nibble) == 0 {
+            return Err(ErrMsg::OptionOutOfRange(CompressorOption::MixerWarmStart));
         }
+        Ok(MixerWarmStart{cm_share, seed_high_nibble})
+    }
+    pub fn to_byte(&self) -> u8 {
+        (self.seed_high_nibble << 4) | self.cm_share
+    }
+    // None for the 0 every command without a warm start holds
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        if byte == 0 {
+            return None;
+        }
+        Some(MixerWarmStart{cm_share: byte & 0xf, seed_high_nibble: byte >> 4})
+    }
+    pub fn of<SliceType:SliceWrapper<u8>>(pm: &PredictionModeContextMap<SliceType>) -> Option<Self> {
+        match pm.predmode_speed_and_distance_context_map.slice().get(MIXER_WARM_START_OFFSET) {
+            Some(byte) => Self::from_byte(*byte),
+            None => None,
+        }
+    }
+}
+
+// brotli's block switches hold a u8 block type; divans' hold any u32 one, though only
+// EXTENDED_FEATURE2_WIDE_BLOCK_TYPES streams can carry one past 255
+#[derive(Debug,Copy,Clone,Default,PartialEq,Eq)]
+pub struct BlockSwitch(pub u32);
+
+impl BlockSwitch {
+    #[inline(always)]
+    pub fn new(block_type: u32) -> Self {
+        BlockSwitch(block_type)
+    }
+    #[inline(always)]
+    pub fn block_type(&self) -> u32 {
+        self.0
+    }
+}
+
+impl From<brotli::enc::interface::BlockSwitch> for BlockSwitch {
+    fn from(bs: brotli::enc::interface::BlockSwitch) -> Self {
+        BlockSwitch(u32::from(bs.block_type()))
     }
 }
-impl<SliceType:SliceWrapper<u8>+Default+Clone+Copy> Copy for LiteralCommand<SliceType> {
+
+#[derive(Debug,Copy,Clone,Default,PartialEq,Eq)]
+pub struct LiteralBlockSwitch(pub BlockSwitch, pub u8);
+
+impl LiteralBlockSwitch {
+    pub fn new(block_type: u32, stride: u8) -> Self {
+        LiteralBlockSwitch(BlockSwitch::new(block_type), stride)
+    }
+    #[inline(always)]
+    pub fn block_type(&self) -> u32 {
+        self.0.block_type()
+    }
+    #[inline(always)]
+    pub fn stride(&self) -> u8 {
+        self.1
+    }
+    #[inline(always)]
+    pub fn update_stride(&mut self, new_stride: u8) {
+        self.1 = new_stride;
+    }
 }
 
+impl From<brotli::enc::interface::LiteralBlockSwitch> for LiteralBlockSwitch {
+    fn from(bs: brotli::enc::interface::LiteralBlockSwitch) -> Self {
+        LiteralBlockSwitch(BlockSwitch::from(bs.0), bs.stride())
+    }
+}
 
+// brotli's Command but for the block switches, so that the IR can name block types past 255
 #[derive(Debug)]
 pub enum Command<SliceType:SliceWrapper<u8> > {
     Copy(CopyCommand),
@@ -202,36 +594,122 @@ pub enum Command<SliceType:SliceWrapper<u8> > {
     BlockSwitchDistance(BlockSwitch),
     PredictionMode(PredictionModeContextMap<SliceType>),
 }
-impl<SliceType:SliceWrapper<u8>+Default+Clone+Copy> Copy for Command<SliceType> {
-}
-impl<SliceType:SliceWrapper<u8>+Default+Clone> Clone for Command<SliceType> {
-    fn clone(&self) -> Command<SliceType> {
-        match self {
-            &Command::Copy(ref cpy) => {
-                Command::Copy(cpy.clone())
-            },
-            &Command::Dict(ref dict) => {
-                Command::Dict(dict.clone())
-            },
-            &Command::Literal(ref lit) => {
-                Command::Literal(lit.clone())
-            },
-            &Command::PredictionMode(ref lit) => {
-                Command::PredictionMode(lit.clone())
-            },
-            &Command::BlockSwitchCommand(ref bs) => {
-                Command::BlockSwitchCommand(bs.clone())
-            },
-            &Command::BlockSwitchLiteral(ref bs) => {
-                Command::BlockSwitchLiteral(bs.clone())
+
+pub type StaticCommand = Command<SliceOffset>;
+
+impl<SliceType:SliceWrapper<u8>+Default> Command<SliceType> {
+    #[inline]
+    pub fn free_array<F>(&mut self, apply_func: &mut F) where F: FnMut(SliceType) {
+        match *self {
+            Command::Literal(ref mut lit) => {
+                apply_func(core::mem::replace(&mut lit.data, SliceType::default()))
             },
-            &Command::BlockSwitchDistance(ref bs) => {
-                Command::BlockSwitchDistance(bs.clone())
+            Command::PredictionMode(ref mut pm) => {
+                apply_func(core::mem::replace(&mut pm.literal_context_map, SliceType::default()));
+                apply_func(core::mem::replace(&mut pm.predmode_speed_and_distance_context_map, SliceType::default()));
             },
+            _ => {},
         }
     }
 }
- */
+
+impl<SliceType:SliceWrapper<u8>> Default for Command<SliceType> {
+    #[inline(always)]
+    fn default() -> Command<SliceType> {
+        Command::<SliceType>::nop()
+    }
+}
+
+impl<SliceType:SliceWrapper<u8>> Nop<Command<SliceType>> for Command<SliceType> {
+    #[inline(always)]
+    fn nop() -> Command<SliceType> {
+        Command::Copy(CopyCommand::nop())
+    }
+}
+
+impl<SliceType:SliceWrapper<u8>+Clone> Clone for Command<SliceType> {
+    #[inline(always)]
+    fn clone(&self) -> Command<SliceType> {
+        match *self {
+            Command::Copy(ref copy) => Command::Copy(*copy),
+            Command::Dict(ref dict) => Command::Dict(*dict),
+            Command::Literal(ref literal) => Command::Literal(literal.clone()),
+            Command::BlockSwitchCommand(ref switch) => Command::BlockSwitchCommand(*switch),
+            Command::BlockSwitchLiteral(ref switch) => Command::BlockSwitchLiteral(*switch),
+            Command::BlockSwitchDistance(ref switch) => Command::BlockSwitchDistance(*switch),
+            Command::PredictionMode(ref pm) => Command::PredictionMode(pm.clone()),
+        }
+    }
+}
+
+impl<SliceType:SliceWrapper<u8>+Clone+Copy> Copy for Command<SliceType> {
+}
+
+impl<SliceType:SliceWrapper<u8>> From<brotli::enc::interface::Command<SliceType>> for Command<SliceType> {
+    fn from(cmd: brotli::enc::interface::Command<SliceType>) -> Self {
+        match cmd {
+            brotli::enc::interface::Command::Copy(copy) => Command::Copy(copy),
+            brotli::enc::interface::Command::Dict(dict) => Command::Dict(dict),
+            brotli::enc::interface::Command::Literal(literal) => Command::Literal(literal),
+            brotli::enc::interface::Command::BlockSwitchCommand(switch) => Command::BlockSwitchCommand(switch.into()),
+            brotli::enc::interface::Command::BlockSwitchLiteral(switch) => Command::BlockSwitchLiteral(switch.into()),
+            brotli::enc::interface::Command::BlockSwitchDistance(switch) => Command::BlockSwitchDistance(switch.into()),
+            brotli::enc::interface::Command::PredictionMode(pm) => Command::PredictionMode(pm),
+        }
+    }
+}
+
+#[inline(always)]
+pub fn free_cmd_inline<SliceTypeAllocator:Allocator<u8>> (xself: &mut Command<SliceTypeAllocator::AllocatedMemory>, m8: &mut SliceTypeAllocator) {
+    match *xself {
+        Command::Literal(ref mut lit) => {
+            m8.free_cell(core::mem::replace(&mut lit.data, SliceTypeAllocator::AllocatedMemory::default()))
+        },
+        Command::PredictionMode(ref mut pm) => {
+            m8.free_cell(core::mem::replace(&mut pm.literal_context_map, SliceTypeAllocator::AllocatedMemory::default()));
+            m8.free_cell(core::mem::replace(&mut pm.predmode_speed_and_distance_context_map, SliceTypeAllocator::AllocatedMemory::default()));
+        },
+        Command::Dict(_) |
+        Command::Copy(_) |
+        Command::BlockSwitchCommand(_) |
+        Command::BlockSwitchLiteral(_) |
+        Command::BlockSwitchDistance(_) => {},
+    }
+}
+
+#[inline(never)]
+pub fn free_cmd<SliceTypeAllocator:Allocator<u8>> (xself: &mut Command<SliceTypeAllocator::AllocatedMemory>, m8: &mut SliceTypeAllocator) {
+    free_cmd_inline(xself, m8)
+}
+
+// brotli's thaw_pair, for the commands of its callback
+pub fn thaw_pair<'a, SliceType: Unfreezable + SliceWrapper<u8>>(xself: &brotli::enc::interface::Command<SliceType>,
+                                                                data: &brotli::InputPair<'a>) -> Command<brotli::InputReference<'a>> {
+    Command::from(brotli::enc::interface::thaw_pair(xself, data))
+}
+
+pub fn thaw<'a, SliceType: Unfreezable + SliceWrapper<u8>>(xself: &Command<SliceType>, data: &'a [u8]) -> Command<brotli::InputReference<'a>> {
+    match *xself {
+        Command::Literal(ref lit) => {
+            Command::Literal(LiteralCommand{
+                data: lit.data.thaw(data),
+                prob: FeatureFlagSliceType::<brotli::InputReference<'a>>::default(),
+                high_entropy: lit.high_entropy,
+            })
+        },
+        Command::PredictionMode(ref pm) => {
+            Command::PredictionMode(PredictionModeContextMap{
+                literal_context_map: pm.literal_context_map.thaw(data),
+                predmode_speed_and_distance_context_map: pm.predmode_speed_and_distance_context_map.thaw(data),
+            })
+        },
+        Command::Dict(ref d) => Command::Dict(*d),
+        Command::Copy(ref c) => Command::Copy(*c),
+        Command::BlockSwitchCommand(ref c) => Command::BlockSwitchCommand(*c),
+        Command::BlockSwitchLiteral(ref c) => Command::BlockSwitchLiteral(*c),
+        Command::BlockSwitchDistance(ref c) => Command::BlockSwitchDistance(*c),
+    }
+}
 pub const NUM_STREAMS: usize = 2;
 pub const STREAM_ID_MASK: StreamID = 0x1;
 pub type StreamID = u8;
@@ -263,6 +741,11 @@ pub trait StreamMuxer<AllocU8: Allocator<u8> > {
     fn linearize(&mut self, output:&mut[u8]) -> usize;
     #[inline(always)]
     fn flush(&mut self, output:&mut[u8]) -> usize;
+    // writes out whatever is buffered, however little, without ending the stream
+    fn drain(&mut self, output:&mut[u8]) -> usize;
+    // have flush write filler ahead of the eof marker so that overhead bytes plus everything
+    // this muxer writes comes to a multiple of alignment (0 or 1 for no filler)
+    fn set_frame_alignment(&mut self, alignment: usize, overhead: usize);
     #[inline(always)]
     fn wrote_eof(&self) -> bool;
     #[inline(always)]
@@ -289,6 +772,59 @@ pub trait StreamDemuxer<AllocU8: Allocator<u8> > {
     fn free_demux(&mut self, m8: &mut AllocU8);
 }
 
+// What a stream of input_len bytes may grow to: random input stays well within it under either
+// compressor with the default options.  The _to_vec calls reserve it up front, and grow the Vec
+// further should a stream outgrow it.
+pub fn max_compressed_size(input_len: usize) -> usize {
+    input_len.saturating_add(input_len / 8).saturating_add(4096)
+}
+
+// The bytes a stream written with opts spends outside its mux packets: the header, the mux's eof
+// marker, the trailer and the authentication tag if any.  Exact, but for the filler of frame
+// alignment, up to frame_alignment - 1 bytes more.  An input of under STORED_INPUT_LIMIT bytes that
+// the compressor stores (see DivansCompressorOptions::stores_tiny_inputs) takes at most
+// STORED_FRAME_HEADER_LENGTH bytes over its own length instead.
+pub fn framing_overhead(opts: &DivansCompressorOptions) -> usize {
+    let framing = opts.framing_tag.as_ref();
+    let tag_len = if opts.authentication_key.is_some() { wire::MAC_LENGTH } else { 0 };
+    wire::header_length(framing) + ::mux::EOF_MARKER.len() + wire::checksum_length(framing, opts.checksum) + tag_len
+}
+
+// Worst case estimates of the bytes one command takes in the mux packets, its command type
+// included, were every symbol coded the least probable its model allows.  Real commands take a
+// small fraction of these; they bound what a caller packing streams by command count must reserve.
+// Each mux packet adds MUX_PACKET_HEADER_SIZE bytes, and each stream ANS_CHUNK_STATE_SIZE bytes
+// per ANS_CHUNK_SYMBOLS symbols, on top.
+const MAX_NIBBLE_BITS: usize = probability::interface::LOG2_SCALE as usize;
+const MAX_BIT_BITS: usize = 8;
+// up to two tree bits, the nibble, and the bits of EXTENDED_FEATURE2_DICT_COPY_FUSION and AUTO_NO_DICT
+const MAX_COMMAND_TYPE_BITS: usize = 4 * MAX_BIT_BITS + MAX_NIBBLE_BITS;
+// up to 10 nibbles of length and 10 of distance
+pub const MAX_COPY_COMMAND_SIZE: usize = (MAX_COMMAND_TYPE_BITS + 20 * MAX_NIBBLE_BITS + 7) / 8;
+// up to 2 nibbles of word size, 4 of word index and 2 of transform
+pub const MAX_DICT_COMMAND_SIZE: usize = (MAX_COMMAND_TYPE_BITS + 8 * MAX_NIBBLE_BITS + 7) / 8;
+// up to 11 nibbles of length; MAX_LITERAL_BYTE_SIZE more per byte
+pub const MAX_LITERAL_COMMAND_SIZE: usize = (MAX_COMMAND_TYPE_BITS + 11 * MAX_NIBBLE_BITS + 7) / 8;
+pub const MAX_LITERAL_BYTE_SIZE: usize = (2 * MAX_NIBBLE_BITS + 7) / 8;
+pub const MUX_PACKET_HEADER_SIZE: usize = ::mux::MAX_HEADER_SIZE;
+pub const ANS_CHUNK_STATE_SIZE: usize = 16;
+pub const ANS_CHUNK_SYMBOLS: usize = ::ans::NUM_SYMBOLS_BEFORE_FLUSH as usize;
+
+// Extends output to its capacity, reserving at least reserve bytes past its length, for f to write
+// into from its old length on, then cuts it back to what f wrote.
+#[cfg(feature="std")]
+fn append_within_capacity<Ret, F:FnOnce(&mut [u8], &mut usize) -> Ret>(output: &mut Vec<u8>,
+                                                                      reserve: usize,
+                                                                      f: F) -> Ret {
+    output.reserve(reserve);
+    let mut output_offset = output.len();
+    let capacity = output.capacity();
+    output.resize(capacity, 0);
+    let ret = f(&mut output[..], &mut output_offset);
+    output.truncate(output_offset);
+    ret
+}
+
 pub trait Compressor {
     fn encode(&mut self,
               input:&[u8],
@@ -300,9 +836,71 @@ pub trait Compressor {
                                           input_offset : &mut usize,
                                           output :&mut[u8],
                                           output_offset: &mut usize) -> DivansOutputResult;
+    // Pulls commands from input as the output has room for them, so a frontend may generate the IR
+    // lazily.  A command is only taken off input once it is fully encoded: one left at the front
+    // after NeedsMoreOutput is partially written and must be offered again on the next call.
+    fn encode_command_iter<SliceType:SliceWrapper<u8>+Default,
+                           Commands:Iterator<Item=Command<SliceType>>>(&mut self,
+                                                                       input: &mut core::iter::Peekable<Commands>,
+                                                                       output :&mut[u8],
+                                                                       output_offset: &mut usize) -> DivansOutputResult {
+        loop {
+            match input.peek() {
+                Some(cmd) => {
+                    let mut cmd_offset = 0usize;
+                    match self.encode_commands(core::slice::from_ref(cmd), &mut cmd_offset, output, output_offset) {
+                        DivansOutputResult::Success => {},
+                        res => return res,
+                    }
+                },
+                None => return DivansOutputResult::Success,
+            }
+            input.next();
+        }
+    }
+    // ends the stream as finish does; kept for the callers that predate finish
     fn flush(&mut self,
                                           output :&mut[u8],
                                           output_offset: &mut usize) -> DivansOutputResult;
+    // Models everything handed to encode so far and writes out what the coders have produced from it,
+    // leaving the stream open: encode may carry on afterwards.  Each coder writes in chunks of
+    // ans::NUM_SYMBOLS_BEFORE_FLUSH symbols, so the chunk still filling comes out with what follows or
+    // with finish, and a decoder may not get through all that was flushed until then.  Fails with NotAllowedToEncodeAfterFlush once finishing.
+    fn sync_flush(&mut self,
+                  output :&mut[u8],
+                  output_offset: &mut usize) -> DivansOutputResult;
+    // Ends the stream: writes the EOF node, drains the coders and writes the trailer, returning Success
+    // once all of it is out.  Input and commands are refused with NotAllowedToEncodeAfterFlush after it.
+    fn finish(&mut self,
+              output :&mut[u8],
+              output_offset: &mut usize) -> DivansOutputResult {
+        self.flush(output, output_offset)
+    }
+    // encode, appending to output rather than a fixed slice, so it never returns NeedsMoreOutput
+    #[cfg(feature="std")]
+    fn encode_to_vec(&mut self,
+                     input:&[u8],
+                     input_offset: &mut usize,
+                     output: &mut Vec<u8>) -> DivansResult {
+        let mut reserve = max_compressed_size(input.len().saturating_sub(*input_offset));
+        loop {
+            match append_within_capacity(output, reserve, |out, out_offset| self.encode(input, input_offset, out, out_offset)) {
+                DivansResult::NeedsMoreOutput => reserve = core::cmp::max(output.len(), reserve),
+                res => return res,
+            }
+        }
+    }
+    // flush, appending the rest of the stream to output; never returns NeedsMoreOutput
+    #[cfg(feature="std")]
+    fn flush_to_vec(&mut self, output: &mut Vec<u8>) -> DivansOutputResult {
+        let mut reserve = max_compressed_size(0);
+        loop {
+            match append_within_capacity(output, reserve, |out, out_offset| self.flush(out, out_offset)) {
+                DivansOutputResult::NeedsMoreOutput => reserve = core::cmp::max(output.len(), reserve),
+                res => return res,
+            }
+        }
+    }
 }
 
 pub trait Decompressor {
@@ -311,6 +909,22 @@ pub trait Decompressor {
               input_offset : &mut usize,
               output :&mut[u8],
               output_offset: &mut usize) -> DivansResult;
+    // decode, appending to output rather than a fixed slice, so it never returns NeedsMoreOutput.
+    // Room for four times the remaining input is reserved at first; a caller that knows the
+    // decompressed size may reserve it beforehand instead.
+    #[cfg(feature="std")]
+    fn decode_to_vec(&mut self,
+                     input:&[u8],
+                     input_offset: &mut usize,
+                     output: &mut Vec<u8>) -> DivansResult {
+        let mut reserve = input.len().saturating_sub(*input_offset).saturating_mul(4).saturating_add(4096);
+        loop {
+            match append_within_capacity(output, reserve, |out, out_offset| self.decode(input, input_offset, out, out_offset)) {
+                DivansResult::NeedsMoreOutput => reserve = core::cmp::max(output.len(), reserve),
+                res => return res,
+            }
+        }
+    }
 }
 
 pub trait CommandDecoder {
@@ -333,6 +947,7 @@ pub enum BillingDesignation {
     CrossCommand(CrossCommandBilling),
     PredModeCtxMap(PredictionModeSubstate),
     BlockType(BlockTypeState),
+    StaticLiteralTables,
 }
 
 #[derive(PartialEq, Eq, Hash, Debug, Clone)]
@@ -350,8 +965,13 @@ pub trait NewWithAllocator<AllocU8: Allocator<u8>> {
     fn free(&mut self, m8: &mut AllocU8);
 }
 
+// an independent copy whose buffers come from m8; the copy is released with its own free
+pub trait DuplicateWithAllocator<AllocU8: Allocator<u8>> : Sized {
+    fn duplicate(&self, m8: &mut AllocU8) -> Self;
+}
+
 pub trait BillingCapability { // maybe we should have called it capa-bill-ity
-    fn debug_print(&self, _size:usize) {
+    fn debug_print(&self, _size:u64) {
         //intentially a default noop, can be filled out by decoders
     }
 }
@@ -413,9 +1033,35 @@ pub trait ArithmeticEncoderOrDecoder : Sized {
                                    _billing: BillingDesignation) -> ProbRange {
         self.get_or_put_nibble_without_billing(nibble, prob)
     }
+    // true if get_or_put_nibble_batch does better than coding the nibbles one at a time
+    const BATCHES_NIBBLES: bool = false;
+    // Codes nibbles[i] with probs[i], for up to MAX_NIBBLE_BATCH nibbles whose CDFs are all known
+    // up front.  Stops early once the internal buffer needs draining or filling and returns how
+    // many nibbles it coded, at least one.
+    #[inline(always)]
+    fn get_or_put_nibble_batch_without_billing<C: CDF16>(&mut self,
+                                                         nibbles: &mut [u8],
+                                                         probs: &[C]) -> usize {
+        debug_assert!(nibbles.len() <= MAX_NIBBLE_BATCH && nibbles.len() <= probs.len());
+        for (index, (nibble, prob)) in nibbles.iter_mut().zip(probs.iter()).enumerate() {
+            if index != 0 && self.has_data_to_drain_or_fill() {
+                return index;
+            }
+            self.get_or_put_nibble_without_billing(nibble, prob);
+        }
+        nibbles.len()
+    }
+    #[inline(always)]
+    fn get_or_put_nibble_batch<C: CDF16>(&mut self,
+                                         nibbles: &mut [u8],
+                                         probs: &[C],
+                                         _billing: BillingDesignation) -> usize {
+        self.get_or_put_nibble_batch_without_billing(nibbles, probs)
+    }
 
     fn close(&mut self) -> DivansResult;
 }
+pub const MAX_NIBBLE_BATCH: usize = 8;
 pub trait DivansCompressorFactory<
      AllocU8:Allocator<u8>,
      AllocU32:Allocator<u32>,
@@ -429,7 +1075,8 @@ pub trait DivansCompressorFactory<
 }
 
 #[repr(u8)]
-#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum BrotliCompressionSetting {
     UseInternalCommandSelection = 0,
     UseBrotliCommandSelection = 1,
@@ -441,13 +1088,15 @@ impl Default for BrotliCompressionSetting {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct DivansCompressorOptions{
     pub literal_adaptation: Option<[probability::Speed;4]>,
     pub window_size: Option<i32>,
     pub lgblock: Option<u32>,
     pub quality: Option<u16>,
     pub q9_5: bool,
+    #[cfg_attr(feature="serde", serde(with="literal_prediction_mode_serde::option"))]
     pub force_literal_context_mode: Option<LiteralPredictionModeNibble>,
     pub dynamic_context_mixing: Option<u8>,
     pub stride_detection_quality: Option<u8>,
@@ -459,6 +1108,51 @@ pub struct DivansCompressorOptions{
     pub prior_bitmask_detection: u8,
     pub brotli_literal_byte_score: Option<u32>,
     pub divans_ir_optimizer: u8,
+    // cost a sample of each literal block type under the context map, mixed and stride models and
+    // switch the block type to one of them when it clearly beats the mixing values brotli chose
+    pub literal_prescan: bool,
+    // STREAM_FEATURE_* bits for the header; the brotli hybrid compressor only honors the coding choices
+    // (STREAM_FEATURE_COMMAND_TYPE_TREE, STREAM_FEATURE_DISTANCE_BUCKETING, STREAM_FEATURE_CONTEXT_MAP_RLE
+    // and STREAM_FEATURE_SPARSE_CONTEXT_MAP)
+    pub stream_features: u8,
+    // anything but the identity also sets STREAM_FEATURE_DISTANCE_BUCKETING
+    pub distance_bucketing: DistanceBucketing,
+    // pad the frame, header through checksum, to a multiple of this many bytes (e.g. a container's
+    // sector size); sets STREAM_FEATURE_MUX_PADDING
+    pub frame_alignment: Option<u32>,
+    // append an HMAC-SHA256 tag under this key after the checksum; sets STREAM_FEATURE_AUTHENTICATED.
+    // Never serialized, so logged options do not leak it
+    #[cfg_attr(feature="serde", serde(skip))]
+    pub authentication_key: Option<[u8; AUTHENTICATION_KEY_LENGTH]>,
+    // start the literal mixers here rather than at an even split; needs dynamic_context_mixing of 2 or more
+    pub mixer_warm_start: Option<MixerWarmStart>,
+    // EXTENDED_FEATURE_* bits; any set switches the header to MAGIC_NUMBER_EXTENDED.
+    // Only the brotli hybrid compressor chooses tables for EXTENDED_FEATURE_STATIC_LITERAL_MODEL;
+    // the others send uniform ones.  Likewise it alone picks nibble orders for
+    // EXTENDED_FEATURE_LITERAL_NIBBLE_ORDER; the others keep those of the block switches they are given.
+    pub extended_stream_features: u8,
+    // written in place of the magic number and the checksum tag (see wire::FramingTag)
+    pub framing_tag: Option<wire::FramingTag>,
+    // keep the high nibble literal priors of just the contexts used so far; off saves reallocating
+    // them at prediction mode commands, and either way the stream is the same
+    pub compact_priors: bool,
+    // the newest format version the stream may need; the compressors fail rather than write a
+    // newer one.  None writes whatever the other options call for (see written_format_version)
+    pub format_version: Option<FormatVersion>,
+    // steers the literal modeling of the brotli hybrid compressor; Unknown lets it sniff the input
+    pub content_hint: ContentHint,
+    // Stop adapting every probability and mixer weight once the output reaches this many bytes:
+    // decoding skips the model updates and a long, uniform input cannot drift them.  Sets
+    // EXTENDED_FEATURE_FREEZE_ADAPTATION; entropy_map prices the same commands with and without it.
+    pub freeze_adaptation_after: Option<u32>,
+    // Reset every probability and mixer weight each time the output passes a multiple of this many
+    // bytes, a multiple of PRIORS_RESET_GRANULARITY: a corrupt bit garbles the models only until the
+    // next reset, and a decoder restarting there needs just the window.  Costs some ratio per reset.
+    pub priors_reset_interval: Option<u32>,
+    // EXTENDED_FEATURE2_* bits, coded in the header byte after the priors reset interval
+    pub extended_stream_features2: u8,
+    // the checksum the trailer holds; any but Crc32c switches the header to MAGIC_NUMBER_EXTENDED
+    pub checksum: ChecksumType,
 }
 impl Default for DivansCompressorOptions{
     fn default() ->Self {
@@ -479,6 +1173,561 @@ impl Default for DivansCompressorOptions{
             prior_bitmask_detection: 1,
             brotli_literal_byte_score: None,
             divans_ir_optimizer: 0,
+            literal_prescan: false,
+            stream_features: STREAM_FEATURE_COMMAND_TYPE_TREE | STREAM_FEATURE_CONTEXT_MAP_RLE,
+            distance_bucketing: DistanceBucketing::default(),
+            frame_alignment: None,
+            authentication_key: None,
+            mixer_warm_start: None,
+            extended_stream_features: 0,
+            framing_tag: None,
+            compact_priors: true,
+            format_version: None,
+            content_hint: ContentHint::Unknown,
+            freeze_adaptation_after: None,
+            priors_reset_interval: None,
+            extended_stream_features2: 0,
+            checksum: ChecksumType::Crc32c,
+        }
+    }
+}
+
+impl DivansCompressorOptions {
+    // The factories quietly clamp or override settings they cannot honor; this reports the
+    // offending fields instead so callers can surface them before compressing anything.
+    pub fn validate(&self) -> Result<(), ErrMsg> {
+        let stride = self.force_stride_value as u8;
+        if let Some(window_size) = self.window_size {
+            if !(10..=24).contains(&window_size) {
+                return Err(ErrMsg::OptionOutOfRange(CompressorOption::WindowSize));
+            }
+        }
+        if let Some(mixing) = self.dynamic_context_mixing {
+            if mixing >> 3 != 0 { // shares a nibble with the adv context map bit
+                return Err(ErrMsg::OptionOutOfRange(CompressorOption::DynamicContextMixing));
+            }
+            // CrossCommandBookKeeping turns mixing on to combine the stride and context map predictions
+            if mixing == 0 && self.use_context_map && stride != StrideSelection::PriorDisabled as u8 {
+                return Err(ErrMsg::ConflictingOptions(CompressorOption::DynamicContextMixing,
+                                                      CompressorOption::ForceStrideValue));
+            }
+        }
+        if let Some(prior_depth) = self.prior_depth {
+            if prior_depth > 15 {
+                return Err(ErrMsg::OptionOutOfRange(CompressorOption::PriorDepth));
+            }
+            let forced_stride = stride != StrideSelection::PriorDisabled as u8 && stride != StrideSelection::UseBrotliRec as u8;
+            if prior_depth == 0 && forced_stride {
+                return Err(ErrMsg::ConflictingOptions(CompressorOption::ForceStrideValue,
+                                                      CompressorOption::PriorDepth));
+            }
+        }
+        if let Some(speeds) = self.literal_adaptation {
+            for speed in speeds.iter() {
+                // the frequentist CDFs rescale once the total reaches lim, which must leave room
+                // for an increment below i16::MAX; the stream carries each speed as an f8 pair
+                if speed.inc() < 0 || speed.inc() > speed.lim() || speed.lim() > 0x4000
+                    || probability::Speed::from_f8_tuple(speed.to_f8_tuple()) != *speed {
+                    return Err(ErrMsg::OptionOutOfRange(CompressorOption::LiteralAdaptation));
+                }
+            }
+        }
+        if let Some(warm_start) = self.mixer_warm_start {
+            MixerWarmStart::new(warm_start.cm_share, warm_start.seed_high_nibble)?;
+            // below 2 there is neither a mixer nor context mixing priors to start
+            if self.dynamic_context_mixing.unwrap_or(0) < 2 {
+                return Err(ErrMsg::ConflictingOptions(CompressorOption::MixerWarmStart,
+                                                      CompressorOption::DynamicContextMixing));
+            }
+        }
+        if self.extended_stream_features2 & !EXTENDED_FEATURES2_SUPPORTED != 0 {
+            return Err(ErrMsg::OptionOutOfRange(CompressorOption::ExtendedStreamFeatures2));
+        }
+        if let Some(interval) = self.priors_reset_interval {
+            if interval == 0 || interval % PRIORS_RESET_GRANULARITY != 0 {
+                return Err(ErrMsg::OptionOutOfRange(CompressorOption::PriorsResetInterval));
+            }
+        }
+        self.check_format_version()
+    }
+    // each option that moves the stream past the original format, with the version it needs
+    fn format_version_needs(&self) -> [(bool, FormatVersion, CompressorOption); 11] {
+        [(self.stream_features != 0, FormatVersion::StreamFeatures, CompressorOption::StreamFeatures),
+         (!self.distance_bucketing.is_identity(), FormatVersion::StreamFeatures, CompressorOption::DistanceBucketing),
+         (self.frame_alignment.is_some(), FormatVersion::StreamFeatures, CompressorOption::FrameAlignment),
+         (self.authentication_key.is_some(), FormatVersion::StreamFeatures, CompressorOption::AuthenticationKey),
+         (self.mixer_warm_start.is_some(), FormatVersion::StreamFeatures, CompressorOption::MixerWarmStart),
+         (self.extended_stream_features != 0, FormatVersion::Extended, CompressorOption::ExtendedStreamFeatures),
+         (self.framing_tag.is_some(), FormatVersion::Extended, CompressorOption::FramingTag),
+         (self.freeze_adaptation_after.is_some(), FormatVersion::Extended, CompressorOption::FreezeAdaptationAfter),
+         (self.priors_reset_interval.is_some(), FormatVersion::Extended, CompressorOption::PriorsResetInterval),
+         (self.extended_stream_features2 != 0, FormatVersion::Extended, CompressorOption::ExtendedStreamFeatures2),
+         (self.checksum != ChecksumType::Crc32c, FormatVersion::Extended, CompressorOption::Checksum)]
+    }
+    // Whether the compressors write an input of under STORED_INPUT_LIMIT bytes as a stored frame when
+    // the stream would be larger.  A stored frame has no trailer to splice at, frame, tag or pad.
+    pub fn stores_tiny_inputs(&self) -> bool {
+        self.format_version.is_none_or(|version| version >= FormatVersion::StoredFrames)
+            && self.framing_tag.is_none() && self.authentication_key.is_none() && self.frame_alignment.is_none()
+    }
+    // the format version of the streams written with these options, whatever format_version says
+    pub fn written_format_version(&self) -> FormatVersion {
+        let mut ret = FormatVersion::Original;
+        for &(needed, version, _) in self.format_version_needs().iter() {
+            if needed && version > ret {
+                ret = version;
+            }
+        }
+        ret
+    }
+    // names the first option needing a newer format version than the one pinned, if any
+    pub fn check_format_version(&self) -> Result<(), ErrMsg> {
+        if let Some(pinned) = self.format_version {
+            for &(needed, version, option) in self.format_version_needs().iter() {
+                if needed && version > pinned {
+                    return Err(ErrMsg::ConflictingOptions(CompressorOption::FormatVersion, option));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// What the trailer holds of the header and compressed bytes, named by header byte HEADER_CHECKSUM_OFFSET.
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChecksumType {
+    Crc32c, // 4 bytes of crc32c (Castagnoli)
+    Xxh3, // 8 bytes of XXH3-64, for archives large enough that 32 bits collide
+}
+
+impl ChecksumType {
+    pub fn from_header_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(ChecksumType::Crc32c),
+            1 => Some(ChecksumType::Xxh3),
+            _ => None,
+        }
+    }
+    pub fn header_byte(&self) -> u8 {
+        match *self {
+            ChecksumType::Crc32c => 0,
+            ChecksumType::Xxh3 => 1,
+        }
+    }
+    // bytes of the digest at the start of the trailer
+    pub fn digest_length(&self) -> usize {
+        match *self {
+            ChecksumType::Crc32c => wire::CRC_LENGTH,
+            ChecksumType::Xxh3 => wire::WIDE_DIGEST_LENGTH,
+        }
+    }
+}
+
+// A hash of the decompressed output a decoder can keep as it writes it, for callers that check
+// the output against a digest stored elsewhere.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputDigestType {
+    Crc32c, // the same crc32c (Castagnoli) as the trailer, in the low 32 bits
+    Xxh3, // XXH3-64 with no seed and the default secret
+}
+
+// What a decoder does with the crc trailer.  Only Verify hashes the stream as it decodes;
+// the authentication tag of a STREAM_FEATURE_AUTHENTICATED stream is checked regardless.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChecksumPolicy {
+    Verify, // hash the stream and fail with BadChecksum if the stored crc differs
+    SkipButParse, // check only the trailer's framing, and keep the stored crc for expected_crc()
+    TrustCaller, // consume the trailer without looking at it
+}
+
+impl ChecksumPolicy {
+    pub fn from_skip_crc(skip_crc: bool) -> Self {
+        if skip_crc {
+            ChecksumPolicy::SkipButParse
+        } else {
+            ChecksumPolicy::Verify
+        }
+    }
+    pub fn hashes_stream(&self) -> bool {
+        *self == ChecksumPolicy::Verify
+    }
+    // whether a stored crc differing from the computed one fails the decode
+    pub fn checks_crc(&self) -> bool {
+        *self == ChecksumPolicy::Verify
+    }
+    // whether a tag after the crc other than the one expected fails the decode
+    pub fn checks_tag(&self) -> bool {
+        *self != ChecksumPolicy::TrustCaller
+    }
+    // Compares the crc, crc_len bytes of it, and the tag after it (wire::checksum_length bytes, which
+    // computed holds) of the computed and stored trailers, in time that does not depend on where they
+    // differ.  When the crc goes unchecked the stored one stands in for the computed one in the
+    // BadChecksum, so that only the tag differs there.
+    pub fn check_trailer(&self, computed: &[u8], stored: &[u8], crc_len: usize) -> DivansOpResult {
+        let checksum_len = computed.len();
+        let crc_matches = mac::tags_match(&computed[..crc_len], &stored[..crc_len]);
+        let tag_matches = mac::tags_match(&computed[crc_len..], &stored[crc_len..checksum_len]);
+        if (crc_matches || !self.checks_crc()) && (tag_matches || !self.checks_tag()) {
+            return DivansOpResult::Success;
+        }
+        let crc = if self.checks_crc() { computed } else { stored };
+        DivansOpResult::Failure(ErrMsg::BadChecksum(fold_checksum(&crc[..crc_len], &computed[crc_len..]),
+                                                    fold_checksum(&stored[..crc_len], &stored[crc_len..checksum_len])))
+    }
+}
+
+// What a decoder does with bytes that follow the trailer of the last stream, such as the padding of
+// a container that rounds its members up to a block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrailingBytesPolicy {
+    Reject, // leave them unconsumed, so decompress and the readers and writers fail with TrailingInput
+    Ignore, // consume and drop them, in the call that ends the stream and any after it
+    Count, // as Ignore, keeping a count of them for trailing_bytes()
+}
+
+// the crc, whether of 4 or 8 bytes, and the tag after it xored into the high half 4 bytes at a time
+fn fold_checksum(crc: &[u8], tag: &[u8]) -> u64 {
+    let mut folded = [0u8; wire::WIDE_DIGEST_LENGTH];
+    folded[..crc.len()].clone_from_slice(crc);
+    for (index, t) in tag.iter().enumerate() {
+        folded[wire::CRC_LENGTH + index % wire::CRC_LENGTH] ^= *t;
+    }
+    wire::u64_from_le(&folded[..])
+}
+
+// The settings a stream was encoded with, as recovered by a decoder from the header,
+// the first prediction mode command and the first literal block switch.
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodedStreamOptions {
+    pub window_size: u8,
+    pub stream_features: u8,
+    pub extended_stream_features: u8,
+    pub checksum: ChecksumType,
+    pub checksum_verified: bool, // false if the decoder was told to skip the crc
+    pub dictionary_id: Option<u32>, // None: only the built-in brotli dictionary can be referenced
+    #[cfg_attr(feature="serde", serde(with="literal_prediction_mode_serde"))]
+    pub literal_prediction_mode: LiteralPredictionModeNibble,
+    pub mixing_level: u8, // dynamic context mixing between the stride and context map models, 0 if off
+    pub context_map: bool, // whether literals are modeled with the brotli context map
+    pub literal_adaptation: [probability::Speed; 4],
+    pub stride: Option<u8>, // None until a literal block switch is decoded
+    pub mixer_warm_start: Option<MixerWarmStart>,
+    pub freeze_adaptation_after: Option<u32>,
+    pub priors_reset_interval: Option<u32>,
+    pub extended_stream_features2: u8,
+}
+
+impl Default for DecodedStreamOptions {
+    fn default() -> Self {
+        DecodedStreamOptions {
+            window_size: 0,
+            stream_features: 0,
+            extended_stream_features: 0,
+            checksum: ChecksumType::Crc32c,
+            checksum_verified: true,
+            dictionary_id: None,
+            literal_prediction_mode: LiteralPredictionModeNibble::default(),
+            mixing_level: 0,
+            context_map: false,
+            literal_adaptation: [probability::Speed::MUD; 4],
+            stride: None,
+            mixer_warm_start: None,
+            freeze_adaptation_after: None,
+            priors_reset_interval: None,
+            extended_stream_features2: 0,
         }
     }
 }
+
+// LiteralPredictionModeNibble is brotli's, so serde sees the mode as the u8 it wraps
+#[cfg(feature="serde")]
+mod literal_prediction_mode_serde {
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+    use super::{LiteralPredictionModeNibble, LITERAL_PREDICTION_MODE_SIGN};
+    fn from_u8<E: de::Error>(mode: u8) -> Result<LiteralPredictionModeNibble, E> {
+        if mode > LITERAL_PREDICTION_MODE_SIGN {
+            return Err(E::invalid_value(de::Unexpected::Unsigned(u64::from(mode)), &"a literal prediction mode of 0 to 3"));
+        }
+        Ok(LiteralPredictionModeNibble(mode))
+    }
+    pub fn serialize<S: Serializer>(mode: &LiteralPredictionModeNibble, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(mode.0)
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<LiteralPredictionModeNibble, D::Error> {
+        from_u8(u8::deserialize(deserializer)?)
+    }
+    pub mod option {
+        use super::*;
+        pub fn serialize<S: Serializer>(mode: &Option<LiteralPredictionModeNibble>, serializer: S) -> Result<S::Ok, S::Error> {
+            mode.map(|m| m.0).serialize(serializer)
+        }
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<LiteralPredictionModeNibble>, D::Error> {
+            match Option::<u8>::deserialize(deserializer)? {
+                Some(mode) => from_u8(mode).map(Some),
+                None => Ok(None),
+            }
+        }
+    }
+}
+
+// Counts of what a decoder emitted, gathered as each command is taken off the command buffer.
+// The counts are complete once decode returns Success.
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DecodeStats {
+    pub literal_commands: u64,
+    pub copy_commands: u64,
+    pub dict_commands: u64,
+    pub prediction_mode_commands: u64,
+    pub literal_block_switches: u64,
+    pub command_block_switches: u64,
+    pub distance_block_switches: u64,
+    pub literal_bytes: u64,
+    pub copy_bytes: u64,
+    pub dict_bytes: u64,
+    pub copy_distance_sum: u64,
+    pub decode_nanos: u64, // time spent inside decode calls; stays 0 without the std feature
+}
+
+impl DecodeStats {
+    pub fn total_bytes(&self) -> u64 {
+        self.literal_bytes + self.copy_bytes + self.dict_bytes
+    }
+    pub fn avg_copy_distance(&self) -> Option<f64> {
+        if self.copy_commands == 0 {
+            return None;
+        }
+        Some(self.copy_distance_sum as f64 / self.copy_commands as f64)
+    }
+    pub fn avg_copy_length(&self) -> Option<f64> {
+        if self.copy_commands == 0 {
+            return None;
+        }
+        Some(self.copy_bytes as f64 / self.copy_commands as f64)
+    }
+    // None when no decode time was recorded
+    pub fn bytes_per_ms(&self) -> Option<f64> {
+        if self.decode_nanos == 0 {
+            return None;
+        }
+        Some(self.total_bytes() as f64 * 1_000_000.0 / self.decode_nanos as f64)
+    }
+    #[cfg(feature="std")]
+    pub fn add_decode_time(&mut self, start: &std::time::Instant) {
+        let elapsed = start.elapsed();
+        self.decode_nanos = self.decode_nanos.saturating_add(
+            elapsed.as_secs().saturating_mul(1_000_000_000).saturating_add(u64::from(elapsed.subsec_nanos())));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(feature="serde")]
+    use serde_json;
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use std::vec::Vec;
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use alloc_stdlib::HeapAlloc;
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use conformance::conformance_commands;
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use probability::Speed;
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use error::ErrorKind;
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use divans_compressor::DivansCompressorFactoryStruct;
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use test_support::{new_decompressor, encode_with_options, compress_hybrid, decode};
+    #[cfg(feature="serde")]
+    #[test]
+    fn test_options_and_stats_serde() {
+        let mut opts = DivansCompressorOptions::default();
+        opts.literal_adaptation = Some([probability::Speed::MUD, probability::Speed::FAST, probability::Speed::SLOW, probability::Speed::MED]);
+        opts.force_literal_context_mode = Some(LiteralPredictionModeNibble(LITERAL_PREDICTION_MODE_UTF8));
+        opts.force_stride_value = StrideSelection::Stride4;
+        opts.distance_bucketing = DistanceBucketing::new(2, 8).unwrap();
+        opts.mixer_warm_start = Some(MixerWarmStart::new(3, 6).unwrap());
+        opts.framing_tag = wire::FramingTag::new(b"tag");
+        opts.format_version = Some(FormatVersion::Extended);
+        opts.content_hint = ContentHint::Utf16;
+        opts.checksum = ChecksumType::Xxh3;
+        let json = serde_json::to_string(&opts).unwrap();
+        assert_eq!(serde_json::from_str::<DivansCompressorOptions>(&json).unwrap(), opts);
+        // the key stays out of anything logged, and comes back unset
+        opts.authentication_key = Some([0x5a; AUTHENTICATION_KEY_LENGTH]);
+        let keyed = serde_json::to_string(&opts).unwrap();
+        assert_eq!(keyed, json);
+        assert!(!keyed.contains("authentication_key"));
+        let bad_mode = json.replace("\"force_literal_context_mode\":2", "\"force_literal_context_mode\":4");
+        assert!(bad_mode != json);
+        assert!(serde_json::from_str::<DivansCompressorOptions>(&bad_mode).is_err());
+
+        let mut decoded = DecodedStreamOptions::default();
+        decoded.literal_prediction_mode = LiteralPredictionModeNibble(LITERAL_PREDICTION_MODE_SIGN);
+        decoded.stride = Some(3);
+        let json = serde_json::to_string(&decoded).unwrap();
+        assert!(json.contains("\"literal_prediction_mode\":3"));
+        assert_eq!(serde_json::from_str::<DecodedStreamOptions>(&json).unwrap(), decoded);
+
+        let stats = DecodeStats{literal_commands: 2, copy_commands: 1, literal_bytes: 7, copy_bytes: 4,
+                                copy_distance_sum: 9, ..DecodeStats::default()};
+        let json = serde_json::to_string(&stats).unwrap();
+        assert!(json.contains("\"copy_bytes\":4"));
+        assert_eq!(serde_json::from_str::<DecodeStats>(&json).unwrap(), stats);
+    }
+
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    #[test]
+    fn test_encode_command_iter() {
+        let cmds = conformance_commands("mixed");
+        let from_slice = encode_with_options(&cmds[..], DivansCompressorOptions::default()).unwrap();
+        let mut opts = DivansCompressorOptions::default();
+        opts.window_size = Some(16);
+        let mut compressor = DivansCompressorFactoryStruct::<HeapAlloc<u8>, HeapAlloc<DefaultCDF16>>::new(
+            HeapAlloc::<u8>::new(0),
+            HeapAlloc::<u32>::new(0),
+            HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+            opts,
+            ());
+        // commands are produced on demand and a tiny buffer leaves most of them partially encoded at least once
+        let mut pulled = 0usize;
+        let mut input = (0..cmds.len()).map(|index| {
+            pulled += 1;
+            cmds[index].clone()
+        }).peekable();
+        let mut ret = Vec::<u8>::new();
+        let mut buffer = [0u8; 5];
+        loop {
+            let mut offset = 0usize;
+            let res = compressor.encode_command_iter(&mut input, &mut buffer[..], &mut offset);
+            ret.extend(&buffer[..offset]);
+            match res {
+                DivansOutputResult::Success => break,
+                DivansOutputResult::NeedsMoreOutput => {},
+                DivansOutputResult::Failure(m) => panic!("{:?}", m),
+            }
+        }
+        loop {
+            let mut offset = 0usize;
+            let res = compressor.flush(&mut buffer[..], &mut offset);
+            ret.extend(&buffer[..offset]);
+            match res {
+                DivansOutputResult::Success => break,
+                DivansOutputResult::NeedsMoreOutput => {},
+                DivansOutputResult::Failure(m) => panic!("{:?}", m),
+            }
+        }
+        compressor.free();
+        drop(input);
+        assert_eq!(pulled, cmds.len());
+        assert_eq!(ret, from_slice);
+    }
+
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    #[test]
+    fn test_options_validation() {
+        assert!(DivansCompressorOptions::default().validate().is_ok());
+        let mut opts = DivansCompressorOptions::default();
+        opts.force_stride_value = StrideSelection::Stride8;
+        opts.prior_depth = Some(0);
+        match opts.validate() {
+            Err(ErrMsg::ConflictingOptions(CompressorOption::ForceStrideValue, CompressorOption::PriorDepth)) => {},
+            res => panic!("{:?}", res),
+        }
+        opts.prior_depth = Some(3);
+        assert!(opts.validate().is_ok());
+        opts.dynamic_context_mixing = Some(0);
+        match opts.validate() {
+            Err(ErrMsg::ConflictingOptions(CompressorOption::DynamicContextMixing, CompressorOption::ForceStrideValue)) => {},
+            res => panic!("{:?}", res),
+        }
+        opts.use_context_map = false;
+        assert!(opts.validate().is_ok());
+
+        let mut opts = DivansCompressorOptions::default();
+        opts.window_size = Some(30);
+        match opts.validate() {
+            Err(ErrMsg::OptionOutOfRange(CompressorOption::WindowSize)) => {},
+            res => panic!("{:?}", res),
+        }
+        let mut opts = DivansCompressorOptions::default();
+        opts.literal_adaptation = Some([Speed::ENCODER_DEFAULT_PALETTE[3]; 4]);
+        assert!(opts.validate().is_ok());
+        // 0x0109 has no exact f8 encoding, and an increment past the limit rescales on every symbol
+        for speed in [Speed::new(0x0109, 0x2000), Speed::new(0x0200, 0x0100)].iter() {
+            opts.literal_adaptation = Some([Speed::MUD, Speed::MUD, *speed, Speed::MUD]);
+            match opts.validate() {
+                Err(ErrMsg::OptionOutOfRange(CompressorOption::LiteralAdaptation)) => {},
+                res => panic!("{:?}: {:?}", speed, res),
+            }
+        }
+    }
+
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    #[test]
+    fn test_options_and_results_compare() {
+        let mut opts = DivansCompressorOptions::default();
+        opts.force_stride_value = StrideSelection::Stride4;
+        let copy = opts;
+        assert_eq!(copy, opts);
+        opts.literal_adaptation = Some([Speed::MUD; 4]);
+        assert!(copy != opts);
+        assert_eq!(opts.validate(), Ok(()));
+        opts.window_size = Some(30);
+        assert_eq!(opts.validate(), Err(ErrMsg::OptionOutOfRange(CompressorOption::WindowSize)));
+        assert_eq!(format!("{}", ErrMsg::BadChecksum(1, 2)), "BadChecksum(1, 2)");
+        assert_eq!(DivansResult::from(DivansOutputResult::NeedsMoreOutput), DivansResult::NeedsMoreOutput);
+    }
+
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    #[test]
+    fn test_encode_decode_to_vec() {
+        let mut seed = 0x2545f491u32;
+        let mut input = Vec::<u8>::new();
+        for i in 0..300000u32 {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            input.push(if i & 0xf000 == 0 { (seed >> 24) as u8 } else { b"divans "[(i % 7) as usize] });
+        }
+        let mut compressor = DivansCompressorFactoryStruct::<HeapAlloc<u8>, HeapAlloc<DefaultCDF16>>::new(
+            HeapAlloc::<u8>::new(0),
+            HeapAlloc::<u32>::new(0),
+            HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+            DivansCompressorOptions::default(),
+            ());
+        // output already there is kept
+        let mut stream = b"prefix".to_vec();
+        let mut input_offset = 0usize;
+        assert_eq!(compressor.encode_to_vec(&input[..], &mut input_offset, &mut stream), DivansResult::NeedsMoreInput);
+        assert_eq!(input_offset, input.len());
+        assert_eq!(compressor.flush_to_vec(&mut stream), DivansOutputResult::Success);
+        compressor.free();
+        assert_eq!(&stream[..6], b"prefix");
+        assert!(stream.len() - 6 <= ::max_compressed_size(input.len()));
+        assert_eq!(decode(&stream[6..]).unwrap(), input);
+        let mut decompressor = new_decompressor(false, false);
+        // the reservation made for the compressed size falls short of the input, so this grows it
+        let mut output = Vec::<u8>::new();
+        let mut stream_offset = 6usize;
+        assert_eq!(decompressor.decode_to_vec(&stream[..], &mut stream_offset, &mut output), DivansResult::Success);
+        decompressor.free();
+        assert_eq!(stream_offset, stream.len());
+        assert_eq!(output, input);
+    }
+
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    #[test]
+    fn test_coder_id() {
+        let input = &include_bytes!("../testdata/alice29")[..4096];
+        let stream = compress_hybrid(input, DivansCompressorOptions::default());
+        assert_eq!(stream[HEADER_CODER_ID_OFFSET], CODER_ID);
+        assert_eq!(decode(&stream[..]).unwrap(), input);
+        // a stream coded at another precision is refused rather than decoded to garbage
+        let mut other = stream.clone();
+        other[HEADER_CODER_ID_OFFSET] ^= 1;
+        assert_eq!(decode(&other[..]), Err(ErrMsg::UnsupportedCoder(CODER_ID ^ 1)));
+        assert_eq!(ErrorKind::of(ErrMsg::UnsupportedCoder(CODER_ID ^ 1)), ErrorKind::Unsupported);
+    }
+}
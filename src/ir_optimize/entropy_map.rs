@@ -0,0 +1,266 @@
+// Where a stream spends its bits: the commands of a divans stream are priced one at a time by an
+// actuary, the tallying codec the IR optimizer weighs its merges with, and each command's bits are
+// spread evenly over the bytes it produces.  Commands producing no bytes (prediction modes, block
+// switches) are charged to the byte after them; any after the last byte are dropped.
+// The map sums to roughly the size of the stream less its header and checksum.
+use core;
+use codec;
+use alloc::{Allocator, SliceWrapper};
+use codec::io::DemuxerAndRingBuffer;
+use mux::DevNull;
+use interface::{Command, DefaultCDF16, DivansCompressorOptions, DivansResult, ErrMsg, STREAM_FEATURE_DISTANCE_BUCKETING,
+                EXTENDED_FEATURE_FREEZE_ADAPTATION};
+#[cfg(all(feature="std", feature="decoder"))]
+use interface::{DecodedStreamOptions, Nop};
+#[cfg(all(feature="std", feature="decoder"))]
+use ::StaticCommand;
+#[cfg(all(feature="std", feature="decoder"))]
+use std::vec::Vec;
+#[cfg(all(feature="std", feature="decoder"))]
+use alloc_stdlib::HeapAlloc;
+#[cfg(all(feature="std", feature="decoder"))]
+use error::Error;
+use super::statistics_tracking_codec::{Actuary, TallyingArithmeticEncoder, ToggleProbabilityBlend, total_billing_cost, clear_billing_cost};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EntropyRegion {
+    pub start: u64, // uncompressed offset of the first byte of the region
+    pub end: u64, // one past its last byte
+    pub bits: f64,
+}
+
+impl EntropyRegion {
+    pub fn bits_per_byte(&self) -> f64 {
+        if self.end == self.start {
+            return 0.0;
+        }
+        self.bits / (self.end - self.start) as f64
+    }
+}
+
+fn command_len<SliceType:SliceWrapper<u8>>(cmd: &Command<SliceType>) -> u64 {
+    match *cmd {
+        Command::Literal(ref lit) => lit.data.slice().len() as u64,
+        Command::Copy(ref copy) => u64::from(copy.num_bytes),
+        Command::Dict(ref dict) => u64::from(dict.final_size),
+        _ => 0,
+    }
+}
+
+pub struct EntropyMapper<AllocU8:Allocator<u8>, AllocCDF16:Allocator<DefaultCDF16>> {
+    actuary: Actuary<ToggleProbabilityBlend, DefaultCDF16, AllocU8, AllocCDF16>,
+    region_size: u64,
+    position: u64,
+    region: EntropyRegion, // the region position falls in, not yet emitted
+}
+
+impl<AllocU8:Allocator<u8>, AllocCDF16:Allocator<DefaultCDF16>> EntropyMapper<AllocU8, AllocCDF16> {
+    // prices commands as an encoder given opts would code them, in regions of region_size bytes
+    pub fn new(m8: AllocU8, mcdf16: AllocCDF16, opts: &DivansCompressorOptions, region_size: u64) -> Self {
+        let window_size = opts.window_size.unwrap_or(22).clamp(10, 24);
+        let mut actuary = Actuary::<ToggleProbabilityBlend, DefaultCDF16, AllocU8, AllocCDF16>::new(m8,
+                                                                                                    mcdf16,
+                                                                                                    TallyingArithmeticEncoder::default(),
+                                                                                                    TallyingArithmeticEncoder::default(),
+                                                                                                    ToggleProbabilityBlend::default(),
+                                                                                                    DemuxerAndRingBuffer::<AllocU8, DevNull<AllocU8>>::default(),
+                                                                                                    window_size as usize,
+                                                                                                    opts.dynamic_context_mixing.unwrap_or(0),
+                                                                                                    opts.prior_depth,
+                                                                                                    opts.literal_adaptation,
+                                                                                                    opts.use_context_map,
+                                                                                                    opts.force_stride_value,
+                                                                                                    false);
+        if opts.distance_bucketing.is_identity() {
+            actuary.set_stream_features(opts.stream_features);
+        } else {
+            actuary.set_stream_features(opts.stream_features | STREAM_FEATURE_DISTANCE_BUCKETING);
+            actuary.set_distance_bucketing(opts.distance_bucketing);
+        }
+        actuary.set_mixer_warm_start(opts.mixer_warm_start);
+        actuary.set_compact_priors(opts.compact_priors);
+        if opts.freeze_adaptation_after.is_some() {
+            actuary.set_extended_stream_features(opts.extended_stream_features | EXTENDED_FEATURE_FREEZE_ADAPTATION);
+        } else {
+            actuary.set_extended_stream_features(opts.extended_stream_features);
+        }
+        actuary.set_freeze_adaptation_after(opts.freeze_adaptation_after);
+        actuary.set_priors_reset_interval(opts.priors_reset_interval);
+        actuary.set_extended_stream_features2(opts.extended_stream_features2);
+        EntropyMapper {
+            actuary,
+            region_size: core::cmp::max(1, region_size),
+            position: 0,
+            region: EntropyRegion::default(),
+        }
+    }
+    // prices cmd, handing each region it completes to emit
+    pub fn push<SliceType:SliceWrapper<u8>, Emit:FnMut(EntropyRegion)>(&mut self,
+                                                                       cmd: &Command<SliceType>,
+                                                                       mut emit: Emit) -> Result<(), ErrMsg> {
+        let mut cmd_offset = 0usize;
+        let mut unused = 0usize;
+        let mut unused2 = 0usize;
+        match self.actuary.encode_or_decode(&[], &mut unused, &mut[], &mut unused2,
+                                            &codec::CommandSliceArray(core::slice::from_ref(cmd)), &mut cmd_offset) {
+            DivansResult::NeedsMoreOutput => return Err(ErrMsg::DrainOrFillNeedsInput(4)),
+            DivansResult::Failure(e) => return Err(e),
+            DivansResult::NeedsMoreInput | DivansResult::Success => if cmd_offset != 1 {
+                return Err(ErrMsg::DrainOrFillNeedsInput(5));
+            },
+        }
+        let bits = f64::from(total_billing_cost(&self.actuary));
+        clear_billing_cost(&mut self.actuary);
+        let mut remaining = command_len(cmd);
+        if remaining == 0 {
+            self.region.bits += bits;
+            return Ok(());
+        }
+        let bits_per_byte = bits / remaining as f64;
+        while remaining != 0 {
+            let region_end = (self.position / self.region_size + 1) * self.region_size;
+            let step = core::cmp::min(remaining, region_end - self.position);
+            self.region.bits += bits_per_byte * step as f64;
+            self.position += step;
+            remaining -= step;
+            if self.position == region_end {
+                self.region.end = region_end;
+                emit(self.region);
+                self.region = EntropyRegion {
+                    start: region_end,
+                    end: region_end,
+                    bits: 0.0,
+                };
+            }
+        }
+        Ok(())
+    }
+    // hands the last, partial region to emit and returns the allocators
+    pub fn finish<Emit:FnMut(EntropyRegion)>(mut self, mut emit: Emit) -> (AllocU8, AllocCDF16) {
+        if self.position != self.region.start {
+            self.region.end = self.position;
+            emit(self.region);
+        }
+        self.actuary.free()
+    }
+}
+
+// how an encoder would have been set up to write a stream with these settings
+#[cfg(all(feature="std", feature="decoder"))]
+fn stream_compressor_options(stream: &DecodedStreamOptions) -> DivansCompressorOptions {
+    DivansCompressorOptions {
+        window_size: Some(i32::from(stream.window_size)),
+        stream_features: stream.stream_features,
+        extended_stream_features: stream.extended_stream_features,
+        dynamic_context_mixing: Some(stream.mixing_level),
+        use_context_map: stream.context_map,
+        literal_adaptation: Some(stream.literal_adaptation),
+        mixer_warm_start: stream.mixer_warm_start,
+        freeze_adaptation_after: stream.freeze_adaptation_after,
+        priors_reset_interval: stream.priors_reset_interval,
+        extended_stream_features2: stream.extended_stream_features2,
+        ..DivansCompressorOptions::default()
+    }
+}
+
+// Maps the bits of a compressed stream over the bytes it decodes to, region_size bytes a region.
+// The commands are priced with opts when given, so another preset can be tried on the same parse,
+// or else with the settings the stream was written with.
+#[cfg(all(feature="std", feature="decoder"))]
+pub fn entropy_map(compressed: &[u8],
+                   opts: Option<DivansCompressorOptions>,
+                   region_size: u64) -> Result<Vec<EntropyRegion>, Error> {
+    use decoded_command_iter::DecodedCommandIter;
+    use divans_decompressor::{DivansDecompressorFactory, DivansDecompressorFactoryStruct};
+    let decompressor = DivansDecompressorFactoryStruct::<HeapAlloc<u8>,
+                                                         HeapAlloc<DefaultCDF16>,
+                                                         HeapAlloc<StaticCommand>>::new(
+        HeapAlloc::<u8>::new(0),
+        HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+        HeapAlloc::<StaticCommand>::new(StaticCommand::nop()),
+        false,
+        false);
+    let mut remaining = compressed;
+    let mut input_buffer = [0u8; 4096];
+    let mut iter = DecodedCommandIter::new(decompressor, &mut input_buffer[..], |buf: &mut [u8]| {
+        let len = core::cmp::min(buf.len(), remaining.len());
+        buf[..len].clone_from_slice(&remaining[..len]);
+        remaining = &remaining[len..];
+        len
+    });
+    let mut regions = Vec::<EntropyRegion>::new();
+    let mut mapper: Option<EntropyMapper<HeapAlloc<u8>, HeapAlloc<DefaultCDF16>>> = None;
+    let mut result = Ok(());
+    while let Some(cmd) = iter.next() {
+        let cmd = match cmd {
+            Ok(cmd) => cmd,
+            Err(m) => {
+                result = Err(m);
+                break;
+            },
+        };
+        if mapper.is_none() {
+            // the stream settings are known once its first prediction mode command is decoded
+            let mapper_opts = match opts {
+                Some(opts) => opts,
+                None => stream_compressor_options(&iter.decompressor().options().unwrap_or_default()),
+            };
+            mapper = Some(EntropyMapper::new(HeapAlloc::<u8>::new(0),
+                                             HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+                                             &mapper_opts,
+                                             region_size));
+        }
+        if let Some(ref mut mapper) = mapper {
+            result = mapper.push(&cmd, |region| regions.push(region));
+        }
+        iter.free_command(cmd);
+        if result.is_err() {
+            break;
+        }
+    }
+    iter.into_inner().free();
+    if let Some(mapper) = mapper {
+        mapper.finish(|region| regions.push(region));
+    }
+    let output_len = regions.last().map(|region| region.end).unwrap_or(0);
+    result.map(|_| regions).map_err(|m| Error::from(m).in_state("mapping entropy").at((compressed.len() - remaining.len()) as u64, output_len))
+}
+
+#[cfg(all(test, feature="std", feature="decoder"))]
+mod test {
+    use interface::{
+        DivansCompressorOptions,
+        ErrMsg,
+    };
+    use test_support::compress_hybrid;
+
+    #[test]
+    fn test_entropy_map() {
+        use ::synthetic::Synthetic;
+        use ir_optimize::entropy_map;
+        let mut input = include_bytes!("../../testdata/alice29")[..65536].to_vec();
+        input.extend(Synthetic::Random.generate(3, 32768));
+        let stream = compress_hybrid(&input[..], DivansCompressorOptions::default());
+        let regions = entropy_map(&stream[..], None, 4096).unwrap();
+        assert_eq!(regions.len(), 24);
+        for (index, region) in regions.iter().enumerate() {
+            assert_eq!(region.start, index as u64 * 4096);
+            assert_eq!(region.end, region.start + 4096);
+        }
+        let bits: f64 = regions.iter().map(|region| region.bits).sum();
+        assert!((bits / 8.0 - stream.len() as f64).abs() < stream.len() as f64 * 0.02, "{} vs {}", bits / 8.0, stream.len());
+        assert!(regions[4..16].iter().all(|region| region.bits_per_byte() < 4.0), "{:?}", regions);
+        assert!(regions[16..].iter().all(|region| region.bits_per_byte() > 7.5), "{:?}", regions);
+        // the same commands priced without the context map or mixing
+        let mut opts = DivansCompressorOptions::default();
+        opts.use_context_map = false;
+        opts.dynamic_context_mixing = Some(0);
+        let repriced = entropy_map(&stream[..], Some(opts), 4096).unwrap();
+        assert_eq!(repriced.len(), regions.len());
+        assert!(repriced.iter().map(|region| region.bits).sum::<f64>() != bits);
+        match entropy_map(&stream[..stream.len() / 2], None, 4096) {
+            Err(e) => assert_eq!(e.code(), Some(ErrMsg::TruncatedInput)),
+            Ok(_) => panic!("a truncated stream mapped"),
+        }
+    }
+}
@@ -11,6 +11,10 @@ use alloc::{SliceWrapper, Allocator};
 pub use super::interface::{ArithmeticEncoderOrDecoder, NewWithAllocator, DivansResult, ErrMsg};
 mod statistics_tracking_codec;
 mod cache;
+mod entropy_map;
+pub use self::entropy_map::{EntropyMapper, EntropyRegion};
+#[cfg(all(feature="std", feature="decoder"))]
+pub use self::entropy_map::entropy_map;
 use self::statistics_tracking_codec::{TallyingArithmeticEncoder, OneCommandThawingArray, TwoCommandThawingArray, ToggleProbabilityBlend,
                                       take_billing_snapshot, billing_snapshot_delta,reset_billing_snapshot};
 pub fn should_merge<SelectedCDF:CDF16,
@@ -31,7 +35,7 @@ pub fn should_merge<SelectedCDF:CDF16,
     if lit.data.0 + lit.data.1 as usize + copy.num_bytes as usize > mb.0.orig_offset + mb.0.len() as usize && lit.data.0 < mb.0.orig_offset + mb.0.len() as usize {
         return Ok(false); // can't merge: would wrap the metablock
     }
-    let codec_snapshot = actuary.cross_command_state.snapshot_literal_or_copy_state();
+    let codec_snapshot = actuary.cross_command_state.begin_speculation();
     actuary.cross_command_state.specialization.will_it_blend = false;
 
     // lets see if the copy would hit the distance_lru cache
@@ -103,6 +107,7 @@ pub fn should_merge<SelectedCDF:CDF16,
     let cur_cost = billing_snapshot_delta(actuary);
     actuary.cross_command_state.specialization.will_it_blend = true;
     actuary.cross_command_state.restore_literal_or_copy_snapshot(codec_snapshot);
+    actuary.cross_command_state.end_speculation();
     reset_billing_snapshot(actuary);
     /*let full_cost = total_billing_cost(actuary);
     eprintln!("{}) At {} bits: Checking cost of Copy of d:{} l:{} (cur hit: {} future hit: {}) = {} vs combined longer literal at {}\n",
@@ -161,7 +166,7 @@ pub fn ir_optimize<'a, SelectedCDF:CDF16,
                                                             opt.force_stride_value,
                                                             false);
     {
-        let immutable_pm = Command::PredictionMode(PredictionModeContextMap::<brotli::InputReference>{
+        let immutable_pm = super::interface::Command::PredictionMode(PredictionModeContextMap::<brotli::InputReference>{
             literal_context_map:brotli::InputReference::from(&pm.literal_context_map),
             predmode_speed_and_distance_context_map:brotli::InputReference::from(&pm.predmode_speed_and_distance_context_map),
         });
@@ -1,5 +1,5 @@
 pub use ::interface::{ArithmeticEncoderOrDecoder, NewWithAllocator, DivansResult, ReadableBytes, WritableBytes};
-use probability::{ProbRange, CDF16, LOG2_SCALE};
+use probability::{ProbRange, CDF16, LOG2_SCALE, ExternalProb};
 use alloc::{SliceWrapper, Allocator};
 use brotli;
 use codec::CommandArray;
@@ -9,7 +9,7 @@ use codec::{EncoderOrDecoderSpecialization};
 use mux::DevNull;
 use codec::io::DemuxerAndRingBuffer;
 use cmd_to_divans::EncoderSpecialization;
-use brotli::interface::{Command, CopyCommand, LiteralCommand, DictCommand};
+use interface::{Command, CopyCommand, LiteralCommand, DictCommand, thaw_pair};
 
 
 #[allow(non_camel_case_types)]
@@ -36,6 +36,9 @@ impl TallyingArithmeticEncoder {
     pub fn total_cost(&self) ->floatY {
         self.cost
     }
+    pub fn clear(&mut self) {
+        *self = TallyingArithmeticEncoder::default();
+    }
     pub fn tally(&mut self, data: ProbRange) {
         self.cost += LOG2_SCALE as floatY - FastLog2u16(data.freq as u16) as floatY;
     }
@@ -67,20 +70,21 @@ impl ArithmeticEncoderOrDecoder for TallyingArithmeticEncoder {
     fn get_or_put_bit_without_billing(&mut self,
                                       bit: &mut bool,
                                       prob_of_false: u8) {
-        let prob = 
-            if *bit {
-                i16::from(prob_of_false)
-            } else {
-                255 - i16::from(prob_of_false)
-            };
+        // the ranges the ANS encoder codes the bit with
+        let prob_of_false = i16::from(core::cmp::max(prob_of_false, 1));
         let start = if *bit {
-            i16::from(prob_of_false)
+            prob_of_false
         } else {
             0
         };
+        let freq = if *bit {
+            256 - prob_of_false
+        } else {
+            prob_of_false
+        };
         self.tally(ProbRange{
-            start: (start << 7) -1,
-            freq: (prob << 7) - 1,
+            start: start << 7,
+            freq: freq << 7,
         });
     }
     #[inline(always)]
@@ -148,7 +152,6 @@ pub fn billing_snapshot_delta<SelectedCDF:CDF16,
     }
 }
 
-#[allow(dead_code)]
 pub fn total_billing_cost<SelectedCDF:CDF16,
                           AllocU8:Allocator<u8>,
                           AllocCDF16:Allocator<SelectedCDF>,
@@ -167,12 +170,33 @@ pub fn total_billing_cost<SelectedCDF:CDF16,
     }
 }
 
+// a codec that only tallies what its commands would cost
+pub type Actuary<Spc, SelectedCDF, AllocU8, AllocCDF16> = codec::DivansCodec<TallyingArithmeticEncoder,
+                                                                            Spc,
+                                                                            DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>,
+                                                                            DevNull<AllocU8>,
+                                                                            SelectedCDF,
+                                                                            AllocU8,
+                                                                            AllocCDF16>;
+
+// zeroes the tally, so long runs of small costs do not drown in the float precision of a large total
+pub fn clear_billing_cost<SelectedCDF:CDF16,
+                          AllocU8:Allocator<u8>,
+                          AllocCDF16:Allocator<SelectedCDF>,
+                          Spc: EncoderOrDecoderSpecialization,
+                          >(codec:&mut Actuary<Spc, SelectedCDF, AllocU8, AllocCDF16>) {
+    match codec.cross_command_state.thread_ctx {
+        codec::ThreadContext::Worker => {},
+        codec::ThreadContext::MainThread(ref mut ctx) => ctx.lit_coder.clear(),
+    }
+    codec.cross_command_state.coder.clear()
+}
 
 pub struct OneCommandThawingArray<'a>(pub &'a brotli::interface::Command<brotli::SliceOffset>, pub &'a brotli::InputPair<'a>);
 
 impl<'a> CommandArray for OneCommandThawingArray<'a> {
-    fn get_input_command(&self, _offset:usize) -> brotli::interface::Command<brotli::InputReference> {
-        brotli::thaw_pair(self.0, self.1)
+    fn get_input_command(&self, _offset:usize) -> Command<brotli::InputReference> {
+        thaw_pair(self.0, self.1)
     }
     fn len(&self) -> usize {
         1
@@ -182,8 +206,8 @@ impl<'a> CommandArray for OneCommandThawingArray<'a> {
 pub struct TwoCommandThawingArray<'a>(pub [&'a brotli::interface::Command<brotli::SliceOffset>;2], pub &'a brotli::InputPair<'a>);
 
 impl<'a> CommandArray for TwoCommandThawingArray<'a> {
-    fn get_input_command(&self, offset:usize) -> brotli::interface::Command<brotli::InputReference> {
-        brotli::thaw_pair(self.0[offset], self.1)
+    fn get_input_command(&self, offset:usize) -> Command<brotli::InputReference> {
+        thaw_pair(self.0[offset], self.1)
     }
     fn len(&self) -> usize {
         self.0.len()
@@ -249,6 +273,11 @@ impl EncoderOrDecoderSpecialization for ToggleProbabilityBlend {
                         index: usize) -> u8 {
         self.enc.get_literal_byte(in_cmd, index)
     }
+    fn get_external_prob<ISlice:SliceWrapper<u8>>(&self,
+                                                  in_cmd: &LiteralCommand<ISlice>,
+                                                  nibble_index: u32) -> Option<ExternalProb> {
+        self.enc.get_external_prob(in_cmd, nibble_index)
+    }
     fn get_recoder_output<'a>(&'a mut self,
                               passed_in_output_bytes: &'a mut [u8]) -> &'a mut[u8] {
         self.enc.get_recoder_output(passed_in_output_bytes)
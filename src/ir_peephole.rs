@@ -0,0 +1,278 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// A peephole pass for IR from frontends that emit each command without looking at its neighbours,
+// run on the commands before they are handed to Compressor::encode_commands.  Within each buffer it
+//   merges a copy into the copy before it when it has the same distance, which continues it,
+//   merges adjacent literals,
+//   and turns copies of up to MAX_TINY_COPY bytes into literals when those look cheaper.
+// The cost of a literal is its order 0 cost under the literals seen so far and the cost of a copy
+// an estimate of its command, length and distance; neither is the codec's, but both follow the
+// input.  The output is the same bytes either way.
+// Divans block switches are commands of their own, so no copy crosses one and none is split.
+use core;
+use alloc::{Allocator, SliceWrapper, SliceWrapperMut};
+use brotli::enc::util::{floatX, FastLog2, FastLog2u16};
+use interface::{Command, CopyCommand, LiteralCommand, FeatureFlagSliceType, Nop};
+
+// copies of at most this many bytes are candidates for literals
+pub const MAX_TINY_COPY: u32 = 3;
+// bytes of output kept to read tiny copies from
+const LOG_PEEPHOLE_WINDOW: usize = 16;
+const PEEPHOLE_WINDOW: usize = 1 << LOG_PEEPHOLE_WINDOW;
+// estimated bits of a copy command and its length, and of a distance reused from the last few
+const COPY_COMMAND_BITS: floatX = 6.0;
+const COPY_RECENT_DISTANCE_BITS: floatX = 2.0;
+const COPY_NEW_DISTANCE_EXTRA_BITS: floatX = 4.0;
+const LITERAL_COUNT_LIMIT: u16 = 0x4000;
+
+pub struct IrPeephole<AllocU8:Allocator<u8>> {
+    history: AllocU8::AllocatedMemory,
+    history_pos: usize, // bytes of output so far, modulo nothing
+    history_known: usize, // trailing bytes of history that match the output; dictionary words break it
+    recent_distances: [u32; 4],
+    literal_counts: [u16; 256],
+    literal_total: u16,
+}
+
+impl<AllocU8:Allocator<u8>> IrPeephole<AllocU8> {
+    pub fn new(m8: &mut AllocU8) -> Self {
+        IrPeephole {
+            history: m8.alloc_cell(PEEPHOLE_WINDOW),
+            history_pos: 0,
+            history_known: 0,
+            recent_distances: [0; 4],
+            literal_counts: [1; 256],
+            literal_total: 256,
+        }
+    }
+    pub fn free(&mut self, m8: &mut AllocU8) {
+        m8.free_cell(core::mem::take(&mut self.history));
+    }
+    fn push_byte(&mut self, byte: u8) {
+        self.history.slice_mut()[self.history_pos & (PEEPHOLE_WINDOW - 1)] = byte;
+        self.history_pos = self.history_pos.wrapping_add(1);
+        self.history_known = core::cmp::min(self.history_known + 1, PEEPHOLE_WINDOW);
+    }
+    fn count_literal(&mut self, byte: u8) {
+        if self.literal_total >= LITERAL_COUNT_LIMIT {
+            self.literal_total = 0;
+            for count in self.literal_counts.iter_mut() {
+                *count = (*count + 1) >> 1;
+                self.literal_total += *count;
+            }
+        }
+        self.literal_counts[byte as usize] += 1;
+        self.literal_total += 1;
+    }
+    // replays a command into the history and the statistics the costs come from
+    fn observe<SliceType:SliceWrapper<u8>>(&mut self, cmd: &Command<SliceType>) {
+        match *cmd {
+            Command::Literal(ref lit) => {
+                for byte in lit.data.slice().iter() {
+                    self.count_literal(*byte);
+                    self.push_byte(*byte);
+                }
+            },
+            Command::Copy(ref copy) => {
+                let distance = copy.distance as usize;
+                for _ in 0..copy.num_bytes {
+                    if distance == 0 || distance > self.history_known {
+                        self.history_known = 0; // reaches back past what is kept
+                        self.history_pos = self.history_pos.wrapping_add(1);
+                    } else {
+                        let byte = self.history.slice()[self.history_pos.wrapping_sub(distance) & (PEEPHOLE_WINDOW - 1)];
+                        self.push_byte(byte);
+                    }
+                }
+                if copy.num_bytes != 0 && copy.distance != self.recent_distances[0] {
+                    self.recent_distances = [copy.distance, self.recent_distances[0],
+                                             self.recent_distances[1], self.recent_distances[2]];
+                }
+            },
+            Command::Dict(ref dict) => {
+                self.history_known = 0;
+                self.history_pos = self.history_pos.wrapping_add(usize::from(dict.final_size));
+            },
+            _ => {},
+        }
+    }
+    fn copy_cost(&self, copy: &CopyCommand) -> floatX {
+        if self.recent_distances.contains(&copy.distance) {
+            COPY_COMMAND_BITS + COPY_RECENT_DISTANCE_BITS
+        } else {
+            COPY_COMMAND_BITS + COPY_NEW_DISTANCE_EXTRA_BITS + FastLog2(u64::from(copy.distance))
+        }
+    }
+    // the bytes copy would produce, when they are all in the history and look cheaper as literals
+    fn tiny_copy_bytes(&self, copy: &CopyCommand, bytes: &mut [u8; MAX_TINY_COPY as usize]) -> bool {
+        let distance = copy.distance as usize;
+        if copy.num_bytes == 0 || copy.num_bytes > MAX_TINY_COPY || distance == 0 || distance > self.history_known {
+            return false;
+        }
+        let mut literal_cost = 0.0 as floatX;
+        for index in 0..copy.num_bytes as usize {
+            // a copy shorter than its distance reads history only; a longer one also its own output
+            let byte = if index < distance {
+                self.history.slice()[self.history_pos.wrapping_sub(distance - index) & (PEEPHOLE_WINDOW - 1)]
+            } else {
+                bytes[index - distance]
+            };
+            bytes[index] = byte;
+            literal_cost += FastLog2u16(self.literal_total) - FastLog2u16(self.literal_counts[byte as usize]);
+        }
+        literal_cost < self.copy_cost(copy)
+    }
+    // Rewrites cmds, returning how many of them are left at the front; the rest are nops.  Literal
+    // data replaced by merged literals is freed to m8, which the new data comes from.
+    pub fn optimize(&mut self, m8: &mut AllocU8, cmds: &mut [Command<AllocU8::AllocatedMemory>]) -> usize {
+        let mut out = 0usize;
+        for index in 0..cmds.len() {
+            let mut cmd = core::mem::replace(&mut cmds[index], Command::<AllocU8::AllocatedMemory>::nop());
+            if let Command::Copy(copy) = cmd {
+                let mut bytes = [0u8; MAX_TINY_COPY as usize];
+                if self.tiny_copy_bytes(&copy, &mut bytes) {
+                    let mut data = m8.alloc_cell(copy.num_bytes as usize);
+                    data.slice_mut().clone_from_slice(&bytes[..copy.num_bytes as usize]);
+                    cmd = Command::Literal(LiteralCommand::<AllocU8::AllocatedMemory>{
+                        data,
+                        prob: FeatureFlagSliceType::<AllocU8::AllocatedMemory>::default(),
+                        high_entropy: false,
+                    });
+                }
+            }
+            self.observe(&cmd);
+            if out != 0 && merge(m8, &mut cmds[out - 1], &mut cmd) {
+                continue;
+            }
+            cmds[out] = cmd;
+            out += 1;
+        }
+        out
+    }
+}
+
+// folds next into prev where the two are one command, freeing what next held
+fn merge<AllocU8:Allocator<u8>>(m8: &mut AllocU8,
+                                prev: &mut Command<AllocU8::AllocatedMemory>,
+                                next: &mut Command<AllocU8::AllocatedMemory>) -> bool {
+    match (prev, next) {
+        (&mut Command::Copy(ref mut prev_copy), &mut Command::Copy(ref next_copy)) => {
+            if prev_copy.distance != next_copy.distance {
+                return false;
+            }
+            match prev_copy.num_bytes.checked_add(next_copy.num_bytes) {
+                Some(num_bytes) => {
+                    prev_copy.num_bytes = num_bytes;
+                    true
+                },
+                None => false,
+            }
+        },
+        (&mut Command::Literal(ref mut prev_lit), &mut Command::Literal(ref mut next_lit)) => {
+            // literals with external probabilities keep theirs
+            if !prev_lit.prob.slice().is_empty() || !next_lit.prob.slice().is_empty() {
+                return false;
+            }
+            let prev_len = prev_lit.data.slice().len();
+            let mut data = m8.alloc_cell(prev_len + next_lit.data.slice().len());
+            data.slice_mut()[..prev_len].clone_from_slice(prev_lit.data.slice());
+            data.slice_mut()[prev_len..].clone_from_slice(next_lit.data.slice());
+            m8.free_cell(core::mem::replace(&mut prev_lit.data, data));
+            m8.free_cell(core::mem::take(&mut next_lit.data));
+            prev_lit.high_entropy = prev_lit.high_entropy && next_lit.high_entropy;
+            true
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature="std")]
+mod test {
+    extern crate std;
+    use std::vec::Vec;
+    use core;
+    use alloc::{Allocator, SliceWrapper, SliceWrapperMut};
+    use alloc_stdlib::HeapAlloc;
+    use interface::{Command, CopyCommand, LiteralCommand, FeatureFlagSliceType};
+    use super::IrPeephole;
+
+    fn literal(m8: &mut HeapAlloc<u8>, data: &[u8]) -> Command<<HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory> {
+        let mut cell = m8.alloc_cell(data.len());
+        cell.slice_mut().clone_from_slice(data);
+        Command::Literal(LiteralCommand{
+            data: cell,
+            prob: FeatureFlagSliceType::default(),
+            high_entropy: false,
+        })
+    }
+
+    fn copy(distance: u32, num_bytes: u32) -> Command<<HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory> {
+        Command::Copy(CopyCommand{distance: distance, num_bytes: num_bytes})
+    }
+
+    fn replay<SliceType:SliceWrapper<u8>>(cmds: &[Command<SliceType>]) -> Vec<u8> {
+        let mut out = Vec::<u8>::new();
+        for cmd in cmds.iter() {
+            match *cmd {
+                Command::Literal(ref lit) => out.extend(lit.data.slice()),
+                Command::Copy(ref copy) => for _ in 0..copy.num_bytes {
+                    let byte = out[out.len() - copy.distance as usize];
+                    out.push(byte);
+                },
+                _ => panic!("unexpected command"),
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_peephole_merges_and_inlines() {
+        let mut m8 = HeapAlloc::<u8>::new(0);
+        let mut peephole = IrPeephole::new(&mut m8);
+        let text = &include_bytes!("../testdata/alice29")[..4096];
+        let mut cmds = [literal(&mut m8, text),
+                        literal(&mut m8, b"ab"),
+                        copy(1000, 2), // a tiny copy from far back reads as two literals
+                        literal(&mut m8, b"cd"),
+                        copy(4096, 40),
+                        copy(4096, 24), // continues the copy before it
+                        copy(7, 9)];
+        let expected = replay(&cmds[..]);
+        let len = peephole.optimize(&mut m8, &mut cmds[..]);
+        assert_eq!(replay(&cmds[..len]), expected);
+        assert_eq!(len, 3);
+        match cmds[1] {
+            Command::Copy(ref c) => assert_eq!((c.distance, c.num_bytes), (4096, 64)),
+            _ => panic!("expected the merged copy"),
+        }
+        match cmds[0] {
+            Command::Literal(ref lit) => assert_eq!(lit.data.slice().len(), 4096 + 6),
+            _ => panic!("expected the merged literal"),
+        }
+        for cmd in cmds[len..].iter() {
+            match *cmd {
+                Command::Copy(ref c) => assert_eq!(c.num_bytes, 0),
+                _ => panic!("expected a nop"),
+            }
+        }
+        for cmd in cmds.iter_mut() {
+            if let Command::Literal(ref mut lit) = *cmd {
+                m8.free_cell(core::mem::replace(&mut lit.data, Default::default()));
+            }
+        }
+        peephole.free(&mut m8);
+    }
+}
@@ -13,6 +13,10 @@
 //! This is documentation for the `divans` crate
 //!
 //! The `divans` crate is meant to be used for generic data compression
+//!
+//! The [`prelude`] holds the stable API: options, the streaming encoder and decoder, the one-shot
+//! [`compress`] and [`decompress`], the error type and the format constants.  The codec state machine
+//! is only public with the `internals` feature, as an unstable interface that may change in any release.
 
 #![cfg_attr(feature="benchmark", feature(test))]
 //#![cfg_attr(feature="simd", feature(platform_intrinsics))]
@@ -20,6 +24,7 @@
 #![cfg_attr(not(feature="no-stdlib-rust-binding"),cfg_attr(not(feature="std"), feature(compiler_builtins_lib)))]
 #![cfg_attr(not(feature="no-stdlib-rust-binding"),cfg_attr(not(feature="std"), crate_type="cdylib"))]
 #![no_std]
+#![cfg_attr(feature="forbid_unsafe", forbid(unsafe_code))]
 
 #[cfg(not(test))]
 #[cfg(any(feature="findspeed", feature="billing"))]
@@ -43,6 +48,17 @@ extern crate alloc_stdlib;
 
 extern crate alloc_no_stdlib as alloc;
 extern crate brotli;
+#[cfg(feature="bytes")]
+extern crate bytes;
+#[cfg(feature="logging")]
+extern crate log;
+#[cfg(feature="serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(all(test, feature="serde"))]
+extern crate serde_json;
+#[macro_use]
+mod logging;
 pub mod resizable_buffer;
 pub mod interface;
 pub mod slice_util;
@@ -52,39 +68,122 @@ mod probability;
 mod priors;
 #[macro_use]
 mod arithmetic_coder;
+#[cfg(feature="encoder")]
 mod debug_encoder;
 mod cmd_to_raw;
+#[cfg(feature="encoder")]
 mod raw_to_cmd;
+#[cfg(feature="internals")]
+pub mod codec;
+#[cfg(not(feature="internals"))]
 mod codec;
+#[cfg(feature="encoder")]
 mod cmd_to_divans;
 mod divans_to_raw;
 #[macro_use]
 mod billing;
+#[cfg(all(feature="encoder", feature="decoder"))]
 pub mod test_helper;
+#[cfg(all(feature="encoder", feature="decoder"))]
 mod test_ans;
+#[cfg(all(feature="encoder", feature="decoder"))]
 mod test_mux;
+#[cfg(all(feature="encoder", feature="decoder"))]
+mod test_flaky;
+#[cfg(all(feature="encoder", feature="decoder"))]
+mod test_stream_features;
+#[cfg(all(feature="encoder", feature="decoder"))]
+mod test_support;
 mod ans;
+#[cfg(feature="encoder")]
 mod brotli_ir_gen;
+#[cfg(feature="encoder")]
 mod divans_compressor;
+#[cfg(feature="decoder")]
 mod divans_decompressor;
+#[cfg(feature="decoder")]
 mod parallel_decompressor;
+#[cfg(feature="decoder")]
 mod stub_parallel_decompressor;
+#[cfg(feature="encoder")]
+mod budget;
+#[cfg(feature="encoder")]
+mod calibrate;
+#[cfg(feature="encoder")]
+mod literal_prescan;
+#[cfg(feature="encoder")]
+mod static_literal_model;
+#[cfg(feature="encoder")]
+mod ir_peephole;
+#[cfg(feature="encoder")]
 pub mod ir_optimize;
 pub mod mux;
+pub mod wire;
+pub mod mac;
+pub mod paged_output;
+#[cfg(feature="decoder")]
+pub mod decoded_command_iter;
+#[cfg(all(feature="encoder", feature="decoder"))]
+mod splice;
+#[cfg(all(feature="encoder", feature="decoder"))]
+mod archive;
+mod priors_model;
+mod output_digest;
+#[cfg(feature="encoder")]
+mod stored_frame;
+mod error;
+mod cancel;
+#[cfg(feature="encoder")]
+mod timings;
 pub mod constants;
+#[cfg(all(feature="encoder", feature="decoder"))]
+pub mod conformance;
+pub mod synthetic;
+#[cfg(feature="internals")]
 pub mod threading;
+#[cfg(not(feature="internals"))]
+mod threading;
+#[cfg(feature="internals")]
 pub mod multithreading;
+#[cfg(not(feature="internals"))]
+mod multithreading;
+mod oneshot;
+#[cfg(feature="decoder")]
+mod universal_decoder;
+#[cfg(feature="decoder")]
+mod verify;
+pub mod prelude;
 pub use self::interface::{DivansInputResult,DivansOpResult,DivansOutputResult, DivansResult, ErrMsg, MAGIC_NUMBER};
 pub use alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut, StackAllocator};
-pub use interface::{DivansCompressorFactory, BlockSwitch, LiteralBlockSwitch, Command, Compressor, CopyCommand, Decompressor, DictCommand, LiteralCommand, Nop, NewWithAllocator, ArithmeticEncoderOrDecoder, LiteralPredictionModeNibble, PredictionModeContextMap, free_cmd, FeatureFlagSliceType,
-                    DefaultCDF16};
+pub use interface::{DivansCompressorFactory, BlockSwitch, LiteralBlockSwitch, Command, Compressor, CopyCommand, Decompressor, DictCommand, LiteralCommand, Nop, NewWithAllocator, DuplicateWithAllocator, ArithmeticEncoderOrDecoder, LiteralPredictionModeNibble, PredictionModeContextMap, free_cmd, FeatureFlagSliceType,
+                    DefaultCDF16, DefaultBitModel, CompressorOption, FormatVersion, ContentHint, MAX_NIBBLE_BATCH, max_compressed_size, framing_overhead, CDF_BACKEND};
 
+#[cfg(feature="encoder")]
 pub use brotli_ir_gen::{BrotliDivansHybridCompressor,BrotliDivansHybridCompressorFactory};
-pub use cmd_to_raw::DivansRecodeState;
+pub use cmd_to_raw::{DivansRecodeState, CommandToRawConverter};
+#[cfg(feature="encoder")]
+pub use raw_to_cmd::{GreedyParser, GREEDY_MIN_MATCH, MAX_REPEAT_HINTS};
+#[cfg(all(feature="std", feature="encoder"))]
+pub use raw_to_cmd::PreparedDictionary;
 pub use codec::CMD_BUFFER_SIZE;
+pub use cancel::CancelToken;
+#[cfg(feature="encoder")]
+pub use ir_peephole::{IrPeephole, MAX_TINY_COPY};
+#[cfg(feature="encoder")]
+pub use ir_optimize::{EntropyMapper, EntropyRegion};
+#[cfg(all(feature="timings", feature="encoder"))]
+pub use timings::{Clock, EncodeStage, EncodeTimings};
 pub use divans_to_raw::DecoderSpecialization;
+#[cfg(feature="encoder")]
 pub use cmd_to_divans::EncoderSpecialization;
-pub use codec::{EncoderOrDecoderSpecialization, DivansCodec, StrideSelection};
+pub use codec::StrideSelection;
+#[cfg(feature="internals")]
+pub use codec::{EncoderOrDecoderSpecialization, DivansCodec, SubDigest, StreamDigest};
+#[cfg(feature="internals")]
+pub use codec::trace;
+#[cfg(feature="internals")]
+pub use codec::weights::{MixerSnapshot, MIXER_SNAPSHOT_QUEUE_SIZE};
+#[cfg(feature="encoder")]
 pub use divans_compressor::{DivansCompressor, DivansCompressorFactoryStruct};
 
 #[cfg(not(feature="safe"))]
@@ -93,31 +192,94 @@ mod ffi;
 pub use ffi::*;
 mod reader;
 mod writer;
-#[cfg(feature="std")]
+#[cfg(all(feature="std", feature="encoder"))]
 pub use reader::DivansBrotliHybridCompressorReader;
-#[cfg(feature="std")]
+#[cfg(all(feature="std", feature="encoder"))]
 pub use reader::DivansExperimentalCompressorReader;
-#[cfg(feature="std")]
+#[cfg(all(feature="std", feature="decoder"))]
 pub use reader::DivansDecompressorReader;
 
-#[cfg(feature="std")]
+#[cfg(all(feature="std", feature="encoder"))]
 pub use writer::DivansBrotliHybridCompressorWriter;
-#[cfg(feature="std")]
+#[cfg(all(feature="std", feature="encoder"))]
 pub use writer::DivansExperimentalCompressorWriter;
-#[cfg(feature="std")]
+#[cfg(all(feature="std", feature="decoder"))]
 pub use writer::DivansDecompressorWriter;
+pub use paged_output::{PageAllocator, PagedOutput};
+#[cfg(feature="decoder")]
+pub use decoded_command_iter::{CommandTap, DecodedCommandIter};
+#[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+pub use ir_optimize::entropy_map;
+#[cfg(all(feature="std", feature="encoder"))]
+pub use budget::{encode_with_budget, BudgetGovernor, BUDGET_LEVELS};
+#[cfg(all(feature="std", feature="encoder"))]
+pub use calibrate::{calibrate, CalibrationCandidate, CalibrationMeasurement, RecommendedOptions, SpeedClass,
+                    CALIBRATION_CANDIDATES, CALIBRATION_SAMPLE_LIMIT};
+#[cfg(all(feature="timings", feature="std", feature="encoder"))]
+pub use timings::{encode_with_timings, StdClock};
+#[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+pub use splice::{splice, spliced_streams, replace_range, compress_parallel, compress_parallel_chunked, decompress_parallel,
+                 chunk_boundaries, Chunking, SplicedStream};
+#[cfg(feature="std")]
+pub use error::{Error, ErrorKind};
+#[cfg(all(feature="std", feature="encoder"))]
+pub use oneshot::compress;
+#[cfg(all(feature="std", feature="decoder"))]
+pub use oneshot::{decompress, decompress_into};
+#[cfg(all(feature="std", feature="decoder"))]
+pub use universal_decoder::{StreamFormat, UniversalDecoder};
+#[cfg(all(feature="std", feature="decoder"))]
+pub use verify::{verify, verify_reader, Report};
+#[cfg(feature="billing")]
+pub use billing::{BillingEntry, BillingReport};
+#[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+pub use archive::{ArchiveEntry, ArchiveReader, ArchiveWriter, ARCHIVE_MAGIC};
+pub use output_digest::{OutputDigest, Xxh3};
+pub use priors_model::{PriorsModel, PriorsTable, PriorsTableIter, PRIORS_MODEL_MAGIC, PRIORS_MODEL_VERSION};
+#[cfg(feature="std")]
+pub use priors_model::PriorsModelWriter;
 
 
 pub use probability::Speed;
 
 
-pub use probability::CDF2;
+pub use probability::{BitModel, BitCounter12, CDF2};
 pub use probability::CDF16;
 pub use probability::BaseCDF;
+pub use probability::{ExternalProb, EXTERNAL_PROB_TOTAL};
 
 pub use interface::BrotliCompressionSetting;
 pub use interface::DivansCompressorOptions;
+pub use interface::{DistanceBucketing, MixerWarmStart, DecodedStreamOptions, DecodeStats, ChecksumType, ChecksumPolicy, OutputDigestType, TrailingBytesPolicy};
+#[cfg(feature="decoder")]
 pub use divans_decompressor::{DivansDecompressor,
                               DivansDecompressorFactory,
                               DivansDecompressorFactoryStruct,
                               StaticCommand};
+
+#[cfg(all(test, feature="std", feature="encoder", feature="decoder"))]
+mod test {
+    use conformance::{conformance_commands, conformance_expected_output};
+    use interface::DivansCompressorOptions;
+    use test_support::{encode_with_options, compress_hybrid, decode, decode_with, DecodeConfig};
+
+    // Small enough to run under Miri (cargo miri test --features forbid_unsafe test_miri_), which the
+    // rest of the suite is not.
+    #[test]
+    fn test_miri_roundtrip() {
+        let commands = conformance_commands("mixed");
+        let expected = conformance_expected_output(&commands[..]).unwrap();
+        let mut opts = DivansCompressorOptions::default();
+        opts.window_size = Some(16);
+        let stream = encode_with_options(&commands[..], opts).unwrap();
+        assert_eq!(decode(&stream[..]).unwrap(), expected);
+        let input = &include_bytes!("../testdata/alice29")[..2048];
+        opts.quality = Some(5);
+        let stream = compress_hybrid(input, opts);
+        for multithread in [false, true].iter() {
+            let mut config = DecodeConfig::new(*multithread);
+            config.output_chunk = 512;
+            assert_eq!(decode_with(&stream[..], config, |_| {}).into_output().unwrap(), input);
+        }
+    }
+}
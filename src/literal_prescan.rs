@@ -0,0 +1,496 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Per literal block type model selection for a metablock.
+// A sample of each block type's literals is costed under the context map, mixed and stride
+// models, and under the mixing values already chosen, with small adaptive nibble counters.
+// When a single model beats the chosen mixing values it is written into the mixing values of the
+// actual contexts the block type maps to, and its stride into the block switches.
+// The literal history used as context skips the bytes produced by copies, so costs are estimates.
+// For EXTENDED_FEATURE_LITERAL_NIBBLE_ORDER streams the same sample also picks the nibble order of
+// each block type.
+// Metablocks of 2 byte code units, found by sniffing or named by ContentHint::Utf16, first have
+// every literal switched to the stride 2 model.
+use core;
+use brotli;
+use brotli::enc::util::{floatX, FastLog2u16};
+use alloc::{Allocator, SliceWrapper, SliceWrapperMut};
+use brotli::interface::Command; // these see the commands of brotli's callback, before divans' own
+use interface::{PredictionModeContextMap, LITERAL_BLOCK_SWITCH_LSB_FIRST};
+use codec::{get_lut0, get_lut1};
+
+// literal bytes costed per block type
+pub const PRESCAN_SAMPLE_BYTES: u32 = 4096;
+// block types with fewer sampled literals keep the configured mixing values
+const PRESCAN_MIN_SAMPLE_BYTES: u32 = 256;
+// fraction of the configured cost a single model must come in under to replace it
+const PRESCAN_REQUIRED_GAIN: floatX = 0.9375;
+// mixing values of the candidate models: context map, mixed, and strides 1 through 4
+const PRESCAN_MODELS: [u8; 6] = [0, 1, 4, 5, 6, 7];
+// the last candidate follows the mixing values already in the prediction mode
+const NUM_CANDIDATES: usize = PRESCAN_MODELS.len() + 1;
+const LOG_PRESCAN_SLOTS: usize = 12;
+const PRESCAN_SLOTS: usize = 1 << LOG_PRESCAN_SLOTS;
+const PRESCAN_COUNT_LIMIT: u8 = 127;
+// bytes from the start of a metablock sniffed for its layout
+const SNIFF_BYTES: usize = 4096;
+// the mixing value of the stride 2 model
+const UTF16_MIXING: u8 = 5;
+
+#[inline(always)]
+fn slot(candidate: usize, mixing: u8, high: bool, a: u8, b: u8, btype: u8) -> usize {
+    let key = (u32::from(a) | (u32::from(b) << 8) | (u32::from(btype) << 16) | ((high as u32) << 24)
+               | (u32::from(mixing) << 25)).wrapping_mul(0x9E3779B1);
+    (candidate << LOG_PRESCAN_SLOTS) | (key >> (32 - LOG_PRESCAN_SLOTS)) as usize
+}
+
+// cost in bits of nibble under the adaptive counters at slot_index, which are then updated
+fn cost_and_update(counts: &mut [u8], slot_index: usize, nibble: u8) -> floatX {
+    let bins = &mut counts[slot_index * 16..slot_index * 16 + 16];
+    let total: u16 = bins.iter().map(|x| u16::from(*x)).sum();
+    let cost = FastLog2u16(total + 16) - FastLog2u16(u16::from(bins[nibble as usize]) + 1);
+    if bins[nibble as usize] == PRESCAN_COUNT_LIMIT {
+        for bin in bins.iter_mut() {
+            *bin >>= 1;
+        }
+    }
+    bins[nibble as usize] += 1;
+    cost
+}
+
+// the context literal.rs selects for the high nibble under the given mixing value
+#[inline(always)]
+fn high_context(mixing: u8, actual_context: u8, history: u32) -> (u8, u8) {
+    match mixing {
+        0 | 2 | 3 => (actual_context, 0),
+        1 => (actual_context, history as u8 & 0xf0),
+        _ => ((history >> (8 * u32::from(core::cmp::min(mixing, 7) - 4))) as u8, actual_context),
+    }
+}
+
+// the context literal.rs selects for the low nibble under the given mixing value
+#[inline(always)]
+fn low_context(mixing: u8, actual_context: u8, history: u32, high_nibble: u8) -> (u8, u8) {
+    match mixing {
+        0 | 2 | 3 => (actual_context, high_nibble),
+        1 => (history as u8, high_nibble | (actual_context << 4)),
+        _ => ((history >> (8 * u32::from(core::cmp::min(mixing, 7) - 4))) as u8, high_nibble),
+    }
+}
+
+pub fn prescan_literal_blocks<AllocU8:Allocator<u8>,
+                              SliceType:SliceWrapper<u8>+SliceWrapperMut<u8>>(
+    m8: &mut AllocU8,
+    pm: &mut PredictionModeContextMap<SliceType>,
+    cmds: &mut [Command<brotli::SliceOffset>],
+    mb: &brotli::InputPair) {
+    if !pm.has_context_speeds() || pm.literal_prediction_mode().0 > 3 {
+        return;
+    }
+    let lut0 = get_lut0(pm.literal_prediction_mode());
+    let lut1 = get_lut1(pm.literal_prediction_mode());
+    let mut counts = m8.alloc_cell(NUM_CANDIDATES * PRESCAN_SLOTS * 16);
+    let mut sampled = [0u32; 256];
+    let mut costs = [[0.0 as floatX; NUM_CANDIDATES]; 256];
+    {
+        let cmap = pm.literal_context_map.slice();
+        let mixing_values = pm.get_mixing_values();
+        let mut btype = 0u8;
+        let mut history = 0u32; // the last 4 literals, most recent in the low byte
+        for cmd in cmds.iter() {
+            match *cmd {
+                Command::BlockSwitchLiteral(ref bs) => btype = bs.block_type(),
+                Command::Literal(_) => {
+                    let data = match brotli::thaw_pair(cmd, mb) {
+                        Command::Literal(lit) => lit.data.data,
+                        _ => unreachable!(),
+                    };
+                    for literal in data.iter() {
+                        if sampled[btype as usize] < PRESCAN_SAMPLE_BYTES {
+                            sampled[btype as usize] += 1;
+                            let selected_context = lut0[history as u8 as usize] | lut1[(history >> 8) as u8 as usize];
+                            let actual_context = match cmap.get(usize::from(btype) * 64 + usize::from(selected_context)) {
+                                Some(ctx) => *ctx,
+                                None => selected_context,
+                            };
+                            let high_nibble = *literal >> 4;
+                            let low_nibble = *literal & 0xf;
+                            let btype_costs = &mut costs[btype as usize];
+                            for (candidate, mixing) in PRESCAN_MODELS.iter().enumerate() {
+                                let high = high_context(*mixing, actual_context, history);
+                                let low = low_context(*mixing, actual_context, history, high_nibble);
+                                btype_costs[candidate] +=
+                                    cost_and_update(counts.slice_mut(), slot(candidate, *mixing, true, high.0, high.1, btype), high_nibble)
+                                    + cost_and_update(counts.slice_mut(), slot(candidate, *mixing, false, low.0, low.1, btype), low_nibble);
+                            }
+                            // indexed the way literal.rs indexes the mixing values
+                            let configured = PRESCAN_MODELS.len();
+                            let high_mixing = mixing_values[usize::from(actual_context) | (usize::from(history as u8 >> 4) << 8)];
+                            let low_mixing = mixing_values[usize::from(actual_context) | (usize::from(high_nibble) << 8) | 4096];
+                            let high = high_context(high_mixing, actual_context, history);
+                            let low = low_context(low_mixing, actual_context, history, high_nibble);
+                            btype_costs[configured] +=
+                                cost_and_update(counts.slice_mut(), slot(configured, high_mixing, true, high.0, high.1, btype), high_nibble)
+                                + cost_and_update(counts.slice_mut(), slot(configured, low_mixing, false, low.0, low.1, btype), low_nibble);
+                        }
+                        history = (history << 8) | u32::from(*literal);
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+    m8.free_cell(counts);
+    // a block type switches to a single model only when it is clearly cheaper than the configured
+    // mixing values on a sample large enough to trust
+    let configured = PRESCAN_MODELS.len();
+    let mut winner = [configured; 256];
+    for (btype, btype_costs) in costs.iter().enumerate() {
+        if sampled[btype] < PRESCAN_MIN_SAMPLE_BYTES {
+            continue;
+        }
+        let mut best_cost = btype_costs[configured] * PRESCAN_REQUIRED_GAIN;
+        for (candidate, cost) in btype_costs[..configured].iter().enumerate() {
+            if *cost < best_cost {
+                best_cost = *cost;
+                winner[btype] = candidate;
+            }
+        }
+    }
+    // contexts shared between block types go to the choice backed by the most sampled bytes
+    let mut votes = [[0u32; NUM_CANDIDATES]; 256];
+    {
+        let cmap = pm.literal_context_map.slice();
+        for btype in 0..256usize {
+            if sampled[btype] == 0 {
+                continue;
+            }
+            let mut seen = [false; 256];
+            for selected_context in 0..64usize {
+                if let Some(actual_context) = cmap.get(btype * 64 + selected_context) {
+                    if !seen[*actual_context as usize] {
+                        seen[*actual_context as usize] = true;
+                        votes[*actual_context as usize][winner[btype]] += sampled[btype];
+                    }
+                }
+            }
+        }
+    }
+    let mixing_values = pm.get_mixing_values_mut();
+    for (actual_context, context_votes) in votes.iter().enumerate() {
+        let mut best = configured;
+        for (candidate, vote) in context_votes.iter().enumerate() {
+            if *vote > context_votes[best] {
+                best = candidate;
+            }
+        }
+        if best != configured {
+            for nibble_prefix in 0..32usize {
+                mixing_values[actual_context | (nibble_prefix << 8)] = PRESCAN_MODELS[best];
+            }
+        }
+    }
+    for cmd in cmds.iter_mut() {
+        if let Command::BlockSwitchLiteral(ref mut bs) = *cmd {
+            let candidate = winner[bs.block_type() as usize];
+            if candidate != configured && PRESCAN_MODELS[candidate] >= 4 {
+                bs.update_stride(PRESCAN_MODELS[candidate] - 3);
+            }
+        }
+    }
+}
+
+// Marks the literal block switches of block types that cost clearly less coded low nibble first.
+// Each order is costed coding its first nibble after the same half of the byte before and its
+// second nibble after the first.  The block type a metablock starts in has no switch to mark, so
+// it stays high nibble first until one switches to it.
+pub fn choose_literal_nibble_order<AllocU8:Allocator<u8>,
+                                   SliceType:SliceWrapper<u8>+SliceWrapperMut<u8>>(
+    m8: &mut AllocU8,
+    pm: &mut PredictionModeContextMap<SliceType>,
+    cmds: &mut [Command<brotli::SliceOffset>],
+    mb: &brotli::InputPair) {
+    if pm.literal_prediction_mode().0 > 3 {
+        return;
+    }
+    let lut0 = get_lut0(pm.literal_prediction_mode());
+    let lut1 = get_lut1(pm.literal_prediction_mode());
+    let mut counts = m8.alloc_cell(2 * PRESCAN_SLOTS * 16); // high nibble first, then low nibble first
+    let mut sampled = [0u32; 256];
+    let mut costs = [[0.0 as floatX; 2]; 256];
+    {
+        let cmap = pm.literal_context_map.slice();
+        let mut btype = 0u8;
+        let mut history = 0u32; // the last 2 literals, most recent in the low byte
+        for cmd in cmds.iter() {
+            match *cmd {
+                Command::BlockSwitchLiteral(ref bs) => btype = bs.block_type(),
+                Command::Literal(_) => {
+                    let data = match brotli::thaw_pair(cmd, mb) {
+                        Command::Literal(lit) => lit.data.data,
+                        _ => unreachable!(),
+                    };
+                    for literal in data.iter() {
+                        if sampled[btype as usize] < PRESCAN_SAMPLE_BYTES {
+                            sampled[btype as usize] += 1;
+                            let selected_context = lut0[history as u8 as usize] | lut1[(history >> 8) as u8 as usize];
+                            let actual_context = match cmap.get(usize::from(btype) * 64 + usize::from(selected_context)) {
+                                Some(ctx) => *ctx,
+                                None => selected_context,
+                            };
+                            for (order, rotation) in [0u32, 4].iter().enumerate() {
+                                let coded = literal.rotate_left(*rotation);
+                                let prev_coded = (history as u8).rotate_left(*rotation);
+                                costs[btype as usize][order] +=
+                                    cost_and_update(counts.slice_mut(), slot(order, 0, true, actual_context, prev_coded >> 4, btype), coded >> 4)
+                                    + cost_and_update(counts.slice_mut(), slot(order, 0, false, actual_context, coded >> 4, btype), coded & 0xf);
+                            }
+                        }
+                        history = (history << 8) | u32::from(*literal);
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+    m8.free_cell(counts);
+    let mut lsb_first = [false; 256];
+    for (btype, btype_costs) in costs.iter().enumerate() {
+        lsb_first[btype] = sampled[btype] >= PRESCAN_MIN_SAMPLE_BYTES && btype_costs[1] < btype_costs[0] * PRESCAN_REQUIRED_GAIN;
+    }
+    // the mixing values are chosen for each prefix of the high nibble first byte, which a low nibble
+    // first block does not have: its actual contexts mix the same way whatever the prefix
+    if pm.has_context_speeds() {
+        let mut flatten = [false; 256];
+        {
+            let cmap = pm.literal_context_map.slice();
+            for (btype, _) in lsb_first.iter().enumerate().filter(|&(_, lsb)| *lsb) {
+                for selected_context in 0..64usize {
+                    if let Some(actual_context) = cmap.get(btype * 64 + selected_context) {
+                        flatten[*actual_context as usize] = true;
+                    }
+                }
+            }
+        }
+        let mixing_values = pm.get_mixing_values_mut();
+        for actual_context in 0..256usize {
+            if !flatten[actual_context] {
+                continue;
+            }
+            let mut votes = [0u8; 8];
+            for nibble_prefix in 0..32usize {
+                votes[usize::from(mixing_values[actual_context | (nibble_prefix << 8)] & 7)] += 1;
+            }
+            let mut best = 0;
+            for (value, vote) in votes.iter().enumerate() {
+                if *vote > votes[best] {
+                    best = value;
+                }
+            }
+            for nibble_prefix in 0..32usize {
+                mixing_values[actual_context | (nibble_prefix << 8)] = best as u8;
+            }
+        }
+    }
+    for cmd in cmds.iter_mut() {
+        if let Command::BlockSwitchLiteral(ref mut bs) = *cmd {
+            if lsb_first[bs.block_type() as usize] {
+                let stride = bs.stride();
+                bs.update_stride(stride | LITERAL_BLOCK_SWITCH_LSB_FIRST);
+            }
+        }
+    }
+}
+
+// Whether the metablock looks like 2 byte code units: the bytes of one parity, the halves naming
+// the script, nearly all repeat the byte two before while most of those of the other parity do not.
+pub fn sniff_utf16(mb: &brotli::InputPair) -> bool {
+    let mut repeats = [0u32; 2];
+    let mut sniffed = 0usize;
+    let mut history = 0u16; // the last 2 bytes, most recent in the low byte
+    for (index, byte) in mb.0.data.iter().chain(mb.1.data.iter()).take(SNIFF_BYTES).enumerate() {
+        if index >= 2 && *byte == (history >> 8) as u8 {
+            repeats[index & 1] += 1;
+        }
+        history = (history << 8) | u16::from(*byte);
+        sniffed += 1;
+    }
+    if sniffed < PRESCAN_MIN_SAMPLE_BYTES as usize {
+        return false;
+    }
+    let more = core::cmp::max(repeats[0], repeats[1]);
+    let fewer = core::cmp::min(repeats[0], repeats[1]);
+    let per_parity = (sniffed / 2 - 1) as u32;
+    more * 4 >= per_parity * 3 && fewer * 2 < more
+}
+
+// Codes every literal after the byte two before it, the same half of the previous code unit, under
+// the actual context of the byte before, which tells the first byte of a unit from the second.
+pub fn use_utf16_model<SliceType:SliceWrapper<u8>+SliceWrapperMut<u8>>(
+    pm: &mut PredictionModeContextMap<SliceType>,
+    cmds: &mut [Command<brotli::SliceOffset>]) {
+    if !pm.has_context_speeds() {
+        return;
+    }
+    for mixing in pm.get_mixing_values_mut().iter_mut() {
+        *mixing = UTF16_MIXING;
+    }
+    for cmd in cmds.iter_mut() {
+        if let Command::BlockSwitchLiteral(ref mut bs) = *cmd {
+            bs.update_stride(UTF16_MIXING - 3);
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature="std")]
+mod test {
+    extern crate std;
+    use std::vec::Vec;
+    use alloc_stdlib::HeapAlloc;
+    use brotli;
+    use brotli::interface::{Command, LiteralBlockSwitch};
+    use interface::{LiteralCommand, PredictionModeContextMap, FeatureFlagSliceType,
+                    LiteralPredictionModeNibble, LITERAL_BLOCK_SWITCH_LSB_FIRST};
+    use super::{prescan_literal_blocks, choose_literal_nibble_order};
+    use brotli::enc::interface::{DISTANCE_CONTEXT_MAP_OFFSET, LITERAL_PREDICTION_MODE_LSB6};
+    #[cfg(feature="decoder")]
+    use interface::{
+        DivansCompressorOptions,
+        ContentHint,
+    };
+    #[cfg(feature="decoder")]
+    use test_support::{compress_hybrid, decode};
+
+    fn literal(start: usize, len: usize) -> Command<brotli::SliceOffset> {
+        Command::Literal(LiteralCommand::<brotli::SliceOffset>{
+            data: brotli::SliceOffset(start, len as u32),
+            prob: FeatureFlagSliceType::<brotli::SliceOffset>::default(),
+            high_entropy: false,
+        })
+    }
+
+    #[test]
+    fn test_prescan_picks_stride_for_records() {
+        let mut data = Vec::<u8>::new();
+        data.extend(&include_bytes!("../testdata/alice29")[..8192]);
+        let text_len = data.len();
+        for i in 0..2048usize {
+            data.extend(&[b'A' + (i % 3) as u8, (i / 7) as u8, 0x80 | (i % 5) as u8, (i / 300) as u8]);
+        }
+        let mut cmds = [literal(0, text_len),
+                        Command::BlockSwitchLiteral(LiteralBlockSwitch::new(1, 0)),
+                        literal(text_len, data.len() - text_len)];
+        // block type 1 uses its own actual contexts
+        let mut literal_context_map = [0u8; 128];
+        for (index, item) in literal_context_map.iter_mut().enumerate() {
+            *item = index as u8;
+        }
+        let mut predmode = [0u8; DISTANCE_CONTEXT_MAP_OFFSET + 4];
+        let mut pm = PredictionModeContextMap::<brotli::InputReferenceMut>{
+            literal_context_map: brotli::InputReferenceMut{data: &mut literal_context_map[..], orig_offset: 0},
+            predmode_speed_and_distance_context_map: brotli::InputReferenceMut{data: &mut predmode[..], orig_offset: 0},
+        };
+        pm.set_literal_prediction_mode(LiteralPredictionModeNibble(LITERAL_PREDICTION_MODE_LSB6));
+        let mut m8 = HeapAlloc::<u8>::new(0);
+        let mb = brotli::InputPair(brotli::InputReference{data: &data[..], orig_offset: 0},
+                                   brotli::InputReference{data: &[], orig_offset: data.len()});
+        prescan_literal_blocks(&mut m8, &mut pm, &mut cmds[..], &mb);
+        let mixing_values = pm.get_mixing_values();
+        for actual_context in 64..128usize {
+            // stride 4 predicts every field of the records from the previous record
+            assert_eq!(mixing_values[actual_context], 7);
+            assert_eq!(mixing_values[actual_context | (31 << 8)], 7);
+        }
+        for actual_context in 128..256usize {
+            assert_eq!(mixing_values[actual_context], 0); // untouched
+        }
+        match cmds[1] {
+            Command::BlockSwitchLiteral(ref bs) => assert_eq!(bs.stride(), 4),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_nibble_order_follows_the_predictable_nibble() {
+        let mut data = Vec::<u8>::new();
+        data.extend(&include_bytes!("../testdata/alice29")[..8192]);
+        let text_len = data.len();
+        // counting low nibbles under noisy high nibbles
+        let mut seed = 0x2545f491u32;
+        for i in 0..8192usize {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            data.push(((seed >> 24) as u8 & 0xf0) | (i & 0xf) as u8);
+        }
+        let mut cmds = [literal(0, text_len),
+                        Command::BlockSwitchLiteral(LiteralBlockSwitch::new(1, 2)),
+                        literal(text_len, data.len() - text_len),
+                        Command::BlockSwitchLiteral(LiteralBlockSwitch::new(0, 0)),
+                        literal(0, text_len)];
+        // a single actual context, so the nibbles are all there is to go on
+        let mut literal_context_map = [0u8; 128];
+        let mut predmode = [0u8; DISTANCE_CONTEXT_MAP_OFFSET + 4];
+        let mut pm = PredictionModeContextMap::<brotli::InputReferenceMut>{
+            literal_context_map: brotli::InputReferenceMut{data: &mut literal_context_map[..], orig_offset: 0},
+            predmode_speed_and_distance_context_map: brotli::InputReferenceMut{data: &mut predmode[..], orig_offset: 0},
+        };
+        let mut m8 = HeapAlloc::<u8>::new(0);
+        let mb = brotli::InputPair(brotli::InputReference{data: &data[..], orig_offset: 0},
+                                   brotli::InputReference{data: &[], orig_offset: data.len()});
+        choose_literal_nibble_order(&mut m8, &mut pm, &mut cmds[..], &mb);
+        match cmds[1] {
+            Command::BlockSwitchLiteral(ref bs) => assert_eq!(bs.stride(), 2 | LITERAL_BLOCK_SWITCH_LSB_FIRST),
+            _ => unreachable!(),
+        }
+        match cmds[3] {
+            Command::BlockSwitchLiteral(ref bs) => assert_eq!(bs.stride(), 0),
+            _ => unreachable!(),
+        }
+    }
+
+    #[cfg(feature="decoder")]
+    #[test]
+    fn test_utf16_content() {
+        // code units each following the one before it loosely, in runs of Latin and Cyrillic, so most
+        // of what there is to learn is two bytes back
+        let mut utf16 = Vec::<u8>::new();
+        let mut seed = 0x2545f491u32;
+        let mut letter = 0u16;
+        for index in 0..(1usize << 17) {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            let noise = (seed >> 8) & 0xffff;
+            letter = (letter * 7 + ((noise * noise) >> 29) as u16) & 0x1f;
+            let unit = if (index / 512) % 4 == 3 {0x0430 + letter} else {u16::from(b'a') + letter};
+            utf16.extend(&unit.to_le_bytes());
+        }
+        let mut opts = DivansCompressorOptions::default();
+        opts.quality = Some(9);
+        opts.content_hint = ContentHint::Opaque;
+        let opaque = compress_hybrid(&utf16[..], opts);
+        opts.content_hint = ContentHint::Utf16;
+        let hinted = compress_hybrid(&utf16[..], opts);
+        opts.content_hint = ContentHint::Unknown;
+        assert_eq!(compress_hybrid(&utf16[..], opts), hinted);
+        assert!(hinted.len() < opaque.len(), "opaque {} hinted {}", opaque.len(), hinted.len());
+        assert_eq!(decode(&hinted[..]).unwrap(), utf16);
+        // the mixing values are in the stream, so neither needs the hint to decode
+        assert_eq!(decode(&opaque[..]).unwrap(), utf16);
+        // single byte text is not sniffed as code units
+        let text = include_bytes!("../testdata/alice29");
+        opts.content_hint = ContentHint::Opaque;
+        let plain = compress_hybrid(&text[..], opts);
+        opts.content_hint = ContentHint::Unknown;
+        assert_eq!(compress_hybrid(&text[..], opts), plain);
+    }
+}
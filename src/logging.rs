@@ -0,0 +1,75 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Diagnostics of the codec.  With the logging feature they go to the log crate at trace level
+// under the divans target, as a message with key-values, so a service can capture them with
+// whatever logger it installed; otherwise they compile to nothing.  Values are plain integers
+// and bools, which is all the key-value support of log takes without its std features.
+//     divans_trace!(num_bytes = n, distance = d; "decoded copy");
+
+#[cfg(feature="logging")]
+macro_rules! divans_trace(
+    ($($key:ident = $val:expr),+ ; $msg:expr) => {
+        ::log::trace!(target: "divans", $($key = $val),+ ; $msg)
+    };
+);
+
+#[cfg(not(feature="logging"))]
+macro_rules! divans_trace(
+    ($($key:ident = $val:expr),+ ; $msg:expr) => { {
+        $(let _ = &$val;)+
+    } };
+);
+
+#[cfg(all(test, feature="logging", feature="std", feature="encoder", feature="decoder"))]
+mod test {
+    use conformance::conformance_commands;
+    use test_support::{encode, decode_with, DecodeConfig};
+
+    // sums the num_bytes of the decoded copies logged on the calling thread, since other tests
+    // decode alongside
+    struct CopyBytesLogger;
+
+    thread_local! {
+        static LOGGED_COPY_BYTES: ::core::cell::Cell<u64> = ::core::cell::Cell::new(0);
+    }
+
+    impl ::log::Log for CopyBytesLogger {
+        fn enabled(&self, metadata: &::log::Metadata) -> bool {
+            metadata.target() == "divans"
+        }
+        fn log(&self, record: &::log::Record) {
+            if !self.enabled(record.metadata()) || record.args().as_str() != Some("decoded copy") {
+                return;
+            }
+            if let Some(num_bytes) = record.key_values().get(::log::kv::Key::from_str("num_bytes")).and_then(|v| v.to_u64()) {
+                LOGGED_COPY_BYTES.with(|total| total.set(total.get() + num_bytes));
+            }
+        }
+        fn flush(&self) {
+        }
+    }
+
+    #[test]
+    fn test_logging_reports_decoded_copies() {
+        static LOGGER: CopyBytesLogger = CopyBytesLogger;
+        ::log::set_logger(&LOGGER).unwrap();
+        ::log::set_max_level(::log::LevelFilter::Trace);
+        let commands = conformance_commands("mixed");
+        let stream = encode(&commands[..], 0).unwrap();
+        let stats = decode_with(&stream[..], DecodeConfig::new(false), |_| {}).stats;
+        assert!(stats.copy_bytes != 0);
+        assert_eq!(LOGGED_COPY_BYTES.with(|total| total.get()), stats.copy_bytes);
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// HMAC-SHA256 (RFC 2104, FIPS 180-4) for the authenticated trailer of STREAM_FEATURE_AUTHENTICATED
+// streams. The crc only catches accidents; the tag also catches anyone without the key editing the stream.
+use core;
+use wire::MAC_LENGTH;
+
+const BLOCK_LENGTH: usize = 64;
+
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+#[derive(Clone, Copy)]
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; BLOCK_LENGTH],
+    block_len: usize,
+    total_len: u64,
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Sha256 {
+            state: INITIAL_STATE,
+            block: [0; BLOCK_LENGTH],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+}
+
+impl Sha256 {
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for (index, word) in self.block.chunks(4).enumerate() {
+            w[index] = (u32::from(word[0]) << 24) | (u32::from(word[1]) << 16)
+                | (u32::from(word[2]) << 8) | u32::from(word[3]);
+        }
+        for index in 16..64 {
+            let s0 = w[index - 15].rotate_right(7) ^ w[index - 15].rotate_right(18) ^ (w[index - 15] >> 3);
+            let s1 = w[index - 2].rotate_right(17) ^ w[index - 2].rotate_right(19) ^ (w[index - 2] >> 10);
+            w[index] = w[index - 16].wrapping_add(s0).wrapping_add(w[index - 7]).wrapping_add(s1);
+        }
+        let mut v = self.state;
+        for index in 0..64 {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(ROUND_CONSTANTS[index]).wrapping_add(w[index]);
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0.wrapping_add(maj);
+            v = [t1.wrapping_add(t2), v[0], v[1], v[2], v[3].wrapping_add(t1), v[4], v[5], v[6]];
+        }
+        for (state, val) in self.state.iter_mut().zip(v.iter()) {
+            *state = state.wrapping_add(*val);
+        }
+    }
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.total_len = self.total_len.wrapping_add(data.len() as u64);
+        while data.len() != 0 {
+            let to_copy = core::cmp::min(BLOCK_LENGTH - self.block_len, data.len());
+            self.block[self.block_len..self.block_len + to_copy].clone_from_slice(&data[..to_copy]);
+            self.block_len += to_copy;
+            data = &data[to_copy..];
+            if self.block_len == BLOCK_LENGTH {
+                self.compress();
+                self.block_len = 0;
+            }
+        }
+    }
+    // the digest of everything written so far; self may keep being updated afterwards
+    pub fn digest(&self) -> [u8; MAC_LENGTH] {
+        let mut tail = *self;
+        let bit_len = self.total_len.wrapping_mul(8);
+        tail.update(&[0x80]);
+        while tail.block_len != BLOCK_LENGTH - 8 {
+            tail.update(&[0]);
+        }
+        let mut len_bytes = [0u8; 8];
+        for (index, byte) in len_bytes.iter_mut().enumerate() {
+            *byte = (bit_len >> (56 - 8 * index)) as u8;
+        }
+        tail.update(&len_bytes[..]);
+        let mut ret = [0u8; MAC_LENGTH];
+        for (out, word) in ret.chunks_mut(4).zip(tail.state.iter()) {
+            out[0] = (*word >> 24) as u8;
+            out[1] = (*word >> 16) as u8;
+            out[2] = (*word >> 8) as u8;
+            out[3] = *word as u8;
+        }
+        ret
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct HmacSha256 {
+    inner: Sha256,
+    outer: Sha256,
+}
+
+impl HmacSha256 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut block_key = [0u8; BLOCK_LENGTH];
+        if key.len() > BLOCK_LENGTH {
+            let mut hashed = Sha256::default();
+            hashed.update(key);
+            block_key[..MAC_LENGTH].clone_from_slice(&hashed.digest()[..]);
+        } else {
+            block_key[..key.len()].clone_from_slice(key);
+        }
+        let mut ipad = [0x36u8; BLOCK_LENGTH];
+        let mut opad = [0x5cu8; BLOCK_LENGTH];
+        for ((i, o), k) in ipad.iter_mut().zip(opad.iter_mut()).zip(block_key.iter()) {
+            *i ^= *k;
+            *o ^= *k;
+        }
+        let mut ret = HmacSha256 {
+            inner: Sha256::default(),
+            outer: Sha256::default(),
+        };
+        ret.inner.update(&ipad[..]);
+        ret.outer.update(&opad[..]);
+        ret
+    }
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data)
+    }
+    // the tag of everything written so far
+    pub fn tag(&self) -> [u8; MAC_LENGTH] {
+        let mut outer = self.outer;
+        outer.update(&self.inner.digest()[..]);
+        outer.digest()
+    }
+}
+
+// so that a StreamDigest can be printed without revealing key material
+impl core::fmt::Debug for HmacSha256 {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str("HmacSha256")
+    }
+}
+
+// compares every byte regardless of where the first difference is
+pub fn tags_match(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        diff |= *x ^ *y;
+    }
+    diff == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use conformance::{conformance_commands, conformance_expected_output};
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use interface::{
+        DivansCompressorOptions,
+        ErrMsg,
+        HEADER_FEATURE_OFFSET,
+        STREAM_FEATURE_COMMAND_TYPE_TREE,
+        STREAM_FEATURE_AUTHENTICATED,
+        AUTHENTICATION_KEY_LENGTH,
+    };
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use test_support::{encode, encode_with_options, decode_with, DecodeConfig};
+    fn hex(s: &str) -> [u8; MAC_LENGTH] {
+        let mut ret = [0u8; MAC_LENGTH];
+        for (index, byte) in ret.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * index..2 * index + 2], 16).unwrap();
+        }
+        ret
+    }
+    #[test]
+    fn test_sha256_known_answers() {
+        let mut abc = Sha256::default();
+        abc.update(b"abc");
+        assert_eq!(abc.digest(), hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"));
+        // two blocks, fed a byte at a time
+        let mut split = Sha256::default();
+        for byte in b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq".iter() {
+            split.update(&[*byte]);
+        }
+        assert_eq!(split.digest(), hex("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"));
+    }
+    #[test]
+    fn test_hmac_rfc4231() {
+        let mut case1 = HmacSha256::new(&[0x0b; 20]);
+        case1.update(b"Hi There");
+        assert_eq!(case1.tag(), hex("b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"));
+        let mut case2 = HmacSha256::new(b"Jefe");
+        case2.update(b"what do ya want ");
+        case2.update(b"for nothing?");
+        assert_eq!(case2.tag(), hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"));
+        // a key longer than the block is hashed first
+        let mut case6 = HmacSha256::new(&[0xaa; 131]);
+        case6.update(b"Test Using Larger Than Block-Size Key - Hash Key First");
+        assert_eq!(case6.tag(), hex("60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"));
+        assert!(tags_match(&case6.tag()[..], &case6.tag()[..]));
+        assert!(!tags_match(&case6.tag()[..], &case2.tag()[..]));
+    }
+
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    #[test]
+    fn test_authenticated_trailer() {
+        let commands = conformance_commands("mixed");
+        let expected = conformance_expected_output(&commands[..]).unwrap();
+        let key = [0x5au8; AUTHENTICATION_KEY_LENGTH];
+        let mut other_key = key;
+        other_key[31] ^= 1;
+        let plain = encode(&commands[..], STREAM_FEATURE_COMMAND_TYPE_TREE).unwrap();
+        let mut opts = DivansCompressorOptions::default();
+        opts.stream_features = STREAM_FEATURE_COMMAND_TYPE_TREE;
+        opts.authentication_key = Some(key);
+        let tagged = encode_with_options(&commands[..], opts).unwrap();
+        assert_eq!(tagged[HEADER_FEATURE_OFFSET], STREAM_FEATURE_COMMAND_TYPE_TREE | STREAM_FEATURE_AUTHENTICATED);
+        assert_eq!(tagged.len(), plain.len() + 32);
+        let mut bad_tag = tagged.clone();
+        let last = bad_tag.len() - 1;
+        bad_tag[last] ^= 0x80;
+        for multithread in [false, true].iter() {
+            let decode_authenticated = |stream: &[u8], key: Option<[u8; AUTHENTICATION_KEY_LENGTH]>| {
+                let mut config = DecodeConfig::new(*multithread);
+                config.authentication_key = key;
+                decode_with(stream, config, |_| {})
+            };
+            assert_eq!(decode_authenticated(&tagged[..], Some(key)).into_output(), Ok(expected.clone()));
+            match decode_authenticated(&tagged[..], None).result {
+                Err(ErrMsg::MissingAuthenticationKey) => {},
+                res => panic!("{:?}", res),
+            }
+            // dropping the tag and the feature bit must not get past a decoder that holds the key
+            match decode_authenticated(&plain[..], Some(key)).result {
+                Err(ErrMsg::StreamNotAuthenticated) => {},
+                res => panic!("{:?}", res),
+            }
+            for &(stream, key) in [(&tagged, other_key), (&bad_tag, key)].iter() {
+                let decoded = decode_authenticated(&stream[..], Some(key));
+                match decoded.result {
+                    Err(ErrMsg::BadAuthenticationTag) => {},
+                    res => panic!("{:?}", res),
+                }
+                // the last of the output is only released once the tag checks out
+                assert!(decoded.output.len() < expected.len());
+                assert_eq!(&decoded.output[..], &expected[..decoded.output.len()]);
+            }
+        }
+    }
+}
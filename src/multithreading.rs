@@ -1,15 +1,21 @@
 #![cfg(feature="std")]
 use core;
 
-use std::sync::{Arc, Mutex, Condvar};
+use std::sync::{Arc, Mutex, MutexGuard, Condvar, PoisonError, mpsc};
+use std::thread;
+use std::vec::Vec;
+use core::hash::Hasher;
 use threading::{SerialWorker, MainToThread, ThreadToMain, CommandResult, ThreadData, NUM_SERIAL_COMMANDS_BUFFERED, NUM_DATA_BUFFERED,};
 use slice_util::{AllocatedMemoryRange, AllocatedMemoryPrefix};
 use alloc::{Allocator, SliceWrapper, SliceWrapperMut};
 use alloc_util::RepurposingAlloc;
 use cmd_to_raw::DivansRecodeState;
-use interface::{PredictionModeContextMap, EncoderOrDecoderRecoderSpecialization, Command, DivansOpResult, DivansOutputResult, ErrMsg};
-use std::time::{SystemTime, Duration};
-use threading::{StaticCommand, PullAllocatedCommand, downcast_command};
+use interface::{PredictionModeContextMap, EncoderOrDecoderRecoderSpecialization, Command, LiteralCommand, FeatureFlagSliceType, DivansOpResult, DivansOutputResult, ErrMsg, StreamID, NUM_STREAMS};
+use std::time::{SystemTime, Duration, Instant};
+use threading::{StaticCommand, PullAllocatedCommand, LiteralSpan, downcast_command};
+use codec::{CMD_BUFFER_SIZE, CMD_CODER, LIT_CODER};
+use codec::weights::{MixerSnapshot, MixerSnapshotQueue};
+use codec::SubDigest;
 #[cfg(feature="threadlog")]
 const MAX_LOG_SIZE: usize = 8192;
 #[cfg(not(feature="threadlog"))]
@@ -80,6 +86,11 @@ macro_rules! thread_debug {
     };
 }
 
+// A queue poisoned by a panicking worker is still sound: the main thread only reads the error from it.
+fn lock_queue<'a, T>(lock: &'a Mutex<T>) -> MutexGuard<'a, T> {
+    lock.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> Clone for MultiWorker<AllocU8, AllocCommand> {
     fn clone(&self) -> Self {
         Self {
@@ -125,7 +136,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> MultiWorker<A
     fn broadcast_err_internal(&mut self, err: ErrMsg, _thread_event_type: ThreadEventType) {
         let _elapsed = unguarded_debug_time!(self);
         let &(ref lock, ref cvar) = &*self.queue;
-        let mut worker = lock.lock().unwrap();
+        let mut worker = lock_queue(lock);
         if worker.waiters != 0 {
             cvar.notify_one();
         }
@@ -133,22 +144,125 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> MultiWorker<A
         thread_debug!(_thread_event_type, output.len(), self, _elapsed);
         return ret;        
     }
+    // MainToThread::pull, waiting no longer than timeout for a result if one is given
+    fn pull_within(&mut self,
+                   output:&mut AllocatedMemoryPrefix<StaticCommand, AllocCommand>,
+                   spans:&mut AllocatedMemoryPrefix<u8, AllocU8>,
+                   consumed_data:&mut [[AllocatedMemoryRange<u8, AllocU8>;NUM_DATA_BUFFERED];NUM_STREAMS],
+                   pm:&mut [PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>; 2],
+                   timeout: Option<Duration>) -> CommandResult {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let _elapsed = unguarded_debug_time!(self);
+            let &(ref lock, ref cvar) = &*self.queue;
+            let mut worker = lock_queue(lock);
+            if worker.result_ready() {
+                if worker.waiters != 0 {
+                    cvar.notify_one(); // FIXME: do we want to signal here?
+                }
+                let ret = worker.pull(output, spans, consumed_data, pm);
+                thread_debug!(ThreadEventType::M_PULL_COMMAND_RESULT, output.len(), self, _elapsed);
+                return ret;
+            } else if worker.err.is_none() {
+                let remaining = match deadline {
+                    Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                        Some(remaining) if remaining != Duration::new(0, 0) => Some(remaining),
+                        _ => return CommandResult::WouldBlock,
+                    },
+                    None => None,
+                };
+                thread_debug!(ThreadEventType::M_WAIT_PULL_COMMAND_RESULT, 0, self, _elapsed);
+                worker.waiters += 1;
+                match remaining {
+                    Some(remaining) => cvar.wait_timeout(worker, remaining).unwrap_or_else(PoisonError::into_inner).0.waiters -= 1,
+                    None => cvar.wait(worker).unwrap_or_else(PoisonError::into_inner).waiters -= 1,
+                }
+            } else {
+                return CommandResult::Err(worker.err.unwrap());
+            }
+        }
+    }
+    // ThreadToMain::pull_data for any stream; wakes with the error should the main thread give up
+    pub fn pull_stream_data(&mut self, stream_id: StreamID) -> ThreadData<AllocU8> {
+        loop {
+            let _elapsed = unguarded_debug_time!(self);
+            let &(ref lock, ref cvar) = &*self.queue;
+            let mut worker = lock_queue(lock);
+            #[cfg(test)]
+            {
+                if worker.killed {
+                    drop(worker);
+                    panic!("worker killed");
+                }
+            }
+            if worker.data_ready(stream_id) || worker.err.is_some() {
+                let ret = worker.pull_stream_data(stream_id);
+                thread_debug!(ThreadEventType::W_PULL_DATA, match ret {ThreadData::Data(ref d) => d.len(), ThreadData::Yield => 0, ThreadData::Eof=> 99999999, ThreadData::Err(_) => 0,}, self, _elapsed);
+                return ret;
+            } else {
+                thread_debug!(ThreadEventType::W_WAIT_PULL_DATA, 0, self, _elapsed);
+                worker.waiters += 1;
+                let _ign = cvar.wait(worker);
+                _ign.unwrap_or_else(PoisonError::into_inner).waiters -= 1;
+            }
+        }
+    }
+    // the literal mixer snapshots handed over by a worker that decodes the literals
+    pub fn push_mixer_snapshots(&mut self, snapshots: &mut MixerSnapshotQueue) {
+        snapshots.move_into(&mut lock_queue(&self.queue.0).mixer_snapshots);
+    }
+    pub fn take_mixer_snapshots(&mut self, out: &mut [MixerSnapshot]) -> usize {
+        lock_queue(&self.queue.0).mixer_snapshots.take(out)
+    }
+    pub fn mixer_snapshots_dropped(&self) -> u64 {
+        lock_queue(&self.queue.0).mixer_snapshots.dropped()
+    }
     pub fn free(&mut self, m8: &mut RepurposingAlloc<u8, AllocU8>, mcommand: &mut AllocCommand) {
         let &(ref lock, ref cvar) = &*self.queue;
-        let mut worker = lock.lock().unwrap();
+        let mut worker = lock_queue(lock);
         if worker.waiters != 0 {
             worker.broadcast_err_internal(ErrMsg::UnexpectedEof);
             cvar.notify_one();
         }
         worker.free(m8, mcommand);
     }
+    // makes the worker thread panic the next time it asks for data
+    #[cfg(test)]
+    pub fn kill(&mut self) {
+        let (ref lock, ref cvar) = *self.queue;
+        lock_queue(lock).killed = true;
+        cvar.notify_all();
+    }
+}
+// Held by the worker thread: should it unwind, the main thread is told with WorkerPanicked
+// rather than being left waiting on a queue nobody will fill.
+pub struct WorkerPanicGuard<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>>(pub MultiWorker<AllocU8, AllocCommand>);
+
+impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> Drop for WorkerPanicGuard<AllocU8, AllocCommand> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let (ref lock, ref cvar) = *self.0.queue;
+            lock_queue(lock).broadcast_err_internal(ErrMsg::WorkerPanicked);
+            cvar.notify_all();
+        }
+    }
 }
+
 impl<AllocU8:Allocator<u8>, AllocCommand: Allocator<StaticCommand>> PullAllocatedCommand<AllocU8, AllocCommand> for MultiWorker<AllocU8, AllocCommand> {
     fn pull_command_buf(&mut self,
                         output:&mut AllocatedMemoryPrefix<StaticCommand, AllocCommand>,
-                        consumed_data:&mut [AllocatedMemoryRange<u8, AllocU8>;NUM_DATA_BUFFERED],
+                        spans:&mut AllocatedMemoryPrefix<u8, AllocU8>,
+                        consumed_data:&mut [[AllocatedMemoryRange<u8, AllocU8>;NUM_DATA_BUFFERED];NUM_STREAMS],
                         pm:&mut [PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>; 2]) -> CommandResult {
-        self.pull(output, consumed_data, pm)
+        self.pull(output, spans, consumed_data, pm)
+    }
+    fn pull_command_buf_within(&mut self,
+                               output:&mut AllocatedMemoryPrefix<StaticCommand, AllocCommand>,
+                               spans:&mut AllocatedMemoryPrefix<u8, AllocU8>,
+                               consumed_data:&mut [[AllocatedMemoryRange<u8, AllocU8>;NUM_DATA_BUFFERED];NUM_STREAMS],
+                               pm:&mut [PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>; 2],
+                               timeout: Duration) -> CommandResult {
+        self.pull_within(output, spans, consumed_data, pm, Some(timeout))
     }
 }
 
@@ -161,28 +275,30 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> MainToThread<
         loop { // FIXME: should this loop forever? We should never run out of context map room
             let _elapsed = unguarded_debug_time!(self);
             let &(ref lock, ref cvar) = &*self.queue;
-            let mut worker = lock.lock().unwrap();
+            let mut worker = lock_queue(lock);
             if worker.cm_space_ready() {
                 thread_debug!(ThreadEventType::M_PUSH_CONTEXT_MAP, 1, self, _elapsed);
                 if worker.waiters != 0 {
                     cvar.notify_one();
                 }
                 return worker.push_context_map(cm);
+            } else if worker.err.is_some() {
+                return Ok(()); // nobody is left to take the map: the error reaches the caller on its next pull
             } else {
                 thread_debug!(ThreadEventType::M_WAIT_PUSH_CONTEXT_MAP, 0, self, _elapsed);
                 worker.waiters += 1;
                 let _ign = cvar.wait(worker); // always safe to loop around again
-                _ign.unwrap().waiters -= 1;
+                _ign.unwrap_or_else(PoisonError::into_inner).waiters -= 1;
             }
         }
     }
     #[inline(always)]
-    fn push(&mut self, data: &mut AllocatedMemoryRange<u8, AllocU8>) -> Result<(),()> {
+    fn push(&mut self, stream_id: StreamID, data: &mut AllocatedMemoryRange<u8, AllocU8>) -> Result<(),()> {
         let _elapsed = unguarded_debug_time!(self);
         let _len = data.len();
         let &(ref lock, ref cvar) = &*self.queue;
-        let mut worker = lock.lock().unwrap();
-        match worker.push(data) {
+        let mut worker = lock_queue(lock);
+        match worker.push(stream_id, data) {
             Ok(()) => {
                 thread_debug!(ThreadEventType::M_PUSH_DATA, _len, self, _elapsed);
                 if worker.waiters != 0 {
@@ -204,29 +320,10 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> MainToThread<
     #[inline(always)]
     fn pull(&mut self,
             output:&mut Self::CommandOutputType,
-            consumed_data:&mut [AllocatedMemoryRange<u8, AllocU8>;NUM_DATA_BUFFERED],
+            spans:&mut AllocatedMemoryPrefix<u8, AllocU8>,
+            consumed_data:&mut [[AllocatedMemoryRange<u8, AllocU8>;NUM_DATA_BUFFERED];NUM_STREAMS],
             pm:&mut [PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>; 2]) -> CommandResult {
-        loop {
-            let _elapsed = unguarded_debug_time!(self);
-            let &(ref lock, ref cvar) = &*self.queue;
-            let mut worker = lock.lock().unwrap();
-            if worker.result_ready() {
-                if worker.waiters != 0 {
-                    cvar.notify_one(); // FIXME: do we want to signal here?
-                }
-                let ret = worker.pull(output, consumed_data, pm);
-                thread_debug!(ThreadEventType::M_PULL_COMMAND_RESULT, output.len(), self, _elapsed);
-                return ret;
-            } else if worker.err.is_none() {
-                thread_debug!(ThreadEventType::M_WAIT_PULL_COMMAND_RESULT, 0, self, _elapsed);
-                worker.waiters += 1;
-                let _ign = cvar.wait(worker);
-                _ign.unwrap().waiters -= 1;
-                //return CommandResult::ProcessedData(AllocatedMemoryRange::<u8, AllocU8>::default()); // FIXME: busy wait
-            } else {
-                return CommandResult::Err(worker.err.unwrap());
-            }
-        }
+        self.pull_within(output, spans, consumed_data, pm, None)
     }
     fn broadcast_err(&mut self,
                      err:ErrMsg) {
@@ -239,21 +336,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
     const ISOLATED:bool = true;
     #[inline(always)]
     fn pull_data(&mut self) -> ThreadData<AllocU8> {
-        loop {
-            let _elapsed = unguarded_debug_time!(self);
-            let &(ref lock, ref cvar) = &*self.queue;
-            let mut worker = lock.lock().unwrap();
-            if worker.data_ready() {
-                let ret = worker.pull_data();
-                thread_debug!(ThreadEventType::W_PULL_DATA, match ret {ThreadData::Data(ref d) => d.len(), ThreadData::Yield => 0, ThreadData::Eof=> 99999999,}, self, _elapsed);
-                return ret;
-            } else {
-                thread_debug!(ThreadEventType::W_WAIT_PULL_DATA, 0, self, _elapsed);
-                worker.waiters += 1;
-                let _ign = cvar.wait(worker);
-                _ign.unwrap().waiters -= 1;
-            }
-        }
+        self.pull_stream_data(CMD_CODER as StreamID)
     }
     #[inline(always)]
     fn pull_context_map(&mut self,
@@ -261,7 +344,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
         loop {
             let _elapsed = unguarded_debug_time!(self);
             let &(ref lock, ref cvar) = &*self.queue;
-            let mut worker = lock.lock().unwrap();
+            let mut worker = lock_queue(lock);
             if worker.cm_ready() {
                 if worker.waiters != 0 {
                     cvar.notify_one();
@@ -272,7 +355,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
                 thread_debug!(ThreadEventType::W_WAIT_PULL_CONTEXT_MAP, 0, self, _elapsed);
                 worker.waiters += 1;
                 let _ign = cvar.wait(worker);
-                _ign.unwrap().waiters -= 1;
+                _ign.unwrap_or_else(PoisonError::into_inner).waiters -= 1;
             }
         }
     }
@@ -289,7 +372,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
         loop {
             let _elapsed = unguarded_debug_time!(self);
             let &(ref lock, ref cvar) = &*self.queue;
-            let mut worker = lock.lock().unwrap();
+            let mut worker = lock_queue(lock);
             if worker.result_space_ready() {
                 thread_debug!(ThreadEventType::W_PUSH_CMD, 1, self, _elapsed);
                 if worker.waiters != 0 {
@@ -300,7 +383,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
                 thread_debug!(ThreadEventType::W_WAIT_PUSH_CMD, 0, self, _elapsed);
                 worker.waiters += 1;
                 let _ign = cvar.wait(worker);
-                _ign.unwrap().waiters -= 1;
+                _ign.unwrap_or_else(PoisonError::into_inner).waiters -= 1;
             }
         }
     }
@@ -313,7 +396,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
         loop {
             let _elapsed = unguarded_debug_time!(self);
             let &(ref lock, ref cvar) = &*self.queue;
-            let mut worker = lock.lock().unwrap();
+            let mut worker = lock_queue(lock);
             if worker.result_space_ready() {
                 if worker.waiters != 0 {
                     cvar.notify_one();
@@ -324,7 +407,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
                 thread_debug!(ThreadEventType::W_WAIT_PUSH_CONSUMED_DATA, 0, self, _elapsed);
                 worker.waiters += 1;
                 let _ign = cvar.wait(worker);
-                _ign.unwrap().waiters -= 1;
+                _ign.unwrap_or_else(PoisonError::into_inner).waiters -= 1;
             }
         }
     }
@@ -334,7 +417,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
         loop {
             let _elapsed = unguarded_debug_time!(self);
             let &(ref lock, ref cvar) = &*self.queue;
-            let mut worker = lock.lock().unwrap();
+            let mut worker = lock_queue(lock);
             if worker.result_space_ready() {
                 if worker.waiters != 0 {
                     cvar.notify_one();
@@ -345,7 +428,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
                 thread_debug!(ThreadEventType::W_WAIT_PUSH_EOF, 1, self, _elapsed);
                 worker.waiters += 1;
                 let _ign = cvar.wait(worker);
-                _ign.unwrap().waiters -=1;
+                _ign.unwrap_or_else(PoisonError::into_inner).waiters -=1;
             }
         }
     }
@@ -355,9 +438,14 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
     }
 }
 
+// Commands are handed to the main thread in batches of up to CMD_BUFFER_SIZE, so the lock and wakeup
+// are paid once per batch rather than once per command; the main thread drains each pull from its
+// cmd_buffer. A batch goes out early for a context map, consumed data or eof, or once the bytes of
+// the literal spans in it fill the span buffer.
 pub struct BufferedMultiWorker<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> {
     pub worker: MultiWorker<AllocU8, AllocCommand>,
     buffer: AllocatedMemoryPrefix<StaticCommand, AllocCommand>,
+    spans: AllocatedMemoryPrefix<u8, AllocU8>,
     min_buffer_push_len: usize,
 }
 /*
@@ -377,19 +465,58 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> BufferedMulti
             min_buffer_push_len: 2,
             worker:worker,
             buffer: AllocatedMemoryPrefix::realloc(mc.alloc_cell(NUM_SERIAL_COMMANDS_BUFFERED), 0),
+            spans: AllocatedMemoryPrefix::default(),
         }
     }
-    fn force_push(&mut self, eof_inside: bool, data: &mut AllocatedMemoryRange<u8, AllocU8>, pm: Option<&mut PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>>) -> DivansOpResult {
-        if self.min_buffer_push_len * 2 < self.buffer.max_len(){
-            self.min_buffer_push_len <<= 2;
+    // lets push_literal_span ship decoded literals, in batches of up to span_len bytes
+    pub fn alloc_literal_spans(&mut self, m8: &mut AllocU8, span_len: usize) {
+        self.spans = AllocatedMemoryPrefix::realloc(m8.alloc_cell(span_len), 0);
+        let result_spans = AllocatedMemoryPrefix::realloc(m8.alloc_cell(span_len), 0);
+        lock_queue(&self.worker.queue.0).set_result_spans(result_spans);
+    }
+    // room left for literal bytes in the batch being gathered
+    pub fn span_space(&self) -> usize {
+        self.spans.0.len() - self.spans.1 as usize
+    }
+    // ships bytes the worker decoded as a literal span, remaining bytes of the literal still to come;
+    // they must fit in span_space
+    pub fn push_literal_span(&mut self, bytes: &[u8], high_entropy: bool, remaining: usize) -> DivansOutputResult {
+        let start = self.spans.1;
+        self.spans.0.slice_mut()[start as usize..start as usize + bytes.len()].clone_from_slice(bytes);
+        self.spans.1 += bytes.len() as u32;
+        self.buffer.0.slice_mut()[self.buffer.1 as usize] = Command::Literal(LiteralCommand{
+            data: LiteralSpan::Span{start: start, len: bytes.len() as u32, remaining: remaining as u32},
+            prob: FeatureFlagSliceType::default(),
+            high_entropy: high_entropy,
+        });
+        self.buffer.1 += 1;
+        if self.buffer.1 as usize == self.buffer.0.len() || self.span_space() == 0 {
+            DivansOutputResult::from(self.force_push(false, CMD_CODER as StreamID, &mut AllocatedMemoryRange::<u8, AllocU8>::default(), None))
+        } else {
+            DivansOutputResult::Success
+        }
+    }
+    // hands back the literal data the worker is done with, along with the commands so far, and waits
+    // for more
+    pub fn pull_literal_data(&mut self, consumed: &mut AllocatedMemoryRange<u8, AllocU8>) -> ThreadData<AllocU8> {
+        if let DivansOpResult::Failure(e) = self.force_push(false, LIT_CODER as StreamID, consumed, None) {
+            return ThreadData::Err(e);
+        }
+        self.worker.pull_stream_data(LIT_CODER as StreamID)
+    }
+    fn force_push(&mut self, eof_inside: bool, stream_id: StreamID, data: &mut AllocatedMemoryRange<u8, AllocU8>, pm: Option<&mut PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>>) -> DivansOpResult {
+        // the floor runs to twice CMD_BUFFER_SIZE so that halving it while the main thread is caught up
+        // still leaves full batches; push_cmd caps the batch itself at CMD_BUFFER_SIZE
+        if self.min_buffer_push_len < 2 * CMD_BUFFER_SIZE {
+            self.min_buffer_push_len = core::cmp::min(self.min_buffer_push_len << 2, 2 * CMD_BUFFER_SIZE);
         }
         loop {
             let _elapsed = unguarded_debug_time!(self.worker);
             let &(ref lock, ref cvar) = &*self.worker.queue;
-            let mut worker = lock.lock().unwrap();
+            let mut worker = lock_queue(lock);
             let mut did_notify = false;
             if data.0.len() != 0 { // before we get to sending commands, lets make sure data is taken care of
-                match worker.push_consumed_data(data, None) {
+                match worker.push_consumed_stream_data(stream_id, data) {
                     DivansOutputResult::Success => {
                         thread_debug!(ThreadEventType::W_PUSH_CONSUMED_DATA, data.0.len() as u32, self.worker, _elapsed);
                     },
@@ -397,7 +524,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> BufferedMulti
                         thread_debug!(ThreadEventType::W_WAIT_PUSH_CONSUMED_DATA, data.0.len(), self.worker, _elapsed);
                         worker.waiters += 1;
                         let _ign = cvar.wait(worker);
-                        _ign.unwrap().waiters -= 1;
+                        _ign.unwrap_or_else(PoisonError::into_inner).waiters -= 1;
                         continue;
                     }
                     DivansOutputResult::Failure(e) => {
@@ -409,7 +536,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> BufferedMulti
                     did_notify = true;
                 }
             }
-            if worker.result_multi_space_ready(self.buffer.1 as usize) {
+            if worker.result_multi_space_ready(self.buffer.1 as usize) && worker.result_span_space_ready(self.spans.1 as usize) {
                 thread_debug!(ThreadEventType::W_PUSH_CMD, self.buffer.1, self.worker, _elapsed);
                 if eof_inside {
                     worker.set_eof_hint(); // so other side gets more aggressive about pulling
@@ -417,7 +544,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> BufferedMulti
                 if worker.waiters != 0 && !did_notify{
                     cvar.notify_one();
                 }
-                let extant_space = worker.insert_results(&mut self.buffer, pm);
+                let extant_space = worker.insert_results(&mut self.buffer, &mut self.spans, pm);
                 if extant_space <= 16 {
                     self.min_buffer_push_len = core::cmp::max(self.min_buffer_push_len >> 1, 4);
                 }
@@ -427,7 +554,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> BufferedMulti
                 thread_debug!(ThreadEventType::W_WAIT_PUSH_CMD, self.buffer.1, self.worker, _elapsed);
                 worker.waiters += 1;
                 let _ign = cvar.wait(worker);
-                _ign.unwrap().waiters -= 1;
+                _ign.unwrap_or_else(PoisonError::into_inner).waiters -= 1;
             } else {
                 return DivansOpResult::Failure(worker.err.unwrap());
             }
@@ -436,6 +563,8 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> BufferedMulti
     pub fn free(&mut self, m8: &mut RepurposingAlloc<u8, AllocU8>, mc: &mut AllocCommand) {
         mc.free_cell(core::mem::replace(&mut self.buffer.0,
                                         AllocCommand::AllocatedMemory::default()));
+        m8.get_base_alloc().free_cell(core::mem::replace(&mut self.spans.0,
+                                                         AllocU8::AllocatedMemory::default()));
         self.worker.free(m8, mc);
     }
 }
@@ -465,9 +594,9 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
         self.buffer.0.slice_mut()[self.buffer.1 as usize] = static_command;
         self.buffer.1 += 1;
         if pm.is_some() {
-            DivansOutputResult::from(self.force_push(false, &mut AllocatedMemoryRange::<u8, AllocU8>::default(), pm))
-        } else if self.buffer.1 as usize == self.buffer.0.len() || self.buffer.1 as usize == self.min_buffer_push_len {
-            DivansOutputResult::from(self.force_push(false, &mut AllocatedMemoryRange::<u8, AllocU8>::default(), None))
+            DivansOutputResult::from(self.force_push(false, CMD_CODER as StreamID, &mut AllocatedMemoryRange::<u8, AllocU8>::default(), pm))
+        } else if self.buffer.1 as usize == self.buffer.0.len() || self.buffer.1 as usize == core::cmp::min(self.min_buffer_push_len, CMD_BUFFER_SIZE) {
+            DivansOutputResult::from(self.force_push(false, CMD_CODER as StreamID, &mut AllocatedMemoryRange::<u8, AllocU8>::default(), None))
         } else {
             //FIXME: why does this case not do anything
             DivansOutputResult::Success
@@ -478,15 +607,254 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
                     data:&mut AllocatedMemoryRange<u8, AllocU8>,
                     _m8: Option<&mut RepurposingAlloc<u8, AllocU8>>,
     ) -> DivansOutputResult {
-        DivansOutputResult::from(self.force_push(false, data, None))
+        DivansOutputResult::from(self.force_push(false, CMD_CODER as StreamID, data, None))
     }
    #[inline(always)]
     fn push_eof(&mut self,
     ) -> DivansOutputResult {
-        DivansOutputResult::from(self.force_push(true, &mut AllocatedMemoryRange::<u8, AllocU8>::default(), None))
+        DivansOutputResult::from(self.force_push(true, CMD_CODER as StreamID, &mut AllocatedMemoryRange::<u8, AllocU8>::default(), None))
     }
    #[inline(always)]
     fn broadcast_err(&mut self, err: ErrMsg) {
         self.worker.broadcast_err_internal(err, ThreadEventType::W_BROADCAST_ERR)
     }
 }
+
+// bytes gathered before a batch is handed to the crc thread
+const CRC_WORKER_BATCH: usize = 1 << 16;
+
+// Hashes the compressed stream for the crc trailer on a thread of its own, so the decoding thread
+// only copies what it consumes.  finish() waits for the thread to catch up and returns the crc.
+pub struct CrcWorker {
+    pending: Vec<u8>,
+    sender: mpsc::Sender<Vec<u8>>,
+    thread: thread::JoinHandle<SubDigest>,
+}
+
+impl CrcWorker {
+    // crc holds whatever was hashed before the worker took over, e.g. the header
+    pub fn new(mut crc: SubDigest) -> Self {
+        let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+        let thread = thread::spawn(move || {
+            for batch in receiver.iter() {
+                crc.write(&batch[..]);
+            }
+            crc
+        });
+        CrcWorker {
+            pending: Vec::with_capacity(CRC_WORKER_BATCH),
+            sender,
+            thread,
+        }
+    }
+    pub fn write(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+        if self.pending.len() >= CRC_WORKER_BATCH {
+            let batch = core::mem::replace(&mut self.pending, Vec::with_capacity(CRC_WORKER_BATCH));
+            // the thread only goes away with the receiver, which it holds until the sender drops
+            let _ = self.sender.send(batch);
+        }
+    }
+    pub fn finish(self) -> SubDigest {
+        let CrcWorker { pending, sender, thread } = self;
+        let _ = sender.send(pending);
+        drop(sender);
+        thread.join().expect("crc worker thread panicked")
+    }
+}
+
+#[cfg(all(test, feature="encoder", feature="decoder"))]
+mod test {
+    use std::vec::Vec;
+    use conformance::{conformance_commands, conformance_expected_output, ConformanceSlice};
+    use wire::CHECKSUM_LENGTH;
+    use interface::{
+        Command,
+        FeatureFlagSliceType,
+        LiteralCommand,
+        Decompressor,
+        DivansCompressorOptions,
+        DivansResult,
+        ErrMsg,
+    };
+    use test_support::{new_decompressor, encode, compress_hybrid, decode_with, DecodeConfig};
+    use alloc::{Allocator, SliceWrapper};
+    use alloc_stdlib::HeapAlloc;
+    use std::thread;
+    use slice_util::{AllocatedMemoryRange, AllocatedMemoryPrefix};
+    use threading::{MainToThread, ThreadToMain, CommandResult, StaticCommand, NUM_SERIAL_COMMANDS_BUFFERED,
+                    empty_prediction_mode_context_map};
+    use interface::{CopyCommand, DivansOutputResult, Nop};
+    use DecoderSpecialization;
+    use codec::CMD_BUFFER_SIZE;
+    use super::BufferedMultiWorker;
+
+    #[test]
+    fn test_threaded_crc() {
+        let mut commands = conformance_commands("mixed");
+        // several batches' worth of incompressible literals
+        let mut state = 7u32;
+        for _ in 0..12 {
+            let data = (0..16384).map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            }).collect();
+            commands.push(Command::Literal(LiteralCommand {
+                data: ConformanceSlice(data),
+                prob: FeatureFlagSliceType::<ConformanceSlice>::default(),
+                high_entropy: true,
+            }));
+        }
+        let expected = conformance_expected_output(&commands[..]).unwrap();
+        let stream = encode(&commands[..], 0).unwrap();
+        assert!(stream.len() > 2 << 16);
+        let mut bad_crc = stream.clone();
+        bad_crc[stream.len() - CHECKSUM_LENGTH] ^= 1;
+        for multithread in [false, true].iter() {
+            let mut config = DecodeConfig::new(*multithread);
+            config.threaded_crc = true;
+            let decode_threaded_crc = |stream: &[u8]| decode_with(stream, config, |decompressor| {
+                // the crc worker is started with the stream, if at all
+                assert_eq!(decompressor.set_threaded_crc(false), Err(ErrMsg::WrongInternalDecoderState));
+            }).into_output();
+            assert_eq!(decode_threaded_crc(&stream[..]), Ok(expected.clone()));
+            match decode_threaded_crc(&bad_crc[..]) {
+                Err(ErrMsg::BadChecksum(_, _)) => {},
+                res => panic!("{:?}", res),
+            }
+        }
+    }
+
+    #[test]
+    fn test_pull_timeout() {
+        use std::time::Duration;
+        let input = ::synthetic::Synthetic::Random.generate(11, 200000);
+        let stream = compress_hybrid(&input[..], DivansCompressorOptions::default());
+        for multithread in [false, true].iter() {
+            let mut decompressor = new_decompressor(false, *multithread);
+            // never waits, so decode only ever returns what the worker has ready
+            decompressor.set_pull_timeout(Some(Duration::new(0, 0))).unwrap();
+            let mut output = Vec::<u8>::new();
+            let mut buffer = [0u8; 4096];
+            let mut input_offset = 0usize;
+            loop {
+                let mut output_offset = 0usize;
+                let res = decompressor.decode(&stream[..], &mut input_offset, &mut buffer[..], &mut output_offset);
+                output.extend(&buffer[..output_offset]);
+                match res {
+                    DivansResult::Success => break,
+                    DivansResult::NeedsMoreOutput => {},
+                    DivansResult::NeedsMoreInput => panic!("truncated stream"),
+                    DivansResult::Failure(m) => panic!("{:?}", m),
+                }
+                assert_eq!(decompressor.set_pull_timeout(None), Err(ErrMsg::WrongInternalDecoderState));
+            }
+            decompressor.free();
+            assert_eq!(output, input);
+        }
+    }
+
+    #[test]
+    fn test_worker_panic() {
+        let input = ::synthetic::Synthetic::Random.generate(12, 200000);
+        let stream = compress_hybrid(&input[..], DivansCompressorOptions::default());
+        let mut decompressor = new_decompressor(false, true);
+        let mut buffer = [0u8; 4096];
+        let mut input_offset = 0usize;
+        let mut input_end = 0usize;
+        let failure = loop {
+            if input_end == 16384 {
+                // the worker is mid-stream, waiting on input that is yet to be handed over
+                match decompressor {
+                    ::divans_decompressor::DivansDecompressor::MultiDecode(ref mut process) => process.kill_worker(),
+                    _ => panic!("expected a multithreaded decoder"),
+                }
+            }
+            let mut output_offset = 0usize;
+            match decompressor.decode(&stream[..input_end], &mut input_offset, &mut buffer[..], &mut output_offset) {
+                DivansResult::Success => panic!("decoded with a dead worker"),
+                DivansResult::NeedsMoreOutput => {},
+                DivansResult::NeedsMoreInput => input_end = core::cmp::min(input_end + 4096, stream.len()),
+                DivansResult::Failure(m) => break m,
+            }
+        };
+        assert_eq!(failure, ErrMsg::WorkerPanicked);
+        let mut output_offset = 0usize;
+        match decompressor.decode(&stream[..], &mut input_offset, &mut buffer[..], &mut output_offset) {
+            DivansResult::Failure(m) => assert_eq!(m, ErrMsg::WorkerPanicked),
+            res => panic!("{:?}", res),
+        }
+        decompressor.free();
+    }
+
+    #[test]
+    fn test_small_commands_batched() {
+        const NUM_COMMANDS: u32 = 20000;
+        let mut mc = HeapAlloc::<StaticCommand>::new(StaticCommand::nop());
+        let mut producer = BufferedMultiWorker::<HeapAlloc<u8>, HeapAlloc<StaticCommand>>::new(&mut mc);
+        let mut consumer = producer.worker.clone();
+        let producer_thread = thread::spawn(move || {
+            let mut specialization = DecoderSpecialization::new();
+            let mut output_offset = 0usize;
+            let mut batch_lens = Vec::<usize>::new();
+            for index in 0..NUM_COMMANDS {
+                let mut cmd = Command::Copy(CopyCommand{distance: index + 1, num_bytes: 4});
+                let gathered = producer.buffer.1 as usize + 1;
+                assert_eq!(producer.push_cmd(&mut cmd, None, None, &mut specialization, &mut [], &mut output_offset),
+                           DivansOutputResult::Success);
+                if producer.buffer.1 == 0 {
+                    batch_lens.push(gathered);
+                }
+            }
+            if producer.buffer.1 != 0 {
+                batch_lens.push(producer.buffer.1 as usize);
+            }
+            assert_eq!(producer.push_eof(), DivansOutputResult::Success);
+            (producer, batch_lens)
+        });
+        let mut output = AllocatedMemoryPrefix::<StaticCommand, HeapAlloc<StaticCommand>>::realloc(
+            mc.alloc_cell(NUM_SERIAL_COMMANDS_BUFFERED), 0);
+        let mut spans = AllocatedMemoryPrefix::<u8, HeapAlloc<u8>>::default();
+        let mut consumed_data = [[AllocatedMemoryRange::<u8, HeapAlloc<u8>>::default(),
+                                  AllocatedMemoryRange::<u8, HeapAlloc<u8>>::default()],
+                                 [AllocatedMemoryRange::<u8, HeapAlloc<u8>>::default(),
+                                  AllocatedMemoryRange::<u8, HeapAlloc<u8>>::default()]];
+        let mut pm = [empty_prediction_mode_context_map(), empty_prediction_mode_context_map()];
+        let mut received = 0u32;
+        let mut pulls = 0u32;
+        loop {
+            output.1 = 0;
+            let res = consumer.pull(&mut output, &mut spans, &mut consumed_data, &mut pm);
+            pulls += 1;
+            for cmd in output.slice().iter() {
+                received += 1;
+                match *cmd {
+                    Command::Copy(ref copy) => assert_eq!(copy.distance, received),
+                    ref other => panic!("{:?}", other),
+                }
+            }
+            match res {
+                CommandResult::Ok => {},
+                CommandResult::Eof => break,
+                res => panic!("{:?}", res),
+            }
+        }
+        assert_eq!(received, NUM_COMMANDS);
+        // however eagerly the main thread pulls, no batch holds a single command
+        assert!(pulls <= NUM_COMMANDS / 2 + 1, "{} pulls", pulls);
+        let (mut producer, batch_lens) = producer_thread.join().unwrap();
+        assert_eq!(batch_lens.iter().sum::<usize>(), NUM_COMMANDS as usize);
+        for len in batch_lens.iter() {
+            assert!(*len <= CMD_BUFFER_SIZE, "{:?}", batch_lens);
+        }
+        // the first batches never leave more than 16 commands queued, so the floor only doubles from 2;
+        // once it has grown, every batch but the last holds a full cmd_buffer
+        assert_eq!(batch_lens[..4], [2, 4, 8, CMD_BUFFER_SIZE]);
+        for len in batch_lens[4..batch_lens.len() - 1].iter() {
+            assert_eq!(*len, CMD_BUFFER_SIZE);
+        }
+        let mut m8 = ::alloc_util::RepurposingAlloc::<u8, HeapAlloc<u8>>::new(HeapAlloc::<u8>::new(0));
+        mc.free_cell(core::mem::replace(&mut output.0, <HeapAlloc<StaticCommand> as Allocator<StaticCommand>>::AllocatedMemory::default()));
+        producer.free(&mut m8, &mut mc);
+    }
+}
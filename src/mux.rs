@@ -5,22 +5,29 @@ use core;
 use alloc::{Allocator, SliceWrapper, SliceWrapperMut};
 use super::slice_util;
 use slice_util::AllocatedMemoryRange;
+use wire;
 pub use interface::{StreamID, StreamMuxer, StreamDemuxer, NUM_STREAMS, STREAM_ID_MASK, ReadableBytes, WritableBytes};
+use interface::DuplicateWithAllocator;
+#[derive(Clone, Copy)]
 enum BytesToDeserialize {
     None,
     Some(StreamID, u32),
     Header0(StreamID),
     Header1(StreamID, u8),
 }
+#[derive(Clone, Copy)]
 enum StreamState {
     Running,
+    #[cfg(feature="encoder")]
+    Padding(usize), // filler bytes still owed ahead of the eof marker
     EofStart,
     EofMid,
     EofDone,
 }
 
-const MAX_HEADER_SIZE: usize = 3;
-const MAX_FLUSH_VARIANCE: usize = 131073;
+pub const MAX_HEADER_SIZE: usize = 3;
+#[cfg(feature="encoder")]
+const MAX_FLUSH_VARIANCE: u64 = 131073;
 
 
 
@@ -28,13 +35,17 @@ pub struct Mux<AllocU8:Allocator<u8> > {
    buf: [AllocatedMemoryRange<u8, AllocU8>; NUM_STREAMS as usize],
    cur_stream_bytes_avail: u32,
    cur_stream:StreamID,
-   last_flush:[usize; NUM_STREAMS as usize],
-   bytes_flushed: usize,
+   last_flush:[u64; NUM_STREAMS as usize],
+   bytes_flushed: u64,
    bytes_to_deserialize:BytesToDeserialize,
    eof: StreamState,
+   bytes_serialized: u64,
+   frame_alignment: usize,
+   frame_overhead: usize,
 }
 
-fn chunk_size(last_flushed:usize, lagging_stream: bool) -> usize {
+#[cfg(feature="encoder")]
+fn chunk_size(last_flushed:u64, lagging_stream: bool) -> usize {
     if lagging_stream  {
         return 16;
     }
@@ -46,12 +57,16 @@ fn chunk_size(last_flushed:usize, lagging_stream: bool) -> usize {
     }
     return 65536;
 }
+#[cfg(feature="encoder")]
 #[derive(Debug)]
 enum MuxSliceHeader {
     Var([u8;MAX_HEADER_SIZE]),
     Fixed([u8;1]),
 }
 pub const EOF_MARKER: [u8;3] = [0xff, 0xfe, 0xff];
+// a packet header no stream produces; skipped by the demuxer wherever a header may start
+pub const PAD_MARKER: u8 = 0x0f;
+#[cfg(feature="encoder")]
 fn get_code(stream_id: StreamID, bytes_to_write: usize, is_lagging: bool) -> (MuxSliceHeader, usize) {
     //eprintln!("want to: {},{},", stream_id, bytes_to_write);
     if is_lagging == false || bytes_to_write == 4096 || bytes_to_write == 16384 || bytes_to_write >= 65536 {
@@ -71,9 +86,10 @@ fn get_code(stream_id: StreamID, bytes_to_write: usize, is_lagging: bool) -> (Mu
     }
     assert!(bytes_to_write < 65536);
     //eprintln!("({},{})", stream_id, bytes_to_write);
+    let len_bytes = wire::u16_to_le((bytes_to_write - 1) as u16);
     let ret = [stream_id as u8,
-               (bytes_to_write - 1) as u8 & 0xff,
-               ((bytes_to_write - 1)>> 8) as u8 & 0xff];
+               len_bytes[0],
+               len_bytes[1]];
     return (MuxSliceHeader::Var(ret), bytes_to_write);
 }
 impl<AllocU8:Allocator<u8> > Mux<AllocU8> {
@@ -112,6 +128,26 @@ impl<AllocU8:Allocator<u8> > Default for Mux<AllocU8> {
             ],
             last_flush:[0;NUM_STREAMS as usize],
             bytes_flushed: 0,
+            bytes_serialized: 0,
+            frame_alignment: 0,
+            frame_overhead: 0,
+        }
+    }
+}
+impl<AllocU8:Allocator<u8> > DuplicateWithAllocator<AllocU8> for Mux<AllocU8> {
+    fn duplicate(&self, m8: &mut AllocU8) -> Self {
+        Mux::<AllocU8> {
+            buf:[self.buf[0].duplicate(m8),
+                 self.buf[1].duplicate(m8)],
+            cur_stream_bytes_avail: self.cur_stream_bytes_avail,
+            cur_stream: self.cur_stream,
+            last_flush: self.last_flush,
+            bytes_flushed: self.bytes_flushed,
+            bytes_to_deserialize: self.bytes_to_deserialize,
+            eof: self.eof,
+            bytes_serialized: self.bytes_serialized,
+            frame_alignment: self.frame_alignment,
+            frame_overhead: self.frame_overhead,
         }
     }
 }
@@ -161,6 +197,7 @@ impl<AllocU8: Allocator<u8> > StreamDemuxer<AllocU8> for Mux<AllocU8>{
 }
 
 
+#[cfg(feature="encoder")]
 impl<AllocU8:Allocator<u8> > StreamMuxer<AllocU8> for Mux<AllocU8> {
     fn write(&mut self, stream_id: StreamID, data: &[u8], m8: &mut AllocU8) -> usize {
         self.push_data(stream_id, data, m8);
@@ -191,6 +228,15 @@ impl<AllocU8:Allocator<u8> > StreamMuxer<AllocU8> for Mux<AllocU8> {
     fn flush(&mut self, output:&mut[u8]) -> usize {
         self.serialize_close(output)
     }
+    fn drain(&mut self, output:&mut[u8]) -> usize {
+        let ret = self.flush_internal(output);
+        self.bytes_serialized += ret as u64;
+        ret
+    }
+    fn set_frame_alignment(&mut self, alignment: usize, overhead: usize) {
+        self.frame_alignment = alignment;
+        self.frame_overhead = overhead;
+    }
     fn wrote_eof(&self) -> bool {
         self.is_eof()
     }
@@ -284,6 +330,7 @@ impl<AllocU8:Allocator<u8>> Mux<AllocU8> {
       (buf, write_cursor)
    }
    // copy the remaining data from a previous serialize
+   #[cfg(feature="encoder")]
    fn serialize_leftover(&mut self, output:&mut[u8]) -> usize {
        let to_copy = core::cmp::min(self.cur_stream_bytes_avail as usize, output.len());
        output.split_at_mut(to_copy).0.clone_from_slice(
@@ -292,6 +339,7 @@ impl<AllocU8:Allocator<u8>> Mux<AllocU8> {
        self.cur_stream_bytes_avail -= to_copy as u32;
        to_copy
    }
+    #[cfg(feature="encoder")]
     fn serialize_stream_id(&mut self, stream_id: StreamID, output: &mut [u8], output_offset: &mut usize, is_lagging: bool) {
         let buf_ent = &mut self.buf[usize::from(stream_id)];
         let populated_cursor = &mut buf_ent.1.end;
@@ -301,7 +349,7 @@ impl<AllocU8:Allocator<u8>> Mux<AllocU8> {
         // find the header and number of bytes that should be written to it
         let (header, mut num_bytes_should_write) = get_code(stream_id, *populated_cursor - *outputted_cursor, is_lagging);
         //eprint!("{}) header {:?} bytes: {}\n", stream_id, header, num_bytes_should_write);
-        self.bytes_flushed += num_bytes_should_write;
+        self.bytes_flushed += num_bytes_should_write as u64;
         assert!(*outputted_cursor >= MAX_HEADER_SIZE);
         match header {
             MuxSliceHeader::Var(hdr) =>  {
@@ -390,7 +438,7 @@ impl<AllocU8:Allocator<u8>> Mux<AllocU8> {
                     return ret + 1 + self.deserialize(input.split_at(1).1, m8);
                 },
                 BytesToDeserialize::Header1(stream_id, lsb) => {
-                    self.bytes_to_deserialize = BytesToDeserialize::Some(stream_id, (lsb as u32 | (input[0] as u32) << 8) + 1);
+                    self.bytes_to_deserialize = BytesToDeserialize::Some(stream_id, u32::from(wire::u16_from_le(&[lsb, input[0]])) + 1);
                     //eprint!("{}) Deserializing {}\n", stream_id, (lsb as u32 | (input[0] as u32) << 8) + 1);
                     //eprintln!("({},{}),", stream_id, (lsb as u32 | (input[0] as u32) << 8) + 1);
                     return ret + 1 + self.deserialize(input.split_at(1).1, m8);
@@ -408,6 +456,11 @@ impl<AllocU8:Allocator<u8>> Mux<AllocU8> {
                     ret += to_push.len();
                 }
                 BytesToDeserialize::None => {
+                    if input[0] == PAD_MARKER {
+                        input = input.split_at(1).1;
+                        ret += 1;
+                        continue;
+                    }
                     if input[0] == EOF_MARKER[0] || input[0] == EOF_MARKER[1] || input[0] == EOF_MARKER[2] {
                         if input[0] == EOF_MARKER[0] || match self.eof {
                             StreamState::Running => false,
@@ -426,7 +479,7 @@ impl<AllocU8:Allocator<u8>> Mux<AllocU8> {
                             return ret + 1 + self.deserialize(input.split_at(1).1, m8); 
                         }
                         count = 3;
-                        bytes_to_copy = (input[1] as u32 | (input[2] as u32) << 8) + 1;
+                        bytes_to_copy = u32::from(wire::u16_from_le(&input[1..3])) + 1;
                         //eprintln!("({},{}),", stream_id, bytes_to_copy);
                     } else {
                         count = 1;
@@ -442,6 +495,7 @@ impl<AllocU8:Allocator<u8>> Mux<AllocU8> {
         }
         ret
     }
+    #[cfg(feature="encoder")]
     pub fn serialize(&mut self, output:&mut [u8]) -> usize {
         let mut output_offset = 0usize;
         if self.cur_stream_bytes_avail != 0 {
@@ -474,19 +528,47 @@ impl<AllocU8:Allocator<u8>> Mux<AllocU8> {
              break;
            }
         }
+        self.bytes_serialized += output_offset as u64;
         output_offset
     }
+    // the filler that brings the frame, eof marker included, to a multiple of frame_alignment
+    #[cfg(feature="encoder")]
+    fn padding_needed(&self, pending: usize) -> usize {
+        if self.frame_alignment <= 1 {
+            return 0;
+        }
+        let frame_len = (self.frame_overhead + pending + EOF_MARKER.len()) as u64 + self.bytes_serialized;
+        let alignment = self.frame_alignment as u64;
+        ((alignment - frame_len % alignment) % alignment) as usize
+    }
+    #[cfg(feature="encoder")]
     pub fn serialize_close(&mut self, output:&mut [u8]) -> usize {
         match self.eof {
             StreamState::EofDone => return 0,
             _ => {},
         }
         let mut ret = self.flush_internal(output);
+        if output.len() == ret {
+            self.bytes_serialized += ret as u64;
+            return ret;
+        }
+        if let StreamState::Running = self.eof {
+            self.eof = StreamState::Padding(self.padding_needed(ret));
+        }
+        if let StreamState::Padding(remaining) = self.eof {
+            let to_write = core::cmp::min(remaining, output.len() - ret);
+            for item in output.split_at_mut(ret).1.split_at_mut(to_write).0.iter_mut() {
+                *item = PAD_MARKER;
+            }
+            ret += to_write;
+            self.eof = StreamState::Padding(remaining - to_write);
+        }
+        self.bytes_serialized += ret as u64;
         if output.len() == ret {
             return ret;
         }
         match self.eof {
-            StreamState::Running => {
+            StreamState::Padding(0) => {
                 output[ret] = EOF_MARKER[0];
                 ret += 1;
                 self.eof = StreamState::EofStart;
@@ -517,6 +599,7 @@ impl<AllocU8:Allocator<u8>> Mux<AllocU8> {
         }
         return ret;
     }
+    #[cfg(feature="encoder")]
     fn flush_internal(&mut self, output:&mut [u8]) -> usize {
         let mut output_offset = 0usize;
         if self.cur_stream_bytes_avail != 0 {
@@ -524,7 +607,7 @@ impl<AllocU8:Allocator<u8>> Mux<AllocU8> {
         }
         while output_offset < output.len() {
             let mut flushed_any = false;
-            let mut last_flush: Option<usize> = None;
+            let mut last_flush: Option<u64> = None;
             for (lf, buf) in self.last_flush.iter().zip(self.buf.iter()) {
                 let rc = buf.1.start;
                 let wc = buf.1.end;
@@ -575,6 +658,15 @@ impl<AllocU8: Allocator<u8> > Default for DevNull<AllocU8>{
         }
     }
 }
+impl<AllocU8: Allocator<u8> > DuplicateWithAllocator<AllocU8> for DevNull<AllocU8> {
+    fn duplicate(&self, _m8: &mut AllocU8) -> Self {
+        DevNull::<AllocU8> {
+            cursor: self.cursor,
+            empty: slice_util::AllocatedMemoryRange::<u8, AllocU8>::default(),
+            _placeholder: core::marker::PhantomData::<AllocU8>::default(),
+        }
+    }
+}
 impl<AllocU8: Allocator<u8> > StreamDemuxer<AllocU8> for DevNull<AllocU8>{
     fn write_linear(&mut self, data:&[u8], _m8: &mut AllocU8) -> usize {
         debug_assert_eq!(data.len(), 0);
@@ -640,6 +732,11 @@ impl<AllocU8:Allocator<u8> > StreamMuxer<AllocU8> for DevNull<AllocU8> {
     fn flush(&mut self, _output:&mut[u8]) -> usize {
         0
     }
+    fn drain(&mut self, _output:&mut[u8]) -> usize {
+        0
+    }
+    fn set_frame_alignment(&mut self, _alignment: usize, _overhead: usize) {
+    }
     fn wrote_eof(&self) -> bool {
         true
     }
@@ -0,0 +1,209 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Whole buffer compression and decompression for callers with everything in memory, who would rather
+// not drive the streaming encode/flush and decode loops themselves.
+#![cfg(feature="std")]
+use std::vec::Vec;
+use error::Error;
+#[cfg(feature="encoder")]
+use interface::{Compressor, DivansCompressorOptions, DivansOutputResult};
+#[cfg(any(feature="encoder", feature="decoder"))]
+use interface::{DivansResult, ErrMsg};
+#[cfg(feature="decoder")]
+use alloc_stdlib::HeapAlloc;
+#[cfg(feature="decoder")]
+use interface::{Decompressor, DefaultCDF16, Nop};
+#[cfg(feature="decoder")]
+use divans_decompressor::{DivansDecompressorFactory, DivansDecompressorFactoryStruct, StaticCommand};
+#[cfg(feature="encoder")]
+use writer::new_brotli_hybrid_compressor;
+
+// Compresses input with the brotli hybrid compressor.  Options the compressor would quietly clamp
+// are refused up front (see DivansCompressorOptions::validate).
+#[cfg(feature="encoder")]
+pub fn compress(input: &[u8], opts: DivansCompressorOptions) -> Result<Vec<u8>, Error> {
+    opts.validate().map_err(|m| Error::from(m).in_state("checking options"))?;
+    let mut compressor = new_brotli_hybrid_compressor(opts);
+    let mut input_offset = 0usize;
+    let mut unused = 0usize;
+    let mut result = Ok(());
+    while result.is_ok() && input_offset < input.len() {
+        match compressor.encode(input, &mut input_offset, &mut [], &mut unused) {
+            DivansResult::NeedsMoreInput | DivansResult::Success => {},
+            DivansResult::NeedsMoreOutput => result = Err(ErrMsg::BrotliInternalEncodeStreamNeedsOutputWithoutFlush),
+            DivansResult::Failure(m) => result = Err(m),
+        }
+    }
+    let mut ret = Vec::<u8>::new();
+    let mut buffer = [0u8; 4096];
+    while result.is_ok() {
+        let mut offset = 0usize;
+        let res = compressor.flush(&mut buffer[..], &mut offset);
+        ret.extend_from_slice(&buffer[..offset]);
+        match res {
+            DivansOutputResult::Success => break,
+            DivansOutputResult::NeedsMoreOutput => {},
+            DivansOutputResult::Failure(m) => result = Err(m),
+        }
+    }
+    compressor.free();
+    let output_len = ret.len() as u64;
+    result.map(|_| ret).map_err(|m| Error::from(m).in_state("compressing").at(input_offset as u64, output_len))
+}
+
+// Decompresses a complete stream, spliced or not.  Bytes past the end of the stream are an error,
+// as is a stream that ends early.
+#[cfg(feature="decoder")]
+pub fn decompress(stream: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut decompressor = DivansDecompressorFactoryStruct::<HeapAlloc<u8>,
+                                                             HeapAlloc<DefaultCDF16>,
+                                                             HeapAlloc<StaticCommand>>::new(
+        HeapAlloc::<u8>::new(0),
+        HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+        HeapAlloc::<StaticCommand>::new(StaticCommand::nop()),
+        false,
+        false);
+    let mut ret = Vec::<u8>::new();
+    let mut buffer = [0u8; 4096];
+    let mut input_offset = 0usize;
+    let result = loop {
+        let mut output_offset = 0usize;
+        let res = decompressor.decode(stream, &mut input_offset, &mut buffer[..], &mut output_offset);
+        ret.extend_from_slice(&buffer[..output_offset]);
+        match res {
+            DivansResult::Success => if input_offset == stream.len() {
+                break Ok(());
+            } else {
+                break Err(ErrMsg::TrailingInput(stream[input_offset]));
+            },
+            DivansResult::NeedsMoreOutput => {},
+            DivansResult::NeedsMoreInput => break Err(ErrMsg::TruncatedInput),
+            DivansResult::Failure(m) => break Err(m),
+        }
+    };
+    decompressor.free();
+    let output_len = ret.len() as u64;
+    result.map(|_| ret).map_err(|m| Error::from(m).in_state("decompressing").at(input_offset as u64, output_len))
+}
+
+// Decompresses a complete stream straight into output, which must be large enough to hold all of it,
+// and returns the bytes written.  Spares the caller the loop and the copy out of a bounce buffer, and
+// decodes contiguously (see DivansDecompressor::decode_contiguous): output running out is an error
+// right where it happens rather than a pause to resume from.  Errors as decompress does, and with
+// OutputTooSmall if the stream decodes to more than output holds.
+#[cfg(feature="decoder")]
+pub fn decompress_into(input: &[u8], output: &mut [u8]) -> Result<usize, Error> {
+    let mut decompressor = DivansDecompressorFactoryStruct::<HeapAlloc<u8>,
+                                                             HeapAlloc<DefaultCDF16>,
+                                                             HeapAlloc<StaticCommand>>::new(
+        HeapAlloc::<u8>::new(0),
+        HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+        HeapAlloc::<StaticCommand>::new(StaticCommand::nop()),
+        false,
+        false);
+    let mut input_offset = 0usize;
+    let mut output_offset = 0usize;
+    let result = match decompressor.decode_contiguous(input, &mut input_offset, output, &mut output_offset) {
+        DivansResult::Success => if input_offset == input.len() {
+            Ok(output_offset)
+        } else {
+            Err(ErrMsg::TrailingInput(input[input_offset]))
+        },
+        DivansResult::NeedsMoreOutput => Err(ErrMsg::OutputTooSmall),
+        DivansResult::NeedsMoreInput => Err(ErrMsg::TruncatedInput),
+        DivansResult::Failure(m) => Err(m),
+    };
+    decompressor.free();
+    result.map_err(|m| Error::from(m).in_state("decompressing").at(input_offset as u64, output_offset as u64))
+}
+
+#[cfg(test)]
+#[cfg(all(feature="encoder", feature="decoder"))]
+mod test {
+    use super::{compress, decompress_into, decompress};
+    use interface::{CompressorOption, DivansCompressorOptions, ErrMsg};
+    use error::ErrorKind;
+    use splice::splice;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_oneshot_roundtrip() {
+        let input = &include_bytes!("../testdata/alice29")[..32768];
+        let mut opts = DivansCompressorOptions::default();
+        opts.quality = Some(9);
+        let stream = compress(input, opts).unwrap();
+        assert!(stream.len() < input.len());
+        assert_eq!(&decompress(&stream[..]).unwrap()[..], input);
+        assert_eq!(decompress(&stream[..stream.len() - 1]).unwrap_err().code(), Some(ErrMsg::TruncatedInput));
+        let mut trailing = stream.clone();
+        trailing.push(7);
+        assert_eq!(decompress(&trailing[..]).unwrap_err().code(), Some(ErrMsg::TrailingInput(7)));
+        assert_eq!(&decompress(&compress(&[], opts).unwrap()[..]).unwrap()[..], &[] as &[u8]);
+        opts.window_size = Some(40);
+        let err = compress(input, opts).unwrap_err();
+        assert_eq!(err.code(), Some(ErrMsg::OptionOutOfRange(CompressorOption::WindowSize)));
+        assert_eq!(err.kind(), ErrorKind::of(ErrMsg::OptionOutOfRange(CompressorOption::WindowSize)));
+    }
+
+    #[test]
+    fn test_decompress_into() {
+        let input = &include_bytes!("../testdata/alice29")[..32768];
+        let stream = compress(input, DivansCompressorOptions::default()).unwrap();
+        let mut output = vec![0u8; input.len() + 100];
+        assert_eq!(decompress_into(&stream[..], &mut output[..]).unwrap(), input.len());
+        assert_eq!(&output[..input.len()], input);
+        let err = decompress_into(&stream[..], &mut output[..input.len() - 1]).unwrap_err();
+        assert_eq!(err.code(), Some(ErrMsg::OutputTooSmall));
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(decompress_into(&stream[..stream.len() - 1], &mut output[..]).unwrap_err().code(),
+                   Some(ErrMsg::TruncatedInput));
+        let mut trailing = stream.clone();
+        trailing.push(7);
+        assert_eq!(decompress_into(&trailing[..], &mut output[..]).unwrap_err().code(), Some(ErrMsg::TrailingInput(7)));
+        // stored frames too
+        let stored = compress(&input[..5], DivansCompressorOptions::default()).unwrap();
+        assert_eq!(decompress_into(&stored[..], &mut output[..5]).unwrap(), 5);
+        assert_eq!(&output[..5], &input[..5]);
+        assert_eq!(decompress_into(&compress(&[], DivansCompressorOptions::default()).unwrap()[..], &mut []).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_decompress_into_matches_streaming() {
+        let input = &include_bytes!("../testdata/alice29")[..];
+        let mut streams = Vec::new();
+        for &(len, quality, window_size) in [(65536usize, 9u16, 16i32), (100000, 10, 22), (4096, 5, 10), (30, 9, 16)].iter() {
+            let mut opts = DivansCompressorOptions::default();
+            opts.quality = Some(quality);
+            opts.window_size = Some(window_size);
+            streams.push(compress(&input[..len], opts).unwrap());
+        }
+        let spliced = splice(&streams[3][..], &streams[0][..]).unwrap();
+        streams.push(splice(&spliced[..], &streams[2][..]).unwrap());
+        for stream in streams.iter() {
+            let expected = decompress(&stream[..]).unwrap();
+            let mut output = vec![0u8; expected.len()];
+            assert_eq!(decompress_into(&stream[..], &mut output[..]).unwrap(), expected.len());
+            assert_eq!(output, expected);
+            // running out fails right there, with what fit matching the streaming decode
+            for &short in [1usize, 4095, expected.len() / 2].iter().filter(|&&short| short <= expected.len()) {
+                let mut output = vec![0u8; expected.len() - short];
+                let err = decompress_into(&stream[..], &mut output[..]).unwrap_err();
+                assert_eq!(err.code(), Some(ErrMsg::OutputTooSmall));
+                let written = err.output_offset().unwrap() as usize;
+                assert_eq!(&output[..written], &expected[..written]);
+            }
+        }
+    }
+}
@@ -0,0 +1,372 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// The digests a decoder can keep of its decompressed output (see
+// DivansDecompressor::set_output_digest), so callers checking a hash stored elsewhere need not
+// make a second pass over the data.
+use core;
+use core::hash::Hasher;
+use interface::OutputDigestType;
+use codec::decoder::{default_crc, SubDigest};
+
+const PRIME32_1: u64 = 0x9E37_79B1;
+const PRIME32_2: u64 = 0x85EB_CA77;
+const PRIME32_3: u64 = 0xC2B2_AE3D;
+const PRIME64_1: u64 = 0x9E37_79B1_85EB_CA87;
+const PRIME64_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const PRIME64_3: u64 = 0x1656_67B1_9E37_79F9;
+const PRIME64_4: u64 = 0x85EB_CA77_C2B2_AE63;
+const PRIME64_5: u64 = 0x27D4_EB2F_1656_67C5;
+const PRIME_MX1: u64 = 0x1656_6791_9E37_79F9;
+const PRIME_MX2: u64 = 0x9FB2_1C65_1E98_DF25;
+
+const SECRET: [u8; 192] = [
+    0xb8, 0xfe, 0x6c, 0x39, 0x23, 0xa4, 0x4b, 0xbe, 0x7c, 0x01, 0x81, 0x2c, 0xf7, 0x21, 0xad, 0x1c,
+    0xde, 0xd4, 0x6d, 0xe9, 0x83, 0x90, 0x97, 0xdb, 0x72, 0x40, 0xa4, 0xa4, 0xb7, 0xb3, 0x67, 0x1f,
+    0xcb, 0x79, 0xe6, 0x4e, 0xcc, 0xc0, 0xe5, 0x78, 0x82, 0x5a, 0xd0, 0x7d, 0xcc, 0xff, 0x72, 0x21,
+    0xb8, 0x08, 0x46, 0x74, 0xf7, 0x43, 0x24, 0x8e, 0xe0, 0x35, 0x90, 0xe6, 0x81, 0x3a, 0x26, 0x4c,
+    0x3c, 0x28, 0x52, 0xbb, 0x91, 0xc3, 0x00, 0xcb, 0x88, 0xd0, 0x65, 0x8b, 0x1b, 0x53, 0x2e, 0xa3,
+    0x71, 0x64, 0x48, 0x97, 0xa2, 0x0d, 0xf9, 0x4e, 0x38, 0x19, 0xef, 0x46, 0xa9, 0xde, 0xac, 0xd8,
+    0xa8, 0xfa, 0x76, 0x3f, 0xe3, 0x9c, 0x34, 0x3f, 0xf9, 0xdc, 0xbb, 0xc7, 0xc7, 0x0b, 0x4f, 0x1d,
+    0x8a, 0x51, 0xe0, 0x4b, 0xcd, 0xb4, 0x59, 0x31, 0xc8, 0x9f, 0x7e, 0xc9, 0xd9, 0x78, 0x73, 0x64,
+    0xea, 0xc5, 0xac, 0x83, 0x34, 0xd3, 0xeb, 0xc3, 0xc5, 0x81, 0xa0, 0xff, 0xfa, 0x13, 0x63, 0xeb,
+    0x17, 0x0d, 0xdd, 0x51, 0xb7, 0xf0, 0xda, 0x49, 0xd3, 0x16, 0x55, 0x26, 0x29, 0xd4, 0x68, 0x9e,
+    0x2b, 0x16, 0xbe, 0x58, 0x7d, 0x47, 0xa1, 0xfc, 0x8f, 0xf8, 0xb8, 0xd1, 0x7a, 0xd0, 0x31, 0xce,
+    0x45, 0xcb, 0x3a, 0x8f, 0x95, 0x16, 0x04, 0x28, 0xaf, 0xd7, 0xfb, 0xca, 0xbb, 0x4b, 0x40, 0x7e,
+];
+
+const STRIPE_LEN: usize = 64;
+const SECRET_CONSUME_RATE: usize = 8;
+const STRIPES_PER_BLOCK: usize = (SECRET.len() - STRIPE_LEN) / SECRET_CONSUME_RATE;
+const BUFFER_LEN: usize = 4 * STRIPE_LEN;
+const MIDSIZE_MAX: usize = 240;
+
+fn read64(data: &[u8], offset: usize) -> u64 {
+    let mut word = [0u8; 8];
+    word.clone_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(word)
+}
+
+fn read32(data: &[u8], offset: usize) -> u64 {
+    let mut word = [0u8; 4];
+    word.clone_from_slice(&data[offset..offset + 4]);
+    u64::from(u32::from_le_bytes(word))
+}
+
+fn mul128_fold64(a: u64, b: u64) -> u64 {
+    let product = u128::from(a) * u128::from(b);
+    (product as u64) ^ ((product >> 64) as u64)
+}
+
+fn xxh64_avalanche(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(PRIME64_2);
+    h ^= h >> 29;
+    h = h.wrapping_mul(PRIME64_3);
+    h ^ (h >> 32)
+}
+
+fn avalanche(mut h: u64) -> u64 {
+    h ^= h >> 37;
+    h = h.wrapping_mul(PRIME_MX1);
+    h ^ (h >> 32)
+}
+
+fn rrmxmx(mut h: u64, len: u64) -> u64 {
+    h ^= h.rotate_left(49) ^ h.rotate_left(24);
+    h = h.wrapping_mul(PRIME_MX2);
+    h ^= (h >> 35).wrapping_add(len);
+    h = h.wrapping_mul(PRIME_MX2);
+    h ^ (h >> 28)
+}
+
+fn mix16(data: &[u8], offset: usize, secret_offset: usize) -> u64 {
+    mul128_fold64(read64(data, offset) ^ read64(&SECRET, secret_offset),
+                  read64(data, offset + 8) ^ read64(&SECRET, secret_offset + 8))
+}
+
+// XXH3-64 with no seed of an input of at most MIDSIZE_MAX bytes
+fn hash_short(data: &[u8]) -> u64 {
+    let len = data.len();
+    if len == 0 {
+        return xxh64_avalanche(read64(&SECRET, 56) ^ read64(&SECRET, 64));
+    }
+    if len <= 3 {
+        let combined = (u64::from(data[0]) << 16) | (u64::from(data[len >> 1]) << 24)
+            | u64::from(data[len - 1]) | ((len as u64) << 8);
+        return xxh64_avalanche(combined ^ (read32(&SECRET, 0) ^ read32(&SECRET, 4)));
+    }
+    if len <= 8 {
+        let input = read32(data, len - 4).wrapping_add(read32(data, 0) << 32);
+        return rrmxmx(input ^ read64(&SECRET, 8) ^ read64(&SECRET, 16), len as u64);
+    }
+    if len <= 16 {
+        let lo = read64(data, 0) ^ read64(&SECRET, 24) ^ read64(&SECRET, 32);
+        let hi = read64(data, len - 8) ^ read64(&SECRET, 40) ^ read64(&SECRET, 48);
+        return avalanche((len as u64).wrapping_add(lo.swap_bytes()).wrapping_add(hi).wrapping_add(mul128_fold64(lo, hi)));
+    }
+    let mut acc = (len as u64).wrapping_mul(PRIME64_1);
+    if len <= 128 {
+        let rounds = (len - 1) / 32 + 1;
+        for round in (0..rounds).rev() {
+            acc = acc.wrapping_add(mix16(data, 16 * round, 32 * round));
+            acc = acc.wrapping_add(mix16(data, len - 16 * (round + 1), 32 * round + 16));
+        }
+        return avalanche(acc);
+    }
+    for round in 0..8 {
+        acc = acc.wrapping_add(mix16(data, 16 * round, 16 * round));
+    }
+    acc = avalanche(acc);
+    for round in 8..len / 16 {
+        acc = acc.wrapping_add(mix16(data, 16 * round, 16 * (round - 8) + 3));
+    }
+    avalanche(acc.wrapping_add(mix16(data, len - 16, 136 - 17)))
+}
+
+fn accumulate_stripe(acc: &mut [u64; 8], stripe: &[u8], secret_offset: usize) {
+    for lane in 0..8 {
+        let value = read64(stripe, 8 * lane);
+        let key = value ^ read64(&SECRET, secret_offset + 8 * lane);
+        acc[lane ^ 1] = acc[lane ^ 1].wrapping_add(value);
+        acc[lane] = acc[lane].wrapping_add((key & 0xffff_ffff).wrapping_mul(key >> 32));
+    }
+}
+
+fn scramble(acc: &mut [u64; 8]) {
+    for (lane, item) in acc.iter_mut().enumerate() {
+        let mut value = *item;
+        value ^= value >> 47;
+        value ^= read64(&SECRET, SECRET.len() - STRIPE_LEN + 8 * lane);
+        *item = value.wrapping_mul(PRIME32_1);
+    }
+}
+
+// Streaming XXH3-64 with no seed and the default secret, as XXH3_64bits computes it.  Input goes
+// through a buffer of four stripes, consumed only once more input follows, so that the last stripe
+// and inputs short enough for the one shot paths are still at hand for finish.
+#[derive(Clone, Copy)]
+pub struct Xxh3 {
+    acc: [u64; 8],
+    buffer: [u8; BUFFER_LEN],
+    buffered: usize,
+    stripes_in_block: usize,
+    total_len: u64,
+}
+
+impl Default for Xxh3 {
+    fn default() -> Self {
+        Xxh3 {
+            acc: [PRIME32_3, PRIME64_1, PRIME64_2, PRIME64_3, PRIME64_4, PRIME32_2, PRIME64_5, PRIME32_1],
+            buffer: [0u8; BUFFER_LEN],
+            buffered: 0,
+            stripes_in_block: 0,
+            total_len: 0,
+        }
+    }
+}
+
+impl core::fmt::Debug for Xxh3 {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "Xxh3({:016x})", self.finish())
+    }
+}
+
+fn consume_stripes(acc: &mut [u64; 8], stripes_in_block: &mut usize, data: &[u8]) {
+    for stripe in data.chunks(STRIPE_LEN) {
+        accumulate_stripe(acc, stripe, *stripes_in_block * SECRET_CONSUME_RATE);
+        *stripes_in_block += 1;
+        if *stripes_in_block == STRIPES_PER_BLOCK {
+            scramble(acc);
+            *stripes_in_block = 0;
+        }
+    }
+}
+
+impl Hasher for Xxh3 {
+    fn write(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+        while !data.is_empty() {
+            if self.buffered == BUFFER_LEN {
+                consume_stripes(&mut self.acc, &mut self.stripes_in_block, &self.buffer[..]);
+                self.buffered = 0;
+            }
+            let to_copy = core::cmp::min(BUFFER_LEN - self.buffered, data.len());
+            self.buffer[self.buffered..self.buffered + to_copy].clone_from_slice(&data[..to_copy]);
+            self.buffered += to_copy;
+            data = &data[to_copy..];
+        }
+    }
+    fn finish(&self) -> u64 {
+        if self.total_len <= MIDSIZE_MAX as u64 {
+            return hash_short(&self.buffer[..self.buffered]);
+        }
+        let mut acc = self.acc;
+        let mut stripes_in_block = self.stripes_in_block;
+        let mut last_stripe = [0u8; STRIPE_LEN];
+        if self.buffered >= STRIPE_LEN {
+            let whole = (self.buffered - 1) / STRIPE_LEN * STRIPE_LEN;
+            consume_stripes(&mut acc, &mut stripes_in_block, &self.buffer[..whole]);
+            last_stripe.clone_from_slice(&self.buffer[self.buffered - STRIPE_LEN..self.buffered]);
+        } else {
+            // the rest of the stripe is still at the end of the buffer from before
+            let carried = STRIPE_LEN - self.buffered;
+            last_stripe[..carried].clone_from_slice(&self.buffer[BUFFER_LEN - carried..]);
+            last_stripe[carried..].clone_from_slice(&self.buffer[..self.buffered]);
+        }
+        accumulate_stripe(&mut acc, &last_stripe[..], SECRET.len() - STRIPE_LEN - 7);
+        let mut result = self.total_len.wrapping_mul(PRIME64_1);
+        for pair in 0..4 {
+            result = result.wrapping_add(mul128_fold64(acc[2 * pair] ^ read64(&SECRET, 11 + 16 * pair),
+                                                       acc[2 * pair + 1] ^ read64(&SECRET, 11 + 16 * pair + 8)));
+        }
+        avalanche(result)
+    }
+}
+
+// only the hasher of kind is fed
+#[derive(Clone, Copy, Debug)]
+pub struct OutputDigest {
+    kind: OutputDigestType,
+    crc: SubDigest,
+    xxh3: Xxh3,
+}
+
+impl OutputDigest {
+    pub fn new(kind: OutputDigestType) -> Self {
+        OutputDigest {
+            kind,
+            crc: default_crc(),
+            xxh3: Xxh3::default(),
+        }
+    }
+    pub fn kind(&self) -> OutputDigestType {
+        self.kind
+    }
+}
+
+impl Hasher for OutputDigest {
+    fn write(&mut self, data: &[u8]) {
+        match self.kind {
+            OutputDigestType::Crc32c => self.crc.write(data),
+            OutputDigestType::Xxh3 => self.xxh3.write(data),
+        }
+    }
+    // the crc32c in the low 32 bits, or the whole XXH3-64
+    fn finish(&self) -> u64 {
+        match self.kind {
+            OutputDigestType::Crc32c => self.crc.finish(),
+            OutputDigestType::Xxh3 => self.xxh3.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::hash::Hasher;
+    use super::Xxh3;
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use codec::SubDigest;
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use splice::splice;
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use interface::{
+        DivansCompressorOptions,
+        ErrMsg,
+        OutputDigestType,
+    };
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use test_support::{new_decompressor, compress_hybrid, decode_with, DecodeConfig};
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use std::vec::Vec;
+
+    // the input xxhsum's sanity check hashes
+    fn sanity_buffer(len: usize) -> [u8; 2400] {
+        let mut ret = [0u8; 2400];
+        let mut generator = 2654435761u64;
+        for item in ret[..len].iter_mut() {
+            *item = (generator >> 56) as u8;
+            generator = generator.wrapping_mul(11400714785074694797);
+        }
+        ret
+    }
+
+    #[test]
+    fn test_xxh3_vectors() {
+        let buffer = sanity_buffer(2400);
+        let vectors = [(0usize, 0x2D06800538D394C2u64), (1, 0xC44BDFF4074EECDB), (6, 0x27B56A84CD2D7325),
+                       (12, 0xA713DAF0DFBB77E7), (24, 0xA3FE70BF9D3510EB), (48, 0x397DA259ECBA1F11),
+                       (80, 0xBCDEFBBB2C47C90A), (195, 0xCD94217EE362EC3A), (403, 0xCDEB804D65C6DEA4),
+                       (512, 0x617E49599013CB6B), (2048, 0xDD59E2C3A5F038E0), (2240, 0x6E73A90539CF2948),
+                       (2367, 0xCB37AEB9E5D361ED)];
+        for &(len, expected) in vectors.iter() {
+            let mut whole = Xxh3::default();
+            whole.write(&buffer[..len]);
+            assert_eq!(whole.finish(), expected, "len {}", len);
+            // fed in pieces straddling the buffer and block boundaries
+            for piece in [1usize, 7, 64, 255, 256, 1000].iter() {
+                let mut pieces = Xxh3::default();
+                for chunk in buffer[..len].chunks(*piece) {
+                    pieces.write(chunk);
+                }
+                assert_eq!(pieces.finish(), expected, "len {} in pieces of {}", len, piece);
+            }
+        }
+    }
+
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    fn decode_with_output_digest(stream: &[u8], kind: OutputDigestType, multithread: bool, output_chunk: usize) -> (Vec<u8>, Option<u64>) {
+        let mut config = DecodeConfig::new(multithread);
+        config.output_digest = Some(kind);
+        config.output_chunk = output_chunk;
+        let decoded = decode_with(stream, config, |decompressor| {
+            assert_eq!(decompressor.set_output_digest(None), Err(ErrMsg::WrongInternalDecoderState));
+        });
+        assert_eq!(decoded.result, Ok(()));
+        (decoded.output, decoded.output_digest)
+    }
+
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    #[test]
+    fn test_output_digest() {
+        use interface::STORED_MAGIC;
+        let first = ::synthetic::Synthetic::Random.generate(3, 5000);
+        let second = b"output digest ".repeat(400);
+        let opts = DivansCompressorOptions::default();
+        let a = compress_hybrid(&first[..], opts);
+        let b = compress_hybrid(&second[..], opts);
+        let tiny = compress_hybrid(&first[..20], opts);
+        assert_eq!(&tiny[..STORED_MAGIC.len()], &STORED_MAGIC[..]);
+        let spliced = splice(&a[..], &b[..]).unwrap();
+        let mut expected = first.clone();
+        expected.extend(&second[..]);
+        let decompressor = new_decompressor(false, false);
+        assert_eq!(decompressor.output_digest(), None);
+        decompressor.free();
+        for (stream, output) in [(&a[..], &first[..]), (&tiny[..], &first[..20]), (&spliced[..], &expected[..])].iter() {
+            let mut crc = SubDigest::default();
+            crc.write(output);
+            let mut xxh3 = Xxh3::default();
+            xxh3.write(output);
+            for multithread in [false, true].iter() {
+                for output_chunk in [1usize, 333, 1 << 16].iter() {
+                    assert_eq!(decode_with_output_digest(stream, OutputDigestType::Crc32c, *multithread, *output_chunk),
+                               (output.to_vec(), Some(crc.finish())));
+                    assert_eq!(decode_with_output_digest(stream, OutputDigestType::Xxh3, *multithread, *output_chunk),
+                               (output.to_vec(), Some(xxh3.finish())));
+                }
+            }
+        }
+    }
+}
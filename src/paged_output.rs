@@ -0,0 +1,229 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Scatter-gather output: rather than one contiguous output slice, the codec fills a chain of
+// fixed-size pages requested from a PageAllocator, and each page is handed back as soon as it is
+// full, so it may go straight to writev or an io_uring submission while the next one fills.
+use core;
+use alloc::{SliceWrapper, SliceWrapperMut};
+use interface::{Command, Compressor, Decompressor, DivansResult, DivansOutputResult};
+
+pub trait PageAllocator {
+    type Page: SliceWrapperMut<u8>;
+    // a fresh page to write into; returning None makes the current call report NeedsMoreOutput
+    fn alloc_page(&mut self) -> Option<Self::Page>;
+    // a page the codec is finished with, holding used bytes: every page but the last is full
+    fn emit_page(&mut self, page: Self::Page, used: usize);
+}
+
+pub struct PagedOutput<P: PageAllocator> {
+    pages: P,
+    current: Option<P::Page>,
+    current_offset: usize,
+}
+
+impl<P: PageAllocator> PagedOutput<P> {
+    pub fn new(allocator: P) -> Self {
+        PagedOutput {
+            pages: allocator,
+            current: None,
+            current_offset: 0,
+        }
+    }
+    pub fn page_allocator(&mut self) -> &mut P {
+        &mut self.pages
+    }
+    // emits the partially filled page, if any, and returns the allocator
+    pub fn into_inner(mut self) -> P {
+        self.emit_partial_page();
+        self.pages
+    }
+    fn emit_partial_page(&mut self) {
+        if let Some(page) = self.current.take() {
+            if self.current_offset != 0 {
+                self.pages.emit_page(page, self.current_offset);
+            }
+        }
+        self.current_offset = 0;
+    }
+    // Calls op on the unused tail of the current page until it stops asking for output.
+    // Full pages are emitted along the way.  None means the allocator ran dry.
+    fn fill<Ret: Copy+Into<DivansResult>, F: FnMut(&mut [u8], &mut usize) -> Ret>(
+        &mut self, mut op: F) -> Option<Ret> {
+        loop {
+            if self.current.is_none() {
+                match self.pages.alloc_page() {
+                    Some(page) => {
+                        assert!(!page.slice().is_empty());
+                        self.current = Some(page);
+                        self.current_offset = 0;
+                    },
+                    None => return None,
+                }
+            }
+            let ret;
+            let page_full;
+            {
+                let page = self.current.as_mut().unwrap().slice_mut();
+                ret = op(page, &mut self.current_offset);
+                page_full = self.current_offset == page.len();
+            }
+            if page_full {
+                let page = self.current.take().unwrap();
+                self.pages.emit_page(page, self.current_offset);
+                self.current_offset = 0;
+            }
+            match ret.into() {
+                DivansResult::NeedsMoreOutput => {},
+                _ => return Some(ret),
+            }
+        }
+    }
+    pub fn encode<C: Compressor>(&mut self,
+                                 compressor: &mut C,
+                                 input: &[u8],
+                                 input_offset: &mut usize) -> DivansResult {
+        self.fill(|page, page_offset| compressor.encode(input, input_offset, page, page_offset))
+            .unwrap_or(DivansResult::NeedsMoreOutput)
+    }
+    pub fn encode_commands<C: Compressor, SliceType: SliceWrapper<u8>+Default>(
+        &mut self,
+        compressor: &mut C,
+        input: &[Command<SliceType>],
+        input_offset: &mut usize) -> DivansOutputResult {
+        self.fill(|page, page_offset| compressor.encode_commands(input, input_offset, page, page_offset))
+            .unwrap_or(DivansOutputResult::NeedsMoreOutput)
+    }
+    pub fn encode_command_iter<C: Compressor, SliceType: SliceWrapper<u8>+Default,
+                               Commands: Iterator<Item=Command<SliceType>>>(
+        &mut self,
+        compressor: &mut C,
+        input: &mut core::iter::Peekable<Commands>) -> DivansOutputResult {
+        self.fill(|page, page_offset| compressor.encode_command_iter(input, page, page_offset))
+            .unwrap_or(DivansOutputResult::NeedsMoreOutput)
+    }
+    // once the compressor reports Success the last, partially filled page is emitted too
+    pub fn flush<C: Compressor>(&mut self, compressor: &mut C) -> DivansOutputResult {
+        let ret = self.fill(|page, page_offset| compressor.flush(page, page_offset))
+            .unwrap_or(DivansOutputResult::NeedsMoreOutput);
+        if let DivansOutputResult::Success = ret {
+            self.emit_partial_page();
+        }
+        ret
+    }
+    // once the decompressor reports Success the last, partially filled page is emitted too
+    pub fn decode<D: Decompressor>(&mut self,
+                                   decompressor: &mut D,
+                                   input: &[u8],
+                                   input_offset: &mut usize) -> DivansResult {
+        let ret = self.fill(|page, page_offset| decompressor.decode(input, input_offset, page, page_offset))
+            .unwrap_or(DivansResult::NeedsMoreOutput);
+        if let DivansResult::Success = ret {
+            self.emit_partial_page();
+        }
+        ret
+    }
+}
+
+#[cfg(all(test, feature="std", feature="encoder", feature="decoder"))]
+mod test {
+    use std::vec::Vec;
+    use alloc_stdlib::HeapAlloc;
+    use conformance::{conformance_commands, conformance_expected_output, ConformanceSlice};
+    use super::{PageAllocator, PagedOutput};
+    use divans_compressor::DivansCompressorFactoryStruct;
+    use interface::{
+        DivansCompressorFactory,
+        DivansCompressorOptions,
+        DivansOutputResult,
+        DivansResult,
+        DefaultCDF16,
+    };
+    use test_support::{new_decompressor, encode_with_options};
+
+    // hands out pages of a fixed size and records what comes back, in order
+    struct TestPages {
+        page_size: usize,
+        pages_left: usize,
+        emitted: Vec<(Vec<u8>, usize)>,
+    }
+
+    impl PageAllocator for TestPages {
+        type Page = ConformanceSlice;
+        fn alloc_page(&mut self) -> Option<ConformanceSlice> {
+            if self.pages_left == 0 {
+                return None;
+            }
+            self.pages_left -= 1;
+            Some(ConformanceSlice(vec![0u8; self.page_size]))
+        }
+        fn emit_page(&mut self, page: ConformanceSlice, used: usize) {
+            self.emitted.push((page.0, used));
+        }
+    }
+
+    impl TestPages {
+        fn gather(&self) -> Vec<u8> {
+            for &(ref page, used) in self.emitted[..self.emitted.len() - 1].iter() {
+                assert_eq!(used, page.len());
+            }
+            self.emitted.iter().flat_map(|&(ref page, used)| page[..used].iter().cloned()).collect()
+        }
+    }
+
+    #[test]
+    fn test_paged_output_roundtrip() {
+        let cmds = conformance_commands("mixed");
+        let contiguous = encode_with_options(&cmds[..], DivansCompressorOptions::default()).unwrap();
+        let mut opts = DivansCompressorOptions::default();
+        opts.window_size = Some(16);
+        let mut compressor = DivansCompressorFactoryStruct::<HeapAlloc<u8>, HeapAlloc<DefaultCDF16>>::new(
+            HeapAlloc::<u8>::new(0),
+            HeapAlloc::<u32>::new(0),
+            HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+            opts,
+            ());
+        // the allocator runs dry partway through and is topped up
+        let mut paged = PagedOutput::new(TestPages{page_size: 7, pages_left: 3, emitted: Vec::new()});
+        let mut command_offset = 0usize;
+        while command_offset != cmds.len() {
+            match paged.encode_commands(&mut compressor, &cmds[..], &mut command_offset) {
+                DivansOutputResult::Failure(m) => panic!("{:?}", m),
+                DivansOutputResult::NeedsMoreOutput => paged.page_allocator().pages_left += 3,
+                DivansOutputResult::Success => {},
+            }
+        }
+        loop {
+            match paged.flush(&mut compressor) {
+                DivansOutputResult::Failure(m) => panic!("{:?}", m),
+                DivansOutputResult::NeedsMoreOutput => paged.page_allocator().pages_left += 3,
+                DivansOutputResult::Success => break,
+            }
+        }
+        compressor.free();
+        let pages = paged.into_inner();
+        assert!(pages.emitted.len() > 1);
+        assert_eq!(pages.gather(), contiguous);
+
+        let mut decompressor = new_decompressor(false, false);
+        let mut paged = PagedOutput::new(TestPages{page_size: 64, pages_left: usize::max_value(), emitted: Vec::new()});
+        let mut input_offset = 0usize;
+        match paged.decode(&mut decompressor, &contiguous[..], &mut input_offset) {
+            DivansResult::Success => {},
+            res => panic!("{:?}", res),
+        }
+        decompressor.free();
+        assert_eq!(paged.into_inner().gather(), conformance_expected_output(&cmds[..]).unwrap());
+    }
+}
@@ -5,30 +5,42 @@ use ::interface;
 use ::interface::{NewWithAllocator};
 use ::DecoderSpecialization;
 use ::codec;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, PoisonError};
 use divans_decompressor::HeaderParser;
 use super::mux::{Mux,DevNull};
-use codec::decoder::{DecoderResult, DivansDecoderCodec};
+use codec::decoder::{DecoderResult, DivansDecoderCodec, SubDigest};
+use codec::weights::MixerSnapshot;
+use output_digest::OutputDigest;
 use threading::{ThreadToMainDemuxer, ThreadToMain};
-use multithreading::{BufferedMultiWorker, MultiWorker};
+use multithreading::{BufferedMultiWorker, MultiWorker, CrcWorker, WorkerPanicGuard};
+use codec::span::SpanWorker;
 
-use ::interface::{DivansResult, DivansInputResult, ErrMsg};
+use ::interface::{DivansResult, DivansInputResult, ErrMsg, DecodedStreamOptions, DecodeStats, StallWatchdog};
 use ::ArithmeticEncoderOrDecoder;
 use ::alloc::{Allocator};
 use std::thread;
+use std::time::Duration;
+#[cfg(feature="trace")]
+use std::vec::Vec;
+#[cfg(feature="trace")]
+use codec::trace::TraceLog;
 use super::divans_decompressor::StaticCommand;
 
+type LiteralWorker<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand> = SpanWorker<interface::DefaultCDF16, AllocU8, AllocCDF16, AllocCommand, DefaultDecoder>;
+
 pub struct ParallelDivansProcess<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                                  AllocU8:Allocator<u8>,
                                  AllocCDF16:Allocator<interface::DefaultCDF16>,
-                                 AllocCommand:Allocator<StaticCommand>> {
+                                 AllocCommand:Allocator<StaticCommand>,
+                                 D:Hasher+Default=SubDigest> {
     codec: Arc<Mutex<Option<codec::DivansCodec<DefaultDecoder,
                                          DecoderSpecialization,
-                                         ThreadToMainDemuxer<AllocU8, BufferedMultiWorker<AllocU8, AllocCommand>>,
+                                         ThreadToMainDemuxer<AllocU8, LiteralWorker<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand>>,
                                          DevNull<AllocU8>,
                                          interface::DefaultCDF16,
                                          AllocU8,
-                                         AllocCDF16>>>,
+                                         AllocCDF16,
+                                         D>>>,
                >,
     worker: MultiWorker<AllocU8, AllocCommand>,
     literal_decoder: Option<DivansDecoderCodec<interface::DefaultCDF16,
@@ -36,17 +48,24 @@ pub struct ParallelDivansProcess<DefaultDecoder: ArithmeticEncoderOrDecoder + Ne
                                                AllocCDF16,
                                                AllocCommand,
                                                DefaultDecoder,
-                                               Mux<AllocU8>>>,
-    bytes_encoded: usize,
+                                               Mux<AllocU8>,
+                                               D>>,
+    bytes_encoded: u64,
     mcommand: AllocCommand,
+    stall_watchdog: StallWatchdog,
+    finished: bool, // decode returned Success, so the worker is done with the codec
+    next_stream: Option<HeaderParser<AllocU8, AllocCDF16, AllocCommand>>,
+    pull_timeout: Option<Duration>,
+    failure: Option<ErrMsg>, // every later decode returns it: a failed worker may have left the codec half updated
 }
 
 
 impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + interface::BillingCapability + Send + 'static,
      AllocU8:Allocator<u8> + Send + 'static,
      AllocCDF16:Allocator<interface::DefaultCDF16> + Send + 'static,
-     AllocCommand:Allocator<StaticCommand> + Send + 'static>
-    ParallelDivansProcess<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand>
+     AllocCommand:Allocator<StaticCommand> + Send + 'static,
+     D:Hasher+Default+Send+'static>
+    ParallelDivansProcess<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, D>
     where <AllocU8 as Allocator<u8>>::AllocatedMemory: core::marker::Send,
           <AllocCDF16 as Allocator<interface::DefaultCDF16>>::AllocatedMemory: core::marker::Send,
           <AllocCommand as Allocator<StaticCommand>>::AllocatedMemory: core::marker::Send,
@@ -63,24 +82,27 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
         let mcdf16:AllocCDF16;
         let mut mc: AllocCommand;
         let raw_header:[u8; interface::HEADER_LENGTH];
-        let skip_crc:bool;
+        let checksum_policy;
+        let window = header.take_window(window_size);
         m8 = header.m8.take().unwrap();
-        raw_header = header.header;
-        skip_crc = header.skip_crc;
+        raw_header = header.canonical_header();
+        let next_stream = header.next_stream();
+        checksum_policy = header.checksum_policy;
         mcdf16 = header.mcdf16.take().unwrap();
         mc = header.mcommand.take().unwrap();
         //update this if you change the SelectedArithmeticDecoder macro
         let cmd_decoder = DefaultDecoder::new(&mut m8);
         let lit_decoder = DefaultDecoder::new(&mut m8);
-        let linear_input_bytes = ThreadToMainDemuxer::<AllocU8,BufferedMultiWorker<AllocU8, AllocCommand>>::new(
-            BufferedMultiWorker::<AllocU8, AllocCommand>::new(&mut mc));
+        let linear_input_bytes = ThreadToMainDemuxer::<AllocU8, LiteralWorker<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand>>::new(
+            SpanWorker::new(BufferedMultiWorker::<AllocU8, AllocCommand>::new(&mut mc)));
         let mut codec = codec::DivansCodec::<DefaultDecoder,
                                              DecoderSpecialization,
-                                             ThreadToMainDemuxer<AllocU8, BufferedMultiWorker<AllocU8, AllocCommand>>,
+                                             ThreadToMainDemuxer<AllocU8, LiteralWorker<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand>>,
                                              DevNull<AllocU8>,
                                              interface::DefaultCDF16,
                                              AllocU8,
-                                             AllocCDF16>::new(m8,
+                                             AllocCDF16,
+                                             D>::new_with_window(m8,
                                                               mcdf16,
                                                               cmd_decoder,
                                                               lit_decoder,
@@ -92,16 +114,45 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
                                                               None,
                                                           true,
                                                               codec::StrideSelection::UseBrotliRec,
-                                                              skip_crc);
-        if !skip_crc {
-            codec.get_crc().write(&raw_header[..]);
+                                                              !checksum_policy.hashes_stream(),
+                                                              window);
+        codec.set_checksum_policy(checksum_policy);
+        codec.set_stream_features(raw_header[interface::HEADER_FEATURE_OFFSET]);
+        codec.set_extended_stream_features(interface::extended_stream_features(&raw_header[..]));
+        codec.set_freeze_adaptation_after(interface::freeze_adaptation_after(&raw_header[..]));
+        codec.set_priors_reset_interval(interface::priors_reset_interval(&raw_header[..]));
+        codec.set_extended_stream_features2(interface::extended_stream_features2(&raw_header[..]));
+        codec.set_framing_tag(header.framing);
+        // parse_header refused checksums this build does not know
+        if let Some(checksum) = interface::checksum_type(&raw_header[..]) {
+            codec.set_checksum_type(checksum);
+        }
+        codec.set_cancel_token(header.cancel.clone());
+        codec.set_compact_priors(header.compact_priors);
+        if let Some(key) = header.authentication_key {
+            codec.set_authentication_key(&key[..]);
+        }
+        if checksum_policy.hashes_stream() {
+            codec.get_crc().write(&header.header[..header.header_length()]);
+        }
+        #[cfg(feature="trace")]
+        {
+            codec.trace().input_pos = header.header_length() as u64;
         }
-        let main_thread_codec = codec.fork(&mut mc);
-        assert_eq!(*codec.get_crc(), main_thread_codec.crc);
-        let multi_worker = (codec.demuxer().worker).worker.clone();
+        let mut main_thread_codec = codec.fork(&mut mc);
+        main_thread_codec.observe_header(&raw_header[..]);
+        main_thread_codec.paranoid = header.paranoid;
+        if header.threaded_crc && checksum_policy.hashes_stream() && main_thread_codec.crc.wide.is_none() {
+            main_thread_codec.crc_worker = Some(CrcWorker::new(main_thread_codec.crc.crc));
+        }
+        assert_eq!(codec.get_crc().crc, main_thread_codec.crc.crc);
+        codec.demuxer().worker.hand_literals_to_worker(&mut main_thread_codec);
+        let multi_worker = codec.demuxer().worker.worker.worker.clone();
         let thread_codec = Arc::new(Mutex::new(Some(codec)));
         let worker_codec = thread_codec.clone();
+        let panic_guard = WorkerPanicGuard(multi_worker.clone());
         thread::spawn(move || {
+            let _panic_guard = panic_guard;
             let mut guard = thread_codec.lock().unwrap();
             if let Some(ref mut process_codec) = *guard {
                 let mut unused_out = 0usize;
@@ -131,19 +182,151 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
                 panic!("Thread started with None-process_codec")
             }
         });
-        ParallelDivansProcess::<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand> {
+        ParallelDivansProcess::<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, D> {
             mcommand:mc,
             codec:worker_codec,
             literal_decoder:Some(main_thread_codec),
             bytes_encoded:0,
+            stall_watchdog: StallWatchdog::default(),
+            finished: false,
             worker: multi_worker,
+            next_stream: Some(next_stream),
+            pull_timeout: header.pull_timeout,
+            failure: None,
+        }
+    }
+    pub fn options(&self) -> Option<DecodedStreamOptions> {
+        match self.literal_decoder {
+            Some(ref literal_decoder) => literal_decoder.options(),
+            None => None,
+        }
+    }
+    // before Success the worker's block switch counts are only included if it is not holding the codec
+    pub fn stats(&self) -> DecodeStats {
+        let mut ret = match self.literal_decoder {
+            Some(ref literal_decoder) => literal_decoder.stats(),
+            None => DecodeStats::default(),
+        };
+        let guard = if self.finished {
+            self.codec.lock().ok()
+        } else {
+            self.codec.try_lock().ok()
+        };
+        if let Some(guard) = guard {
+            if let Some(ref codec) = *guard {
+                let (command_block_switches, distance_block_switches) = codec.block_switch_counts();
+                ret.command_block_switches = command_block_switches;
+                ret.distance_block_switches = distance_block_switches;
+            }
+        }
+        ret
+    }
+    // the worker decodes the literals, so it has the snapshots
+    pub fn take_mixer_snapshots(&mut self, out: &mut [MixerSnapshot]) -> usize {
+        self.worker.take_mixer_snapshots(out)
+    }
+    pub fn mixer_snapshots_dropped(&self) -> u64 {
+        self.worker.mixer_snapshots_dropped()
+    }
+    // the default before Success unless the worker is not holding the codec
+    pub fn mixer_snapshot(&self) -> MixerSnapshot {
+        let guard = if self.finished {
+            self.codec.lock().ok()
+        } else {
+            self.codec.try_lock().ok()
+        };
+        if let Some(guard) = guard {
+            if let Some(ref codec) = *guard {
+                if let Some(snapshot) = codec.cross_command_state.demuxer.worker.mixer_snapshot() {
+                    return snapshot;
+                }
+            }
+        }
+        MixerSnapshot::default()
+    }
+    pub fn expected_crc(&self) -> Option<u32> {
+        match self.literal_decoder {
+            Some(ref literal_decoder) => literal_decoder.expected_crc(),
+            None => None,
+        }
+    }
+    pub fn expected_checksum(&self) -> Option<u64> {
+        match self.literal_decoder {
+            Some(ref literal_decoder) => literal_decoder.expected_checksum(),
+            None => None,
+        }
+    }
+    pub fn output_needed(&self) -> usize {
+        match self.literal_decoder {
+            Some(ref literal_decoder) => literal_decoder.output_needed(),
+            None => 0,
+        }
+    }
+    pub fn take_window(&mut self) -> Option<AllocU8::AllocatedMemory> {
+        match self.literal_decoder {
+            Some(ref mut literal_decoder) => literal_decoder.take_window(),
+            None => None,
+        }
+    }
+    pub fn window(&self) -> Option<(&[u8], usize)> {
+        match self.literal_decoder {
+            Some(ref literal_decoder) => literal_decoder.window(),
+            None => None,
+        }
+    }
+    pub fn take_next_stream(&mut self) -> Option<HeaderParser<AllocU8, AllocCDF16, AllocCommand>> {
+        match self.literal_decoder {
+            Some(ref literal_decoder) if literal_decoder.another_stream_follows() => self.next_stream.take(),
+            _ => None,
+        }
+    }
+    pub fn output_digest(&self) -> Option<&OutputDigest> {
+        self.next_stream.as_ref().and_then(|next_stream| next_stream.output_digest.as_ref())
+    }
+    pub fn output_digest_mut(&mut self) -> Option<&mut OutputDigest> {
+        self.next_stream.as_mut().and_then(|next_stream| next_stream.output_digest.as_mut())
+    }
+    pub fn next_stream(&self) -> Option<&HeaderParser<AllocU8, AllocCDF16, AllocCommand>> {
+        self.next_stream.as_ref()
+    }
+    pub fn next_stream_mut(&mut self) -> Option<&mut HeaderParser<AllocU8, AllocCDF16, AllocCommand>> {
+        self.next_stream.as_mut()
+    }
+    pub fn take_digest(&mut self) -> D {
+        if let Some(ref mut literal_decoder) = self.literal_decoder {
+            return literal_decoder.take_digest();
+        }
+        if let Ok(mut guard) = self.codec.try_lock() {
+            if let Some(ref mut codec) = *guard {
+                return codec.take_digest();
+            }
         }
+        D::default()
+    }
+    // the worker's command states are only included once it has released the codec
+    #[cfg(feature="trace")]
+    pub fn take_trace(&mut self) -> Vec<u8> {
+        let mut log = TraceLog::default();
+        if let Ok(mut guard) = self.codec.try_lock() {
+            if let Some(ref mut codec) = *guard {
+                log.merge(codec.trace());
+            }
+        }
+        if let Some(ref mut literal_decoder) = self.literal_decoder {
+            log.merge(&mut literal_decoder.trace);
+        }
+        log.take()
+    }
+    #[cfg(test)]
+    pub fn kill_worker(&mut self) {
+        self.worker.kill();
     }
     pub fn free_ref(&mut self) {
         self.worker.broadcast_err(ErrMsg::UnexpectedEof); // in case we still have a worker holding the lock, not done
-        if let Some(ref mut codec) = *self.codec.lock().unwrap() {
+        if let Some(ref mut codec) = *self.codec.lock().unwrap_or_else(PoisonError::into_inner) {
             let lit_decoder = core::mem::replace(&mut self.literal_decoder, None);
-            if let Some(ld) = lit_decoder {
+            if let Some(mut ld) = lit_decoder {
+                codec.cross_command_state.demuxer.worker.take_literals_back(&mut ld);
                 codec.join(ld, &mut self.mcommand);
             }
             codec.cross_command_state.demuxer.worker.free(codec.cross_command_state.thread_ctx.m8().as_mut().unwrap(), &mut self.mcommand);
@@ -154,9 +337,10 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
     pub fn free(mut self) -> (AllocU8, AllocCDF16, AllocCommand) {
         use codec::NUM_ARITHMETIC_CODERS;
         self.worker.broadcast_err(ErrMsg::UnexpectedEof); // in case we still have a worker holding the lock, not done
-        if let Some(mut codec) = core::mem::replace(&mut *self.codec.lock().unwrap(), None) {
+        if let Some(mut codec) = core::mem::replace(&mut *self.codec.lock().unwrap_or_else(PoisonError::into_inner), None) {
             let lit_decoder = core::mem::replace(&mut self.literal_decoder, None);
-            if let Some(ld) = lit_decoder {
+            if let Some(mut ld) = lit_decoder {
+                codec.cross_command_state.demuxer.worker.take_literals_back(&mut ld);
                 codec.join(ld, &mut self.mcommand);
             }
             for index in 0..NUM_ARITHMETIC_CODERS {
@@ -174,6 +358,41 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
               input_offset:&mut usize,
               output:&mut [u8],
               output_offset: &mut usize) -> DivansResult {
+        if let Some(err) = self.failure {
+            return DivansResult::Failure(err);
+        }
+        let start_offsets = (*input_offset, *output_offset);
+        let had_work = *input_offset < input.len() && *output_offset < output.len();
+        #[cfg(feature="std")]
+        let start = std::time::Instant::now();
+        let step = self.decode_step(input, input_offset, output, output_offset);
+        #[cfg(feature="std")]
+        {
+            if let Some(ref mut literal_decoder) = self.literal_decoder {
+                literal_decoder.stats.add_decode_time(&start);
+            }
+        }
+        let result = match step {
+            DecoderResult::Processed(result) => result,
+            // no stall: the worker is still busy with the input it was handed
+            DecoderResult::WouldBlock => return DivansResult::NeedsMoreOutput,
+            DecoderResult::Yield => unreachable!(), // we are not marked cooperative
+        };
+        if let DivansResult::Success = result {
+            self.finished = true;
+        }
+        let progressed = start_offsets != (*input_offset, *output_offset);
+        let result = self.stall_watchdog.check(result, progressed, had_work);
+        if let DivansResult::Failure(err) = result {
+            self.failure = Some(err);
+        }
+        result
+    }
+    fn decode_step(&mut self,
+              input:&[u8],
+              input_offset:&mut usize,
+              output:&mut [u8],
+              output_offset: &mut usize) -> DecoderResult {
         let old_output_offset = *output_offset;
         if let Some(literal_decoder) =  self.literal_decoder.as_mut() {
             loop {
@@ -181,27 +400,25 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
                                                            input,
                                                            input_offset) {
                     DivansInputResult::Success => {},
-                    need_something => return DivansResult::from(need_something),
+                    need_something => return DecoderResult::Processed(DivansResult::from(need_something)),
                 }
                 if literal_decoder.commands_or_data_to_receive() {
                     break; // we have successfully delivered a buffer to our worker and then can, at worst pull the result
                 }
             }
-            let retval = literal_decoder.decode_process_output(
+            #[cfg(feature="trace")]
+            {
+                literal_decoder.trace.output_pos = self.bytes_encoded.wrapping_sub(old_output_offset as u64);
+            }
+            let retval = literal_decoder.decode_process_output_within(
                 &mut self.worker,
                 output,
-                output_offset);
-            self.bytes_encoded += *output_offset - old_output_offset;
-            match retval {
-                DecoderResult::Processed(divans_retval) => {
-                    return divans_retval;
-                },
-                DecoderResult::Yield => {
-                    unreachable!(); // we are not marked cooperative
-                },
-            }
+                output_offset,
+                self.pull_timeout);
+            self.bytes_encoded += (*output_offset - old_output_offset) as u64;
+            retval
         } else {
-            return DivansResult::Failure(ErrMsg::DecodingDecoderAlreadyFreed);
+            DecoderResult::Processed(DivansResult::Failure(ErrMsg::DecodingDecoderAlreadyFreed))
         }
     }
 }
@@ -0,0 +1,38 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+//! The stable subset of the crate: options, the streaming encoder and decoder, the one-shot
+//! helpers, the error type and the format constants.  `use divans::prelude::*;` covers most callers.
+//!
+//! Everything else, the codec state machine above all, may change between minor releases.  It is
+//! public only with the `internals` feature; a default build cannot name it:
+#![cfg_attr(not(feature="internals"), doc = "```compile_fail,E0432\nuse divans::DivansCodec;\n```")]
+
+pub use interface::{DivansCompressorOptions, BrotliCompressionSetting, FormatVersion, ContentHint, ErrMsg,
+                    CompressorOption, MAGIC_NUMBER, max_compressed_size, framing_overhead};
+pub use codec::StrideSelection;
+#[cfg(feature="std")]
+pub use error::{Error, ErrorKind};
+#[cfg(all(feature="std", feature="encoder"))]
+pub use oneshot::compress;
+#[cfg(all(feature="std", feature="decoder"))]
+pub use oneshot::{decompress, decompress_into};
+#[cfg(all(feature="std", feature="encoder"))]
+pub use reader::DivansBrotliHybridCompressorReader;
+#[cfg(all(feature="std", feature="encoder"))]
+pub use writer::DivansBrotliHybridCompressorWriter;
+#[cfg(all(feature="std", feature="decoder"))]
+pub use reader::DivansDecompressorReader;
+#[cfg(all(feature="std", feature="decoder"))]
+pub use writer::DivansDecompressorWriter;
@@ -56,6 +56,20 @@ impl PriorMultiIndex for (usize, usize, usize, usize) {
     fn num_dimensions() -> usize { 4usize }
 }
 
+// Asks for the cache line holding item ahead of its use, so the load of a CDF picked by a context
+// that is known early overlaps the work before it.  A no-op where there is no prefetch instruction.
+#[cfg(all(not(feature="safe"), target_arch="x86_64"))]
+#[inline(always)]
+pub fn prefetch<T>(item: &T) {
+    unsafe {
+        core::arch::x86_64::_mm_prefetch::<{core::arch::x86_64::_MM_HINT_T0}>(item as *const T as *const i8);
+    }
+}
+#[cfg(not(all(not(feature="safe"), target_arch="x86_64")))]
+#[inline(always)]
+pub fn prefetch<T>(_item: &T) {
+}
+
 pub trait PriorCollection<T: BaseCDF + Default, AllocT: Allocator<T>, B: Clone> {
     fn name() -> Option<&'static str> { None }
 
@@ -68,6 +82,8 @@ pub trait PriorCollection<T: BaseCDF + Default, AllocT: Allocator<T>, B: Clone>
     fn index_to_billing_type(index: usize) -> B;
 
     fn get<I: PriorMultiIndex>(&mut self, billing: B, index: I) -> &mut T;
+    // prefetches what get would return for the same arguments
+    fn prefetch<I: PriorMultiIndex>(&self, billing: B, index: I);
     fn get_with_raw_index(&self, billing: B, index: usize) -> &T;
     fn get_with_raw_index_mut(&mut self, billing: B, index: usize) -> &mut T;
     fn summarize_speed_costs(&self); // may want to return a struct rather than just printing out
@@ -121,6 +137,13 @@ macro_rules! define_prior_struct {
                 debug_assert!(linearized_index < Self::NUM_ALL_PRIORS);
                 &mut self.priors.slice_mut()[linearized_index]
             }
+            #[inline(always)]
+            fn prefetch<I: PriorMultiIndex>(&self, _billing: $billing_type, index: I) {
+                let linearized_index = define_prior_struct_helper_collapse_index!(&_billing; index; $($args),*);
+                if let Some(prior) = self.priors.slice().get(linearized_index) {
+                    ::priors::prefetch(prior);
+                }
+            }
             // TODO: technically this does not depend on the template paramters.
             fn num_prior(_billing: &$billing_type) -> usize {
                 define_prior_struct_unary_helper!(product; _billing; $($args),*) as usize
@@ -0,0 +1,330 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Trained prior tables in a file of their own, so a model can ship as an asset and load on any
+// platform.  Every integer is little endian:
+//   PRIORS_MODEL_MAGIC, format version (u8), CDF backend (u8), table count (u16),
+//   per table: name length (u8), name (utf-8), CDF count (u32), per CDF its 16 cumulative values (u16 each),
+//   crc32c of everything before it (u32)
+// The CDF values mean something only to the CDF backend that learned them, so a model is only
+// good for builds with the same DefaultInternalCDF16 (see interface::CDF_BACKEND).
+use core;
+use codec::crc32::{crc32c_init, crc32c_update};
+use interface::{ErrMsg, CDF_BACKEND};
+use probability::{Prob, CDF16};
+use wire::{u16_from_le, u32_from_le};
+#[cfg(feature="std")]
+use std::vec::Vec;
+#[cfg(feature="std")]
+use wire::{u16_to_le, u32_to_le};
+
+pub const PRIORS_MODEL_MAGIC: [u8; 4] = [b'd', b'v', b'p', b'm'];
+pub const PRIORS_MODEL_VERSION: u8 = 1;
+// magic, version, backend and table count
+const MODEL_HEADER_LENGTH: usize = 8;
+const CDF_LENGTH: usize = 32;
+const MODEL_CHECKSUM_LENGTH: usize = 4;
+
+// one named table of a model: cdf(index) holds the 16 cumulative values of its index'th CDF
+#[derive(Clone, Copy, Debug)]
+pub struct PriorsTable<'a> {
+    pub name: &'a str,
+    data: &'a [u8],
+}
+
+impl<'a> PriorsTable<'a> {
+    pub fn len(&self) -> usize {
+        self.data.len() / CDF_LENGTH
+    }
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+    pub fn cdf(&self, index: usize) -> [Prob; 16] {
+        let mut ret = [0 as Prob; 16];
+        for (value, bytes) in ret.iter_mut().zip(self.data[index * CDF_LENGTH..(index + 1) * CDF_LENGTH].chunks(2)) {
+            *value = u16_from_le(bytes) as Prob;
+        }
+        ret
+    }
+}
+
+// A view of a serialized model.  Nothing is checked until validate, which the other calls expect
+// to have passed: they may panic on bytes that would fail it.
+#[derive(Clone, Copy, Debug)]
+pub struct PriorsModel<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> PriorsModel<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        PriorsModel {
+            bytes,
+        }
+    }
+    // Checks the model is whole, undamaged and learned by this build's CDF backend: TruncatedInput if
+    // it ends early, BadChecksum if its checksum does not match, UnsupportedPriorsModel for another
+    // format version or backend and PriorsModelMalformed for anything else out of place.
+    pub fn validate(&self) -> Result<(), ErrMsg> {
+        if self.bytes.len() < MODEL_HEADER_LENGTH + MODEL_CHECKSUM_LENGTH {
+            return Err(if PRIORS_MODEL_MAGIC.starts_with(&self.bytes[..core::cmp::min(4, self.bytes.len())]) {
+                ErrMsg::TruncatedInput
+            } else {
+                ErrMsg::PriorsModelMalformed
+            });
+        }
+        if self.bytes[..4] != PRIORS_MODEL_MAGIC {
+            return Err(ErrMsg::PriorsModelMalformed);
+        }
+        // the layout of the tables walks the model to its end, so a short model reads as truncated
+        // rather than as damaged
+        let mut offset = MODEL_HEADER_LENGTH;
+        for _ in 0..self.num_tables() {
+            offset = self.table_end(offset).ok_or(ErrMsg::TruncatedInput)?;
+        }
+        if offset + MODEL_CHECKSUM_LENGTH > self.bytes.len() {
+            return Err(ErrMsg::TruncatedInput);
+        }
+        let stored = u32_from_le(&self.bytes[offset..]);
+        let computed = crc32c_update(crc32c_init(), &self.bytes[..offset]);
+        if stored != computed {
+            return Err(ErrMsg::BadChecksum(u64::from(computed) << 32, u64::from(stored) << 32));
+        }
+        if offset + MODEL_CHECKSUM_LENGTH != self.bytes.len() {
+            return Err(ErrMsg::PriorsModelMalformed);
+        }
+        if self.version() != PRIORS_MODEL_VERSION || self.backend() != CDF_BACKEND {
+            return Err(ErrMsg::UnsupportedPriorsModel(self.version(), self.backend()));
+        }
+        for table in self.tables() {
+            for index in 0..table.len() {
+                if !valid_cdf(&table.cdf(index)) {
+                    return Err(ErrMsg::PriorsModelMalformed);
+                }
+            }
+        }
+        let mut offset = MODEL_HEADER_LENGTH;
+        for _ in 0..self.num_tables() {
+            let name_length = usize::from(self.bytes[offset]);
+            if core::str::from_utf8(&self.bytes[offset + 1..offset + 1 + name_length]).is_err() {
+                return Err(ErrMsg::PriorsModelMalformed);
+            }
+            offset = self.table_end(offset).unwrap();
+        }
+        Ok(())
+    }
+    pub fn version(&self) -> u8 {
+        self.bytes[4]
+    }
+    // the interface::CDF_BACKEND of the build that wrote the model
+    pub fn backend(&self) -> u8 {
+        self.bytes[5]
+    }
+    pub fn num_tables(&self) -> usize {
+        usize::from(u16_from_le(&self.bytes[6..]))
+    }
+    pub fn tables(&self) -> PriorsTableIter<'a> {
+        PriorsTableIter {
+            bytes: self.bytes,
+            offset: MODEL_HEADER_LENGTH,
+            remaining: self.num_tables(),
+        }
+    }
+    pub fn table(&self, name: &str) -> Option<PriorsTable<'a>> {
+        self.tables().find(|table| table.name == name)
+    }
+    // where the table at offset ends, if the model is long enough to hold it
+    fn table_end(&self, offset: usize) -> Option<usize> {
+        let name_length = usize::from(*self.bytes.get(offset)?);
+        let count_offset = offset + 1 + name_length;
+        if count_offset + 4 > self.bytes.len() {
+            return None;
+        }
+        let end = (u32_from_le(&self.bytes[count_offset..]) as usize).checked_mul(CDF_LENGTH)?.checked_add(count_offset + 4)?;
+        if end > self.bytes.len() {
+            return None;
+        }
+        Some(end)
+    }
+}
+
+fn valid_cdf(cdf: &[Prob; 16]) -> bool {
+    cdf[15] > 0 && cdf[0] >= 0 && cdf.windows(2).all(|pair| pair[0] <= pair[1])
+}
+
+pub struct PriorsTableIter<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    remaining: usize,
+}
+
+impl<'a> Iterator for PriorsTableIter<'a> {
+    type Item = PriorsTable<'a>;
+    fn next(&mut self) -> Option<PriorsTable<'a>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let name_length = usize::from(self.bytes[self.offset]);
+        let name = core::str::from_utf8(&self.bytes[self.offset + 1..self.offset + 1 + name_length]).unwrap_or("");
+        let count_offset = self.offset + 1 + name_length;
+        let end = count_offset + 4 + u32_from_le(&self.bytes[count_offset..]) as usize * CDF_LENGTH;
+        let data = &self.bytes[count_offset + 4..end];
+        self.offset = end;
+        Some(PriorsTable {
+            name,
+            data,
+        })
+    }
+}
+
+// Builds a model out of the CDFs of this build, one named table at a time.
+#[cfg(feature="std")]
+pub struct PriorsModelWriter {
+    bytes: Vec<u8>,
+    num_tables: u16,
+}
+
+#[cfg(feature="std")]
+impl Default for PriorsModelWriter {
+    fn default() -> Self {
+        PriorsModelWriter::new()
+    }
+}
+
+#[cfg(feature="std")]
+impl PriorsModelWriter {
+    pub fn new() -> Self {
+        let mut bytes = Vec::<u8>::new();
+        bytes.extend(&PRIORS_MODEL_MAGIC[..]);
+        bytes.extend(&[PRIORS_MODEL_VERSION, CDF_BACKEND, 0, 0]);
+        PriorsModelWriter {
+            bytes,
+            num_tables: 0,
+        }
+    }
+    // fails with PriorsModelMalformed for an empty name or one over 255 bytes, a name already
+    // used, or once the model holds 65535 tables
+    pub fn add_table<Cdf:CDF16>(&mut self, name: &str, cdfs: &[Cdf]) -> Result<(), ErrMsg> {
+        if name.is_empty() || name.len() > usize::from(u8::MAX) || self.num_tables == u16::MAX
+            || cdfs.len() > u32::MAX as usize {
+            return Err(ErrMsg::PriorsModelMalformed);
+        }
+        if PriorsModel::new(&self.bytes[..]).table(name).is_some() {
+            return Err(ErrMsg::PriorsModelMalformed);
+        }
+        self.bytes.push(name.len() as u8);
+        self.bytes.extend(name.as_bytes());
+        self.bytes.extend(&u32_to_le(cdfs.len() as u32));
+        for cdf in cdfs.iter() {
+            for symbol in 0..16 {
+                self.bytes.extend(&u16_to_le(cdf.cdf(symbol) as u16));
+            }
+        }
+        self.num_tables += 1;
+        let count = u16_to_le(self.num_tables);
+        self.bytes[6..8].clone_from_slice(&count[..]);
+        Ok(())
+    }
+    pub fn finish(mut self) -> Vec<u8> {
+        let crc = crc32c_update(crc32c_init(), &self.bytes[..]);
+        self.bytes.extend(&u32_to_le(crc));
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature="std")]
+mod test {
+    use super::*;
+    use interface::DefaultCDF16;
+    use probability::{BaseCDF, Speed};
+    fn trained_cdfs(count: usize) -> Vec<DefaultCDF16> {
+        (0..count).map(|index| {
+            let mut cdf = DefaultCDF16::default();
+            for step in 0..index * 3 {
+                cdf.blend(((index + step * step) & 0xf) as u8, Speed::MED);
+            }
+            cdf
+        }).collect()
+    }
+    fn model_bytes() -> Vec<u8> {
+        let mut writer = PriorsModelWriter::new();
+        writer.add_table("literal", &trained_cdfs(5)[..]).unwrap();
+        writer.add_table("copy", &trained_cdfs(0)[..]).unwrap();
+        writer.add_table("distance", &trained_cdfs(3)[..]).unwrap();
+        assert_eq!(writer.add_table("copy", &trained_cdfs(1)[..]), Err(ErrMsg::PriorsModelMalformed));
+        assert_eq!(writer.add_table("", &trained_cdfs(1)[..]), Err(ErrMsg::PriorsModelMalformed));
+        writer.finish()
+    }
+    #[test]
+    fn test_priors_model_roundtrip() {
+        let bytes = model_bytes();
+        let model = PriorsModel::new(&bytes[..]);
+        assert_eq!(model.validate(), Ok(()));
+        assert_eq!(model.num_tables(), 3);
+        let names: Vec<&str> = model.tables().map(|table| table.name).collect();
+        assert_eq!(names, ["literal", "copy", "distance"]);
+        let literal = model.table("literal").unwrap();
+        for (index, cdf) in trained_cdfs(5).iter().enumerate() {
+            for symbol in 0..16 {
+                assert_eq!(literal.cdf(index)[symbol], cdf.cdf(symbol as u8));
+            }
+        }
+        assert!(model.table("copy").unwrap().is_empty());
+        assert!(model.table("nibble").is_none());
+    }
+    #[test]
+    fn test_priors_model_layout() {
+        // the bytes do not depend on the platform that wrote them
+        let mut writer = PriorsModelWriter::new();
+        writer.add_table("a", &[DefaultCDF16::default()][..]).unwrap();
+        let bytes = writer.finish();
+        assert_eq!(&bytes[..12], &[b'd', b'v', b'p', b'm', PRIORS_MODEL_VERSION, CDF_BACKEND, 1, 0, 1, b'a', 1, 0][..]);
+        assert_eq!(&bytes[12..14], &[0, 0][..]);
+        for symbol in 0..16 {
+            let value = DefaultCDF16::default().cdf(symbol as u8) as u16;
+            assert_eq!(&bytes[14 + 2 * symbol..16 + 2 * symbol], &[value as u8, (value >> 8) as u8][..]);
+        }
+        assert_eq!(u32_from_le(&bytes[46..]), crc32c_update(0, &bytes[..46]));
+        assert_eq!(bytes.len(), 50);
+    }
+    #[test]
+    fn test_priors_model_validate() {
+        let bytes = model_bytes();
+        for len in 0..bytes.len() {
+            assert_eq!(PriorsModel::new(&bytes[..len]).validate(), Err(ErrMsg::TruncatedInput), "{}", len);
+        }
+        let mut damaged = bytes.clone();
+        damaged[40] ^= 1;
+        match PriorsModel::new(&damaged[..]).validate() {
+            Err(ErrMsg::BadChecksum(_, _)) => {},
+            res => panic!("{:?}", res),
+        }
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(PriorsModel::new(&trailing[..]).validate(), Err(ErrMsg::PriorsModelMalformed));
+        let mut magic = bytes.clone();
+        magic[0] = b'D';
+        assert_eq!(PriorsModel::new(&magic[..]).validate(), Err(ErrMsg::PriorsModelMalformed));
+        // models from another build are turned away once they check out otherwise
+        for (offset, val) in [(4, PRIORS_MODEL_VERSION + 1), (5, CDF_BACKEND ^ 1)].iter() {
+            let mut other = bytes.clone();
+            other[*offset] = *val;
+            let checksum_offset = other.len() - 4;
+            let crc = crc32c_update(crc32c_init(), &other[..checksum_offset]);
+            other[checksum_offset..].clone_from_slice(&u32_to_le(crc)[..]);
+            assert_eq!(PriorsModel::new(&other[..]).validate(), Err(ErrMsg::UnsupportedPriorsModel(other[4], other[5])));
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use super::interface::{Prob, BaseCDF, Speed, CDF_MAX, CDF16, BLEND_FIXED_POINT_PRECISION};
+use super::interface::{Prob, BaseCDF, Speed, CDF_BITS, CDF_MAX, CDF16, BLEND_FIXED_POINT_PRECISION};
 
 
 #[allow(unused)]
@@ -150,7 +150,7 @@ impl BaseCDF for BlendCDF16 {
         CDF_MAX as Prob
     }
     fn log_max(&self) -> Option<i8> {
-        Some(15)
+        Some(CDF_BITS as i8)
     }
     fn div_by_max(&self, val:i32) -> i32 {
         return val>>self.log_max().unwrap()
@@ -1,70 +1,110 @@
 use core;
-use super::interface::{BaseCDF, Prob, CDF16, Speed, BLEND_FIXED_POINT_PRECISION};
+use super::interface::{BaseCDF, Prob, CDF16, Speed, BLEND_FIXED_POINT_PRECISION, CDF_MAX};
+use interface::ErrMsg;
+
+// the sum of the 16 entries of every ExternalProb
+pub const EXTERNAL_PROB_TOTAL: u16 = 0x7fff;
+
+// A caller supplied distribution over the 16 values of one literal nibble.
+// Every value must be possible and the entries must sum to EXTERNAL_PROB_TOTAL.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExternalProb {
+    pdf: [u16; 16],
+}
+
+impl ExternalProb {
+    pub fn new(pdf: [u16; 16]) -> Result<Self, ErrMsg> {
+        let mut sum = 0u32;
+        for (nibble, p) in pdf.iter().enumerate() {
+            if *p == 0 {
+                return Err(ErrMsg::ExternalProbZero(nibble as u8));
+            }
+            sum += u32::from(*p);
+        }
+        if sum != u32::from(EXTERNAL_PROB_TOTAL) {
+            return Err(ErrMsg::ExternalProbNotNormalized(sum));
+        }
+        Ok(ExternalProb{pdf: pdf})
+    }
+    // Builds the distribution from the probability of each of the 4 bits of the nibble being set,
+    // most significant bit first, in 255ths: the layout of the per literal prob slice.
+    pub fn from_bit_probabilities(probs: &[u8; 4]) -> Self {
+        let mut pdf = [1f64; 16];
+        for (nibble, p) in pdf.iter_mut().enumerate() {
+            for bit in 0..4 {
+                let p1 = f64::from(probs[bit]) / f64::from(u8::max_value());
+                if (nibble & (1 << (3 - bit))) != 0 {
+                    *p *= p1;
+                } else {
+                    *p *= 1f64 - p1;
+                }
+            }
+        }
+        Self::from_weights(&pdf, EXTERNAL_PROB_TOTAL)
+    }
+    // scales nonnegative weights to total_weight, keeping every value possible
+    fn from_weights(weights: &[f64; 16], total_weight: u16) -> Self {
+        let mut sum = 0f64;
+        for w in weights.iter() {
+            sum += *w;
+        }
+        let spare = f64::from(total_weight - 16);
+        let mut pdf = [1u16; 16];
+        let mut total = 16u16;
+        for (p, w) in pdf.iter_mut().zip(weights.iter()) {
+            let scaled = if sum > 0f64 { (*w / sum * spare) as u16 } else { 0 };
+            *p += scaled;
+            total += scaled;
+        }
+        // rounding down leaves a little mass over; it goes to the most likely value
+        let mut max_index = 0;
+        for (index, p) in pdf.iter().enumerate() {
+            if *p > pdf[max_index] {
+                max_index = index;
+            }
+        }
+        pdf[max_index] += total_weight - total;
+        ExternalProb{pdf: pdf}
+    }
+    pub fn pdf(&self) -> &[u16; 16] {
+        &self.pdf
+    }
+}
 
 #[derive(Clone,Copy)]
 pub struct ExternalProbCDF16 {
     pub cdf: [Prob; 16],
-    pub nibble: usize,
 }
 
 impl Default for ExternalProbCDF16 {
     fn default() -> Self {
         ExternalProbCDF16 {
             cdf: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-            nibble: 0,
         }
     }
 }
 
 impl ExternalProbCDF16 {
-    pub fn init<T: BaseCDF>(&mut self, _n: u8, probs: &[u8], mix: &T) {
-        //println_stderr!("init for {:x}", _n);
-        //println_stderr!("init for {:x} {:x} {:x} {:x}", probs[0], probs[1], probs[2], probs[3]);
-        //average the two probabilities
-        assert!(probs.len() == 4);
-        self.nibble = _n as usize;
-        let mut pcdf = [1f64;16];
-        for nibble in 0..16 {
-            //println_stderr!("setting for {:x}", nibble);
-            for bit in 0..4 {
-                let p1 = f64::from(probs[bit]) / f64::from(u8::max_value());
-                let isone = (nibble & (1<<(3 - bit))) != 0;
-                //println_stderr!("bit {:} is {:} {:}", bit, isone, p1);
-                if isone {
-                    pcdf[nibble] *= p1;
-                } else {
-                    pcdf[nibble] *= 1f64 - p1;
-                }
-            }
-        }
-        let mut mcdf = [1f64;16];
-        for nibble in 1..16 {
-            let prev = nibble - 1;
-            let c = f64::from(mix.cdf(nibble));
-            let p = f64::from(mix.cdf(prev));
-            let m = f64::from(mix.max());
-            let d = (c - p) / m;
-            assert!(d < 1.0);
-            mcdf[nibble as usize] = d;
+    // the average of the external distribution and the one the model (mix) would have coded with,
+    // at the precision of the coder
+    pub fn new<T: BaseCDF>(external: &ExternalProb, mix: &T) -> Self {
+        let mut pcdf = [0f64; 16];
+        let m = f64::from(mix.max());
+        let mut prev = 0f64;
+        for (nibble, p) in pcdf.iter_mut().enumerate() {
+            let c = f64::from(mix.cdf(nibble as u8));
+            let model = (c - prev) / m;
+            prev = c;
+            *p = (f64::from(external.pdf[nibble]) / f64::from(EXTERNAL_PROB_TOTAL) + model) / 2f64;
         }
-        for nibble in 0..16 {
-            pcdf[nibble] = (pcdf[nibble] + mcdf[nibble])/2f64;
-        }
-        let mut sum = 0f64;
-        for pcdf_nibble in &mut pcdf {
-            sum += *pcdf_nibble;
-            *pcdf_nibble = sum;
-        }
-        for pcdf_nibble in &mut pcdf {
-            *pcdf_nibble /= sum;
-        }
-        for nibble in 0..16 {
-            let p = pcdf[nibble];
-            let res = (p * f64::from(Prob::max_value())) as Prob;
-            let least1 = core::cmp::max(res, 1);
-            self.cdf[nibble] = core::cmp::min(least1, self.max() - 1);
-            //println_stderr!("cdf set {:x} {:x} {:}", nibble, self.cdf[nibble], p);
+        let averaged = ExternalProb::from_weights(&pcdf, CDF_MAX as u16);
+        let mut ret = ExternalProbCDF16::default();
+        let mut sum: Prob = 0;
+        for (c, p) in ret.cdf.iter_mut().zip(averaged.pdf.iter()) {
+            sum += *p as Prob;
+            *c = sum;
         }
+        ret
     }
 }
 
@@ -77,7 +117,7 @@ impl BaseCDF for ExternalProbCDF16 {
         self.entropy() != Self::default().entropy()
     }
     fn max(&self) -> Prob {
-        Prob::max_value()
+        CDF_MAX
     }
     fn log_max(&self) -> Option<i8> { None }
     fn cdf(&self, symbol: u8) -> Prob {
@@ -116,3 +156,54 @@ impl CDF16 for ExternalProbCDF16 {
         return;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use interface::ErrMsg;
+    use super::{ExternalProb, ExternalProbCDF16, EXTERNAL_PROB_TOTAL};
+    use super::super::{BaseCDF, FrequentistCDF16};
+
+    #[test]
+    fn test_external_prob_validation() {
+        let mut pdf = [EXTERNAL_PROB_TOTAL / 16; 16];
+        match ExternalProb::new(pdf) {
+            Err(ErrMsg::ExternalProbNotNormalized(sum)) => assert_eq!(sum, u32::from(EXTERNAL_PROB_TOTAL) - 15),
+            res => panic!("{:?}", res),
+        }
+        pdf[3] += 15;
+        assert_eq!(ExternalProb::new(pdf).unwrap().pdf()[3], EXTERNAL_PROB_TOTAL / 16 + 15);
+        pdf[3] += pdf[7];
+        pdf[7] = 0;
+        match ExternalProb::new(pdf) {
+            Err(ErrMsg::ExternalProbZero(7)) => {},
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_external_prob_from_bits_is_normalized() {
+        for probs in [[0u8, 0, 0, 0], [255, 255, 255, 255], [128, 1, 254, 77]].iter() {
+            let prob = ExternalProb::from_bit_probabilities(probs);
+            assert_eq!(ExternalProb::new(*prob.pdf()).unwrap(), prob);
+        }
+        // all bits certainly set puts nearly everything on 0xf
+        let prob = ExternalProb::from_bit_probabilities(&[255, 255, 255, 255]);
+        assert_eq!(prob.pdf()[0xf], EXTERNAL_PROB_TOTAL - 15);
+    }
+
+    #[test]
+    fn test_external_prob_cdf_averages_with_model() {
+        let mut pdf = [1u16; 16];
+        pdf[5] = EXTERNAL_PROB_TOTAL - 15;
+        let cdf = ExternalProbCDF16::new(&ExternalProb::new(pdf).unwrap(), &FrequentistCDF16::default());
+        assert_eq!(cdf.cdf(15), cdf.max());
+        let mut prev = 0;
+        for nibble in 0..16u8 {
+            assert!(cdf.cdf(nibble) > prev);
+            prev = cdf.cdf(nibble);
+        }
+        // half the mass from the external distribution and a sixteenth of the rest from the model
+        let p5 = cdf.cdf(5) - cdf.cdf(4);
+        assert!(p5 > cdf.max() / 2 && p5 < cdf.max() / 2 + cdf.max() / 16);
+    }
+}
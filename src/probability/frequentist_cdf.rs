@@ -1,5 +1,5 @@
 use core;
-use super::interface::{Prob, BaseCDF, Speed, CDF16, BLEND_FIXED_POINT_PRECISION};
+use super::interface::{Prob, BaseCDF, Speed, CDF16, BLEND_FIXED_POINT_PRECISION, CDF_BITS};
 fn to_bit_i32(val: i32, shift_val: u8) -> u32 {
     if val != 0 {
         1 << shift_val
@@ -60,8 +60,9 @@ impl CDF16 for FrequentistCDF16 {
         let ourmax = i32::from(self.max());
         let othermax = i32::from(other.max());
         let ourmax_times_othermax = ourmax * othermax;
-        let leading_zeros_combo = core::cmp::min(ourmax_times_othermax.leading_zeros(), 17);
-        let desired_shift = 17 - leading_zeros_combo;
+        // rescale so the product keeps under 1 << CDF_BITS
+        let leading_zeros_combo = core::cmp::min(ourmax_times_othermax.leading_zeros(), 32 - CDF_BITS as u32);
+        let desired_shift = 32 - CDF_BITS as u32 - leading_zeros_combo;
         let inv_mix_rate = (1 << BLEND_FIXED_POINT_PRECISION) - mix_rate;
         for (s, o) in retval.cdf.iter_mut().zip(other.cdf.iter()) {
           let rescaled_self = (i32::from(*s) * othermax) >> desired_shift;
@@ -73,11 +74,11 @@ impl CDF16 for FrequentistCDF16 {
     #[inline(always)]
     fn blend(&mut self, symbol: u8, speed: Speed) {
         const CDF_BIAS : [Prob;16] = [1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16];
-        let increment : Prob = speed.inc() as Prob;
+        let increment : Prob = speed.scaled_inc() as Prob;
         for i in (symbol as usize)..16 {
             self.cdf[i] = self.cdf[i].wrapping_add(increment);
         }
-        if self.cdf[15] >= speed.lim() {
+        if self.cdf[15] >= speed.scaled_lim() {
             for i in 0..16 {
                 self.cdf[i] = self.cdf[i].wrapping_add(CDF_BIAS[i]).wrapping_sub(self.cdf[i].wrapping_add(CDF_BIAS[i]) >> 2);
             }
@@ -214,6 +214,13 @@ pub trait BaseCDF {
     }
 }
 
+// An adaptive model for a single binary decision, coded as the probability (out of 256) that it is false.
+pub trait BitModel: Default + Copy {
+    fn prob_of_false(&self) -> u8;
+    fn blend(&mut self, symbol: bool, speed: &Speed);
+    fn used(&self) -> bool;
+}
+
 #[derive(Clone, Copy)]
 pub struct CDF2 {
     counts: [u8; 2],
@@ -268,8 +275,14 @@ impl BaseCDF for CDF2 {
     }
 }
 
-impl CDF2 {
-    pub fn blend(&mut self, symbol: bool, _speed: &Speed) {
+impl BitModel for CDF2 {
+    fn prob_of_false(&self) -> u8 {
+        self.prob
+    }
+    fn used(&self) -> bool {
+        BaseCDF::used(self)
+    }
+    fn blend(&mut self, symbol: bool, _speed: &Speed) {
         let fcount = self.counts[0];
         let tcount = self.counts[1];
         debug_assert!(fcount != 0);
@@ -295,7 +308,44 @@ impl CDF2 {
         }
     }
 }
+
+// LZMA-style state machine counter: a 12 bit probability that moves 1/32 of the way toward
+// each observation, so it keeps tracking a shifting source where CDF2's counts saturate.
+#[derive(Clone, Copy)]
+pub struct BitCounter12 {
+    prob: u16,
+}
+
+const BIT_COUNTER_BITS: u32 = 12;
+const BIT_COUNTER_SHIFT: u32 = 5;
+
+impl Default for BitCounter12 {
+    fn default() -> Self {
+        BitCounter12 {
+            prob: 1 << (BIT_COUNTER_BITS - 1),
+        }
+    }
+}
+
+impl BitModel for BitCounter12 {
+    fn prob_of_false(&self) -> u8 {
+        // the update stalls within 31 of either end, so this stays in 1..=254
+        (self.prob >> (BIT_COUNTER_BITS - 8)) as u8
+    }
+    fn used(&self) -> bool {
+        self.prob != 1 << (BIT_COUNTER_BITS - 1)
+    }
+    fn blend(&mut self, symbol: bool, _speed: &Speed) {
+        if symbol {
+            self.prob -= self.prob >> BIT_COUNTER_SHIFT;
+        } else {
+            self.prob += ((1 << BIT_COUNTER_BITS) - self.prob) >> BIT_COUNTER_SHIFT;
+        }
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
 pub struct Speed(i16,i16);
 pub const SPEED_PALETTE_SIZE: usize = 15;
 pub type SpeedPalette = [Speed;SPEED_PALETTE_SIZE];
@@ -362,6 +412,16 @@ impl Speed {
     pub fn inc(&self) -> i16 {
         self.0
     }
+    // the increment at CDF_BITS of precision, which stays nonzero if the speed adapts at all
+    #[inline(always)]
+    pub fn scaled_inc(&self) -> i16 {
+        (self.inc() + (1 << SPEED_SHIFT) - 1) >> SPEED_SHIFT
+    }
+    // the limit at CDF_BITS of precision
+    #[inline(always)]
+    pub fn scaled_lim(&self) -> i16 {
+        self.lim() >> SPEED_SHIFT
+    }
     #[inline(always)]
     pub fn set_lim(&mut self, lim: i16) {
         debug_assert!(lim <= 0x4000); // otherwise some sse hax fail
@@ -424,9 +484,15 @@ pub trait CDF16: Sized + Default + Copy + BaseCDF {
 }
 
 pub const BLEND_FIXED_POINT_PRECISION : i8 = 15;
+// The precision the entropy coder works at.  Speeds are given for 15 bits and rescaled to it
+// (see Speed::scaled_inc); the 12 bit build renormalizes less often, which pays on 32 bit targets.
+#[cfg(not(feature="cdf12"))]
 pub const CDF_BITS : usize = 15; // 15 bits
+#[cfg(feature="cdf12")]
+pub const CDF_BITS : usize = 12;
 pub const LOG2_SCALE: u32 = CDF_BITS as u32;
-pub const CDF_MAX : Prob = 32_767; // last value is implicitly 32768
+pub const CDF_MAX : Prob = ((1i32 << CDF_BITS) - 1) as Prob; // last value is implicitly 1 << CDF_BITS
+const SPEED_SHIFT: u32 = 15 - CDF_BITS as u32;
 const CDF_LIMIT : i64 = (CDF_MAX as i64) + 1;
 
 
@@ -587,6 +653,7 @@ pub fn u8_to_speed(data: u8) -> i16 {
 mod test {
     use super::speed_to_u8;
     use super::u8_to_speed;
+    use super::{BitModel, BitCounter12, CDF2, Speed};
     fn tst_u8_to_speed(data: i16) {
         assert_eq!(u8_to_speed(speed_to_u8(data)), data);
     }
@@ -614,4 +681,31 @@ mod test {
         tst_u8_to_speed(1536);
         tst_u8_to_speed(1664);
     }
+    fn observations_until_flip<M: BitModel>(model: &mut M) -> usize {
+        for _ in 0..4096 {
+            model.blend(false, &Speed::ROCKET);
+        }
+        assert!(model.prob_of_false() > 250);
+        let mut count = 0;
+        while model.prob_of_false() >= 128 {
+            model.blend(true, &Speed::ROCKET);
+            count += 1;
+        }
+        count
+    }
+    #[test]
+    fn test_bit_models_adapt() {
+        assert!(!CDF2::default().used());
+        assert!(!BitCounter12::default().used());
+        assert_eq!(BitCounter12::default().prob_of_false(), 128);
+        // after a long run the count pair needs about as many observations again to turn around
+        assert!(observations_until_flip(&mut CDF2::default()) > 200);
+        assert!(observations_until_flip(&mut BitCounter12::default()) < 32);
+        let mut counter = BitCounter12::default();
+        for _ in 0..4096 {
+            counter.blend(true, &Speed::ROCKET);
+        }
+        assert!(counter.used());
+        assert_eq!(counter.prob_of_false(), 1);
+    }
 }
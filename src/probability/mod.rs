@@ -22,6 +22,7 @@ pub mod numeric;
 mod common_tests;
 pub mod interface;
 pub mod external_cdf;
+pub mod static_cdf;
 pub mod blend_cdf;
 pub mod frequentist_cdf;
 mod variant_speed_cdf;
@@ -29,12 +30,13 @@ mod variant_speed_cdf;
 pub mod simd_frequentist_cdf;
 pub mod opt_frequentist_cdf;
 
-pub use self::interface::{BaseCDF, CDF16, CDF2, Speed, SpeedPalette, Prob, LOG2_SCALE, BLEND_FIXED_POINT_PRECISION, ProbRange, SPEED_PALETTE_SIZE};
+pub use self::interface::{BaseCDF, BitModel, BitCounter12, CDF16, CDF2, Speed, SpeedPalette, Prob, LOG2_SCALE, BLEND_FIXED_POINT_PRECISION, ProbRange, SPEED_PALETTE_SIZE};
 #[cfg(feature="debug_entropy")]
 pub use self::interface::DebugWrapperCDF16;
 pub use self::blend_cdf::{BlendCDF16};
 pub use self::frequentist_cdf::FrequentistCDF16;
-pub use self::external_cdf::ExternalProbCDF16;
+pub use self::external_cdf::{ExternalProbCDF16, ExternalProb, EXTERNAL_PROB_TOTAL};
+pub use self::static_cdf::{StaticCDF16, STATIC_CDF_BYTES};
 #[cfg(feature="simd")]
 pub use self::simd_frequentist_cdf::SIMDFrequentistCDF16;
 pub use self::opt_frequentist_cdf::OptFrequentistCDF16;
@@ -1,6 +1,6 @@
 use core;
 use packed_simd::FromBits;
-use super::interface::{Prob, BaseCDF, Speed, CDF16, BLEND_FIXED_POINT_PRECISION, SymStartFreq, LOG2_SCALE};
+use super::interface::{Prob, BaseCDF, Speed, CDF16, BLEND_FIXED_POINT_PRECISION, SymStartFreq, LOG2_SCALE, CDF_BITS};
 use super::numeric;
 
 use packed_simd::{i32x2, f64x2, i16x16, i64x4, i16x8, i8x32, i8x16, u32x8, u8x16, i64x2, i32x8};
@@ -93,7 +93,7 @@ impl BaseCDF for SIMDFrequentistCDF16 {
             sym: sym,
         }
     }
-    #[cfg(any(feature="portable-simd", not(target_arch="x86_64")))]
+    #[cfg(any(feature="portable-simd", feature="safe", not(target_arch="x86_64")))]
     #[inline(always)]
     fn cdf_offset_to_sym_start_and_freq(&self,
                                         cdf_offset_p: Prob) -> SymStartFreq {
@@ -110,7 +110,7 @@ impl BaseCDF for SIMDFrequentistCDF16 {
         self.sym_to_start_and_freq_with_div_hint(symbol_id, inv_max_and_bitlen)
     }
     #[cfg(target_arch = "x86_64")]
-    #[cfg(not(feature="portable-simd"))]
+    #[cfg(not(any(feature="portable-simd", feature="safe")))]
     #[cfg(feature="avx2")]
     #[inline(always)]
     fn cdf_offset_to_sym_start_and_freq(&self,
@@ -124,7 +124,7 @@ impl BaseCDF for SIMDFrequentistCDF16 {
         self.sym_to_start_and_freq_with_div_hint(symbol_id, inv_max_and_bitlen)
     }
     #[cfg(target_arch = "x86_64")]
-    #[cfg(not(feature="portable-simd"))]
+    #[cfg(not(any(feature="portable-simd", feature="safe")))]
     #[cfg(not(feature="avx2"))]
     #[inline(always)]
     fn cdf_offset_to_sym_start_and_freq(&self,
@@ -189,8 +189,8 @@ impl CDF16 for SIMDFrequentistCDF16 {
         let ourmax = i32::from(self.max());
         let othermax = i32::from(other.max());
         let ourmax_times_othermax = ourmax * othermax;
-        let leading_zeros_combo = core::cmp::min(ourmax_times_othermax.leading_zeros(), 17);
-        let desired_shift = 17 - leading_zeros_combo;
+        let leading_zeros_combo = core::cmp::min(ourmax_times_othermax.leading_zeros(), 32 - CDF_BITS as u32);
+        let desired_shift = 32 - CDF_BITS as u32 - leading_zeros_combo;
 
         let inv_mix_rate = (1 << BLEND_FIXED_POINT_PRECISION) - mix_rate;
         let mix_rate_v = i32x8::splat(mix_rate);
@@ -211,12 +211,12 @@ impl CDF16 for SIMDFrequentistCDF16 {
     }
     #[inline(always)]
     fn blend(&mut self, symbol: u8, speed: Speed) {
-        let increment_v = i16x16::splat(speed.inc());
+        let increment_v = i16x16::splat(speed.scaled_inc());
         let one_to_16 = i16x16::new(1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16);
         let mask_v = one_to_16.gt(i16x16::splat(i16::from(symbol)));
         self.cdf = self.cdf + (increment_v & i16x16::from_bits(mask_v));
         let mut cdf_max = self.max();
-        if cdf_max >= speed.lim() {
+        if cdf_max >= speed.scaled_lim() {
             let cdf_bias = one_to_16;
             self.cdf = self.cdf + cdf_bias - ((self.cdf + cdf_bias) >> 2);
             cdf_max = self.max();
@@ -0,0 +1,164 @@
+use super::interface::{BaseCDF, Prob, CDF16, Speed, CDF_BITS, CDF_MAX, BLEND_FIXED_POINT_PRECISION};
+
+// the bytes StaticCDF16::write_bytes writes
+pub const STATIC_CDF_BYTES: usize = 32;
+
+// A nibble distribution that never adapts: the static literal model codes with one chosen by the
+// encoder for each metablock.  It travels as 16 weight exponents, value n weighing 1 << exponent n,
+// and is rebuilt from them with integer math so that both sides get the same CDF.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StaticCDF16 {
+    pub cdf: [Prob; 16],
+}
+
+impl Default for StaticCDF16 {
+    fn default() -> Self {
+        StaticCDF16::from_weight_exponents(&[0u8; 16])
+    }
+}
+
+impl StaticCDF16 {
+    // exponents holds 16 values below 16; every nibble keeps a pdf of at least 2 so the coder
+    // can still code it
+    pub fn from_weight_exponents(exponents: &[u8]) -> Self {
+        let mut sum = 0u32;
+        for e in exponents[..16].iter() {
+            sum += 1u32 << (e & 0xf);
+        }
+        let spare = CDF_MAX as u32 - 32;
+        let mut pdf = [2u32; 16];
+        let mut total = 32u32;
+        for (p, e) in pdf.iter_mut().zip(exponents.iter()) {
+            let scaled = (1u32 << (e & 0xf)) * spare / sum;
+            *p += scaled;
+            total += scaled;
+        }
+        // rounding down leaves a little mass over; it goes to the most likely value
+        let mut max_index = 0;
+        for (index, p) in pdf.iter().enumerate() {
+            if *p > pdf[max_index] {
+                max_index = index;
+            }
+        }
+        pdf[max_index] += CDF_MAX as u32 - total;
+        let mut ret = StaticCDF16{cdf: [0; 16]};
+        let mut cumulative = 0u32;
+        for (c, p) in ret.cdf.iter_mut().zip(pdf.iter()) {
+            cumulative += *p;
+            *c = cumulative as Prob;
+        }
+        ret
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut ret = StaticCDF16{cdf: [0; 16]};
+        for (c, b) in ret.cdf.iter_mut().zip(bytes[..STATIC_CDF_BYTES].chunks(2)) {
+            *c = (u16::from(b[0]) | (u16::from(b[1]) << 8)) as Prob;
+        }
+        ret
+    }
+    pub fn write_bytes(&self, bytes: &mut [u8]) {
+        for (c, b) in self.cdf.iter().zip(bytes[..STATIC_CDF_BYTES].chunks_mut(2)) {
+            b[0] = *c as u8;
+            b[1] = (*c as u16 >> 8) as u8;
+        }
+    }
+}
+
+// The weight exponents that best approximate the nibble counts of a table: 15 for the most
+// frequent value, one less for each halving below it, and 0 for values never seen.
+pub fn weight_exponents(counts: &[u32]) -> [u8; 16] {
+    let mut ret = [0u8; 16];
+    let max = u64::from(*counts[..16].iter().max().unwrap_or(&0));
+    for (e, count) in ret.iter_mut().zip(counts.iter()) {
+        if *count == 0 {
+            continue;
+        }
+        let mut halvings = 0;
+        while halvings < 14 && (u64::from(*count) * 3) << halvings < max << 1 {
+            halvings += 1;
+        }
+        *e = 15 - halvings;
+    }
+    ret
+}
+
+impl BaseCDF for StaticCDF16 {
+    fn num_symbols() -> u8 { 16 }
+    fn used(&self) -> bool {
+        *self != Self::default()
+    }
+    fn max(&self) -> Prob {
+        CDF_MAX
+    }
+    fn log_max(&self) -> Option<i8> {
+        Some(CDF_BITS as i8)
+    }
+    fn div_by_max(&self, val:i32) -> i32 {
+        val >> CDF_BITS
+    }
+    fn cdf(&self, symbol: u8) -> Prob {
+        self.cdf[symbol as usize]
+    }
+    fn valid(&self) -> bool {
+        let mut prev = 0;
+        for item in self.cdf.iter() {
+            if *item < prev + 2 {
+                return false;
+            }
+            prev = *item;
+        }
+        prev == CDF_MAX
+    }
+}
+
+impl CDF16 for StaticCDF16 {
+    fn average(&self, other:&Self, mix_rate:i32) -> Self {
+        let mut retval = *self;
+        let inv_mix_rate = (1 << BLEND_FIXED_POINT_PRECISION) - mix_rate;
+        for (s, o) in retval.cdf.iter_mut().zip(other.cdf.iter()) {
+            *s = ((i32::from(*s) * mix_rate + i32::from(*o) * inv_mix_rate) >> BLEND_FIXED_POINT_PRECISION) as Prob;
+        }
+        retval
+    }
+    fn blend(&mut self, _symbol: u8, _speed: Speed) {
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{StaticCDF16, weight_exponents, STATIC_CDF_BYTES};
+    use super::super::BaseCDF;
+
+    #[test]
+    fn test_static_cdf_is_valid_for_extreme_weights() {
+        let mut exponents = [0u8; 16];
+        assert!(StaticCDF16::from_weight_exponents(&exponents).valid());
+        exponents[9] = 15;
+        let cdf = StaticCDF16::from_weight_exponents(&exponents);
+        assert!(cdf.valid());
+        assert!(cdf.pdf(9) > cdf.max() - 16 * 4);
+        for (index, e) in exponents.iter_mut().enumerate() {
+            *e = index as u8;
+        }
+        let cdf = StaticCDF16::from_weight_exponents(&exponents);
+        assert!(cdf.valid());
+        for nibble in 9..16u8 {
+            assert!(cdf.pdf(nibble) > cdf.pdf(nibble - 1));
+        }
+        let mut bytes = [0u8; STATIC_CDF_BYTES];
+        cdf.write_bytes(&mut bytes);
+        assert_eq!(StaticCDF16::from_bytes(&bytes), cdf);
+    }
+
+    #[test]
+    fn test_weight_exponents_follow_counts() {
+        let mut counts = [0u32; 16];
+        counts[0] = 1000;
+        counts[1] = 500;
+        counts[2] = 260;
+        counts[3] = 1;
+        let exponents = weight_exponents(&counts);
+        assert_eq!(&exponents[..5], &[15, 14, 13, 5, 0]);
+        assert_eq!(weight_exponents(&[0u32; 16]), [0u8; 16]);
+    }
+}
@@ -0,0 +1,227 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Finds brotli dictionary words under any of the brotli transforms: a word may be cut at
+// either end, have its case changed and pick up a transform's prefix and suffix.
+// Words are indexed by the ASCII lowercased 4 bytes they contribute first, both from their
+// start and, for the omit-first transforms, from each cut point.  Every candidate is then run
+// through TransformDictionaryWord and compared byte for byte, so hash collisions cost time only.
+use core;
+use brotli::dictionary::{kBrotliDictionary, kBrotliDictionaryOffsetsByLength, kBrotliDictionarySizeBitsByLength,
+                         kBrotliMinDictionaryWordLength, kBrotliMaxDictionaryWordLength};
+use brotli::transform::{kNumTransforms, kTransforms};
+use brotli::TransformDictionaryWord;
+pub use alloc::{SliceWrapper, SliceWrapperMut};
+use interface::DictCommand;
+#[cfg(feature="std")]
+use alloc_stdlib::HeapAlloc;
+#[cfg(feature="std")]
+use alloc::Allocator;
+
+const DICT_HASH_BITS: u32 = 16;
+// the number of entries of the zeroed table DictSearch::new fills in
+pub const DICT_TABLE_SIZE: usize = 1 << DICT_HASH_BITS;
+// the most bytes a transform adds before or after the word
+const MAX_TRANSFORM_AFFIX: usize = 13;
+const MAX_TRANSFORMED_LENGTH: usize = kBrotliMaxDictionaryWordLength as usize + MAX_TRANSFORM_AFFIX;
+// a larger cut is reserved (kOmitFirst8) or missing from kTransforms
+const MAX_OMIT_FIRST: usize = 9;
+// kTransforms codes the omit-first cuts as 12 (kOmitFirst1) through 20 (kOmitFirst9)
+const OMIT_FIRST_BASE: u8 = 11;
+
+pub struct DictSearch<Table:SliceWrapper<u32> > {
+    // 1 + ((omit_first << 24) | (word_size << 16) | word_id), 0 when empty
+    ht: Table,
+    // the bytes each transform writes before the word
+    prefixes: [([u8; MAX_TRANSFORM_AFFIX], u8); kNumTransforms as usize],
+}
+
+#[inline(always)]
+fn hash4_lower(data: &[u8]) -> usize {
+    let mut val = 0u32;
+    for (index, byte) in data[..4].iter().enumerate() {
+        val |= u32::from(byte.to_ascii_lowercase()) << (8 * index);
+    }
+    (val.wrapping_mul(0x1e35a7bd) >> (32 - DICT_HASH_BITS)) as usize
+}
+
+fn dictionary_word(word_size: usize, word_id: usize) -> &'static [u8] {
+    let start = kBrotliDictionaryOffsetsByLength[word_size] as usize + word_id * word_size;
+    &kBrotliDictionary[start..start + word_size]
+}
+
+impl<Table:SliceWrapperMut<u32> + SliceWrapper<u32> > DictSearch<Table> {
+    // indexes the dictionary into ht, a zeroed table of DICT_TABLE_SIZE entries
+    pub fn new(ht: Table) -> Self {
+        assert_eq!(ht.slice().len(), DICT_TABLE_SIZE);
+        let mut ret = DictSearch::<Table> {
+            ht,
+            prefixes: [([0u8; MAX_TRANSFORM_AFFIX], 0u8); kNumTransforms as usize],
+        };
+        // a word of a byte that no prefix, suffix or case change produces marks where the prefix ends
+        let marker = [1u8; MAX_OMIT_FIRST + 4];
+        for (transform, prefix) in ret.prefixes.iter_mut().enumerate() {
+            let mut transformed = [0u8; MAX_TRANSFORMED_LENGTH];
+            TransformDictionaryWord(&mut transformed[..], &marker[..], marker.len() as i32, transform as i32);
+            let prefix_len = transformed.iter().position(|b| *b == 1).unwrap();
+            prefix.0[..prefix_len].clone_from_slice(&transformed[..prefix_len]);
+            prefix.1 = prefix_len as u8;
+        }
+        // cut points first and long words last, so a collision keeps the word start of the longest word
+        for omit_first in (0..MAX_OMIT_FIRST + 1).rev() {
+            for (word_size, size_bits) in kBrotliDictionarySizeBitsByLength.iter().enumerate()
+                .skip(core::cmp::max(kBrotliMinDictionaryWordLength as usize, omit_first + 4)) {
+                for word_id in 0..(1usize << *size_bits) {
+                    let word = dictionary_word(word_size, word_id);
+                    ret.ht.slice_mut()[hash4_lower(&word[omit_first..])] =
+                        ((omit_first << 24) | (word_size << 16) | word_id) as u32 + 1;
+                }
+            }
+        }
+        ret
+    }
+}
+
+impl<Table:SliceWrapper<u32> > DictSearch<Table> {
+    // Returns the longest transformed dictionary word that data[pos..] starts with, along with its length.
+    pub fn find_match(&self, data: &[u8], pos: usize) -> Option<(DictCommand, usize)> {
+        let mut best: Option<(DictCommand, usize)> = None;
+        for (transform, &(ref prefix, prefix_len)) in self.prefixes.iter().enumerate() {
+            let prefix = &prefix[..prefix_len as usize];
+            let word_pos = pos + prefix.len();
+            if word_pos + 4 > data.len() || &data[pos..word_pos] != prefix {
+                continue;
+            }
+            let entry = self.ht.slice()[hash4_lower(&data[word_pos..])];
+            if entry == 0 {
+                continue;
+            }
+            let entry = entry as usize - 1;
+            let omit_first = entry >> 24;
+            let kind = kTransforms[transform].transform;
+            if omit_first != 0 && kind != OMIT_FIRST_BASE + omit_first as u8 {
+                continue; // indexed at a cut point this transform does not make
+            }
+            let word_size = (entry >> 16) & 0xff;
+            let word_id = entry & 0xffff;
+            let mut transformed = [0u8; MAX_TRANSFORMED_LENGTH];
+            let final_len = TransformDictionaryWord(&mut transformed[..],
+                                                    dictionary_word(word_size, word_id),
+                                                    word_size as i32,
+                                                    transform as i32) as usize;
+            if final_len == 0 || pos + final_len > data.len() || data[pos..pos + final_len] != transformed[..final_len] {
+                continue;
+            }
+            match best {
+                Some((_, best_len)) if best_len >= final_len => {},
+                _ => best = Some((DictCommand {
+                    word_size: word_size as u8,
+                    transform: transform as u8,
+                    final_size: final_len as u8,
+                    empty: 0,
+                    word_id: word_id as u32,
+                }, final_len)),
+            }
+        }
+        best
+    }
+    // hands back the table, to be freed by whoever allocated it
+    pub fn into_table(self) -> Table {
+        self.ht
+    }
+}
+
+// The dictionary index built once and shared, through an Arc, by any number of compressors on
+// any number of threads; see DivansCompressor::set_prepared_dictionary.  It never changes once built.
+#[cfg(feature="std")]
+pub struct PreparedDictionary {
+    search: DictSearch<<HeapAlloc<u32> as Allocator<u32>>::AllocatedMemory>,
+}
+
+#[cfg(feature="std")]
+impl PreparedDictionary {
+    pub fn new() -> Self {
+        PreparedDictionary {
+            search: DictSearch::new(HeapAlloc::<u32>::new(0).alloc_cell(DICT_TABLE_SIZE)),
+        }
+    }
+    pub fn find_match(&self, data: &[u8], pos: usize) -> Option<(DictCommand, usize)> {
+        self.search.find_match(data, pos)
+    }
+}
+
+#[cfg(feature="std")]
+impl Default for PreparedDictionary {
+    fn default() -> Self {
+        PreparedDictionary::new()
+    }
+}
+
+#[cfg(all(test, feature="std", feature="decoder"))]
+mod test {
+    use std::vec::Vec;
+    use alloc_stdlib::HeapAlloc;
+    use conformance::{conformance_commands, conformance_expected_output};
+    use divans_compressor::DivansCompressorFactoryStruct;
+    use interface::{
+        DivansCompressorFactory,
+        DivansCompressorOptions,
+        DefaultCDF16,
+        STREAM_FEATURE_NO_DICT,
+    };
+    use test_support::{compress_with, decode};
+
+    #[test]
+    fn test_prepared_dictionary() {
+        use std::sync::Arc;
+        use std::thread;
+        use raw_to_cmd::PreparedDictionary;
+        let mut input = Vec::<u8>::new();
+        for name in ["dict", "mixed", "dict", "copy"].iter().cycle().take(32) {
+            input.extend(conformance_expected_output(&conformance_commands(name)[..]).unwrap());
+        }
+        let input = Arc::new(input);
+        let compress = |opts: DivansCompressorOptions, dict: Option<Arc<PreparedDictionary>>, input: &[u8]| {
+            let mut compressor = DivansCompressorFactoryStruct::<HeapAlloc<u8>, HeapAlloc<DefaultCDF16>>::new(
+                HeapAlloc::<u8>::new(0),
+                HeapAlloc::<u32>::new(0),
+                HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+                opts,
+                ());
+            if let Some(dict) = dict {
+                compressor.set_prepared_dictionary(dict);
+            }
+            let stream = compress_with(&mut compressor, input).unwrap();
+            compressor.free();
+            stream
+        };
+        let opts = DivansCompressorOptions::default();
+        let unshared = compress(opts, None, &input[..]);
+        // one index serves every thread, and each stream comes out as if it had built its own
+        let dict = Arc::new(PreparedDictionary::new());
+        let workers: Vec<_> = (0..4).map(|_| {
+            let dict = dict.clone();
+            let input = input.clone();
+            thread::spawn(move || compress(opts, Some(dict), &input[..]))
+        }).collect();
+        for worker in workers {
+            assert_eq!(worker.join().unwrap(), unshared);
+        }
+        assert_eq!(Arc::strong_count(&dict), 1);
+        assert_eq!(decode(&unshared[..]).unwrap(), &input[..]);
+        let mut no_dict = opts;
+        no_dict.stream_features |= STREAM_FEATURE_NO_DICT;
+        assert!(compress(no_dict, None, &input[..]).len() > unshared.len());
+    }
+}
@@ -0,0 +1,413 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// A single-probe greedy LZ parser: the reference inverse of the recoder.
+// It makes no attempt at a good parse; every candidate is checked byte for byte against
+// the history, so the emitted copies always reproduce the input exactly.
+use core;
+use brotli::InputReference;
+pub use alloc::{Allocator, SliceWrapper, SliceWrapperMut};
+use interface::{Command, CopyCommand, DictCommand, LiteralCommand, DivansOutputResult, FeatureFlagSliceType};
+use super::dict_search::{DictSearch, DICT_TABLE_SIZE};
+#[cfg(feature="std")]
+use super::dict_search::PreparedDictionary;
+#[cfg(feature="std")]
+use std::sync::Arc;
+use super::repeat_hint::{RepeatHint, RepeatHints};
+
+const HASH_BITS: u32 = 14;
+pub const GREEDY_MIN_MATCH: usize = 4;
+// a dictionary word must beat the copy found at a position by this many bytes: a copy is cheaper
+// to code once the distance has been seen, and it also keeps the hash chain going
+const DICT_MIN_GAIN: usize = 2;
+
+pub struct GreedyParser<AllocU32:Allocator<u32> > {
+    // 1 + the last position whose first GREEDY_MIN_MATCH bytes hashed to the bucket, 0 when empty
+    ht: AllocU32::AllocatedMemory,
+    dict: Option<DictSearch<AllocU32::AllocatedMemory>>,
+    #[cfg(feature="std")]
+    prepared: Option<Arc<PreparedDictionary>>, // searched instead of dict when set
+    hints: RepeatHints<AllocU32>,
+    data_offset: u64, // input offset of data[0] in parse, to place the hints
+}
+
+#[inline(always)]
+fn hash4(data: &[u8]) -> usize {
+    let val = u32::from(data[0]) | (u32::from(data[1]) << 8) | (u32::from(data[2]) << 16) | (u32::from(data[3]) << 24);
+    (val.wrapping_mul(0x1e35a7bd) >> (32 - HASH_BITS)) as usize
+}
+
+impl<AllocU32:Allocator<u32> > GreedyParser<AllocU32> {
+    pub fn new(m32: &mut AllocU32) -> Self {
+        GreedyParser {
+            ht: m32.alloc_cell(1 << HASH_BITS),
+            dict: None,
+            #[cfg(feature="std")]
+            prepared: None,
+            hints: RepeatHints::default(),
+            data_offset: 0,
+        }
+    }
+    // also match brotli dictionary words, under every transform, where no longer copy exists
+    pub fn enable_dictionary(&mut self, m32: &mut AllocU32) {
+        if self.dict.is_none() && !self.has_prepared_dictionary() {
+            self.dict = Some(DictSearch::new(m32.alloc_cell(DICT_TABLE_SIZE)));
+        }
+    }
+    // match dictionary words with an index built elsewhere rather than building one here
+    #[cfg(feature="std")]
+    pub fn use_prepared_dictionary(&mut self, m32: &mut AllocU32, dict: Arc<PreparedDictionary>) {
+        if let Some(dict) = self.dict.take() {
+            m32.free_cell(dict.into_table());
+        }
+        self.prepared = Some(dict);
+    }
+    #[cfg(feature="std")]
+    fn has_prepared_dictionary(&self) -> bool {
+        self.prepared.is_some()
+    }
+    #[cfg(not(feature="std"))]
+    fn has_prepared_dictionary(&self) -> bool {
+        false
+    }
+    #[cfg(feature="std")]
+    fn find_dict_match(&self, data: &[u8], pos: usize) -> Option<(DictCommand, usize)> {
+        match self.prepared {
+            Some(ref prepared) => prepared.find_match(data, pos),
+            None => self.dict.as_ref().and_then(|dict| dict.find_match(data, pos)),
+        }
+    }
+    #[cfg(not(feature="std"))]
+    fn find_dict_match(&self, data: &[u8], pos: usize) -> Option<(DictCommand, usize)> {
+        self.dict.as_ref().and_then(|dict| dict.find_match(data, pos))
+    }
+    // the copy at hint.offset of the input is emitted as is, where it checks out, without searching;
+    // false if it starts before the end of the last hint or too many hints are waiting
+    pub fn add_hint(&mut self, m32: &mut AllocU32, hint: RepeatHint) -> bool {
+        self.hints.push(m32, hint)
+    }
+    // where the data passed to the following parse calls starts in the input
+    pub fn set_data_offset(&mut self, data_offset: u64) {
+        self.data_offset = data_offset;
+    }
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        if pos + GREEDY_MIN_MATCH <= data.len() {
+            self.ht.slice_mut()[hash4(&data[pos..])] = pos as u32 + 1;
+        }
+    }
+    // returns the (distance, length) of the match at pos, if the hashed candidate is a real one
+    fn find_match(&self, data: &[u8], history_start: usize, pos: usize, max_distance: usize) -> Option<(usize, usize)> {
+        if pos + GREEDY_MIN_MATCH > data.len() {
+            return None;
+        }
+        let entry = self.ht.slice()[hash4(&data[pos..])] as usize;
+        if entry == 0 {
+            return None;
+        }
+        let candidate = entry - 1;
+        if candidate < history_start || candidate >= pos || pos - candidate > max_distance {
+            return None;
+        }
+        // compared one byte at a time so overlapping copies are measured the way they decode
+        let mut len = 0usize;
+        while pos + len < data.len() && data[candidate + len] == data[pos + len] {
+            len += 1;
+        }
+        if len < GREEDY_MIN_MATCH {
+            return None;
+        }
+        Some((pos - candidate, len))
+    }
+    // the (distance, length) of the hinted copy covering pos, cut short where it stops matching;
+    // hints behind pos are dropped
+    fn hinted_match(&mut self, data: &[u8], history_start: usize, pos: usize, max_distance: usize) -> Option<(usize, usize)> {
+        let input_pos = self.data_offset + pos as u64;
+        let mut hint = self.hints.front()?;
+        while hint.end() <= input_pos {
+            self.hints.pop_front();
+            hint = self.hints.front()?;
+        }
+        let distance = hint.distance as usize;
+        if hint.offset > input_pos || distance == 0 || distance > pos - history_start || distance > max_distance {
+            return None;
+        }
+        let limit = core::cmp::min(data.len() - pos, (hint.end() - input_pos) as usize);
+        let mut len = 0usize;
+        while len < limit && data[pos - distance + len] == data[pos + len] {
+            len += 1;
+        }
+        if len < GREEDY_MIN_MATCH {
+            return None;
+        }
+        Some((distance, len))
+    }
+    // Parses data[*pos..] into literal, copy and, once enabled, dictionary commands, using data[history_start..*pos] as the
+    // bytes already emitted.  Copies never reach before history_start nor farther than max_distance.
+    // Returns NeedsMoreOutput when output fills; *pos marks where to resume with the same data.
+    pub fn parse<'a>(&mut self,
+                     data: &'a [u8],
+                     history_start: usize,
+                     pos: &mut usize,
+                     max_distance: usize,
+                     output: &mut [Command<InputReference<'a>>],
+                     output_offset: &mut usize) -> DivansOutputResult {
+        let mut literal_start = *pos;
+        let mut cur = *pos;
+        while cur < data.len() {
+            if *output_offset == output.len() {
+                return DivansOutputResult::NeedsMoreOutput;
+            }
+            let hinted = self.hinted_match(data, history_start, cur, max_distance);
+            let copy = hinted.or_else(|| self.find_match(data, history_start, cur, max_distance));
+            let dict = match hinted {
+                None => match self.find_dict_match(data, cur) {
+                    Some((dict_cmd, len)) => if len >= copy.map_or(0, |(_, copy_len)| copy_len) + DICT_MIN_GAIN {
+                        Some((dict_cmd, len))
+                    } else {
+                        None
+                    },
+                    None => None,
+                },
+                Some(_) => None,
+            };
+            let (cmd, len) = match (dict, copy) {
+                (Some((dict_cmd, len)), _) => (Command::Dict(dict_cmd), len),
+                (None, Some((distance, len))) => (Command::Copy(CopyCommand{
+                    distance: distance as u32,
+                    num_bytes: len as u32,
+                }), len),
+                (None, None) => {
+                    self.insert(data, cur);
+                    cur += 1;
+                    continue;
+                },
+            };
+            if literal_start != cur {
+                output[*output_offset] = Command::Literal(literal_command(data, literal_start, cur));
+                *output_offset += 1;
+                *pos = cur;
+                if *output_offset == output.len() {
+                    return DivansOutputResult::NeedsMoreOutput;
+                }
+            }
+            output[*output_offset] = cmd;
+            *output_offset += 1;
+            for index in cur..cur + len {
+                self.insert(data, index);
+            }
+            cur += len;
+            *pos = cur;
+            literal_start = cur;
+        }
+        if literal_start != data.len() {
+            if *output_offset == output.len() {
+                return DivansOutputResult::NeedsMoreOutput;
+            }
+            output[*output_offset] = Command::Literal(literal_command(data, literal_start, data.len()));
+            *output_offset += 1;
+        }
+        *pos = data.len();
+        DivansOutputResult::Success
+    }
+    pub fn free(&mut self, m32: &mut AllocU32) {
+        m32.free_cell(core::mem::replace(&mut self.ht, AllocU32::AllocatedMemory::default()));
+        if let Some(dict) = self.dict.take() {
+            m32.free_cell(dict.into_table());
+        }
+        #[cfg(feature="std")]
+        {
+            self.prepared = None;
+        }
+        self.hints.free(m32);
+    }
+}
+
+fn literal_command<'a>(data: &'a [u8], start: usize, end: usize) -> LiteralCommand<InputReference<'a>> {
+    LiteralCommand::<InputReference<'a>>{
+        data: InputReference{
+            data: &data[start..end],
+            orig_offset: start,
+        },
+        prob: FeatureFlagSliceType::<InputReference>::default(),
+        high_entropy: false,
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature="std")]
+mod test {
+    extern crate std;
+    use std::vec::Vec;
+    use alloc_stdlib::HeapAlloc;
+    use brotli::InputReference;
+    use brotli::TransformDictionaryWord;
+    use brotli::dictionary::{kBrotliDictionary, kBrotliDictionaryOffsetsByLength};
+    use interface::{Command, DivansOutputResult};
+    use super::{GreedyParser, GREEDY_MIN_MATCH};
+    use super::super::repeat_hint::RepeatHint;
+
+    fn replay(cmds: &[Command<InputReference>], out: &mut Vec<u8>) {
+        for cmd in cmds.iter() {
+            match *cmd {
+                Command::Literal(ref lit) => out.extend(lit.data.data),
+                Command::Dict(ref dict) => {
+                    let start = kBrotliDictionaryOffsetsByLength[dict.word_size as usize] as usize
+                        + dict.word_id as usize * dict.word_size as usize;
+                    let mut transformed = [0u8; 64];
+                    let len = TransformDictionaryWord(&mut transformed[..],
+                                                      &kBrotliDictionary[start..start + dict.word_size as usize],
+                                                      i32::from(dict.word_size),
+                                                      i32::from(dict.transform));
+                    assert_eq!(len as usize, dict.final_size as usize);
+                    out.extend(&transformed[..len as usize]);
+                },
+                Command::Copy(ref copy) => {
+                    assert!(copy.num_bytes as usize >= GREEDY_MIN_MATCH);
+                    assert!(copy.distance as usize <= out.len());
+                    for _ in 0..copy.num_bytes {
+                        let b = out[out.len() - copy.distance as usize];
+                        out.push(b);
+                    }
+                },
+                _ => panic!("unexpected command"),
+            }
+        }
+    }
+
+    fn greedy_roundtrip(data: &[u8], cmds_per_call: usize, max_distance: usize) -> (usize, usize) {
+        let (num_copies, num_dict, literal_bytes) = greedy_roundtrip_dict(data, cmds_per_call, max_distance, false);
+        assert_eq!(num_dict, 0);
+        (num_copies, literal_bytes)
+    }
+
+    fn greedy_roundtrip_dict(data: &[u8], cmds_per_call: usize, max_distance: usize, use_dict: bool) -> (usize, usize, usize) {
+        let mut m32 = HeapAlloc::<u32>::new(0);
+        let mut parser = GreedyParser::new(&mut m32);
+        if use_dict {
+            parser.enable_dictionary(&mut m32);
+        }
+        let mut out = Vec::<u8>::new();
+        let mut pos = 0usize;
+        let mut num_copies = 0usize;
+        let mut num_dict = 0usize;
+        let mut literal_bytes = 0usize;
+        loop {
+            let mut cmds = [Command::<InputReference>::default(); 8];
+            let mut offset = 0usize;
+            let res = parser.parse(data, 0, &mut pos, max_distance, &mut cmds[..cmds_per_call], &mut offset);
+            for cmd in cmds[..offset].iter() {
+                match *cmd {
+                    Command::Copy(_) => num_copies += 1,
+                    Command::Dict(_) => num_dict += 1,
+                    Command::Literal(ref lit) => literal_bytes += lit.data.data.len(),
+                    _ => {},
+                }
+            }
+            replay(&cmds[..offset], &mut out);
+            assert_eq!(out.len(), pos);
+            match res {
+                DivansOutputResult::Success => break,
+                DivansOutputResult::NeedsMoreOutput => assert!(offset != 0),
+                DivansOutputResult::Failure(m) => panic!("{:?}", m),
+            }
+        }
+        assert_eq!(&out[..], data);
+        parser.free(&mut m32);
+        (num_copies, num_dict, literal_bytes)
+    }
+
+    #[test]
+    fn test_greedy_roundtrip_text() {
+        let data = &include_bytes!("../../testdata/alice29")[..16384];
+        for cmds_per_call in [1usize, 2, 8].iter() {
+            let (num_copies, literal_bytes) = greedy_roundtrip(data, *cmds_per_call, 1 << 20);
+            assert!(num_copies != 0);
+            assert!(literal_bytes < data.len());
+        }
+    }
+
+    #[test]
+    fn test_greedy_respects_max_distance() {
+        let mut data = Vec::<u8>::new();
+        for i in 0..4096u32 {
+            data.push((i % 251) as u8);
+        }
+        let (near_copies, _) = greedy_roundtrip(&data[..], 8, 1 << 20);
+        assert!(near_copies != 0);
+        let (far_copies, literal_bytes) = greedy_roundtrip(&data[..], 8, 250);
+        assert_eq!(far_copies, 0);
+        assert_eq!(literal_bytes, data.len());
+        assert_eq!(greedy_roundtrip(&[], 1, 1 << 20), (0, 0));
+        assert_eq!(greedy_roundtrip(b"aaaaaaaaaaaaaaaaaaaa", 1, 1 << 20), (1, 1));
+    }
+
+    #[test]
+    fn test_greedy_dictionary_transforms() {
+        // nothing repeats, so only dictionary words can shorten the literals: this needs case
+        // changes (Information, WEBSITE) and transforms that add a prefix or suffix
+        let data = b"Information about THE WEBSITE, of the people. Something changed";
+        let (num_copies, num_dict, literal_bytes) = greedy_roundtrip_dict(&data[..], 8, 1 << 20, true);
+        assert_eq!(num_copies, 0);
+        assert!(num_dict >= 4, "{}", num_dict);
+        assert!(literal_bytes * 2 < data.len(), "{}", literal_bytes);
+        let text = &include_bytes!("../../testdata/alice29")[..16384];
+        for cmds_per_call in [1usize, 8].iter() {
+            let (_, num_dict, dict_literal_bytes) = greedy_roundtrip_dict(text, *cmds_per_call, 1 << 20, true);
+            assert!(num_dict != 0);
+            assert!(dict_literal_bytes < greedy_roundtrip(text, 8, 1 << 20).1);
+        }
+    }
+
+    #[test]
+    fn test_greedy_follows_repeat_hints() {
+        // pseudorandom bytes, whose repeats the single probe hash table forgets long before they recur
+        let mut data = Vec::<u8>::new();
+        let mut seed = 0x2545f491u32;
+        for _ in 0..65536 {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            data.push((seed >> 24) as u8);
+        }
+        for i in 40000..48000 {
+            data[i] = data[i - 39000];
+        }
+        let mut m32 = HeapAlloc::<u32>::new(0);
+        let mut parser = GreedyParser::new(&mut m32);
+        // data starts 5000 bytes into the input; the second hint overlaps the first and the third
+        // never checks out
+        assert!(parser.add_hint(&mut m32, RepeatHint{offset: 45000, len: 8000, distance: 39000}));
+        assert!(!parser.add_hint(&mut m32, RepeatHint{offset: 52999, len: 10, distance: 5}));
+        assert!(parser.add_hint(&mut m32, RepeatHint{offset: 55000, len: 100, distance: 39001}));
+        let mut out = Vec::<u8>::new();
+        let mut pos = 0usize;
+        let mut copies = Vec::<(u32, u32)>::new();
+        parser.set_data_offset(5000);
+        loop {
+            let mut cmds = [Command::<InputReference>::default(); 8];
+            let mut offset = 0usize;
+            let res = parser.parse(&data[..], 0, &mut pos, 1 << 20, &mut cmds[..], &mut offset);
+            for cmd in cmds[..offset].iter() {
+                if let Command::Copy(ref copy) = *cmd {
+                    copies.push((copy.distance, copy.num_bytes));
+                }
+            }
+            replay(&cmds[..offset], &mut out);
+            if let DivansOutputResult::Success = res {
+                break;
+            }
+        }
+        assert_eq!(&out[..], &data[..]);
+        assert_eq!(copies, [(39000, 8000)]);
+        parser.free(&mut m32);
+    }
+}
@@ -14,18 +14,30 @@
 
 use core;
 mod hash_match;
+mod greedy;
+mod dict_search;
+mod repeat_hint;
 use self::hash_match::HashMatch;
+pub use self::greedy::{GreedyParser, GREEDY_MIN_MATCH};
+#[cfg(feature="std")]
+pub use self::dict_search::PreparedDictionary;
+#[cfg(feature="std")]
+use std::sync::Arc;
+use self::repeat_hint::RepeatHint;
+pub use self::repeat_hint::MAX_REPEAT_HINTS;
 use brotli::InputReference;
 pub use alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut, StackAllocator};
 pub use super::slice_util::SliceReference;
-pub use interface::{DivansResult, DivansOutputResult};
-pub use super::interface::{PredictionModeContextMap, Command, Compressor, LiteralCommand, CopyCommand, DictCommand, FeatureFlagSliceType};
+pub use interface::{DivansResult, DivansOutputResult, ErrMsg};
+pub use super::interface::{PredictionModeContextMap, Command, Compressor, CopyCommand, DictCommand};
 pub struct RawToCmdState<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>,
     AllocU32:Allocator<u32>>{
     pub ring_buffer: RingBuffer,
     ring_buffer_decode_index: u32,
     ring_buffer_output_index: u32,
+    parsed_total: u64, // input offset of the byte at ring_buffer_output_index
     hash_match: HashMatch<AllocU32>,
+    parser: GreedyParser<AllocU32>,
     pub has_produced_header: bool,
 }
 
@@ -35,10 +47,31 @@ impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>, AllocU32:Allocator<u32>
             ring_buffer: rb,
             ring_buffer_decode_index: 0,
             ring_buffer_output_index: 0,
+            parsed_total: 0,
             hash_match:HashMatch::<AllocU32>::new(m32),
+            parser:GreedyParser::<AllocU32>::new(m32),
             has_produced_header: false, // only produce header if no ir_translation
         }
     }
+    // lets the parser emit dictionary words; leave off for streams with STREAM_FEATURE_NO_DICT
+    pub fn enable_dictionary(&mut self, m32: &mut AllocU32) {
+        self.parser.enable_dictionary(m32);
+    }
+    // searches dict for dictionary words from now on, dropping any index of this parser's own
+    #[cfg(feature="std")]
+    pub fn use_prepared_dictionary(&mut self, m32: &mut AllocU32, dict: Arc<PreparedDictionary>) {
+        self.parser.use_prepared_dictionary(m32, dict);
+    }
+    // The input at offset repeats the input distance bytes before it for len bytes, as far as the
+    // upper layer knows: the parser emits that copy instead of searching there.  The copy is still
+    // checked against the input, and only the part that matches is used.  Hints come in input order,
+    // at most MAX_REPEAT_HINTS of them ahead of the parser, and only for input not yet parsed.
+    pub fn hint_repeat(&mut self, m32: &mut AllocU32, offset: u64, len: u32, distance: u32) -> Result<(), ErrMsg> {
+        if offset < self.parsed_total || !self.parser.add_hint(m32, RepeatHint{offset, len, distance}) {
+            return Err(ErrMsg::RepeatHintRejected);
+        }
+        Ok(())
+    }
     pub fn raw_input_ir_mode(&mut self) {
         self.has_produced_header = true; // do not wish an additional prediction mode command at the end
     }
@@ -66,7 +99,8 @@ impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>, AllocU32:Allocator<u32>
             self.ring_buffer.slice_mut()[(self.ring_buffer_decode_index as usize)..(self.ring_buffer_decode_index as usize + max_copy)].clone_from_slice(&input[*input_offset..(*input_offset + max_copy)]);
             *input_offset += max_copy;
             self.ring_buffer_decode_index += max_copy as u32;
-            if self.ring_buffer_output_index != 0 {
+            // only wrap once the end is reached: a partially flushed lap may still be pending
+            if self.ring_buffer_output_index != 0 && self.ring_buffer_decode_index as usize == self.ring_buffer.slice().len() {
                self.ring_buffer_decode_index = 0;
             }
         }
@@ -139,19 +173,22 @@ impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>, AllocU32:Allocator<u32>
                 return DivansOutputResult::NeedsMoreOutput;
             }
         }
+        // stay clear of the edge of the window, as brotli does
+        let max_distance = self.ring_buffer.slice().len() - 16;
+        let ring: &'a [u8] = self.ring_buffer.slice();
         if self.ring_buffer_decode_index < self.ring_buffer_output_index {
-           let max_copy = self.ring_buffer.slice().len() - self.ring_buffer_output_index as usize;
-           if max_copy != 0 {
-               output[*output_offset] = Command::Literal(
-                   LiteralCommand::<InputReference<'a> >{
-                       data: InputReference{
-                           data:self.ring_buffer.slice().split_at(self.ring_buffer_output_index as usize).1.split_at(max_copy).0,
-                           orig_offset:self.ring_buffer_output_index as usize,
-                       },
-                       prob: FeatureFlagSliceType::<InputReference>::default(),
-                       high_entropy: false,
-                   });
-               *output_offset += 1;
+           // the tail of the previous lap: the front of the ring has been overwritten, so only
+           // the bytes between the decode and output indices remain as history
+           let mut pos = self.ring_buffer_output_index as usize;
+           let data_offset = self.parsed_total - pos as u64;
+           self.parser.set_data_offset(data_offset);
+           let res = self.parser.parse(ring, self.ring_buffer_decode_index as usize, &mut pos,
+                                       max_distance, output, output_offset);
+           self.parsed_total = data_offset + pos as u64;
+           self.ring_buffer_output_index = pos as u32;
+           match res {
+               DivansOutputResult::Success => {},
+               res => return res,
            }
            if self.ring_buffer_decode_index as usize == self.ring_buffer.slice().len() {
                self.ring_buffer_decode_index = 0;
@@ -159,27 +196,23 @@ impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>, AllocU32:Allocator<u32>
            self.ring_buffer_output_index = 0
         }
         if self.ring_buffer_decode_index != self.ring_buffer_output_index {
-           if *output_offset == output.len() {
-               return DivansOutputResult::NeedsMoreOutput;
-           }
-           let max_copy = self.ring_buffer_decode_index as usize - self.ring_buffer_output_index as usize;
-           output[*output_offset] = Command::Literal(
-               LiteralCommand::<InputReference<'a>>{
-                   data: InputReference{
-                       data:self.ring_buffer.slice().split_at(self.ring_buffer_output_index as usize).1.split_at(max_copy).0,
-                       orig_offset:self.ring_buffer_output_index as usize,
-                   },
-                   prob: FeatureFlagSliceType::<InputReference>::default(),
-                   high_entropy: false,
-               });
-           *output_offset += 1;
-           assert!(self.ring_buffer_output_index <= self.ring_buffer.slice().len() as u32);
-           self.ring_buffer_output_index = self.ring_buffer_decode_index;
+           let mut pos = self.ring_buffer_output_index as usize;
+           let data_offset = self.parsed_total - pos as u64;
+           self.parser.set_data_offset(data_offset);
+           let res = self.parser.parse(&ring[..self.ring_buffer_decode_index as usize], 0, &mut pos,
+                                       max_distance, output, output_offset);
+           self.parsed_total = data_offset + pos as u64;
+           self.ring_buffer_output_index = pos as u32;
            assert!(self.ring_buffer_output_index <= self.ring_buffer.slice().len() as u32);
+           match res {
+               DivansOutputResult::Success => {},
+               res => return res,
+           }
         }
         DivansOutputResult::Success
     }
     pub fn free(&mut self, m32: &mut AllocU32) {
         self.hash_match.free(m32);
+        self.parser.free(m32);
     }
 }
@@ -0,0 +1,176 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Copies an upper layer already knows about, such as the chunk matches of a deduplication layer.
+// They wait here in input order until the parser reaches them.
+use core;
+use alloc::{Allocator, SliceWrapper, SliceWrapperMut};
+
+pub const MAX_REPEAT_HINTS: usize = 4096;
+const HINT_WORDS: usize = 4; // low and high word of the offset, length, distance
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RepeatHint {
+    pub offset: u64, // of the first byte of the copy in the input
+    pub len: u32,
+    pub distance: u32,
+}
+
+impl RepeatHint {
+    pub fn end(&self) -> u64 {
+        self.offset + u64::from(self.len)
+    }
+}
+
+pub struct RepeatHints<AllocU32:Allocator<u32> > {
+    queue: AllocU32::AllocatedMemory, // HINT_WORDS per hint, allocated with the first one
+    start: usize,
+    len: usize,
+    end: u64, // of the last hint added
+}
+
+impl<AllocU32:Allocator<u32> > Default for RepeatHints<AllocU32> {
+    fn default() -> Self {
+        RepeatHints {
+            queue: AllocU32::AllocatedMemory::default(),
+            start: 0,
+            len: 0,
+            end: 0,
+        }
+    }
+}
+
+impl<AllocU32:Allocator<u32> > RepeatHints<AllocU32> {
+    // false if the hint starts before the end of the last one or the queue is full
+    pub fn push(&mut self, m32: &mut AllocU32, hint: RepeatHint) -> bool {
+        if hint.offset < self.end || self.len == MAX_REPEAT_HINTS {
+            return false;
+        }
+        if self.queue.slice().is_empty() {
+            self.queue = m32.alloc_cell(MAX_REPEAT_HINTS * HINT_WORDS);
+        }
+        let index = (self.start + self.len) % MAX_REPEAT_HINTS * HINT_WORDS;
+        self.queue.slice_mut()[index..index + HINT_WORDS].clone_from_slice(
+            &[hint.offset as u32, (hint.offset >> 32) as u32, hint.len, hint.distance]);
+        self.len += 1;
+        self.end = hint.end();
+        true
+    }
+    pub fn front(&self) -> Option<RepeatHint> {
+        if self.len == 0 {
+            return None;
+        }
+        let words = &self.queue.slice()[self.start * HINT_WORDS..(self.start + 1) * HINT_WORDS];
+        Some(RepeatHint {
+            offset: u64::from(words[0]) | (u64::from(words[1]) << 32),
+            len: words[2],
+            distance: words[3],
+        })
+    }
+    pub fn pop_front(&mut self) {
+        if self.len != 0 {
+            self.start = (self.start + 1) % MAX_REPEAT_HINTS;
+            self.len -= 1;
+        }
+    }
+    pub fn free(&mut self, m32: &mut AllocU32) {
+        m32.free_cell(core::mem::take(&mut self.queue));
+    }
+}
+
+#[cfg(all(test, feature="std", feature="decoder"))]
+mod test {
+    use std::vec::Vec;
+    use alloc_stdlib::HeapAlloc;
+    use decoded_command_iter::DecodedCommandIter;
+    use divans_compressor::DivansCompressorFactoryStruct;
+    use interface::{
+        Command,
+        Compressor,
+        DivansCompressorFactory,
+        DivansCompressorOptions,
+        DivansOutputResult,
+        DivansResult,
+        ErrMsg,
+        DefaultCDF16,
+    };
+    use test_support::{new_decompressor, decode};
+
+    #[test]
+    fn test_repeat_hint_roundtrip() {
+        // pseudorandom bytes with a block repeated from far back, which the greedy parser forgets
+        let mut input = Vec::<u8>::new();
+        let mut seed = 0x2545f491u32;
+        for _ in 0..65536 {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            input.push((seed >> 24) as u8);
+        }
+        for i in 40000..48000 {
+            input[i] = input[i - 39000];
+        }
+        let mut compressor = DivansCompressorFactoryStruct::<HeapAlloc<u8>, HeapAlloc<DefaultCDF16>>::new(
+            HeapAlloc::<u8>::new(0),
+            HeapAlloc::<u32>::new(0),
+            HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+            DivansCompressorOptions::default(),
+            ());
+        compressor.hint_repeat(40000, 8000, 39000).unwrap();
+        assert_eq!(compressor.hint_repeat(47000, 100, 1), Err(ErrMsg::RepeatHintRejected));
+        let mut stream = Vec::<u8>::new();
+        let mut buffer = [0u8; 4096];
+        let mut input_offset = 0usize;
+        while input_offset != input.len() {
+            let mut offset = 0usize;
+            if let DivansResult::Failure(m) = compressor.encode(&input[..], &mut input_offset, &mut buffer[..], &mut offset) {
+                panic!("{:?}", m);
+            }
+            stream.extend(&buffer[..offset]);
+        }
+        loop {
+            let mut offset = 0usize;
+            let res = compressor.flush(&mut buffer[..], &mut offset);
+            stream.extend(&buffer[..offset]);
+            match res {
+                DivansOutputResult::Success => break,
+                DivansOutputResult::NeedsMoreOutput => {},
+                DivansOutputResult::Failure(m) => panic!("{:?}", m),
+            }
+        }
+        // the whole input has been parsed by now
+        assert_eq!(compressor.hint_repeat(60000, 100, 1000), Err(ErrMsg::RepeatHintRejected));
+        compressor.free();
+        assert_eq!(decode(&stream[..]).unwrap(), input);
+        let decompressor = new_decompressor(false, false);
+        let mut remaining = &stream[..];
+        let mut input_buffer = [0u8; 4096];
+        let mut iter = DecodedCommandIter::new(decompressor, &mut input_buffer[..], |buf: &mut [u8]| {
+            let len = core::cmp::min(buf.len(), remaining.len());
+            buf[..len].clone_from_slice(&remaining[..len]);
+            remaining = &remaining[len..];
+            len
+        });
+        let mut decoded = Vec::new();
+        for cmd in iter.by_ref() {
+            decoded.push(cmd.unwrap());
+        }
+        assert_eq!(decoded.iter().filter(|cmd| match **cmd {
+            Command::Copy(ref copy) => copy.distance == 39000 && copy.num_bytes == 8000,
+            _ => false,
+        }).count(), 1);
+        for cmd in decoded.drain(..) {
+            iter.free_command(cmd);
+        }
+        iter.into_inner().free();
+    }
+}
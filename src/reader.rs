@@ -1,22 +1,25 @@
 #![cfg(feature="std")]
 pub use alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut, StackAllocator};
 pub use alloc_stdlib::HeapAlloc;
-use core;
 use std::error;
 use std::io;
 use std::io::{Read};
 use super::interface::{DivansResult, DivansOutputResult, ErrMsg};
-use ::interface::{Compressor, DivansCompressorFactory, Decompressor};
+#[cfg(feature="encoder")]
+use ::interface::{Compressor, DivansCompressorFactory};
+#[cfg(feature="decoder")]
+use ::interface::{Decompressor, TrailingBytesPolicy};
+#[cfg(feature="decoder")]
 use ::DivansDecompressorFactory;
+#[cfg(feature="encoder")]
 use ::brotli;
+#[cfg(feature="encoder")]
 use ::interface;
+#[cfg(feature="decoder")]
 use ::StaticCommand;
+#[cfg(feature="decoder")]
 use ::brotli::interface::Nop;
-impl core::fmt::Display for ErrMsg {
-    fn fmt(&self, f:&mut core::fmt::Formatter) -> core::result::Result<(), core::fmt::Error> {
-        <Self as core::fmt::Debug>::fmt(self, f)
-    }
-}
+use ::error::Error;
 impl error::Error for ErrMsg {
     fn description(&self) -> &str {
         "Divans error"
@@ -24,6 +27,7 @@ impl error::Error for ErrMsg {
     fn cause(&self) -> Option<&error::Error> {None}
 }
 trait Processor {
+   const STATE: &'static str; // what the Error of a failure says was going on
    fn process(&mut self, input:&[u8], input_offset:&mut usize, output:&mut [u8], output_offset:&mut usize) -> DivansResult;
    fn close(&mut self, output:&mut [u8], output_offset:&mut usize) -> DivansOutputResult;
 }
@@ -39,6 +43,8 @@ struct GenReader<R: Read,
   has_flushed: bool,
   input: R,
   read_error: Option<io::Error>,
+  total_in: u64, // bytes handed to the compressor
+  total_out: u64,
 }
 
 
@@ -83,6 +89,8 @@ impl<R:Read, P:Processor, BufferType:SliceWrapperMut<u8>> Read for GenReader<R,P
            };
            avail_in -= self.input_offset - old_input_offset;
            avail_out -= output_offset - old_output_offset;
+           self.total_in += (self.input_offset - old_input_offset) as u64;
+           self.total_out += (output_offset - old_output_offset) as u64;
            if avail_in == 0 {
              match self.read_error.take() {
                Some(err) => return Err(err),
@@ -94,10 +102,15 @@ impl<R:Read, P:Processor, BufferType:SliceWrapperMut<u8>> Read for GenReader<R,P
            }
            match ret {
                DivansResult::Failure(m) => {
-                   return Err(io::Error::new(io::ErrorKind::InvalidData, m));
+                   return Err(io::Error::from(Error::from(m).in_state(P::STATE).at(self.total_in, self.total_out)));
                },
              DivansResult::Success => {
-               if self.input_eof && avail_in == 0 && self.has_flushed {
+               if avail_in != 0 {
+                 // past the end of the stream, and not dropped (see TrailingBytesPolicy)
+                 let trailing = ErrMsg::TrailingInput(self.input_buffer.slice_mut()[self.input_offset]);
+                 return Err(io::Error::from(Error::from(trailing).in_state(P::STATE).at(self.total_in, self.total_out)));
+               }
+               if self.input_eof && self.has_flushed {
                  break;
                }
              },
@@ -118,6 +131,8 @@ impl<R:Read, C:Processor, BufferType:SliceWrapperMut<u8>> GenReader<R,C,BufferTy
             input_eof : false,
             has_flushed: !needs_flush,
             read_error: None,
+            total_in: 0,
+            total_out: 0,
         }
     }
     pub fn copy_to_front(&mut self) {
@@ -133,6 +148,7 @@ impl<R:Read, C:Processor, BufferType:SliceWrapperMut<u8>> GenReader<R,C,BufferTy
         }
     }
 }
+#[cfg(feature="encoder")]
 type DivansBrotliFactory = ::BrotliDivansHybridCompressorFactory<HeapAlloc<u8>,
 //                                                                 HeapAlloc<u32>,
                                                                  HeapAlloc<::DefaultCDF16>,
@@ -155,10 +171,13 @@ type DivansBrotliFactory = ::BrotliDivansHybridCompressorFactory<HeapAlloc<u8>,
                                                                      HeapAlloc<brotli::enc::histogram::ContextType>,
                                                                      HeapAlloc<brotli::enc::entropy_encode::HuffmanTree>,
                                                                      HeapAlloc<brotli::enc::ZopfliNode>>>;
+#[cfg(feature="encoder")]
 type DivansBrotliConstructedCompressor = <DivansBrotliFactory as ::DivansCompressorFactory<HeapAlloc<u8>,
                                                                                            HeapAlloc<u32>,
                                                                                            HeapAlloc<::DefaultCDF16>>>::ConstructedCompressor;
+#[cfg(feature="encoder")]
 impl<T:Compressor> Processor for T {
+   const STATE: &'static str = "compressing";
    fn process(&mut self, input:&[u8], input_offset:&mut usize, output:&mut [u8], output_offset:&mut usize) -> DivansResult {
        self.encode(input, input_offset, output, output_offset)
    }
@@ -167,15 +186,18 @@ impl<T:Compressor> Processor for T {
    }
 
 }
+#[cfg(feature="encoder")]
 pub struct DivansBrotliHybridCompressorReader<R:Read>(GenReader<R,
                                                                 DivansBrotliConstructedCompressor,
                                                                 <HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory,
                                                                >);
+#[cfg(feature="encoder")]
 impl<R:Read> Read for DivansBrotliHybridCompressorReader<R> {
 	fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
         self.0.read(buf)
     }
 }
+#[cfg(feature="encoder")]
 impl<R:Read> DivansBrotliHybridCompressorReader<R> {
     pub fn new(reader: R, opts: interface::DivansCompressorOptions, mut buffer_size: usize) -> Self {
        if buffer_size == 0 {
@@ -220,20 +242,25 @@ impl<R:Read> DivansBrotliHybridCompressorReader<R> {
 }
 
 
+#[cfg(feature="encoder")]
 type DivansCustomFactory = ::DivansCompressorFactoryStruct<HeapAlloc<u8>,
                                                          HeapAlloc<::DefaultCDF16>>;
+#[cfg(feature="encoder")]
 type DivansCustomConstructedCompressor = <DivansCustomFactory as ::DivansCompressorFactory<HeapAlloc<u8>,
                                                                                            HeapAlloc<u32>,
                                                                                            HeapAlloc<::DefaultCDF16>>>::ConstructedCompressor;
+#[cfg(feature="encoder")]
 pub struct DivansExperimentalCompressorReader<R:Read>(GenReader<R,
                                                                 DivansCustomConstructedCompressor,
                                                                  <HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory,
                                                                >);
+#[cfg(feature="encoder")]
 impl<R:Read> Read for DivansExperimentalCompressorReader<R> {
 	fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
         self.0.read(buf)
     }
 }
+#[cfg(feature="encoder")]
 impl<R:Read> DivansExperimentalCompressorReader<R> {
     pub fn new(reader: R, opts: interface::DivansCompressorOptions, mut buffer_size: usize) -> Self {
        if buffer_size == 0 {
@@ -259,9 +286,11 @@ impl<R:Read> DivansExperimentalCompressorReader<R> {
 }
 
 
+#[cfg(feature="decoder")]
 type StandardDivansDecompressorFactory = ::DivansDecompressorFactoryStruct<HeapAlloc<u8>,
                                                                            HeapAlloc<::DefaultCDF16>,
                                                                            HeapAlloc<StaticCommand>>;
+#[cfg(feature="decoder")]
 type DivansConstructedDecompressor = ::DivansDecompressor<<StandardDivansDecompressorFactory as ::DivansDecompressorFactory<HeapAlloc<u8>,
                                                                                                                             HeapAlloc<::DefaultCDF16>,
                                                                                                                             HeapAlloc<StaticCommand>>
@@ -269,7 +298,9 @@ type DivansConstructedDecompressor = ::DivansDecompressor<<StandardDivansDecompr
                                                           HeapAlloc<u8>,
                                                           HeapAlloc<::DefaultCDF16>,
                                                           HeapAlloc<StaticCommand>>;
+#[cfg(feature="decoder")]
 impl Processor for DivansConstructedDecompressor {
+   const STATE: &'static str = "decompressing";
    fn process(&mut self, input:&[u8], input_offset:&mut usize, output:&mut [u8], output_offset:&mut usize) -> DivansResult {
        self.decode(input, input_offset, output, output_offset)
    }
@@ -286,15 +317,18 @@ impl Processor for DivansConstructedDecompressor {
    }
 
 }
+#[cfg(feature="decoder")]
 pub struct DivansDecompressorReader<R:Read>(GenReader<R,
                                                       DivansConstructedDecompressor,
                                                       <HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory,
                                                       >);
+#[cfg(feature="decoder")]
 impl<R:Read> Read for DivansDecompressorReader<R> {
 	fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
         self.0.read(buf)
     }
 }
+#[cfg(feature="decoder")]
 impl<R:Read> DivansDecompressorReader<R> {
     pub fn new(reader: R, mut buffer_size: usize, skip_crc:bool, multithread:bool) -> Self {
        if buffer_size == 0 {
@@ -318,8 +352,16 @@ impl<R:Read> DivansDecompressorReader<R> {
                           false,
                        ))
     }
+    // see DivansDecompressor::set_trailing_bytes_policy; only possible before the first read
+    pub fn set_trailing_bytes_policy(&mut self, policy: TrailingBytesPolicy) -> Result<(), ErrMsg> {
+        self.0.compressor.set_trailing_bytes_policy(policy)
+    }
+    pub fn trailing_bytes(&self) -> Option<u64> {
+        self.0.compressor.trailing_bytes()
+    }
 }
 #[cfg(test)]
+#[cfg(all(feature="encoder", feature="decoder"))]
 mod test {
     use std::vec::Vec;
     use std::io;
@@ -469,6 +511,21 @@ mod test {
                            prior_bitmask_detection: 1,
                            stride_detection_quality: Some(2),
                            divans_ir_optimizer:0,
+                           literal_prescan: false,
+                           stream_features: 0,
+                           distance_bucketing: interface::DistanceBucketing::default(),
+                           mixer_warm_start: None,
+                           extended_stream_features: 0,
+                           framing_tag: None,
+                           compact_priors: true,
+                           format_version: None,
+                           content_hint: interface::ContentHint::Unknown,
+                           freeze_adaptation_after: None,
+                           priors_reset_interval: None,
+                           extended_stream_features2: 0,
+                           checksum: interface::ChecksumType::Crc32c,
+                           frame_alignment: None,
+                           authentication_key: None,
                        },
                        1);
     }
@@ -492,6 +549,21 @@ mod test {
                            speed_detection_quality: None,
                            stride_detection_quality: None,
                            divans_ir_optimizer:1,
+                           literal_prescan: false,
+                           stream_features: 0,
+                           distance_bucketing: interface::DistanceBucketing::default(),
+                           mixer_warm_start: None,
+                           extended_stream_features: 0,
+                           framing_tag: None,
+                           compact_priors: true,
+                           format_version: None,
+                           content_hint: interface::ContentHint::Unknown,
+                           freeze_adaptation_after: None,
+                           priors_reset_interval: None,
+                           extended_stream_features2: 0,
+                           checksum: interface::ChecksumType::Crc32c,
+                           frame_alignment: None,
+                           authentication_key: None,
                        },
                        4095);
     }
@@ -515,6 +587,21 @@ mod test {
                            prior_bitmask_detection: 1,
                            stride_detection_quality: None,
                            divans_ir_optimizer:0,
+                           literal_prescan: false,
+                           stream_features: 0,
+                           distance_bucketing: interface::DistanceBucketing::default(),
+                           mixer_warm_start: None,
+                           extended_stream_features: 0,
+                           framing_tag: None,
+                           compact_priors: true,
+                           format_version: None,
+                           content_hint: interface::ContentHint::Unknown,
+                           freeze_adaptation_after: None,
+                           priors_reset_interval: None,
+                           extended_stream_features2: 0,
+                           checksum: interface::ChecksumType::Crc32c,
+                           frame_alignment: None,
+                           authentication_key: None,
                        },
                        4095);
     }
@@ -538,6 +625,21 @@ mod test {
                            stride_detection_quality: Some(1),
                            prior_bitmask_detection: 1,
                            divans_ir_optimizer:1,
+                           literal_prescan: false,
+                           stream_features: 0,
+                           distance_bucketing: interface::DistanceBucketing::default(),
+                           mixer_warm_start: None,
+                           extended_stream_features: 0,
+                           framing_tag: None,
+                           compact_priors: true,
+                           format_version: None,
+                           content_hint: interface::ContentHint::Unknown,
+                           freeze_adaptation_after: None,
+                           priors_reset_interval: None,
+                           extended_stream_features2: 0,
+                           checksum: interface::ChecksumType::Crc32c,
+                           frame_alignment: None,
+                           authentication_key: None,
                        },
                        310000);
     }
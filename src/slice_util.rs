@@ -13,6 +13,7 @@
 //   limitations under the License.
 use core;
 use brotli;
+use alloc_util;
 pub use alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut, StackAllocator};
 
 #[derive(Copy,Clone,Default,Debug)]
@@ -133,6 +134,74 @@ impl<'a, T> Default for SliceReference<'a, T> {
     }
 }
 
+// A window into a refcounted buffer, so commands can point into data a server already holds
+// instead of copying it into allocator-owned memory. Clones share the buffer.
+#[cfg(feature="std")]
+#[derive(Clone, Debug)]
+pub struct SharedSlice {
+    buf: std::sync::Arc<[u8]>,
+    range: core::ops::Range<usize>,
+}
+
+#[cfg(feature="std")]
+impl SharedSlice {
+    pub fn new(buf: std::sync::Arc<[u8]>) -> Self {
+        let len = buf.len();
+        SharedSlice {
+            buf: buf,
+            range: 0..len,
+        }
+    }
+    // the bytes [start, start + len) of this window, sharing the same buffer
+    pub fn subslice(&self, start: usize, len: usize) -> Self {
+        assert!(start + len <= self.range.end - self.range.start);
+        SharedSlice {
+            buf: self.buf.clone(),
+            range: (self.range.start + start)..(self.range.start + start + len),
+        }
+    }
+}
+
+#[cfg(feature="std")]
+impl Default for SharedSlice {
+    fn default() -> Self {
+        SharedSlice::new(std::sync::Arc::from(&[][..]))
+    }
+}
+
+#[cfg(feature="std")]
+impl From<std::sync::Arc<[u8]>> for SharedSlice {
+    fn from(buf: std::sync::Arc<[u8]>) -> Self {
+        SharedSlice::new(buf)
+    }
+}
+
+#[cfg(feature="std")]
+impl SliceWrapper<u8> for SharedSlice {
+    fn slice(&self) -> &[u8] {
+        &self.buf[self.range.clone()]
+    }
+}
+
+// bytes::Bytes is already a refcounted window, so it only needs the trait
+#[cfg(feature="bytes")]
+#[derive(Clone, Debug, Default)]
+pub struct BytesSlice(pub bytes::Bytes);
+
+#[cfg(feature="bytes")]
+impl From<bytes::Bytes> for BytesSlice {
+    fn from(buf: bytes::Bytes) -> Self {
+        BytesSlice(buf)
+    }
+}
+
+#[cfg(feature="bytes")]
+impl SliceWrapper<u8> for BytesSlice {
+    fn slice(&self) -> &[u8] {
+        &self.0[..]
+    }
+}
+
 pub struct AllocatedMemoryPrefix<T, AllocT:Allocator<T>>(pub AllocT::AllocatedMemory, pub u32);
 
 impl<T, AllocT: Allocator<T>> core::ops::Index<usize> for AllocatedMemoryPrefix<T, AllocT> {
@@ -188,6 +257,9 @@ impl <T, AllocT:Allocator<T>> AllocatedMemoryPrefix<T, AllocT> {
         debug_assert!(len <= mem.slice().len(), "Must realloc to a smaller size for AllocatedMemoryPrefix");
         AllocatedMemoryPrefix::<T, AllocT>(mem, len as u32)
     }
+    pub fn duplicate(&self, m8 : &mut AllocT) -> Self where T: Clone {
+        AllocatedMemoryPrefix::<T, AllocT>(alloc_util::duplicate_cell(m8, &self.0), self.1)
+    }
 }
 
 
@@ -241,7 +313,64 @@ impl <T, AllocT:Allocator<T>> AllocatedMemoryRange<T, AllocT> {
         debug_assert!(range.start <= range.end);
         AllocatedMemoryRange::<T, AllocT>(mem, range)
     }
+    pub fn duplicate(&self, m8 : &mut AllocT) -> Self where T: Clone {
+        AllocatedMemoryRange::<T, AllocT>(alloc_util::duplicate_cell(m8, &self.0), self.1.clone())
+    }
 }
 
+#[cfg(all(test, feature="std", feature="encoder", feature="decoder"))]
+mod test {
+    use std::vec::Vec;
+    use conformance::{conformance_expected_output, ConformanceSlice};
+    use super::SharedSlice;
+    use interface::{
+        Command,
+        FeatureFlagSliceType,
+        LiteralCommand,
+        CopyCommand,
+        DivansCompressorOptions,
+    };
+    use test_support::{encode_with_options, decode};
 
-
+    #[test]
+    fn test_shared_slice_commands() {
+        let text: &[u8] = b"refcounted buffers held by a server can be coded in place, refcounted buffers";
+        let buf = std::sync::Arc::<[u8]>::from(text);
+        let shared = SharedSlice::new(buf.clone());
+        let mut owned_cmds = Vec::<Command<ConformanceSlice>>::new();
+        let mut shared_cmds = Vec::<Command<SharedSlice>>::new();
+        for &(start, len) in [(0usize, 20usize), (20, 40), (60, 17)].iter() {
+            owned_cmds.push(Command::Literal(LiteralCommand::<ConformanceSlice> {
+                data: ConformanceSlice(text[start..start + len].to_vec()),
+                prob: FeatureFlagSliceType::<ConformanceSlice>::default(),
+                high_entropy: false,
+            }));
+            shared_cmds.push(Command::Literal(LiteralCommand::<SharedSlice> {
+                data: shared.subslice(start, len),
+                prob: FeatureFlagSliceType::<SharedSlice>::default(),
+                high_entropy: false,
+            }));
+        }
+        owned_cmds.push(Command::Copy(CopyCommand{distance: 77, num_bytes: 18}));
+        shared_cmds.push(Command::Copy(CopyCommand{distance: 77, num_bytes: 18}));
+        let owned = encode_with_options(&owned_cmds[..], DivansCompressorOptions::default()).unwrap();
+        let from_shared = encode_with_options(&shared_cmds[..], DivansCompressorOptions::default()).unwrap();
+        assert_eq!(owned, from_shared);
+        assert_eq!(decode(&from_shared[..]).unwrap(), conformance_expected_output(&owned_cmds[..]).unwrap());
+        #[cfg(feature="bytes")]
+        {
+            let bytes = ::bytes::Bytes::from_static(text);
+            let bytes_cmds: Vec<Command<::slice_util::BytesSlice>> = [(0usize, 20usize), (20, 40), (60, 17)].iter().map(
+                |&(start, len)| Command::Literal(LiteralCommand::<::slice_util::BytesSlice> {
+                    data: ::slice_util::BytesSlice(bytes.slice(start..start + len)),
+                    prob: FeatureFlagSliceType::<::slice_util::BytesSlice>::default(),
+                    high_entropy: false,
+                })).chain(Some(Command::Copy(CopyCommand{distance: 77, num_bytes: 18}))).collect();
+            assert_eq!(encode_with_options(&bytes_cmds[..], DivansCompressorOptions::default()).unwrap(), owned);
+        }
+        // the commands only held references
+        drop(shared_cmds);
+        drop(shared);
+        assert_eq!(std::sync::Arc::strong_count(&buf), 1);
+    }
+}
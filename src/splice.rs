@@ -0,0 +1,571 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Joins compressed streams without decoding them, eg. to append log segments server-side.
+// Each stream keeps its own header, models and crc: the only byte that changes is the tag of the
+// first stream's trailer, which becomes SPLICE_TAG so a decoder starts over on the stream after it
+// and writes its output right after the output of the first.
+// Since the spliced streams decode independently, an edit only needs to re-encode the ones it touches,
+// and input cut into chunks may be compressed, or decompressed given the layout, a chunk per thread.
+#![cfg(feature="std")]
+use core;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::vec::Vec;
+use alloc_stdlib::HeapAlloc;
+use interface::{Compressor, Decompressor, DivansCompressorOptions, DivansOutputResult, DivansResult, ErrMsg, FormatVersion, Nop,
+                DefaultCDF16, HEADER_FEATURE_OFFSET, HEADER_LENGTH, MAGIC_NUMBER, MAGIC_NUMBER_EXTENDED,
+                STORED_FRAME_HEADER_LENGTH, STORED_MAGIC, STREAM_FEATURE_AUTHENTICATED};
+use error::Error;
+use divans_decompressor::{DivansDecompressorFactory, DivansDecompressorFactoryStruct, StaticCommand};
+use wire::{CHECKSUM_LENGTH, CHECKSUM_TAG, SPLICE_TAG};
+use writer::new_brotli_hybrid_compressor;
+
+// where one of the streams making up a spliced stream lies
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SplicedStream {
+    pub input: Range<usize>, // in the compressed bytes, trailer included
+    pub output: Range<usize>, // in what they decode to
+}
+
+fn check_header(stream: &[u8]) -> Result<(), ErrMsg> {
+    if stream.len() < HEADER_LENGTH + CHECKSUM_LENGTH {
+        return Err(ErrMsg::TruncatedInput);
+    }
+    if stream[..2] != MAGIC_NUMBER[..2] {
+        return Err(ErrMsg::MagicNumberWrongA(stream[0], stream[1]));
+    }
+    if stream[2..4] != MAGIC_NUMBER[2..] && stream[2..4] != MAGIC_NUMBER_EXTENDED[2..] {
+        return Err(ErrMsg::MagicNumberWrongB(stream[2], stream[3]));
+    }
+    if stream[HEADER_FEATURE_OFFSET] & STREAM_FEATURE_AUTHENTICATED != 0 {
+        return Err(ErrMsg::SpliceAuthenticated);
+    }
+    Ok(())
+}
+
+// A stored frame (see interface::STORED_MAGIC), as the compressors write for tiny inputs, re-encoded as
+// a stream with a trailer to splice at; None if stream is no stored frame.
+fn restream_stored_frame(stream: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    if stream.len() < STORED_FRAME_HEADER_LENGTH || stream[..STORED_MAGIC.len()] != STORED_MAGIC[..] {
+        return Ok(None);
+    }
+    let end = STORED_FRAME_HEADER_LENGTH + usize::from(stream[STORED_MAGIC.len()]);
+    if stream.len() < end {
+        return Err(Error::from(ErrMsg::TruncatedInput));
+    }
+    if stream.len() > end {
+        return Err(Error::from(ErrMsg::TrailingInput(stream[end])));
+    }
+    encode(&stream[STORED_FRAME_HEADER_LENGTH..end], DivansCompressorOptions::default()).map(Some)
+}
+
+// A stream that decodes to what a decodes to followed by what b does.  Either may itself be spliced,
+// or be a stored frame, which is re-encoded as a stream first.
+// Authenticated streams are refused, as is an a that does not end in a checksum trailer.
+pub fn splice(a: &[u8], b: &[u8]) -> Result<Vec<u8>, Error> {
+    if let Some(a) = restream_stored_frame(a).map_err(|e| e.in_state("checking the first stream"))? {
+        return splice(&a[..], b);
+    }
+    if let Some(b) = restream_stored_frame(b).map_err(|e| e.in_state("checking the second stream"))? {
+        return splice(a, &b[..]);
+    }
+    check_header(a).map_err(|m| Error::from(m).in_state("checking the first stream"))?;
+    check_header(b).map_err(|m| Error::from(m).in_state("checking the second stream"))?;
+    let tag_offset = a.len() - CHECKSUM_TAG.len();
+    if a[tag_offset..] != CHECKSUM_TAG {
+        return Err(Error::from(ErrMsg::TruncatedInput).in_state("checking the first stream"));
+    }
+    let mut ret = Vec::with_capacity(a.len() + b.len());
+    ret.extend_from_slice(&a[..tag_offset]);
+    ret.extend_from_slice(&SPLICE_TAG[..]);
+    ret.extend_from_slice(b);
+    Ok(ret)
+}
+
+// Decodes stream one spliced stream at a time, appending each to layout and, given out, its output.
+// The last may end in SPLICE_TAG, so that a run of streams can be cut out and decoded by itself.
+// A failure says how far into stream and its output it got.
+fn decode_spliced(stream: &[u8], mut out: Option<&mut Vec<u8>>, layout: &mut Vec<SplicedStream>) -> Result<(), Error> {
+    let mut decompressor = DivansDecompressorFactoryStruct::<HeapAlloc<u8>,
+                                                             HeapAlloc<DefaultCDF16>,
+                                                             HeapAlloc<StaticCommand>>::new(
+        HeapAlloc::<u8>::new(0),
+        HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+        HeapAlloc::<StaticCommand>::new(StaticCommand::nop()),
+        false,
+        false);
+    decompressor.set_stop_between_streams(true)?;
+    let mut buffer = [0u8; 4096];
+    let mut input_offset = 0usize;
+    let mut output_len = 0usize;
+    let mut start = (0usize, 0usize);
+    let result = loop {
+        let mut output_offset = 0usize;
+        let res = decompressor.decode(stream, &mut input_offset, &mut buffer[..], &mut output_offset);
+        if let Some(ref mut out) = out {
+            out.extend_from_slice(&buffer[..output_offset]);
+        }
+        output_len += output_offset;
+        match res {
+            DivansResult::Success => {
+                layout.push(SplicedStream{input: start.0..input_offset, output: start.1..output_len});
+                start = (input_offset, output_len);
+                if input_offset == stream.len() {
+                    break Ok(());
+                }
+                if !stream[..input_offset].ends_with(&SPLICE_TAG[..]) {
+                    break Err(ErrMsg::TrailingInput(stream[input_offset]));
+                }
+            },
+            DivansResult::NeedsMoreOutput => {},
+            DivansResult::NeedsMoreInput => break Err(ErrMsg::TruncatedInput),
+            DivansResult::Failure(m) => break Err(m),
+        }
+    };
+    decompressor.free();
+    result.map_err(|m| Error::from(m).in_state("decompressing").at(input_offset as u64, output_len as u64))
+}
+
+// The layout of a spliced stream, which takes decoding it; worth keeping next to the stream to edit it repeatedly.
+pub fn spliced_streams(stream: &[u8]) -> Result<Vec<SplicedStream>, Error> {
+    let mut layout = Vec::<SplicedStream>::new();
+    decode_spliced(stream, None, &mut layout)?;
+    if stream.ends_with(&SPLICE_TAG[..]) {
+        return Err(Error::from(ErrMsg::TruncatedInput).in_state("decompressing")); // the stream it was spliced to is missing
+    }
+    Ok(layout)
+}
+
+fn encode(input: &[u8], mut opts: DivansCompressorOptions) -> Result<Vec<u8>, Error> {
+    // a stored frame has no trailer to splice at
+    opts.format_version = Some(core::cmp::min(opts.format_version.unwrap_or(FormatVersion::LATEST), FormatVersion::Extended));
+    let mut compressor = new_brotli_hybrid_compressor(opts);
+    let mut input_offset = 0usize;
+    let mut unused = 0usize;
+    let mut result = Ok(());
+    while result.is_ok() && input_offset < input.len() {
+        match compressor.encode(input, &mut input_offset, &mut [], &mut unused) {
+            DivansResult::NeedsMoreInput | DivansResult::Success => {},
+            DivansResult::NeedsMoreOutput => result = Err(ErrMsg::BrotliInternalEncodeStreamNeedsOutputWithoutFlush),
+            DivansResult::Failure(m) => result = Err(m),
+        }
+    }
+    let mut ret = Vec::<u8>::new();
+    let mut buffer = [0u8; 4096];
+    while result.is_ok() {
+        let mut offset = 0usize;
+        let res = compressor.flush(&mut buffer[..], &mut offset);
+        ret.extend_from_slice(&buffer[..offset]);
+        match res {
+            DivansOutputResult::Success => break,
+            DivansOutputResult::NeedsMoreOutput => {},
+            DivansOutputResult::Failure(m) => result = Err(m),
+        }
+    }
+    compressor.free();
+    let output_len = ret.len() as u64;
+    result.map(|_| ret).map_err(|m| Error::from(m).in_state("compressing").at(input_offset as u64, output_len))
+}
+
+// Replaces the output bytes in range with content.  Only the streams of layout (see spliced_streams)
+// that the range touches are decoded and re-encoded with opts, into a single stream spliced in their place.
+pub fn replace_range(stream: &[u8],
+                     layout: &[SplicedStream],
+                     range: Range<usize>,
+                     content: &[u8],
+                     opts: DivansCompressorOptions) -> Result<Vec<u8>, Error> {
+    let total = match layout.last() {
+        Some(last) if last.input.end == stream.len() => last.output.end,
+        _ => return Err(Error::from(ErrMsg::InputChangedAfterContinuation)), // the layout is of some other stream
+    };
+    if range.start > range.end || range.end > total {
+        return Err(Error::from(ErrMsg::InputOffsetOutOfBounds));
+    }
+    // an insertion at a boundary goes into the stream before it
+    let first = layout.iter().position(|s| range.start < s.output.end).unwrap_or(layout.len() - 1);
+    let last = core::cmp::max(first, layout.iter().rposition(|s| s.output.start < range.end).unwrap_or(first));
+    let affected = layout[first].input.start..layout[last].input.end;
+    let base = layout[first].output.start;
+    let mut decoded = Vec::<u8>::new();
+    decode_spliced(&stream[affected.clone()], Some(&mut decoded), &mut Vec::new())
+        .map_err(|e| e.shifted(affected.start as u64, base as u64))?;
+    if decoded.len() != layout[last].output.end - base {
+        return Err(Error::from(ErrMsg::InputChangedAfterContinuation));
+    }
+    let mut replacement = Vec::<u8>::with_capacity(decoded.len() + content.len());
+    replacement.extend_from_slice(&decoded[..range.start - base]);
+    replacement.extend_from_slice(content);
+    replacement.extend_from_slice(&decoded[range.end - base..]);
+    let mut reencoded = encode(&replacement[..], opts)?;
+    if affected.end != stream.len() {
+        reencoded = splice(&reencoded[..], &stream[affected.end..])?;
+    }
+    let mut ret = Vec::<u8>::with_capacity(affected.start + reencoded.len());
+    ret.extend_from_slice(&stream[..affected.start]); // ends in SPLICE_TAG already
+    ret.extend_from_slice(&reencoded[..]);
+    Ok(ret)
+}
+
+// Calls work on 0..count from up to threads threads and returns the results in order, or the first failure.
+fn run_on_pool<T: Send, F: Fn(usize) -> Result<T, Error> + Sync>(count: usize, threads: usize, work: F) -> Result<Vec<T>, Error> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new((0..count).map(|_| None).collect::<Vec<Option<Result<T, Error>>>>());
+    thread::scope(|scope| {
+        for _ in 0..core::cmp::min(core::cmp::max(threads, 1), count) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                if index >= count {
+                    break;
+                }
+                let result = work(index);
+                results.lock().unwrap()[index] = Some(result);
+            });
+        }
+    });
+    results.into_inner().unwrap().into_iter().map(|result| result.unwrap()).collect()
+}
+
+// How compress_parallel cuts its input.  Either way the cuts depend on the input alone, never on the
+// threads, so the output is the same however many there are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Chunking {
+    Fixed(usize), // every so many bytes
+    // Where a rolling hash of the bytes before says so: about every so many bytes, and never under a
+    // quarter or over four times that.  An edit moves only the cuts near it, so the streams of the
+    // rest of the input come out as before.
+    Content(usize),
+}
+
+const fn gear_table() -> [u64; 256] {
+    let mut ret = [0u64; 256];
+    let mut state = 0x9e3779b97f4a7c15u64;
+    let mut index = 0;
+    while index < 256 {
+        // splitmix64
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        ret[index] = z ^ (z >> 31);
+        index += 1;
+    }
+    ret
+}
+
+static GEAR: [u64; 256] = gear_table();
+
+// the pieces chunking cuts input into, in order; one empty piece for empty input
+pub fn chunk_boundaries(input: &[u8], chunking: Chunking) -> Vec<Range<usize>> {
+    let mut ret = Vec::<Range<usize>>::new();
+    match chunking {
+        Chunking::Fixed(chunk_size) => {
+            assert!(chunk_size != 0);
+            let mut start = 0usize;
+            while start < input.len() {
+                let end = core::cmp::min(start + chunk_size, input.len());
+                ret.push(start..end);
+                start = end;
+            }
+        },
+        Chunking::Content(average) => {
+            assert!(average != 0);
+            let min_size = core::cmp::max(1, average / 4);
+            let max_size = average.saturating_mul(4);
+            // the hash gathers a bit per byte, so it forgets all but the last 64 bytes
+            let mask_bits = average.next_power_of_two().trailing_zeros();
+            let mask = if mask_bits == 0 { 0 } else { !0u64 << (64 - mask_bits) };
+            let mut start = 0usize;
+            while start < input.len() {
+                let limit = core::cmp::min(start.saturating_add(max_size), input.len());
+                let mut end = limit;
+                let mut hash = 0u64;
+                for (index, byte) in input[start..limit].iter().enumerate() {
+                    hash = (hash << 1).wrapping_add(GEAR[usize::from(*byte)]);
+                    if index + 1 >= min_size && hash & mask == 0 {
+                        end = start + index + 1;
+                        break;
+                    }
+                }
+                ret.push(start..end);
+                start = end;
+            }
+        },
+    }
+    if ret.is_empty() {
+        ret.push(0..0);
+    }
+    ret
+}
+
+// Compresses input in chunk_size pieces on up to threads threads and splices the streams together,
+// like pigz: each chunk starts from fresh models, which costs some ratio for a near linear speedup.
+// Returns the layout alongside, since decompress_parallel needs it and finding it otherwise takes a decode.
+pub fn compress_parallel(input: &[u8],
+                         chunk_size: usize,
+                         threads: usize,
+                         opts: DivansCompressorOptions) -> Result<(Vec<u8>, Vec<SplicedStream>), Error> {
+    compress_parallel_chunked(input, Chunking::Fixed(chunk_size), threads, opts, false)
+}
+
+// compress_parallel cutting the input as chunking says.  With verify, each chunk is compressed again
+// on this thread and the call fails with NondeterministicOutput should any stream differ, for callers
+// needing reproducible output who would rather pay twice than trust it.
+pub fn compress_parallel_chunked(input: &[u8],
+                                 chunking: Chunking,
+                                 threads: usize,
+                                 opts: DivansCompressorOptions,
+                                 verify: bool) -> Result<(Vec<u8>, Vec<SplicedStream>), Error> {
+    if opts.authentication_key.is_some() {
+        return Err(Error::from(ErrMsg::SpliceAuthenticated));
+    }
+    let chunks = chunk_boundaries(input, chunking);
+    let streams = run_on_pool(chunks.len(), threads, |index| encode(&input[chunks[index].clone()], opts))?;
+    if verify {
+        for (chunk, stream) in chunks.iter().zip(streams.iter()) {
+            if encode(&input[chunk.clone()], opts)? != *stream {
+                return Err(Error::from(ErrMsg::NondeterministicOutput).at(0, chunk.start as u64));
+            }
+        }
+    }
+    let mut ret = Vec::<u8>::with_capacity(streams.iter().map(|stream| stream.len()).sum());
+    let mut layout = Vec::<SplicedStream>::with_capacity(chunks.len());
+    for (index, (chunk, stream)) in chunks.iter().zip(streams.iter()).enumerate() {
+        layout.push(SplicedStream{input: ret.len()..ret.len() + stream.len(),
+                                  output: chunk.clone()});
+        ret.extend_from_slice(&stream[..]);
+        if index + 1 != streams.len() {
+            let tag_offset = ret.len() - SPLICE_TAG.len();
+            ret[tag_offset..].clone_from_slice(&SPLICE_TAG[..]);
+        }
+    }
+    Ok((ret, layout))
+}
+
+// Decodes the streams of layout (see compress_parallel and spliced_streams) on up to threads threads.
+pub fn decompress_parallel(stream: &[u8], layout: &[SplicedStream], threads: usize) -> Result<Vec<u8>, Error> {
+    let mut expected_start = (0usize, 0usize);
+    for item in layout.iter() {
+        if (item.input.start, item.output.start) != expected_start || item.input.end < item.input.start
+            || item.output.end < item.output.start {
+            return Err(Error::from(ErrMsg::InputChangedAfterContinuation)); // the layout is of some other stream
+        }
+        expected_start = (item.input.end, item.output.end);
+    }
+    if expected_start.0 != stream.len() || layout.is_empty() || stream.ends_with(&SPLICE_TAG[..]) {
+        return Err(Error::from(ErrMsg::InputChangedAfterContinuation));
+    }
+    let outputs = run_on_pool(layout.len(), threads, |index| {
+        let item = &layout[index];
+        let mut output = Vec::<u8>::with_capacity(item.output.end - item.output.start);
+        decode_spliced(&stream[item.input.clone()], Some(&mut output), &mut Vec::new())
+            .map_err(|e| e.shifted(item.input.start as u64, item.output.start as u64))?;
+        if output.len() != item.output.end - item.output.start {
+            return Err(Error::from(ErrMsg::InputChangedAfterContinuation));
+        }
+        Ok(output)
+    })?;
+    let mut ret = Vec::<u8>::with_capacity(expected_start.1);
+    for output in outputs.iter() {
+        ret.extend_from_slice(&output[..]);
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use std::vec::Vec;
+    use conformance::{conformance_commands, conformance_expected_output};
+    use super::{splice, spliced_streams, replace_range, compress_parallel, compress_parallel_chunked, decompress_parallel,
+                 chunk_boundaries, Chunking};
+    use wire::{CHECKSUM_LENGTH, SPLICE_TAG};
+    use interface::{
+        DivansCompressorOptions,
+        ErrMsg,
+        ChecksumPolicy,
+        STREAM_FEATURE_COMMAND_TYPE_TREE,
+        AUTHENTICATION_KEY_LENGTH,
+        STORED_MAGIC,
+    };
+    use test_support::{encode, encode_with_options, decode, decode_with, DecodeConfig};
+    use oneshot::compress;
+
+    #[test]
+    fn test_splice() {
+        let first = conformance_commands("mixed");
+        let second = conformance_commands("dict");
+        let third = conformance_commands("copy");
+        let mut expected = conformance_expected_output(&first[..]).unwrap();
+        expected.extend(conformance_expected_output(&second[..]).unwrap());
+        let a = encode(&first[..], STREAM_FEATURE_COMMAND_TYPE_TREE).unwrap();
+        let b = encode(&second[..], 0).unwrap();
+        let spliced = splice(&a[..], &b[..]).unwrap();
+        assert_eq!(spliced.len(), a.len() + b.len());
+        assert_eq!(decode(&spliced[..]).unwrap(), expected);
+        for multithread in [false, true].iter() {
+            let mut config = DecodeConfig::new(*multithread);
+            config.threaded_crc = true;
+            assert_eq!(decode_with(&spliced[..], config, |_| {}).into_output(), Ok(expected.clone()));
+            let mut config = DecodeConfig::new(*multithread);
+            config.checksum_policy = Some(ChecksumPolicy::SkipButParse);
+            assert_eq!(decode_with(&spliced[..], config, |_| {}).result, Ok(()));
+        }
+        // a spliced stream may be spliced again
+        let c = encode(&third[..], 0).unwrap();
+        expected.extend(conformance_expected_output(&third[..]).unwrap());
+        assert_eq!(decode(&splice(&spliced[..], &c[..]).unwrap()[..]).unwrap(), expected);
+        // each stream keeps checking its own crc
+        let mut bad_crc = spliced.clone();
+        bad_crc[a.len() - CHECKSUM_LENGTH] ^= 1;
+        match decode(&bad_crc[..]) {
+            Err(ErrMsg::BadChecksum(_, _)) => {},
+            res => panic!("{:?}", res),
+        }
+        assert_eq!(splice(&a[..a.len() - 1], &b[..]).unwrap_err().code(), Some(ErrMsg::TruncatedInput));
+        assert_eq!(splice(&a[..], &b[1..]).unwrap_err().code(), Some(ErrMsg::MagicNumberWrongA(b[1], b[2])));
+        let key = [0x5au8; AUTHENTICATION_KEY_LENGTH];
+        let mut opts = DivansCompressorOptions::default();
+        opts.authentication_key = Some(key);
+        let tagged = encode_with_options(&first[..], opts).unwrap();
+        assert_eq!(splice(&tagged[..], &b[..]).unwrap_err().code(), Some(ErrMsg::SpliceAuthenticated));
+        assert_eq!(splice(&a[..], &tagged[..]).unwrap_err().code(), Some(ErrMsg::SpliceAuthenticated));
+        // nothing may be appended to an authenticated stream by rewriting its trailer by hand
+        let mut appended = tagged.clone();
+        let tag_offset = tagged.len() - 32 - 4;
+        appended[tag_offset..tag_offset + 4].clone_from_slice(&SPLICE_TAG[..]);
+        appended.extend_from_slice(&b[..]);
+        let mut config = DecodeConfig::new(false);
+        config.authentication_key = Some(key);
+        match decode_with(&appended[..], config, |_| {}).result {
+            Err(ErrMsg::BadChecksum(_, _)) => {},
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_splice_stored_frames() {
+        let a = compress(b"hello", DivansCompressorOptions::default()).unwrap();
+        let b = compress(b"world", DivansCompressorOptions::default()).unwrap();
+        assert_eq!(a[..4], STORED_MAGIC);
+        assert_eq!(decode(&splice(&a[..], &b[..]).unwrap()[..]).unwrap(), b"helloworld");
+        let input = &include_bytes!("../testdata/alice29")[..1024];
+        let long = compress(input, DivansCompressorOptions::default()).unwrap();
+        let mut expected = input.to_vec();
+        expected.extend_from_slice(b"hello");
+        assert_eq!(decode(&splice(&long[..], &a[..]).unwrap()[..]).unwrap(), expected);
+        let spliced = splice(&a[..], &long[..]).unwrap();
+        assert_eq!(decode(&spliced[..]).unwrap(), [&b"hello"[..], input].concat());
+        assert_eq!(spliced_streams(&spliced[..]).unwrap().len(), 2);
+        let empty = compress(b"", DivansCompressorOptions::default()).unwrap();
+        assert_eq!(decode(&splice(&empty[..], &b[..]).unwrap()[..]).unwrap(), b"world");
+        assert_eq!(splice(&a[..a.len() - 1], &b[..]).unwrap_err().code(), Some(ErrMsg::TruncatedInput));
+        assert_eq!(splice(&a[..], &[&b[..], &b"!"[..]].concat()[..]).unwrap_err().code(), Some(ErrMsg::TrailingInput(b'!')));
+    }
+
+    #[test]
+    fn test_replace_range() {
+        let mut stream = Vec::<u8>::new();
+        let mut expected = Vec::<u8>::new();
+        for name in ["mixed", "dict", "copy"].iter() {
+            let commands = conformance_commands(name);
+            let encoded = encode(&commands[..], 0).unwrap();
+            stream = if stream.is_empty() {encoded} else {splice(&stream[..], &encoded[..]).unwrap()};
+            expected.extend(conformance_expected_output(&commands[..]).unwrap());
+        }
+        let layout = spliced_streams(&stream[..]).unwrap();
+        assert_eq!(layout.len(), 3);
+        assert_eq!((layout[2].input.end, layout[2].output.end), (stream.len(), expected.len()));
+        let opts = DivansCompressorOptions::default();
+        // an edit inside the middle stream leaves the bytes of the others alone
+        let mid = layout[1].output.start + 3;
+        assert!(mid + 5 <= layout[1].output.end);
+        let edited = replace_range(&stream[..], &layout[..], mid..mid + 5, b"spliced in", opts).unwrap();
+        let mut want = expected.clone();
+        want.splice(mid..mid + 5, b"spliced in".iter().cloned());
+        assert_eq!(decode(&edited[..]).unwrap(), want);
+        assert_eq!(&edited[..layout[1].input.start], &stream[..layout[1].input.start]);
+        assert!(edited.ends_with(&stream[layout[2].input.clone()]));
+        assert_eq!(spliced_streams(&edited[..]).unwrap().len(), 3);
+        // a deletion across a boundary re-encodes both streams as one
+        let edited = replace_range(&stream[..], &layout[..], 2..layout[1].output.start + 1, b"", opts).unwrap();
+        let mut want = expected.clone();
+        want.drain(2..layout[1].output.start + 1);
+        assert_eq!(decode(&edited[..]).unwrap(), want);
+        assert_eq!(spliced_streams(&edited[..]).unwrap().len(), 2);
+        // appending
+        let end = expected.len();
+        let edited = replace_range(&stream[..], &layout[..], end..end, b"tail", opts).unwrap();
+        assert!(decode(&edited[..]).unwrap().ends_with(b"tail"));
+        assert_eq!(replace_range(&stream[..], &layout[..], end..end + 1, b"", opts).unwrap_err().code(), Some(ErrMsg::InputOffsetOutOfBounds));
+        assert_eq!(replace_range(&edited[..], &layout[..], 0..1, b"", opts).unwrap_err().code(), Some(ErrMsg::InputChangedAfterContinuation));
+        assert_eq!(spliced_streams(&stream[..layout[1].input.end]).unwrap_err().code(), Some(ErrMsg::TruncatedInput));
+    }
+
+    #[test]
+    fn test_compress_parallel() {
+        let mut input = Vec::<u8>::new();
+        for name in ["mixed", "dict", "copy", "mixed"].iter() {
+            input.extend(conformance_expected_output(&conformance_commands(name)[..]).unwrap());
+        }
+        let chunk_size = input.len() / 3 + 1;
+        let opts = DivansCompressorOptions::default();
+        let (stream, layout) = compress_parallel(&input[..], chunk_size, 4, opts).unwrap();
+        assert_eq!(layout.len(), 3);
+        assert_eq!(layout, spliced_streams(&stream[..]).unwrap());
+        assert_eq!(decode(&stream[..]).unwrap(), input);
+        for threads in [1usize, 2, 8].iter() {
+            assert_eq!(decompress_parallel(&stream[..], &layout[..], *threads).unwrap(), input);
+        }
+        // the same chunks compressed on one thread come out the same
+        assert_eq!(compress_parallel(&input[..], chunk_size, 1, opts).unwrap().0, stream);
+        let (empty, empty_layout) = compress_parallel(&[], chunk_size, 4, opts).unwrap();
+        assert_eq!(decode(&empty[..]).unwrap(), Vec::<u8>::new());
+        assert_eq!(decompress_parallel(&empty[..], &empty_layout[..], 4).unwrap(), Vec::<u8>::new());
+        assert_eq!(decompress_parallel(&stream[..], &layout[1..], 4).unwrap_err().code(), Some(ErrMsg::InputChangedAfterContinuation));
+        assert_eq!(decompress_parallel(&stream[..], &layout[..2], 4).unwrap_err().code(), Some(ErrMsg::InputChangedAfterContinuation));
+        let mut keyed = opts;
+        keyed.authentication_key = Some([0x5au8; AUTHENTICATION_KEY_LENGTH]);
+        assert_eq!(compress_parallel(&input[..], chunk_size, 4, keyed).unwrap_err().code(), Some(ErrMsg::SpliceAuthenticated));
+    }
+
+    #[test]
+    fn test_content_chunking() {
+        let input = ::synthetic::Synthetic::Random.generate(7, 40000);
+        let chunking = Chunking::Content(4096);
+        let chunks = chunk_boundaries(&input[..], chunking);
+        assert!(chunks.len() > 2);
+        assert_eq!(chunks[0].start, 0);
+        assert_eq!(chunks[chunks.len() - 1].end, input.len());
+        for (chunk, next) in chunks.iter().zip(chunks.iter().skip(1)) {
+            assert_eq!(chunk.end, next.start);
+            assert!(chunk.len() >= 1024 && chunk.len() <= 16384);
+        }
+        let opts = DivansCompressorOptions::default();
+        let (stream, layout) = compress_parallel_chunked(&input[..], chunking, 1, opts, true).unwrap();
+        assert_eq!(layout.iter().map(|s| s.output.clone()).collect::<Vec<_>>(), chunks);
+        for threads in [2usize, 8].iter() {
+            assert_eq!(compress_parallel_chunked(&input[..], chunking, *threads, opts, true).unwrap().0, stream);
+        }
+        assert_eq!(decompress_parallel(&stream[..], &layout[..], 4).unwrap(), input);
+        // an insertion near the start moves only the cuts around it
+        let mut edited = input.clone();
+        edited.splice(10..10, [1u8, 2, 3].iter().cloned());
+        let edited_chunks = chunk_boundaries(&edited[..], chunking);
+        let shifted: Vec<_> = edited_chunks.iter().skip(1).map(|c| c.start - 3..c.end - 3).collect();
+        assert_eq!(&shifted[shifted.len() - 2..], &chunks[chunks.len() - 2..]);
+        assert_eq!(chunk_boundaries(&[], chunking), [0..0]);
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// The static literal tables of a metablock, for EXTENDED_FEATURE_STATIC_LITERAL_MODEL streams.
+// The nibbles of its literals are counted in the tables codec/static_literal.rs codes them with,
+// and each table's counts become its weight exponents.  The context of a literal comes from the
+// bytes before it in the metablock, copies included, so only the first literal may be miscounted.
+// Literals of a block type coded low nibble first are counted that way round.
+use brotli;
+use alloc::{Allocator, SliceWrapper, SliceWrapperMut};
+use brotli::interface::Command; // these see the commands of brotli's callback, before divans' own
+use interface::{PredictionModeContextMap, LITERAL_BLOCK_SWITCH_LSB_FIRST};
+use codec::{get_lut0, get_lut1};
+use codec::static_literal::{STATIC_LITERAL_TABLES_PER_CONTEXT, STATIC_LITERAL_WEIGHT_BYTES};
+use probability::static_cdf::weight_exponents;
+
+// a table coding fewer nibbles than this stays uniform: its weights would cost more than they save
+const MIN_TABLE_NIBBLES: u32 = 48;
+
+// the byte at offset of the input, if the metablock holds it
+fn metablock_byte(mb: &brotli::InputPair, offset: usize) -> Option<u8> {
+    for part in [&mb.0, &mb.1].iter() {
+        if offset >= part.orig_offset && offset - part.orig_offset < part.data.len() {
+            return Some(part.data[offset - part.orig_offset]);
+        }
+    }
+    None
+}
+
+// fills weights (STATIC_LITERAL_WEIGHT_BYTES of them) with the tables for cmds
+pub fn choose_static_literal_weights<AllocU32:Allocator<u32>,
+                                     SliceType:SliceWrapper<u8>>(
+    m32: &mut AllocU32,
+    pm: &PredictionModeContextMap<SliceType>,
+    cmds: &[Command<brotli::SliceOffset>],
+    mb: &brotli::InputPair,
+    weights: &mut [u8]) {
+    for w in weights.iter_mut() {
+        *w = 0;
+    }
+    if pm.literal_prediction_mode().0 > 3 {
+        return;
+    }
+    let lut0 = get_lut0(pm.literal_prediction_mode());
+    let lut1 = get_lut1(pm.literal_prediction_mode());
+    let mut counts = m32.alloc_cell(STATIC_LITERAL_WEIGHT_BYTES); // one per nibble value of each table
+    {
+        let cmap = pm.literal_context_map.slice();
+        let counts = counts.slice_mut();
+        let mut btype = 0u8;
+        let mut order_rotation = 0u32;
+        let mut history = 0u32; // the last 2 bytes, most recent in the low byte
+        for cmd in cmds.iter() {
+            match *cmd {
+                Command::BlockSwitchLiteral(ref bs) => {
+                    btype = bs.block_type();
+                    order_rotation = u32::from(bs.stride() & LITERAL_BLOCK_SWITCH_LSB_FIRST != 0) << 2;
+                },
+                Command::Literal(ref lit) => {
+                    let start = lit.data.0;
+                    if let (Some(prev), Some(prev_prev)) = (metablock_byte(mb, start.wrapping_sub(1)),
+                                                            metablock_byte(mb, start.wrapping_sub(2))) {
+                        history = u32::from(prev) | (u32::from(prev_prev) << 8);
+                    }
+                    let data = match brotli::thaw_pair(cmd, mb) {
+                        Command::Literal(lit) => lit.data.data,
+                        _ => unreachable!(),
+                    };
+                    for literal in data.iter() {
+                        let selected_context = lut0[history as u8 as usize] | lut1[(history >> 8) as u8 as usize];
+                        let actual_context = match cmap.get(usize::from(btype) * 64 + usize::from(selected_context)) {
+                            Some(ctx) => *ctx,
+                            None => selected_context,
+                        };
+                        let coded = literal.rotate_left(order_rotation);
+                        let high_nibble = coded >> 4;
+                        let high_table = usize::from(actual_context) * STATIC_LITERAL_TABLES_PER_CONTEXT;
+                        let low_table = high_table + 1 + usize::from(high_nibble);
+                        counts[high_table * 16 + usize::from(high_nibble)] += 1;
+                        counts[low_table * 16 + usize::from(coded & 0xf)] += 1;
+                        history = (history << 8) | u32::from(*literal);
+                    }
+                },
+                _ => {},
+            }
+        }
+        for (table_weights, table_counts) in weights.chunks_mut(16).zip(counts.chunks(16)) {
+            if table_counts.iter().sum::<u32>() >= MIN_TABLE_NIBBLES {
+                table_weights.clone_from_slice(&weight_exponents(table_counts));
+            }
+        }
+    }
+    m32.free_cell(counts);
+}
+
+#[cfg(all(test, feature="std", feature="decoder"))]
+mod test {
+    use std::vec::Vec;
+    use conformance::{conformance_commands, conformance_expected_output};
+    use interface::{
+        DivansCompressorOptions,
+        HEADER_EXTENDED_FEATURE_OFFSET,
+        MAGIC_NUMBER_EXTENDED,
+        EXTENDED_FEATURE_STATIC_LITERAL_MODEL,
+    };
+    use test_support::{encode_with_options, compress_hybrid, decode, decoded_options};
+
+    #[test]
+    fn test_static_literal_model_roundtrip() {
+        // the raw compressor picks no tables, so every literal is coded uniformly
+        let commands = conformance_commands("mixed");
+        let expected = conformance_expected_output(&commands[..]).unwrap();
+        let mut opts = DivansCompressorOptions::default();
+        opts.extended_stream_features = EXTENDED_FEATURE_STATIC_LITERAL_MODEL;
+        let uniform = encode_with_options(&commands[..], opts).unwrap();
+        assert_eq!(uniform[HEADER_EXTENDED_FEATURE_OFFSET], EXTENDED_FEATURE_STATIC_LITERAL_MODEL);
+        assert_eq!(decode(&uniform[..]).unwrap(), expected);
+        // skewed literals with few repeats, which the hybrid compressor's tables capture
+        let alphabet = b"eeeeeeeetttttaaaaooooiiinnnsshhrrdl  ";
+        let mut seed = 0x2545f491u32;
+        let mut input = Vec::<u8>::new();
+        for _ in 0..200000 {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            input.push(alphabet[(seed >> 16) as usize % alphabet.len()]);
+        }
+        let stream = compress_hybrid(&input[..], opts);
+        assert_eq!(stream[..4], MAGIC_NUMBER_EXTENDED);
+        assert_eq!(decode(&stream[..]).unwrap(), input);
+        for multithread in [false, true].iter() {
+            assert_eq!(decoded_options(&stream[..], *multithread).unwrap().extended_stream_features,
+                       EXTENDED_FEATURE_STATIC_LITERAL_MODEL);
+        }
+        assert!(stream.len() < input.len() / 2, "{} of {}", stream.len(), input.len());
+        opts.extended_stream_features = 0;
+        let adaptive = compress_hybrid(&input[..], opts);
+        assert!(stream.len() < adaptive.len() * 11 / 10, "{} vs {}", stream.len(), adaptive.len());
+    }
+}
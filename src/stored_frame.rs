@@ -0,0 +1,276 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Keeps tiny inputs from growing by more than STORED_FRAME_HEADER_LENGTH bytes.  A compressor
+// holds its input back while there is under STORED_INPUT_LIMIT bytes of it.  Should more arrive,
+// the held input goes to the modeled encoder ahead of it.  Should flush come first, the input is
+// written as a stored frame (see interface::STORED_MAGIC) unless the modeled stream comes out no
+// larger: a stream never takes less than its header and trailer, and otherwise the input is small
+// enough to encode on the spot into a buffer of the stored frame's size.
+use core;
+use interface::{DivansOutputResult, DivansResult, ErrMsg, HEADER_LENGTH, STORED_FRAME_HEADER_LENGTH,
+                STORED_INPUT_LIMIT, STORED_MAGIC};
+use wire::CHECKSUM_LENGTH;
+
+const MAX_FRAME_LENGTH: usize = STORED_FRAME_HEADER_LENGTH + STORED_INPUT_LIMIT - 1;
+
+pub struct TinyInput {
+    held: [u8; STORED_INPUT_LIMIT - 1],
+    held_len: usize,
+    replayed: usize, // of the held input, into the modeled encoder
+    holding: bool,
+    // the whole output, once flush has chosen it
+    frame: [u8; MAX_FRAME_LENGTH],
+    frame_len: usize,
+    frame_written: usize,
+}
+
+impl TinyInput {
+    // holds input back only if enabled, ie. DivansCompressorOptions::stores_tiny_inputs
+    pub fn new(enabled: bool) -> Self {
+        TinyInput {
+            held: [0u8; STORED_INPUT_LIMIT - 1],
+            held_len: 0,
+            replayed: 0,
+            holding: enabled,
+            frame: [0u8; MAX_FRAME_LENGTH],
+            frame_len: 0,
+            frame_written: 0,
+        }
+    }
+}
+
+impl TinyInput {
+    // the bytes of the chosen frame flush has yet to write, None unless it chose one
+    pub fn frame_remaining(&self) -> Option<usize> {
+        if self.frame_len == 0 {
+            return None;
+        }
+        Some(self.frame_len - self.frame_written)
+    }
+}
+
+// the compressors that hold tiny inputs back, with their encode and flush as they were before
+pub trait ModeledCompressor {
+    fn tiny_input(&mut self) -> &mut TinyInput;
+    fn encode_modeled(&mut self,
+                      input: &[u8],
+                      input_offset: &mut usize,
+                      output: &mut [u8],
+                      output_offset: &mut usize) -> DivansResult;
+    fn flush_modeled(&mut self, output: &mut [u8], output_offset: &mut usize) -> DivansOutputResult;
+    fn sync_flush_modeled(&mut self, output: &mut [u8], output_offset: &mut usize) -> DivansOutputResult;
+}
+
+// Stops holding input back and hands what was held to the modeled encoder; NeedsMoreInput once it
+// all went in, whatever the encoder says of input to come.
+pub fn release_held_input<C:ModeledCompressor>(compressor: &mut C, output: &mut [u8], output_offset: &mut usize) -> DivansResult {
+    let (held, held_len, mut replayed) = {
+        let tiny = compressor.tiny_input();
+        tiny.holding = false;
+        (tiny.held, tiny.held_len, tiny.replayed)
+    };
+    while replayed != held_len {
+        let res = compressor.encode_modeled(&held[..held_len], &mut replayed, output, output_offset);
+        compressor.tiny_input().replayed = replayed;
+        match res {
+            DivansResult::NeedsMoreInput | DivansResult::Success => {},
+            res => return res,
+        }
+    }
+    DivansResult::NeedsMoreInput
+}
+
+pub fn encode<C:ModeledCompressor>(compressor: &mut C,
+                                   input: &[u8],
+                                   input_offset: &mut usize,
+                                   output: &mut [u8],
+                                   output_offset: &mut usize) -> DivansResult {
+    {
+        let tiny = compressor.tiny_input();
+        if tiny.frame_len != 0 {
+            return DivansResult::Failure(ErrMsg::NotAllowedToEncodeAfterFlush);
+        }
+        if tiny.holding {
+            let remaining = &input[*input_offset..];
+            if tiny.held_len + remaining.len() < STORED_INPUT_LIMIT {
+                tiny.held[tiny.held_len..tiny.held_len + remaining.len()].clone_from_slice(remaining);
+                tiny.held_len += remaining.len();
+                *input_offset = input.len();
+                return DivansResult::NeedsMoreInput;
+            }
+        }
+    }
+    match release_held_input(compressor, output, output_offset) {
+        DivansResult::NeedsMoreInput => {},
+        res => return res,
+    }
+    compressor.encode_modeled(input, input_offset, output, output_offset)
+}
+
+pub fn flush<C:ModeledCompressor>(compressor: &mut C, output: &mut [u8], output_offset: &mut usize) -> DivansOutputResult {
+    if compressor.tiny_input().holding {
+        match choose_frame(compressor) {
+            Ok(()) => {},
+            Err(m) => return DivansOutputResult::Failure(m),
+        }
+    }
+    let tiny = compressor.tiny_input();
+    if tiny.frame_len == 0 {
+        return compressor.flush_modeled(output, output_offset);
+    }
+    let to_write = core::cmp::min(tiny.frame_len - tiny.frame_written, output.len() - *output_offset);
+    output[*output_offset..*output_offset + to_write].clone_from_slice(&tiny.frame[tiny.frame_written..tiny.frame_written + to_write]);
+    *output_offset += to_write;
+    tiny.frame_written += to_write;
+    if tiny.frame_written == tiny.frame_len {
+        DivansOutputResult::Success
+    } else {
+        DivansOutputResult::NeedsMoreOutput
+    }
+}
+
+// Once a stream is being written out before its end, it is modeled whatever its size: held input
+// goes to the modeled encoder for good.
+pub fn sync_flush<C:ModeledCompressor>(compressor: &mut C, output: &mut [u8], output_offset: &mut usize) -> DivansOutputResult {
+    if compressor.tiny_input().frame_len != 0 {
+        return DivansOutputResult::Failure(ErrMsg::NotAllowedToEncodeAfterFlush);
+    }
+    match release_held_input(compressor, output, output_offset) {
+        DivansResult::NeedsMoreInput | DivansResult::Success => {},
+        DivansResult::NeedsMoreOutput => return DivansOutputResult::NeedsMoreOutput,
+        DivansResult::Failure(m) => return DivansOutputResult::Failure(m),
+    }
+    compressor.sync_flush_modeled(output, output_offset)
+}
+
+// fills in the frame with the modeled stream if it is no larger than the stored frame, else with that
+fn choose_frame<C:ModeledCompressor>(compressor: &mut C) -> Result<(), ErrMsg> {
+    let stored_len = STORED_FRAME_HEADER_LENGTH + compressor.tiny_input().held_len;
+    let mut frame = [0u8; MAX_FRAME_LENGTH];
+    let mut frame_len = 0usize;
+    let mut modeled = HEADER_LENGTH + CHECKSUM_LENGTH <= stored_len;
+    if modeled {
+        match release_held_input(compressor, &mut frame[..stored_len], &mut frame_len) {
+            DivansResult::NeedsMoreInput | DivansResult::Success => {},
+            DivansResult::NeedsMoreOutput => modeled = false,
+            DivansResult::Failure(m) => return Err(m),
+        }
+    }
+    if modeled {
+        match compressor.flush_modeled(&mut frame[..stored_len], &mut frame_len) {
+            DivansOutputResult::Success => {},
+            DivansOutputResult::NeedsMoreOutput => modeled = false,
+            DivansOutputResult::Failure(m) => return Err(m),
+        }
+    }
+    let tiny = compressor.tiny_input();
+    tiny.holding = false;
+    if !modeled {
+        frame[..STORED_MAGIC.len()].clone_from_slice(&STORED_MAGIC[..]);
+        frame[STORED_MAGIC.len()] = tiny.held_len as u8;
+        frame[STORED_FRAME_HEADER_LENGTH..stored_len].clone_from_slice(&tiny.held[..tiny.held_len]);
+        frame_len = stored_len;
+    }
+    tiny.frame = frame;
+    tiny.frame_len = frame_len;
+    Ok(())
+}
+
+#[cfg(all(test, feature="std", feature="decoder"))]
+mod test {
+    use std::vec::Vec;
+    use alloc_stdlib::HeapAlloc;
+    use splice::{compress_parallel, decompress_parallel};
+    use wire::FramingTag;
+    use divans_compressor::DivansCompressorFactoryStruct;
+    use interface::{
+        Decompressor,
+        DivansCompressorFactory,
+        DivansCompressorOptions,
+        FormatVersion,
+        DivansResult,
+        DefaultCDF16,
+        MAGIC_NUMBER,
+        STORED_FRAME_HEADER_LENGTH,
+    };
+    use test_support::{new_decompressor, compress_hybrid, compress_with, decode};
+
+    #[test]
+    fn test_stored_frames() {
+        use interface::{STORED_INPUT_LIMIT, STORED_MAGIC};
+        let random = ::synthetic::Synthetic::Random.generate(7, STORED_INPUT_LIMIT + 8);
+        let repeating = b"abcd".repeat(STORED_INPUT_LIMIT);
+        let raw = |input: &[u8], opts: DivansCompressorOptions| {
+            let mut compressor = DivansCompressorFactoryStruct::<HeapAlloc<u8>, HeapAlloc<DefaultCDF16>>::new(
+                HeapAlloc::<u8>::new(0),
+                HeapAlloc::<u32>::new(0),
+                HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+                opts,
+                ());
+            let stream = compress_with(&mut compressor, input).unwrap();
+            compressor.free();
+            stream
+        };
+        let opts = DivansCompressorOptions::default();
+        for len in [0, 1, 5, 19, 20, 40, STORED_INPUT_LIMIT - 1, STORED_INPUT_LIMIT].iter() {
+            for input in [&random[..*len], &repeating[..*len]].iter() {
+                for stream in [raw(input, opts), compress_hybrid(input, opts)].iter() {
+                    assert_eq!(decode(&stream[..]).unwrap(), *input);
+                    if *len < STORED_INPUT_LIMIT {
+                        assert!(stream.len() <= input.len() + STORED_FRAME_HEADER_LENGTH, "{} {}", len, stream.len());
+                    } else {
+                        assert_eq!(&stream[..MAGIC_NUMBER.len()], &MAGIC_NUMBER[..]);
+                    }
+                }
+            }
+        }
+        // random input only ever grows as a stream
+        let stored = compress_hybrid(&random[..40], opts);
+        assert_eq!(&stored[..STORED_MAGIC.len()], &STORED_MAGIC[..]);
+        assert_eq!(stored.len(), 40 + STORED_FRAME_HEADER_LENGTH);
+        // a byte at a time each way
+        let mut decompressor = new_decompressor(false, false);
+        let mut decoded = Vec::<u8>::new();
+        let mut input_offset = 0usize;
+        loop {
+            let mut byte = [0u8; 1];
+            let mut output_offset = 0usize;
+            let end = core::cmp::min(input_offset + 1, stored.len());
+            let res = decompressor.decode(&stored[..end], &mut input_offset, &mut byte[..], &mut output_offset);
+            decoded.extend(&byte[..output_offset]);
+            match res {
+                DivansResult::Success => break,
+                DivansResult::NeedsMoreInput => assert!(input_offset < stored.len()),
+                DivansResult::NeedsMoreOutput => {},
+                DivansResult::Failure(m) => panic!("{:?}", m),
+            }
+        }
+        decompressor.free();
+        assert_eq!(decoded, &random[..40]);
+        // decoders from before stored frames get a stream, as do spliceable and framed ones
+        let mut pinned = opts;
+        pinned.format_version = Some(FormatVersion::Extended);
+        assert!(!pinned.stores_tiny_inputs());
+        let mut framed = opts;
+        framed.framing_tag = Some(FramingTag::new(b"tag").unwrap());
+        assert!(!framed.stores_tiny_inputs());
+        let stream = compress_hybrid(&random[..40], pinned);
+        assert_eq!(&stream[..MAGIC_NUMBER.len()], &MAGIC_NUMBER[..]);
+        assert_eq!(decode(&stream[..]).unwrap(), &random[..40]);
+        let (parallel, layout) = compress_parallel(&random[..], 40, 2, opts).unwrap();
+        assert_eq!(layout.len(), 2);
+        assert_eq!(decompress_parallel(&parallel[..], &layout[..], 2).unwrap(), random);
+    }
+}
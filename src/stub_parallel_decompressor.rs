@@ -1,25 +1,32 @@
 #![cfg(not(feature="std"))]
 pub use interface::{DivansCompressorFactory, BlockSwitch, LiteralBlockSwitch, Command, Compressor, CopyCommand, Decompressor, DictCommand, LiteralCommand, Nop, NewWithAllocator, ArithmeticEncoderOrDecoder, LiteralPredictionModeNibble, PredictionModeContextMap, free_cmd, FeatureFlagSliceType,
-                    DefaultCDF16, DivansResult};
+                    DefaultCDF16, DivansResult, DecodedStreamOptions, DecodeStats};
 pub use alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut, StackAllocator};
 pub use super::divans_decompressor::StaticCommand;
 pub use core::marker::PhantomData;
+use core::hash::Hasher;
+use codec::decoder::SubDigest;
+use codec::weights::MixerSnapshot;
+use output_digest::OutputDigest;
 
 pub struct ParallelDivansProcess<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                                  AllocU8:Allocator<u8>,
                                  AllocCDF16:Allocator<DefaultCDF16>,
-                                 AllocCommand:Allocator<StaticCommand>> {
+                                 AllocCommand:Allocator<StaticCommand>,
+                                 D:Hasher+Default=SubDigest> {
     p0: PhantomData<DefaultDecoder>,
     p1: PhantomData<AllocU8>,
     p2: PhantomData<AllocCDF16>,
     p3: PhantomData<AllocCommand>,
+    p4: PhantomData<D>,
 }
 
 impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
      AllocU8:Allocator<u8>,
      AllocCDF16:Allocator<DefaultCDF16>,
-     AllocCommand:Allocator<StaticCommand>>
-    ParallelDivansProcess<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand> {
+     AllocCommand:Allocator<StaticCommand>,
+     D:Hasher+Default>
+    ParallelDivansProcess<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand, D> {
 
     pub fn new<T>(_header: &mut T, mut _window_size: usize) -> Self {
         unimplemented!();
@@ -31,6 +38,54 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                   _output_offset: &mut usize) -> DivansResult {
         unimplemented!();
     }
+    pub fn options(&self) -> Option<DecodedStreamOptions> {
+        unimplemented!();
+    }
+    pub fn stats(&self) -> DecodeStats {
+        unimplemented!();
+    }
+    pub fn take_mixer_snapshots(&mut self, _out: &mut [MixerSnapshot]) -> usize {
+        unimplemented!();
+    }
+    pub fn mixer_snapshots_dropped(&self) -> u64 {
+        unimplemented!();
+    }
+    pub fn mixer_snapshot(&self) -> MixerSnapshot {
+        unimplemented!();
+    }
+    pub fn expected_crc(&self) -> Option<u32> {
+        unimplemented!();
+    }
+    pub fn expected_checksum(&self) -> Option<u64> {
+        unimplemented!();
+    }
+    pub fn output_needed(&self) -> usize {
+        unimplemented!();
+    }
+    pub fn take_window(&mut self) -> Option<AllocU8::AllocatedMemory> {
+        unimplemented!();
+    }
+    pub fn window(&self) -> Option<(&[u8], usize)> {
+        unimplemented!();
+    }
+    pub fn take_next_stream<T>(&mut self) -> Option<T> {
+        unimplemented!();
+    }
+    pub fn output_digest(&self) -> Option<&OutputDigest> {
+        unimplemented!();
+    }
+    pub fn output_digest_mut(&mut self) -> Option<&mut OutputDigest> {
+        unimplemented!();
+    }
+    pub fn next_stream<T>(&self) -> Option<&T> {
+        unimplemented!();
+    }
+    pub fn next_stream_mut<T>(&mut self) -> Option<&mut T> {
+        unimplemented!();
+    }
+    pub fn take_digest(&mut self) -> D {
+        unimplemented!();
+    }
     pub fn free_ref(&mut self){
         unimplemented!();
     }
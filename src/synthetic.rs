@@ -0,0 +1,210 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Generated inputs for benchmarks and for tests of the adaptive heuristics, so neither depends on a
+// corpus that cannot be shipped.  The same kind, seed and length always give the same bytes.
+use core;
+#[cfg(feature="std")]
+use std::vec::Vec;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Synthetic {
+    // words of a vocabulary of this many, each usually followed by one of a few favorites, in
+    // sentences and lines
+    Text{vocabulary: u32},
+    // fixed size records of a counter, a timestamp, a few categories and padding
+    Records{stride: usize},
+    // incompressible
+    Random,
+    // a random block of period bytes repeated over and over, mutation_rate in 256 bytes replaced
+    Repeats{period: usize, mutation_rate: u8},
+    // all of the above in turn, in spans of 4 to 64 KiB
+    Mixed,
+}
+
+// Knuth's MMIX linear congruential generator, handing out its high bits
+struct Lcg(u64);
+
+impl Lcg {
+    fn new(seed: u64) -> Self {
+        let mut ret = Lcg(seed ^ 0x2545f4914f6cdd1d);
+        ret.next();
+        ret
+    }
+    fn next(&mut self) -> u32 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (self.0 >> 32) as u32
+    }
+    // below bound, which is nonzero
+    fn below(&mut self, bound: u32) -> u32 {
+        ((u64::from(self.next()) * u64::from(bound)) >> 32) as u32
+    }
+    // below bound, favoring small values the way word frequencies do
+    fn skewed_below(&mut self, bound: u32) -> u32 {
+        let uniform = u64::from(self.next());
+        ((((uniform * uniform) >> 32) * u64::from(bound)) >> 32) as u32
+    }
+}
+
+const ONSETS: [&[u8]; 16] = [b"", b"b", b"c", b"d", b"f", b"g", b"h", b"l", b"m", b"n", b"p", b"r", b"s", b"t", b"th", b"st"];
+const NUCLEI: [&[u8]; 8] = [b"a", b"e", b"i", b"o", b"u", b"ea", b"ou", b"y"];
+
+// writes as much of word index of the vocabulary of seed as fits, returning how much that was
+fn write_word(seed: u64, index: u32, capitalize: bool, output: &mut [u8]) -> usize {
+    let mut rng = Lcg::new(seed.wrapping_add(u64::from(index).wrapping_mul(0x9e3779b97f4a7c15)));
+    let syllables = 1 + rng.skewed_below(4) as usize;
+    let mut len = 0usize;
+    for _ in 0..syllables {
+        let onset = ONSETS[rng.below(ONSETS.len() as u32) as usize];
+        let nucleus = NUCLEI[rng.below(NUCLEI.len() as u32) as usize];
+        for &byte in onset.iter().chain(nucleus.iter()) {
+            if len == output.len() {
+                return len;
+            }
+            output[len] = if capitalize && len == 0 { byte.to_ascii_uppercase() } else { byte };
+            len += 1;
+        }
+    }
+    len
+}
+
+fn fill_text(rng: &mut Lcg, seed: u64, vocabulary: u32, output: &mut [u8]) {
+    let vocabulary = core::cmp::max(vocabulary, 1);
+    let mut offset = 0usize;
+    let mut word = 0u32;
+    let mut sentence_start = true;
+    while offset < output.len() {
+        word = if rng.below(2) == 0 {
+            (word.wrapping_mul(31).wrapping_add(rng.below(4))) % vocabulary // a favorite follower
+        } else {
+            rng.skewed_below(vocabulary)
+        };
+        offset += write_word(seed, word, sentence_start, &mut output[offset..]);
+        sentence_start = rng.below(12) == 0;
+        let separator: &[u8] = if !sentence_start {
+            if rng.below(16) == 0 { b", " } else { b" " }
+        } else if rng.below(4) == 0 {
+            b".\n"
+        } else {
+            b". "
+        };
+        for &byte in separator.iter() {
+            if offset < output.len() {
+                output[offset] = byte;
+                offset += 1;
+            }
+        }
+    }
+}
+
+fn fill_records(rng: &mut Lcg, stride: usize, output: &mut [u8]) {
+    let stride = core::cmp::max(stride, 1);
+    let mut timestamp = rng.next();
+    for (index, record) in output.chunks_mut(stride).enumerate() {
+        timestamp = timestamp.wrapping_add(rng.skewed_below(1000));
+        let mut fields = [0u8; 8];
+        fields[..4].clone_from_slice(&(index as u32).to_le_bytes());
+        fields[4..].clone_from_slice(&timestamp.to_le_bytes());
+        for (position, byte) in record.iter_mut().enumerate() {
+            *byte = match position {
+                0..=7 => fields[position],
+                _ if position + 1 == stride => b'\n',
+                _ if position < 12 => b'A' + rng.skewed_below(8) as u8,
+                _ => b' ',
+            };
+        }
+    }
+}
+
+fn fill_random(rng: &mut Lcg, output: &mut [u8]) {
+    for byte in output.iter_mut() {
+        *byte = (rng.next() >> 24) as u8;
+    }
+}
+
+fn fill_repeats(rng: &mut Lcg, period: usize, mutation_rate: u8, output: &mut [u8]) {
+    let period = core::cmp::max(period, 1);
+    for index in 0..output.len() {
+        output[index] = if index >= period && rng.below(256) >= u32::from(mutation_rate) {
+            output[index - period]
+        } else {
+            (rng.next() >> 24) as u8
+        };
+    }
+}
+
+impl Synthetic {
+    pub fn fill(&self, seed: u64, output: &mut [u8]) {
+        let mut rng = Lcg::new(seed);
+        match *self {
+            Synthetic::Text{vocabulary} => fill_text(&mut rng, seed, vocabulary, output),
+            Synthetic::Records{stride} => fill_records(&mut rng, stride, output),
+            Synthetic::Random => fill_random(&mut rng, output),
+            Synthetic::Repeats{period, mutation_rate} => fill_repeats(&mut rng, period, mutation_rate, output),
+            Synthetic::Mixed => {
+                const SPANS: [Synthetic; 4] = [Synthetic::Text{vocabulary: 2000},
+                                               Synthetic::Records{stride: 24},
+                                               Synthetic::Random,
+                                               Synthetic::Repeats{period: 8192, mutation_rate: 4}];
+                let mut offset = 0usize;
+                let mut span = 0usize;
+                while offset < output.len() {
+                    let len = core::cmp::min(4096 + rng.below(60 * 1024) as usize, output.len() - offset);
+                    SPANS[span % SPANS.len()].fill(u64::from(rng.next()), &mut output[offset..offset + len]);
+                    offset += len;
+                    span += 1;
+                }
+            },
+        }
+    }
+    #[cfg(feature="std")]
+    pub fn generate(&self, seed: u64, len: usize) -> Vec<u8> {
+        let mut ret = vec![0u8; len];
+        self.fill(seed, &mut ret[..]);
+        ret
+    }
+}
+
+#[cfg(all(test, feature="std", feature="encoder", feature="decoder"))]
+mod test {
+    use std::vec::Vec;
+    use interface::{
+        DivansCompressorOptions,
+    };
+    use test_support::{compress_hybrid, decode};
+
+    #[test]
+    fn test_synthetic_inputs() {
+        use ::synthetic::Synthetic;
+        let kinds = [Synthetic::Text{vocabulary: 2000},
+                     Synthetic::Records{stride: 24},
+                     Synthetic::Random,
+                     Synthetic::Repeats{period: 8192, mutation_rate: 4},
+                     Synthetic::Mixed];
+        let mut ratios = Vec::<f64>::new();
+        for kind in kinds.iter() {
+            let input = kind.generate(7, 131072);
+            assert_eq!(input, kind.generate(7, 131072));
+            assert!(input != kind.generate(8, 131072));
+            // a shorter input is a prefix of a longer one
+            assert_eq!(&kind.generate(7, 1000)[..], &input[..1000]);
+            let stream = compress_hybrid(&input[..], DivansCompressorOptions::default());
+            assert_eq!(decode(&stream[..]).unwrap(), input);
+            ratios.push(stream.len() as f64 / input.len() as f64);
+        }
+        assert!(ratios[0] < 0.5 && ratios[1] < 0.5 && ratios[3] < 0.25, "{:?}", ratios);
+        assert!(ratios[2] > 0.99, "{:?}", ratios);
+        assert!(ratios[4] > ratios[0] && ratios[4] < ratios[2], "{:?}", ratios);
+    }
+}
@@ -18,7 +18,9 @@ use arithmetic_coder::{
     ByteQueue,
 };
 use interface::{
+    ArithmeticEncoderOrDecoder,
     NewWithAllocator,
+    MAX_NIBBLE_BATCH,
 };
 #[allow(unused_imports)]
 use alloc::{
@@ -801,3 +803,67 @@ fn entropy16_big_trait_test() {
     let mut start = [0u8; SZ];
     help_rt(&mut src[..],&mut dst[..],&mut end[..],&mut start[..], true)
 }
+
+fn drain_test_encoder<AllocU8: Allocator<u8>>(e: &mut ANSEncoder<AllocU8>, dst: &mut std::vec::Vec<u8>) {
+    let q = e.get_internal_buffer_mut();
+    let qb = q.num_pop_bytes_avail();
+    let n = dst.len();
+    dst.resize(n + qb, 0);
+    q.pop_data(&mut dst[n..]);
+}
+
+#[test]
+fn nibble_batch_matches_single_nibbles() {
+    const NUM_NIBBLES: usize = 65536 * 2 + 11; // crosses two chunk flushes
+    let mut cdfs = [DefaultCDF16::default(); 16];
+    for (index, cdf) in cdfs.iter_mut().enumerate() {
+        for _ in 0..index * 4 {
+            cdf.blend(index as u8, Speed::MED);
+        }
+    }
+    let mut nibbles = std::vec::Vec::<u8>::new();
+    let mut probs = std::vec::Vec::<DefaultCDF16>::new();
+    let mut seed = 0x1234567u32;
+    for index in 0..NUM_NIBBLES {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        nibbles.push((seed >> 28) as u8);
+        probs.push(cdfs[(index * 7) & 0xf]);
+    }
+    let mut m8 = HeapAllocator::<u8>{default_value: 0u8};
+    let mut single = ANSEncoder::new(&mut m8);
+    let mut single_out = std::vec::Vec::<u8>::new();
+    for (nibble, prob) in nibbles.iter().zip(probs.iter()) {
+        let mut nib = *nibble;
+        single.get_or_put_nibble_without_billing(&mut nib, prob);
+        drain_test_encoder(&mut single, &mut single_out);
+    }
+    single.close();
+    drain_test_encoder(&mut single, &mut single_out);
+
+    let mut batched = ANSEncoder::new(&mut m8);
+    let mut batched_out = std::vec::Vec::<u8>::new();
+    let mut index = 0;
+    let mut batch_len = 1;
+    while index < NUM_NIBBLES {
+        let end = core::cmp::min(NUM_NIBBLES, index + batch_len);
+        let coded = batched.get_or_put_nibble_batch_without_billing(&mut nibbles[index..end], &probs[index..end]);
+        assert!(coded != 0 && coded <= end - index);
+        drain_test_encoder(&mut batched, &mut batched_out);
+        index += coded;
+        batch_len = batch_len % MAX_NIBBLE_BATCH + 1;
+    }
+    batched.close();
+    drain_test_encoder(&mut batched, &mut batched_out);
+    assert_eq!(single_out.len(), batched_out.len());
+    assert!(single_out == batched_out);
+
+    let mut d = ANSDecoder::new(&mut m8);
+    let mut offset = 0;
+    for (nibble, prob) in nibbles.iter().zip(probs.iter()) {
+        while d.get_internal_buffer().num_push_bytes_avail() != 0 {
+            let q = d.get_internal_buffer_mut();
+            offset += q.push_data(&batched_out[offset..]);
+        }
+        assert_eq!(d.get_nibble(prob).0, *nibble);
+    }
+}
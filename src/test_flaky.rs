@@ -0,0 +1,478 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Drives the codec's resumption paths (drain/fill, coder shutdown, mux drain and
+// checksum) by injecting spurious NeedsMoreOutput/NeedsMoreInput from the coders.
+#![cfg(test)]
+extern crate std;
+use std::vec::Vec;
+use core;
+use core::marker::PhantomData;
+use brotli::InputReference;
+use ans::{ANSEncoder, ANSDecoder};
+use codec::{DivansCodec, StrideSelection, CommandSliceArray, EmptyCommandArray};
+use codec::io::DemuxerAndRingBuffer;
+use mux::{Mux, DevNull};
+use cmd_to_divans::EncoderSpecialization;
+use divans_to_raw::DecoderSpecialization;
+use divans_compressor::{make_header, write_header};
+use divans_decompressor::{DivansDecompressorFactory, StaticCommand};
+use interface::{
+    BlockSwitch,
+    Command,
+    CopyCommand,
+    Decompressor,
+    DivansCompressorOptions,
+    DivansOutputResult,
+    DivansResult,
+    ErrMsg,
+    FeatureFlagSliceType,
+    LiteralBlockSwitch,
+    LiteralCommand,
+    NewWithAllocator,
+    Nop,
+    HEADER_LENGTH,
+    STREAM_FEATURE_COMMAND_TYPE_TREE,
+    EXTENDED_FEATURE_DICT_TRANSFORM_MODEL,
+    EXTENDED_FEATURE2_DICT_COPY_FUSION,
+};
+use ::DefaultCDF16;
+use super::test_helper::{HeapAllocator, FlakyCoder, FaultSchedule, ChunkFuzz, Encode, Decode};
+
+const WINDOW_SIZE: u8 = 16;
+
+struct Never;
+impl FaultSchedule for Never {
+    const PERIOD: u64 = 0xffffffffffffffff;
+    const FAULT: DivansResult = DivansResult::Success;
+}
+struct OutEvery1;
+impl FaultSchedule for OutEvery1 {
+    const PERIOD: u64 = 1;
+    const FAULT: DivansResult = DivansResult::NeedsMoreOutput;
+}
+struct OutEvery3;
+impl FaultSchedule for OutEvery3 {
+    const PERIOD: u64 = 3;
+    const FAULT: DivansResult = DivansResult::NeedsMoreOutput;
+}
+struct OutEvery29;
+impl FaultSchedule for OutEvery29 {
+    const PERIOD: u64 = 29;
+    const FAULT: DivansResult = DivansResult::NeedsMoreOutput;
+}
+struct InEvery1;
+impl FaultSchedule for InEvery1 {
+    const PERIOD: u64 = 1;
+    const FAULT: DivansResult = DivansResult::NeedsMoreInput;
+}
+struct InEvery5;
+impl FaultSchedule for InEvery5 {
+    const PERIOD: u64 = 5;
+    const FAULT: DivansResult = DivansResult::NeedsMoreInput;
+}
+struct StuckIn;
+impl FaultSchedule for StuckIn {
+    const PERIOD: u64 = 1;
+    const FAULT: DivansResult = DivansResult::NeedsMoreInput;
+    const STICKY: bool = true;
+}
+struct InEvery31;
+impl FaultSchedule for InEvery31 {
+    const PERIOD: u64 = 31;
+    const FAULT: DivansResult = DivansResult::NeedsMoreInput;
+}
+
+fn lit(data: &'static [u8]) -> Command<InputReference<'static>> {
+    Command::Literal(LiteralCommand::<InputReference<'static>> {
+        data: InputReference{data: data, orig_offset: 0},
+        prob: FeatureFlagSliceType::<InputReference<'static>>::default(),
+        high_entropy: false,
+    })
+}
+
+fn copy(distance: u32, num_bytes: u32) -> Command<InputReference<'static>> {
+    Command::Copy(CopyCommand{distance: distance, num_bytes: num_bytes})
+}
+
+fn test_commands() -> Vec<Command<InputReference<'static>>> {
+    vec![lit(b"The quick brown fox jumps over the lazy dog. "),
+         copy(45, 44),
+         lit(b"Pack my box with five dozen liquor jugs!"),
+         copy(1, 17),
+         Command::BlockSwitchLiteral(LiteralBlockSwitch::new(1, 0)),
+         lit(b"\x00\x01\x02\x03\xfe\xfd\xfc\xfb"),
+         copy(97, 64),
+         Command::BlockSwitchCommand(BlockSwitch::new(1)),
+         Command::BlockSwitchDistance(BlockSwitch::new(1)),
+         copy(8, 300),
+         lit(b"sphinx of black quartz, judge my vow"),
+         copy(36, 35),
+    ]
+}
+
+fn expected_output(cmds: &[Command<InputReference<'static>>]) -> Vec<u8> {
+    let mut ret = Vec::<u8>::new();
+    for cmd in cmds.iter() {
+        match *cmd {
+            Command::Literal(ref l) => ret.extend(l.data.data),
+            Command::Copy(ref c) => for _ in 0..c.num_bytes {
+                let b = ret[ret.len() - c.distance as usize];
+                ret.push(b);
+            },
+            _ => {},
+        }
+    }
+    ret
+}
+
+type FlakyEncoder<Schedule> = DivansCodec<FlakyCoder<ANSEncoder<HeapAllocator<u8>>, Schedule>,
+                                          EncoderSpecialization,
+                                          DemuxerAndRingBuffer<HeapAllocator<u8>, DevNull<HeapAllocator<u8>>>,
+                                          Mux<HeapAllocator<u8>>,
+                                          DefaultCDF16,
+                                          HeapAllocator<u8>,
+                                          HeapAllocator<DefaultCDF16>>;
+
+fn new_flaky_encoder<Schedule:FaultSchedule>(stream_features: u8) -> FlakyEncoder<Schedule> {
+    let mut m8 = HeapAllocator::<u8>{default_value: 0};
+    let mcdf16 = HeapAllocator::<DefaultCDF16>{default_value: DefaultCDF16::default()};
+    let cmd_coder = FlakyCoder::<ANSEncoder<HeapAllocator<u8>>, Schedule>::new(&mut m8);
+    let lit_coder = FlakyCoder::<ANSEncoder<HeapAllocator<u8>>, Schedule>::new(&mut m8);
+    let mut codec = DivansCodec::<FlakyCoder<ANSEncoder<HeapAllocator<u8>>, Schedule>,
+                                  EncoderSpecialization,
+                                  DemuxerAndRingBuffer<HeapAllocator<u8>, DevNull<HeapAllocator<u8>>>,
+                                  Mux<HeapAllocator<u8>>,
+                                  DefaultCDF16,
+                                  HeapAllocator<u8>,
+                                  HeapAllocator<DefaultCDF16>>::new(
+        m8,
+        mcdf16,
+        cmd_coder,
+        lit_coder,
+        EncoderSpecialization::new(),
+        DemuxerAndRingBuffer::<HeapAllocator<u8>, DevNull<HeapAllocator<u8>>>::default(),
+        WINDOW_SIZE as usize,
+        1,
+        None,
+        None,
+        false,
+        StrideSelection::UseBrotliRec,
+        false);
+    codec.set_stream_features(stream_features);
+    codec
+}
+
+fn encode_commands<Schedule:FaultSchedule>(codec: &mut FlakyEncoder<Schedule>,
+                                           cmds: &[Command<InputReference<'static>>],
+                                           buf: &mut [u8],
+                                           ret: &mut Vec<u8>) {
+    let mut cmd_offset = 0usize;
+    let mut iterations = 0usize;
+    loop {
+        iterations += 1;
+        assert!(iterations < 1000000, "encoder made no progress");
+        let mut output_offset = 0usize;
+        let mut unused = 0usize;
+        let res = codec.encode_or_decode(&[],
+                                         &mut unused,
+                                         buf,
+                                         &mut output_offset,
+                                         &CommandSliceArray(cmds),
+                                         &mut cmd_offset);
+        ret.extend(&buf[..output_offset]);
+        match res {
+            DivansResult::Success | DivansResult::NeedsMoreInput => if cmd_offset == cmds.len() {
+                break;
+            },
+            DivansResult::NeedsMoreOutput => {},
+            DivansResult::Failure(m) => panic!("{:?}", m),
+        }
+    }
+}
+
+fn flush_encoder<Schedule:FaultSchedule>(codec: &mut FlakyEncoder<Schedule>,
+                                         buf: &mut [u8],
+                                         ret: &mut Vec<u8>) {
+    let mut iterations = 0usize;
+    loop {
+        iterations += 1;
+        assert!(iterations < 1000000, "flush made no progress");
+        let mut output_offset = 0usize;
+        let res = codec.flush(buf, &mut output_offset);
+        ret.extend(&buf[..output_offset]);
+        match res {
+            DivansOutputResult::Success => break,
+            DivansOutputResult::NeedsMoreOutput => {},
+            DivansOutputResult::Failure(m) => panic!("{:?}", m),
+        }
+    }
+}
+
+fn flaky_encode<Schedule:FaultSchedule>(cmds: &[Command<InputReference<'static>>],
+                                        chunk_size: usize,
+                                        stream_features: u8) -> (Vec<u8>, usize) {
+    let mut codec = new_flaky_encoder::<Schedule>(stream_features);
+    let mut ret = vec![0u8; HEADER_LENGTH];
+    let mut header_progress = 0usize;
+    let mut header_offset = 0usize;
+    match write_header(&mut header_progress, &make_header(WINDOW_SIZE, codec.stream_features(), codec.extended_stream_features(), codec.freeze_adaptation_after(), codec.priors_reset_interval(), codec.extended_stream_features2(), codec.checksum_type()), codec.framing_tag(), &mut ret[..], &mut header_offset, codec.get_crc()) {
+        DivansOutputResult::Success => {},
+        res => panic!("{:?}", res),
+    }
+    let mut buf = vec![0u8; chunk_size];
+    encode_commands(&mut codec, cmds, &mut buf[..], &mut ret);
+    flush_encoder(&mut codec, &mut buf[..], &mut ret);
+    let faults = codec.get_coder(0).faults_injected + codec.get_coder(1).faults_injected;
+    codec.free();
+    (ret, faults)
+}
+
+struct FlakyDecompressorFactory<Schedule:FaultSchedule>(PhantomData<Schedule>);
+
+impl<Schedule:FaultSchedule> DivansDecompressorFactory<HeapAllocator<u8>,
+                                                       HeapAllocator<DefaultCDF16>,
+                                                       HeapAllocator<StaticCommand>>
+    for FlakyDecompressorFactory<Schedule> {
+    type DefaultDecoder = FlakyCoder<ANSDecoder, Schedule>;
+}
+
+// Only the serial decoder is exercised: the threaded worker treats NeedsMoreInput
+// from the command coder as a request to block until the main thread sends more bytes.
+fn flaky_decode<Schedule:FaultSchedule+Send+'static>(stream: &[u8],
+                                                     input_chunk: usize,
+                                                     output_chunk: usize) -> Vec<u8> {
+    let mut decompressor = FlakyDecompressorFactory::<Schedule>::new(
+        HeapAllocator::<u8>{default_value: 0},
+        HeapAllocator::<DefaultCDF16>{default_value: DefaultCDF16::default()},
+        HeapAllocator::<StaticCommand>{default_value: StaticCommand::nop()},
+        false,
+        false);
+    let mut ret = Vec::<u8>::new();
+    let mut buf = vec![0u8; output_chunk];
+    let mut input_offset = 0usize;
+    let mut input_avail = core::cmp::min(input_chunk, stream.len());
+    let mut iterations = 0usize;
+    loop {
+        iterations += 1;
+        assert!(iterations < 1000000, "decoder made no progress");
+        let mut output_offset = 0usize;
+        let res = decompressor.decode(&stream[..input_avail],
+                                      &mut input_offset,
+                                      &mut buf[..],
+                                      &mut output_offset);
+        ret.extend(&buf[..output_offset]);
+        match res {
+            DivansResult::Success => break,
+            DivansResult::NeedsMoreOutput => {},
+            DivansResult::NeedsMoreInput => {
+                input_avail = core::cmp::min(input_avail + input_chunk, stream.len());
+            },
+            DivansResult::Failure(m) => panic!("{:?}", m),
+        }
+    }
+    assert_eq!(input_offset, stream.len());
+    decompressor.free();
+    ret
+}
+
+#[test]
+fn test_flaky_encoder_matches_reference() {
+    let cmds = test_commands();
+    for stream_features in [0, STREAM_FEATURE_COMMAND_TYPE_TREE].iter() {
+        let (reference, no_faults) = flaky_encode::<Never>(&cmds[..], 65536, *stream_features);
+        assert_eq!(no_faults, 0);
+        for chunk in [1usize, 2, 7, 65536].iter() {
+            let (stream, faults) = flaky_encode::<OutEvery1>(&cmds[..], *chunk, *stream_features);
+            assert!(faults > 0);
+            assert_eq!(stream, reference);
+            let (stream, faults) = flaky_encode::<OutEvery3>(&cmds[..], *chunk, *stream_features);
+            assert!(faults > 0);
+            assert_eq!(stream, reference);
+            let (stream, faults) = flaky_encode::<OutEvery29>(&cmds[..], *chunk, *stream_features);
+            assert!(faults > 0);
+            assert_eq!(stream, reference);
+        }
+        assert_eq!(flaky_decode::<Never>(&reference[..], 65536, 65536), expected_output(&cmds[..]));
+    }
+}
+
+#[test]
+fn test_flaky_decoder_serial() {
+    let cmds = test_commands();
+    let expected = expected_output(&cmds[..]);
+    for stream_features in [0, STREAM_FEATURE_COMMAND_TYPE_TREE].iter() {
+        let (stream, _) = flaky_encode::<Never>(&cmds[..], 65536, *stream_features);
+        for &(input_chunk, output_chunk) in [(1usize, 1usize), (3, 65536), (65536, 5), (65536, 65536)].iter() {
+            assert_eq!(flaky_decode::<InEvery1>(&stream[..], input_chunk, output_chunk), expected);
+            assert_eq!(flaky_decode::<InEvery5>(&stream[..], input_chunk, output_chunk), expected);
+            assert_eq!(flaky_decode::<InEvery31>(&stream[..], input_chunk, output_chunk), expected);
+        }
+    }
+}
+
+#[test]
+fn test_stuck_encoder_reports_stall() {
+    let cmds = test_commands();
+    let mut codec = new_flaky_encoder::<StuckIn>(0);
+    let mut buf = vec![0u8; 65536];
+    let mut cmd_offset = 0usize;
+    let mut stalled_after = None;
+    for call in 0..8 {
+        let mut output_offset = 0usize;
+        let mut unused = 0usize;
+        match codec.encode_or_decode(&[],
+                                     &mut unused,
+                                     &mut buf[..],
+                                     &mut output_offset,
+                                     &CommandSliceArray(&cmds[..]),
+                                     &mut cmd_offset) {
+            DivansResult::Failure(ErrMsg::Stalled) => {
+                stalled_after = Some(call);
+                break;
+            },
+            DivansResult::NeedsMoreInput => {},
+            res => panic!("{:?}", res),
+        }
+    }
+    // the first call is allowed to come back empty handed; the second identical one is reported
+    assert_eq!(stalled_after, Some(1));
+    assert_eq!(cmd_offset, 0);
+    codec.free();
+}
+
+// Decodes on a single codec that writes the file bytes itself, so each command waits in
+// PopulateRingBuffer for the caller's tiny output buffer while the next one is decoded ahead.
+// Also returns the command types found staged when the decoder asked for more output, repeats dropped.
+fn decode_unforked(stream: &[u8], output_chunk: usize) -> (Vec<u8>, Vec<u8>) {
+    let mut m8 = HeapAllocator::<u8>{default_value: 0};
+    let mcdf16 = HeapAllocator::<DefaultCDF16>{default_value: DefaultCDF16::default()};
+    let cmd_coder = ANSDecoder::new(&mut m8);
+    let lit_coder = ANSDecoder::new(&mut m8);
+    let mut codec = DivansCodec::<ANSDecoder,
+                                  DecoderSpecialization,
+                                  DemuxerAndRingBuffer<HeapAllocator<u8>, Mux<HeapAllocator<u8>>>,
+                                  DevNull<HeapAllocator<u8>>,
+                                  DefaultCDF16,
+                                  HeapAllocator<u8>,
+                                  HeapAllocator<DefaultCDF16>>::new(
+        m8,
+        mcdf16,
+        cmd_coder,
+        lit_coder,
+        DecoderSpecialization::new(),
+        DemuxerAndRingBuffer::<HeapAllocator<u8>, Mux<HeapAllocator<u8>>>::default(),
+        WINDOW_SIZE as usize,
+        0,
+        None,
+        None,
+        true,
+        StrideSelection::UseBrotliRec,
+        true);
+    codec.set_stream_features(stream[6]);
+    let mut ret = Vec::<u8>::new();
+    let mut staged = Vec::<u8>::new();
+    let mut buf = vec![0u8; output_chunk];
+    let mut input_offset = HEADER_LENGTH;
+    let mut iterations = 0usize;
+    loop {
+        iterations += 1;
+        assert!(iterations < 1000000, "decoder made no progress");
+        let mut output_offset = 0usize;
+        let mut unused = 0usize;
+        let res = codec.encode_or_decode(stream,
+                                         &mut input_offset,
+                                         &mut buf[..],
+                                         &mut output_offset,
+                                         &EmptyCommandArray::default(),
+                                         &mut unused);
+        ret.extend(&buf[..output_offset]);
+        match res {
+            DivansResult::Success => break,
+            DivansResult::NeedsMoreOutput => if let Some(command_type) = codec.speculative_command_type() {
+                if staged.last() != Some(&command_type) {
+                    staged.push(command_type);
+                }
+            },
+            res => panic!("{:?}", res),
+        }
+    }
+    assert_eq!(codec.speculative_command_type(), None);
+    codec.free();
+    (ret, staged)
+}
+
+#[test]
+fn test_unforked_decoder_small_output() {
+    // enough output to wrap the window, so populating the ring buffer has to wait on the caller
+    let mut cmds = test_commands();
+    for _ in 0..4 {
+        cmds.push(copy(500, 40000));
+        cmds.extend(test_commands());
+    }
+    let expected = expected_output(&cmds[..]);
+    for stream_features in [0, STREAM_FEATURE_COMMAND_TYPE_TREE].iter() {
+        let (stream, _) = flaky_encode::<Never>(&cmds[..], 65536, *stream_features);
+        for output_chunk in [1usize, 7].iter() {
+            let (output, staged) = decode_unforked(&stream[..], *output_chunk);
+            assert_eq!(output, expected);
+            // the literal after each long copy was found while the copy waited
+            assert!(staged.iter().any(|command_type| *command_type == 3), "{:?}", staged);
+        }
+        assert_eq!(decode_unforked(&stream[..], 65536).0, expected);
+    }
+}
+
+#[test]
+fn test_unforked_decoder_ends_with_staged_command_type() {
+    // the end of the stream is decoded while the final copy still waits on the caller, so the
+    // staged type is the end code, taken with is_end false and leading into the checksum
+    let mut cmds = test_commands();
+    cmds.push(copy(500, 40000));
+    let expected = expected_output(&cmds[..]);
+    for stream_features in [0, STREAM_FEATURE_COMMAND_TYPE_TREE].iter() {
+        let (stream, _) = flaky_encode::<Never>(&cmds[..], 65536, *stream_features);
+        for output_chunk in [1usize, 7, 4096].iter() {
+            let (output, staged) = decode_unforked(&stream[..], *output_chunk);
+            assert_eq!(output, expected);
+            assert_eq!(staged.last(), Some(&0xf));
+        }
+    }
+}
+
+#[test]
+fn test_chunk_fuzz() {
+    use alloc_stdlib::HeapAlloc;
+    use divans_decompressor::DivansDecompressorFactoryStruct;
+    use writer::new_brotli_hybrid_compressor;
+    use interface::DefaultCDF16 as CDF;
+    let input = &include_bytes!("../testdata/alice29")[..8192];
+    let mut opts = DivansCompressorOptions::default();
+    opts.quality = Some(5);
+    opts.window_size = Some(16);
+    opts.extended_stream_features = EXTENDED_FEATURE_DICT_TRANSFORM_MODEL;
+    opts.extended_stream_features2 = EXTENDED_FEATURE2_DICT_COPY_FUSION;
+    let new_decoder = |multithread: bool| Decode(DivansDecompressorFactoryStruct::<HeapAlloc<u8>,
+                                                                                 HeapAlloc<CDF>,
+                                                                                 HeapAlloc<StaticCommand>>::new(
+        HeapAlloc::<u8>::new(0),
+        HeapAlloc::<CDF>::new(CDF::default()),
+        HeapAlloc::<StaticCommand>::new(StaticCommand::nop()),
+        false,
+        multithread));
+    let stream = ChunkFuzz::drive(|| Encode(new_brotli_hybrid_compressor(opts)), input, 1);
+    for multithread in [false, true].iter() {
+        assert_eq!(&ChunkFuzz::drive(|| new_decoder(*multithread), &stream[..], 2)[..], input);
+    }
+}
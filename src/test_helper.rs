@@ -59,3 +59,249 @@ impl<T: core::clone::Clone> alloc::Allocator<T> for HeapAllocator<T> {
     }
     fn free_cell(self: &mut HeapAllocator<T>, _data: Rebox<T>) {}
 }
+
+use interface::{ArithmeticEncoderOrDecoder, BillingCapability, BillingDesignation, NewWithAllocator, DuplicateWithAllocator,
+                DivansResult, DivansOutputResult, ReadableBytes, WritableBytes, Compressor, Decompressor};
+use probability::{CDF16, ProbRange};
+
+pub const SAFE_FILL_BYTES: usize = 16;
+
+// Describes when a FlakyCoder misbehaves: after every PERIOD symbols it reports FAULT
+// from its next drain/fill (or close) without making any progress.
+// A STICKY schedule never recovers, like a broken backend that keeps asking for buffers it ignores.
+// A decoder is only allowed to ask for input once fewer than SAFE_FILL_BYTES remain,
+// since the literal decoder relies on fills succeeding when enough input is buffered.
+pub trait FaultSchedule {
+    const PERIOD: u64;
+    const FAULT: DivansResult;
+    const STICKY: bool = false;
+}
+
+// Wraps a real coder and spuriously asks for more output (encoder) or more input (decoder)
+// so the codec has to resume from every drain point it has; the bytes on the wire must
+// not change.
+pub struct FlakyCoder<Coder:ArithmeticEncoderOrDecoder, Schedule:FaultSchedule> {
+    pub coder: Coder,
+    pub symbols_coded: u64,
+    pub armed: bool,
+    pub faults_injected: usize,
+    _schedule: core::marker::PhantomData<Schedule>,
+}
+
+impl<Coder:ArithmeticEncoderOrDecoder, Schedule:FaultSchedule> FlakyCoder<Coder, Schedule> {
+    pub fn wrap(coder: Coder) -> Self {
+        FlakyCoder::<Coder, Schedule> {
+            coder: coder,
+            symbols_coded: 0,
+            armed: false,
+            faults_injected: 0,
+            _schedule: core::marker::PhantomData::<Schedule>::default(),
+        }
+    }
+    fn tick(&mut self) {
+        self.symbols_coded += 1;
+        if self.symbols_coded % Schedule::PERIOD == 0 {
+            self.armed = true;
+        }
+    }
+    fn fire(&mut self) -> bool {
+        if self.armed {
+            self.armed = Schedule::STICKY;
+            self.faults_injected += 1;
+            return true;
+        }
+        false
+    }
+}
+
+impl<AllocU8:alloc::Allocator<u8>,
+     Coder:ArithmeticEncoderOrDecoder+NewWithAllocator<AllocU8>,
+     Schedule:FaultSchedule> NewWithAllocator<AllocU8> for FlakyCoder<Coder, Schedule> {
+    fn new(m8: &mut AllocU8) -> Self {
+        FlakyCoder::<Coder, Schedule>::wrap(Coder::new(m8))
+    }
+    fn free(&mut self, m8: &mut AllocU8) {
+        self.coder.free(m8)
+    }
+}
+
+impl<AllocU8:alloc::Allocator<u8>,
+     Coder:ArithmeticEncoderOrDecoder+DuplicateWithAllocator<AllocU8>,
+     Schedule:FaultSchedule> DuplicateWithAllocator<AllocU8> for FlakyCoder<Coder, Schedule> {
+    fn duplicate(&self, m8: &mut AllocU8) -> Self {
+        FlakyCoder::<Coder, Schedule> {
+            coder: self.coder.duplicate(m8),
+            symbols_coded: self.symbols_coded,
+            armed: self.armed,
+            faults_injected: self.faults_injected,
+            _schedule: core::marker::PhantomData::<Schedule>::default(),
+        }
+    }
+}
+
+impl<Coder:ArithmeticEncoderOrDecoder, Schedule:FaultSchedule> BillingCapability for FlakyCoder<Coder, Schedule> {
+}
+
+impl<Coder:ArithmeticEncoderOrDecoder, Schedule:FaultSchedule> ArithmeticEncoderOrDecoder for FlakyCoder<Coder, Schedule> {
+    fn mov(&mut self) -> Self {
+        FlakyCoder::<Coder, Schedule> {
+            coder: self.coder.mov(),
+            symbols_coded: self.symbols_coded,
+            armed: self.armed,
+            faults_injected: self.faults_injected,
+            _schedule: core::marker::PhantomData::<Schedule>::default(),
+        }
+    }
+    fn has_data_to_drain_or_fill(&self) -> bool {
+        self.armed || self.coder.has_data_to_drain_or_fill()
+    }
+    fn drain_or_fill_internal_buffer_unchecked(&mut self,
+                                               input: &mut ReadableBytes,
+                                               output: &mut WritableBytes) -> DivansResult {
+        if let DivansResult::NeedsMoreInput = Schedule::FAULT {
+            if input.data.len() - *input.read_offset >= SAFE_FILL_BYTES {
+                self.armed = false; // a real decoder could not stall here
+            }
+        }
+        if self.fire() {
+            return Schedule::FAULT;
+        }
+        if !self.coder.has_data_to_drain_or_fill() {
+            return DivansResult::Success;
+        }
+        self.coder.drain_or_fill_internal_buffer_unchecked(input, output)
+    }
+    fn get_or_put_bit_without_billing(&mut self,
+                                      bit: &mut bool,
+                                      prob_of_false: u8) {
+        self.tick();
+        self.coder.get_or_put_bit_without_billing(bit, prob_of_false)
+    }
+    fn get_or_put_bit(&mut self,
+                      bit: &mut bool,
+                      prob_of_false: u8,
+                      billing: BillingDesignation) {
+        self.tick();
+        self.coder.get_or_put_bit(bit, prob_of_false, billing)
+    }
+    fn get_or_put_nibble_without_billing<C: CDF16>(&mut self,
+                                                   nibble: &mut u8,
+                                                   prob: &C) -> ProbRange {
+        self.tick();
+        self.coder.get_or_put_nibble_without_billing(nibble, prob)
+    }
+    fn get_or_put_nibble<C: CDF16>(&mut self,
+                                   nibble: &mut u8,
+                                   prob: &C,
+                                   billing: BillingDesignation) -> ProbRange {
+        self.tick();
+        self.coder.get_or_put_nibble(nibble, prob, billing)
+    }
+    fn close(&mut self) -> DivansResult {
+        if self.fire() {
+            return Schedule::FAULT;
+        }
+        self.coder.close()
+    }
+}
+
+// A streaming encoder or decoder ChunkFuzz can feed.  finish runs once all input is taken, until
+// it returns Success; a decoder is done within step, so running out of input there is an error.
+pub trait ChunkedCodec {
+    fn step(&mut self, input: &[u8], input_offset: &mut usize, output: &mut [u8], output_offset: &mut usize) -> DivansResult;
+    fn finish(&mut self, _output: &mut [u8], _output_offset: &mut usize) -> DivansResult {
+        DivansResult::NeedsMoreInput
+    }
+}
+
+pub struct Encode<C:Compressor>(pub C);
+
+impl<C:Compressor> ChunkedCodec for Encode<C> {
+    fn step(&mut self, input: &[u8], input_offset: &mut usize, output: &mut [u8], output_offset: &mut usize) -> DivansResult {
+        self.0.encode(input, input_offset, output, output_offset)
+    }
+    fn finish(&mut self, output: &mut [u8], output_offset: &mut usize) -> DivansResult {
+        match self.0.flush(output, output_offset) {
+            DivansOutputResult::Success => DivansResult::Success,
+            DivansOutputResult::NeedsMoreOutput => DivansResult::NeedsMoreOutput,
+            DivansOutputResult::Failure(m) => DivansResult::Failure(m),
+        }
+    }
+}
+
+pub struct Decode<D:Decompressor>(pub D);
+
+impl<D:Decompressor> ChunkedCodec for Decode<D> {
+    fn step(&mut self, input: &[u8], input_offset: &mut usize, output: &mut [u8], output_offset: &mut usize) -> DivansResult {
+        self.0.decode(input, input_offset, output, output_offset)
+    }
+}
+
+// Replays the same input through fresh codecs under many random schedules of input and output
+// chunk sizes, and asserts every schedule produces what whole buffers do.  Where FlakyCoder makes
+// the coder stop at every drain point, this makes the caller's buffers end at arbitrary bytes, so
+// the copy, literal and dict substates have to resume from wherever a split lands.
+pub struct ChunkFuzz {
+    state: u64,
+}
+
+impl ChunkFuzz {
+    pub const SCHEDULES: usize = 200;
+    // returns the output, identical for every schedule
+    pub fn drive<C:ChunkedCodec, F:FnMut() -> C>(mut new_codec: F, input: &[u8], seed: u64) -> Vec<u8> {
+        let reference = ChunkFuzz::run(&mut new_codec(), input, None);
+        let mut schedule = ChunkFuzz { state: seed | 1 };
+        for index in 0..ChunkFuzz::SCHEDULES {
+            let output = ChunkFuzz::run(&mut new_codec(), input, Some(&mut schedule));
+            assert!(output == reference,
+                    "schedule {} of seed {} produced {} bytes unlike the {} of whole buffers",
+                    index, seed, output.len(), reference.len());
+        }
+        reference
+    }
+    fn next(&mut self) -> u64 {
+        // xorshift64
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+    // single bytes a third of the time, so a split can land between any two bytes
+    fn chunk_size(&mut self) -> usize {
+        let r = self.next();
+        match r % 3 {
+            0 => 1,
+            1 => 1 + (r >> 8) as usize % 16,
+            _ => 1 + (r >> 8) as usize % 4096,
+        }
+    }
+    fn run<C:ChunkedCodec>(codec: &mut C, input: &[u8], mut schedule: Option<&mut ChunkFuzz>) -> Vec<u8> {
+        let mut ret = Vec::<u8>::new();
+        let mut buffer = vec![0u8; 65536];
+        let mut input_offset = 0usize;
+        let mut finishing = false;
+        loop {
+            let (input_end, output_len) = match schedule {
+                Some(ref mut schedule) => (core::cmp::min(input.len(), input_offset + schedule.chunk_size()),
+                                           schedule.chunk_size()),
+                None => (input.len(), buffer.len()),
+            };
+            let mut output_offset = 0usize;
+            let res = if finishing {
+                codec.finish(&mut buffer[..output_len], &mut output_offset)
+            } else {
+                codec.step(&input[..input_end], &mut input_offset, &mut buffer[..output_len], &mut output_offset)
+            };
+            ret.extend(&buffer[..output_offset]);
+            match res {
+                DivansResult::Success => return ret,
+                DivansResult::NeedsMoreOutput => {},
+                DivansResult::NeedsMoreInput => if input_offset == input.len() {
+                    assert!(!finishing, "ran out of input after {} bytes of output", ret.len());
+                    finishing = true;
+                },
+                DivansResult::Failure(m) => panic!("{:?} after {} bytes of input", m, input_offset),
+            }
+        }
+    }
+}
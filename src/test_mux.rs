@@ -8,6 +8,14 @@ use alloc::SliceWrapperMut;
 use alloc::SliceWrapper;
 use interface::StreamDemuxer;
 use super::mux;
+use conformance::{conformance_commands, conformance_expected_output};
+use interface::{
+    DivansCompressorOptions,
+    HEADER_FEATURE_OFFSET,
+    STREAM_FEATURE_COMMAND_TYPE_TREE,
+    STREAM_FEATURE_MUX_PADDING,
+};
+use test_support::{encode, encode_with_options, decode};
 
 fn help_test_mux(i0:&[u8], i1:&[u8], copy_pattern: &[(mux::StreamID, usize)], in_buf_size: usize, out_buf_size: usize) {
     let mut m8 = HeapAlloc::<u8>::new(0);
@@ -63,7 +71,7 @@ fn help_test_mux(i0:&[u8], i1:&[u8], copy_pattern: &[(mux::StreamID, usize)], in
         }
     }
     m8.free_cell(buf);
-    assert_eq!(input, [&[], &[]]);
+    assert_eq!(input, [&[] as &[u8], &[]]);
     assert_eq!(mux.is_eof(), true);
     mux.free(&mut m8);
 }
@@ -1205,3 +1213,54 @@ fn unit_test_decode_mux() {
     assert_eq!(mux.edit(0).slice(), &to_decode[3..3+16]);
     assert_eq!(mux.edit(1).slice(), &to_decode[6+16..6+32]);
 }
+
+#[test]
+fn test_padded_mux_close() {
+    use interface::StreamMuxer;
+    let i0 = rand(1000, 7);
+    let i1 = rand(300, 11);
+    for &(alignment, overhead, out_buf_size) in [(512usize, 24usize, 1usize), (512, 24, 4096), (7, 0, 3), (1, 24, 4096)].iter() {
+        let mut m8 = HeapAlloc::<u8>::new(0);
+        let mut mux = mux::Mux::<HeapAlloc<u8>>::default();
+        mux.set_frame_alignment(alignment, overhead);
+        mux.push_data(0, &i0[..], &mut m8);
+        mux.push_data(1, &i1[..], &mut m8);
+        let mut buf = vec![0u8; out_buf_size];
+        let mut v = Vec::<u8>::new();
+        loop {
+            let amt = mux.serialize_close(&mut buf[..]);
+            if amt == 0 {
+                break;
+            }
+            v.extend(&buf[..amt]);
+        }
+        assert_eq!((v.len() + overhead) % alignment, 0);
+        assert_eq!(&v[v.len() - 3..], &super::mux::EOF_MARKER[..]);
+        mux.free(&mut m8);
+        let mut mux = mux::Mux::<HeapAlloc<u8>>::default();
+        assert_eq!(mux.deserialize(&v[..], &mut m8), v.len());
+        assert_eq!(mux.data_avail(0), &i0[..]);
+        assert_eq!(mux.data_avail(1), &i1[..]);
+        mux.consume_data(0, i0.len());
+        mux.consume_data(1, i1.len());
+        assert_eq!(mux.is_eof(), true);
+        mux.free(&mut m8);
+    }
+}
+
+#[test]
+fn test_frame_alignment_roundtrip() {
+    let commands = conformance_commands("mixed");
+    let expected = conformance_expected_output(&commands[..]).unwrap();
+    let plain = encode(&commands[..], STREAM_FEATURE_COMMAND_TYPE_TREE).unwrap();
+    for alignment in [7u32, 512, 4096].iter() {
+        let mut opts = DivansCompressorOptions::default();
+        opts.stream_features = STREAM_FEATURE_COMMAND_TYPE_TREE;
+        opts.frame_alignment = Some(*alignment);
+        let padded = encode_with_options(&commands[..], opts).unwrap();
+        assert_eq!(padded[HEADER_FEATURE_OFFSET], STREAM_FEATURE_COMMAND_TYPE_TREE | STREAM_FEATURE_MUX_PADDING);
+        assert_eq!(padded.len() % *alignment as usize, 0);
+        assert!(padded.len() >= plain.len() && padded.len() - plain.len() < *alignment as usize);
+        assert_eq!(decode(&padded[..]).unwrap(), expected);
+    }
+}
@@ -0,0 +1,53 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+#![cfg(all(test, feature="std"))]
+use conformance::{conformance_commands, conformance_expected_output};
+use interface::{
+    ErrMsg,
+    HEADER_FEATURE_OFFSET,
+    STREAM_FEATURE_NO_DICT,
+    STREAM_FEATURE_NO_BLOCK_SWITCH,
+};
+use test_support::{encode, decode};
+
+#[test]
+fn test_stream_features_roundtrip() {
+    for scenario in ["literal", "copy", "prediction_mode"].iter() {
+        let commands = conformance_commands(scenario);
+        let expected = conformance_expected_output(&commands[..]).unwrap();
+        let full = encode(&commands[..], 0).unwrap();
+        let restricted = encode(&commands[..], STREAM_FEATURE_NO_DICT | STREAM_FEATURE_NO_BLOCK_SWITCH).unwrap();
+        assert_eq!(full[HEADER_FEATURE_OFFSET], 0);
+        assert_eq!(restricted[HEADER_FEATURE_OFFSET], STREAM_FEATURE_NO_DICT | STREAM_FEATURE_NO_BLOCK_SWITCH);
+        assert!(restricted.len() <= full.len(), "{}: {} > {}", scenario, restricted.len(), full.len());
+        assert_eq!(decode(&full[..]).unwrap(), expected);
+        assert_eq!(decode(&restricted[..]).unwrap(), expected);
+    }
+    let commands = conformance_commands("block_switch_command");
+    let no_dict = encode(&commands[..], STREAM_FEATURE_NO_DICT).unwrap();
+    assert_eq!(decode(&no_dict[..]).unwrap(), conformance_expected_output(&commands[..]).unwrap());
+}
+
+#[test]
+fn test_stream_features_reject_disabled_commands() {
+    match encode(&conformance_commands("dict")[..], STREAM_FEATURE_NO_DICT) {
+        Err(ErrMsg::CommandDisabledByStreamFeatures(2)) => {},
+        res => panic!("{:?}", res.map(|v| v.len())),
+    }
+    match encode(&conformance_commands("block_switch_distance")[..], STREAM_FEATURE_NO_BLOCK_SWITCH) {
+        Err(ErrMsg::CommandDisabledByStreamFeatures(6)) => {},
+        res => panic!("{:?}", res.map(|v| v.len())),
+    }
+}
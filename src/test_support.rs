@@ -0,0 +1,285 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Encode and decode fixtures shared by the round trip tests of the modules, driving the public
+// compressor and decompressor on heap allocators.
+#![cfg(all(test, feature="std"))]
+use std::vec::Vec;
+use std::io::Write;
+use alloc_stdlib::HeapAlloc;
+use alloc::SliceWrapper;
+use conformance::ConformanceSlice;
+use wire::FramingTag;
+use writer::DivansBrotliHybridCompressorWriter;
+use divans_compressor::DivansCompressorFactoryStruct;
+use divans_decompressor::{DivansDecompressor, DivansDecompressorFactory, DivansDecompressorFactoryStruct, StaticCommand};
+use interface::{
+    Command,
+    Compressor,
+    Decompressor,
+    DivansCompressorFactory,
+    DivansCompressorOptions,
+    DivansOutputResult,
+    DivansResult,
+    ErrMsg,
+    ChecksumPolicy,
+    OutputDigestType,
+    DecodedStreamOptions,
+    DecodeStats,
+    Nop,
+    DefaultCDF16,
+    AUTHENTICATION_KEY_LENGTH,
+};
+
+pub type TestDecompressor = DivansDecompressor<<DivansDecompressorFactoryStruct<HeapAlloc<u8>,
+                                                                               HeapAlloc<DefaultCDF16>,
+                                                                               HeapAlloc<StaticCommand>>
+                                                as DivansDecompressorFactory<HeapAlloc<u8>,
+                                                                             HeapAlloc<DefaultCDF16>,
+                                                                             HeapAlloc<StaticCommand>>>::DefaultDecoder,
+                                               HeapAlloc<u8>,
+                                               HeapAlloc<DefaultCDF16>,
+                                               HeapAlloc<StaticCommand>>;
+
+pub fn new_decompressor(skip_crc: bool, multithread: bool) -> TestDecompressor {
+    DivansDecompressorFactoryStruct::<HeapAlloc<u8>,
+                                      HeapAlloc<DefaultCDF16>,
+                                      HeapAlloc<StaticCommand>>::new(
+        HeapAlloc::<u8>::new(0),
+        HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+        HeapAlloc::<StaticCommand>::new(StaticCommand::nop()),
+        skip_crc,
+        multithread)
+}
+
+// Decodes stream output_chunk bytes a call, handing the decompressor to between after every call
+// that returned NeedsMoreOutput; returns whatever was emitted before decoding finished or failed.
+pub fn decode_to_end<F:FnMut(&mut TestDecompressor)>(decompressor: &mut TestDecompressor,
+                                                     stream: &[u8],
+                                                     output_chunk: usize,
+                                                     mut between: F) -> (Vec<u8>, Result<(), ErrMsg>) {
+    let mut ret = Vec::<u8>::new();
+    let mut buffer = vec![0u8; output_chunk];
+    let mut input_offset = 0usize;
+    let result = loop {
+        let mut output_offset = 0usize;
+        let res = decompressor.decode(stream, &mut input_offset, &mut buffer[..], &mut output_offset);
+        ret.extend(&buffer[..output_offset]);
+        match res {
+            DivansResult::Success => break Ok(()),
+            DivansResult::NeedsMoreOutput => {},
+            DivansResult::NeedsMoreInput => panic!("truncated stream"),
+            DivansResult::Failure(m) => break Err(m),
+        }
+        between(decompressor);
+    };
+    (ret, result)
+}
+
+pub fn encode(commands: &[Command<ConformanceSlice>], stream_features: u8) -> Result<Vec<u8>, ErrMsg> {
+    let mut opts = DivansCompressorOptions::default();
+    opts.stream_features = stream_features;
+    encode_with_options(commands, opts)
+}
+
+pub fn encode_with_options<SliceType:SliceWrapper<u8>+Default>(commands: &[Command<SliceType>],
+                                                             mut opts: DivansCompressorOptions) -> Result<Vec<u8>, ErrMsg> {
+    opts.window_size = Some(16);
+    let mut compressor = DivansCompressorFactoryStruct::<HeapAlloc<u8>, HeapAlloc<DefaultCDF16>>::new(
+        HeapAlloc::<u8>::new(0),
+        HeapAlloc::<u32>::new(0),
+        HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+        opts,
+        ());
+    let mut ret = Vec::<u8>::new();
+    let mut buffer = [0u8; 4096];
+    let mut command_offset = 0usize;
+    let mut result = Ok(());
+    while result.is_ok() && command_offset != commands.len() {
+        let mut offset = 0usize;
+        let res = compressor.encode_commands(commands, &mut command_offset, &mut buffer[..], &mut offset);
+        ret.extend(&buffer[..offset]);
+        if let DivansOutputResult::Failure(m) = res {
+            result = Err(m);
+        }
+    }
+    while result.is_ok() {
+        let mut offset = 0usize;
+        let res = compressor.flush(&mut buffer[..], &mut offset);
+        ret.extend(&buffer[..offset]);
+        match res {
+            DivansOutputResult::Success => break,
+            DivansOutputResult::NeedsMoreOutput => {},
+            DivansOutputResult::Failure(m) => result = Err(m),
+        }
+    }
+    compressor.free();
+    result.map(|_| ret)
+}
+
+// compresses raw input the way the std writer does
+pub fn compress_hybrid(input: &[u8], opts: DivansCompressorOptions) -> Vec<u8> {
+    let mut stream = Vec::<u8>::new();
+    {
+        let mut compressor = DivansBrotliHybridCompressorWriter::new(&mut stream, opts, 0);
+        compressor.write_all(input).unwrap();
+        compressor.flush().unwrap();
+    }
+    stream
+}
+
+// encodes input through compressor 4096 bytes a call, then flushes it
+pub fn compress_with<C:Compressor>(compressor: &mut C, input: &[u8]) -> Result<Vec<u8>, ErrMsg> {
+    let mut stream = Vec::<u8>::new();
+    let mut buffer = [0u8; 4096];
+    let mut input_offset = 0usize;
+    while input_offset != input.len() {
+        let mut offset = 0usize;
+        let end = core::cmp::min(input.len(), input_offset + 4096);
+        let res = compressor.encode(&input[..end], &mut input_offset, &mut buffer[..], &mut offset);
+        stream.extend(&buffer[..offset]);
+        if let DivansResult::Failure(m) = res {
+            return Err(m);
+        }
+    }
+    loop {
+        let mut offset = 0usize;
+        let res = compressor.flush(&mut buffer[..], &mut offset);
+        stream.extend(&buffer[..offset]);
+        match res {
+            DivansOutputResult::Success => return Ok(stream),
+            DivansOutputResult::NeedsMoreOutput => {},
+            DivansOutputResult::Failure(m) => return Err(m),
+        }
+    }
+}
+
+// How decode_with sets up its decompressor before the first call, and the room each call gets
+#[derive(Clone, Copy, Debug)]
+pub struct DecodeConfig {
+    pub skip_crc: bool,
+    pub multithread: bool,
+    pub output_chunk: usize,
+    pub authentication_key: Option<[u8; AUTHENTICATION_KEY_LENGTH]>,
+    pub framing: Option<FramingTag>,
+    pub checksum_policy: Option<ChecksumPolicy>,
+    pub threaded_crc: bool,
+    pub output_digest: Option<OutputDigestType>,
+}
+
+impl DecodeConfig {
+    pub fn new(multithread: bool) -> Self {
+        DecodeConfig {
+            skip_crc: false,
+            multithread: multithread,
+            output_chunk: 4096,
+            authentication_key: None,
+            framing: None,
+            checksum_policy: None,
+            threaded_crc: false,
+            output_digest: None,
+        }
+    }
+}
+
+// what decode_with makes of a stream: whatever was emitted before decoding finished or failed,
+// and what the decompressor reports afterwards
+pub struct Decoded {
+    pub output: Vec<u8>,
+    pub result: Result<(), ErrMsg>,
+    pub options: Option<DecodedStreamOptions>,
+    pub stats: DecodeStats,
+    pub expected_checksum: Option<u64>,
+    pub expected_crc: Option<u32>,
+    pub output_digest: Option<u64>,
+}
+
+impl Decoded {
+    pub fn into_output(self) -> Result<Vec<u8>, ErrMsg> {
+        let output = self.output;
+        self.result.map(|_| output)
+    }
+}
+
+// Decodes stream with a decompressor set up as config says, handing it to between after every
+// call that returned NeedsMoreOutput (see decode_to_end).
+pub fn decode_with<F:FnMut(&mut TestDecompressor)>(stream: &[u8], config: DecodeConfig, between: F) -> Decoded {
+    let mut decompressor = match config.authentication_key {
+        Some(key) => DivansDecompressorFactoryStruct::<HeapAlloc<u8>,
+                                                       HeapAlloc<DefaultCDF16>,
+                                                       HeapAlloc<StaticCommand>>::new_authenticated(
+            HeapAlloc::<u8>::new(0),
+            HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+            HeapAlloc::<StaticCommand>::new(StaticCommand::nop()),
+            key,
+            config.multithread),
+        None => new_decompressor(config.skip_crc, config.multithread),
+    };
+    decompressor.set_framing_tag(config.framing).unwrap();
+    if let Some(policy) = config.checksum_policy {
+        decompressor.set_checksum_policy(policy).unwrap();
+    }
+    decompressor.set_threaded_crc(config.threaded_crc).unwrap();
+    decompressor.set_output_digest(config.output_digest).unwrap();
+    let (output, result) = decode_to_end(&mut decompressor, stream, config.output_chunk, between);
+    let ret = Decoded {
+        output: output,
+        result: result,
+        options: decompressor.options(),
+        stats: decompressor.stats(),
+        expected_checksum: decompressor.expected_checksum(),
+        expected_crc: decompressor.expected_crc(),
+        output_digest: decompressor.output_digest(),
+    };
+    decompressor.free();
+    ret
+}
+
+pub fn decode(stream: &[u8]) -> Result<Vec<u8>, ErrMsg> {
+    decode_with(stream, DecodeConfig::new(false), |_| {}).into_output()
+}
+
+pub fn decoded_options(stream: &[u8], multithread: bool) -> Option<DecodedStreamOptions> {
+    let mut config = DecodeConfig::new(multithread);
+    config.skip_crc = true;
+    let decoded = decode_with(stream, config, |_| {});
+    if let Err(m) = decoded.result {
+        panic!("{:?}", m);
+    }
+    decoded.options
+}
+
+pub fn assert_same_command<SliceA:SliceWrapper<u8>, SliceB:SliceWrapper<u8>>(orig: &Command<SliceA>, dec: &Command<SliceB>) {
+    match (orig, dec) {
+        (&Command::Literal(ref a), &Command::Literal(ref b)) => {
+            assert_eq!(a.data.slice(), b.data.slice());
+            assert_eq!(a.high_entropy, b.high_entropy);
+        },
+        (&Command::Copy(a), &Command::Copy(b)) => assert_eq!((a.distance, a.num_bytes), (b.distance, b.num_bytes)),
+        (&Command::Dict(a), &Command::Dict(b)) => assert_eq!((a.word_id, a.word_size, a.transform, a.final_size, a.empty),
+                                                              (b.word_id, b.word_size, b.transform, b.final_size, b.empty)),
+        (&Command::BlockSwitchCommand(a), &Command::BlockSwitchCommand(b)) => assert_eq!(a.block_type(), b.block_type()),
+        (&Command::BlockSwitchDistance(a), &Command::BlockSwitchDistance(b)) => assert_eq!(a.block_type(), b.block_type()),
+        (&Command::BlockSwitchLiteral(a), &Command::BlockSwitchLiteral(b)) => assert_eq!((a.block_type(), a.stride()),
+                                                                                          (b.block_type(), b.stride())),
+        // the decoder's maps are always full size, of which the stream fills the front
+        (&Command::PredictionMode(ref a), &Command::PredictionMode(ref b)) => {
+            let lit_len = a.literal_context_map.slice().len();
+            assert_eq!(a.literal_context_map.slice(), &b.literal_context_map.slice()[..lit_len]);
+            assert_eq!(a.distance_context_map(), &b.distance_context_map()[..a.distance_context_map().len()]);
+            assert_eq!(a.literal_prediction_mode(), b.literal_prediction_mode());
+        },
+        _ => panic!("command type differs"),
+    }
+}
@@ -1,21 +1,52 @@
 use core;
+use core::time::Duration;
 #[allow(unused_imports)]
 use interface::{DivansCompressorFactory, BlockSwitch, LiteralBlockSwitch, Command, Compressor, CopyCommand, Decompressor, DictCommand, LiteralCommand, Nop, NewWithAllocator, ArithmeticEncoderOrDecoder, LiteralPredictionModeNibble, PredictionModeContextMap, free_cmd, FeatureFlagSliceType, StreamDemuxer, ReadableBytes, StreamID, NUM_STREAMS, EncoderOrDecoderRecoderSpecialization};
 use ::interface::{DivansOutputResult, ErrMsg};
-use slice_util::{AllocatedMemoryRange, AllocatedMemoryPrefix, SlicePlaceholder32};
+use slice_util::{AllocatedMemoryRange, AllocatedMemoryPrefix};
 use alloc::{SliceWrapper, SliceWrapperMut, Allocator};
 use alloc_util::RepurposingAlloc;
 use ::alloc_util::UninitializedOnAlloc;
 use cmd_to_raw::DivansRecodeState;
+use codec::weights::MixerSnapshotQueue;
+use codec::CMD_CODER;
 pub enum ThreadData<AllocU8:Allocator<u8>> {
     Data(AllocatedMemoryRange<u8, AllocU8>),
     Yield,
     Eof,
     Err(ErrMsg)
 }
-pub type StaticCommand = Command<SlicePlaceholder32<u8>>;
+pub type StaticCommand = Command<LiteralSpan>;
 pub const NUM_DATA_BUFFERED:usize = 2;
 
+// The bytes of a literal as they reach the main thread: just the length when the main thread is to
+// decode them, or where the worker left them, already decoded, in the spans pulled with the batch.
+// A literal too long for one span buffer arrives as several spans, each with the count of its
+// bytes still to come in later spans.
+#[derive(Clone,Copy,Debug)]
+pub enum LiteralSpan {
+    Length(u32),
+    Span{start: u32, len: u32, remaining: u32},
+}
+
+impl Default for LiteralSpan {
+    fn default() -> Self {
+        LiteralSpan::Length(0)
+    }
+}
+
+impl SliceWrapper<u8> for LiteralSpan {
+    fn slice(&self) -> &[u8] {
+        &[]
+    }
+    fn len(&self) -> usize {
+        match *self {
+            LiteralSpan::Length(len) => len as usize,
+            LiteralSpan::Span{len, ..} => len as usize,
+        }
+    }
+}
+
 impl<AllocU8:Allocator<u8>> Default for ThreadData<AllocU8> {
     fn default() -> Self {
         ThreadData::Data(AllocatedMemoryRange::<u8, AllocU8>::default())
@@ -28,29 +59,45 @@ pub fn empty_prediction_mode_context_map<ISl:SliceWrapper<u8>+Default>() -> Pred
         predmode_speed_and_distance_context_map:ISl::default(),
     }
 }
-#[derive(Clone,Copy,Debug)]
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
 pub enum CommandResult {
     Ok,
     Eof,
     Err(ErrMsg),
+    WouldBlock, // nothing was ready within the timeout of pull_command_buf_within
 }
 pub trait PullAllocatedCommand<AllocU8:Allocator<u8>, AllocCommand: Allocator<StaticCommand>> {
     fn pull_command_buf(&mut self,
             output:&mut AllocatedMemoryPrefix<StaticCommand, AllocCommand>,
-                        consumed_data:&mut [AllocatedMemoryRange<u8, AllocU8>;NUM_DATA_BUFFERED],
+                        spans:&mut AllocatedMemoryPrefix<u8, AllocU8>,
+                        consumed_data:&mut [[AllocatedMemoryRange<u8, AllocU8>;NUM_DATA_BUFFERED];NUM_STREAMS],
                         pm:&mut [PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>; 2]) -> CommandResult;
+    // pull_command_buf, giving up with CommandResult::WouldBlock once nothing has been ready for
+    // timeout; workers that never wait can leave it be
+    fn pull_command_buf_within(&mut self,
+                               output:&mut AllocatedMemoryPrefix<StaticCommand, AllocCommand>,
+                               spans:&mut AllocatedMemoryPrefix<u8, AllocU8>,
+                               consumed_data:&mut [[AllocatedMemoryRange<u8, AllocU8>;NUM_DATA_BUFFERED];NUM_STREAMS],
+                               pm:&mut [PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>; 2],
+                               _timeout: Duration) -> CommandResult {
+        self.pull_command_buf(output, spans, consumed_data, pm)
+    }
 }
 pub trait MainToThread<AllocU8:Allocator<u8>> {
     const COOPERATIVE_MAIN: bool;
     type CommandOutputType: SliceWrapperMut<StaticCommand>+Default;
     #[inline(always)]
     fn push_context_map(&mut self, cm: PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>) -> Result<(),()>;
+    // data for the worker to decode from stream_id; only workers that decode literals take the
+    // literal stream
     #[inline(always)]
-    fn push(&mut self, data: &mut AllocatedMemoryRange<u8, AllocU8>) -> Result<(),()>;
+    fn push(&mut self, stream_id: StreamID, data: &mut AllocatedMemoryRange<u8, AllocU8>) -> Result<(),()>;
+    // spans receives the decoded literal bytes the pulled commands point into, if any
     #[inline(always)]
     fn pull(&mut self,
             output:&mut Self::CommandOutputType,
-            consumed_data:&mut [AllocatedMemoryRange<u8, AllocU8>;NUM_DATA_BUFFERED],
+            spans:&mut AllocatedMemoryPrefix<u8, AllocU8>,
+            consumed_data:&mut [[AllocatedMemoryRange<u8, AllocU8>;NUM_DATA_BUFFERED];NUM_STREAMS],
             pm:&mut [PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>; 2]) -> CommandResult;
     fn broadcast_err(&mut self, err:ErrMsg);
 }
@@ -87,16 +134,21 @@ pub trait ThreadToMain<AllocU8:Allocator<u8>> {
 }
 pub const NUM_SERIAL_COMMANDS_BUFFERED: usize = 256;
 pub struct SerialWorker<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> {
-    data_len: usize,
-    data: [ThreadData<AllocU8>;NUM_DATA_BUFFERED],
+    data_len: [usize; NUM_STREAMS],
+    data: [[ThreadData<AllocU8>;NUM_DATA_BUFFERED]; NUM_STREAMS],
     cm_len: usize,
     cm: [PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>; 2],
     result: AllocatedMemoryPrefix<StaticCommand, AllocCommand>,
-    result_data: [AllocatedMemoryRange<u8, AllocU8>; NUM_DATA_BUFFERED],
+    result_spans: AllocatedMemoryPrefix<u8, AllocU8>,
+    result_data: [[AllocatedMemoryRange<u8, AllocU8>; NUM_DATA_BUFFERED]; NUM_STREAMS],
     result_cm: [PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>; 2],
+    // snapshots of the literal mixer, when the worker decodes the literals
+    pub mixer_snapshots: MixerSnapshotQueue,
     pub waiters: u8,
     eof_present_in_result: CommandResult, // retriever should try to get everything
     pub err: Option<ErrMsg>,
+    #[cfg(test)]
+    pub killed: bool, // makes the worker thread of a MultiWorker panic (see MultiWorker::kill)
 }
 impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> SerialWorker<AllocU8, AllocCommand> {
     pub fn broadcast_err_internal(&mut self, err: ErrMsg) {
@@ -105,30 +157,27 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> SerialWorker<
     }
     pub fn result_ready(&self) -> bool {
         if let CommandResult::Ok = self.eof_present_in_result {
-            return self.result.1 != 0 || self.result_data[0].0.len() != 0;
+            return self.result.1 != 0 || self.result_data.iter().any(|data| data[0].0.len() != 0);
         }
         true
     }
     pub fn result_space_ready(&self) -> bool {
         self.result.0.len() > self.result.1 as usize
     }
-    pub fn debug_result_commands_ready(&self) -> usize {
-        self.result.1 as usize
-    }
     pub fn result_multi_space_ready(&self, space_needed:usize) -> bool {
         self.result.0.len() - self.result.1 as usize >= space_needed
     }
+    pub fn result_span_space_ready(&self, space_needed:usize) -> bool {
+        self.result_spans.0.len() - self.result_spans.1 as usize >= space_needed
+    }
     pub fn cm_space_ready(&self) -> bool {
         self.cm_len != self.cm.len()
     }
     pub fn cm_ready(&self) -> bool {
         self.cm_len != 0
     }
-    pub fn data_ready(&self) -> bool {
-        self.data_len != 0
-    }
-    pub fn returned_data_space_ready(&self) -> bool {
-        self.result_data[NUM_DATA_BUFFERED -1].0.len() == 0
+    pub fn data_ready(&self, stream_id: StreamID) -> bool {
+        self.data_len[stream_id as usize] != 0
     }
     pub fn set_error(&mut self, m:ErrMsg) {
         self.eof_present_in_result = CommandResult::Err(m);
@@ -143,19 +192,61 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> SerialWorker<
     fn get_failure(&self) -> DivansOutputResult {
         DivansOutputResult::Failure(self.err.unwrap())
     }
+    pub fn pull_stream_data(&mut self, stream_id: StreamID) -> ThreadData<AllocU8> {
+        if self.err.is_some() {
+            return ThreadData::Err(self.err.unwrap());
+        }
+        let stream = stream_id as usize;
+        if self.data_len[stream] == 0 {
+            return ThreadData::Yield;
+        }
+        assert_eq!(self.data[stream].len(), 2);
+        let first = core::mem::replace(&mut self.data[stream][1], ThreadData::Eof);
+        let ret = core::mem::replace(&mut self.data[stream][0], first);
+        self.data_len[stream] -= 1;
+        ret
+    }
+    pub fn push_consumed_stream_data(&mut self,
+                                     stream_id: StreamID,
+                                     data:&mut AllocatedMemoryRange<u8, AllocU8>) -> DivansOutputResult {
+        if self.err.is_some() {
+            return self.get_failure();
+        }
+        let result_data = &mut self.result_data[stream_id as usize];
+        if result_data[0].0.len() == 0 {
+           core::mem::swap(&mut result_data[0], data); 
+        } else if result_data[1].0.len() == 0 {
+            core::mem::swap(&mut result_data[1], data); 
+        } else {
+            return DivansOutputResult::NeedsMoreOutput;
+        }
+        DivansOutputResult::Success
+    }
 
     // returns the old space
     pub fn insert_results(&mut self,
                           cmds:&mut AllocatedMemoryPrefix<StaticCommand, AllocCommand>,
+                          spans:&mut AllocatedMemoryPrefix<u8, AllocU8>,
                           cm:Option<&mut PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>>) -> usize {
         let old_len = self.result.1;
         if self.result.1 == 0 {
             core::mem::swap(&mut self.result, cmds);
+            core::mem::swap(&mut self.result_spans, spans);
         } else {
-            self.result.0.slice_mut().split_at_mut(old_len as usize).1.split_at_mut(cmds.len()).0.clone_from_slice(cmds.slice());
+            let span_offset = self.result_spans.1;
+            self.result_spans.0.slice_mut().split_at_mut(span_offset as usize).1.split_at_mut(spans.len()).0.clone_from_slice(spans.slice());
+            self.result_spans.1 += spans.len() as u32;
+            let appended = self.result.0.slice_mut().split_at_mut(old_len as usize).1.split_at_mut(cmds.len()).0;
+            appended.clone_from_slice(cmds.slice());
+            for cmd in appended.iter_mut() {
+                if let Command::Literal(LiteralCommand{data:LiteralSpan::Span{ref mut start, ..}, ..}) = *cmd {
+                    *start += span_offset;
+                }
+            }
             self.result.1 += cmds.len() as u32;
         }
         cmds.1 = 0;
+        spans.1 = 0;
         if let Some(context_map) = cm {
             assert_eq!(self.result_cm[1].has_context_speeds(), false);
             if self.result_cm[0].has_context_speeds() {
@@ -172,40 +263,53 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> SerialWorker<
         SerialWorker::<AllocU8, AllocCommand> {
             waiters: 0,
             eof_present_in_result: CommandResult::Ok,
-            data_len: 0,
-            data:[ThreadData::<AllocU8>::default(),
-                  ThreadData::<AllocU8>::default()],
+            data_len: [0; NUM_STREAMS],
+            data:[[ThreadData::<AllocU8>::default(),
+                   ThreadData::<AllocU8>::default()],
+                  [ThreadData::<AllocU8>::default(),
+                   ThreadData::<AllocU8>::default()]],
             cm_len: 0,
             cm: [empty_prediction_mode_context_map::<AllocatedMemoryPrefix<u8, AllocU8>>(),
                  empty_prediction_mode_context_map::<AllocatedMemoryPrefix<u8, AllocU8>>()],
             result:AllocatedMemoryPrefix::<StaticCommand, AllocCommand>::realloc(mc.alloc_cell(NUM_SERIAL_COMMANDS_BUFFERED),0),
+            result_spans:AllocatedMemoryPrefix::<u8, AllocU8>::default(),
+            mixer_snapshots: MixerSnapshotQueue::default(),
             result_cm: [empty_prediction_mode_context_map::<AllocatedMemoryPrefix<u8, AllocU8>>(),
                         empty_prediction_mode_context_map::<AllocatedMemoryPrefix<u8, AllocU8>>()],
-            result_data:[AllocatedMemoryRange::<u8, AllocU8>::default(),
-                         AllocatedMemoryRange::<u8, AllocU8>::default()],
+            result_data:[[AllocatedMemoryRange::<u8, AllocU8>::default(),
+                          AllocatedMemoryRange::<u8, AllocU8>::default()],
+                         [AllocatedMemoryRange::<u8, AllocU8>::default(),
+                          AllocatedMemoryRange::<u8, AllocU8>::default()]],
             err: None,
+            #[cfg(test)]
+            killed: false,
         }
     }
+    // the spans the results are returned in, for a worker that decodes the literals
+    pub fn set_result_spans(&mut self, spans: AllocatedMemoryPrefix<u8, AllocU8>) {
+        self.result_spans = spans;
+    }
     pub fn free(&mut self, m8: &mut RepurposingAlloc<u8, AllocU8>, mc:&mut AllocCommand) {
         mc.free_cell(core::mem::replace(&mut self.result.0, AllocCommand::AllocatedMemory::default()));
         self.result.1 = 0;
-        for item in self.data.iter_mut() {
+        m8.get_base_alloc().free_cell(core::mem::replace(&mut self.result_spans, AllocatedMemoryPrefix::<u8, AllocU8>::default()).0);
+        for item in self.data.iter_mut().flat_map(|data| data.iter_mut()) {
             if let ThreadData::Data(ref mut buf) = *item {
                 m8.get_base_alloc().free_cell(core::mem::replace(buf, AllocatedMemoryRange::<u8, AllocU8>::default()).0);
             }
         }
-        for item in self.result_data.iter_mut() {
+        for item in self.result_data.iter_mut().flat_map(|data| data.iter_mut()) {
             m8.get_base_alloc().free_cell(core::mem::replace(item, AllocatedMemoryRange::<u8, AllocU8>::default()).0);
         }
         for item in self.cm.iter_mut() {
             let cur_item = core::mem::replace(item, empty_prediction_mode_context_map::<AllocatedMemoryPrefix<u8, AllocU8>>());
             free_cmd(&mut Command::PredictionMode(cur_item),
-                     &mut m8.use_cached_allocation::<UninitializedOnAlloc>());
+                     &mut m8.use_slab_arena::<UninitializedOnAlloc>());
         }
         for item in self.result_cm.iter_mut() {
             let cur_item = core::mem::replace(item, empty_prediction_mode_context_map::<AllocatedMemoryPrefix<u8, AllocU8>>());
             free_cmd(&mut Command::PredictionMode(cur_item),
-                     &mut m8.use_cached_allocation::<UninitializedOnAlloc>());
+                     &mut m8.use_slab_arena::<UninitializedOnAlloc>());
         }
     }
 }
@@ -213,9 +317,10 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> SerialWorker<
 impl<AllocU8:Allocator<u8>, AllocCommand: Allocator<StaticCommand>> PullAllocatedCommand<AllocU8, AllocCommand> for SerialWorker<AllocU8, AllocCommand> {
     fn pull_command_buf(&mut self,
                         output:&mut AllocatedMemoryPrefix<StaticCommand, AllocCommand>,
-                        consumed_data:&mut [AllocatedMemoryRange<u8, AllocU8>;NUM_DATA_BUFFERED],
+                        spans:&mut AllocatedMemoryPrefix<u8, AllocU8>,
+                        consumed_data:&mut [[AllocatedMemoryRange<u8, AllocU8>;NUM_DATA_BUFFERED];NUM_STREAMS],
                         pm:&mut [PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>; 2]) -> CommandResult {
-        self.pull(output, consumed_data, pm)
+        self.pull(output, spans, consumed_data, pm)
     }
 }
 
@@ -232,18 +337,20 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> MainToThread<
         Ok(())
     }
     #[inline(always)]
-    fn push(&mut self, data: &mut AllocatedMemoryRange<u8, AllocU8>) -> Result<(),()> {
-        if self.data_len == self.data.len() || data.slice().len() == 0 {
+    fn push(&mut self, stream_id: StreamID, data: &mut AllocatedMemoryRange<u8, AllocU8>) -> Result<(),()> {
+        let stream = stream_id as usize;
+        if self.data_len[stream] == self.data[stream].len() || data.slice().len() == 0 {
             return Err(());
         }
-        self.data[self.data_len] = ThreadData::Data(core::mem::replace(data, AllocatedMemoryRange::<u8, AllocU8>::default()));
-        self.data_len += 1;
+        self.data[stream][self.data_len[stream]] = ThreadData::Data(core::mem::replace(data, AllocatedMemoryRange::<u8, AllocU8>::default()));
+        self.data_len[stream] += 1;
         Ok(())        
     }
     #[inline(always)]
     fn pull(&mut self,
             output:&mut Self::CommandOutputType,
-            consumed_data:&mut [AllocatedMemoryRange<u8, AllocU8>;NUM_DATA_BUFFERED],
+            spans:&mut AllocatedMemoryPrefix<u8, AllocU8>,
+            consumed_data:&mut [[AllocatedMemoryRange<u8, AllocU8>;NUM_DATA_BUFFERED];NUM_STREAMS],
             pm:&mut [PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>; 2]) -> CommandResult {    
         if self.result.len() == 0 {
             assert_eq!(self.result_cm[0].has_context_speeds(), false);
@@ -251,6 +358,8 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> MainToThread<
         }
         
         core::mem::swap(output, &mut self.result);
+        core::mem::swap(spans, &mut self.result_spans);
+        self.result_spans.1 = 0;
         core::mem::swap(consumed_data, &mut self.result_data);
         core::mem::swap(pm, &mut self.result_cm);
         self.eof_present_in_result
@@ -365,8 +474,8 @@ impl<AllocU8:Allocator<u8>, WorkerInterface:ThreadToMain<AllocU8>> StreamDemuxer
     }
     #[inline(always)]
     fn free_demux(&mut self, m8: &mut AllocU8){
+        // freed here rather than handed back: the worker has been freed already, so it would leak there
         if self.slice.0.slice().len() != 0 {
-            self.worker.push_consumed_data(&mut self.slice, None);
             m8.free_cell(core::mem::replace(&mut self.slice.0, AllocU8::AllocatedMemory::default()));
         }
     }
@@ -421,15 +530,16 @@ impl <AllocU8:Allocator<u8>, WorkerInterface:ThreadToMain<AllocU8>+MainToThread<
         self.worker.push_context_map(cm)
     }
     #[inline(always)]
-    fn push(&mut self, data: &mut AllocatedMemoryRange<u8, AllocU8>) -> Result<(),()> {
-        self.worker.push(data)
+    fn push(&mut self, stream_id: StreamID, data: &mut AllocatedMemoryRange<u8, AllocU8>) -> Result<(),()> {
+        self.worker.push(stream_id, data)
     }
     #[inline(always)]
     fn pull(&mut self,
             output:&mut Self::CommandOutputType,
-            consumed_data:&mut [AllocatedMemoryRange<u8, AllocU8>;NUM_DATA_BUFFERED],
+            spans:&mut AllocatedMemoryPrefix<u8, AllocU8>,
+            consumed_data:&mut [[AllocatedMemoryRange<u8, AllocU8>;NUM_DATA_BUFFERED];NUM_STREAMS],
             pm:&mut [PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>; 2]) -> CommandResult {    
-        self.worker.pull(output, consumed_data, pm)
+        self.worker.pull(output, spans, consumed_data, pm)
     }
     fn broadcast_err(&mut self, err:ErrMsg) {
         <WorkerInterface as ThreadToMain<AllocU8>>::broadcast_err(&mut self.worker, err);
@@ -448,7 +558,7 @@ pub fn downcast_command<AllocU8:Allocator<u8>>(cmd: &mut Command<AllocatedMemory
         &mut Command::Dict(d) => return (Command::Dict(d), None),
         &mut Command::Copy(c) => return (Command::Copy(c), None),
         &mut Command::Literal(ref l) => return (Command::Literal(LiteralCommand{
-            data:SlicePlaceholder32::<u8>::new(l.data.len() as u32),
+            data:LiteralSpan::Length(l.data.len() as u32),
             prob:FeatureFlagSliceType::default(),
             high_entropy: l.high_entropy,
         }), None),
@@ -460,18 +570,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
     const ISOLATED:bool = true;
     #[inline(always)]
     fn pull_data(&mut self) -> ThreadData<AllocU8> {
-        if self.err.is_some() {
-            return ThreadData::Err(self.err.unwrap());
-        }
-        if self.data_len == 0 {
-            return ThreadData::Yield;
-        }
-        assert!(self.data_len != 0);
-        assert_eq!(self.data.len(), 2);
-        let first = core::mem::replace(&mut self.data[1], ThreadData::Eof);
-        let ret = core::mem::replace(&mut self.data[0], first);
-        self.data_len -= 1;
-        ret
+        self.pull_stream_data(CMD_CODER as StreamID)
     }
     #[inline(always)]
     fn pull_context_map(&mut self,
@@ -529,17 +628,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
                     data:&mut AllocatedMemoryRange<u8, AllocU8>,
                     _m8: Option<&mut RepurposingAlloc<u8, AllocU8>>,
     ) -> DivansOutputResult {
-        if self.err.is_some() {
-            return self.get_failure();
-        }
-        if self.result_data[0].0.len() == 0 {
-           core::mem::swap(&mut self.result_data[0], data); 
-        } else if self.result_data[1].0.len() == 0 {
-            core::mem::swap(&mut self.result_data[1], data); 
-        } else {
-            return DivansOutputResult::NeedsMoreOutput;
-        }
-        DivansOutputResult::Success
+        self.push_consumed_stream_data(CMD_CODER as StreamID, data)
     }
    #[inline(always)]
     fn push_eof(&mut self,
@@ -0,0 +1,231 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Where an encode spends its time, to tell whether it is bound by the parser or by the coder.
+// Nothing is measured until a compressor is given a Clock with set_clock; the clock is only read
+// at stage boundaries, so the output is the same with or without one.
+//   parse: finding the commands in the input (brotli, or the raw compressor's match finder)
+//   model: choosing how the commands are modeled (literal prescan, nibble order, static literal
+//          tables and the IR optimizer's trial encodes); the brotli hybrid compressor only
+//   entropy code: the codec coding the commands, less recode
+//   recode: the codec replaying the commands into the ring buffer the literal contexts come from
+#![cfg(feature="timings")]
+#[cfg(feature="std")]
+use std::vec::Vec;
+#[cfg(feature="std")]
+use interface::{Compressor, DivansCompressorOptions, DivansResult, DivansOutputResult, ErrMsg};
+#[cfg(feature="std")]
+use writer::new_brotli_hybrid_compressor;
+#[cfg(feature="std")]
+use error::Error;
+
+// nanoseconds since any fixed point, never going backwards
+pub trait Clock: Sync {
+    fn now_ns(&self) -> u64;
+}
+
+// the monotonic clock of std, counted from its first use
+#[cfg(feature="std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdClock;
+
+#[cfg(feature="std")]
+impl Clock for StdClock {
+    fn now_ns(&self) -> u64 {
+        static EPOCH: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+        let elapsed = EPOCH.get_or_init(std::time::Instant::now).elapsed();
+        elapsed.as_secs().saturating_mul(1_000_000_000).saturating_add(u64::from(elapsed.subsec_nanos()))
+    }
+}
+
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncodeStage {
+    Parse,
+    Model,
+    EntropyCode,
+    Recode,
+}
+
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EncodeTimings {
+    pub parse_ns: u64,
+    pub model_ns: u64,
+    pub entropy_code_ns: u64,
+    pub recode_ns: u64,
+}
+
+impl EncodeTimings {
+    pub fn total_ns(&self) -> u64 {
+        self.parse_ns + self.model_ns + self.entropy_code_ns + self.recode_ns
+    }
+    // the stage that took longest; None if nothing was measured
+    pub fn bottleneck(&self) -> Option<EncodeStage> {
+        let stages = [(EncodeStage::Parse, self.parse_ns),
+                      (EncodeStage::Model, self.model_ns),
+                      (EncodeStage::EntropyCode, self.entropy_code_ns),
+                      (EncodeStage::Recode, self.recode_ns)];
+        let mut ret = None;
+        let mut longest = 0u64;
+        for &(stage, ns) in stages.iter() {
+            if ns > longest {
+                ret = Some(stage);
+                longest = ns;
+            }
+        }
+        ret
+    }
+}
+
+// reads 0 without a clock
+#[derive(Clone, Copy, Default)]
+pub struct StageClock {
+    clock: Option<&'static dyn Clock>,
+}
+
+impl StageClock {
+    pub fn new(clock: &'static dyn Clock) -> Self {
+        StageClock {
+            clock: Some(clock),
+        }
+    }
+    #[inline(always)]
+    pub fn now(&self) -> u64 {
+        match self.clock {
+            Some(clock) => clock.now_ns(),
+            None => 0,
+        }
+    }
+    #[inline(always)]
+    pub fn since(&self, start: u64) -> u64 {
+        self.now().saturating_sub(start)
+    }
+}
+
+// Compresses input with the brotli hybrid compressor, as DivansBrotliHybridCompressorWriter would,
+// timing its stages with clock (eg. &StdClock).
+#[cfg(feature="std")]
+pub fn encode_with_timings(input: &[u8],
+                           opts: DivansCompressorOptions,
+                           clock: &'static dyn Clock) -> Result<(Vec<u8>, EncodeTimings), Error> {
+    let mut compressor = new_brotli_hybrid_compressor(opts);
+    compressor.set_clock(clock);
+    let mut input_offset = 0usize;
+    let mut unused = 0usize;
+    let mut result = Ok(());
+    while result.is_ok() && input_offset < input.len() {
+        match compressor.encode(input, &mut input_offset, &mut [], &mut unused) {
+            DivansResult::NeedsMoreInput | DivansResult::Success => {},
+            DivansResult::NeedsMoreOutput => result = Err(ErrMsg::BrotliInternalEncodeStreamNeedsOutputWithoutFlush),
+            DivansResult::Failure(m) => result = Err(m),
+        }
+    }
+    let mut ret = Vec::<u8>::new();
+    let mut buffer = [0u8; 4096];
+    while result.is_ok() {
+        let mut offset = 0usize;
+        let res = compressor.flush(&mut buffer[..], &mut offset);
+        ret.extend(&buffer[..offset]);
+        match res {
+            DivansOutputResult::Success => break,
+            DivansOutputResult::NeedsMoreOutput => {},
+            DivansOutputResult::Failure(m) => result = Err(m),
+        }
+    }
+    let timings = compressor.timings();
+    compressor.free();
+    let output_len = ret.len() as u64;
+    result.map(|_| (ret, timings)).map_err(|m| Error::from(m).in_state("compressing").at(input_offset as u64, output_len))
+}
+
+#[cfg(all(test, feature="std", feature="decoder"))]
+mod test {
+    use std::vec::Vec;
+    use alloc_stdlib::HeapAlloc;
+    use divans_compressor::DivansCompressorFactoryStruct;
+    use interface::{
+        Compressor,
+        DivansCompressorFactory,
+        DivansCompressorOptions,
+        DivansOutputResult,
+        DivansResult,
+        DefaultCDF16,
+    };
+    use test_support::{compress_hybrid, decode};
+
+    // ticks once per reading, so every stage that is timed at all shows up
+    #[cfg(feature="timings")]
+    struct TickingClock(std::sync::atomic::AtomicU64);
+
+    #[cfg(feature="timings")]
+    impl ::Clock for TickingClock {
+        fn now_ns(&self) -> u64 {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        }
+    }
+
+    #[cfg(feature="timings")]
+    #[test]
+    fn test_encode_with_timings() {
+        static TICKS: TickingClock = TickingClock(std::sync::atomic::AtomicU64::new(0));
+        let mut input = Vec::<u8>::new();
+        for i in 0..65536u32 {
+            input.push(b"the quick brown fox "[(i % 20) as usize] ^ (i / 4096) as u8);
+        }
+        let mut opts = DivansCompressorOptions::default();
+        opts.literal_prescan = true;
+        let (stream, timings) = ::encode_with_timings(&input[..], opts, &::StdClock).unwrap();
+        assert_eq!(stream, compress_hybrid(&input[..], opts));
+        assert_eq!(decode(&stream[..]).unwrap(), input);
+        assert!(timings.total_ns() != 0);
+        assert!(timings.bottleneck().is_some());
+        let (ticked, timings) = ::encode_with_timings(&input[..], opts, &TICKS).unwrap();
+        assert_eq!(ticked, stream);
+        assert!(timings.parse_ns != 0 && timings.model_ns != 0 && timings.entropy_code_ns != 0 && timings.recode_ns != 0);
+        // the raw compressor has no model stage
+        let mut compressor = DivansCompressorFactoryStruct::<HeapAlloc<u8>, HeapAlloc<DefaultCDF16>>::new(
+            HeapAlloc::<u8>::new(0),
+            HeapAlloc::<u32>::new(0),
+            HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+            DivansCompressorOptions::default(),
+            ());
+        compressor.set_clock(&TICKS);
+        let mut raw = Vec::<u8>::new();
+        let mut buffer = [0u8; 4096];
+        let mut input_offset = 0usize;
+        while input_offset < input.len() {
+            let mut offset = 0usize;
+            match compressor.encode(&input[..], &mut input_offset, &mut buffer[..], &mut offset) {
+                DivansResult::Failure(m) => panic!("{:?}", m),
+                _ => raw.extend(&buffer[..offset]),
+            }
+        }
+        loop {
+            let mut offset = 0usize;
+            let res = compressor.flush(&mut buffer[..], &mut offset);
+            raw.extend(&buffer[..offset]);
+            match res {
+                DivansOutputResult::Success => break,
+                DivansOutputResult::NeedsMoreOutput => {},
+                DivansOutputResult::Failure(m) => panic!("{:?}", m),
+            }
+        }
+        let timings = compressor.timings();
+        compressor.free();
+        assert_eq!(decode(&raw[..]).unwrap(), input);
+        assert_eq!(timings.model_ns, 0);
+        assert!(timings.parse_ns != 0 && timings.entropy_code_ns != 0 && timings.recode_ns != 0);
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// One decode path for services moving their data from brotli to divans, which hold both for a while.
+// The first byte tells the formats apart: every divans stream, stored frames included, starts with
+// MAGIC_NUMBER[0], which never starts a valid brotli stream (it would declare an empty last metablock
+// followed by nonzero padding bits).  Streams framed by a caller's tag (see wire::FramingTag) start
+// with the tag instead, so they are read as brotli; decode them with a DivansDecompressor given the tag.
+#![cfg(feature="std")]
+use std::boxed::Box;
+use alloc_stdlib::HeapAlloc;
+use brotli::{BrotliDecompressStream, BrotliResult, BrotliState, HuffmanCode};
+use interface::{Decompressor, DefaultCDF16, DivansResult, ErrMsg, Nop, MAGIC_NUMBER};
+use divans_decompressor::{DivansDecompressor, DivansDecompressorFactory, DivansDecompressorFactoryStruct, StaticCommand};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamFormat {
+    Divans,
+    Brotli,
+}
+
+impl StreamFormat {
+    // the format of the stream starting with prefix, None until it holds a byte
+    pub fn sniff(prefix: &[u8]) -> Option<Self> {
+        match prefix.first() {
+            Some(&byte) if byte == MAGIC_NUMBER[0] => Some(StreamFormat::Divans),
+            Some(_) => Some(StreamFormat::Brotli),
+            None => None,
+        }
+    }
+}
+
+type HeapDivansDecompressor = DivansDecompressor<<DivansDecompressorFactoryStruct<HeapAlloc<u8>,
+                                                                                  HeapAlloc<DefaultCDF16>,
+                                                                                  HeapAlloc<StaticCommand>>
+                                                  as DivansDecompressorFactory<HeapAlloc<u8>,
+                                                                               HeapAlloc<DefaultCDF16>,
+                                                                               HeapAlloc<StaticCommand>>>::DefaultDecoder,
+                                                 HeapAlloc<u8>,
+                                                 HeapAlloc<DefaultCDF16>,
+                                                 HeapAlloc<StaticCommand>>;
+
+enum UniversalState {
+    Sniffing,
+    Divans(HeapDivansDecompressor),
+    Brotli(Box<BrotliState<HeapAlloc<u8>, HeapAlloc<u32>, HeapAlloc<HuffmanCode>>>),
+}
+
+// Decodes a divans or a brotli stream, whichever the input turns out to hold.  skip_crc and
+// multithread are passed on to the divans decompressor; brotli streams carry no crc to skip.
+pub struct UniversalDecoder {
+    state: UniversalState,
+    skip_crc: bool,
+    multithread: bool,
+}
+
+impl UniversalDecoder {
+    pub fn new(skip_crc: bool, multithread: bool) -> Self {
+        UniversalDecoder {
+            state: UniversalState::Sniffing,
+            skip_crc,
+            multithread,
+        }
+    }
+    // None until decode has been handed the first byte of the stream
+    pub fn format(&self) -> Option<StreamFormat> {
+        match self.state {
+            UniversalState::Sniffing => None,
+            UniversalState::Divans(_) => Some(StreamFormat::Divans),
+            UniversalState::Brotli(_) => Some(StreamFormat::Brotli),
+        }
+    }
+    pub fn free(self) {
+        if let UniversalState::Divans(decompressor) = self.state {
+            decompressor.free();
+        }
+    }
+}
+
+impl Decompressor for UniversalDecoder {
+    fn decode(&mut self,
+              input: &[u8],
+              input_offset: &mut usize,
+              output: &mut [u8],
+              output_offset: &mut usize) -> DivansResult {
+        if let UniversalState::Sniffing = self.state {
+            self.state = match StreamFormat::sniff(&input[*input_offset..]) {
+                None => return DivansResult::NeedsMoreInput,
+                Some(StreamFormat::Divans) => UniversalState::Divans(
+                    DivansDecompressorFactoryStruct::<HeapAlloc<u8>,
+                                                      HeapAlloc<DefaultCDF16>,
+                                                      HeapAlloc<StaticCommand>>::new(
+                        HeapAlloc::<u8>::new(0),
+                        HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+                        HeapAlloc::<StaticCommand>::new(StaticCommand::nop()),
+                        self.skip_crc,
+                        self.multithread)),
+                Some(StreamFormat::Brotli) => UniversalState::Brotli(Box::new(BrotliState::new(
+                    HeapAlloc::<u8>::new(0),
+                    HeapAlloc::<u32>::new(0),
+                    HeapAlloc::<HuffmanCode>::new(HuffmanCode::default())))),
+            };
+        }
+        match self.state {
+            UniversalState::Sniffing => unreachable!(),
+            UniversalState::Divans(ref mut decompressor) => decompressor.decode(input, input_offset, output, output_offset),
+            UniversalState::Brotli(ref mut state) => {
+                let mut available_in = input.len() - *input_offset;
+                let mut available_out = output.len() - *output_offset;
+                let mut total_out = 0usize;
+                match BrotliDecompressStream(&mut available_in, input_offset, input,
+                                             &mut available_out, output_offset, output,
+                                             &mut total_out, state) {
+                    BrotliResult::ResultSuccess => DivansResult::Success,
+                    BrotliResult::NeedsMoreInput => DivansResult::NeedsMoreInput,
+                    BrotliResult::NeedsMoreOutput => DivansResult::NeedsMoreOutput,
+                    BrotliResult::ResultFailure => DivansResult::Failure(ErrMsg::BrotliDecompressStreamFail),
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature="encoder")]
+mod test {
+    use std::vec::Vec;
+    use brotli;
+    use super::{StreamFormat, UniversalDecoder};
+    use interface::{Decompressor, DivansCompressorOptions, DivansResult, ErrMsg};
+    use oneshot::compress;
+
+    fn decode_all(stream: &[u8], multithread: bool) -> (Option<StreamFormat>, Result<Vec<u8>, ErrMsg>) {
+        let mut decoder = UniversalDecoder::new(false, multithread);
+        let mut ret = Vec::<u8>::new();
+        let mut buffer = [0u8; 333];
+        let mut input_offset = 0usize;
+        let result = loop {
+            let mut output_offset = 0usize;
+            // a byte at a time, so the format is sniffed from as little input as it can be
+            let input_end = core::cmp::min(input_offset + 1, stream.len());
+            let res = decoder.decode(&stream[..input_end], &mut input_offset, &mut buffer[..], &mut output_offset);
+            ret.extend_from_slice(&buffer[..output_offset]);
+            match res {
+                DivansResult::Success => break Ok(ret),
+                DivansResult::NeedsMoreOutput => {},
+                DivansResult::NeedsMoreInput => if input_end == stream.len() {
+                    break Err(ErrMsg::TruncatedInput);
+                },
+                DivansResult::Failure(m) => break Err(m),
+            }
+        };
+        let format = decoder.format();
+        decoder.free();
+        (format, result)
+    }
+
+    #[test]
+    fn test_universal_decoder() {
+        let input = &include_bytes!("../testdata/alice29")[..16384];
+        let divans = compress(input, DivansCompressorOptions::default()).unwrap();
+        let stored = compress(&input[..5], DivansCompressorOptions::default()).unwrap();
+        let mut brotli_stream = Vec::<u8>::new();
+        brotli::BrotliCompress(&mut &input[..], &mut brotli_stream, &brotli::enc::BrotliEncoderParams::default()).unwrap();
+        let mut empty_brotli = Vec::<u8>::new();
+        brotli::BrotliCompress(&mut &[][..], &mut empty_brotli, &brotli::enc::BrotliEncoderParams::default()).unwrap();
+        for multithread in [false, true].iter() {
+            assert_eq!(decode_all(&divans[..], *multithread), (Some(StreamFormat::Divans), Ok(input.to_vec())));
+            assert_eq!(decode_all(&stored[..], *multithread), (Some(StreamFormat::Divans), Ok(input[..5].to_vec())));
+            assert_eq!(decode_all(&brotli_stream[..], *multithread), (Some(StreamFormat::Brotli), Ok(input.to_vec())));
+            assert_eq!(decode_all(&empty_brotli[..], *multithread), (Some(StreamFormat::Brotli), Ok(Vec::new())));
+        }
+        assert_eq!(decode_all(&[], false), (None, Err(ErrMsg::TruncatedInput)));
+        // an empty brotli stream, and the same with a padding bit set
+        assert_eq!(decode_all(&[0x3f], false), (Some(StreamFormat::Brotli), Ok(Vec::new())));
+        assert_eq!(decode_all(&[0x7f], false).1, Err(ErrMsg::BrotliDecompressStreamFail));
+        // the first byte of every divans stream is not one brotli accepts
+        assert_eq!(decode_all(&[0xff], false).0, Some(StreamFormat::Divans));
+        let mut brotli_decoder = brotli::Decompressor::new(&[0xffu8][..], 4096);
+        assert!(::std::io::Read::read_to_end(&mut brotli_decoder, &mut Vec::new()).is_err());
+    }
+}
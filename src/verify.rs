@@ -0,0 +1,244 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// A cheap "is this file sane" gate for archive ingestion: decodes a stream, spliced or not, with
+// every check the decoder has turned on, dropping the output as it goes.  Beyond the checksum, the
+// trailer and the commands themselves, decoding is paranoid (see DivansDecompressor::set_paranoid),
+// so copies reaching before the start of the stream and models left holding invalid CDFs fail too.
+#![cfg(feature="std")]
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use alloc_stdlib::HeapAlloc;
+use error::Error;
+use interface::{Decompressor, DecodedStreamOptions, DecodeStats, DefaultCDF16, DivansResult, ErrMsg, Nop};
+use divans_decompressor::{DivansDecompressorFactory, DivansDecompressorFactoryStruct, StaticCommand};
+
+const VERIFY_BUFFER_SIZE: usize = 65536;
+
+// What verify found.  options and stats are those of the last stream decoded, as far as it got.
+#[derive(Debug)]
+pub struct Report {
+    pub input_bytes: u64, // consumed before the stream ended or decoding failed
+    pub output_bytes: u64, // that the input decoded to, over every spliced stream
+    pub options: Option<DecodedStreamOptions>,
+    pub stats: DecodeStats,
+    pub error: Option<Error>, // None if the input is exactly a sane stream
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+// Verifies the stream held by the file at path; a file that cannot be opened or read is reported as an Io error.
+pub fn verify<P: AsRef<Path>>(path: P) -> Report {
+    match File::open(path) {
+        Ok(file) => verify_reader(file),
+        Err(err) => Report {
+            input_bytes: 0,
+            output_bytes: 0,
+            options: None,
+            stats: DecodeStats::default(),
+            error: Some(Error::from(err).in_state("opening")),
+        },
+    }
+}
+
+// Verifies the stream reader holds, which must end with it: bytes after the trailer of the last
+// stream are an error, as is a stream that ends early.
+pub fn verify_reader<R: Read>(mut reader: R) -> Report {
+    let mut decompressor = DivansDecompressorFactoryStruct::<HeapAlloc<u8>,
+                                                             HeapAlloc<DefaultCDF16>,
+                                                             HeapAlloc<StaticCommand>>::new(
+        HeapAlloc::<u8>::new(0),
+        HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+        HeapAlloc::<StaticCommand>::new(StaticCommand::nop()),
+        false,
+        false);
+    decompressor.set_paranoid(true).expect("before the first decode");
+    let mut input = vec![0u8; VERIFY_BUFFER_SIZE];
+    let mut output = vec![0u8; VERIFY_BUFFER_SIZE];
+    let mut input_len = 0usize;
+    let mut input_offset = 0usize;
+    let mut input_bytes = 0u64;
+    let mut output_bytes = 0u64;
+    let mut eof = false;
+    let result: Result<(), Error> = loop {
+        if input_offset == input_len && !eof {
+            match read_some(&mut reader, &mut input[..]) {
+                Ok(0) => eof = true,
+                Ok(len) => {
+                    input_len = len;
+                    input_offset = 0;
+                },
+                Err(err) => break Err(Error::from(err).in_state("reading")),
+            }
+        }
+        let mut output_offset = 0usize;
+        let start = input_offset;
+        let res = decompressor.decode(&input[..input_len], &mut input_offset, &mut output[..], &mut output_offset);
+        input_bytes += (input_offset - start) as u64;
+        output_bytes += output_offset as u64;
+        let code = match res {
+            DivansResult::Success => {
+                if input_offset == input_len && !eof {
+                    // a stream that ended with the reader leaves nothing to read
+                    match read_some(&mut reader, &mut input[..]) {
+                        Ok(0) => break Ok(()),
+                        Ok(len) => {
+                            input_len = len;
+                            input_offset = 0;
+                        },
+                        Err(err) => break Err(Error::from(err).in_state("reading")),
+                    }
+                }
+                if input_offset == input_len {
+                    break Ok(());
+                }
+                ErrMsg::TrailingInput(input[input_offset])
+            },
+            DivansResult::NeedsMoreOutput => continue,
+            DivansResult::NeedsMoreInput => if eof {
+                ErrMsg::TruncatedInput
+            } else {
+                continue
+            },
+            DivansResult::Failure(m) => m,
+        };
+        break Err(Error::from(code).in_state("verifying").at(input_bytes, output_bytes));
+    };
+    let options = decompressor.options();
+    let stats = decompressor.stats();
+    decompressor.free();
+    Report {
+        input_bytes,
+        output_bytes,
+        options,
+        stats,
+        error: result.err(),
+    }
+}
+
+// reads at least a byte unless the reader is done, retrying interruptions
+fn read_some<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    loop {
+        match reader.read(buffer) {
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+            res => return res,
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature="encoder")]
+mod test {
+    use super::{verify, verify_reader};
+    use std::vec::Vec;
+    use conformance::{conformance_commands, conformance_encode, ConformanceSlice, CONFORMANCE_SCENARIOS};
+    use codec::StrideSelection;
+    use error::ErrorKind;
+    use interface::{Command, CopyCommand, DivansCompressorOptions, ErrMsg, FeatureFlagSliceType, LiteralCommand};
+    use oneshot::compress;
+
+    #[test]
+    fn test_verify_sane_streams() {
+        let input = &include_bytes!("../testdata/alice29")[..65536];
+        let mut opts = DivansCompressorOptions::default();
+        opts.quality = Some(11);
+        let stream = compress(input, opts).unwrap();
+        let report = verify_reader(&stream[..]);
+        assert!(report.is_ok(), "{:?}", report.error);
+        assert_eq!(report.input_bytes, stream.len() as u64);
+        assert_eq!(report.output_bytes, input.len() as u64);
+        assert_eq!(report.stats.total_bytes(), input.len() as u64);
+        assert!(report.options.unwrap().checksum_verified);
+        // the conformance vectors copy from as far back as they can
+        for scenario in CONFORMANCE_SCENARIOS.iter() {
+            let vector = conformance_encode(&conformance_commands(scenario)[..], StrideSelection::UseBrotliRec, None, 1).unwrap();
+            let report = verify_reader(&vector[..]);
+            assert!(report.is_ok(), "{} {:?}", scenario, report.error);
+        }
+    }
+
+    // hands out what it holds at most chunk bytes per read
+    struct ShortReads<'a> {
+        data: &'a [u8],
+        chunk: usize,
+    }
+
+    impl<'a> ::std::io::Read for ShortReads<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+            let len = ::core::cmp::min(::core::cmp::min(self.chunk, buf.len()), self.data.len());
+            buf[..len].clone_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_verify_short_reads() {
+        let input = &include_bytes!("../testdata/alice29")[..16384];
+        let stream = compress(input, DivansCompressorOptions::default()).unwrap();
+        for chunk in [1usize, 7, 256, stream.len() - 1].iter() {
+            let report = verify_reader(ShortReads{data: &stream[..], chunk: *chunk});
+            assert!(report.is_ok(), "{} {:?}", chunk, report.error);
+            assert_eq!(report.input_bytes, stream.len() as u64);
+            assert_eq!(report.output_bytes, input.len() as u64);
+        }
+        // the byte after the trailer only turns up in a read after the stream succeeded
+        let mut trailing = stream.clone();
+        trailing.push(9);
+        let report = verify_reader(ShortReads{data: &trailing[..], chunk: stream.len()});
+        assert_eq!(report.error.unwrap().code(), Some(ErrMsg::TrailingInput(9)));
+        assert_eq!(report.input_bytes, stream.len() as u64);
+    }
+
+    #[test]
+    fn test_verify_rejects() {
+        let input = &include_bytes!("../testdata/alice29")[..32768];
+        let stream = compress(input, DivansCompressorOptions::default()).unwrap();
+        let report = verify_reader(&stream[..stream.len() - 1]);
+        assert_eq!(report.error.unwrap().code(), Some(ErrMsg::TruncatedInput));
+        let mut trailing = stream.clone();
+        trailing.push(7);
+        assert_eq!(verify_reader(&trailing[..]).error.unwrap().code(), Some(ErrMsg::TrailingInput(7)));
+        let mut corrupt = stream.clone();
+        let middle = corrupt.len() / 2;
+        corrupt[middle] ^= 0x10;
+        assert!(!verify_reader(&corrupt[..]).is_ok());
+        let missing = verify("testdata/no such file");
+        assert_eq!(missing.error.unwrap().kind(), ErrorKind::Io);
+    }
+
+    #[test]
+    fn test_verify_copy_before_stream_start() {
+        // a plain decoder fills the window with zeros and decodes this, but no encoder would write it
+        let commands: Vec<Command<ConformanceSlice>> = vec![
+            Command::Literal(LiteralCommand {
+                data: ConformanceSlice(b"0123456789".to_vec()),
+                prob: FeatureFlagSliceType::default(),
+                high_entropy: false,
+            }),
+            Command::Copy(CopyCommand{distance: 20, num_bytes: 4}),
+        ];
+        let stream = conformance_encode(&commands[..], StrideSelection::UseBrotliRec, None, 1).unwrap();
+        assert_eq!(::oneshot::decompress(&stream[..]).unwrap().len(), 14);
+        let report = verify_reader(&stream[..]);
+        let err = report.error.unwrap();
+        assert_eq!(err.code(), Some(ErrMsg::CopyBeforeStreamStart));
+        assert_eq!(err.kind(), ErrorKind::Corrupt);
+    }
+}
@@ -0,0 +1,407 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Byte order of the multi-byte fields in the divans format. Every field is little endian and is
+// assembled one byte at a time, so neither the encoder nor the decoder depends on host byte order.
+use interface::{ChecksumType, HEADER_LENGTH};
+
+// bytes in the stream trailer: the crc followed by CHECKSUM_TAG
+pub const CHECKSUM_LENGTH: usize = 8;
+pub const CHECKSUM_TAG: [u8; 4] = [b'a', b'n', b's', b'~'];
+// stands in for CHECKSUM_TAG when another stream follows the trailer (see splice)
+pub const SPLICE_TAG: [u8; 4] = [b'a', b'n', b's', b'+'];
+// bytes of HMAC-SHA256 tag after the crc trailer of a STREAM_FEATURE_AUTHENTICATED stream
+pub const MAC_LENGTH: usize = 32;
+// bytes of the crc at the start of the trailer
+pub const CRC_LENGTH: usize = 4;
+// bytes of the digest in its place in the trailer of a ChecksumType::Xxh3 stream
+pub const WIDE_DIGEST_LENGTH: usize = 8;
+// bytes of the magic number at the start of the header
+pub const MAGIC_NUMBER_LENGTH: usize = 4;
+pub const MIN_FRAMING_TAG_LENGTH: usize = 2;
+pub const MAX_FRAMING_TAG_LENGTH: usize = 8;
+pub const MAX_HEADER_LENGTH: usize = HEADER_LENGTH - MAGIC_NUMBER_LENGTH + MAX_FRAMING_TAG_LENGTH;
+pub const MAX_CHECKSUM_LENGTH: usize = WIDE_DIGEST_LENGTH + MAX_FRAMING_TAG_LENGTH;
+pub const MAX_TRAILER_LENGTH: usize = MAX_CHECKSUM_LENGTH + MAC_LENGTH;
+// the header and trailer of a crc32c stream with neither a framing tag nor authentication, for callers
+// accounting for stored sizes (see interface::framing_overhead)
+pub const HEADER_SIZE: usize = HEADER_LENGTH;
+pub const TRAILER_SIZE: usize = CHECKSUM_LENGTH;
+
+// A caller's own tag, written in place of both the magic number and CHECKSUM_TAG so that a stream
+// embedded in a container framed by that tag needs no framing of its own.  Encoder and decoder must
+// be given the same one; the decoder refuses a stream that does not carry it at both ends.
+// A framed stream never has another spliced on after it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FramingTag {
+    tag: [u8; MAX_FRAMING_TAG_LENGTH],
+    len: u8,
+}
+
+impl FramingTag {
+    // None unless tag holds MIN_FRAMING_TAG_LENGTH to MAX_FRAMING_TAG_LENGTH bytes
+    pub fn new(tag: &[u8]) -> Option<Self> {
+        if tag.len() < MIN_FRAMING_TAG_LENGTH || tag.len() > MAX_FRAMING_TAG_LENGTH {
+            return None;
+        }
+        let mut ret = FramingTag{tag: [0u8; MAX_FRAMING_TAG_LENGTH], len: tag.len() as u8};
+        ret.tag[..tag.len()].clone_from_slice(tag);
+        Some(ret)
+    }
+    pub fn bytes(&self) -> &[u8] {
+        &self.tag[..usize::from(self.len)]
+    }
+}
+
+// as just its bytes, so a deserialized tag is held to the lengths new allows
+#[cfg(feature="serde")]
+impl serde::Serialize for FramingTag {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.bytes())
+    }
+}
+
+#[cfg(feature="serde")]
+impl<'de> serde::Deserialize<'de> for FramingTag {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use core::fmt;
+        use serde::de::{Error, SeqAccess, Visitor};
+        struct FramingTagVisitor;
+        impl<'de> Visitor<'de> for FramingTagVisitor {
+            type Value = FramingTag;
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                write!(formatter, "{} to {} bytes", MIN_FRAMING_TAG_LENGTH, MAX_FRAMING_TAG_LENGTH)
+            }
+            fn visit_bytes<E: Error>(self, tag: &[u8]) -> Result<FramingTag, E> {
+                FramingTag::new(tag).ok_or_else(|| E::invalid_length(tag.len(), &self))
+            }
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<FramingTag, A::Error> {
+                let mut tag = [0u8; MAX_FRAMING_TAG_LENGTH + 1];
+                let mut len = 0;
+                while let Some(byte) = seq.next_element()? {
+                    if len == tag.len() {
+                        return Err(A::Error::invalid_length(len + 1, &self));
+                    }
+                    tag[len] = byte;
+                    len += 1;
+                }
+                self.visit_bytes(&tag[..len])
+            }
+        }
+        deserializer.deserialize_bytes(FramingTagVisitor)
+    }
+}
+
+// bytes of the header as written
+pub fn header_length(framing: Option<&FramingTag>) -> usize {
+    match framing {
+        Some(framing) => HEADER_LENGTH - MAGIC_NUMBER_LENGTH + framing.bytes().len(),
+        None => HEADER_LENGTH,
+    }
+}
+
+// the header as written: header with its magic number swapped for the framing tag
+pub fn frame_header(header: &[u8; HEADER_LENGTH], framing: Option<&FramingTag>) -> ([u8; MAX_HEADER_LENGTH], usize) {
+    let mut ret = [0u8; MAX_HEADER_LENGTH];
+    let tag = match framing {
+        Some(framing) => framing.bytes(),
+        None => &header[..MAGIC_NUMBER_LENGTH],
+    };
+    ret[..tag.len()].clone_from_slice(tag);
+    ret[tag.len()..tag.len() + HEADER_LENGTH - MAGIC_NUMBER_LENGTH].clone_from_slice(&header[MAGIC_NUMBER_LENGTH..]);
+    (ret, tag.len() + HEADER_LENGTH - MAGIC_NUMBER_LENGTH)
+}
+
+// bytes of the crc and the tag after it, without any authentication tag
+pub fn checksum_length(framing: Option<&FramingTag>, checksum: ChecksumType) -> usize {
+    match framing {
+        Some(framing) => checksum.digest_length() + framing.bytes().len(),
+        None => checksum.digest_length() + CHECKSUM_TAG.len(),
+    }
+}
+
+#[inline(always)]
+pub fn u16_to_le(val: u16) -> [u8; 2] {
+    [val as u8, (val >> 8) as u8]
+}
+
+#[inline(always)]
+pub fn u16_from_le(bytes: &[u8]) -> u16 {
+    u16::from(bytes[0]) | (u16::from(bytes[1]) << 8)
+}
+
+#[inline(always)]
+pub fn u32_to_le(val: u32) -> [u8; 4] {
+    [val as u8, (val >> 8) as u8, (val >> 16) as u8, (val >> 24) as u8]
+}
+
+#[inline(always)]
+pub fn u32_from_le(bytes: &[u8]) -> u32 {
+    u32::from(bytes[0]) | (u32::from(bytes[1]) << 8) | (u32::from(bytes[2]) << 16) | (u32::from(bytes[3]) << 24)
+}
+
+#[inline(always)]
+pub fn u64_to_le(val: u64) -> [u8; 8] {
+    let low = u32_to_le(val as u32);
+    let high = u32_to_le((val >> 32) as u32);
+    [low[0], low[1], low[2], low[3], high[0], high[1], high[2], high[3]]
+}
+
+#[inline(always)]
+pub fn u64_from_le(bytes: &[u8]) -> u64 {
+    u64::from(u32_from_le(bytes)) | (u64::from(u32_from_le(&bytes[4..])) << 32)
+}
+
+// the trailer written after the last command: the 32 bit crc followed by CHECKSUM_TAG
+pub fn checksum_trailer(crc: u32) -> [u8; CHECKSUM_LENGTH] {
+    let crc_bytes = u32_to_le(crc);
+    [crc_bytes[0], crc_bytes[1], crc_bytes[2], crc_bytes[3],
+     CHECKSUM_TAG[0], CHECKSUM_TAG[1], CHECKSUM_TAG[2], CHECKSUM_TAG[3]]
+}
+
+// the checksum trailer, the crc as wide as checksum calls for and then the framing tag if any in
+// place of CHECKSUM_TAG, followed by the tag when the stream is authenticated; returns the used length
+pub fn stream_trailer(crc: u64, checksum: ChecksumType, framing: Option<&FramingTag>, tag: Option<[u8; MAC_LENGTH]>) -> ([u8; MAX_TRAILER_LENGTH], usize) {
+    let mut ret = [0u8; MAX_TRAILER_LENGTH];
+    let crc_len = checksum.digest_length();
+    ret[..crc_len].clone_from_slice(&u64_to_le(crc)[..crc_len]);
+    let tag_bytes = match framing {
+        Some(framing) => framing.bytes(),
+        None => &CHECKSUM_TAG[..],
+    };
+    ret[crc_len..crc_len + tag_bytes.len()].clone_from_slice(tag_bytes);
+    let checksum_len = checksum_length(framing, checksum);
+    match tag {
+        Some(tag) => {
+            ret[checksum_len..checksum_len + MAC_LENGTH].clone_from_slice(&tag[..]);
+            (ret, checksum_len + MAC_LENGTH)
+        },
+        None => (ret, checksum_len),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use conformance::{conformance_commands, conformance_expected_output};
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use codec::SubDigest;
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use output_digest::Xxh3;
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use splice::splice;
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use error::ErrorKind;
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use core::hash::Hasher;
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use interface::{
+        DivansCompressorOptions,
+        CompressorOption,
+        FormatVersion,
+        ErrMsg,
+        ChecksumType,
+        ChecksumPolicy,
+        HEADER_LENGTH,
+        HEADER_CHECKSUM_OFFSET,
+        MAGIC_NUMBER_EXTENDED,
+        EXTENDED_FEATURE_CONTEXT_MAP_MODEL,
+        AUTHENTICATION_KEY_LENGTH,
+        STORED_FRAME_HEADER_LENGTH,
+        framing_overhead,
+    };
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use mux::EOF_MARKER;
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    use test_support::{encode, encode_with_options, compress_hybrid, decode, decoded_options, decode_with, DecodeConfig};
+    // spelled out byte by byte so a big-endian host fails here rather than producing foreign streams
+    #[test]
+    fn test_fields_are_little_endian() {
+        assert_eq!(u16_to_le(0x1234), [0x34, 0x12]);
+        assert_eq!(u16_from_le(&[0x34, 0x12]), 0x1234);
+        assert_eq!(u32_to_le(0x12345678), [0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(u32_from_le(&[0x78, 0x56, 0x34, 0x12]), 0x12345678);
+        assert_eq!(u64_from_le(&[0xef, 0xcd, 0xab, 0x89, 0x67, 0x45, 0x23, 0x01]), 0x0123456789abcdef);
+        assert_eq!(checksum_trailer(0xdeadbeef), [0xef, 0xbe, 0xad, 0xde, b'a', b'n', b's', b'~']);
+    }
+    #[test]
+    fn test_framing_tag_replaces_magic_and_checksum_tag() {
+        assert_eq!(FramingTag::new(b"x"), None);
+        assert_eq!(FramingTag::new(b"123456789"), None);
+        let framing = FramingTag::new(b"PRIV").unwrap();
+        let mut header = [0u8; HEADER_LENGTH];
+        for (index, h) in header.iter_mut().enumerate() {
+            *h = index as u8;
+        }
+        for tag in [&b"ab"[..], &b"PRIVATE!"[..]].iter() {
+            let other = FramingTag::new(tag).unwrap();
+            let (framed, len) = frame_header(&header, Some(&other));
+            assert_eq!(len, header_length(Some(&other)));
+            assert_eq!(&framed[..tag.len()], *tag);
+            assert_eq!(&framed[tag.len()..len], &header[MAGIC_NUMBER_LENGTH..]);
+        }
+        let (framed, len) = frame_header(&header, None);
+        assert_eq!(&framed[..len], &header[..]);
+        let (trailer, len) = stream_trailer(0xdeadbeef, ChecksumType::Crc32c, Some(&framing), None);
+        assert_eq!(&trailer[..len], &[0xef, 0xbe, 0xad, 0xde, b'P', b'R', b'I', b'V']);
+        let (trailer, len) = stream_trailer(0x0123456789abcdef, ChecksumType::Xxh3, Some(&framing), None);
+        assert_eq!(&trailer[..len], &[0xef, 0xcd, 0xab, 0x89, 0x67, 0x45, 0x23, 0x01, b'P', b'R', b'I', b'V']);
+        assert_eq!(len, checksum_length(Some(&framing), ChecksumType::Xxh3));
+        let (trailer, len) = stream_trailer(0xdeadbeef, ChecksumType::Crc32c, None, Some([7u8; MAC_LENGTH]));
+        assert_eq!(&trailer[..CHECKSUM_LENGTH], &checksum_trailer(0xdeadbeef));
+        assert_eq!(len, CHECKSUM_LENGTH + MAC_LENGTH);
+    }
+    #[test]
+    fn test_roundtrip_matches_host_conversion() {
+        for val in [0u32, 1, 0xff, 0x100, 0xffff, 0x10000, 0x7fffffff, 0xffffffff].iter() {
+            let le = u32_to_le(*val);
+            assert_eq!(u32_from_le(&le[..]), *val);
+            assert_eq!(le, val.to_le_bytes());
+        }
+        for val in [0u16, 1, 0xff, 0x100, 0xfffe, 0xffff].iter() {
+            assert_eq!(u16_from_le(&u16_to_le(*val)[..]), *val);
+        }
+    }
+    #[cfg(feature="serde")]
+    #[test]
+    fn test_framing_tag_serde() {
+        let tag = FramingTag::new(b"ab\x00cd").unwrap();
+        let json = ::serde_json::to_string(&tag).unwrap();
+        assert_eq!(json, "[97,98,0,99,100]");
+        assert_eq!(::serde_json::from_str::<FramingTag>(&json).unwrap(), tag);
+        assert!(::serde_json::from_str::<FramingTag>("[1]").is_err());
+        assert!(::serde_json::from_str::<FramingTag>("[1,2,3,4,5,6,7,8,9]").is_err());
+    }
+
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    #[test]
+    fn test_framing_tag_roundtrip() {
+        let commands = conformance_commands("mixed");
+        let expected = conformance_expected_output(&commands[..]).unwrap();
+        let plain = encode_with_options(&commands[..], DivansCompressorOptions::default()).unwrap();
+        for (tag, extended_stream_features) in [(&b"PRIVATE!"[..], 0), (&b"pv"[..], EXTENDED_FEATURE_CONTEXT_MAP_MODEL)].iter() {
+            let framing = FramingTag::new(tag).unwrap();
+            let mut opts = DivansCompressorOptions::default();
+            opts.framing_tag = Some(framing);
+            opts.extended_stream_features = *extended_stream_features;
+            let stream = encode_with_options(&commands[..], opts).unwrap();
+            assert!(stream.starts_with(tag) && stream.ends_with(tag));
+            if *extended_stream_features == 0 {
+                assert_eq!(stream.len(), plain.len() + 2 * (tag.len() - CHECKSUM_TAG.len()));
+            }
+            // the crc covers the header as written
+            let checksum_offset = stream.len() - 4 - tag.len();
+            let mut digest = SubDigest::default();
+            digest.write(&stream[..checksum_offset]);
+            assert_eq!(digest.finish(), u64::from(u32_from_le(&stream[checksum_offset..])));
+            for multithread in [false, true].iter() {
+                let decode_framed = |stream: &[u8], framing: Option<FramingTag>| {
+                    let mut config = DecodeConfig::new(*multithread);
+                    config.framing = framing;
+                    decode_with(stream, config, |decompressor| {
+                        // the tag is fixed once decoding has begun
+                        assert_eq!(decompressor.set_framing_tag(None), Err(ErrMsg::WrongInternalDecoderState));
+                    }).into_output()
+                };
+                assert_eq!(decode_framed(&stream[..], Some(framing)).unwrap(), expected);
+                assert_eq!(decode_framed(&stream[..], FramingTag::new(b"other")), Err(ErrMsg::FramingTagMismatch));
+                match decode_framed(&stream[..], None) {
+                    Err(ErrMsg::MagicNumberWrongA(_, _)) => {},
+                    res => panic!("{:?}", res.map(|output| output.len())),
+                }
+                assert_eq!(decode_framed(&plain[..], Some(framing)), Err(ErrMsg::FramingTagMismatch));
+                let mut bad_tag = stream.clone();
+                *bad_tag.last_mut().unwrap() ^= 1;
+                match decode_framed(&bad_tag[..], Some(framing)) {
+                    Err(ErrMsg::BadChecksum(computed, stored)) => assert!(computed != stored),
+                    res => panic!("{:?}", res.map(|output| output.len())),
+                }
+            }
+        }
+    }
+
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    #[test]
+    fn test_wide_checksum_trailer() {
+        let commands = conformance_commands("mixed");
+        let expected = conformance_expected_output(&commands[..]).unwrap();
+        let mut opts = DivansCompressorOptions::default();
+        opts.checksum = ChecksumType::Xxh3;
+        let stream = encode_with_options(&commands[..], opts).unwrap();
+        assert_eq!(stream[..4], MAGIC_NUMBER_EXTENDED);
+        assert_eq!(stream[HEADER_CHECKSUM_OFFSET], ChecksumType::Xxh3.header_byte());
+        let trailer = stream.len() - WIDE_DIGEST_LENGTH - CHECKSUM_TAG.len();
+        assert_eq!(&stream[trailer + WIDE_DIGEST_LENGTH..], &CHECKSUM_TAG[..]);
+        let mut xxh3 = Xxh3::default();
+        xxh3.write(&stream[..trailer]);
+        let digest = u64_from_le(&stream[trailer..]);
+        assert_eq!(xxh3.finish(), digest);
+        let mut bad_digest = stream.clone();
+        bad_digest[trailer + WIDE_DIGEST_LENGTH - 1] ^= 1; // a bit a 32 bit crc would not hold
+        for multithread in [false, true].iter() {
+            assert_eq!(decode_with(&stream[..], DecodeConfig::new(*multithread), |_| {}).into_output().unwrap(), expected);
+            assert_eq!(decoded_options(&stream[..], *multithread).unwrap().checksum, ChecksumType::Xxh3);
+            let mut config = DecodeConfig::new(*multithread);
+            config.checksum_policy = Some(ChecksumPolicy::SkipButParse);
+            let decoded = decode_with(&stream[..], config, |_| {});
+            assert_eq!((decoded.result, decoded.expected_checksum), (Ok(()), Some(digest)));
+            // only a crc32c trailer has a crc to report
+            assert_eq!(decoded.expected_crc, None);
+            // the crc worker only hashes crc32c, so the digest is hashed inline
+            let mut config = DecodeConfig::new(*multithread);
+            config.threaded_crc = true;
+            assert_eq!(decode_with(&stream[..], config, |_| {}).into_output(), Ok(expected.clone()));
+            config = DecodeConfig::new(*multithread);
+            config.checksum_policy = Some(ChecksumPolicy::Verify);
+            match decode_with(&bad_digest[..], config, |_| {}).result {
+                Err(ErrMsg::BadChecksum(computed, stored)) => assert_eq!(stored, computed ^ (1 << 56)),
+                res => panic!("{:?}", res),
+            }
+        }
+        // a spliced stream switches checksums from one stream to the next
+        let plain = encode(&commands[..], 0).unwrap();
+        let mut twice = expected.clone();
+        twice.extend(&expected[..]);
+        assert_eq!(decode(&splice(&stream[..], &plain[..]).unwrap()[..]).unwrap(), twice);
+        assert_eq!(decode(&splice(&plain[..], &stream[..]).unwrap()[..]).unwrap(), twice);
+        let mut unknown = stream.clone();
+        unknown[HEADER_CHECKSUM_OFFSET] = 0xff;
+        assert_eq!(decode(&unknown[..]), Err(ErrMsg::UnsupportedChecksum(0xff)));
+        assert_eq!(ErrorKind::of(ErrMsg::UnsupportedChecksum(0xff)), ErrorKind::Unsupported);
+        assert_eq!(framing_overhead(&opts), framing_overhead(&DivansCompressorOptions::default()) + 4);
+        opts.format_version = Some(FormatVersion::StreamFeatures);
+        assert_eq!(opts.validate(), Err(ErrMsg::ConflictingOptions(CompressorOption::FormatVersion,
+                                                                   CompressorOption::Checksum)));
+    }
+
+    #[cfg(all(feature="std", feature="encoder", feature="decoder"))]
+    #[test]
+    fn test_framing_overhead() {
+        let input = &include_bytes!("../testdata/alice29")[..4096];
+        let plain = DivansCompressorOptions::default();
+        assert_eq!(framing_overhead(&plain), ::wire::HEADER_SIZE + EOF_MARKER.len() + ::wire::TRAILER_SIZE);
+        let mut keyed = plain;
+        keyed.authentication_key = Some([0x5au8; AUTHENTICATION_KEY_LENGTH]);
+        assert_eq!(framing_overhead(&keyed), framing_overhead(&plain) + 32);
+        for &(opts, tag_len) in [(plain, 0), (keyed, 32)].iter() {
+            let stream = compress_hybrid(input, opts);
+            let eof = stream.len() - ::wire::TRAILER_SIZE - tag_len - EOF_MARKER.len();
+            assert_eq!(&stream[eof..eof + EOF_MARKER.len()], &EOF_MARKER[..]);
+            assert!(stream.len() > framing_overhead(&opts));
+        }
+        // the empty input is stored rather than framed
+        assert!(compress_hybrid(&[], plain).len() <= STORED_FRAME_HEADER_LENGTH);
+    }
+}
@@ -5,14 +5,24 @@ use std::io;
 use std::io::Write;
 use core;
 use super::interface::{DivansResult, DivansOutputResult, ErrMsg};
-use ::interface::{Compressor, DivansCompressorFactory, Decompressor};
+#[cfg(feature="encoder")]
+use ::interface::{Compressor, DivansCompressorFactory};
+#[cfg(feature="decoder")]
+use ::interface::{Decompressor, TrailingBytesPolicy};
+#[cfg(feature="decoder")]
 use ::DivansDecompressorFactory;
+#[cfg(feature="encoder")]
 use ::brotli;
+#[cfg(feature="encoder")]
 use ::interface;
+#[cfg(feature="decoder")]
 use ::StaticCommand;
+#[cfg(feature="decoder")]
 use ::brotli::interface::Nop;
+use ::error::Error;
 
 trait Processor {
+   const STATE: &'static str; // what the Error of a failure says was going on
    fn process(&mut self, input:&[u8], input_offset:&mut usize, output:&mut [u8], output_offset:&mut usize) -> DivansResult;
    fn close(&mut self, output:&mut [u8], output_offset:&mut usize) -> DivansOutputResult;
 }
@@ -24,10 +34,12 @@ struct GenWriter<W: Write,
   output_buffer: BufferType,
   has_flushed: bool,
   output: W,
+  total_in: u64, // bytes handed to the compressor
+  total_out: u64,
 }
 
 
-impl<W:Write, P:Processor, BufferType:SliceWrapperMut<u8>> Write for GenWriter<W,P,BufferType> {
+impl<W:Write, C:Processor, BufferType:SliceWrapperMut<u8>> Write for GenWriter<W,C,BufferType> {
     fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
         let mut avail_in = buf.len();
         let mut input_offset : usize = 0;
@@ -40,19 +52,24 @@ impl<W:Write, P:Processor, BufferType:SliceWrapperMut<u8>> Write for GenWriter<W
                 self.output_buffer.slice_mut(),
                 &mut output_offset);
             avail_in -= input_offset - old_input_offset;
+            self.total_in += (input_offset - old_input_offset) as u64;
+            self.total_out += output_offset as u64;
             match self.output.write_all(&self.output_buffer.slice_mut()[..output_offset]) {
                 Ok(_) => {},
                 Err(e) => return Err(e),
             }
             match op_result {
                 DivansResult::NeedsMoreInput => if avail_in != 0 {
-                    return Err(io::Error::new(
-                        io::ErrorKind::UnexpectedEof,
-                        ErrMsg::TrailingInput(core::cmp::min(avail_in, 255) as u8)));
+                    return Err(self.error(ErrMsg::TrailingInput(core::cmp::min(avail_in, 255) as u8), C::STATE));
                 },
                 DivansResult::NeedsMoreOutput => continue,
-                DivansResult::Success => return Ok(buf.len()),
-                DivansResult::Failure(m) => return Err(io::Error::new(io::ErrorKind::InvalidInput, m)),
+                DivansResult::Success => if avail_in != 0 {
+                    // past the end of the stream, and not dropped (see TrailingBytesPolicy)
+                    return Err(self.error(ErrMsg::TrailingInput(buf[input_offset]), C::STATE));
+                } else {
+                    return Ok(buf.len());
+                },
+                DivansResult::Failure(m) => return Err(self.error(m, C::STATE)),
             }
             if avail_in == 0 {
                 break
@@ -66,13 +83,14 @@ impl<W:Write, P:Processor, BufferType:SliceWrapperMut<u8>> Write for GenWriter<W
             let ret = self.compressor.close(
                 self.output_buffer.slice_mut(),
                 &mut output_offset);
+            self.total_out += output_offset as u64;
             match self.output.write_all(&self.output_buffer.slice_mut()[..output_offset]) {
                 Ok(_) => {},
                 Err(e) => return Err(e),
             }
             match ret {
                 DivansOutputResult::Failure(m) => {
-                    return Err(io::Error::new(io::ErrorKind::InvalidInput, m))
+                    return Err(self.error(m, "flushing"))
                 }
                 DivansOutputResult::NeedsMoreOutput => {},
                 DivansOutputResult::Success => {
@@ -90,10 +108,16 @@ impl<W:Write, C:Processor, BufferType:SliceWrapperMut<u8>> GenWriter<W,C,BufferT
             compressor:compressor,
             output_buffer: buffer,
             has_flushed: !needs_flush,
+            total_in: 0,
+            total_out: 0,
         }
     }
+    fn error(&self, m: ErrMsg, state: &'static str) -> io::Error {
+        io::Error::from(Error::from(m).in_state(state).at(self.total_in, self.total_out))
+    }
 }
-type DivansBrotliFactory = ::BrotliDivansHybridCompressorFactory<HeapAlloc<u8>,
+#[cfg(feature="encoder")]
+pub type DivansBrotliFactory = ::BrotliDivansHybridCompressorFactory<HeapAlloc<u8>,
 //                                                         HeapAlloc<u32>,
                                                          HeapAlloc<::DefaultCDF16>,
                                                                  brotli::enc::CombiningAllocator<
@@ -115,10 +139,13 @@ type DivansBrotliFactory = ::BrotliDivansHybridCompressorFactory<HeapAlloc<u8>,
                                                                      HeapAlloc<brotli::enc::histogram::ContextType>,
                                                                      HeapAlloc<brotli::enc::entropy_encode::HuffmanTree>,
                                                                      HeapAlloc<brotli::enc::ZopfliNode>>>;
-type DivansBrotliConstructedCompressor = <DivansBrotliFactory as ::DivansCompressorFactory<HeapAlloc<u8>,
+#[cfg(feature="encoder")]
+pub type DivansBrotliConstructedCompressor = <DivansBrotliFactory as ::DivansCompressorFactory<HeapAlloc<u8>,
                                                                                            HeapAlloc<u32>,
                                                                                            HeapAlloc<::DefaultCDF16>>>::ConstructedCompressor;
+#[cfg(feature="encoder")]
 impl<T:Compressor> Processor for T {
+   const STATE: &'static str = "compressing";
    fn process(&mut self, input:&[u8], input_offset:&mut usize, output:&mut [u8], output_offset:&mut usize) -> DivansResult {
        self.encode(input, input_offset, output, output_offset)
    }
@@ -127,10 +154,12 @@ impl<T:Compressor> Processor for T {
    }
 
 }
+#[cfg(feature="encoder")]
 pub struct DivansBrotliHybridCompressorWriter<W:Write>(GenWriter<W,
                                                                 DivansBrotliConstructedCompressor,
                                                                 <HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory,
                                                                >);
+#[cfg(feature="encoder")]
 impl<W:Write> Write for DivansBrotliHybridCompressorWriter<W> {
 	fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
         self.0.write(buf)
@@ -139,6 +168,35 @@ impl<W:Write> Write for DivansBrotliHybridCompressorWriter<W> {
         self.0.flush()
     }
 }
+#[cfg(feature="encoder")]
+// also used by the budgeted encoder, which needs the concrete compressor type
+pub fn new_brotli_hybrid_compressor(opts: interface::DivansCompressorOptions) -> DivansBrotliConstructedCompressor {
+    DivansBrotliFactory::new(HeapAlloc::<u8>::new(0),
+                             HeapAlloc::<u32>::new(0),
+                             HeapAlloc::<::DefaultCDF16>::new(::DefaultCDF16::default()),
+                             opts,
+                             brotli::enc::CombiningAllocator::new(
+                                 HeapAlloc::<u8>::new(0),
+                                 HeapAlloc::<u16>::new(0),
+                                 HeapAlloc::<i32>::new(0),
+                                 HeapAlloc::<u32>::new(0),
+                                 HeapAlloc::<u64>::new(0),
+                                 HeapAlloc::<brotli::enc::command::Command>::new(brotli::enc::command::Command::default()),
+                                 HeapAlloc::<brotli::enc::util::floatX>::new(0.0 as brotli::enc::util::floatX),
+                                 HeapAlloc::<brotli::enc::v8>::new(brotli::enc::v8::default()),
+                                 HeapAlloc::<brotli::enc::s16>::new(brotli::enc::s16::default()),
+                                 HeapAlloc::<brotli::enc::PDF>::new(brotli::enc::PDF::default()),
+                                 HeapAlloc::<brotli::enc::StaticCommand>::new(brotli::enc::StaticCommand::default()),
+                                 HeapAlloc::<brotli::enc::histogram::HistogramLiteral>::new(brotli::enc::histogram::HistogramLiteral::default()),
+                                 HeapAlloc::<brotli::enc::histogram::HistogramCommand>::new(brotli::enc::histogram::HistogramCommand::default()),
+                                 HeapAlloc::<brotli::enc::histogram::HistogramDistance>::new(brotli::enc::histogram::HistogramDistance::default()),
+                                 HeapAlloc::<brotli::enc::cluster::HistogramPair>::new(brotli::enc::cluster::HistogramPair::default()),
+                                 HeapAlloc::<brotli::enc::histogram::ContextType>::new(brotli::enc::histogram::ContextType::default()),
+                                 HeapAlloc::<brotli::enc::entropy_encode::HuffmanTree>::new(brotli::enc::entropy_encode::HuffmanTree::default()),
+                                 HeapAlloc::<brotli::enc::ZopfliNode>::new(brotli::enc::ZopfliNode::default()),
+                             ))
+}
+#[cfg(feature="encoder")]
 impl<W:Write> DivansBrotliHybridCompressorWriter<W> {
     pub fn new(writer: W, opts: interface::DivansCompressorOptions, mut buffer_size: usize) -> Self {
        if buffer_size == 0 {
@@ -151,32 +209,7 @@ impl<W:Write> DivansBrotliHybridCompressorWriter<W> {
                        DivansBrotliConstructedCompressor,
                        <HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory>::new(
                           writer,
-                          DivansBrotliFactory::new(
-                                           m8,
-                                           HeapAlloc::<u32>::new(0),
-                                           HeapAlloc::<::DefaultCDF16>::new(::DefaultCDF16::default()),
-                                           opts,
-                                           brotli::enc::CombiningAllocator::new(
-                                               HeapAlloc::<u8>::new(0),
-                                               HeapAlloc::<u16>::new(0),
-                                               HeapAlloc::<i32>::new(0),
-                                               HeapAlloc::<u32>::new(0),
-                                               HeapAlloc::<u64>::new(0),
-                                               HeapAlloc::<brotli::enc::command::Command>::new(brotli::enc::command::Command::default()),
-                                               HeapAlloc::<brotli::enc::util::floatX>::new(0.0 as brotli::enc::util::floatX),
-                                               HeapAlloc::<brotli::enc::v8>::new(brotli::enc::v8::default()),
-                                               HeapAlloc::<brotli::enc::s16>::new(brotli::enc::s16::default()),
-                                               HeapAlloc::<brotli::enc::PDF>::new(brotli::enc::PDF::default()),
-                                               HeapAlloc::<brotli::enc::StaticCommand>::new(brotli::enc::StaticCommand::default()),
-                                               HeapAlloc::<brotli::enc::histogram::HistogramLiteral>::new(brotli::enc::histogram::HistogramLiteral::default()),
-                                               HeapAlloc::<brotli::enc::histogram::HistogramCommand>::new(brotli::enc::histogram::HistogramCommand::default()),
-                                               HeapAlloc::<brotli::enc::histogram::HistogramDistance>::new(brotli::enc::histogram::HistogramDistance::default()),
-                                               HeapAlloc::<brotli::enc::cluster::HistogramPair>::new(brotli::enc::cluster::HistogramPair::default()),
-                                               HeapAlloc::<brotli::enc::histogram::ContextType>::new(brotli::enc::histogram::ContextType::default()),
-                                               HeapAlloc::<brotli::enc::entropy_encode::HuffmanTree>::new(brotli::enc::entropy_encode::HuffmanTree::default()),
-                                               HeapAlloc::<brotli::enc::ZopfliNode>::new(brotli::enc::ZopfliNode::default()),
-                                           ),
-                              ),
+                          new_brotli_hybrid_compressor(opts),
                           buffer,
                           true,
                        ))
@@ -184,15 +217,19 @@ impl<W:Write> DivansBrotliHybridCompressorWriter<W> {
 }
 
 
+#[cfg(feature="encoder")]
 type DivansCustomFactory = ::DivansCompressorFactoryStruct<HeapAlloc<u8>,
                                                          HeapAlloc<::DefaultCDF16>>;
+#[cfg(feature="encoder")]
 type DivansCustomConstructedCompressor = <DivansCustomFactory as ::DivansCompressorFactory<HeapAlloc<u8>,
                                                                                            HeapAlloc<u32>,
                                                                                            HeapAlloc<::DefaultCDF16>>>::ConstructedCompressor;
+#[cfg(feature="encoder")]
 pub struct DivansExperimentalCompressorWriter<W:Write>(GenWriter<W,
                                                                 DivansCustomConstructedCompressor,
                                                                  <HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory,
                                                                >);
+#[cfg(feature="encoder")]
 impl<W:Write> Write for DivansExperimentalCompressorWriter<W> {
     fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
         self.0.write(buf)
@@ -201,6 +238,7 @@ impl<W:Write> Write for DivansExperimentalCompressorWriter<W> {
         self.0.flush()
     }
 }
+#[cfg(feature="encoder")]
 impl<W:Write> DivansExperimentalCompressorWriter<W> {
     pub fn new(writer: W, opts: interface::DivansCompressorOptions, mut buffer_size: usize) -> Self {
        if buffer_size == 0 {
@@ -226,9 +264,11 @@ impl<W:Write> DivansExperimentalCompressorWriter<W> {
 }
 
 
+#[cfg(feature="decoder")]
 type StandardDivansDecompressorFactory = ::DivansDecompressorFactoryStruct<HeapAlloc<u8>,
                                                                            HeapAlloc<::DefaultCDF16>,
                                                                            HeapAlloc<StaticCommand>>;
+#[cfg(feature="decoder")]
 type DivansConstructedDecompressor = ::DivansDecompressor<<StandardDivansDecompressorFactory as ::DivansDecompressorFactory<HeapAlloc<u8>,
                                                                                                                             HeapAlloc<::DefaultCDF16>,
                                                                                                                             HeapAlloc<StaticCommand>>
@@ -236,7 +276,9 @@ type DivansConstructedDecompressor = ::DivansDecompressor<<StandardDivansDecompr
                                                           HeapAlloc<u8>,
                                                           HeapAlloc<::DefaultCDF16>,
                                                           HeapAlloc<StaticCommand>>;
+#[cfg(feature="decoder")]
 impl Processor for DivansConstructedDecompressor {
+   const STATE: &'static str = "decompressing";
    fn process(&mut self, input:&[u8], input_offset:&mut usize, output:&mut [u8], output_offset:&mut usize) -> DivansResult {
        self.decode(input, input_offset, output, output_offset)
    }
@@ -251,10 +293,12 @@ impl Processor for DivansConstructedDecompressor {
    }
 
 }
+#[cfg(feature="decoder")]
 pub struct DivansDecompressorWriter<W:Write>(GenWriter<W,
                                                       DivansConstructedDecompressor,
                                                       <HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory,
                                                       >);
+#[cfg(feature="decoder")]
 impl<W:Write> Write for DivansDecompressorWriter<W> {
 	fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
         self.0.write(buf)
@@ -263,6 +307,7 @@ impl<W:Write> Write for DivansDecompressorWriter<W> {
         self.0.flush()
     }
 }
+#[cfg(feature="decoder")]
 impl<W:Write> DivansDecompressorWriter<W> {
     pub fn new(writer: W, mut buffer_size: usize, skip_crc:bool, multithread: bool) -> Self {
        if buffer_size == 0 {
@@ -286,9 +331,17 @@ impl<W:Write> DivansDecompressorWriter<W> {
                           false,
                        ))
     }
+    // see DivansDecompressor::set_trailing_bytes_policy; only possible before the first write
+    pub fn set_trailing_bytes_policy(&mut self, policy: TrailingBytesPolicy) -> Result<(), ErrMsg> {
+        self.0.compressor.set_trailing_bytes_policy(policy)
+    }
+    pub fn trailing_bytes(&self) -> Option<u64> {
+        self.0.compressor.trailing_bytes()
+    }
 }
 
 #[cfg(test)]
+#[cfg(all(feature="encoder", feature="decoder"))]
 mod test {
     use core;
     use std::vec::Vec;
@@ -439,6 +492,21 @@ mod test {
                            stride_detection_quality: Some(2),
                            prior_bitmask_detection: 1,
                            divans_ir_optimizer:1,
+                           literal_prescan: false,
+                           stream_features: 0,
+                           distance_bucketing: interface::DistanceBucketing::default(),
+                           mixer_warm_start: None,
+                           extended_stream_features: 0,
+                           framing_tag: None,
+                           compact_priors: true,
+                           format_version: None,
+                           content_hint: interface::ContentHint::Unknown,
+                           freeze_adaptation_after: None,
+                           priors_reset_interval: None,
+                           extended_stream_features2: 0,
+                           checksum: interface::ChecksumType::Crc32c,
+                           frame_alignment: None,
+                           authentication_key: None,
                        },
                        1);
     }
@@ -462,6 +530,21 @@ mod test {
                            prior_bitmask_detection: 0,
                            stride_detection_quality: None,
                            divans_ir_optimizer:0,
+                           literal_prescan: false,
+                           stream_features: 0,
+                           distance_bucketing: interface::DistanceBucketing::default(),
+                           mixer_warm_start: None,
+                           extended_stream_features: 0,
+                           framing_tag: None,
+                           compact_priors: true,
+                           format_version: None,
+                           content_hint: interface::ContentHint::Unknown,
+                           freeze_adaptation_after: None,
+                           priors_reset_interval: None,
+                           extended_stream_features2: 0,
+                           checksum: interface::ChecksumType::Crc32c,
+                           frame_alignment: None,
+                           authentication_key: None,
                        },
                        4095);
     }
@@ -485,6 +568,21 @@ mod test {
                            prior_bitmask_detection: 1,
                            stride_detection_quality: None,
                            divans_ir_optimizer:1,
+                           literal_prescan: false,
+                           stream_features: 0,
+                           distance_bucketing: interface::DistanceBucketing::default(),
+                           mixer_warm_start: None,
+                           extended_stream_features: 0,
+                           framing_tag: None,
+                           compact_priors: true,
+                           format_version: None,
+                           content_hint: interface::ContentHint::Unknown,
+                           freeze_adaptation_after: None,
+                           priors_reset_interval: None,
+                           extended_stream_features2: 0,
+                           checksum: interface::ChecksumType::Crc32c,
+                           frame_alignment: None,
+                           authentication_key: None,
                        },
                        4095);
     }
@@ -508,6 +606,21 @@ mod test {
                            speed_detection_quality: None,
                            stride_detection_quality: Some(1),
                            divans_ir_optimizer:0,
+                           literal_prescan: false,
+                           stream_features: 0,
+                           distance_bucketing: interface::DistanceBucketing::default(),
+                           mixer_warm_start: None,
+                           extended_stream_features: 0,
+                           framing_tag: None,
+                           compact_priors: true,
+                           format_version: None,
+                           content_hint: interface::ContentHint::Unknown,
+                           freeze_adaptation_after: None,
+                           priors_reset_interval: None,
+                           extended_stream_features2: 0,
+                           checksum: interface::ChecksumType::Crc32c,
+                           frame_alignment: None,
+                           authentication_key: None,
                        },
                        3);
     }
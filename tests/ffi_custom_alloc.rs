@@ -0,0 +1,164 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Drives the C API as c/divans/ffi.h declares it, with allocators of the caller's.  A binary of its
+// own, since the allocator counting what the calling thread takes from Rust replaces the global one.
+#![cfg(all(not(feature="safe"), feature="std", feature="encoder", feature="decoder"))]
+extern crate divans;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::os::raw::c_void;
+use std::ptr;
+use std::thread;
+
+const DIVANS_SUCCESS: u8 = 0;
+
+#[repr(C)]
+#[derive(Clone)]
+struct CAllocator {
+    alloc_func: Option<extern "C" fn(opaque: *mut c_void, size: usize) -> *mut c_void>,
+    free_func: Option<extern "C" fn(opaque: *mut c_void, ptr: *mut c_void)>,
+    opaque: *mut c_void,
+}
+
+extern "C" {
+    fn divans_new_compressor_with_custom_alloc(alloc: CAllocator) -> *mut c_void;
+    fn divans_encode(state: *mut c_void,
+                     input_buf_ptr: *const u8, input_size: usize, input_offset: *mut usize,
+                     output_buf_ptr: *mut u8, output_size: usize, output_offset: *mut usize) -> u8;
+    fn divans_encode_flush(state: *mut c_void,
+                           output_buf_ptr: *mut u8, output_size: usize, output_offset: *mut usize) -> u8;
+    fn divans_free_compressor(state: *mut c_void);
+    fn divans_new_decompressor_with_custom_alloc(alloc: CAllocator, skip_crc: u8, multithread: u8) -> *mut c_void;
+    fn divans_decode(state: *mut c_void,
+                     input_buf_ptr: *const u8, input_size: usize, input_offset: *mut usize,
+                     output_buf_ptr: *mut u8, output_size: usize, output_offset: *mut usize) -> u8;
+    fn divans_free_decompressor(state: *mut c_void);
+}
+
+// counts what the calling thread takes from the Rust allocator, so the custom allocator tests
+// can tell that none of it is needed
+struct CountingAllocator;
+thread_local! {
+    static GLOBAL_ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = GLOBAL_ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+#[global_allocator]
+static COUNTING_ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const HEADER: usize = 32; // the size of the cell, keeping what follows 32-byte aligned
+#[derive(Default)]
+struct Arena {
+    allocations: usize,
+    live: usize,
+}
+extern "C" fn arena_alloc(opaque: *mut c_void, size: usize) -> *mut c_void {
+    let arena = unsafe { &mut *(opaque as *mut Arena) };
+    arena.allocations += 1;
+    arena.live += 1;
+    unsafe {
+        let ptr = System.alloc(Layout::from_size_align(size + HEADER, HEADER).unwrap());
+        *(ptr as *mut usize) = size;
+        ptr.add(HEADER) as *mut c_void
+    }
+}
+extern "C" fn arena_free(opaque: *mut c_void, ptr: *mut c_void) {
+    let arena = unsafe { &mut *(opaque as *mut Arena) };
+    arena.live -= 1;
+    unsafe {
+        let ptr = (ptr as *mut u8).sub(HEADER);
+        System.dealloc(ptr, Layout::from_size_align(*(ptr as *mut usize) + HEADER, HEADER).unwrap());
+    }
+}
+
+fn arena_allocators(arena: &mut Arena) -> CAllocator {
+    CAllocator {
+        alloc_func: Some(arena_alloc),
+        free_func: Some(arena_free),
+        opaque: arena as *mut Arena as *mut c_void,
+    }
+}
+
+// compresses and decompresses input through the C API with allocators over arena, returning the
+// round trip and how many allocations the calling thread made of the Rust allocator meanwhile
+fn roundtrip(input: &[u8], arena: &mut Arena) -> (Vec<u8>, usize) {
+    let allocators = arena_allocators(arena);
+    let mut compressed = vec![0u8; input.len() + 1024];
+    let mut output = vec![0u8; input.len() + 1024];
+    let (mut input_offset, mut compressed_len, mut compressed_offset, mut output_len) = (0usize, 0usize, 0usize, 0usize);
+    let before = GLOBAL_ALLOCATIONS.with(|count| count.get());
+    unsafe {
+        let compressor = divans_new_compressor_with_custom_alloc(allocators.clone());
+        divans_encode(compressor, input.as_ptr(), input.len(), &mut input_offset,
+                      compressed.as_mut_ptr(), compressed.len(), &mut compressed_len);
+        assert_eq!(divans_encode_flush(compressor, compressed.as_mut_ptr(), compressed.len(), &mut compressed_len),
+                   DIVANS_SUCCESS);
+        divans_free_compressor(compressor);
+        let decompressor = divans_new_decompressor_with_custom_alloc(allocators.clone(), 0, 0);
+        assert_eq!(divans_decode(decompressor, compressed.as_ptr(), compressed_len, &mut compressed_offset,
+                                 output.as_mut_ptr(), output.len(), &mut output_len),
+                   DIVANS_SUCCESS);
+        divans_free_decompressor(decompressor);
+    }
+    let global_allocations = GLOBAL_ALLOCATIONS.with(|count| count.get()) - before;
+    output.truncate(output_len);
+    (output, global_allocations)
+}
+
+#[test]
+fn test_custom_allocators_hold_all_state() {
+    let input = &include_bytes!("../testdata/alice29")[..65536];
+    let mut arena = Arena::default();
+    let (output, global_allocations) = roundtrip(input, &mut arena);
+    assert_eq!(&output[..], input);
+    assert_eq!(global_allocations, 0);
+    assert!(arena.allocations != 0);
+    assert_eq!(arena.live, 0);
+}
+
+#[test]
+fn test_custom_allocators_refuse_multithread() {
+    // a multithreaded decoder would need the Rust allocator for its worker
+    let mut arena = Arena::default();
+    let decompressor = unsafe {
+        divans_new_decompressor_with_custom_alloc(arena_allocators(&mut arena), 0, 1)
+    };
+    assert_eq!(decompressor, ptr::null_mut());
+    assert_eq!(arena.allocations, 0);
+}
+
+#[test]
+fn test_concurrent_instances() {
+    let threads: Vec<_> = (0..4usize).map(|index| thread::spawn(move || {
+        let input = &include_bytes!("../testdata/alice29")[index * 16384..(index + 1) * 16384];
+        let mut arena = Arena::default();
+        let (output, global_allocations) = roundtrip(input, &mut arena);
+        assert_eq!(&output[..], input);
+        assert_eq!(global_allocations, 0);
+        assert_eq!(arena.live, 0);
+        arena.allocations
+    })).collect();
+    for handle in threads {
+        assert!(handle.join().unwrap() != 0);
+    }
+}